@@ -0,0 +1,151 @@
+//! Encode/decode throughput of representative packet mixes
+use std::num::NonZeroU16;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use ntex_bytes::{ByteString, Bytes, BytesMut};
+use ntex_codec::{Decoder, Encoder};
+use ntex_mqtt::{v3, v5};
+
+fn v3_packets() -> Vec<v3::codec::Packet> {
+    use v3::codec::{Packet, Publish, QoS};
+
+    let pid = NonZeroU16::new(1).unwrap();
+    vec![
+        Packet::Publish(Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: ByteString::from_static("sensors/room-1/temperature"),
+            packet_id: None,
+            payload: Bytes::from_static(b"21.5"),
+        }),
+        Packet::Publish(Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: ByteString::from_static("devices/device-1/telemetry"),
+            packet_id: Some(pid),
+            payload: Bytes::from(vec![b'x'; 1024]),
+        }),
+        Packet::PublishAck { packet_id: pid },
+        Packet::Subscribe {
+            packet_id: pid,
+            topic_filters: vec![
+                (ByteString::from_static("sensors/+/temperature"), QoS::AtLeastOnce),
+                (ByteString::from_static("devices/#"), QoS::AtMostOnce),
+            ],
+        },
+        Packet::PingRequest,
+    ]
+}
+
+fn v5_packets() -> Vec<v5::codec::Packet> {
+    use v5::codec::{Packet, Publish, PublishAck, PublishAckReason, QoS};
+
+    let pid = NonZeroU16::new(1).unwrap();
+    vec![
+        Packet::Publish(Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            packet_id: None,
+            topic: ByteString::from_static("sensors/room-1/temperature"),
+            payload: Bytes::from_static(b"21.5"),
+            properties: Default::default(),
+        }),
+        Packet::Publish(Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            packet_id: Some(pid),
+            topic: ByteString::from_static("devices/device-1/telemetry"),
+            payload: Bytes::from(vec![b'x'; 1024]),
+            properties: Default::default(),
+        }),
+        Packet::PublishAck(PublishAck {
+            packet_id: pid,
+            reason_code: PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        }),
+        Packet::PingRequest,
+    ]
+}
+
+fn encode_all<C: Encoder>(codec: &C, packets: &[C::Item]) -> BytesMut
+where
+    C::Item: Clone,
+    C::Error: std::fmt::Debug,
+{
+    let mut buf = BytesMut::with_capacity(4096);
+    for pkt in packets {
+        codec.encode(pkt.clone(), &mut buf).unwrap();
+    }
+    buf
+}
+
+fn decode_all<C: Decoder>(codec: &C, mut buf: BytesMut) -> usize
+where
+    C::Error: std::fmt::Debug,
+{
+    let mut count = 0;
+    while codec.decode(&mut buf).unwrap().is_some() {
+        count += 1;
+    }
+    count
+}
+
+fn bench_v3(c: &mut Criterion) {
+    let codec = v3::codec::Codec::new();
+    let packets = v3_packets();
+    let encoded = encode_all(&codec, &packets);
+
+    let mut group = c.benchmark_group("v3 codec");
+    group.throughput(Throughput::Bytes(encoded.len() as u64));
+    group.bench_function("encode", |b| b.iter(|| encode_all(&codec, &packets)));
+    group.bench_function("decode", |b| {
+        b.iter_batched(|| encoded.clone(), |buf| decode_all(&codec, buf), BatchSize::SmallInput)
+    });
+    group.finish();
+}
+
+fn bench_v3_small_publish(c: &mut Criterion) {
+    use v3::codec::{Packet, Publish, QoS};
+
+    let codec = v3::codec::Codec::new();
+    let packets: Vec<_> = (0..100)
+        .map(|_| {
+            Packet::Publish(Publish {
+                dup: false,
+                retain: false,
+                qos: QoS::AtMostOnce,
+                topic: ByteString::from_static("sensors/room-1/temperature"),
+                packet_id: None,
+                payload: Bytes::from_static(b"21.5"),
+            })
+        })
+        .collect();
+    let encoded = encode_all(&codec, &packets);
+
+    let mut group = c.benchmark_group("v3 codec");
+    group.throughput(Throughput::Bytes(encoded.len() as u64));
+    group.bench_function("encode small publish", |b| b.iter(|| encode_all(&codec, &packets)));
+    group.finish();
+}
+
+fn bench_v5(c: &mut Criterion) {
+    let codec = v5::codec::Codec::new();
+    let packets = v5_packets();
+    let encoded = encode_all(&codec, &packets);
+
+    let mut group = c.benchmark_group("v5 codec");
+    group.throughput(Throughput::Bytes(encoded.len() as u64));
+    group.bench_function("encode", |b| b.iter(|| encode_all(&codec, &packets)));
+    group.bench_function("decode", |b| {
+        b.iter_batched(|| encoded.clone(), |buf| decode_all(&codec, buf), BatchSize::SmallInput)
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_v3, bench_v3_small_publish, bench_v5);
+criterion_main!(benches);
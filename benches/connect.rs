@@ -0,0 +1,85 @@
+//! Connection setup over in-memory transport
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use ntex::service::{Service, ServiceFactory};
+use ntex_mqtt::{testing, v3, MqttServer};
+
+const BATCH: u64 = 100;
+
+fn bench_connect(c: &mut Criterion) {
+    let mut sys = ntex::rt::System::new("bench");
+    let v3 = sys.block_on(async {
+        v3::MqttServer::new(|hnd: v3::Handshake<testing::Io>| async move {
+            Ok::<_, ()>(hnd.ack((), false))
+        })
+        .publish(|_: v3::Publish| async { Ok::<_, ()>(()) })
+        .finish()
+        .new_service(())
+        .await
+        .ok()
+        .unwrap()
+    });
+    let selector = sys.block_on(async {
+        MqttServer::new()
+            .v3(v3::MqttServer::new(|hnd: v3::Handshake<testing::Io>| async move {
+                Ok::<_, ()>(hnd.ack((), false))
+            })
+            .publish(|_: v3::Publish| async { Ok::<_, ()>(()) }))
+            .new_service(())
+            .await
+            .ok()
+            .unwrap()
+    });
+
+    let mut group = c.benchmark_group("connect");
+    group.throughput(Throughput::Elements(BATCH));
+    group.bench_function("v3", |b| {
+        b.iter_custom(|iters| {
+            sys.block_on(async {
+                let start = Instant::now();
+                for _ in 0..iters * BATCH {
+                    let (client, server) = testing::duplex();
+                    let fut = v3.call(server);
+                    ntex::rt::spawn(async move {
+                        let _ = fut.await;
+                    });
+                    let client = v3::client::MqttConnector::new("localhost")
+                        .client_id("bench")
+                        .connect_io(client)
+                        .await
+                        .unwrap();
+                    client.sink().close();
+                }
+                start.elapsed()
+            })
+        })
+    });
+    group.bench_function("v3 selector", |b| {
+        b.iter_custom(|iters| {
+            sys.block_on(async {
+                let start = Instant::now();
+                for _ in 0..iters * BATCH {
+                    let (client, server) = testing::duplex();
+                    let fut = selector.call(server);
+                    ntex::rt::spawn(async move {
+                        let _ = fut.await;
+                    });
+                    let client = v3::client::MqttConnector::new("localhost")
+                        .client_id("bench")
+                        .connect_io(client)
+                        .await
+                        .unwrap();
+                    client.sink().close();
+                }
+                start.elapsed()
+            })
+        })
+    });
+    group.finish();
+
+    sys.block_on(async { ntex::rt::time::sleep(Duration::from_millis(10)).await });
+}
+
+criterion_group!(benches, bench_connect);
+criterion_main!(benches);
@@ -0,0 +1,58 @@
+//! Topic matching at various route counts
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ntex::router::{Path, Router};
+use ntex::util::ByteString;
+use ntex_mqtt::TopicFilter;
+
+const ROUTES: &[usize] = &[10, 100, 1000];
+
+fn bench_router(c: &mut Criterion) {
+    let mut group = c.benchmark_group("router");
+
+    for count in ROUTES {
+        let mut builder = Router::<usize>::build();
+        for idx in 0..*count {
+            builder.path(format!("tenant-{}/devices/{{id}}/telemetry", idx), idx);
+        }
+        let router = builder.finish();
+        let topic =
+            ByteString::from(format!("tenant-{}/devices/device-1/telemetry", count - 1));
+
+        group.bench_with_input(BenchmarkId::new("last match", count), &topic, |b, topic| {
+            b.iter(|| {
+                let mut path = Path::new(topic.clone());
+                router.recognize(&mut path).map(|(idx, _)| *idx)
+            })
+        });
+        group.bench_with_input(
+            BenchmarkId::new("no match", count),
+            &ByteString::from_static("unknown/devices/device-1/telemetry"),
+            |b, topic| {
+                b.iter(|| {
+                    let mut path = Path::new(topic.clone());
+                    router.recognize(&mut path).map(|(idx, _)| *idx)
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_filters(c: &mut Criterion) {
+    let mut group = c.benchmark_group("topic filters");
+
+    for count in ROUTES {
+        let filters: Vec<_> = (0..*count)
+            .map(|idx| TopicFilter::new(format!("tenant-{}/devices/+/telemetry", idx)).unwrap())
+            .collect();
+        let topic = format!("tenant-{}/devices/device-1/telemetry", count - 1);
+
+        group.bench_with_input(BenchmarkId::new("matches", count), &topic, |b, topic| {
+            b.iter(|| filters.iter().filter(|f| f.matches_str(topic)).count())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_router, bench_filters);
+criterion_main!(benches);
@@ -0,0 +1,120 @@
+//! QoS 1 publish round-trips over in-memory transport
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use ntex::service::{Service, ServiceFactory};
+use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::{testing, v3, v5};
+
+const BATCH: u64 = 100;
+
+#[derive(Debug)]
+struct BenchError;
+
+impl From<()> for BenchError {
+    fn from(_: ()) -> Self {
+        BenchError
+    }
+}
+
+impl TryFrom<BenchError> for v5::PublishAck {
+    type Error = BenchError;
+
+    fn try_from(err: BenchError) -> Result<Self, Self::Error> {
+        Err(err)
+    }
+}
+
+async fn v3_sink() -> v3::client::MqttSink {
+    let (client, server) = testing::duplex();
+    let srv = v3::MqttServer::new(|hnd: v3::Handshake<testing::Io>| async move {
+        Ok::<_, ()>(hnd.ack((), false))
+    })
+    .publish(|_: v3::Publish| async { Ok::<_, ()>(()) })
+    .finish()
+    .new_service(())
+    .await
+    .ok()
+    .unwrap();
+    ntex::rt::spawn(async move { srv.call(server).await });
+
+    let client = v3::client::MqttConnector::new("localhost")
+        .client_id("bench")
+        .connect_io(client)
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink
+}
+
+async fn v5_sink() -> v5::client::MqttSink {
+    let (client, server) = testing::duplex();
+    let srv = v5::MqttServer::new(|hnd: v5::Handshake<testing::Io>| async move {
+        Ok::<_, BenchError>(hnd.ack(()))
+    })
+    .publish(|p: v5::Publish| async move { Ok::<_, BenchError>(p.ack()) })
+    .finish()
+    .new_service(())
+    .await
+    .ok()
+    .unwrap();
+    ntex::rt::spawn(async move { srv.call(server).await });
+
+    let client = v5::client::MqttConnector::new("localhost")
+        .client_id("bench")
+        .connect_io(client)
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink
+}
+
+fn bench_sink(c: &mut Criterion) {
+    let mut sys = ntex::rt::System::new("bench");
+    let v3 = sys.block_on(v3_sink());
+    let v5 = sys.block_on(v5_sink());
+    let topic = ByteString::from_static("devices/device-1/telemetry");
+    let payload = Bytes::from(vec![b'x'; 128]);
+
+    let mut group = c.benchmark_group("sink qos1");
+    group.throughput(Throughput::Elements(BATCH));
+    group.bench_function("v3", |b| {
+        b.iter_custom(|iters| {
+            sys.block_on(async {
+                let start = Instant::now();
+                for _ in 0..iters * BATCH {
+                    v3.publish(topic.clone(), payload.clone())
+                        .send_at_least_once()
+                        .await
+                        .unwrap();
+                }
+                start.elapsed()
+            })
+        })
+    });
+    group.bench_function("v5", |b| {
+        b.iter_custom(|iters| {
+            sys.block_on(async {
+                let start = Instant::now();
+                for _ in 0..iters * BATCH {
+                    v5.publish(topic.clone(), payload.clone())
+                        .send_at_least_once()
+                        .await
+                        .unwrap();
+                }
+                start.elapsed()
+            })
+        })
+    });
+    group.finish();
+
+    v3.close();
+    v5.close();
+    sys.block_on(async { ntex::rt::time::sleep(Duration::from_millis(10)).await });
+}
+
+criterion_group!(benches, bench_sink);
+criterion_main!(benches);
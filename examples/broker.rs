@@ -0,0 +1,190 @@
+//! Minimal in-memory mqtt v3.1.1 broker
+//!
+//! Broker keeps registry of connected clients, retained messages and
+//! subscriptions and routes incoming publishes to subscribed clients.
+//! All messages are delivered with QoS 0, sessions are not persisted.
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use futures::future::ok;
+use ntex::util::{ByteString, Bytes};
+use ntex::{fn_factory_with_config, fn_service};
+use ntex_mqtt::v3::{self, ControlMessage, ControlResult, MqttSink, QoS, Topic};
+
+#[derive(Debug)]
+struct ServerError;
+
+impl From<()> for ServerError {
+    fn from(_: ()) -> Self {
+        ServerError
+    }
+}
+
+#[derive(Default)]
+struct Broker {
+    /// connected clients, client id -> (connection id, sink)
+    sessions: HashMap<ByteString, (usize, MqttSink)>,
+    /// retained messages, topic -> payload
+    retained: HashMap<ByteString, Bytes>,
+    /// subscriptions, (topic filter, parsed filter, client id)
+    subscriptions: Vec<(ByteString, Topic, ByteString)>,
+    next_conn: usize,
+}
+
+impl Broker {
+    fn route(&self, topic: &ByteString, payload: &Bytes) {
+        for (_, filter, id) in &self.subscriptions {
+            if filter.matches_str(topic) {
+                if let Some((_, sink)) = self.sessions.get(id) {
+                    let _ = sink.publish(topic.clone(), payload.clone()).send_at_most_once();
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ClientSession {
+    id: ByteString,
+    conn: usize,
+    broker: Rc<RefCell<Broker>>,
+}
+
+async fn handshake<Io>(
+    handshake: v3::Handshake<Io>,
+    broker: Rc<RefCell<Broker>>,
+) -> Result<v3::HandshakeAck<Io, ClientSession>, ServerError> {
+    log::info!("new connection: {:?}", handshake);
+
+    let pkt = handshake.packet();
+    if pkt.client_id.is_empty() {
+        return Ok(handshake.identifier_rejected());
+    }
+    let id = pkt.client_id.clone();
+    let clean_session = pkt.clean_session;
+
+    let conn = {
+        let mut b = broker.borrow_mut();
+        b.next_conn += 1;
+        let conn = b.next_conn;
+
+        // session takeover, drop previous connection with same client id
+        if let Some((_, sink)) = b.sessions.insert(id.clone(), (conn, handshake.sink())) {
+            sink.close();
+        }
+        if clean_session {
+            b.subscriptions.retain(|(_, _, client)| *client != id);
+        }
+        conn
+    };
+
+    Ok(handshake.ack(ClientSession { id, conn, broker }, false))
+}
+
+async fn publish(
+    session: v3::Session<ClientSession>,
+    publish: v3::Publish,
+) -> Result<(), ServerError> {
+    let topic = publish.packet().topic.clone();
+    let payload = publish.payload().clone();
+    log::trace!("{}: publish to {:?}", session.id, topic);
+
+    let mut broker = session.broker.borrow_mut();
+    if publish.retain() {
+        if payload.is_empty() {
+            broker.retained.remove(&topic);
+        } else {
+            broker.retained.insert(topic.clone(), payload.clone());
+        }
+    }
+    broker.route(&topic, &payload);
+    Ok(())
+}
+
+async fn control(
+    session: v3::Session<ClientSession>,
+    msg: ControlMessage,
+) -> Result<ControlResult, ServerError> {
+    match msg {
+        ControlMessage::Subscribe(mut s) => {
+            let mut retained = Vec::new();
+            {
+                let mut broker = session.broker.borrow_mut();
+                for mut sub in s.iter_mut() {
+                    let filter = sub.topic().clone();
+                    let parsed = match filter.parse::<Topic>() {
+                        Ok(parsed) => parsed,
+                        Err(_) => {
+                            sub.fail();
+                            continue;
+                        }
+                    };
+                    for (topic, payload) in &broker.retained {
+                        if parsed.matches_str(topic) {
+                            retained.push((topic.clone(), payload.clone()));
+                        }
+                    }
+                    let id = &session.id;
+                    if !broker.subscriptions.iter().any(|(f, _, c)| *f == filter && c == id) {
+                        broker.subscriptions.push((filter, parsed, id.clone()));
+                    }
+                    sub.confirm(QoS::AtMostOnce);
+                }
+            }
+
+            // retained messages must follow subscribe ack
+            if !retained.is_empty() {
+                let sink = session.sink().clone();
+                ntex::rt::spawn(async move {
+                    for (topic, payload) in retained {
+                        let _ = sink.publish(topic, payload).retain().send_at_most_once();
+                    }
+                });
+            }
+            Ok(s.ack())
+        }
+        ControlMessage::Unsubscribe(s) => {
+            let mut broker = session.broker.borrow_mut();
+            for filter in s.iter() {
+                broker.subscriptions.retain(|(f, _, c)| *c != session.id || f != filter);
+            }
+            Ok(s.ack())
+        }
+        ControlMessage::Closed(c) => {
+            log::info!("{}: connection closed", session.id);
+            let mut broker = session.broker.borrow_mut();
+            if broker.sessions.get(&session.id).map(|(conn, _)| *conn) == Some(session.conn) {
+                broker.sessions.remove(&session.id);
+            }
+            Ok(c.ack())
+        }
+        ControlMessage::Ping(p) => Ok(p.ack()),
+        ControlMessage::Disconnect(d) => Ok(d.ack()),
+    }
+}
+
+#[ntex::main]
+async fn main() -> std::io::Result<()> {
+    std::env::set_var("RUST_LOG", "ntex=info,ntex_mqtt=info,broker=trace");
+    env_logger::init();
+
+    ntex::server::Server::build()
+        .bind("mqtt", "127.0.0.1:1883", || {
+            // state is per worker, single worker shares it between all clients
+            let broker = Rc::new(RefCell::new(Broker::default()));
+
+            ntex_mqtt::MqttServer::new().v3(v3::MqttServer::new(fn_service(move |h| {
+                handshake(h, broker.clone())
+            }))
+            .publish(fn_factory_with_config(|session: v3::Session<ClientSession>| {
+                ok::<_, ServerError>(fn_service(move |req| publish(session.clone(), req)))
+            }))
+            .control(fn_factory_with_config(
+                |session: v3::Session<ClientSession>| {
+                    ok::<_, ServerError>(fn_service(move |req| control(session.clone(), req)))
+                },
+            )))
+        })?
+        .workers(1)
+        .run()
+        .await
+}
@@ -32,7 +32,7 @@ async fn main() -> std::io::Result<()> {
     // connect to server
     let client = v5::client::MqttConnector::new("127.0.0.1:1883")
         .client_id("user")
-        .keep_alive(1)
+        .keep_alive(Duration::from_secs(1))
         .connect()
         .await
         .unwrap();
@@ -0,0 +1,58 @@
+//! Automatic acknowledgment of QoS 1 publishes
+use std::{cell::Cell, rc::Rc};
+
+/// Acknowledgment state of auto-acked publish
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum AckState {
+    /// publish is waiting for publish service
+    Pending,
+    /// publish service future is created, dispatcher sends ack
+    Auto,
+    /// ack is sent after publish service completes
+    Manual,
+    /// ack is sent
+    Sent,
+}
+
+/// Ack state shared by dispatcher and publish, router could switch
+/// publish to manual ack
+pub(crate) type AckSlot = Rc<Cell<AckState>>;
+
+pub(crate) fn slot() -> AckSlot {
+    Rc::new(Cell::new(AckState::Pending))
+}
+
+pub(crate) trait WithAutoAck {
+    /// Ack slot of auto-acked publish
+    fn ack_slot(&self) -> Option<AckSlot>;
+}
+
+impl WithAutoAck for crate::v3::Publish {
+    fn ack_slot(&self) -> Option<AckSlot> {
+        self.auto_ack.clone()
+    }
+}
+
+impl WithAutoAck for crate::v5::Publish {
+    fn ack_slot(&self) -> Option<AckSlot> {
+        self.auto_ack.clone()
+    }
+}
+
+/// Publish service is called, ack could be sent by dispatcher
+pub(crate) fn dispatched(ack: &Option<AckSlot>) {
+    if let Some(ref slot) = ack {
+        if slot.get() == AckState::Pending {
+            slot.set(AckState::Auto);
+        }
+    }
+}
+
+/// Switch publish to manual ack, sent ack could not be revoked
+pub(crate) fn set_manual(ack: &Option<AckSlot>) {
+    if let Some(ref slot) = ack {
+        if slot.get() != AckState::Sent {
+            slot.set(AckState::Manual);
+        }
+    }
+}
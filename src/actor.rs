@@ -0,0 +1,59 @@
+//! Per-connection actors
+use std::fmt;
+
+use futures_channel::mpsc;
+
+/// Address of connection actor
+///
+/// Actor is started with `Session::start_actor()`, messages sent to the
+/// address are processed by actor one at a time on connection's worker.
+/// Address is `Send` if message is `Send`, so it could be used from
+/// other threads.
+pub struct Addr<M>(mpsc::UnboundedSender<M>);
+
+impl<M> Addr<M> {
+    pub(crate) fn new(tx: mpsc::UnboundedSender<M>) -> Self {
+        Addr(tx)
+    }
+
+    /// Send message to actor
+    ///
+    /// Message is returned back if actor is stopped.
+    pub fn send(&self, msg: M) -> Result<(), M> {
+        self.0.unbounded_send(msg).map_err(|e| e.into_inner())
+    }
+
+    /// Check if actor is stopped
+    pub fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+}
+
+impl<M> Clone for Addr<M> {
+    fn clone(&self) -> Self {
+        Addr(self.0.clone())
+    }
+}
+
+impl<M> fmt::Debug for Addr<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Addr").field("closed", &self.is_closed()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_addr_send() {
+        fn is_send<T: Send + Sync>() {}
+        is_send::<Addr<String>>();
+
+        let (tx, rx) = mpsc::unbounded();
+        let addr = Addr::new(tx);
+        assert!(!addr.is_closed());
+        std::thread::spawn(move || addr.send("msg".to_string()).unwrap()).join().unwrap();
+        drop(rx);
+    }
+}
@@ -0,0 +1,210 @@
+//! Routing of connections by TLS ALPN protocol
+use std::{future::Future, pin::Pin, rc::Rc, task::Context, task::Poll};
+
+use ntex::service::{boxed, IntoServiceFactory, Service, ServiceFactory};
+
+use crate::error::MqttError;
+
+type ServerFactory<Io, Err, InitErr> =
+    boxed::BoxServiceFactory<(), Io, (), MqttError<Err>, InitErr>;
+
+type Server<Io, Err> = boxed::BoxService<Io, (), MqttError<Err>>;
+
+/// Protocol name of raw mqtt connections
+pub const ALPN_MQTT: &[u8] = b"mqtt";
+
+/// Protocol name of http connections, i.e. mqtt over websocket
+pub const ALPN_HTTP11: &[u8] = b"http/1.1";
+
+/// Transport router
+///
+/// Router selects transport by protocol negotiated with TLS ALPN extension,
+/// so raw mqtt and mqtt over websocket could be served on one TLS port.
+/// Raw mqtt connections (`mqtt` protocol) are handled by mqtt server, http
+/// connections (`http/1.1` protocol) are handled by websocket adapter
+/// service, it is responsible for websocket handshake and for passing
+/// websocket stream to mqtt server. Negotiated protocol is extracted from
+/// connection io after TLS handshake, i.e. with
+/// `SslRef::selected_alpn_protocol()`.
+///
+/// TLS acceptor must be configured to negotiate `mqtt` and `http/1.1`
+/// protocols. Connections without negotiated protocol are handled by
+/// mqtt server.
+///
+/// ```rust,no_run
+/// # use ntex::rt::net::TcpStream;
+/// # use ntex_mqtt::{v3, AlpnRouter, MqttError};
+/// # use tokio_openssl::SslStream;
+/// # async fn handshake(
+/// #     hnd: v3::Handshake<SslStream<TcpStream>>,
+/// # ) -> Result<v3::HandshakeAck<SslStream<TcpStream>, ()>, ()> {
+/// #     Ok(hnd.ack((), false))
+/// # }
+/// # async fn publish(_: v3::Publish) -> Result<(), ()> {
+/// #     Ok(())
+/// # }
+/// # async fn websocket(io: SslStream<TcpStream>) -> Result<(), MqttError<()>> {
+/// #     Ok(())
+/// # }
+/// let router = AlpnRouter::new(
+///     |io: &SslStream<TcpStream>| io.ssl().selected_alpn_protocol().map(|p| p.to_vec()),
+///     v3::MqttServer::new(handshake).publish(publish).finish(),
+/// )
+/// .websocket(ntex::fn_service(websocket));
+/// ```
+pub struct AlpnRouter<Io, Err, InitErr> {
+    protocol: Rc<dyn Fn(&Io) -> Option<Vec<u8>>>,
+    mqtt: ServerFactory<Io, Err, InitErr>,
+    routes: Vec<(Vec<u8>, ServerFactory<Io, Err, InitErr>)>,
+}
+
+impl<Io, Err, InitErr> AlpnRouter<Io, Err, InitErr>
+where
+    Io: 'static,
+    Err: 'static,
+    InitErr: 'static,
+{
+    /// Create router with negotiated protocol extractor and mqtt server
+    pub fn new<P, F, S>(protocol: P, mqtt: F) -> Self
+    where
+        P: Fn(&Io) -> Option<Vec<u8>> + 'static,
+        F: IntoServiceFactory<S>,
+        S: ServiceFactory<
+                Config = (),
+                Request = Io,
+                Response = (),
+                Error = MqttError<Err>,
+                InitError = InitErr,
+            > + 'static,
+    {
+        AlpnRouter {
+            protocol: Rc::new(protocol),
+            mqtt: boxed::factory(mqtt.into_factory()),
+            routes: Vec::new(),
+        }
+    }
+
+    /// Set websocket adapter for `http/1.1` connections
+    pub fn websocket<F, S>(self, adapter: F) -> Self
+    where
+        F: IntoServiceFactory<S>,
+        S: ServiceFactory<
+                Config = (),
+                Request = Io,
+                Response = (),
+                Error = MqttError<Err>,
+                InitError = InitErr,
+            > + 'static,
+    {
+        self.protocol(ALPN_HTTP11, adapter)
+    }
+
+    /// Add service for protocol
+    pub fn protocol<F, S>(mut self, protocol: &[u8], service: F) -> Self
+    where
+        F: IntoServiceFactory<S>,
+        S: ServiceFactory<
+                Config = (),
+                Request = Io,
+                Response = (),
+                Error = MqttError<Err>,
+                InitError = InitErr,
+            > + 'static,
+    {
+        self.routes.retain(|(p, _)| p != protocol);
+        self.routes.push((protocol.to_vec(), boxed::factory(service.into_factory())));
+        self
+    }
+}
+
+impl<Io, Err, InitErr> ServiceFactory for AlpnRouter<Io, Err, InitErr>
+where
+    Io: 'static,
+    Err: 'static,
+    InitErr: 'static,
+{
+    type Config = ();
+    type Request = Io;
+    type Response = ();
+    type Error = MqttError<Err>;
+    type InitError = InitErr;
+    type Service = AlpnRouterService<Io, Err>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Service, Self::InitError>>>>;
+
+    fn new_service(&self, _: ()) -> Self::Future {
+        let mqtt = self.mqtt.new_service(());
+        let futs: Vec<_> =
+            self.routes.iter().map(|(p, srv)| (p.clone(), srv.new_service(()))).collect();
+        let protocol = self.protocol.clone();
+
+        Box::pin(async move {
+            let mqtt = mqtt.await?;
+            let mut routes = Vec::new();
+            for (p, fut) in futs {
+                routes.push((p, fut.await?));
+            }
+            Ok(AlpnRouterService { protocol, mqtt, routes })
+        })
+    }
+}
+
+/// Transport router service
+pub struct AlpnRouterService<Io, Err> {
+    protocol: Rc<dyn Fn(&Io) -> Option<Vec<u8>>>,
+    mqtt: Server<Io, Err>,
+    routes: Vec<(Vec<u8>, Server<Io, Err>)>,
+}
+
+impl<Io, Err> Service for AlpnRouterService<Io, Err>
+where
+    Io: 'static,
+    Err: 'static,
+{
+    type Request = Io;
+    type Response = ();
+    type Error = MqttError<Err>;
+    type Future = Pin<Box<dyn Future<Output = Result<(), MqttError<Err>>>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let mut ready = self.mqtt.poll_ready(cx)?.is_ready();
+        for (_, srv) in self.routes.iter() {
+            ready &= srv.poll_ready(cx)?.is_ready();
+        }
+        if ready {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        let mut ready = self.mqtt.poll_shutdown(cx, is_error).is_ready();
+        for (_, srv) in self.routes.iter() {
+            ready &= srv.poll_shutdown(cx, is_error).is_ready();
+        }
+        if ready {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn call(&self, io: Io) -> Self::Future {
+        let protocol = (*self.protocol)(&io);
+
+        match protocol {
+            None => self.mqtt.call(io),
+            Some(ref p) if p == ALPN_MQTT => self.mqtt.call(io),
+            Some(p) => {
+                if let Some((_, srv)) = self.routes.iter().find(|(name, _)| *name == p) {
+                    srv.call(io)
+                } else {
+                    log::trace!("Unsupported alpn protocol {:?}", p);
+                    Box::pin(async { Err(MqttError::ServerError("Unsupported alpn protocol")) })
+                }
+            }
+        }
+    }
+}
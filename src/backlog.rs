@@ -0,0 +1,120 @@
+//! Outbound queue watermark for slow consumers
+use std::{cell::Cell, time::Duration, time::Instant};
+
+use crate::clock;
+
+/// Action for connection which outbound queue is over watermark
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Log warning, once per backlog
+    Warn,
+    /// Drop QoS 0 publishes until outbound queue is drained
+    DropQos0,
+    /// Close connection, mqtt v5 connection is closed with `QuotaExceeded` reason
+    Disconnect,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Watermark {
+    /// max size of outbound queue in bytes, `0` means unlimited
+    pub(crate) max_size: usize,
+    /// max age of outbound queue, zero means unlimited
+    pub(crate) max_age: Duration,
+    pub(crate) policy: SlowConsumerPolicy,
+}
+
+/// Outbound queue state of a connection
+pub(crate) struct Backlog {
+    watermark: Cell<Option<Watermark>>,
+    since: Cell<Instant>,
+    warned: Cell<bool>,
+}
+
+impl Backlog {
+    pub(crate) fn new() -> Self {
+        Backlog {
+            watermark: Cell::new(None),
+            since: Cell::new(clock::now()),
+            warned: Cell::new(false),
+        }
+    }
+
+    pub(crate) fn set_watermark(&self, watermark: Option<Watermark>) {
+        self.watermark.set(watermark);
+    }
+
+    /// Packet is written to outbound queue of `size` bytes
+    pub(crate) fn on_write(&self, size: usize) {
+        // queue is drained, new backlog starts
+        if size == 0 {
+            self.since.set(clock::now());
+            self.warned.set(false);
+        }
+    }
+
+    /// Check size of outbound queue.
+    ///
+    /// Returns policy if watermark is exceeded. `Warn` policy is returned
+    /// once per backlog.
+    pub(crate) fn check<F>(&self, size: F) -> Option<SlowConsumerPolicy>
+    where
+        F: FnOnce() -> usize,
+    {
+        let wm = self.watermark.get()?;
+        let size = size();
+        if size == 0 {
+            return None;
+        }
+
+        let exceeded = (wm.max_size != 0 && size > wm.max_size)
+            || (wm.max_age != Duration::from_secs(0)
+                && clock::elapsed(self.since.get()) >= wm.max_age);
+        if !exceeded {
+            None
+        } else if wm.policy == SlowConsumerPolicy::Warn {
+            if self.warned.replace(true) {
+                None
+            } else {
+                Some(wm.policy)
+            }
+        } else {
+            Some(wm.policy)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backlog() {
+        let backlog = Backlog::new();
+        assert_eq!(backlog.check(|| 1024), None);
+
+        backlog.set_watermark(Some(Watermark {
+            max_size: 16,
+            max_age: Duration::from_secs(0),
+            policy: SlowConsumerPolicy::Warn,
+        }));
+        backlog.on_write(0);
+        assert_eq!(backlog.check(|| 16), None);
+        assert_eq!(backlog.check(|| 17), Some(SlowConsumerPolicy::Warn));
+        assert_eq!(backlog.check(|| 32), None);
+
+        // drained queue resets warning
+        backlog.on_write(0);
+        assert_eq!(backlog.check(|| 17), Some(SlowConsumerPolicy::Warn));
+
+        backlog.set_watermark(Some(Watermark {
+            max_size: 0,
+            max_age: Duration::from_millis(1),
+            policy: SlowConsumerPolicy::DropQos0,
+        }));
+        backlog.on_write(0);
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(backlog.check(|| 0), None);
+        assert_eq!(backlog.check(|| 1), Some(SlowConsumerPolicy::DropQos0));
+        assert_eq!(backlog.check(|| 1), Some(SlowConsumerPolicy::DropQos0));
+    }
+}
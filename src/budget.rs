@@ -0,0 +1,215 @@
+//! Inbound packet decode statistics and decode-time budget
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{fmt, sync::Arc, time::Duration, time::Instant};
+
+/// Upper bounds of packet size histogram buckets, last bucket is unbounded
+const BUCKETS: [u32; 8] = [16, 64, 256, 1024, 4096, 16384, 65536, u32::MAX];
+
+/// Shared inbound packet decode statistics
+///
+/// Statistics could be shared by several servers and updated from any
+/// server worker. Packet size is the remaining length of the packet,
+/// fixed header is not included.
+#[derive(Clone)]
+pub struct DecodeStats(Arc<StatsInner>);
+
+struct StatsInner {
+    sizes: [AtomicU64; 8],
+    decode_nanos: AtomicU64,
+    max_decode_nanos: AtomicU64,
+    slow_packets: AtomicU64,
+    disconnects: AtomicU64,
+}
+
+impl Default for DecodeStats {
+    fn default() -> Self {
+        DecodeStats::new()
+    }
+}
+
+impl DecodeStats {
+    /// Create new statistics
+    pub fn new() -> Self {
+        DecodeStats(Arc::new(StatsInner {
+            sizes: Default::default(),
+            decode_nanos: AtomicU64::new(0),
+            max_decode_nanos: AtomicU64::new(0),
+            slow_packets: AtomicU64::new(0),
+            disconnects: AtomicU64::new(0),
+        }))
+    }
+
+    /// Number of decoded packets
+    pub fn packets(&self) -> u64 {
+        self.0.sizes.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Packet size histogram
+    ///
+    /// Returns upper bound (inclusive) of each bucket in bytes with number
+    /// of packets in the bucket, last bucket's bound is `u32::MAX`.
+    pub fn size_histogram(&self) -> Vec<(u32, u64)> {
+        BUCKETS
+            .iter()
+            .zip(self.0.sizes.iter())
+            .map(|(bound, c)| (*bound, c.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Total time spent decoding packets
+    pub fn decode_time(&self) -> Duration {
+        Duration::from_nanos(self.0.decode_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Longest time spent decoding single packet
+    pub fn max_decode_time(&self) -> Duration {
+        Duration::from_nanos(self.0.max_decode_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Number of packets that exceeded decode budget
+    pub fn slow_packets(&self) -> u64 {
+        self.0.slow_packets.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections closed because of decode budget violations
+    pub fn disconnects(&self) -> u64 {
+        self.0.disconnects.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, size: u32, elapsed: Duration) {
+        let idx = BUCKETS.iter().position(|b| size <= *b).unwrap_or(BUCKETS.len() - 1);
+        self.0.sizes[idx].fetch_add(1, Ordering::Relaxed);
+
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.0.decode_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.0.max_decode_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Debug for DecodeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecodeStats")
+            .field("packets", &self.packets())
+            .field("decode_time", &self.decode_time())
+            .field("slow_packets", &self.slow_packets())
+            .field("disconnects", &self.disconnects())
+            .finish()
+    }
+}
+
+/// Per-connection decode guard
+///
+/// Peer is disconnected after `max_violations` consecutive packets
+/// that took longer than budget to decode.
+#[derive(Debug, Default)]
+pub(crate) struct DecodeGuard {
+    budget: Cell<Duration>,
+    max_violations: Cell<u32>,
+    violations: Cell<u32>,
+    stats: RefCell<Option<DecodeStats>>,
+}
+
+impl DecodeGuard {
+    pub(crate) fn set_budget(&self, budget: Duration, max_violations: u32) {
+        self.budget.set(budget);
+        self.max_violations.set(max_violations);
+        self.violations.set(0);
+    }
+
+    pub(crate) fn set_stats(&self, stats: Option<DecodeStats>) {
+        *self.stats.borrow_mut() = stats;
+    }
+
+    /// Start measurement, returns `None` if guard is disabled
+    pub(crate) fn start(&self) -> Option<Instant> {
+        if self.budget.get() != Duration::ZERO || self.stats.borrow().is_some() {
+            Some(Instant::now())
+        } else {
+            None
+        }
+    }
+
+    /// Record decoded packet, returns `false` if peer exceeded decode budget
+    pub(crate) fn finish(&self, started: Option<Instant>, size: u32) -> bool {
+        let started = if let Some(started) = started {
+            started
+        } else {
+            return true;
+        };
+        let elapsed = started.elapsed();
+        let stats = self.stats.borrow();
+        if let Some(ref stats) = *stats {
+            stats.record(size, elapsed);
+        }
+
+        let budget = self.budget.get();
+        if budget == Duration::ZERO || elapsed <= budget {
+            self.violations.set(0);
+            true
+        } else {
+            let violations = self.violations.get() + 1;
+            self.violations.set(violations);
+            if let Some(ref stats) = *stats {
+                stats.0.slow_packets.fetch_add(1, Ordering::Relaxed);
+            }
+            if violations >= self.max_violations.get() {
+                log::warn!(
+                    "Decode budget {:?} exceeded by {} consecutive packets",
+                    budget,
+                    violations
+                );
+                if let Some(ref stats) = *stats {
+                    stats.0.disconnects.fetch_add(1, Ordering::Relaxed);
+                }
+                false
+            } else {
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let stats = DecodeStats::new();
+        let guard = DecodeGuard::default();
+        guard.set_stats(Some(stats.clone()));
+
+        assert!(guard.finish(guard.start(), 10));
+        assert!(guard.finish(guard.start(), 16));
+        assert!(guard.finish(guard.start(), 17));
+        assert!(guard.finish(guard.start(), 100_000));
+
+        assert_eq!(stats.packets(), 4);
+        let hist = stats.size_histogram();
+        assert_eq!(hist[0], (16, 2));
+        assert_eq!(hist[1], (64, 1));
+        assert_eq!(hist[7], (u32::MAX, 1));
+        assert_eq!(stats.slow_packets(), 0);
+    }
+
+    #[test]
+    fn test_budget() {
+        let stats = DecodeStats::new();
+        let guard = DecodeGuard::default();
+        assert!(guard.start().is_none());
+
+        guard.set_stats(Some(stats.clone()));
+        guard.set_budget(Duration::from_millis(1), 2);
+
+        let slow = Instant::now() - Duration::from_millis(5);
+        assert!(guard.finish(Some(slow), 10));
+        // fast packet resets violations
+        assert!(guard.finish(guard.start(), 10));
+        assert!(guard.finish(Some(slow), 10));
+        assert!(!guard.finish(Some(slow), 10));
+
+        assert_eq!(stats.slow_packets(), 3);
+        assert_eq!(stats.disconnects(), 1);
+    }
+}
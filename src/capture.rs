@@ -0,0 +1,217 @@
+//! Traffic capture
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+use std::{fmt, fs, path::Path, time::SystemTime, time::UNIX_EPOCH};
+
+use derive_more::{Display, From};
+use ntex_bytes::{Bytes, BytesMut};
+
+use crate::utils::write_variable_length;
+
+/// Direction of captured packet
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// Packet is received from peer
+    Inbound,
+    /// Packet is sent to peer
+    Outbound,
+}
+
+impl Direction {
+    /// Short name of direction, `in` or `out`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Inbound => "in",
+            Direction::Outbound => "out",
+        }
+    }
+}
+
+/// Packet recorder
+///
+/// Recorder writes encoded packets of a connection, one record per line.
+/// Each record is json object with timestamp in microseconds since unix epoch,
+/// protocol version, direction and hex encoded packet, including fixed header:
+///
+/// ```text
+/// {"ts":1634480000123456,"v":3,"dir":"in","data":"c000"}
+/// ```
+///
+/// Recording is enabled for a connection with `MqttSink::set_recorder()`,
+/// recorder could be shared by several connections. Write errors are logged
+/// and ignored.
+#[derive(Clone)]
+pub struct Recorder(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl Recorder {
+    /// Create recorder for writer
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        Recorder(Arc::new(Mutex::new(Box::new(writer))))
+    }
+
+    /// Create recorder that appends records to file
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder::new(file))
+    }
+
+    /// Write record
+    pub fn record(&self, version: u8, direction: Direction, packet: &[u8]) {
+        let ts =
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros()).unwrap_or(0);
+
+        let mut line = String::with_capacity(packet.len() * 2 + 64);
+        line.push_str(&format!(
+            "{{\"ts\":{},\"v\":{},\"dir\":\"{}\",\"data\":\"",
+            ts,
+            version,
+            direction.as_str()
+        ));
+        for b in packet {
+            line.push_str(&format!("{:02x}", b));
+        }
+        line.push_str("\"}\n");
+
+        if let Err(e) = self.0.lock().unwrap().write_all(line.as_bytes()) {
+            log::warn!("Cannot write packet record: {}", e);
+        }
+    }
+
+    /// Flush underlying writer
+    pub fn flush(&self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+
+    /// Write record of decoded inbound packet
+    pub(crate) fn record_inbound(&self, version: u8, first_byte: u8, body: &[u8]) {
+        let mut buf = BytesMut::with_capacity(body.len() + 5);
+        buf.extend_from_slice(&[first_byte]);
+        write_variable_length(body.len() as u32, &mut buf);
+        buf.extend_from_slice(body);
+        self.record(version, Direction::Inbound, &buf);
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder").finish()
+    }
+}
+
+/// Recorded packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Timestamp in microseconds since unix epoch
+    pub ts: u64,
+    /// Protocol version, `3` or `5`
+    pub version: u8,
+    /// Direction of packet
+    pub direction: Direction,
+    /// Encoded packet, including fixed header
+    pub data: Bytes,
+}
+
+/// Errors which can occur when reading recorded packets
+#[derive(Debug, Display, From)]
+pub enum RecordError {
+    #[display(fmt = "Io error: {}", _0)]
+    Io(io::Error),
+    #[display(fmt = "Json error: {}", _0)]
+    Json(serde_json::Error),
+    #[display(fmt = "Invalid record: {}", _0)]
+    #[from(ignore)]
+    Invalid(&'static str),
+}
+
+impl std::error::Error for RecordError {}
+
+impl Record {
+    /// Parse record line
+    pub fn parse(line: &str) -> Result<Record, RecordError> {
+        let rec: serde_json::Value = serde_json::from_str(line)?;
+        let ts = rec["ts"].as_u64().ok_or(RecordError::Invalid("ts"))?;
+        let version = match rec["v"].as_u64() {
+            Some(3) => 3,
+            Some(5) => 5,
+            _ => return Err(RecordError::Invalid("v")),
+        };
+        let direction = match rec["dir"].as_str() {
+            Some("in") => Direction::Inbound,
+            Some("out") => Direction::Outbound,
+            _ => return Err(RecordError::Invalid("dir")),
+        };
+        let hex = rec["data"].as_str().ok_or(RecordError::Invalid("data"))?;
+        if hex.len() % 2 != 0 {
+            return Err(RecordError::Invalid("data"));
+        }
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RecordError::Invalid("data"))?;
+
+        Ok(Record { ts, version, direction, data: Bytes::from(data) })
+    }
+
+    /// Read records, empty lines are skipped
+    pub fn read<R: BufRead>(reader: R) -> Result<Vec<Record>, RecordError> {
+        let mut records = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                records.push(Record::parse(&line)?);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Read records from file
+    pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<Record>, RecordError> {
+        Record::read(io::BufReader::new(fs::File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let buf = Buf::default();
+        let recorder = Recorder::new(buf.clone());
+        recorder.record(5, Direction::Outbound, b"\xc0\x00");
+        recorder.record_inbound(3, 0xd0, b"");
+
+        let data = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = data.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let rec: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(rec["v"], 5);
+        assert_eq!(rec["dir"], "out");
+        assert_eq!(rec["data"], "c000");
+        assert!(rec["ts"].as_u64().unwrap() > 0);
+        let rec: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(rec["dir"], "in");
+        assert_eq!(rec["data"], "d000");
+
+        let records = Record::read(data.as_bytes()).unwrap();
+        assert_eq!(records[0].version, 5);
+        assert_eq!(records[0].direction, Direction::Outbound);
+        assert_eq!(records[0].data, Bytes::from_static(b"\xc0\x00"));
+        assert_eq!(records[1].direction, Direction::Inbound);
+        assert!(Record::parse(r#"{"ts":1,"v":4,"dir":"in","data":""}"#).is_err());
+        assert!(Record::parse(r#"{"ts":1,"v":3,"dir":"in","data":"c"}"#).is_err());
+    }
+}
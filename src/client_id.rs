@@ -0,0 +1,56 @@
+//! Client identifier policies
+//!
+//! Policy is a function that receives client id of `CONNECT` packet and
+//! returns normalized client id, or `None` if client id must be rejected.
+//! Policy is set with `client_id_policy()` method of server builders.
+use std::rc::Rc;
+
+use ntex::util::ByteString;
+
+/// Max length of client id that server must accept
+pub const MAX_SPEC_LENGTH: usize = 23;
+
+pub(crate) type ClientIdPolicy = Rc<dyn Fn(&ByteString) -> Option<ByteString>>;
+
+/// Client id rules of the specification.
+///
+/// Client id must contain only `0-9a-zA-Z` characters and must not be longer
+/// than 23 characters. Empty client id is accepted, server assigns unique id.
+pub fn strict(client_id: &ByteString) -> Option<ByteString> {
+    if client_id.len() <= MAX_SPEC_LENGTH
+        && client_id.bytes().all(|c| c.is_ascii_alphanumeric())
+    {
+        Some(client_id.clone())
+    } else {
+        None
+    }
+}
+
+/// Lowercase client id, rejects ids with whitespace or control characters
+pub fn lowercase(client_id: &ByteString) -> Option<ByteString> {
+    if client_id.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        None
+    } else if client_id.chars().any(|c| c.is_uppercase()) {
+        Some(ByteString::from(client_id.to_lowercase()))
+    } else {
+        Some(client_id.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policies() {
+        let id = |s: &'static str| ByteString::from_static(s);
+
+        assert_eq!(strict(&id("")), Some(id("")));
+        assert_eq!(strict(&id("Client01")), Some(id("Client01")));
+        assert_eq!(strict(&id("client-01")), None);
+        assert_eq!(strict(&id("c123456789012345678901234")), None);
+
+        assert_eq!(lowercase(&id("Client-01")), Some(id("client-01")));
+        assert_eq!(lowercase(&id("client 01")), None);
+    }
+}
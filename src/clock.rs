@@ -0,0 +1,179 @@
+//! Time source of dispatchers and sinks
+//!
+//! All timestamps are taken from runtime clock, so tests could pause time
+//! with `tokio::time::pause()` and drive keep-alive expiry, ack timeouts and
+//! session expiry with `tokio::time::advance()` instead of real sleeps.
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc, time::Duration, time::Instant};
+
+use ntex::framed::State;
+use ntex::rt::time::{sleep, Instant as RtInstant};
+use ntex::util::HashSet;
+
+/// Current time of runtime clock
+pub(crate) fn now() -> Instant {
+    RtInstant::now().into_std()
+}
+
+/// Time elapsed since instant, measured with runtime clock
+pub(crate) fn elapsed(since: Instant) -> Duration {
+    now().saturating_duration_since(since)
+}
+
+/// Low resolution timer shared by connections
+///
+/// Timer tracks keep-alive deadlines of connections. It provides low
+/// resolution timestamps via `Timer::now()`, time is taken from runtime
+/// clock.
+pub struct Timer(Rc<RefCell<Inner>>);
+
+struct Inner {
+    resolution: Duration,
+    current: Option<Instant>,
+    notifications: BTreeMap<Instant, HashSet<State>>,
+    expired: HashSet<State>,
+}
+
+impl Inner {
+    fn unregister(&mut self, expire: Instant, state: &State) {
+        if let Some(states) = self.notifications.get_mut(&expire) {
+            states.remove(state);
+            if states.is_empty() {
+                self.notifications.remove(&expire);
+            }
+        }
+    }
+}
+
+impl Clone for Timer {
+    fn clone(&self) -> Self {
+        Timer(self.0.clone())
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::with(Duration::from_secs(1))
+    }
+}
+
+impl Timer {
+    /// Create timer with resolution
+    pub fn with(resolution: Duration) -> Timer {
+        Timer(Rc::new(RefCell::new(Inner {
+            resolution,
+            current: None,
+            notifications: BTreeMap::default(),
+            expired: HashSet::default(),
+        })))
+    }
+
+    /// Get current time.
+    ///
+    /// This function has to be called within runtime context.
+    pub fn now(&self) -> Instant {
+        let cur = self.0.borrow().current;
+        if let Some(cur) = cur {
+            return cur;
+        }
+
+        let now = now();
+        let interval = {
+            let mut inner = self.0.borrow_mut();
+            inner.current = Some(now);
+            inner.resolution
+        };
+
+        let inner = self.0.clone();
+        ntex::rt::spawn(async move {
+            sleep(interval).await;
+            let empty = {
+                let mut i = inner.borrow_mut();
+                let now = i.current.take().unwrap_or_else(self::now);
+
+                // notify io dispatchers
+                while let Some(key) = i.notifications.keys().next().copied() {
+                    if key > now {
+                        break;
+                    }
+                    for st in i.notifications.remove(&key).unwrap() {
+                        st.wake_dispatcher();
+
+                        i.expired.insert(st);
+                    }
+                }
+                i.notifications.is_empty()
+            };
+
+            // extra tick
+            if !empty {
+                let _ = Timer(inner).now();
+            }
+        });
+
+        now
+    }
+
+    /// Register keep-alive deadline of connection, previous deadline
+    /// and its expiration are removed
+    pub(crate) fn register(&self, expire: Instant, previous: Instant, state: &State) {
+        {
+            let mut inner = self.0.borrow_mut();
+            inner.unregister(previous, state);
+            inner.expired.remove(state);
+            inner.notifications.entry(expire).or_default().insert(state.clone());
+        }
+        let _ = self.now();
+    }
+
+    /// Remove keep-alive deadline of connection
+    pub(crate) fn unregister(&self, expire: Instant, state: &State) {
+        let mut inner = self.0.borrow_mut();
+        inner.unregister(expire, state);
+        inner.expired.remove(state);
+    }
+
+    /// Check if keep-alive deadline of connection is expired
+    pub(crate) fn is_expired(&self, state: &State) -> bool {
+        self.0.borrow().expired.contains(state)
+    }
+}
+
+impl std::fmt::Debug for Timer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Timer").field("resolution", &self.0.borrow().resolution).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ntex::test]
+    async fn test_virtual_clock() {
+        tokio::time::pause();
+
+        let start = now();
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(now() - start >= Duration::from_secs(60));
+
+        let timer = Timer::with(Duration::from_millis(100));
+        let st = State::new();
+        let expire = timer.now() + Duration::from_secs(5);
+        timer.register(expire, expire, &st);
+
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert!(!timer.is_expired(&st));
+        for _ in 0..20 {
+            tokio::time::advance(Duration::from_millis(100)).await;
+        }
+        assert!(timer.is_expired(&st));
+
+        // stale expiration does not apply to new deadline
+        let next = timer.now() + Duration::from_secs(5);
+        timer.register(next, expire, &st);
+        assert!(!timer.is_expired(&st));
+
+        timer.unregister(next, &st);
+        assert!(!timer.is_expired(&st));
+    }
+}
@@ -0,0 +1,184 @@
+use std::sync::{Arc, Mutex};
+use std::{fmt, time::Duration};
+
+use crate::utils::duration_to_secs;
+
+/// Per-listener server configuration
+///
+/// Server factory created with `finish_with_config()` accepts this type as
+/// service config. Settings that are set override server builder settings,
+/// so same server factory could serve several listeners with different limits.
+#[derive(Clone, Debug, Default)]
+pub struct ListenerConfig {
+    pub(crate) max_size: Option<u32>,
+    pub(crate) handshake_timeout: Option<Duration>,
+}
+
+impl ListenerConfig {
+    /// Create new config, no server settings are overridden
+    pub fn new() -> Self {
+        ListenerConfig::default()
+    }
+
+    /// Override max inbound frame size, `0` means unlimited
+    pub fn max_size(mut self, size: u32) -> Self {
+        self.max_size = Some(size);
+        self
+    }
+
+    /// Override handshake timeout, `0` disables timeout
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+}
+
+/// Handle for runtime server configuration
+///
+/// Handle could be shared between servers and worker threads. Changed
+/// values are used by new connections, existing connections keep settings
+/// they were created with. Callbacks registered with `on_change()` get called
+/// after every change and could be used to update existing sessions.
+#[derive(Clone, Default)]
+pub struct ConfigHandle(Arc<ConfigInner>);
+
+#[derive(Default)]
+struct ConfigInner {
+    values: Mutex<ConfigValues>,
+    callbacks: Mutex<Vec<Box<dyn Fn(&ConfigHandle) + Send + Sync>>>,
+}
+
+#[derive(Copy, Clone, Default)]
+struct ConfigValues {
+    max_size: Option<u32>,
+    max_inflight: Option<u16>,
+    keep_alive: Option<Duration>,
+}
+
+impl ConfigHandle {
+    /// Create new handle, no server settings are overridden
+    pub fn new() -> Self {
+        ConfigHandle::default()
+    }
+
+    /// Max inbound frame size for new connections
+    pub fn max_size(&self) -> Option<u32> {
+        self.values().max_size
+    }
+
+    /// Override max inbound frame size, `0` means unlimited
+    pub fn set_max_size(&self, size: u32) {
+        self.update(|v| v.max_size = Some(size))
+    }
+
+    /// Max number of in-flight incoming publishes for new connections
+    pub fn max_inflight(&self) -> Option<u16> {
+        self.values().max_inflight
+    }
+
+    /// Override max number of in-flight incoming publishes.
+    ///
+    /// Overrides `inflight` setting for v3 server and `receive_max` setting
+    /// for v5 server.
+    pub fn set_max_inflight(&self, val: u16) {
+        self.update(|v| v.max_inflight = Some(val))
+    }
+
+    /// Keep-alive timeout for new connections
+    pub fn keep_alive(&self) -> Option<Duration> {
+        self.values().keep_alive
+    }
+
+    /// Override keep-alive timeout set by handshake service.
+    ///
+    /// Timeout is rounded up to whole seconds, `0` disables keep-alive.
+    pub fn set_keep_alive(&self, timeout: Duration) {
+        self.update(|v| v.keep_alive = Some(timeout))
+    }
+
+    /// Remove all overrides, server builder settings are used for new connections
+    pub fn reset(&self) {
+        self.update(|v| *v = ConfigValues::default())
+    }
+
+    /// Register callback that gets called after every configuration change.
+    ///
+    /// Callback runs on the thread that changes configuration.
+    pub fn on_change<F>(&self, f: F)
+    where
+        F: Fn(&ConfigHandle) + Send + Sync + 'static,
+    {
+        self.0.callbacks.lock().unwrap().push(Box::new(f));
+    }
+
+    pub(crate) fn max_size_or(&self, size: u32) -> u32 {
+        self.max_size().unwrap_or(size)
+    }
+
+    pub(crate) fn max_inflight_or(&self, val: u16) -> u16 {
+        self.max_inflight().unwrap_or(val)
+    }
+
+    pub(crate) fn keep_alive_or(&self, secs: u16) -> u16 {
+        self.keep_alive().map(duration_to_secs).unwrap_or(secs)
+    }
+
+    fn values(&self) -> ConfigValues {
+        *self.0.values.lock().unwrap()
+    }
+
+    fn update<F: FnOnce(&mut ConfigValues)>(&self, f: F) {
+        f(&mut self.0.values.lock().unwrap());
+        for cb in self.0.callbacks.lock().unwrap().iter() {
+            (*cb)(self);
+        }
+    }
+}
+
+impl fmt::Debug for ConfigHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let values = self.values();
+        f.debug_struct("ConfigHandle")
+            .field("max_size", &values.max_size)
+            .field("max_inflight", &values.max_inflight)
+            .field("keep_alive", &values.keep_alive)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn test_config_handle() {
+        let cfg = ConfigHandle::new();
+        assert_eq!(cfg.max_size(), None);
+        assert_eq!(cfg.max_inflight(), None);
+        assert_eq!(cfg.keep_alive(), None);
+
+        let changes = Arc::new(AtomicUsize::new(0));
+        let changes2 = changes.clone();
+        cfg.on_change(move |cfg| {
+            assert!(cfg.max_size().is_some());
+            changes2.fetch_add(1, Ordering::Relaxed);
+        });
+
+        let cfg2 = cfg.clone();
+        std::thread::spawn(move || {
+            cfg2.set_max_size(1024);
+            cfg2.set_max_inflight(4);
+        })
+        .join()
+        .unwrap();
+        cfg.set_keep_alive(Duration::from_secs(10));
+
+        assert_eq!(cfg.max_size(), Some(1024));
+        assert_eq!(cfg.max_inflight(), Some(4));
+        assert_eq!(cfg.keep_alive(), Some(Duration::from_secs(10)));
+        assert_eq!(changes.load(Ordering::Relaxed), 3);
+        assert!(format!("{:?}", cfg).contains("1024"));
+    }
+}
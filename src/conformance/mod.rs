@@ -0,0 +1,254 @@
+//! Protocol conformance self-test
+//!
+//! [`v3::check()`] and [`v5::check()`] run a matrix of behaviors mandated by
+//! the specification against a server factory, every rule is checked on a
+//! fresh connection over in-memory transport. Rules cover malformed packets,
+//! reserved bits, invalid packet identifiers and keep-alive violations.
+//! Result is [`Report`] with pass/fail outcome for each rule.
+//!
+//! ```rust,no_run
+//! use ntex::util::Ready;
+//! use ntex_mqtt::{conformance, v3};
+//!
+//! # async fn test() {
+//! let server = v3::MqttServer::new(|con: v3::Handshake<_>| {
+//!     Ready::Ok::<_, ()>(con.ack((), false))
+//! })
+//! .publish(|_: v3::Publish| Ready::Ok::<_, ()>(()))
+//! .finish();
+//!
+//! let report = conformance::v3::check(server, v3::codec::Connect::default()).await;
+//! println!("{}", report);
+//! # }
+//! ```
+use std::{fmt, future::Future, time::Duration};
+
+use ntex::codec::{Decoder, Encoder};
+use ntex::rt::time::{sleep, timeout};
+use ntex::service::{Service, ServiceFactory};
+use ntex::util::{select, BytesMut, Either};
+
+use crate::error::{DecodeError, EncodeError};
+use crate::testing::{duplex, Io};
+
+pub mod v3;
+pub mod v5;
+
+/// Max time to wait for server response
+const RECV_TIMEOUT: Duration = Duration::from_secs(3);
+/// Max time of single rule check
+const RULE_TIMEOUT: Duration = Duration::from_secs(10);
+const CLOSED_CHECK_INTERVAL: Duration = Duration::from_millis(25);
+
+/// Outcome of single conformance rule
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    /// Normative statement id, i.e. `MQTT-3.1.0-1`
+    pub rule: &'static str,
+    /// Short description of required behavior
+    pub title: &'static str,
+    /// Check result, error contains description of observed behavior
+    pub result: Result<(), String>,
+}
+
+impl Outcome {
+    /// Check if server behaves as required
+    pub fn is_passed(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// Conformance report
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    outcomes: Vec<Outcome>,
+}
+
+impl Report {
+    /// Outcomes of all checked rules, in order of execution
+    pub fn outcomes(&self) -> &[Outcome] {
+        &self.outcomes
+    }
+
+    /// Failed rules
+    pub fn failed(&self) -> impl Iterator<Item = &Outcome> {
+        self.outcomes.iter().filter(|o| !o.is_passed())
+    }
+
+    /// Outcome of specific rule
+    pub fn get(&self, rule: &str) -> Option<&Outcome> {
+        self.outcomes.iter().find(|o| o.rule == rule)
+    }
+
+    /// Check if all rules are passed
+    pub fn is_success(&self) -> bool {
+        self.outcomes.iter().all(|o| o.is_passed())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for o in &self.outcomes {
+            match o.result {
+                Ok(_) => writeln!(f, "PASS {:<16} {}", o.rule, o.title)?,
+                Err(ref e) => writeln!(f, "FAIL {:<16} {}: {}", o.rule, o.title, e)?,
+            }
+        }
+        let failed = self.failed().count();
+        write!(f, "{} passed, {} failed", self.outcomes.len() - failed, failed)
+    }
+}
+
+/// Response of server under test
+enum Received<P> {
+    Packet(P),
+    Closed,
+}
+
+/// Client side of connection under test
+struct Probe<C: Decoder> {
+    io: Io,
+    codec: C,
+    buf: BytesMut,
+    /// packets that server may send before it closes connection
+    closing: fn(&C::Item) -> bool,
+}
+
+impl<C, P> Probe<C>
+where
+    C: Decoder<Item = P, Error = DecodeError> + Encoder<Item = P, Error = EncodeError>,
+    P: fmt::Debug,
+{
+    /// Send packet to the server
+    fn send(&self, pkt: P) {
+        let mut buf = BytesMut::new();
+        self.codec.encode(pkt, &mut buf).expect("Cannot encode packet");
+        self.io.write(buf);
+    }
+
+    /// Send raw bytes to the server
+    fn send_raw(&self, data: &[u8]) {
+        self.io.write(data);
+    }
+
+    /// Receive next packet or connection close
+    async fn recv(&mut self) -> Result<Received<P>, String> {
+        loop {
+            match self.codec.decode(&mut self.buf) {
+                Ok(Some(pkt)) => return Ok(Received::Packet(pkt)),
+                Ok(None) => (),
+                Err(e) => return Err(format!("server sent malformed packet: {:?}", e)),
+            }
+            let data = timeout(RECV_TIMEOUT, self.read())
+                .await
+                .map_err(|_| "server did not respond in time".to_string())?;
+            if data.is_empty() {
+                return Ok(Received::Closed);
+            }
+            self.buf.extend_from_slice(&data);
+        }
+    }
+
+    async fn read(&self) -> BytesMut {
+        loop {
+            match select(self.io.read(), sleep(CLOSED_CHECK_INTERVAL)).await {
+                Either::Left(res) => return res.unwrap_or_default(),
+                Either::Right(_) => {
+                    if self.io.is_closed() {
+                        return self.io.read_any();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Receive next packet, `f` must return `Some` for expected packet
+    async fn expect<F, T>(&mut self, f: F) -> Result<T, String>
+    where
+        F: FnOnce(&P) -> Option<T>,
+    {
+        match self.recv().await? {
+            Received::Packet(pkt) => {
+                f(&pkt).ok_or_else(|| format!("unexpected packet: {:?}", pkt))
+            }
+            Received::Closed => Err("connection is closed".to_string()),
+        }
+    }
+
+    /// Server must close connection, optionally after notification packet
+    async fn expect_closed(&mut self) -> Result<(), String> {
+        loop {
+            match self.recv().await {
+                Ok(Received::Closed) => return Ok(()),
+                Ok(Received::Packet(pkt)) if (self.closing)(&pkt) => continue,
+                Ok(Received::Packet(pkt)) => {
+                    return Err(format!("unexpected packet: {:?}, disconnect is expected", pkt))
+                }
+                Err(_) => return Err("connection is not closed".to_string()),
+            }
+        }
+    }
+}
+
+/// Executes rules against server factory
+struct Runner<F, C: Decoder> {
+    factory: F,
+    codec: fn() -> C,
+    closing: fn(&C::Item) -> bool,
+    report: Report,
+}
+
+impl<F, C, P> Runner<F, C>
+where
+    F: ServiceFactory<Config = (), Request = Io>,
+    F::Service: 'static,
+    C: Decoder<Item = P, Error = DecodeError> + Encoder<Item = P, Error = EncodeError>,
+    P: fmt::Debug,
+{
+    fn new(factory: F, codec: fn() -> C, closing: fn(&P) -> bool) -> Self {
+        Runner { factory, codec, closing, report: Report::default() }
+    }
+
+    /// Check rule on fresh connection
+    async fn rule<T, R>(&mut self, rule: &'static str, title: &'static str, f: T)
+    where
+        T: FnOnce(Probe<C>) -> R,
+        R: Future<Output = Result<(), String>>,
+    {
+        let result = match self.factory.new_service(()).await {
+            Ok(srv) => {
+                let (client, server) = duplex();
+                ntex::rt::spawn(async move {
+                    let _ = srv.call(server).await;
+                });
+
+                let probe = Probe {
+                    io: client,
+                    codec: (self.codec)(),
+                    buf: BytesMut::new(),
+                    closing: self.closing,
+                };
+                match timeout(RULE_TIMEOUT, f(probe)).await {
+                    Ok(res) => res,
+                    Err(_) => Err("check is timed out".to_string()),
+                }
+            }
+            Err(_) => Err("cannot create server service".to_string()),
+        };
+
+        log::trace!("Conformance rule {} result: {:?}", rule, result);
+        self.report.outcomes.push(Outcome { rule, title, result });
+    }
+
+    fn finish(self) -> Report {
+        self.report
+    }
+}
+
+/// Offset of variable header of encoded packet
+fn header_len(buf: &[u8]) -> usize {
+    1 + buf[1..].iter().position(|b| b & 0x80 == 0).unwrap_or(0) + 1
+}
+
+/// Malformed remaining length, more than 4 bytes
+const MALFORMED_LENGTH: &[u8] = &[0xC0, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
@@ -0,0 +1,198 @@
+//! Conformance rules of mqtt v3.1.1 protocol
+use std::num::NonZeroU16;
+
+use ntex::service::ServiceFactory;
+use ntex::util::{ByteString, Bytes, BytesMut};
+use ntex_codec::Encoder;
+
+use super::{header_len, Probe, Report, Runner, MALFORMED_LENGTH};
+use crate::testing::Io;
+use crate::types::QoS;
+use crate::v3::codec::{Codec, Connect, ConnectAckReason, Packet, Publish};
+
+/// Run mqtt v3.1.1 conformance rules against server factory.
+///
+/// `connect` is used as template for `CONNECT` packets, handshake service
+/// must accept it. Keep-alive of template must not be shorter than a few
+/// seconds, otherwise keep-alive timeout could be taken for disconnect.
+pub async fn check<F>(factory: F, connect: Connect) -> Report
+where
+    F: ServiceFactory<Config = (), Request = Io>,
+    F::Service: 'static,
+{
+    let mut runner = Runner::new(factory, Codec::new, |_| false);
+
+    runner
+        .rule("MQTT-3.1.0-1", "first packet must be CONNECT", |mut p| async move {
+            p.send(Packet::PingRequest);
+            p.expect_closed().await
+        })
+        .await;
+
+    let c = connect.clone();
+    runner
+        .rule("MQTT-3.1.0-2", "second CONNECT is protocol violation", |mut p| async move {
+            handshake(&mut p, &c).await?;
+            p.send(Packet::Connect(c));
+            p.expect_closed().await
+        })
+        .await;
+
+    let mut buf = encode_connect(&connect);
+    let pos = header_len(&buf) + 5;
+    buf[pos] = b'X';
+    runner
+        .rule("MQTT-3.1.2-1", "invalid protocol name", |mut p| async move {
+            p.send_raw(&buf);
+            p.expect_closed().await
+        })
+        .await;
+
+    let mut buf = encode_connect(&connect);
+    let pos = header_len(&buf) + 7;
+    buf[pos] |= 0x01;
+    runner
+        .rule("MQTT-3.1.2-3", "reserved flag of CONNECT is set", |mut p| async move {
+            p.send_raw(&buf);
+            p.expect_closed().await
+        })
+        .await;
+
+    let c = Connect { keep_alive: 1, ..connect.clone() };
+    runner
+        .rule("MQTT-3.1.2-24", "keep-alive timeout closes connection", |mut p| async move {
+            handshake(&mut p, &c).await?;
+            p.expect_closed().await
+        })
+        .await;
+
+    let c = connect.clone();
+    runner
+        .rule("MQTT-2.2.2-2", "invalid fixed header flags", |mut p| async move {
+            handshake(&mut p, &c).await?;
+            // SUBSCRIBE with flags 0000
+            p.send_raw(&[0x80, 0x06, 0x00, 0x01, 0x00, 0x01, b'a', 0x00]);
+            p.expect_closed().await
+        })
+        .await;
+
+    let c = connect.clone();
+    runner
+        .rule("MQTT-1.5.3-1", "topic is not valid utf-8", |mut p| async move {
+            handshake(&mut p, &c).await?;
+            p.send_raw(&[0x30, 0x05, 0x00, 0x03, 0xFF, 0xFE, 0xFD]);
+            p.expect_closed().await
+        })
+        .await;
+
+    let c = connect.clone();
+    runner
+        .rule("MQTT-2.2.3", "malformed remaining length", |mut p| async move {
+            handshake(&mut p, &c).await?;
+            p.send_raw(MALFORMED_LENGTH);
+            p.expect_closed().await
+        })
+        .await;
+
+    let c = connect.clone();
+    runner
+        .rule("MQTT-2.3.1-1", "packet identifier must be non-zero", |mut p| async move {
+            handshake(&mut p, &c).await?;
+            p.send_raw(&[0x32, 0x05, 0x00, 0x01, b'a', 0x00, 0x00]);
+            p.expect_closed().await
+        })
+        .await;
+
+    let c = connect.clone();
+    runner
+        .rule("MQTT-3.3.1-4", "PUBLISH with QoS 3", |mut p| async move {
+            handshake(&mut p, &c).await?;
+            p.send_raw(&[0x36, 0x05, 0x00, 0x01, b'a', 0x00, 0x01]);
+            p.expect_closed().await
+        })
+        .await;
+
+    let c = connect.clone();
+    runner
+        .rule("MQTT-3.3.2-2", "PUBLISH topic contains wildcards", |mut p| async move {
+            handshake(&mut p, &c).await?;
+            p.send(Packet::Publish(publish("a/#", QoS::AtMostOnce)));
+            p.expect_closed().await
+        })
+        .await;
+
+    let c = connect.clone();
+    runner
+        .rule("MQTT-3.8.3-3", "SUBSCRIBE without topic filters", |mut p| async move {
+            handshake(&mut p, &c).await?;
+            p.send_raw(&[0x82, 0x02, 0x00, 0x01]);
+            p.expect_closed().await
+        })
+        .await;
+
+    let c = connect.clone();
+    runner
+        .rule("MQTT-3.10.3-2", "UNSUBSCRIBE without topic filters", |mut p| async move {
+            handshake(&mut p, &c).await?;
+            p.send_raw(&[0xA2, 0x02, 0x00, 0x01]);
+            p.expect_closed().await
+        })
+        .await;
+
+    let c = connect.clone();
+    runner
+        .rule("MQTT-3.12.4-1", "PINGREQ is answered with PINGRESP", |mut p| async move {
+            handshake(&mut p, &c).await?;
+            p.send(Packet::PingRequest);
+            p.expect(|pkt| match pkt {
+                Packet::PingResponse => Some(()),
+                _ => None,
+            })
+            .await
+        })
+        .await;
+
+    let c = connect;
+    runner
+        .rule("MQTT-4.3.2-2", "QoS 1 PUBLISH is acknowledged", |mut p| async move {
+            handshake(&mut p, &c).await?;
+            p.send(Packet::Publish(publish("a", QoS::AtLeastOnce)));
+            p.expect(|pkt| match pkt {
+                Packet::PublishAck { packet_id } if packet_id.get() == 1 => Some(()),
+                _ => None,
+            })
+            .await
+        })
+        .await;
+
+    runner.finish()
+}
+
+async fn handshake(p: &mut Probe<Codec>, connect: &Connect) -> Result<(), String> {
+    p.send(Packet::Connect(connect.clone()));
+    p.expect(|pkt| match pkt {
+        Packet::ConnectAck { return_code: ConnectAckReason::ConnectionAccepted, .. } => {
+            Some(())
+        }
+        _ => None,
+    })
+    .await
+    .map_err(|e| format!("handshake failed, {}", e))
+}
+
+fn encode_connect(connect: &Connect) -> BytesMut {
+    let mut buf = BytesMut::new();
+    Codec::new().encode(Packet::Connect(connect.clone()), &mut buf).unwrap();
+    buf
+}
+
+fn publish(topic: &'static str, qos: QoS) -> Publish {
+    Publish {
+        dup: false,
+        retain: false,
+        qos,
+        topic: ByteString::from_static(topic),
+        packet_id: if qos == QoS::AtMostOnce { None } else { NonZeroU16::new(1) },
+        payload: Bytes::new(),
+    }
+}
@@ -0,0 +1,212 @@
+//! Client connect helpers
+//!
+//! Client connectors run name resolution as separate stage, resolver could be
+//! replaced with any service that fills addresses of `Connect` message, for
+//! example static host map or custom dns client. Transport connectors skip
+//! name resolution for messages with addresses.
+//!
+//! `TcpConnector` is default transport connector, it races connection
+//! attempts to dual-stack hosts (RFC 8305). `ProxyConnector` establishes tunnel through SOCKS5 or HTTP `CONNECT`
+//! proxy, tls and mqtt handshakes run over established tunnel.
+use std::collections::HashMap;
+use std::task::{Context, Poll};
+use std::{future::Future, io, marker::PhantomData, net::IpAddr, net::SocketAddr, pin::Pin};
+use std::{rc::Rc, time::Duration};
+
+use ntex::rt::time::delay_for;
+use ntex::service::Service;
+use ntex::util::{select, Either};
+
+mod proxy;
+mod tcp;
+
+pub use self::proxy::{Proxy, ProxyConnector, ProxyKind};
+pub use self::tcp::TcpConnector;
+pub use ntex::connect::{Address, Connect, ConnectError, Resolver};
+
+/// Static host map resolver
+///
+/// Hosts that are not in the map are resolved with fallback resolver.
+pub struct StaticResolver<T, R = Resolver<T>> {
+    hosts: Rc<HashMap<String, Vec<IpAddr>>>,
+    fallback: R,
+    _t: PhantomData<T>,
+}
+
+impl<T> StaticResolver<T> {
+    /// Create resolver with system resolver as fallback
+    pub fn new() -> Self {
+        StaticResolver::with_fallback(Resolver::new())
+    }
+}
+
+impl<T> Default for StaticResolver<T> {
+    fn default() -> Self {
+        StaticResolver::new()
+    }
+}
+
+impl<T, R> StaticResolver<T, R> {
+    /// Create resolver with custom fallback resolver
+    pub fn with_fallback(fallback: R) -> Self {
+        StaticResolver { fallback, hosts: Rc::new(HashMap::new()), _t: PhantomData }
+    }
+
+    /// Add host addresses, port is taken from connect request
+    pub fn host<H, I>(mut self, host: H, addrs: I) -> Self
+    where
+        H: Into<String>,
+        I: IntoIterator<Item = IpAddr>,
+    {
+        Rc::get_mut(&mut self.hosts)
+            .expect("Resolver is in use")
+            .insert(host.into(), addrs.into_iter().collect());
+        self
+    }
+}
+
+impl<T, R: Clone> Clone for StaticResolver<T, R> {
+    fn clone(&self) -> Self {
+        StaticResolver {
+            hosts: self.hosts.clone(),
+            fallback: self.fallback.clone(),
+            _t: PhantomData,
+        }
+    }
+}
+
+impl<T, R> Service for StaticResolver<T, R>
+where
+    T: Address,
+    R: Service<Request = Connect<T>, Response = Connect<T>, Error = ConnectError>,
+    R::Future: 'static,
+{
+    type Request = Connect<T>;
+    type Response = Connect<T>;
+    type Error = ConnectError;
+    type Future = Either<ntex::util::Ready<Connect<T>, ConnectError>, R::Future>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.fallback.poll_ready(cx)
+    }
+
+    fn call(&self, req: Connect<T>) -> Self::Future {
+        let host = req.host().split(':').next().unwrap_or("");
+        if let Some(addrs) = self.hosts.get(host) {
+            log::trace!("Static resolver: host {:?} resolved to {:?}", host, addrs);
+            if addrs.is_empty() {
+                return Either::Left(ntex::util::Ready::Err(ConnectError::NoRecords));
+            }
+            let port = req.port();
+            let addrs: Vec<_> = addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+            Either::Left(ntex::util::Ready::Ok(req.set_addrs(addrs)))
+        } else {
+            Either::Right(self.fallback.call(req))
+        }
+    }
+}
+
+pub(crate) type BoxResolver<A> =
+    Rc<dyn Fn(Connect<A>) -> Pin<Box<dyn Future<Output = Result<Connect<A>, ConnectError>>>>>;
+
+pub(crate) fn boxed_resolver<A, R>(resolver: R) -> BoxResolver<A>
+where
+    R: Service<Request = Connect<A>, Response = Connect<A>, Error = ConnectError> + 'static,
+    R::Future: 'static,
+{
+    Rc::new(move |req| Box::pin(resolver.call(req)))
+}
+
+/// Transport connect stage errors
+#[derive(Debug)]
+pub(crate) enum TransportError {
+    Resolve(ConnectError),
+    Timeout,
+    Tls(io::Error),
+    Connect(ConnectError),
+}
+
+impl From<ConnectError> for TransportError {
+    fn from(err: ConnectError) -> Self {
+        match err {
+            ConnectError::Resolver(_)
+            | ConnectError::NoRecords
+            | ConnectError::InvalidInput
+            | ConnectError::Unresolved => TransportError::Resolve(err),
+            // tls connectors report handshake failures as `Other` io errors,
+            // os level connect errors have specific kinds
+            ConnectError::Io(err) if err.kind() == io::ErrorKind::Other => {
+                TransportError::Tls(err)
+            }
+            err => TransportError::Connect(err),
+        }
+    }
+}
+
+/// Resolve address and open transport connection
+pub(crate) async fn connect<A, T>(
+    req: Connect<A>,
+    resolver: Option<BoxResolver<A>>,
+    connector: Rc<T>,
+    timeout: Duration,
+) -> Result<T::Response, TransportError>
+where
+    A: Address,
+    T: Service<Request = Connect<A>, Error = ConnectError>,
+{
+    let req = if let Some(resolver) = resolver {
+        resolver(req).await.map_err(TransportError::Resolve)?
+    } else {
+        req
+    };
+
+    if timeout == Duration::ZERO {
+        Ok(connector.call(req).await?)
+    } else {
+        match select(delay_for(timeout), connector.call(req)).await {
+            Either::Left(_) => Err(TransportError::Timeout),
+            Either::Right(res) => Ok(res?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[ntex::test]
+    async fn test_static_resolver() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let resolver =
+            StaticResolver::new().host("mqtt.test", vec![localhost]).host("none", Vec::new());
+
+        let req = resolver.call(Connect::new("mqtt.test:1883")).await.unwrap();
+        assert_eq!(req.addrs().collect::<Vec<_>>(), vec![SocketAddr::new(localhost, 1883)]);
+
+        let res = resolver.call(Connect::new("none:1883")).await;
+        assert!(std::matches!(res, Err(ConnectError::NoRecords)));
+
+        // fallback resolver
+        let req = resolver.call(Connect::new("127.0.0.1:1884")).await.unwrap();
+        assert_eq!(req.addrs().collect::<Vec<_>>(), vec![SocketAddr::new(localhost, 1884)]);
+    }
+
+    #[test]
+    fn test_transport_error() {
+        assert!(std::matches!(
+            TransportError::from(ConnectError::NoRecords),
+            TransportError::Resolve(_)
+        ));
+        assert!(std::matches!(
+            TransportError::from(ConnectError::Io(io::Error::other("tls"))),
+            TransportError::Tls(_)
+        ));
+        assert!(std::matches!(
+            TransportError::from(ConnectError::Io(io::ErrorKind::ConnectionRefused.into())),
+            TransportError::Connect(_)
+        ));
+    }
+}
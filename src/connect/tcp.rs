@@ -0,0 +1,259 @@
+use std::task::{Context, Poll};
+use std::{collections::VecDeque, future::Future, io, marker::PhantomData, net::SocketAddr};
+use std::{pin::Pin, time::Duration};
+
+use ntex::connect::{Address, Connect, ConnectError, Resolver};
+use ntex::rt::net::TcpStream;
+use ntex::rt::time::{delay_for, Delay};
+use ntex::service::Service;
+
+/// Tcp connector service with dual-stack support
+///
+/// If host resolves to several addresses, connector races connection
+/// attempts as described in RFC 8305 (Happy Eyeballs): address families
+/// are interleaved, next attempt starts when previous attempt fails or is
+/// not completed within attempt delay. First established connection wins.
+pub struct TcpConnector<T> {
+    attempt_delay: Duration,
+    _t: PhantomData<T>,
+}
+
+impl<T> TcpConnector<T> {
+    /// Create connector with default attempt delay of 250 milliseconds
+    pub fn new() -> Self {
+        TcpConnector { attempt_delay: Duration::from_millis(250), _t: PhantomData }
+    }
+
+    /// Set connection attempt delay
+    ///
+    /// RFC 8305 recommends values between 100 milliseconds and 2 seconds.
+    pub fn attempt_delay(mut self, delay: Duration) -> Self {
+        self.attempt_delay = delay;
+        self
+    }
+}
+
+impl<T> Default for TcpConnector<T> {
+    fn default() -> Self {
+        TcpConnector::new()
+    }
+}
+
+impl<T> Clone for TcpConnector<T> {
+    fn clone(&self) -> Self {
+        TcpConnector { attempt_delay: self.attempt_delay, _t: PhantomData }
+    }
+}
+
+impl<T: Address> TcpConnector<T> {
+    /// Resolve and connect to remote host
+    pub fn connect(
+        &self,
+        req: Connect<T>,
+    ) -> impl Future<Output = Result<TcpStream, ConnectError>> {
+        let lookup = Resolver::new().lookup(req);
+        let delay = self.attempt_delay;
+
+        async move {
+            let req = lookup.await?;
+            let addrs = interleave(req.addrs());
+            log::trace!("Tcp connector: connecting to {:?} - {:?}", req.host(), addrs);
+            HappyEyeballs::new(addrs, delay).await
+        }
+    }
+}
+
+impl<T: Address> Service for TcpConnector<T> {
+    type Request = Connect<T>;
+    type Response = TcpStream;
+    type Error = ConnectError;
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream, ConnectError>>>>;
+
+    #[inline]
+    fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn call(&self, req: Connect<T>) -> Self::Future {
+        Box::pin(self.connect(req))
+    }
+}
+
+/// Interleave address families, first family is family of first address
+fn interleave<I: Iterator<Item = SocketAddr>>(addrs: I) -> VecDeque<SocketAddr> {
+    let mut addrs = addrs.peekable();
+    let first_v6 = match addrs.peek() {
+        Some(addr) => addr.is_ipv6(),
+        None => return VecDeque::new(),
+    };
+    let (first, mut second): (VecDeque<_>, VecDeque<_>) =
+        addrs.partition(|addr| addr.is_ipv6() == first_v6);
+
+    let mut result = VecDeque::with_capacity(first.len() + second.len());
+    for addr in first {
+        result.push_back(addr);
+        if let Some(addr) = second.pop_front() {
+            result.push_back(addr);
+        }
+    }
+    result.extend(second);
+    result
+}
+
+type Attempt = Pin<Box<dyn Future<Output = io::Result<TcpStream>>>>;
+
+struct HappyEyeballs {
+    addrs: VecDeque<SocketAddr>,
+    attempts: Vec<Attempt>,
+    delay: Duration,
+    timer: Option<Pin<Box<Delay>>>,
+    error: Option<io::Error>,
+}
+
+impl HappyEyeballs {
+    fn new(addrs: VecDeque<SocketAddr>, delay: Duration) -> Self {
+        HappyEyeballs { addrs, delay, attempts: Vec::new(), timer: None, error: None }
+    }
+
+    fn start_next(&mut self) -> bool {
+        if let Some(addr) = self.addrs.pop_front() {
+            log::trace!("Tcp connector: connection attempt to {:?}", addr);
+            self.attempts.push(Box::pin(TcpStream::connect(addr)));
+            self.timer = if self.addrs.is_empty() {
+                None
+            } else {
+                Some(Box::pin(delay_for(self.delay)))
+            };
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Future for HappyEyeballs {
+    type Output = Result<TcpStream, ConnectError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let timer_fired =
+                this.timer.as_mut().map(|t| t.as_mut().poll(cx).is_ready()).unwrap_or(false);
+            if timer_fired || this.attempts.is_empty() {
+                if this.start_next() {
+                    continue;
+                }
+                if this.attempts.is_empty() {
+                    return Poll::Ready(Err(match this.error.take() {
+                        Some(err) => ConnectError::Io(err),
+                        None => ConnectError::Unresolved,
+                    }));
+                }
+                this.timer = None;
+            }
+
+            let mut failed = false;
+            let mut idx = 0;
+            while idx < this.attempts.len() {
+                match this.attempts[idx].as_mut().poll(cx) {
+                    Poll::Ready(Ok(sock)) => {
+                        let _ = sock.set_nodelay(true);
+                        log::trace!("Tcp connector: connected to {:?}", sock.peer_addr());
+                        return Poll::Ready(Ok(sock));
+                    }
+                    Poll::Ready(Err(err)) => {
+                        log::trace!("Tcp connector: connection attempt failed: {:?}", err);
+                        drop(this.attempts.swap_remove(idx));
+                        this.error = Some(err);
+                        failed = true;
+                    }
+                    Poll::Pending => idx += 1,
+                }
+            }
+
+            // failed attempt starts next attempt immediately
+            if failed && !this.addrs.is_empty() {
+                this.start_next();
+            } else if !failed || !this.attempts.is_empty() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn v4(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+    }
+
+    fn v6(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port)
+    }
+
+    #[test]
+    fn test_interleave() {
+        let addrs = vec![v6(1), v6(2), v6(3), v4(1), v4(2)];
+        assert_eq!(
+            interleave(addrs.into_iter()),
+            VecDeque::from(vec![v6(1), v4(1), v6(2), v4(2), v6(3)])
+        );
+        let addrs = vec![v4(1), v6(1), v6(2), v6(3)];
+        assert_eq!(
+            interleave(addrs.into_iter()),
+            VecDeque::from(vec![v4(1), v6(1), v6(2), v6(3)])
+        );
+        assert!(interleave(Vec::new().into_iter()).is_empty());
+    }
+
+    #[ntex::test]
+    async fn test_connect() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let closed = {
+            let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            l.local_addr().unwrap()
+        };
+        let connector = TcpConnector::new().attempt_delay(Duration::from_millis(50));
+
+        // first address refuses connection
+        let sock = connector
+            .call(Connect::new(String::new()).set_addrs(vec![closed, addr]))
+            .await
+            .unwrap();
+        assert_eq!(sock.peer_addr().unwrap(), addr);
+
+        let res = connector.call(Connect::new(String::new()).set_addrs(vec![closed])).await;
+        assert!(std::matches!(res, Err(ConnectError::Io(_))));
+    }
+
+    #[ntex::test]
+    async fn test_attempt_delay() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // first attempt does not complete, second attempt starts after delay
+        let attempts = vec![
+            Box::pin(futures::future::pending()) as Attempt,
+            Box::pin(TcpStream::connect(addr)) as Attempt,
+        ];
+        let mut he = HappyEyeballs::new(VecDeque::new(), Duration::from_millis(50));
+        he.attempts = attempts;
+        let sock = he.await.unwrap();
+        assert_eq!(sock.peer_addr().unwrap(), addr);
+
+        let start = std::time::Instant::now();
+        let mut he = HappyEyeballs::new(VecDeque::from(vec![addr]), Duration::from_millis(50));
+        he.attempts.push(Box::pin(futures::future::pending()));
+        he.timer = Some(Box::pin(delay_for(Duration::from_millis(50))));
+        let sock = he.await.unwrap();
+        assert_eq!(sock.peer_addr().unwrap(), addr);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
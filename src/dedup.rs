@@ -0,0 +1,158 @@
+//! Inbound duplicate detection
+use std::{cell::RefCell, collections::VecDeque, num::NonZeroU16, rc::Rc};
+
+use ntex::util::{ByteString, HashMap};
+
+type Key = (ByteString, NonZeroU16);
+
+/// Window of recently acknowledged QoS 1 publishes, keyed by client id and packet id.
+///
+/// Window is shared between all connections of a server, so redelivery after
+/// reconnect could be detected. Oldest records get evicted when window is full.
+pub(crate) struct DedupWindow {
+    size: usize,
+    inner: RefCell<WindowInner>,
+}
+
+#[derive(Default)]
+struct WindowInner {
+    seq: u64,
+    acked: HashMap<Key, u64>,
+    order: VecDeque<(Key, u64)>,
+}
+
+impl DedupWindow {
+    pub(crate) fn new(size: u16) -> Self {
+        DedupWindow { size: size as usize, inner: RefCell::new(WindowInner::default()) }
+    }
+}
+
+/// Duplicate detection for a client connection
+pub(crate) struct Dedup {
+    window: Rc<DedupWindow>,
+    client_id: ByteString,
+}
+
+impl Dedup {
+    pub(crate) fn new(window: Rc<DedupWindow>, client_id: ByteString) -> Self {
+        Dedup { window, client_id }
+    }
+
+    /// Check if publish is redelivery of already acknowledged publish.
+    ///
+    /// Only publishes with `dup` flag set could be duplicates, publish without
+    /// `dup` flag starts new exchange with the packet id.
+    pub(crate) fn check(&self, packet_id: NonZeroU16, dup: bool) -> bool {
+        let key = (self.client_id.clone(), packet_id);
+        let mut inner = self.window.inner.borrow_mut();
+        if dup {
+            inner.acked.contains_key(&key)
+        } else {
+            inner.acked.remove(&key);
+            false
+        }
+    }
+
+    /// Record acknowledged publish
+    pub(crate) fn record(&self, packet_id: NonZeroU16) {
+        let key = (self.client_id.clone(), packet_id);
+        let mut inner = self.window.inner.borrow_mut();
+        inner.seq += 1;
+        let seq = inner.seq;
+        inner.acked.insert(key.clone(), seq);
+        inner.order.push_back((key, seq));
+
+        while inner.order.len() > self.window.size {
+            if let Some((key, seq)) = inner.order.pop_front() {
+                // record could be refreshed after it was queued
+                if inner.acked.get(&key) == Some(&seq) {
+                    inner.acked.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// Retransmitted `SUBSCRIBE` and `UNSUBSCRIBE` packets of a connection.
+///
+/// Packet with the same id and type received while the original packet is
+/// still handled by control service is not passed to the service, it gets
+/// acknowledged with the ack of the original packet.
+#[derive(Default)]
+pub(crate) struct InflightControl {
+    packets: RefCell<HashMap<NonZeroU16, (u8, usize)>>,
+}
+
+impl InflightControl {
+    /// Register control packet that is passed to control service
+    pub(crate) fn insert(&self, packet_id: NonZeroU16, packet_type: u8) {
+        self.packets.borrow_mut().insert(packet_id, (packet_type, 0));
+    }
+
+    /// Check if packet is retransmission of in-flight control packet.
+    ///
+    /// Retransmission is recorded and must be acknowledged after original
+    /// packet is handled.
+    pub(crate) fn retransmit(&self, packet_id: NonZeroU16, packet_type: u8) -> bool {
+        match self.packets.borrow_mut().get_mut(&packet_id) {
+            Some((tp, count)) if *tp == packet_type => {
+                *count += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Complete in-flight control packet, returns number of retransmissions
+    pub(crate) fn complete(&self, packet_id: NonZeroU16) -> usize {
+        self.packets.borrow_mut().remove(&packet_id).map(|(_, count)| count).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup() {
+        let window = Rc::new(DedupWindow::new(2));
+        let c1 = Dedup::new(window.clone(), ByteString::from_static("c1"));
+        let c2 = Dedup::new(window, ByteString::from_static("c2"));
+        let id = |v| NonZeroU16::new(v).unwrap();
+
+        assert!(!c1.check(id(1), false));
+        c1.record(id(1));
+        assert!(c1.check(id(1), true));
+        assert!(!c2.check(id(1), true));
+
+        // new exchange with same packet id
+        assert!(!c1.check(id(1), false));
+        assert!(!c1.check(id(1), true));
+
+        // eviction
+        c1.record(id(1));
+        c1.record(id(2));
+        c1.record(id(3));
+        assert!(!c1.check(id(1), true));
+        assert!(c1.check(id(2), true));
+        assert!(c1.check(id(3), true));
+    }
+
+    #[test]
+    fn test_inflight_control() {
+        use crate::types::packet_type::{SUBSCRIBE, UNSUBSCRIBE};
+
+        let control = InflightControl::default();
+        let id = |v| NonZeroU16::new(v).unwrap();
+
+        assert!(!control.retransmit(id(1), SUBSCRIBE));
+        control.insert(id(1), SUBSCRIBE);
+        assert!(control.retransmit(id(1), SUBSCRIBE));
+        assert!(control.retransmit(id(1), SUBSCRIBE));
+        assert!(!control.retransmit(id(1), UNSUBSCRIBE));
+        assert_eq!(control.complete(id(1)), 2);
+
+        assert!(!control.retransmit(id(1), SUBSCRIBE));
+        assert_eq!(control.complete(id(1)), 0);
+    }
+}
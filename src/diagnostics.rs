@@ -0,0 +1,377 @@
+//! Codec diagnostics
+//!
+//! `DiagnosticCodec` wraps v3 or v5 codec and validates invariants of every
+//! encoded and decoded packet:
+//!
+//! * remaining length of fixed header matches packet size and uses
+//!   minimal encoding
+//! * packet id is present only for packets that require it
+//! * packet survives decode/encode round trip byte-for-byte, this catches
+//!   non-canonical property ordering and encoding of third-party peers
+//!
+//! Mismatches are logged with `warn` level as single line reports and do not
+//! affect packet processing. Checks decode and encode every packet one more
+//! time, codec is intended for soak testing, not for production use.
+//!
+//! ```rust
+//! use ntex_mqtt::diagnostics::DiagnosticCodec;
+//! use ntex_mqtt::v3::codec;
+//!
+//! let codec = DiagnosticCodec::new(codec::Codec::new());
+//! assert_eq!(codec.mismatches(), 0);
+//! ```
+use std::cell::{Cell, RefCell};
+use std::fmt;
+
+use ntex_bytes::{Bytes, BytesMut};
+use ntex_codec::{Decoder, Encoder};
+
+pub use crate::capture::Direction;
+use crate::error::{DecodeError, EncodeError};
+use crate::types::QoS;
+use crate::utils::{decode_variable_length, variable_length_size};
+use crate::{v3, v5};
+
+/// Packet type that could be checked by `DiagnosticCodec`
+pub trait DiagnosticPacket: fmt::Debug {
+    /// Check packet id presence rules
+    fn check_packet_id(&self) -> Result<(), &'static str>;
+}
+
+impl DiagnosticPacket for v3::codec::Packet {
+    fn check_packet_id(&self) -> Result<(), &'static str> {
+        if let v3::codec::Packet::Publish(ref pkt) = self {
+            check_publish_id(pkt.qos, pkt.packet_id.is_some())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl DiagnosticPacket for v5::codec::Packet {
+    fn check_packet_id(&self) -> Result<(), &'static str> {
+        if let v5::codec::Packet::Publish(ref pkt) = self {
+            check_publish_id(pkt.qos, pkt.packet_id.is_some())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn check_publish_id(qos: QoS, has_id: bool) -> Result<(), &'static str> {
+    match (qos, has_id) {
+        (QoS::AtMostOnce, true) => Err("packet id is set for QoS 0 publish"),
+        (QoS::AtLeastOnce, false) | (QoS::ExactlyOnce, false) => {
+            Err("packet id is missing for QoS 1 or QoS 2 publish")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Violated codec invariant
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Invariant {
+    /// Remaining length does not match packet size or is not minimally encoded
+    RemainingLength,
+    /// Packet id presence rules
+    PacketId,
+    /// Packet does not survive decode/encode round trip
+    RoundTrip,
+}
+
+impl Invariant {
+    /// Short name of invariant
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Invariant::RemainingLength => "remaining-length",
+            Invariant::PacketId => "packet-id",
+            Invariant::RoundTrip => "round-trip",
+        }
+    }
+}
+
+/// Invariant mismatch report
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// Direction of the packet
+    pub direction: Direction,
+    /// Violated invariant
+    pub invariant: Invariant,
+    /// Mismatch details
+    pub detail: String,
+    /// Packet bytes, including fixed header
+    ///
+    /// Empty for outbound packets that are checked before encoding
+    pub packet: Bytes,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "dir={} invariant={} type={} len={} detail={:?} data=",
+            self.direction.as_str(),
+            self.invariant.as_str(),
+            self.packet.first().map(|b| b >> 4).unwrap_or(0),
+            self.packet.len(),
+            self.detail,
+        )?;
+        for b in self.packet.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Codec wrapper that validates packet invariants
+pub struct DiagnosticCodec<C> {
+    codec: C,
+    frame: RefCell<BytesMut>,
+    mismatches: Cell<usize>,
+    last: RefCell<Option<Report>>,
+}
+
+impl<C> DiagnosticCodec<C> {
+    /// Wrap codec
+    pub fn new(codec: C) -> Self {
+        DiagnosticCodec {
+            codec,
+            frame: RefCell::new(BytesMut::new()),
+            mismatches: Cell::new(0),
+            last: RefCell::new(None),
+        }
+    }
+
+    /// Wrapped codec
+    pub fn get_ref(&self) -> &C {
+        &self.codec
+    }
+
+    /// Number of detected mismatches
+    pub fn mismatches(&self) -> usize {
+        self.mismatches.get()
+    }
+
+    /// Report of last detected mismatch
+    pub fn last_report(&self) -> Option<Report> {
+        self.last.borrow().clone()
+    }
+
+    fn report(
+        &self,
+        direction: Direction,
+        invariant: Invariant,
+        detail: String,
+        packet: &Bytes,
+    ) {
+        let report = Report { direction, invariant, detail, packet: packet.clone() };
+        log::warn!("Codec invariant mismatch: {}", report);
+        self.mismatches.set(self.mismatches.get() + 1);
+        *self.last.borrow_mut() = Some(report);
+    }
+}
+
+impl<C, P> DiagnosticCodec<C>
+where
+    C: Default
+        + Decoder<Item = P, Error = DecodeError>
+        + Encoder<Item = P, Error = EncodeError>,
+    P: DiagnosticPacket,
+{
+    fn check_frame(&self, direction: Direction, frame: Bytes) {
+        match decode_variable_length(frame.get(1..).unwrap_or(&[])) {
+            Ok(Some((len, consumed))) => {
+                if frame.len() != 1 + consumed + len as usize {
+                    let detail = format!(
+                        "remaining length {} does not match packet size {}",
+                        len,
+                        frame.len() - 1 - consumed
+                    );
+                    return self.report(direction, Invariant::RemainingLength, detail, &frame);
+                } else if consumed != variable_length_size(len as usize) {
+                    let detail = format!("remaining length {} uses {} bytes", len, consumed);
+                    return self.report(direction, Invariant::RemainingLength, detail, &frame);
+                }
+            }
+            _ => {
+                let detail = "cannot decode remaining length".to_string();
+                self.report(direction, Invariant::RemainingLength, detail, &frame);
+                return;
+            }
+        }
+
+        let codec = C::default();
+        let mut src = BytesMut::from(&frame[..]);
+        let result = match codec.decode(&mut src) {
+            Ok(Some(pkt)) => {
+                let mut dst = BytesMut::new();
+                codec
+                    .encode(pkt, &mut dst)
+                    .map_err(|e| format!("encode error: {}", e))
+                    .and_then(|_| {
+                        match dst.iter().zip(frame.iter()).position(|(a, b)| a != b) {
+                            Some(pos) => {
+                                Err(format!("re-encoded packet differs at byte {}", pos))
+                            }
+                            None if dst.len() != frame.len() => {
+                                Err(format!("re-encoded packet size is {}", dst.len()))
+                            }
+                            None => Ok(()),
+                        }
+                    })
+            }
+            Ok(None) => Err("incomplete packet".to_string()),
+            Err(e) => Err(format!("decode error: {}", e)),
+        };
+        if let Err(detail) = result {
+            self.report(direction, Invariant::RoundTrip, detail, &frame);
+        }
+    }
+}
+
+impl<C, P> Decoder for DiagnosticCodec<C>
+where
+    C: Default
+        + Decoder<Item = P, Error = DecodeError>
+        + Encoder<Item = P, Error = EncodeError>,
+    P: DiagnosticPacket,
+{
+    type Item = P;
+    type Error = DecodeError;
+
+    fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let snapshot = Bytes::copy_from_slice(src);
+        let result = self.codec.decode(src);
+
+        let consumed = snapshot.len() - src.len();
+        self.frame.borrow_mut().extend_from_slice(&snapshot[..consumed]);
+
+        match result {
+            Ok(Some(pkt)) => {
+                let frame = self.frame.borrow_mut().split().freeze();
+                if let Err(detail) = pkt.check_packet_id() {
+                    self.report(
+                        Direction::Inbound,
+                        Invariant::PacketId,
+                        detail.to_string(),
+                        &frame,
+                    );
+                }
+                self.check_frame(Direction::Inbound, frame);
+                Ok(Some(pkt))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                self.frame.borrow_mut().clear();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<C, P> Encoder for DiagnosticCodec<C>
+where
+    C: Default
+        + Decoder<Item = P, Error = DecodeError>
+        + Encoder<Item = P, Error = EncodeError>,
+    P: DiagnosticPacket,
+{
+    type Item = P;
+    type Error = EncodeError;
+
+    fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // packet is not encoded yet, report contains packet's debug representation
+        if let Err(detail) = item.check_packet_id() {
+            let detail = format!("{}: {:?}", detail, item);
+            self.report(Direction::Outbound, Invariant::PacketId, detail, &Bytes::new());
+        }
+
+        let start = dst.len();
+        self.codec.encode(item, dst)?;
+        let frame = Bytes::copy_from_slice(&dst[start..]);
+        self.check_frame(Direction::Outbound, frame);
+        Ok(())
+    }
+}
+
+impl<C: fmt::Debug> fmt::Debug for DiagnosticCodec<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiagnosticCodec")
+            .field("codec", &self.codec)
+            .field("mismatches", &self.mismatches.get())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntex_bytes::ByteString;
+    use std::num::NonZeroU16;
+
+    #[test]
+    fn test_valid_packets() {
+        let codec = DiagnosticCodec::new(v3::codec::Codec::new());
+        let pkt = v3::codec::Packet::Publish(v3::codec::Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: ByteString::from_static("a/b"),
+            packet_id: NonZeroU16::new(1),
+            payload: Bytes::from_static(b"data"),
+        });
+        let mut buf = BytesMut::new();
+        codec.encode(pkt.clone(), &mut buf).unwrap();
+        // decode in two steps
+        let mut src = buf.split_to(3);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&buf);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(pkt));
+        assert_eq!(codec.mismatches(), 0);
+    }
+
+    #[test]
+    fn test_packet_id() {
+        let codec = DiagnosticCodec::new(v5::codec::Codec::new());
+        let pkt = v5::codec::Packet::Publish(v5::codec::Publish {
+            qos: QoS::AtMostOnce,
+            topic: ByteString::from_static("a/b"),
+            packet_id: NonZeroU16::new(1),
+            dup: false,
+            retain: false,
+            payload: Bytes::new(),
+            properties: Default::default(),
+        });
+        assert!(codec.encode(pkt, &mut BytesMut::new()).is_err());
+        assert_eq!(codec.mismatches(), 1);
+        let report = codec.last_report().unwrap();
+        assert_eq!(report.direction, Direction::Outbound);
+        assert_eq!(report.invariant, Invariant::PacketId);
+    }
+
+    #[test]
+    fn test_remaining_length() {
+        let codec = DiagnosticCodec::new(v3::codec::Codec::new());
+        let mut src = BytesMut::from(&b"\xc0\x80\x00"[..]);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(v3::codec::Packet::PingRequest));
+        let report = codec.last_report().unwrap();
+        assert_eq!(report.invariant, Invariant::RemainingLength);
+        assert_eq!(report.packet, Bytes::from_static(b"\xc0\x80\x00"));
+        assert_eq!(
+            report.to_string(),
+            "dir=in invariant=remaining-length type=12 len=3 \
+             detail=\"remaining length 0 uses 2 bytes\" data=c08000"
+        );
+    }
+
+    #[test]
+    fn test_property_order() {
+        let codec = DiagnosticCodec::new(v5::codec::Codec::new());
+        // publish with user property before content type
+        let mut src =
+            BytesMut::from(&b"\x30\x0f\x00\x01t\x0b\x26\x00\x01k\x00\x01v\x03\x00\x01c"[..]);
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert_eq!(codec.mismatches(), 1);
+        assert_eq!(codec.last_report().unwrap().invariant, Invariant::RoundTrip);
+    }
+}
@@ -1,9 +1,11 @@
 use derive_more::{Display, From};
+#[cfg(feature = "runtime")]
 use ntex::util::Either;
-use std::io;
+use std::{error::Error, fmt, io};
 
 /// Errors which can occur when attempting to handle mqtt connection.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum MqttError<E> {
     /// Publish handler service error
     Service(E),
@@ -15,10 +17,74 @@ pub enum MqttError<E> {
     Disconnected,
     /// Server error
     ServerError(&'static str),
+    /// Connection transport is taken by handshake service
+    Upgraded,
+}
+
+impl<E> MqttError<E> {
+    /// Returns `true` if error is publish or control service error
+    pub fn is_service(&self) -> bool {
+        std::matches!(self, MqttError::Service(_))
+    }
+
+    /// Returns reference to service error
+    pub fn service_error(&self) -> Option<&E> {
+        match self {
+            MqttError::Service(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Returns reference to protocol error
+    pub fn protocol_error(&self) -> Option<&ProtocolError> {
+        match self {
+            MqttError::Protocol(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Convert service error with provided function
+    pub fn map_service<U, F>(self, f: F) -> MqttError<U>
+    where
+        F: FnOnce(E) -> U,
+    {
+        match self {
+            MqttError::Service(e) => MqttError::Service(f(e)),
+            MqttError::Protocol(e) => MqttError::Protocol(e),
+            MqttError::HandshakeTimeout => MqttError::HandshakeTimeout,
+            MqttError::Disconnected => MqttError::Disconnected,
+            MqttError::ServerError(e) => MqttError::ServerError(e),
+            MqttError::Upgraded => MqttError::Upgraded,
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for MqttError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::Service(e) => write!(f, "Service error: {}", e),
+            MqttError::Protocol(e) => write!(f, "Protocol error: {}", e),
+            MqttError::HandshakeTimeout => write!(f, "Handshake timeout"),
+            MqttError::Disconnected => write!(f, "Peer disconnected"),
+            MqttError::ServerError(e) => write!(f, "Server error: {}", e),
+            MqttError::Upgraded => write!(f, "Connection is upgraded"),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for MqttError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MqttError::Service(e) => Some(e),
+            MqttError::Protocol(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 /// Protocol level errors
 #[derive(Debug, Display, From)]
+#[non_exhaustive]
 pub enum ProtocolError {
     /// Mqtt parse error
     #[display(fmt = "Decode error: {:?}", _0)]
@@ -41,6 +107,12 @@ pub enum ProtocolError {
     /// Unknown topic alias
     #[display(fmt = "Unknown topic alias")]
     UnknownTopicAlias,
+    /// Retain is not supported by server
+    #[display(fmt = "Retain is not supported")]
+    RetainNotSupported,
+    /// Publish qos is above server maximum
+    #[display(fmt = "QoS is not supported")]
+    QosNotSupported,
     /// Keep alive timeout
     #[display(fmt = "Keep alive timeout")]
     KeepAliveTimeout,
@@ -49,12 +121,42 @@ pub enum ProtocolError {
     Io(io::Error),
 }
 
+impl Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProtocolError::Decode(e) => Some(e),
+            ProtocolError::Encode(e) => Some(e),
+            ProtocolError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl<E> From<ProtocolError> for MqttError<E> {
     fn from(err: ProtocolError) -> Self {
         MqttError::Protocol(err)
     }
 }
 
+impl<E> From<DecodeError> for MqttError<E> {
+    fn from(err: DecodeError) -> Self {
+        MqttError::Protocol(ProtocolError::Decode(err))
+    }
+}
+
+impl<E> From<EncodeError> for MqttError<E> {
+    fn from(err: EncodeError) -> Self {
+        MqttError::Protocol(ProtocolError::Encode(err))
+    }
+}
+
+impl<E> From<io::Error> for MqttError<E> {
+    fn from(err: io::Error) -> Self {
+        MqttError::Protocol(ProtocolError::Io(err))
+    }
+}
+
+#[cfg(feature = "runtime")]
 impl<E> From<Either<DecodeError, io::Error>> for MqttError<E> {
     fn from(err: Either<DecodeError, io::Error>) -> Self {
         match err {
@@ -64,6 +166,7 @@ impl<E> From<Either<DecodeError, io::Error>> for MqttError<E> {
     }
 }
 
+#[cfg(feature = "runtime")]
 impl<E> From<Either<EncodeError, io::Error>> for MqttError<E> {
     fn from(err: Either<EncodeError, io::Error>) -> Self {
         match err {
@@ -73,6 +176,7 @@ impl<E> From<Either<EncodeError, io::Error>> for MqttError<E> {
     }
 }
 
+#[cfg(feature = "runtime")]
 impl From<Either<DecodeError, io::Error>> for ProtocolError {
     fn from(err: Either<DecodeError, io::Error>) -> Self {
         match err {
@@ -83,6 +187,7 @@ impl From<Either<DecodeError, io::Error>> for ProtocolError {
 }
 
 #[derive(Debug, Display, From)]
+#[non_exhaustive]
 pub enum DecodeError {
     InvalidProtocol,
     InvalidLength,
@@ -91,14 +196,19 @@ pub enum DecodeError {
     ConnectReservedFlagSet,
     ConnAckReservedFlagSet,
     InvalidClientId,
+    InvalidTopic,
+    InvalidTopicFilter,
     UnsupportedPacketType,
     // MQTT v3 only
     PacketIdRequired,
     MaxSizeExceeded,
+    /// Peer packets consistently exceed decode time budget
+    DecodeBudgetExceeded,
     Utf8Error(std::str::Utf8Error),
 }
 
 #[derive(Copy, Clone, Debug, Display, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum EncodeError {
     InvalidLength,
     MalformedPacket,
@@ -106,6 +216,17 @@ pub enum EncodeError {
     UnsupportedVersion,
 }
 
+impl Error for DecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DecodeError::Utf8Error(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error for EncodeError {}
+
 impl PartialEq for DecodeError {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -117,9 +238,12 @@ impl PartialEq for DecodeError {
             (DecodeError::ConnectReservedFlagSet, DecodeError::ConnectReservedFlagSet) => true,
             (DecodeError::ConnAckReservedFlagSet, DecodeError::ConnAckReservedFlagSet) => true,
             (DecodeError::InvalidClientId, DecodeError::InvalidClientId) => true,
+            (DecodeError::InvalidTopic, DecodeError::InvalidTopic) => true,
+            (DecodeError::InvalidTopicFilter, DecodeError::InvalidTopicFilter) => true,
             (DecodeError::UnsupportedPacketType, DecodeError::UnsupportedPacketType) => true,
             (DecodeError::PacketIdRequired, DecodeError::PacketIdRequired) => true,
             (DecodeError::MaxSizeExceeded, DecodeError::MaxSizeExceeded) => true,
+            (DecodeError::DecodeBudgetExceeded, DecodeError::DecodeBudgetExceeded) => true,
             (DecodeError::MalformedPacket, DecodeError::MalformedPacket) => true,
             (DecodeError::Utf8Error(_), _) => false,
             _ => false,
@@ -128,6 +252,7 @@ impl PartialEq for DecodeError {
 }
 
 #[derive(Debug, Display, PartialEq)]
+#[non_exhaustive]
 pub enum SendPacketError {
     /// Encoder error
     Encode(EncodeError),
@@ -137,4 +262,46 @@ pub enum SendPacketError {
     /// Peer disconnected
     #[display(fmt = "Peer disconnected")]
     Disconnected,
+    /// Packet is not acknowledged in time, waiter is expired with `expire_inflight()`
+    #[display(fmt = "Packet acknowledgement is expired")]
+    Expired,
+    /// Packet is not allowed for connection role, e.g. `PINGREQ` from server
+    #[display(fmt = "Packet is not allowed")]
+    NotAllowed,
+}
+
+impl Error for SendPacketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SendPacketError::Encode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_source() {
+        let err: MqttError<io::Error> = DecodeError::InvalidLength.into();
+        assert!(!err.is_service());
+        assert_eq!(err.protocol_error().unwrap().to_string(), "Decode error: InvalidLength");
+        assert_eq!(err.to_string(), "Protocol error: Decode error: InvalidLength");
+        let src = err.source().unwrap().source().unwrap();
+        assert_eq!(src.downcast_ref::<DecodeError>(), Some(&DecodeError::InvalidLength));
+
+        let err: MqttError<io::Error> = io::Error::other("test").into();
+        let src = err.source().unwrap().source().unwrap();
+        assert_eq!(src.downcast_ref::<io::Error>().unwrap().to_string(), "test");
+
+        let err = MqttError::<io::Error>::Service(io::Error::other("service"));
+        assert!(err.is_service());
+        let err = err.map_service(|e| e.to_string());
+        assert_eq!(err.service_error().unwrap(), "service");
+
+        let err = SendPacketError::Encode(EncodeError::InvalidLength);
+        assert!(err.source().unwrap().is::<EncodeError>());
+    }
 }
@@ -0,0 +1,20 @@
+//! Per-connection codec extension
+use crate::error::{DecodeError, EncodeError};
+
+/// Connection codec extension
+///
+/// Extension is set by handshake service and gets applied to every packet
+/// after it is decoded and before it is encoded, `CONNECT` and `CONNACK`
+/// packets are not affected. Could be used for transparent payload compression
+/// or for vendor protocol extensions negotiated with `CONNECT` properties.
+pub trait CodecExtension<P> {
+    /// Transform inbound packet after decoding
+    fn decoded(&self, pkt: P) -> Result<P, DecodeError> {
+        Ok(pkt)
+    }
+
+    /// Transform outbound packet before encoding
+    fn encode(&self, pkt: P) -> Result<P, EncodeError> {
+        Ok(pkt)
+    }
+}
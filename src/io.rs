@@ -1,13 +1,22 @@
 //! Framed transport dispatcher
-use std::task::{Context, Poll};
-use std::{cell::RefCell, collections::VecDeque, future::Future, pin::Pin, rc::Rc, time};
-
-pub(crate) use ntex::framed::{DispatchItem, ReadTask, State, Timer, Write, WriteTask};
-
-use ntex::codec::{AsyncRead, AsyncWrite, Decoder, Encoder};
+use std::task::{Context, Poll, Waker};
+use std::{
+    cell::Cell, cell::RefCell, collections::VecDeque, future::Future, io, mem, pin::Pin,
+    rc::Rc, time,
+};
+
+pub(crate) use ntex::framed::{
+    DispatchItem, OnDisconnect, Read, ReadTask, State, Write, WriteTask,
+};
+
+use ntex::codec::{AsyncRead, AsyncWrite, Decoder, Encoder, ReadBuf};
+use ntex::rt::time::{sleep, sleep_until, Instant as RtInstant, Sleep};
 use ntex::service::{IntoService, Service};
 use ntex::util::Either;
 
+use crate::clock;
+pub(crate) use crate::clock::Timer;
+
 type Response<U> = <U as Encoder>::Item;
 
 pin_project_lite::pin_project! {
@@ -29,6 +38,18 @@ pin_project_lite::pin_project! {
         timer: Timer,
         updated: time::Instant,
         keepalive_timeout: u16,
+        keepalive: Rc<Cell<u16>>,
+        limits: BufferLimits,
+        activity: Rc<Activity>,
+        #[pin]
+        idle: Option<Sleep>,
+        disconnect_timeout: u16,
+        disconnect: Option<OnDisconnect>,
+        status: Rc<Cell<Option<ShutdownStatus>>>,
+        expired: Rc<Cell<bool>>,
+        shutdown_timeout: time::Duration,
+        #[pin]
+        shutdown: Option<Sleep>,
         #[pin]
         response: Option<S::Future>,
         response_idx: usize,
@@ -56,16 +77,235 @@ impl<T> ServiceResult<T> {
     }
 }
 
+/// Per-connection memory limits and transport idle timeouts, `0` means unlimited
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct BufferLimits {
+    /// max size of read buffer
+    pub(crate) max_read_buf: usize,
+    /// max size of read buffer, write buffer and queued responses
+    pub(crate) memory_budget: usize,
+    /// max time without reading any bytes from transport
+    pub(crate) read_timeout: time::Duration,
+    /// max time pending data is not written to transport
+    pub(crate) write_timeout: time::Duration,
+}
+
+impl BufferLimits {
+    fn is_enabled(&self) -> bool {
+        self.max_read_buf != 0 || self.memory_budget != 0
+    }
+
+    fn check(&self, read: &Read<'_>, write: &Write<'_>, queued: usize) -> Option<&'static str> {
+        let read_buf = read.with_buf(|buf| buf.len());
+        if self.max_read_buf != 0 && read_buf > self.max_read_buf {
+            return Some("Read buffer size limit is exceeded");
+        }
+        if self.memory_budget != 0 {
+            let usage = read_buf + write.with_buf(|buf| buf.len()) + queued;
+            if usage > self.memory_budget {
+                return Some("Connection memory budget is exceeded");
+            }
+        }
+        None
+    }
+
+    fn is_idle_enabled(&self) -> bool {
+        self.read_timeout != time::Duration::ZERO || self.write_timeout != time::Duration::ZERO
+    }
+
+    /// Check transport idle timeouts.
+    ///
+    /// Returns time of the next check or error if timeout is exceeded.
+    fn check_idle(
+        &self,
+        activity: &Activity,
+        now: time::Instant,
+    ) -> Result<time::Instant, &'static str> {
+        let mut next = now + time::Duration::from_secs(3600);
+        if self.read_timeout != time::Duration::ZERO {
+            let expire = activity.read.get() + self.read_timeout;
+            if expire <= now {
+                return Err("Read idle timeout");
+            }
+            next = std::cmp::min(next, expire);
+        }
+        if self.write_timeout != time::Duration::ZERO {
+            let expire = match activity.write_blocked.get() {
+                Some(since) => since + self.write_timeout,
+                None => now + self.write_timeout,
+            };
+            if expire <= now {
+                return Err("Write idle timeout");
+            }
+            next = std::cmp::min(next, expire);
+        }
+        Ok(next)
+    }
+}
+
+/// Result of connection transport shutdown
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShutdownStatus {
+    /// Outstanding data is flushed, write side is closed (TLS `close_notify`
+    /// is sent for TLS connections) and peer closed connection.
+    Clean,
+    /// Peer did not close connection within disconnect timeout.
+    Timeout,
+    /// Io error occurred, or connection got closed before outstanding
+    /// data has been written.
+    Error,
+}
+
+/// Waiters for outbound data to be written to the transport
+#[derive(Default)]
+pub(crate) struct FlushWaiters {
+    waiters: RefCell<Vec<Waker>>,
+    closed: Cell<bool>,
+}
+
+impl FlushWaiters {
+    /// Check if write buffer is drained, register waiter otherwise.
+    ///
+    /// Returns `false` if transport is closed before buffer is drained.
+    pub(crate) fn poll_flushed(&self, state: &State, cx: &mut Context<'_>) -> Poll<bool> {
+        if state.write().with_buf(|buf| buf.is_empty()) {
+            Poll::Ready(true)
+        } else if self.closed.get() || state.is_io_err() {
+            Poll::Ready(false)
+        } else {
+            self.waiters.borrow_mut().push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn wake(&self) {
+        for waker in self.waiters.borrow_mut().drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn close(&self) {
+        self.closed.set(true);
+        self.wake();
+    }
+}
+
+/// Transport activity of a connection
+pub(crate) struct Activity {
+    /// time of last read of non-empty data
+    read: Cell<time::Instant>,
+    /// time since pending data could not be written
+    write_blocked: Cell<Option<time::Instant>>,
+    /// peer closed connection
+    eof: Cell<bool>,
+    /// io operation failed
+    failed: Cell<bool>,
+    /// waiters for write buffer flush
+    flush: RefCell<Rc<FlushWaiters>>,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            read: Cell::new(clock::now()),
+            write_blocked: Cell::new(None),
+            eof: Cell::new(false),
+            failed: Cell::new(false),
+            flush: RefCell::new(Rc::default()),
+        }
+    }
+
+    fn shutdown_status(&self, state: &State) -> ShutdownStatus {
+        let flushed = state.write().with_buf(|buf| buf.is_empty());
+        if self.failed.get() || !flushed {
+            ShutdownStatus::Error
+        } else if self.eof.get() {
+            ShutdownStatus::Clean
+        } else {
+            ShutdownStatus::Timeout
+        }
+    }
+
+    fn record<T>(&self, result: &Poll<io::Result<T>>) {
+        if let Poll::Ready(Err(_)) = result {
+            self.failed.set(true);
+        }
+    }
+}
+
+/// Io stream wrapper, records transport activity
+struct ActivityIo<T> {
+    io: T,
+    activity: Rc<Activity>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ActivityIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.io).poll_read(cx, buf);
+        if let Poll::Ready(Ok(_)) = result {
+            if buf.filled().len() > filled {
+                self.activity.read.set(clock::now());
+            } else if buf.remaining() > 0 {
+                self.activity.eof.set(true);
+            }
+        }
+        self.activity.record(&result);
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ActivityIo<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.io).poll_write(cx, buf);
+        match result {
+            Poll::Ready(Ok(n)) if n > 0 => self.activity.write_blocked.set(None),
+            Poll::Pending if self.activity.write_blocked.get().is_none() => {
+                self.activity.write_blocked.set(Some(clock::now()))
+            }
+            _ => (),
+        }
+        self.activity.record(&result);
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.io).poll_flush(cx);
+        if let Poll::Ready(Ok(_)) = result {
+            self.activity.flush.borrow().wake();
+        }
+        self.activity.record(&result);
+        result
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.io).poll_shutdown(cx);
+        self.activity.record(&result);
+        result
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 enum IoDispatcherState {
     Processing,
     Stop,
+    Disconnect,
     Shutdown,
 }
 
 pub(crate) enum IoDispatcherError<S, U> {
     None,
     KeepAlive,
+    Overflow(&'static str),
+    Timeout(&'static str),
     Encoder(U),
     Service(S),
 }
@@ -89,6 +329,16 @@ impl<E1, E2: std::fmt::Debug> IoDispatcherError<E1, E2> {
                 *self = IoDispatcherError::None;
                 Some(DispatchItem::KeepAliveTimeout)
             }
+            IoDispatcherError::Overflow(msg) => {
+                let err = io::Error::other(*msg);
+                *self = IoDispatcherError::None;
+                Some(DispatchItem::IoError(err))
+            }
+            IoDispatcherError::Timeout(msg) => {
+                let err = io::Error::new(io::ErrorKind::TimedOut, *msg);
+                *self = IoDispatcherError::None;
+                Some(DispatchItem::IoError(err))
+            }
             IoDispatcherError::Encoder(_) => {
                 let err = std::mem::replace(self, IoDispatcherError::None);
                 match err {
@@ -120,7 +370,8 @@ where
     {
         let updated = timer.now();
         let keepalive_timeout: u16 = 30;
-        let io = Rc::new(RefCell::new(io));
+        let activity = Rc::new(Activity::new());
+        let io = Rc::new(RefCell::new(ActivityIo { io, activity: activity.clone() }));
 
         // register keepalive timer
         let expire = updated + time::Duration::from_secs(keepalive_timeout as u64);
@@ -147,6 +398,16 @@ where
             timer,
             updated,
             keepalive_timeout,
+            keepalive: Rc::new(Cell::new(keepalive_timeout)),
+            limits: BufferLimits::default(),
+            activity,
+            idle: None,
+            disconnect_timeout: 1,
+            disconnect: None,
+            status: Rc::default(),
+            expired: Rc::default(),
+            shutdown_timeout: time::Duration::ZERO,
+            shutdown: None,
         }
     }
 
@@ -166,10 +427,30 @@ where
         }
 
         self.keepalive_timeout = timeout;
+        self.keepalive.set(timeout);
 
         self
     }
 
+    /// Use shared keep-alive timeout.
+    ///
+    /// Timeout could be changed while dispatcher is running, keep-alive timer
+    /// gets restarted with new timeout.
+    pub(crate) fn keepalive(mut self, keepalive: Rc<Cell<u16>>) -> Self {
+        let timeout = keepalive.get();
+        self.keepalive = keepalive;
+        self.keepalive_timeout(timeout)
+    }
+
+    /// Set per-connection buffer limits.
+    ///
+    /// Connection gets closed with io error if limits are exceeded.
+    /// By default limits are disabled.
+    pub(crate) fn buffer_limits(mut self, limits: BufferLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     /// Set connection disconnect timeout in milliseconds.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
@@ -178,8 +459,46 @@ where
     /// To disable timeout set value to 0.
     ///
     /// By default disconnect timeout is set to 1 seconds.
-    pub(crate) fn disconnect_timeout(self, val: u16) -> Self {
+    pub(crate) fn disconnect_timeout(mut self, val: u16) -> Self {
         self.state.set_disconnect_timeout(val);
+        self.disconnect_timeout = val;
+        self
+    }
+
+    /// Use shared transport shutdown status.
+    ///
+    /// Status is set after io tasks are completed, before service shutdown.
+    pub(crate) fn shutdown_status(mut self, status: Rc<Cell<Option<ShutdownStatus>>>) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Use shared keep-alive expiry flag.
+    ///
+    /// Flag is set if connection is stopped because of keep-alive timeout.
+    pub(crate) fn keepalive_expired(mut self, expired: Rc<Cell<bool>>) -> Self {
+        self.expired = expired;
+        self
+    }
+
+    /// Use shared flush waiters.
+    ///
+    /// Waiters are woken up when written data is flushed to io stream
+    /// and when io tasks are completed.
+    pub(crate) fn flush_waiters(self, waiters: Rc<FlushWaiters>) -> Self {
+        *self.activity.flush.borrow_mut() = waiters;
+        self
+    }
+
+    /// Set in-flight responses shutdown timeout.
+    ///
+    /// Defines how long dispatcher waits for in-flight service responses
+    /// after it has been stopped. Responses that are not ready within this
+    /// time get dropped and connection proceeds to shutdown.
+    ///
+    /// To disable timeout set value to 0. By default timeout is disabled.
+    pub(crate) fn shutdown_timeout(mut self, timeout: time::Duration) -> Self {
+        self.shutdown_timeout = timeout;
         self
     }
 }
@@ -202,6 +521,12 @@ where
     ) {
         let idx = response_idx.wrapping_sub(self.base);
 
+        // response has been dropped by shutdown timeout
+        if idx >= self.queue.len() {
+            log::trace!("in-flight response is dropped, ignoring");
+            return;
+        }
+
         // handle first response
         if idx == 0 {
             let _ = self.queue.pop_front();
@@ -272,8 +597,76 @@ where
                             // service is ready, wake io read task
                             read.resume();
 
+                            // keep-alive timeout has been changed
+                            let timeout = this.keepalive.get();
+                            if timeout != *this.keepalive_timeout {
+                                let prev = *this.updated
+                                    + time::Duration::from_secs(*this.keepalive_timeout as u64);
+                                if timeout == 0 {
+                                    this.timer.unregister(prev, this.state);
+                                } else {
+                                    let updated = this.timer.now();
+                                    this.timer.register(
+                                        updated + time::Duration::from_secs(timeout as u64),
+                                        prev,
+                                        this.state,
+                                    );
+                                    *this.updated = updated;
+                                }
+                                *this.keepalive_timeout = timeout;
+                            }
+
+                            // check buffer limits
+                            if this.limits.is_enabled() && !this.state.is_dispatcher_stopped() {
+                                let queued = this.inner.borrow().queue.len()
+                                    * mem::size_of::<S::Future>();
+                                if let Some(msg) = this.limits.check(&read, &write, queued) {
+                                    log::trace!("{}, stopping dispatcher", msg);
+                                    read.with_buf(|buf| buf.clear());
+                                    let mut inner = this.inner.borrow_mut();
+                                    if inner.error.is_none() {
+                                        inner.error = Some(IoDispatcherError::Overflow(msg));
+                                    }
+                                    this.state.dispatcher_stopped();
+                                }
+                            }
+
+                            // check transport idle timeouts
+                            if this.limits.is_idle_enabled()
+                                && !this.state.is_dispatcher_stopped()
+                            {
+                                match this.limits.check_idle(this.activity, clock::now()) {
+                                    Ok(next) => {
+                                        let next = RtInstant::from_std(next);
+                                        if let Some(mut idle) = this.idle.as_mut().as_pin_mut()
+                                        {
+                                            idle.as_mut().reset(next);
+                                            let _ = idle.poll(cx);
+                                        } else {
+                                            this.idle.set(Some(sleep_until(next)));
+                                            let _ = this
+                                                .idle
+                                                .as_mut()
+                                                .as_pin_mut()
+                                                .unwrap()
+                                                .poll(cx);
+                                        }
+                                    }
+                                    Err(msg) => {
+                                        log::trace!("{}, stopping dispatcher", msg);
+                                        let mut inner = this.inner.borrow_mut();
+                                        if inner.error.is_none() {
+                                            inner.error = Some(IoDispatcherError::Timeout(msg));
+                                        }
+                                        this.state.dispatcher_stopped();
+                                    }
+                                }
+                            }
+
                             // check keepalive timeout
-                            if this.state.is_keepalive() {
+                            if this.timer.is_expired(this.state) {
+                                this.expired.set(true);
+
                                 log::trace!("keepalive timeout");
                                 let mut inner = this.inner.borrow_mut();
                                 if inner.error.is_none() {
@@ -462,12 +855,51 @@ where
 
                 if this.inner.borrow().queue.is_empty() {
                     this.state.shutdown_io();
-                    *this.st = IoDispatcherState::Shutdown;
-                    self.poll(cx)
-                } else {
-                    this.state.register_dispatcher(cx.waker());
-                    Poll::Pending
+                    *this.st = IoDispatcherState::Disconnect;
+                    return self.poll(cx);
+                }
+
+                // check in-flight responses timeout
+                if *this.shutdown_timeout != time::Duration::ZERO {
+                    if this.shutdown.is_none() {
+                        this.shutdown.set(Some(sleep(*this.shutdown_timeout)));
+                    }
+                    if this.shutdown.as_pin_mut().unwrap().poll(cx).is_ready() {
+                        log::trace!("in-flight responses are not completed in time, dropping");
+                        this.response.set(None);
+
+                        let mut inner = this.inner.borrow_mut();
+                        inner.base = inner.base.wrapping_add(inner.queue.len());
+                        inner.queue.clear();
+                        drop(inner);
+
+                        this.state.shutdown_io();
+                        *this.st = IoDispatcherState::Disconnect;
+                        return self.poll(cx);
+                    }
                 }
+
+                this.state.register_dispatcher(cx.waker());
+                Poll::Pending
+            }
+            // wait for io tasks, write task flushes data, closes write side
+            // and drains read side until peer closes connection
+            IoDispatcherState::Disconnect => {
+                if *this.disconnect_timeout != 0 && !this.state.is_io_err() {
+                    let state = &*this.state;
+                    let disconnect =
+                        this.disconnect.get_or_insert_with(|| state.on_disconnect());
+                    if disconnect.poll_ready(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                }
+
+                let status = this.activity.shutdown_status(this.state);
+                log::trace!("io shutdown is completed: {:?}", status);
+                this.status.set(Some(status));
+                this.activity.flush.borrow().close();
+                *this.st = IoDispatcherState::Shutdown;
+                self.poll(cx)
             }
             // shutdown service
             IoDispatcherState::Shutdown => {
@@ -524,7 +956,8 @@ mod tests {
             let timer = Timer::with(time::Duration::from_secs(1));
             let keepalive_timeout = 30;
             let updated = timer.now();
-            let io = Rc::new(RefCell::new(io));
+            let activity = Rc::new(Activity::new());
+            let io = Rc::new(RefCell::new(ActivityIo { io, activity: activity.clone() }));
             ntex::rt::spawn(ReadTask::new(io.clone(), state.clone()));
             ntex::rt::spawn(WriteTask::new(io.clone(), state.clone()));
 
@@ -545,6 +978,16 @@ mod tests {
                 codec,
                 updated,
                 keepalive_timeout,
+                keepalive: Rc::new(Cell::new(keepalive_timeout)),
+                limits: BufferLimits::default(),
+                activity,
+                idle: None,
+                disconnect_timeout: 1,
+                disconnect: None,
+                status: Rc::default(),
+                expired: Rc::default(),
+                shutdown_timeout: time::Duration::ZERO,
+                shutdown: None,
             }
         }
     }
@@ -657,6 +1100,245 @@ mod tests {
         assert!(client.is_server_dropped());
     }
 
+    #[ntex::test]
+    async fn test_shutdown_timeout() {
+        let (client, server) = Io::create();
+        client.remote_buffer_cap(1024);
+        client.write("GET /test HTTP/1\r\n\r\n");
+
+        let st = State::new();
+        let disp = Dispatcher::new(
+            server,
+            BytesCodec,
+            st.clone(),
+            ntex::fn_service(|msg: DispatchItem<BytesCodec>| async move {
+                if let DispatchItem::Item(msg) = msg {
+                    sleep(time::Duration::from_secs(5)).await;
+                    Ok::<_, ()>(Some(msg.freeze()))
+                } else {
+                    Ok(None)
+                }
+            }),
+        )
+        .disconnect_timeout(25)
+        .shutdown_timeout(time::Duration::from_millis(100));
+
+        let done = Rc::new(Cell::new(false));
+        let done2 = done.clone();
+        ntex::rt::spawn(async move {
+            let _ = disp.await;
+            done2.set(true);
+        });
+        sleep(time::Duration::from_millis(50)).await;
+
+        st.close();
+        sleep(time::Duration::from_millis(50)).await;
+        assert!(!done.get());
+
+        // pending response is dropped
+        sleep(time::Duration::from_millis(150)).await;
+        assert!(done.get());
+        assert!(client.is_server_dropped());
+        assert!(client.read_any().is_empty());
+    }
+
+    #[ntex::test]
+    async fn test_shutdown_status() {
+        for clean in &[false, true] {
+            let (client, server) = Io::create();
+            client.remote_buffer_cap(1024);
+            client.write("GET /test HTTP/1\r\n\r\n");
+
+            let st = State::new();
+            let status = Rc::new(Cell::new(None));
+            let disp = Dispatcher::new(
+                server,
+                BytesCodec,
+                st.clone(),
+                ntex::fn_service(|msg: DispatchItem<BytesCodec>| async move {
+                    if let DispatchItem::Item(msg) = msg {
+                        Ok::<_, ()>(Some(msg.freeze()))
+                    } else {
+                        Ok(None)
+                    }
+                }),
+            )
+            .disconnect_timeout(100)
+            .shutdown_status(status.clone());
+            ntex::rt::spawn(async move {
+                let _ = disp.await;
+            });
+            sleep(time::Duration::from_millis(25)).await;
+
+            // response is flushed before write side is closed
+            st.close();
+            sleep(time::Duration::from_millis(25)).await;
+            assert_eq!(client.read_any(), b"GET /test HTTP/1\r\n\r\n".as_ref());
+            assert!(client.is_closed());
+            assert_eq!(status.get(), None);
+
+            if *clean {
+                client.close().await;
+                assert_eq!(status.get(), Some(ShutdownStatus::Clean));
+            } else {
+                // peer does not close connection
+                sleep(time::Duration::from_millis(100)).await;
+                assert_eq!(status.get(), Some(ShutdownStatus::Timeout));
+            }
+        }
+    }
+
+    #[ntex::test]
+    async fn test_keepalive_virtual_clock() {
+        tokio::time::pause();
+
+        let (client, server) = Io::create();
+        client.remote_buffer_cap(1024);
+
+        let ka = Rc::new(Cell::new(false));
+        let ka2 = ka.clone();
+        let expired = Rc::new(Cell::new(false));
+        let disp = Dispatcher::new(
+            server,
+            BytesCodec,
+            State::new(),
+            ntex::fn_service(move |msg: DispatchItem<BytesCodec>| {
+                if let DispatchItem::KeepAliveTimeout = msg {
+                    ka2.set(true);
+                }
+                async { Ok::<_, ()>(None) }
+            }),
+        )
+        .keepalive_timeout(5)
+        .keepalive_expired(expired.clone());
+        ntex::rt::spawn(async move {
+            let _ = disp.await;
+        });
+
+        tokio::time::advance(time::Duration::from_secs(4)).await;
+        assert!(!ka.get());
+        assert!(!expired.get());
+
+        for _ in 0..6 {
+            tokio::time::advance(time::Duration::from_secs(1)).await;
+        }
+        assert!(ka.get());
+        assert!(expired.get());
+    }
+
+    #[ntex::test]
+    async fn test_memory_budget() {
+        let (client, server) = Io::create();
+        client.remote_buffer_cap(0);
+        client.write("GET /test HTTP/1\r\n\r\n");
+
+        let overflow = Rc::new(Cell::new(false));
+        let overflow2 = overflow.clone();
+        let st = State::new();
+        let disp = Dispatcher::new(
+            server,
+            BytesCodec,
+            st.clone(),
+            ntex::fn_service(move |msg: DispatchItem<BytesCodec>| {
+                let overflow = overflow2.clone();
+                async move {
+                    match msg {
+                        DispatchItem::Item(msg) => Ok::<_, ()>(Some(msg.freeze())),
+                        DispatchItem::IoError(_) => {
+                            overflow.set(true);
+                            Ok(None)
+                        }
+                        _ => panic!(),
+                    }
+                }
+            }),
+        )
+        .buffer_limits(BufferLimits { memory_budget: 32, ..Default::default() });
+        ntex::rt::spawn(async move {
+            let _ = disp.disconnect_timeout(25).await;
+        });
+        sleep(time::Duration::from_millis(50)).await;
+        assert!(!overflow.get());
+
+        // peer does not read data, so write buffer exceeds budget
+        let data = Bytes::from_static(b"0123456789abcdef");
+        assert!(st.write().encode(data, &BytesCodec).is_ok());
+        st.wake_dispatcher();
+        sleep(time::Duration::from_millis(50)).await;
+        assert!(overflow.get());
+    }
+
+    #[ntex::test]
+    async fn test_idle_timeouts() {
+        let (client, server) = Io::create();
+        client.remote_buffer_cap(0);
+
+        let timeout = Rc::new(Cell::new(None));
+        let timeout2 = timeout.clone();
+        let st = State::new();
+        let disp = Dispatcher::new(
+            server,
+            BytesCodec,
+            st.clone(),
+            ntex::fn_service(move |msg: DispatchItem<BytesCodec>| {
+                let timeout = timeout2.clone();
+                async move {
+                    match msg {
+                        DispatchItem::Item(msg) => Ok::<_, ()>(Some(msg.freeze())),
+                        DispatchItem::IoError(err) => {
+                            timeout.set(Some(err.to_string()));
+                            Ok(None)
+                        }
+                        _ => panic!(),
+                    }
+                }
+            }),
+        )
+        .buffer_limits(BufferLimits {
+            read_timeout: time::Duration::from_millis(150),
+            write_timeout: time::Duration::from_millis(50),
+            ..Default::default()
+        });
+        ntex::rt::spawn(async move {
+            let _ = disp.disconnect_timeout(25).await;
+        });
+
+        // incoming data resets read timeout
+        sleep(time::Duration::from_millis(100)).await;
+        client.write("GET /test HTTP/1\r\n\r\n");
+        sleep(time::Duration::from_millis(25)).await;
+        assert!(timeout.take().is_none());
+
+        // peer does not read response
+        sleep(time::Duration::from_millis(75)).await;
+        assert_eq!(timeout.take().as_deref(), Some("Write idle timeout"));
+
+        let (client, server) = Io::create();
+        client.remote_buffer_cap(1024);
+        let timeout2 = timeout.clone();
+        let disp = Dispatcher::new(
+            server,
+            BytesCodec,
+            State::new(),
+            ntex::fn_service(move |msg: DispatchItem<BytesCodec>| {
+                if let DispatchItem::IoError(err) = msg {
+                    timeout2.set(Some(err.to_string()));
+                }
+                async { Ok::<_, ()>(None) }
+            }),
+        )
+        .buffer_limits(BufferLimits {
+            read_timeout: time::Duration::from_millis(50),
+            ..Default::default()
+        });
+        ntex::rt::spawn(async move {
+            let _ = disp.disconnect_timeout(25).await;
+        });
+        sleep(time::Duration::from_millis(100)).await;
+        assert_eq!(timeout.take().as_deref(), Some("Read idle timeout"));
+        assert!(client.is_server_dropped());
+    }
+
     #[ntex::test]
     async fn test_err_in_service() {
         let (client, server) = Io::create();
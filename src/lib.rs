@@ -3,27 +3,117 @@
 #![allow(unused_imports)]
 
 //! MQTT Client/Server framework
+//!
+//! With `default-features = false` only packet types and codecs (`v3::codec`,
+//! `v5::codec`, `sn::codec`) and sans-IO client state machines (`v3::proto`,
+//! `v5::proto`) are built, they depend on `ntex-bytes` and `ntex-codec` but
+//! not on ntex runtime. `no_std` targets are not supported.
+//!
+//! `wasm` feature provides v5 client for `wasm32-unknown-unknown` target,
+//! it uses browser `WebSocket` api as transport (`v5::wasm`).
+//!
+//! `diagnostics` feature provides codec wrapper that validates packet invariants
+//! on every encode and decode (`diagnostics::DiagnosticCodec`).
+//!
+//! `proptest` feature provides strategies for packet types (`v3::codec::strategy`,
+//! `v5::codec::strategy`) for use in downstream property tests.
 
 #[macro_use]
 mod topic;
 #[macro_use]
 mod utils;
 
+pub mod capture;
+#[cfg(feature = "runtime")]
+pub mod client_id;
+#[cfg(feature = "runtime")]
+pub mod conformance;
+#[cfg(feature = "runtime")]
+pub mod connect;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostics;
 pub mod error;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod reason;
+pub mod sn;
+#[cfg(feature = "runtime")]
+pub mod sys;
+#[cfg(feature = "runtime")]
+pub mod testing;
 pub mod v3;
 pub mod v5;
 
+#[cfg(feature = "runtime")]
+mod ack;
+#[cfg(feature = "runtime")]
+mod actor;
+#[cfg(feature = "runtime")]
+mod alpn;
+#[cfg(feature = "runtime")]
+mod backlog;
+mod budget;
+#[cfg(feature = "runtime")]
+mod clock;
+#[cfg(feature = "runtime")]
+mod config;
+#[cfg(feature = "runtime")]
+mod dedup;
+mod extension;
+#[cfg(feature = "runtime")]
 mod io;
+#[cfg(feature = "runtime")]
+mod ordered;
+#[cfg(feature = "runtime")]
+mod quota;
+#[cfg(feature = "runtime")]
+mod registry;
+#[cfg(feature = "runtime")]
+mod rewrite;
+#[cfg(feature = "runtime")]
+mod semaphore;
+#[cfg(feature = "runtime")]
 mod server;
+#[cfg(feature = "runtime")]
 mod service;
+#[cfg(feature = "runtime")]
 mod session;
+#[cfg(feature = "runtime")]
+mod sni;
+#[cfg(feature = "runtime")]
+mod timeout;
 pub mod types;
+#[cfg(feature = "runtime")]
 mod version;
 
+#[cfg(feature = "runtime")]
+pub use self::actor::Addr;
+#[cfg(feature = "runtime")]
+pub use self::alpn::{AlpnRouter, AlpnRouterService, ALPN_HTTP11, ALPN_MQTT};
+#[cfg(feature = "runtime")]
+pub use self::backlog::SlowConsumerPolicy;
+pub use self::budget::DecodeStats;
+#[cfg(feature = "runtime")]
+pub use self::config::{ConfigHandle, ListenerConfig};
 pub use self::error::MqttError;
+pub use self::extension::CodecExtension;
+#[cfg(feature = "runtime")]
+pub use self::io::ShutdownStatus;
+#[cfg(feature = "runtime")]
+pub use self::registry::SinkRegistry;
+#[cfg(feature = "runtime")]
+pub use self::rewrite::TopicRewrite;
+#[cfg(feature = "runtime")]
 pub use self::server::MqttServer;
-pub use self::session::Session;
-pub use self::topic::{Level as TopicLevel, Topic};
+#[cfg(feature = "runtime")]
+pub use self::session::{Session, SessionEnd};
+#[cfg(feature = "runtime")]
+pub use self::sni::{SniRouter, SniRouterService};
+pub use self::topic::{Level as TopicLevel, Topic, TopicFilter, TopicName};
+pub use self::types::{InflightPacket, InflightType, RetainPolicy};
+
+#[cfg(feature = "runtime")]
+pub use self::clock::Timer;
 
 // http://www.iana.org/assignments/service-names-port-numbers/service-names-port-numbers.xhtml
 pub const TCP_PORT: u16 = 1883;
@@ -0,0 +1,286 @@
+//! Per-topic ordering of publish handling
+use std::collections::{BTreeMap, BTreeSet};
+use std::task::{Context, Poll, Waker};
+use std::{cell::Cell, cell::RefCell, future::Future, pin::Pin, rc::Rc};
+use std::{collections::hash_map::DefaultHasher, hash::Hash, hash::Hasher};
+
+use ntex::service::Service;
+
+/// Key of ordered lane
+pub(crate) trait OrderKey {
+    fn order_key(&self) -> &str;
+}
+
+impl OrderKey for crate::v3::Publish {
+    fn order_key(&self) -> &str {
+        self.publish_topic()
+    }
+}
+
+impl OrderKey for crate::v5::Publish {
+    fn order_key(&self) -> &str {
+        self.publish_topic()
+    }
+}
+
+/// Lane serializes handler invocations, tickets are served in order of issue
+#[derive(Default)]
+struct Lane {
+    next: Cell<u64>,
+    serving: Cell<u64>,
+    cancelled: RefCell<BTreeSet<u64>>,
+    /// waker of pending ticket, one per ticket
+    waiters: RefCell<BTreeMap<u64, Waker>>,
+}
+
+impl Lane {
+    fn ticket(&self) -> u64 {
+        let ticket = self.next.get();
+        self.next.set(ticket + 1);
+        ticket
+    }
+
+    fn release(&self) {
+        let mut serving = self.serving.get() + 1;
+        let mut cancelled = self.cancelled.borrow_mut();
+        while cancelled.remove(&serving) {
+            serving += 1;
+        }
+        self.serving.set(serving);
+        if let Some(waker) = self.waiters.borrow_mut().remove(&serving) {
+            waker.wake();
+        }
+    }
+
+    fn register(&self, ticket: u64, waker: &Waker) {
+        let mut waiters = self.waiters.borrow_mut();
+        match waiters.get_mut(&ticket) {
+            Some(w) if w.will_wake(waker) => (),
+            Some(w) => *w = waker.clone(),
+            None => {
+                waiters.insert(ticket, waker.clone());
+            }
+        }
+    }
+
+    fn cancel(&self, ticket: u64) {
+        self.waiters.borrow_mut().remove(&ticket);
+        if ticket == self.serving.get() {
+            self.release();
+        } else {
+            self.cancelled.borrow_mut().insert(ticket);
+        }
+    }
+}
+
+/// Publish service wrapper
+///
+/// If ordering is enabled, publishes with the same topic are handled one
+/// after another in order of arrival. Topics are hashed to a fixed number of
+/// lanes, so unrelated topics could share a lane.
+pub(crate) struct Ordered<S> {
+    service: Rc<S>,
+    lanes: Option<Rc<[Lane]>>,
+}
+
+impl<S> Ordered<S> {
+    /// Create service wrapper, `0` lanes disables ordering
+    pub(crate) fn new(service: S, lanes: usize) -> Self {
+        let lanes = if lanes == 0 {
+            None
+        } else {
+            Some((0..lanes).map(|_| Lane::default()).collect::<Vec<_>>().into())
+        };
+        Ordered { service: Rc::new(service), lanes }
+    }
+}
+
+impl<S> Service for Ordered<S>
+where
+    S: Service,
+    S::Request: OrderKey,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = OrderedResponse<S>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        if let Some(ref lanes) = self.lanes {
+            let mut hasher = DefaultHasher::new();
+            req.order_key().hash(&mut hasher);
+            let idx = (hasher.finish() % lanes.len() as u64) as usize;
+            let ticket = lanes[idx].ticket();
+
+            OrderedResponse {
+                fut: None,
+                req: Some((req, self.service.clone())),
+                lane: Some((lanes.clone(), idx, ticket)),
+            }
+        } else {
+            OrderedResponse { fut: Some(self.service.call(req)), req: None, lane: None }
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Publish service response future
+    pub(crate) struct OrderedResponse<S: Service> {
+        #[pin]
+        fut: Option<S::Future>,
+        req: Option<(S::Request, Rc<S>)>,
+        lane: Option<(Rc<[Lane]>, usize, u64)>,
+    }
+
+    impl<S: Service> PinnedDrop for OrderedResponse<S> {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            if let Some((lanes, idx, ticket)) = this.lane.take() {
+                if this.req.is_some() {
+                    lanes[idx].cancel(ticket)
+                } else {
+                    lanes[idx].release()
+                }
+            }
+        }
+    }
+}
+
+impl<S: Service> Future for OrderedResponse<S> {
+    type Output = Result<S::Response, S::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+
+        if this.req.is_some() {
+            let (lanes, idx, ticket) = this.lane.as_ref().unwrap();
+            let lane = &lanes[*idx];
+            if lane.serving.get() != *ticket {
+                lane.register(*ticket, cx.waker());
+                return Poll::Pending;
+            }
+
+            // handler readiness could change while request waited for its turn
+            let srv = &this.req.as_ref().unwrap().1;
+            match srv.poll_ready(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => {
+                    this.req.take();
+                    lane.release();
+                    this.lane.take();
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+            let (req, srv) = this.req.take().unwrap();
+            this.fut.set(Some(srv.call(req)));
+        }
+
+        let fut =
+            this.fut.as_mut().as_pin_mut().expect("OrderedResponse polled after completion");
+        match fut.poll(cx) {
+            Poll::Ready(res) => {
+                this.fut.set(None);
+                if let Some((lanes, idx, _)) = this.lane.take() {
+                    lanes[idx].release();
+                }
+                Poll::Ready(res)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lane() {
+        let lane = Lane::default();
+        let t0 = lane.ticket();
+        let t1 = lane.ticket();
+        let t2 = lane.ticket();
+        let t3 = lane.ticket();
+        assert_eq!(lane.serving.get(), t0);
+
+        // cancelled tickets are skipped
+        lane.cancel(t1);
+        lane.cancel(t2);
+        assert_eq!(lane.serving.get(), t0);
+        lane.release();
+        assert_eq!(lane.serving.get(), t3);
+
+        let t4 = lane.ticket();
+        lane.cancel(t3);
+        assert_eq!(lane.serving.get(), t4);
+
+        // pending ticket keeps single waker
+        let t5 = lane.ticket();
+        let waker = futures::task::noop_waker();
+        lane.register(t5, &waker);
+        lane.register(t5, &waker);
+        assert_eq!(lane.waiters.borrow().len(), 1);
+        lane.release();
+        assert_eq!(lane.serving.get(), t5);
+        assert!(lane.waiters.borrow().is_empty());
+    }
+
+    impl OrderKey for &'static str {
+        fn order_key(&self) -> &str {
+            self
+        }
+    }
+
+    struct Srv(Cell<bool>, Cell<usize>);
+
+    impl Service for Srv {
+        type Request = &'static str;
+        type Response = ();
+        type Error = ();
+        type Future = ntex::util::Ready<(), ()>;
+
+        fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            if self.0.get() {
+                Poll::Ready(Ok(()))
+            } else {
+                Poll::Pending
+            }
+        }
+
+        fn call(&self, _: &'static str) -> Self::Future {
+            self.1.set(self.1.get() + 1);
+            ntex::util::Ready::Ok(())
+        }
+    }
+
+    #[test]
+    fn test_ordered_readiness() {
+        let srv = Ordered::new(Srv(Cell::new(true), Cell::new(0)), 1);
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut f1 = Box::pin(srv.call("topic"));
+        let mut f2 = Box::pin(srv.call("topic"));
+
+        // handler is not called until it is ready
+        srv.service.0.set(false);
+        assert!(f1.as_mut().poll(&mut cx).is_pending());
+        assert!(f2.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(srv.service.1.get(), 0);
+
+        srv.service.0.set(true);
+        assert!(f2.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(f1.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+        assert_eq!(f2.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+        assert_eq!(srv.service.1.get(), 2);
+    }
+}
@@ -0,0 +1,211 @@
+//! MQTT over QUIC transport (experimental)
+//!
+//! Each QUIC connection carries one mqtt session, session uses first
+//! bidirectional stream of the connection. Client opens stream, server
+//! accepts it. TLS and ALPN settings are part of quinn endpoint configuration.
+//!
+//! ```rust,no_run
+//! use ntex_mqtt::{quic, v3};
+//!
+//! # async fn run(server_config: quinn::ServerConfig) -> std::io::Result<()> {
+//! let mut builder = quinn::Endpoint::builder();
+//! builder.listen(server_config);
+//! let (_endpoint, incoming) = builder.bind(&"127.0.0.1:14567".parse().unwrap()).unwrap();
+//!
+//! quic::serve(
+//!     incoming,
+//!     v3::MqttServer::new(|h: v3::Handshake<quic::QuicStream>| async move {
+//!         Ok::<_, ()>(h.ack((), false))
+//!     })
+//!     .publish(|_| async { Ok::<_, ()>(()) })
+//!     .finish(),
+//! )
+//! .await
+//! # }
+//! ```
+use std::task::{Context, Poll};
+use std::{fmt, future::Future, io, marker::PhantomData, net::SocketAddr, pin::Pin, rc::Rc};
+
+use ntex::codec::{AsyncRead, AsyncWrite, ReadBuf};
+use ntex::connect::{Address, Connect, ConnectError, Resolver};
+use ntex::service::{Service, ServiceFactory};
+use ntex::util::{next, poll_fn};
+
+pub use quinn;
+
+/// Bidirectional QUIC stream
+pub struct QuicStream {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    peer: SocketAddr,
+}
+
+impl QuicStream {
+    /// Create stream from quinn stream pair
+    pub fn new(send: quinn::SendStream, recv: quinn::RecvStream, peer: SocketAddr) -> Self {
+        QuicStream { send, recv, peer }
+    }
+
+    /// Remote address of QUIC connection
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Returns quinn stream pair
+    pub fn into_inner(self) -> (quinn::SendStream, quinn::RecvStream) {
+        (self.send, self.recv)
+    }
+}
+
+impl fmt::Debug for QuicStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicStream")
+            .field("id", &self.send.id())
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+/// QUIC connector service
+///
+/// Connector resolves address, establishes QUIC connection and opens
+/// bidirectional stream. Host name is used as TLS server name.
+pub struct QuicConnector<T> {
+    endpoint: quinn::Endpoint,
+    _t: PhantomData<T>,
+}
+
+impl<T> QuicConnector<T> {
+    /// Create connector for client endpoint
+    pub fn new(endpoint: quinn::Endpoint) -> Self {
+        QuicConnector { endpoint, _t: PhantomData }
+    }
+}
+
+impl<T: Address> QuicConnector<T> {
+    /// Resolve and connect to remote host
+    pub fn connect(
+        &self,
+        req: Connect<T>,
+    ) -> impl Future<Output = Result<QuicStream, ConnectError>> {
+        let endpoint = self.endpoint.clone();
+        let lookup = Resolver::new().lookup(req);
+
+        async move {
+            let req = lookup.await?;
+            let host = req.host().split(':').next().unwrap().to_owned();
+            let addr = req.addrs().next().ok_or(ConnectError::NoRecords)?;
+
+            log::trace!("Opening QUIC connection to {:?} ({})", host, addr);
+            let conn =
+                endpoint.connect(&addr, &host).map_err(io_error)?.await.map_err(io_error)?;
+            let (send, recv) = conn.connection.open_bi().await.map_err(io_error)?;
+            Ok(QuicStream::new(send, recv, addr))
+        }
+    }
+}
+
+impl<T> Clone for QuicConnector<T> {
+    fn clone(&self) -> Self {
+        QuicConnector { endpoint: self.endpoint.clone(), _t: PhantomData }
+    }
+}
+
+impl<T: Address + 'static> Service for QuicConnector<T> {
+    type Request = Connect<T>;
+    type Response = QuicStream;
+    type Error = ConnectError;
+    type Future = Pin<Box<dyn Future<Output = Result<QuicStream, ConnectError>>>>;
+
+    #[inline]
+    fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn call(&self, req: Connect<T>) -> Self::Future {
+        Box::pin(self.connect(req))
+    }
+}
+
+/// Serve mqtt sessions from QUIC endpoint
+///
+/// Every accepted connection is handled by new service from `factory`,
+/// session uses first bidirectional stream opened by client. Future
+/// resolves when endpoint is closed.
+pub async fn serve<F>(mut incoming: quinn::Incoming, factory: F) -> io::Result<()>
+where
+    F: ServiceFactory<Config = (), Request = QuicStream> + 'static,
+{
+    let factory = Rc::new(factory);
+
+    while let Some(connecting) = next(&mut incoming).await {
+        let factory = factory.clone();
+        ntex::rt::spawn(async move {
+            let mut conn = match connecting.await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    log::trace!("QUIC connection failed: {}", err);
+                    return;
+                }
+            };
+            let peer = conn.connection.remote_address();
+            let (send, recv) = match next(&mut conn.bi_streams).await {
+                Some(Ok(stream)) => stream,
+                Some(Err(err)) => {
+                    log::trace!("QUIC connection from {} is failed: {}", peer, err);
+                    return;
+                }
+                None => return,
+            };
+
+            let srv = match factory.new_service(()).await {
+                Ok(srv) => srv,
+                Err(_) => {
+                    log::error!("Cannot create mqtt service");
+                    return;
+                }
+            };
+            if poll_fn(|cx| srv.poll_ready(cx)).await.is_err() {
+                log::trace!("Mqtt service is failed");
+                return;
+            }
+            if srv.call(QuicStream::new(send, recv, peer)).await.is_err() {
+                log::trace!("Mqtt session over QUIC from {} is failed", peer);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn io_error<E: fmt::Display>(err: E) -> ConnectError {
+    ConnectError::Io(io::Error::other(err.to_string()))
+}
@@ -0,0 +1,138 @@
+//! Subscription limits
+use std::{cell::RefCell, num::NonZeroU16};
+
+use ntex::util::{ByteString, HashMap, HashSet};
+
+/// Subscription limits of a server, zero means unlimited
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct SubscriptionLimits {
+    /// Max number of topic filters in one `SUBSCRIBE` packet
+    pub(crate) max_topic_filters: u16,
+    /// Max number of active subscriptions of a session
+    pub(crate) max_subscriptions: u32,
+}
+
+impl SubscriptionLimits {
+    /// Create quota for a client connection
+    pub(crate) fn quota(&self) -> Option<SubscriptionQuota> {
+        if self.max_topic_filters == 0 && self.max_subscriptions == 0 {
+            None
+        } else {
+            Some(SubscriptionQuota {
+                limits: *self,
+                inner: RefCell::new(QuotaInner::default()),
+            })
+        }
+    }
+}
+
+/// Subscription accounting for a client connection
+pub(crate) struct SubscriptionQuota {
+    limits: SubscriptionLimits,
+    inner: RefCell<QuotaInner>,
+}
+
+#[derive(Default)]
+struct QuotaInner {
+    active: HashSet<ByteString>,
+    /// filters added by in-flight subscribe packets, by position in packet
+    pending: HashMap<NonZeroU16, Vec<Option<ByteString>>>,
+}
+
+impl SubscriptionQuota {
+    /// Check limits for subscribe packet and reserve new subscriptions.
+    ///
+    /// Returns `false` if packet exceeds limits, nothing is reserved in that case.
+    pub(crate) fn subscribe<'a, I>(&self, packet_id: NonZeroU16, filters: I) -> bool
+    where
+        I: ExactSizeIterator<Item = &'a ByteString>,
+    {
+        let max_filters = self.limits.max_topic_filters as usize;
+        if max_filters != 0 && filters.len() > max_filters {
+            log::trace!("Too many topic filters in subscribe packet: {}", filters.len());
+            return false;
+        }
+
+        let mut inner = self.inner.borrow_mut();
+        let mut added: Vec<Option<ByteString>> = Vec::with_capacity(filters.len());
+        for filter in filters {
+            if inner.active.contains(filter) || added.iter().flatten().any(|f| f == filter) {
+                added.push(None);
+            } else {
+                added.push(Some(filter.clone()));
+            }
+        }
+
+        let count = added.iter().flatten().count();
+        let max_subs = self.limits.max_subscriptions as usize;
+        if max_subs != 0 && inner.active.len() + count > max_subs {
+            log::trace!("Max number of subscriptions is reached: {}", inner.active.len());
+            return false;
+        }
+
+        inner.active.extend(added.iter().flatten().cloned());
+        inner.pending.insert(packet_id, added);
+        true
+    }
+
+    /// Complete subscribe packet, `granted` contains result for each topic
+    /// filter of the packet. Filters that are not granted get released.
+    pub(crate) fn subscribed<I>(&self, packet_id: NonZeroU16, granted: I)
+    where
+        I: Iterator<Item = bool>,
+    {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(added) = inner.pending.remove(&packet_id) {
+            for (filter, granted) in added.into_iter().zip(granted) {
+                if let (Some(filter), false) = (filter, granted) {
+                    inner.active.remove(&filter);
+                }
+            }
+        }
+    }
+
+    /// Release unsubscribed topic filters
+    pub(crate) fn unsubscribe<'a, I>(&self, filters: I)
+    where
+        I: Iterator<Item = &'a ByteString>,
+    {
+        let mut inner = self.inner.borrow_mut();
+        for filter in filters {
+            inner.active.remove(filter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota() {
+        let limits = SubscriptionLimits { max_topic_filters: 2, max_subscriptions: 3 };
+        let quota = limits.quota().unwrap();
+        let id = |v| NonZeroU16::new(v).unwrap();
+        let f = |v: &'static str| ByteString::from_static(v);
+        let (a, b, c, d) = (f("a"), f("b"), f("c"), f("d"));
+
+        assert!(SubscriptionLimits::default().quota().is_none());
+
+        // too many filters in one packet
+        assert!(!quota.subscribe(id(1), [a.clone(), b.clone(), c.clone()].iter()));
+
+        assert!(quota.subscribe(id(1), [a.clone(), b.clone()].iter()));
+        // resubscribe does not add subscription
+        assert!(quota.subscribe(id(2), [a.clone(), c.clone()].iter()));
+        assert!(!quota.subscribe(id(3), [d.clone()].iter()));
+
+        // not granted filters are released
+        quota.subscribed(id(1), [true, true].iter().copied());
+        quota.subscribed(id(2), [false, false].iter().copied());
+        assert!(quota.subscribe(id(3), [d].iter()));
+        quota.subscribed(id(3), [true].iter().copied());
+        assert!(!quota.subscribe(id(4), [c.clone()].iter()));
+
+        quota.unsubscribe([a, b].iter());
+        assert!(quota.subscribe(id(4), [c].iter()));
+    }
+}
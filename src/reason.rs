@@ -0,0 +1,327 @@
+//! Mqtt reason codes
+use std::convert::TryFrom;
+
+use crate::error::DecodeError;
+use crate::v5::codec;
+
+prim_enum! {
+    /// MQTT 5 reason codes
+    ///
+    /// Packet specific reason codes, like `codec::PublishAckReason`,
+    /// convert to and from `ReasonCode`.
+    pub enum ReasonCode {
+        /// Success, normal disconnection or granted QoS 0
+        Success = 0x00,
+        GrantedQos1 = 0x01,
+        GrantedQos2 = 0x02,
+        DisconnectWithWillMessage = 0x04,
+        NoMatchingSubscribers = 0x10,
+        NoSubscriptionExisted = 0x11,
+        ContinueAuth = 0x18,
+        ReAuth = 0x19,
+        UnspecifiedError = 0x80,
+        MalformedPacket = 0x81,
+        ProtocolError = 0x82,
+        ImplementationSpecificError = 0x83,
+        UnsupportedProtocolVersion = 0x84,
+        ClientIdentifierNotValid = 0x85,
+        BadUserNameOrPassword = 0x86,
+        NotAuthorized = 0x87,
+        ServerUnavailable = 0x88,
+        ServerBusy = 0x89,
+        Banned = 0x8A,
+        ServerShuttingDown = 0x8B,
+        BadAuthenticationMethod = 0x8C,
+        KeepAliveTimeout = 0x8D,
+        SessionTakenOver = 0x8E,
+        TopicFilterInvalid = 0x8F,
+        TopicNameInvalid = 0x90,
+        PacketIdentifierInUse = 0x91,
+        PacketIdentifierNotFound = 0x92,
+        ReceiveMaximumExceeded = 0x93,
+        TopicAliasInvalid = 0x94,
+        PacketTooLarge = 0x95,
+        MessageRateTooHigh = 0x96,
+        QuotaExceeded = 0x97,
+        AdministrativeAction = 0x98,
+        PayloadFormatInvalid = 0x99,
+        RetainNotSupported = 0x9A,
+        QosNotSupported = 0x9B,
+        UseAnotherServer = 0x9C,
+        ServerMoved = 0x9D,
+        SharedSubscriptionsNotSupported = 0x9E,
+        ConnectionRateExceeded = 0x9F,
+        MaximumConnectTime = 0xA0,
+        SubscriptionIdentifiersNotSupported = 0xA1,
+        WildcardSubscriptionsNotSupported = 0xA2
+    }
+}
+
+bitflags::bitflags! {
+    /// Packet types that carry reason code
+    pub struct PacketTypes: u16 {
+        const CONNACK    = 0b0000_0000_0001;
+        const PUBACK     = 0b0000_0000_0010;
+        const PUBREC     = 0b0000_0000_0100;
+        const PUBREL     = 0b0000_0000_1000;
+        const PUBCOMP    = 0b0000_0001_0000;
+        const SUBACK     = 0b0000_0010_0000;
+        const UNSUBACK   = 0b0000_0100_0000;
+        const DISCONNECT = 0b0000_1000_0000;
+        const AUTH       = 0b0001_0000_0000;
+    }
+}
+
+impl ReasonCode {
+    /// Returns `true` if reason code indicates failure
+    pub fn is_error(self) -> bool {
+        u8::from(self) >= 0x80
+    }
+
+    /// Returns packet types reason code is allowed in
+    pub fn packet_types(self) -> PacketTypes {
+        const PUB: PacketTypes = PacketTypes::from_bits_truncate(
+            PacketTypes::PUBACK.bits() | PacketTypes::PUBREC.bits(),
+        );
+        const ACKS: PacketTypes = PacketTypes::from_bits_truncate(
+            PUB.bits() | PacketTypes::SUBACK.bits() | PacketTypes::UNSUBACK.bits(),
+        );
+        const CON_DIS: PacketTypes = PacketTypes::from_bits_truncate(
+            PacketTypes::CONNACK.bits() | PacketTypes::DISCONNECT.bits(),
+        );
+
+        match self {
+            ReasonCode::Success => PacketTypes::all(),
+            ReasonCode::GrantedQos1 | ReasonCode::GrantedQos2 => PacketTypes::SUBACK,
+            ReasonCode::DisconnectWithWillMessage => PacketTypes::DISCONNECT,
+            ReasonCode::NoMatchingSubscribers => PUB,
+            ReasonCode::NoSubscriptionExisted => PacketTypes::UNSUBACK,
+            ReasonCode::ContinueAuth | ReasonCode::ReAuth => PacketTypes::AUTH,
+            ReasonCode::UnspecifiedError
+            | ReasonCode::ImplementationSpecificError
+            | ReasonCode::NotAuthorized => ACKS | CON_DIS,
+            ReasonCode::MalformedPacket
+            | ReasonCode::ProtocolError
+            | ReasonCode::ServerBusy
+            | ReasonCode::BadAuthenticationMethod
+            | ReasonCode::PacketTooLarge
+            | ReasonCode::RetainNotSupported
+            | ReasonCode::QosNotSupported
+            | ReasonCode::UseAnotherServer
+            | ReasonCode::ServerMoved
+            | ReasonCode::ConnectionRateExceeded => CON_DIS,
+            ReasonCode::UnsupportedProtocolVersion
+            | ReasonCode::ClientIdentifierNotValid
+            | ReasonCode::BadUserNameOrPassword
+            | ReasonCode::ServerUnavailable
+            | ReasonCode::Banned => PacketTypes::CONNACK,
+            ReasonCode::ServerShuttingDown
+            | ReasonCode::KeepAliveTimeout
+            | ReasonCode::SessionTakenOver
+            | ReasonCode::ReceiveMaximumExceeded
+            | ReasonCode::TopicAliasInvalid
+            | ReasonCode::MessageRateTooHigh
+            | ReasonCode::AdministrativeAction
+            | ReasonCode::MaximumConnectTime => PacketTypes::DISCONNECT,
+            ReasonCode::TopicFilterInvalid => {
+                PacketTypes::SUBACK | PacketTypes::UNSUBACK | PacketTypes::DISCONNECT
+            }
+            ReasonCode::TopicNameInvalid | ReasonCode::PayloadFormatInvalid => PUB | CON_DIS,
+            ReasonCode::PacketIdentifierInUse => ACKS,
+            ReasonCode::PacketIdentifierNotFound => PacketTypes::PUBREL | PacketTypes::PUBCOMP,
+            ReasonCode::QuotaExceeded => PUB | PacketTypes::SUBACK | CON_DIS,
+            ReasonCode::SharedSubscriptionsNotSupported
+            | ReasonCode::SubscriptionIdentifiersNotSupported
+            | ReasonCode::WildcardSubscriptionsNotSupported => {
+                PacketTypes::SUBACK | PacketTypes::DISCONNECT
+            }
+        }
+    }
+
+    /// Returns `true` if reason code is allowed in packets of provided types
+    pub fn is_allowed_in(self, packets: PacketTypes) -> bool {
+        self.packet_types().contains(packets)
+    }
+
+    /// Returns reason code description
+    pub fn to_str(self) -> &'static str {
+        match self {
+            ReasonCode::Success => "Success",
+            ReasonCode::GrantedQos1 => "Granted QoS 1",
+            ReasonCode::GrantedQos2 => "Granted QoS 2",
+            ReasonCode::DisconnectWithWillMessage => "Disconnect with Will Message",
+            ReasonCode::NoMatchingSubscribers => "No matching subscribers",
+            ReasonCode::NoSubscriptionExisted => "No subscription existed",
+            ReasonCode::ContinueAuth => "Continue authentication",
+            ReasonCode::ReAuth => "Re-authenticate",
+            ReasonCode::UnspecifiedError => "Unspecified error",
+            ReasonCode::MalformedPacket => "Malformed Packet",
+            ReasonCode::ProtocolError => "Protocol Error",
+            ReasonCode::ImplementationSpecificError => "Implementation specific error",
+            ReasonCode::UnsupportedProtocolVersion => "Unsupported Protocol Version",
+            ReasonCode::ClientIdentifierNotValid => "Client Identifier not valid",
+            ReasonCode::BadUserNameOrPassword => "Bad User Name or Password",
+            ReasonCode::NotAuthorized => "Not authorized",
+            ReasonCode::ServerUnavailable => "Server unavailable",
+            ReasonCode::ServerBusy => "Server busy",
+            ReasonCode::Banned => "Banned",
+            ReasonCode::ServerShuttingDown => "Server shutting down",
+            ReasonCode::BadAuthenticationMethod => "Bad authentication method",
+            ReasonCode::KeepAliveTimeout => "Keep Alive timeout",
+            ReasonCode::SessionTakenOver => "Session taken over",
+            ReasonCode::TopicFilterInvalid => "Topic Filter invalid",
+            ReasonCode::TopicNameInvalid => "Topic Name invalid",
+            ReasonCode::PacketIdentifierInUse => "Packet Identifier in use",
+            ReasonCode::PacketIdentifierNotFound => "Packet Identifier not found",
+            ReasonCode::ReceiveMaximumExceeded => "Receive Maximum exceeded",
+            ReasonCode::TopicAliasInvalid => "Topic Alias invalid",
+            ReasonCode::PacketTooLarge => "Packet too large",
+            ReasonCode::MessageRateTooHigh => "Message rate too high",
+            ReasonCode::QuotaExceeded => "Quota exceeded",
+            ReasonCode::AdministrativeAction => "Administrative action",
+            ReasonCode::PayloadFormatInvalid => "Payload format invalid",
+            ReasonCode::RetainNotSupported => "Retain not supported",
+            ReasonCode::QosNotSupported => "QoS not supported",
+            ReasonCode::UseAnotherServer => "Use another server",
+            ReasonCode::ServerMoved => "Server moved",
+            ReasonCode::SharedSubscriptionsNotSupported => "Shared Subscriptions not supported",
+            ReasonCode::ConnectionRateExceeded => "Connection rate exceeded",
+            ReasonCode::MaximumConnectTime => "Maximum connect time",
+            ReasonCode::SubscriptionIdentifiersNotSupported => {
+                "Subscription Identifiers not supported"
+            }
+            ReasonCode::WildcardSubscriptionsNotSupported => {
+                "Wildcard Subscriptions not supported"
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ReasonCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.to_str())
+    }
+}
+
+macro_rules! reason_code_conv {
+    ($($name:ty),+) => {
+        $(
+            impl From<$name> for ReasonCode {
+                fn from(v: $name) -> Self {
+                    // packet specific reason codes are subsets of `ReasonCode`
+                    ReasonCode::try_from(u8::from(v)).unwrap()
+                }
+            }
+
+            impl TryFrom<ReasonCode> for $name {
+                type Error = DecodeError;
+
+                fn try_from(v: ReasonCode) -> Result<Self, Self::Error> {
+                    <$name>::try_from(u8::from(v))
+                }
+            }
+
+            impl $name {
+                /// Returns `true` if reason code indicates failure
+                pub fn is_error(self) -> bool {
+                    ReasonCode::from(self).is_error()
+                }
+            }
+        )+
+    };
+}
+
+reason_code_conv!(
+    codec::ConnectAckReason,
+    codec::PublishAckReason,
+    codec::PublishAck2Reason,
+    codec::SubscribeAckReason,
+    codec::UnsubscribeAckReason,
+    codec::DisconnectReasonCode,
+    codec::AuthReasonCode
+);
+
+/// MQTT 3.1.1 return codes
+pub mod v3 {
+    pub use crate::v3::codec::ConnectAckReason;
+
+    /// SUBACK return code for failed subscription
+    pub const SUBSCRIBE_FAILURE: u8 = 0x80;
+
+    impl ConnectAckReason {
+        /// Returns `true` if connection is refused
+        pub fn is_error(self) -> bool {
+            self != ConnectAckReason::ConnectionAccepted
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reason_code() {
+        assert!(!ReasonCode::Success.is_error());
+        assert!(!ReasonCode::NoMatchingSubscribers.is_error());
+        assert!(ReasonCode::QuotaExceeded.is_error());
+        assert_eq!(ReasonCode::try_from(0x97), Ok(ReasonCode::QuotaExceeded));
+        assert_eq!(ReasonCode::QuotaExceeded.to_str(), "Quota exceeded");
+
+        assert!(ReasonCode::Success.is_allowed_in(PacketTypes::AUTH));
+        assert!(ReasonCode::QuotaExceeded.is_allowed_in(PacketTypes::PUBACK));
+        assert!(!ReasonCode::QuotaExceeded.is_allowed_in(PacketTypes::UNSUBACK));
+        assert!(!ReasonCode::PacketIdentifierNotFound
+            .is_allowed_in(PacketTypes::PUBACK | PacketTypes::PUBREL));
+
+        let code = ReasonCode::from(codec::PublishAckReason::NotAuthorized);
+        assert_eq!(code, ReasonCode::NotAuthorized);
+        assert_eq!(
+            codec::PublishAckReason::try_from(code),
+            Ok(codec::PublishAckReason::NotAuthorized)
+        );
+        assert!(codec::PublishAckReason::try_from(ReasonCode::ServerMoved).is_err());
+        assert!(codec::DisconnectReasonCode::ServerMoved.is_error());
+        assert!(v3::ConnectAckReason::NotAuthorized.is_error());
+
+        let err = crate::v5::error::PublishQos1Error::Fail(codec::PublishAck {
+            packet_id: std::num::NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::QuotaExceeded,
+            properties: Default::default(),
+            reason_string: None,
+        });
+        assert_eq!(err.reason_code(), Some(ReasonCode::QuotaExceeded));
+        assert_eq!(crate::v5::error::PublishQos1Error::Disconnected.reason_code(), None);
+    }
+
+    #[test]
+    fn test_packet_reason_codes() {
+        let check = |code: u8, packets: PacketTypes| {
+            assert!(ReasonCode::try_from(code).unwrap().is_allowed_in(packets), "{}", code);
+        };
+        (0..=255u8).for_each(|code| {
+            if codec::ConnectAckReason::try_from(code).is_ok() {
+                check(code, PacketTypes::CONNACK);
+            }
+            // `ReceiveMaximumExceeded` is not allowed in PUBACK by spec
+            if codec::PublishAckReason::try_from(code).is_ok() && code != 0x93 {
+                check(code, PacketTypes::PUBACK | PacketTypes::PUBREC);
+            }
+            if codec::PublishAck2Reason::try_from(code).is_ok() {
+                check(code, PacketTypes::PUBREL | PacketTypes::PUBCOMP);
+            }
+            if codec::SubscribeAckReason::try_from(code).is_ok() {
+                check(code, PacketTypes::SUBACK);
+            }
+            if codec::UnsubscribeAckReason::try_from(code).is_ok() {
+                check(code, PacketTypes::UNSUBACK);
+            }
+            if codec::DisconnectReasonCode::try_from(code).is_ok() {
+                check(code, PacketTypes::DISCONNECT);
+            }
+            if codec::AuthReasonCode::try_from(code).is_ok() {
+                check(code, PacketTypes::AUTH);
+            }
+        });
+    }
+}
@@ -0,0 +1,139 @@
+//! Registry of connected clients
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use ntex::util::{ByteString, HashMap};
+
+/// Sink handles of connected clients, keyed by client id
+///
+/// Server inserts sink of the client after successful handshake and removes
+/// it when connection is closed. If client with the same id connects again,
+/// new connection replaces previous one. Clients with empty client id are not
+/// registered. Registry is shared by connections of a worker, so it must be
+/// created in server factory.
+pub struct SinkRegistry<T>(Rc<RefCell<RegistryInner<T>>>);
+
+struct RegistryInner<T> {
+    seq: u64,
+    sinks: HashMap<ByteString, (u64, T)>,
+}
+
+impl<T> Clone for SinkRegistry<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        SinkRegistry(self.0.clone())
+    }
+}
+
+impl<T> Default for SinkRegistry<T> {
+    fn default() -> Self {
+        SinkRegistry(Rc::new(RefCell::new(RegistryInner { seq: 0, sinks: HashMap::default() })))
+    }
+}
+
+impl<T: Clone> SinkRegistry<T> {
+    /// Create empty registry
+    pub fn new() -> Self {
+        SinkRegistry::default()
+    }
+
+    /// Get sink of connected client
+    pub fn get(&self, client_id: &str) -> Option<T> {
+        self.0.borrow().sinks.get(client_id).map(|(_, sink)| sink.clone())
+    }
+
+    /// Check if client is connected
+    pub fn contains(&self, client_id: &str) -> bool {
+        self.0.borrow().sinks.contains_key(client_id)
+    }
+
+    /// Number of connected clients
+    pub fn len(&self) -> usize {
+        self.0.borrow().sinks.len()
+    }
+
+    /// Check if registry is empty
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().sinks.is_empty()
+    }
+
+    /// Client ids of connected clients
+    pub fn client_ids(&self) -> Vec<ByteString> {
+        self.0.borrow().sinks.keys().cloned().collect()
+    }
+
+    /// Snapshot of connected clients
+    ///
+    /// Registry is not borrowed while snapshot is used, so clients could be
+    /// inserted or removed during iteration.
+    pub fn snapshot(&self) -> Vec<(ByteString, T)> {
+        self.0.borrow().sinks.iter().map(|(id, (_, sink))| (id.clone(), sink.clone())).collect()
+    }
+
+    /// Remove client from registry, connection is not affected
+    pub fn remove(&self, client_id: &str) -> Option<T> {
+        self.0.borrow_mut().sinks.remove(client_id).map(|(_, sink)| sink)
+    }
+
+    /// Insert sink of connected client, returned guard removes sink on drop
+    pub(crate) fn register(&self, client_id: ByteString, sink: T) -> Registration<T> {
+        let mut inner = self.0.borrow_mut();
+        inner.seq += 1;
+        let seq = inner.seq;
+        inner.sinks.insert(client_id.clone(), (seq, sink));
+        Registration { registry: self.clone(), client_id, seq }
+    }
+}
+
+impl<T> fmt::Debug for SinkRegistry<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SinkRegistry").field("clients", &self.0.borrow().sinks.len()).finish()
+    }
+}
+
+/// Registration of a connection
+pub(crate) struct Registration<T> {
+    registry: SinkRegistry<T>,
+    client_id: ByteString,
+    seq: u64,
+}
+
+impl<T> Drop for Registration<T> {
+    fn drop(&mut self) {
+        let mut inner = self.registry.0.borrow_mut();
+        // connection could be replaced by newer connection with the same client id
+        if inner.sinks.get(&self.client_id).map(|(seq, _)| *seq) == Some(self.seq) {
+            inner.sinks.remove(&self.client_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let registry = SinkRegistry::new();
+        let r1 = registry.register(ByteString::from_static("c1"), 1);
+        let r2 = registry.register(ByteString::from_static("c2"), 2);
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.get("c1"), Some(1));
+        let mut snapshot = registry.snapshot();
+        snapshot.sort();
+        assert_eq!(
+            snapshot,
+            vec![(ByteString::from_static("c1"), 1), (ByteString::from_static("c2"), 2)]
+        );
+
+        // reconnect replaces sink, old registration does not remove it
+        let r3 = registry.register(ByteString::from_static("c1"), 3);
+        drop(r1);
+        assert_eq!(registry.get("c1"), Some(3));
+        drop(r3);
+        assert!(!registry.contains("c1"));
+
+        assert_eq!(registry.remove("c2"), Some(2));
+        drop(r2);
+        assert!(registry.is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+//! Topic rewrite rules
+use ntex::util::ByteString;
+
+/// Topic rewrite rules for inbound publishes and subscriptions
+///
+/// Rule replaces prefix of a topic, rules are checked in order of registration
+/// and first matching rule is applied. Topics that do not match any rule are not
+/// changed. For shared subscriptions rules are applied to topic filter that
+/// follows `$share/{group}/` prefix.
+#[derive(Clone, Debug, Default)]
+pub struct TopicRewrite {
+    rules: Vec<(ByteString, ByteString)>,
+}
+
+impl TopicRewrite {
+    /// Create empty rules set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace topic prefix `from` with `to`.
+    ///
+    /// Empty `from` prefix matches any topic, `to` is prepended to topic in that case.
+    pub fn prefix<F, T>(mut self, from: F, to: T) -> Self
+    where
+        ByteString: From<F> + From<T>,
+    {
+        self.rules.push((from.into(), to.into()));
+        self
+    }
+
+    /// Check if rules set is empty
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Rewrite topic name of publish
+    pub fn topic(&self, topic: &ByteString) -> ByteString {
+        for (from, to) in &self.rules {
+            if let Some(rest) = topic.strip_prefix(&from[..]) {
+                return ByteString::from(format!("{}{}", to, rest));
+            }
+        }
+        topic.clone()
+    }
+
+    /// Rewrite topic filter of subscription
+    pub fn filter(&self, filter: &ByteString) -> ByteString {
+        if let Some(rest) = filter.strip_prefix("$share/") {
+            if let Some(pos) = rest.find('/') {
+                let (group, filter) = rest.split_at(pos + 1);
+                let filter = self.topic(&ByteString::from(filter));
+                return ByteString::from(format!("$share/{}{}", group, filter));
+            }
+        }
+        self.topic(filter)
+    }
+}
+
+/// Topic namespace of isolated tenant
+#[derive(Clone, Debug)]
+pub(crate) struct Tenant {
+    prefix: ByteString,
+}
+
+impl Tenant {
+    /// Create tenant namespace
+    ///
+    /// Namespace must be a single non-empty topic level without wildcards.
+    pub(crate) fn new(namespace: &str) -> Option<Self> {
+        if namespace.is_empty()
+            || namespace.starts_with('$')
+            || namespace.contains(&['/', '+', '#', '\0'][..])
+        {
+            None
+        } else {
+            Some(Tenant { prefix: ByteString::from(format!("{}/", namespace)) })
+        }
+    }
+
+    /// Move topic of inbound publish into tenant namespace
+    ///
+    /// Topics reserved for server (starting with `$`) are not allowed.
+    pub(crate) fn topic(&self, topic: &ByteString) -> Option<ByteString> {
+        if topic.starts_with('$') {
+            None
+        } else {
+            Some(ByteString::from(format!("{}{}", self.prefix, topic)))
+        }
+    }
+
+    /// Move topic filter into tenant namespace
+    pub(crate) fn filter(&self, filter: &ByteString) -> Option<ByteString> {
+        if let Some(rest) = filter.strip_prefix("$share/") {
+            if let Some(pos) = rest.find('/') {
+                let (group, filter) = rest.split_at(pos + 1);
+                let filter = self.topic(&ByteString::from(filter))?;
+                return Some(ByteString::from(format!("$share/{}{}", group, filter)));
+            }
+        }
+        self.topic(filter)
+    }
+
+    /// Remove tenant namespace from topic of outbound publish
+    ///
+    /// Topics outside of tenant namespace are not allowed.
+    pub(crate) fn strip(&self, topic: ByteString) -> Option<ByteString> {
+        topic.strip_prefix(&self.prefix[..]).map(ByteString::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite() {
+        let s = |v: &'static str| ByteString::from_static(v);
+        let rewrite = TopicRewrite::new().prefix("legacy/", "devices/").prefix("", "tenant1/");
+        assert!(!rewrite.is_empty());
+
+        assert_eq!(rewrite.topic(&s("legacy/dev1/temp")), "devices/dev1/temp");
+        assert_eq!(rewrite.topic(&s("dev1/temp")), "tenant1/dev1/temp");
+        assert_eq!(rewrite.filter(&s("legacy/+/temp")), "devices/+/temp");
+        assert_eq!(rewrite.filter(&s("#")), "tenant1/#");
+        assert_eq!(rewrite.filter(&s("$share/group/legacy/#")), "$share/group/devices/#");
+
+        let rewrite = TopicRewrite::new().prefix("a/", "b/");
+        assert_eq!(rewrite.topic(&s("c/d")), "c/d");
+        assert!(TopicRewrite::new().is_empty());
+    }
+
+    #[test]
+    fn test_tenant() {
+        let s = |v: &'static str| ByteString::from_static(v);
+        assert!(Tenant::new("").is_none());
+        assert!(Tenant::new("a/b").is_none());
+        assert!(Tenant::new("a+").is_none());
+        assert!(Tenant::new("$SYS").is_none());
+
+        let tenant = Tenant::new("acme").unwrap();
+        assert_eq!(tenant.topic(&s("dev1/temp")).unwrap(), "acme/dev1/temp");
+        assert!(tenant.topic(&s("$SYS/stats")).is_none());
+        assert_eq!(tenant.filter(&s("#")).unwrap(), "acme/#");
+        assert_eq!(tenant.filter(&s("$share/g1/+/temp")).unwrap(), "$share/g1/acme/+/temp");
+        assert!(tenant.filter(&s("$SYS/#")).is_none());
+        assert!(tenant.filter(&s("$share/g1/$SYS/#")).is_none());
+        assert_eq!(tenant.strip(s("acme/dev1/temp")).unwrap(), "dev1/temp");
+        assert_eq!(tenant.strip(s("other/dev1")), None);
+        assert_eq!(tenant.strip(s("acmeother/dev1")), None);
+    }
+}
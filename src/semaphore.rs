@@ -0,0 +1,275 @@
+//! Semaphore for sink's in-flight window
+use std::task::{Context, Poll};
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, future::Future, pin::Pin, rc::Rc};
+
+use ntex::task::LocalWaker;
+
+/// Single-threaded semaphore with fair and cancellation safe permits.
+///
+/// Waiters get permits in FIFO order, new acquirers cannot overtake queued
+/// waiters. If waiter gets dropped after permit is granted, permit is passed
+/// to the next waiter, so wakeups are never lost.
+pub(crate) struct Semaphore {
+    inner: RefCell<Inner>,
+}
+
+struct Inner {
+    cap: usize,
+    acquired: usize,
+    closed: bool,
+    waiters: VecDeque<Rc<Waiter>>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Waiting,
+    Granted,
+    Closed,
+}
+
+struct Waiter {
+    state: Cell<State>,
+    waker: LocalWaker,
+}
+
+impl Semaphore {
+    pub(crate) fn new(cap: usize) -> Self {
+        Semaphore {
+            inner: RefCell::new(Inner {
+                cap,
+                acquired: 0,
+                closed: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Number of permits that could be acquired without waiting
+    pub(crate) fn available(&self) -> usize {
+        let inner = self.inner.borrow();
+        if inner.closed {
+            0
+        } else {
+            inner.cap.saturating_sub(inner.acquired)
+        }
+    }
+
+    /// Change number of permits.
+    ///
+    /// Already acquired permits are not revoked if capacity shrinks.
+    pub(crate) fn set_capacity(&self, cap: usize) {
+        let mut inner = self.inner.borrow_mut();
+        inner.cap = cap;
+        inner.grant();
+    }
+
+    /// Acquire permit if it is available and nobody waits for it
+    pub(crate) fn try_acquire(self: &Rc<Self>) -> Option<Permit> {
+        let mut inner = self.inner.borrow_mut();
+        if !inner.closed && inner.waiters.is_empty() && inner.acquired < inner.cap {
+            inner.acquired += 1;
+            Some(Permit { sem: Some(self.clone()) })
+        } else {
+            None
+        }
+    }
+
+    /// Acquire permit.
+    ///
+    /// Position in the queue is reserved at call time. Future resolves
+    /// to `None` if semaphore is closed.
+    pub(crate) fn acquire(self: &Rc<Self>) -> Acquire {
+        let mut inner = self.inner.borrow_mut();
+        let state = if inner.closed {
+            State::Closed
+        } else if inner.waiters.is_empty() && inner.acquired < inner.cap {
+            inner.acquired += 1;
+            State::Granted
+        } else {
+            State::Waiting
+        };
+        let waiter = Rc::new(Waiter { state: Cell::new(state), waker: LocalWaker::new() });
+        if state == State::Waiting {
+            inner.waiters.push_back(waiter.clone());
+        }
+        Acquire { sem: self.clone(), waiter: Some(waiter) }
+    }
+
+    /// Release permit detached with `Permit::detach()`
+    pub(crate) fn release(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.acquired = inner.acquired.saturating_sub(1);
+        inner.grant();
+    }
+
+    /// Close semaphore, all pending and future acquires fail
+    pub(crate) fn close(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.closed = true;
+        inner.acquired = 0;
+        for waiter in inner.waiters.drain(..) {
+            waiter.state.set(State::Closed);
+            waiter.waker.wake();
+        }
+    }
+}
+
+impl Inner {
+    fn grant(&mut self) {
+        while !self.closed && self.acquired < self.cap {
+            if let Some(waiter) = self.waiters.pop_front() {
+                self.acquired += 1;
+                waiter.state.set(State::Granted);
+                waiter.waker.wake();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+/// Acquired permit, permit is released on drop
+pub(crate) struct Permit {
+    sem: Option<Rc<Semaphore>>,
+}
+
+impl Permit {
+    /// Keep permit acquired after drop.
+    ///
+    /// Permit must be released later with `Semaphore::release()`.
+    pub(crate) fn detach(mut self) {
+        self.sem.take();
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(sem) = self.sem.take() {
+            sem.release();
+        }
+    }
+}
+
+/// Future for `Semaphore::acquire()`
+pub(crate) struct Acquire {
+    sem: Rc<Semaphore>,
+    waiter: Option<Rc<Waiter>>,
+}
+
+impl Future for Acquire {
+    type Output = Option<Permit>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let state = match self.waiter {
+            Some(ref waiter) => waiter.state.get(),
+            None => panic!("Acquire future is polled after completion"),
+        };
+        match state {
+            State::Waiting => {
+                if let Some(ref waiter) = self.waiter {
+                    waiter.waker.register(cx.waker());
+                }
+                Poll::Pending
+            }
+            State::Granted => {
+                self.waiter.take();
+                Poll::Ready(Some(Permit { sem: Some(self.sem.clone()) }))
+            }
+            State::Closed => {
+                self.waiter.take();
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            match waiter.state.get() {
+                State::Waiting => {
+                    self.sem.inner.borrow_mut().waiters.retain(|w| !Rc::ptr_eq(w, &waiter))
+                }
+                // granted permit is not used, pass it to the next waiter
+                State::Granted => self.sem.release(),
+                State::Closed => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn poll(fut: &mut Acquire) -> Poll<Option<Permit>> {
+        fut.poll_unpin(&mut Context::from_waker(futures::task::noop_waker_ref()))
+    }
+
+    #[ntex::test]
+    async fn test_fifo() {
+        let sem = Rc::new(Semaphore::new(1));
+        let p1 = sem.try_acquire().unwrap();
+        assert_eq!(sem.available(), 0);
+
+        let mut a2 = sem.acquire();
+        let mut a3 = sem.acquire();
+        assert!(poll(&mut a2).is_pending());
+        assert!(poll(&mut a3).is_pending());
+
+        // queued waiters cannot be overtaken
+        drop(p1);
+        assert!(sem.try_acquire().is_none());
+        assert!(poll(&mut a3).is_pending());
+        let p2 = a2.await.unwrap();
+
+        drop(p2);
+        let p3 = a3.await.unwrap();
+        p3.detach();
+        assert_eq!(sem.available(), 0);
+        sem.release();
+        assert_eq!(sem.available(), 1);
+    }
+
+    #[ntex::test]
+    async fn test_cancel() {
+        let sem = Rc::new(Semaphore::new(1));
+        let p1 = sem.try_acquire().unwrap();
+        let a2 = sem.acquire();
+        let a3 = sem.acquire();
+        let mut a4 = sem.acquire();
+
+        // dropped waiter leaves the queue
+        drop(a2);
+        drop(p1);
+        assert!(poll(&mut a4).is_pending());
+
+        // granted but not taken permit is passed to the next waiter
+        drop(a3);
+        let p4 = a4.await.unwrap();
+        assert_eq!(sem.available(), 0);
+        drop(p4);
+        assert_eq!(sem.available(), 1);
+    }
+
+    #[ntex::test]
+    async fn test_capacity_and_close() {
+        let sem = Rc::new(Semaphore::new(0));
+        let mut a1 = sem.acquire();
+        assert!(poll(&mut a1).is_pending());
+        sem.set_capacity(2);
+        let p1 = a1.await.unwrap();
+        assert_eq!(sem.available(), 1);
+
+        let _p2 = sem.try_acquire().unwrap();
+        let mut a3 = sem.acquire();
+        assert!(poll(&mut a3).is_pending());
+        sem.close();
+        assert!(std::matches!(poll(&mut a3), Poll::Ready(None)));
+        assert!(sem.try_acquire().is_none());
+        assert_eq!(sem.available(), 0);
+        drop(p1);
+        assert_eq!(sem.available(), 0);
+    }
+}
@@ -15,7 +15,7 @@ use crate::{v3, v5};
 pub struct MqttServer<Io, V3, V5, Err, InitErr> {
     v3: V3,
     v5: V5,
-    handshake_timeout: usize,
+    handshake_timeout: time::Duration,
     _t: marker::PhantomData<(Io, Err, InitErr)>,
 }
 
@@ -33,7 +33,7 @@ impl<Io, Err, InitErr>
         MqttServer {
             v3: DefaultProtocolServer::new(ProtocolVersion::MQTT3),
             v5: DefaultProtocolServer::new(ProtocolVersion::MQTT5),
-            handshake_timeout: 0,
+            handshake_timeout: time::Duration::ZERO,
             _t: marker::PhantomData,
         }
     }
@@ -54,11 +54,11 @@ impl<Io, Err, InitErr> Default
 }
 
 impl<Io, V3, V5, Err, InitErr> MqttServer<Io, V3, V5, Err, InitErr> {
-    /// Set handshake timeout in millis.
+    /// Set handshake timeout.
     ///
     /// Handshake includes `connect` packet.
-    /// By default handshake timeuot is disabled.
-    pub fn handshake_timeout(mut self, timeout: usize) -> Self {
+    /// By default handshake timeout is disabled.
+    pub fn handshake_timeout(mut self, timeout: time::Duration) -> Self {
         self.handshake_timeout = timeout;
         self
     }
@@ -293,7 +293,7 @@ where
 /// Mqtt Server
 pub struct MqttServerImpl<Io, V3, V5, Err> {
     handlers: Rc<(V3, V5)>,
-    handshake_timeout: usize,
+    handshake_timeout: time::Duration,
     _t: marker::PhantomData<(Io, Err)>,
 }
 
@@ -339,8 +339,8 @@ where
     }
 
     fn call(&self, req: Io) -> Self::Future {
-        let delay = if self.handshake_timeout > 0 {
-            Some(Box::pin(sleep(time::Duration::from_secs(self.handshake_timeout as u64))))
+        let delay = if self.handshake_timeout != time::Duration::ZERO {
+            Some(Box::pin(sleep(self.handshake_timeout)))
         } else {
             None
         };
@@ -1,12 +1,14 @@
 use std::task::{Context, Poll};
-use std::{fmt, future::Future, marker::PhantomData, pin::Pin, rc::Rc, time::Duration};
+use std::time::Duration;
+use std::{cell::Cell, fmt, future::Future, marker::PhantomData, pin::Pin, rc::Rc};
 
 use ntex::codec::{AsyncRead, AsyncWrite, Decoder, Encoder};
 use ntex::rt::time::Sleep;
 use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
-use ntex::util::{select, Either};
 
-use super::io::{DispatchItem, Dispatcher, State, Timer};
+use super::io::{
+    BufferLimits, DispatchItem, Dispatcher, FlushWaiters, ShutdownStatus, State, Timer,
+};
 
 type ResponseItem<U> = Option<<U as Encoder>::Item>;
 
@@ -14,7 +16,9 @@ pub(crate) struct FramedService<St, C, T, Io, Codec> {
     connect: C,
     handler: Rc<T>,
     disconnect_timeout: u16,
+    limits: BufferLimits,
     time: Timer,
+    shutdown_timeout: Duration,
     _t: PhantomData<(St, Io, Codec)>,
 }
 
@@ -23,17 +27,49 @@ impl<St, C, T, Io, Codec> FramedService<St, C, T, Io, Codec> {
         FramedService {
             connect,
             disconnect_timeout,
+            limits: BufferLimits::default(),
             handler: Rc::new(service),
             time: Timer::with(Duration::from_secs(1)),
+            shutdown_timeout: Duration::ZERO,
             _t: PhantomData,
         }
     }
+
+    /// Set per-connection buffer limits
+    pub(crate) fn buffer_limits(mut self, limits: BufferLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Set connection timer
+    pub(crate) fn timer(mut self, timer: Timer) -> Self {
+        self.time = timer;
+        self
+    }
+
+    /// Set in-flight responses shutdown timeout
+    pub(crate) fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
 }
 
 impl<St, C, T, Io, Codec> ServiceFactory for FramedService<St, C, T, Io, Codec>
 where
     Io: AsyncRead + AsyncWrite + Unpin + 'static,
-    C: ServiceFactory<Config = (), Request = Io, Response = (Io, State, Codec, St, u16)>,
+    C: ServiceFactory<
+        Request = Io,
+        Response = (
+            Io,
+            State,
+            Codec,
+            St,
+            Rc<Cell<u16>>,
+            Rc<Cell<Option<ShutdownStatus>>>,
+            Rc<Cell<bool>>,
+            Rc<FlushWaiters>,
+        ),
+    >,
     C::Error: fmt::Debug,
     C::Future: 'static,
     <C::Service as Service>::Future: 'static,
@@ -49,7 +85,7 @@ where
     Codec: Decoder + Encoder + Clone + 'static,
     <Codec as Encoder>::Item: 'static,
 {
-    type Config = ();
+    type Config = C::Config;
     type Request = Io;
     type Response = ();
     type Error = C::Error;
@@ -57,18 +93,22 @@ where
     type Service = FramedServiceImpl<St, C::Service, T, Io, Codec>;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Service, Self::InitError>>>>;
 
-    fn new_service(&self, _: ()) -> Self::Future {
-        let fut = self.connect.new_service(());
+    fn new_service(&self, cfg: C::Config) -> Self::Future {
+        let fut = self.connect.new_service(cfg);
         let handler = self.handler.clone();
         let disconnect_timeout = self.disconnect_timeout;
+        let limits = self.limits;
         let time = self.time.clone();
+        let shutdown_timeout = self.shutdown_timeout;
 
         // create connect service and then create service impl
         Box::pin(async move {
             Ok(FramedServiceImpl {
                 handler,
                 disconnect_timeout,
+                limits,
                 time,
+                shutdown_timeout,
                 connect: fut.await?,
                 _t: PhantomData,
             })
@@ -80,14 +120,28 @@ pub(crate) struct FramedServiceImpl<St, C, T, Io, Codec> {
     connect: C,
     handler: Rc<T>,
     disconnect_timeout: u16,
+    limits: BufferLimits,
     time: Timer,
+    shutdown_timeout: Duration,
     _t: PhantomData<(St, Io, Codec)>,
 }
 
 impl<St, C, T, Io, Codec> Service for FramedServiceImpl<St, C, T, Io, Codec>
 where
     Io: AsyncRead + AsyncWrite + Unpin + 'static,
-    C: Service<Request = Io, Response = (Io, State, Codec, St, u16)>,
+    C: Service<
+        Request = Io,
+        Response = (
+            Io,
+            State,
+            Codec,
+            St,
+            Rc<Cell<u16>>,
+            Rc<Cell<Option<ShutdownStatus>>>,
+            Rc<Cell<bool>>,
+            Rc<FlushWaiters>,
+        ),
+    >,
     C::Error: fmt::Debug,
     C::Future: 'static,
     T: ServiceFactory<
@@ -105,7 +159,7 @@ where
     type Request = Io;
     type Response = ();
     type Error = C::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<(), Self::Error>>>>;
+    type Future = FramedServiceResponse<C, T, Io, Codec>;
 
     #[inline]
     fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -121,26 +175,15 @@ where
     fn call(&self, req: Io) -> Self::Future {
         log::trace!("Start connection handshake");
 
-        let handler = self.handler.clone();
-        let timeout = self.disconnect_timeout;
-        let handshake = self.connect.call(req);
-        let time = self.time.clone();
-
-        Box::pin(async move {
-            let (io, st, codec, session, keepalive) = handshake.await.map_err(|e| {
-                log::trace!("Connection handshake failed: {:?}", e);
-                e
-            })?;
-            log::trace!("Connection handshake succeeded");
-
-            let handler = handler.new_service(session).await?;
-            log::trace!("Connection handler is created, starting dispatcher");
-
-            Dispatcher::with(io, st, codec, handler, time)
-                .keepalive_timeout(keepalive as u16)
-                .disconnect_timeout(timeout)
-                .await
-        })
+        FramedServiceResponse {
+            state: FramedServiceState::Handshake { fut: self.connect.call(req) },
+            delay: None,
+            handler: self.handler.clone(),
+            disconnect_timeout: self.disconnect_timeout,
+            limits: self.limits,
+            time: self.time.clone(),
+            shutdown_timeout: self.shutdown_timeout,
+        }
     }
 }
 
@@ -148,7 +191,9 @@ pub(crate) struct FramedService2<St, C, T, Io, Codec> {
     connect: C,
     handler: Rc<T>,
     disconnect_timeout: u16,
+    limits: BufferLimits,
     time: Timer,
+    shutdown_timeout: Duration,
     _t: PhantomData<(St, Io, Codec)>,
 }
 
@@ -157,20 +202,48 @@ impl<St, C, T, Io, Codec> FramedService2<St, C, T, Io, Codec> {
         FramedService2 {
             connect,
             disconnect_timeout,
+            limits: BufferLimits::default(),
             handler: Rc::new(service),
             time: Timer::with(Duration::from_secs(1)),
+            shutdown_timeout: Duration::ZERO,
             _t: PhantomData,
         }
     }
+
+    /// Set per-connection buffer limits
+    pub(crate) fn buffer_limits(mut self, limits: BufferLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Set connection timer
+    pub(crate) fn timer(mut self, timer: Timer) -> Self {
+        self.time = timer;
+        self
+    }
+
+    /// Set in-flight responses shutdown timeout
+    pub(crate) fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
 }
 
 impl<St, C, T, Io, Codec> ServiceFactory for FramedService2<St, C, T, Io, Codec>
 where
     Io: AsyncRead + AsyncWrite + Unpin + 'static,
     C: ServiceFactory<
-        Config = (),
         Request = (Io, State),
-        Response = (Io, State, Codec, St, u16),
+        Response = (
+            Io,
+            State,
+            Codec,
+            St,
+            Rc<Cell<u16>>,
+            Rc<Cell<Option<ShutdownStatus>>>,
+            Rc<Cell<bool>>,
+            Rc<FlushWaiters>,
+        ),
     >,
     C::Error: fmt::Debug,
     C::Future: 'static,
@@ -187,7 +260,7 @@ where
     Codec: Decoder + Encoder + Clone + 'static,
     <Codec as Encoder>::Item: 'static,
 {
-    type Config = ();
+    type Config = C::Config;
     type Request = (Io, State, Option<Pin<Box<Sleep>>>);
     type Response = ();
     type Error = C::Error;
@@ -195,18 +268,22 @@ where
     type Service = FramedServiceImpl2<St, C::Service, T, Io, Codec>;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Service, Self::InitError>>>>;
 
-    fn new_service(&self, _: ()) -> Self::Future {
-        let fut = self.connect.new_service(());
+    fn new_service(&self, cfg: C::Config) -> Self::Future {
+        let fut = self.connect.new_service(cfg);
         let handler = self.handler.clone();
         let disconnect_timeout = self.disconnect_timeout;
+        let limits = self.limits;
         let time = self.time.clone();
+        let shutdown_timeout = self.shutdown_timeout;
 
         // create connect service and then create service impl
         Box::pin(async move {
             Ok(FramedServiceImpl2 {
                 handler,
                 disconnect_timeout,
+                limits,
                 time,
+                shutdown_timeout,
                 connect: fut.await?,
                 _t: PhantomData,
             })
@@ -218,14 +295,28 @@ pub(crate) struct FramedServiceImpl2<St, C, T, Io, Codec> {
     connect: C,
     handler: Rc<T>,
     disconnect_timeout: u16,
+    limits: BufferLimits,
     time: Timer,
+    shutdown_timeout: Duration,
     _t: PhantomData<(St, Io, Codec)>,
 }
 
 impl<St, C, T, Io, Codec> Service for FramedServiceImpl2<St, C, T, Io, Codec>
 where
     Io: AsyncRead + AsyncWrite + Unpin + 'static,
-    C: Service<Request = (Io, State), Response = (Io, State, Codec, St, u16)>,
+    C: Service<
+        Request = (Io, State),
+        Response = (
+            Io,
+            State,
+            Codec,
+            St,
+            Rc<Cell<u16>>,
+            Rc<Cell<Option<ShutdownStatus>>>,
+            Rc<Cell<bool>>,
+            Rc<FlushWaiters>,
+        ),
+    >,
     C::Error: fmt::Debug,
     C::Future: 'static,
     T: ServiceFactory<
@@ -243,7 +334,7 @@ where
     type Request = (Io, State, Option<Pin<Box<Sleep>>>);
     type Response = ();
     type Error = C::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<(), Self::Error>>>>;
+    type Future = FramedServiceResponse<C, T, Io, Codec>;
 
     #[inline]
     fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -259,53 +350,153 @@ where
     fn call(&self, (req, state, delay): (Io, State, Option<Pin<Box<Sleep>>>)) -> Self::Future {
         log::trace!("Start connection handshake");
 
-        let handler = self.handler.clone();
-        let timeout = self.disconnect_timeout;
-        let handshake = self.connect.call((req, state));
-        let time = self.time.clone();
+        FramedServiceResponse {
+            state: FramedServiceState::Handshake { fut: self.connect.call((req, state)) },
+            delay,
+            handler: self.handler.clone(),
+            disconnect_timeout: self.disconnect_timeout,
+            limits: self.limits,
+            time: self.time.clone(),
+            shutdown_timeout: self.shutdown_timeout,
+        }
+    }
+}
 
-        Box::pin(async move {
-            let (io, state, codec, ka, handler) = if let Some(delay) = delay {
-                let res = select(
-                    delay,
-                    Box::pin(async {
-                        let (io, state, codec, st, ka) = handshake.await.map_err(|e| {
-                            log::trace!("Connection handshake failed: {:?}", e);
-                            e
-                        })?;
-                        log::trace!("Connection handshake succeeded");
+type Connection<Io, Codec> = (
+    Io,
+    State,
+    Codec,
+    Rc<Cell<u16>>,
+    Rc<Cell<Option<ShutdownStatus>>>,
+    Rc<Cell<bool>>,
+    Rc<FlushWaiters>,
+);
+
+pin_project_lite::pin_project! {
+    /// Connection future, performs handshake, creates connection handler
+    /// and runs dispatcher
+    pub(crate) struct FramedServiceResponse<C, T, Io, Codec>
+    where
+        C: Service,
+        T: ServiceFactory,
+        T::Service: Service<Request = DispatchItem<Codec>, Response = ResponseItem<Codec>>,
+        <T::Service as Service>::Error: 'static,
+        <T::Service as Service>::Future: 'static,
+        Codec: Encoder,
+        Codec: Decoder,
+        <Codec as Encoder>::Item: 'static,
+    {
+        #[pin]
+        state: FramedServiceState<C, T, Io, Codec>,
+        delay: Option<Pin<Box<Sleep>>>,
+        handler: Rc<T>,
+        disconnect_timeout: u16,
+        limits: BufferLimits,
+        time: Timer,
+        shutdown_timeout: Duration,
+    }
+}
 
-                        let handler = handler.new_service(st).await?;
-                        log::trace!("Connection handler is created, starting dispatcher");
+pin_project_lite::pin_project! {
+    #[project = FramedServiceStateProject]
+    enum FramedServiceState<C, T, Io, Codec>
+    where
+        C: Service,
+        T: ServiceFactory,
+        T::Service: Service<Request = DispatchItem<Codec>, Response = ResponseItem<Codec>>,
+        <T::Service as Service>::Error: 'static,
+        <T::Service as Service>::Future: 'static,
+        Codec: Encoder,
+        Codec: Decoder,
+        <Codec as Encoder>::Item: 'static,
+    {
+        Handshake { #[pin] fut: C::Future },
+        Handler { #[pin] fut: T::Future, conn: Option<Connection<Io, Codec>> },
+        Dispatcher { #[pin] fut: Dispatcher<T::Service, Codec> },
+    }
+}
 
-                        Ok::<_, C::Error>((io, state, codec, ka, handler))
-                    }),
-                )
-                .await;
+impl<St, C, T, Io, Codec> Future for FramedServiceResponse<C, T, Io, Codec>
+where
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    C: Service<
+        Response = (
+            Io,
+            State,
+            Codec,
+            St,
+            Rc<Cell<u16>>,
+            Rc<Cell<Option<ShutdownStatus>>>,
+            Rc<Cell<bool>>,
+            Rc<FlushWaiters>,
+        ),
+    >,
+    C::Error: fmt::Debug,
+    T: ServiceFactory<
+            Config = St,
+            Request = DispatchItem<Codec>,
+            Response = ResponseItem<Codec>,
+            Error = C::Error,
+            InitError = C::Error,
+        > + 'static,
+    <T::Service as Service>::Error: 'static,
+    <T::Service as Service>::Future: 'static,
+    Codec: Decoder + Encoder + Clone + 'static,
+    <Codec as Encoder>::Item: 'static,
+{
+    type Output = Result<(), C::Error>;
 
-                match res {
-                    Either::Left(_) => {
-                        log::warn!("Handshake timed out");
-                        return Ok(());
-                    }
-                    Either::Right(item) => item?,
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.as_mut().project();
+
+        loop {
+            // handshake timeout covers handshake and handler creation
+            if let Some(ref mut delay) = this.delay {
+                if delay.as_mut().poll(cx).is_ready() {
+                    log::warn!("Handshake timed out");
+                    return Poll::Ready(Ok(()));
                 }
-            } else {
-                let (io, state, codec, st, ka) = handshake.await.map_err(|e| {
-                    log::trace!("Connection handshake failed: {:?}", e);
-                    e
-                })?;
-                log::trace!("Connection handshake succeeded");
-
-                let handler = handler.new_service(st).await?;
-                log::trace!("Connection handler is created, starting dispatcher");
-                (io, state, codec, ka, handler)
-            };
+            }
 
-            Dispatcher::with(io, state, codec, handler, time)
-                .keepalive_timeout(ka as u16)
-                .disconnect_timeout(timeout)
-                .await
-        })
+            let state = match this.state.as_mut().project() {
+                FramedServiceStateProject::Handshake { fut } => match fut.poll(cx) {
+                    Poll::Ready(Ok((io, state, codec, st, ka, status, expired, flush))) => {
+                        log::trace!("Connection handshake succeeded");
+                        FramedServiceState::Handler {
+                            fut: this.handler.new_service(st),
+                            conn: Some((io, state, codec, ka, status, expired, flush)),
+                        }
+                    }
+                    Poll::Ready(Err(e)) => {
+                        log::trace!("Connection handshake failed: {:?}", e);
+                        return Poll::Ready(Err(e));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                FramedServiceStateProject::Handler { fut, conn } => match fut.poll(cx) {
+                    Poll::Ready(Ok(handler)) => {
+                        log::trace!("Connection handler is created, starting dispatcher");
+                        let (io, state, codec, ka, status, expired, flush) =
+                            conn.take().unwrap();
+                        *this.delay = None;
+
+                        FramedServiceState::Dispatcher {
+                            fut: Dispatcher::with(io, state, codec, handler, this.time.clone())
+                                .keepalive(ka)
+                                .shutdown_status(status)
+                                .keepalive_expired(expired)
+                                .flush_waiters(flush)
+                                .disconnect_timeout(*this.disconnect_timeout)
+                                .shutdown_timeout(*this.shutdown_timeout)
+                                .buffer_limits(*this.limits),
+                        }
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                },
+                FramedServiceStateProject::Dispatcher { fut } => return fut.poll(cx),
+            };
+            this.state.set(state);
+        }
     }
 }
@@ -1,5 +1,41 @@
-use std::ops::Deref;
-use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use std::{cell::Cell, cell::RefCell, cmp, future::Future, ops::Deref, pin::Pin, rc::Rc};
+
+use futures_channel::mpsc;
+use ntex::task::LocalWaker;
+use ntex::util::{poll_fn, ByteString, HashMap};
+use ntex::Stream;
+
+use crate::actor::Addr;
+
+use crate::v5;
+
+/// Reason of connection session end
+///
+/// Embedding broker could decide whether will message must be published.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// Client sent `DISCONNECT` packet before closing connection, will
+    /// message must not be published. For MQTT 5 only `DISCONNECT` with
+    /// normal disconnection reason is clean.
+    Clean,
+    /// Connection got closed without `DISCONNECT` packet from the client,
+    /// by io or protocol error or by server.
+    Abnormal,
+    /// Connection is closed with `MqttSink::takeover()` by new connection
+    /// of the same client.
+    Takeover,
+    /// Client did not send any packet within keep-alive interval.
+    KeepAliveTimeout,
+}
+
+impl SessionEnd {
+    /// Check if session ended with `DISCONNECT` packet from the client
+    pub fn is_clean(&self) -> bool {
+        *self == SessionEnd::Clean
+    }
+}
 
 /// Mqtt connection session
 pub struct Session<T, St>(Rc<SessionInner<T, St>>);
@@ -7,8 +43,65 @@ pub struct Session<T, St>(Rc<SessionInner<T, St>>);
 struct SessionInner<T, St> {
     st: St,
     sink: T,
+    client_id: ByteString,
     max_receive: u16,
     max_topic_alias: u16,
+    /// will delay interval of v5 connect packet, `None` if will is not set
+    will_delay: Option<u32>,
+    /// session expiry interval of v5 connect packet
+    session_expiry: u32,
+    /// tasks spawned by session
+    tasks: Rc<SessionTasks>,
+}
+
+/// Tasks tied to connection lifetime
+#[derive(Default)]
+pub(crate) struct SessionTasks {
+    closed: Cell<bool>,
+    next_id: Cell<usize>,
+    /// wakers of running tasks
+    running: RefCell<HashMap<usize, Option<Waker>>>,
+    /// connection shutdown waits for running tasks
+    shutdown: LocalWaker,
+}
+
+impl SessionTasks {
+    /// Cancel running tasks, new tasks are not spawned
+    pub(crate) fn close(&self) {
+        self.closed.set(true);
+        for waker in self.running.borrow_mut().values_mut() {
+            if let Some(waker) = waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Check if all tasks are completed
+    pub(crate) fn poll_completed(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.running.borrow().is_empty() {
+            Poll::Ready(())
+        } else {
+            self.shutdown.register(cx.waker());
+            Poll::Pending
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.running.borrow().len()
+    }
+}
+
+/// Removes task from running tasks
+struct TaskGuard(usize, Rc<SessionTasks>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        let mut running = self.1.running.borrow_mut();
+        running.remove(&self.0);
+        if running.is_empty() {
+            self.1.shutdown.wake();
+        }
+    }
 }
 
 impl<T, St> Clone for Session<T, St> {
@@ -19,12 +112,38 @@ impl<T, St> Clone for Session<T, St> {
 }
 
 impl<T, St> Session<T, St> {
-    pub(crate) fn new(st: St, sink: T) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive: 0, max_topic_alias: 0 }))
+    pub(crate) fn new(st: St, sink: T, client_id: ByteString) -> Self {
+        Session(Rc::new(SessionInner {
+            st,
+            sink,
+            client_id,
+            max_receive: 0,
+            max_topic_alias: 0,
+            will_delay: None,
+            session_expiry: 0,
+            tasks: Rc::new(SessionTasks::default()),
+        }))
     }
 
-    pub(crate) fn new_v5(st: St, sink: T, max_receive: u16, max_topic_alias: u16) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive, max_topic_alias }))
+    pub(crate) fn new_v5(
+        st: St,
+        sink: T,
+        client_id: ByteString,
+        max_receive: u16,
+        max_topic_alias: u16,
+        will_delay: Option<u32>,
+        session_expiry: u32,
+    ) -> Self {
+        Session(Rc::new(SessionInner {
+            st,
+            sink,
+            client_id,
+            max_receive,
+            max_topic_alias,
+            will_delay,
+            session_expiry,
+            tasks: Rc::new(SessionTasks::default()),
+        }))
     }
 
     #[inline]
@@ -37,9 +156,103 @@ impl<T, St> Session<T, St> {
         &self.0.st
     }
 
+    #[inline]
+    /// Client identifier from `Connect` packet
+    pub fn client_id(&self) -> &ByteString {
+        &self.0.client_id
+    }
+
+    /// Spawn task tied to connection lifetime.
+    ///
+    /// Task is cancelled when connection closes, connection shutdown
+    /// completes after all session tasks are dropped. Task is not
+    /// spawned if connection is already closed.
+    pub fn spawn<F>(&self, fut: F)
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        let tasks = self.0.tasks.clone();
+        if tasks.closed.get() {
+            return;
+        }
+        let id = tasks.next_id.get();
+        tasks.next_id.set(id.wrapping_add(1));
+        tasks.running.borrow_mut().insert(id, None);
+
+        ntex::rt::spawn(async move {
+            let guard = TaskGuard(id, tasks);
+            let mut fut = Box::pin(fut);
+            poll_fn(|cx| {
+                if guard.1.closed.get() || fut.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(());
+                }
+                if let Some(waker) = guard.1.running.borrow_mut().get_mut(&id) {
+                    *waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            })
+            .await;
+        });
+    }
+
+    /// Start actor tied to connection lifetime.
+    ///
+    /// Messages sent to returned address are processed one at a time by `f`
+    /// in a session task, `f` receives session as actor context. Actor stops
+    /// when connection closes or when all addresses are dropped.
+    pub fn start_actor<M, F, R>(&self, mut f: F) -> Addr<M>
+    where
+        T: 'static,
+        St: 'static,
+        M: 'static,
+        F: FnMut(Session<T, St>, M) -> R + 'static,
+        R: Future<Output = ()> + 'static,
+    {
+        let (tx, mut rx) = mpsc::unbounded();
+        let session = self.clone();
+        self.spawn(async move {
+            while let Some(msg) = poll_fn(|cx| Pin::new(&mut rx).poll_next(cx)).await {
+                f(session.clone(), msg).await;
+            }
+        });
+        Addr::new(tx)
+    }
+
+    /// Number of running session tasks
+    pub fn tasks(&self) -> usize {
+        self.0.tasks.len()
+    }
+
     pub(crate) fn params(&self) -> (u16, u16) {
         (self.0.max_receive, self.0.max_topic_alias)
     }
+
+    pub(crate) fn session_tasks(&self) -> Rc<SessionTasks> {
+        self.0.tasks.clone()
+    }
+}
+
+impl<St> Session<v5::MqttSink, St> {
+    /// Delay of will message publication after connection is closed.
+    ///
+    /// `disconnect` is `DISCONNECT` packet received from the client, if any.
+    /// Returns `None` if client did not set will or will is deleted by normal
+    /// disconnect. Will is published after will delay interval or when the session
+    /// ends, whichever happens first, session expiry interval could be updated
+    /// by `DISCONNECT` packet.
+    pub fn will_delay(&self, disconnect: Option<&v5::codec::Disconnect>) -> Option<Duration> {
+        let delay = self.0.will_delay?;
+        let expiry = match disconnect {
+            Some(pkt)
+                if pkt.reason_code == v5::codec::DisconnectReasonCode::NormalDisconnection =>
+            {
+                return None
+            }
+            Some(pkt) => pkt.session_expiry_interval_secs.unwrap_or(self.0.session_expiry),
+            None => self.0.session_expiry,
+        };
+        Some(Duration::from_secs(cmp::min(delay, expiry) as u64))
+    }
 }
 
 impl<T, St> Deref for Session<T, St> {
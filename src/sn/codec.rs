@@ -0,0 +1,681 @@
+//! MQTT-SN v1.2 protocol codec
+use std::convert::TryFrom;
+
+use ntex_bytes::{Buf, BufMut, ByteString, Bytes, BytesMut};
+use ntex_codec::{Decoder, Encoder};
+
+use crate::error::{DecodeError, EncodeError};
+pub use crate::types::QoS;
+
+pub(crate) mod msg_type {
+    pub(crate) const ADVERTISE: u8 = 0x00;
+    pub(crate) const SEARCHGW: u8 = 0x01;
+    pub(crate) const GWINFO: u8 = 0x02;
+    pub(crate) const CONNECT: u8 = 0x04;
+    pub(crate) const CONNACK: u8 = 0x05;
+    pub(crate) const WILLTOPICREQ: u8 = 0x06;
+    pub(crate) const WILLTOPIC: u8 = 0x07;
+    pub(crate) const WILLMSGREQ: u8 = 0x08;
+    pub(crate) const WILLMSG: u8 = 0x09;
+    pub(crate) const REGISTER: u8 = 0x0A;
+    pub(crate) const REGACK: u8 = 0x0B;
+    pub(crate) const PUBLISH: u8 = 0x0C;
+    pub(crate) const PUBACK: u8 = 0x0D;
+    pub(crate) const PUBCOMP: u8 = 0x0E;
+    pub(crate) const PUBREC: u8 = 0x0F;
+    pub(crate) const PUBREL: u8 = 0x10;
+    pub(crate) const SUBSCRIBE: u8 = 0x12;
+    pub(crate) const SUBACK: u8 = 0x13;
+    pub(crate) const UNSUBSCRIBE: u8 = 0x14;
+    pub(crate) const UNSUBACK: u8 = 0x15;
+    pub(crate) const PINGREQ: u8 = 0x16;
+    pub(crate) const PINGRESP: u8 = 0x17;
+    pub(crate) const DISCONNECT: u8 = 0x18;
+}
+
+const PROTOCOL_ID: u8 = 0x01;
+
+const DUP: u8 = 0b1000_0000;
+const QOS_SHIFT: u8 = 5;
+const QOS_MASK: u8 = 0b0110_0000;
+const RETAIN: u8 = 0b0001_0000;
+const WILL: u8 = 0b0000_1000;
+const CLEAN_SESSION: u8 = 0b0000_0100;
+const TOPIC_TYPE_MASK: u8 = 0b0000_0011;
+
+const TOPIC_NORMAL: u8 = 0;
+const TOPIC_PREDEFINED: u8 = 1;
+const TOPIC_SHORT: u8 = 2;
+
+prim_enum! {
+    /// MQTT-SN return code
+    pub enum ReturnCode {
+        /// Accepted
+        Accepted = 0,
+        /// Rejected, congestion
+        Congestion = 1,
+        /// Rejected, invalid topic id
+        InvalidTopicId = 2,
+        /// Rejected, not supported
+        NotSupported = 3
+    }
+}
+
+/// Topic of publish packet
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TopicId {
+    /// Topic id registered with `REGISTER` packet
+    Normal(u16),
+    /// Pre-defined topic id
+    Predefined(u16),
+    /// Short topic name, two characters
+    Short([u8; 2]),
+}
+
+impl TopicId {
+    /// Raw topic id value
+    pub fn value(&self) -> u16 {
+        match self {
+            TopicId::Normal(id) | TopicId::Predefined(id) => *id,
+            TopicId::Short(name) => u16::from_be_bytes(*name),
+        }
+    }
+
+    fn topic_type(&self) -> u8 {
+        match self {
+            TopicId::Normal(_) => TOPIC_NORMAL,
+            TopicId::Predefined(_) => TOPIC_PREDEFINED,
+            TopicId::Short(_) => TOPIC_SHORT,
+        }
+    }
+}
+
+/// Topic of subscribe and unsubscribe packets
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SubscribeTopic {
+    /// Topic name or topic filter
+    Name(ByteString),
+    /// Pre-defined topic id
+    Predefined(u16),
+    /// Short topic name, two characters
+    Short([u8; 2]),
+}
+
+impl SubscribeTopic {
+    fn topic_type(&self) -> u8 {
+        match self {
+            SubscribeTopic::Name(_) => TOPIC_NORMAL,
+            SubscribeTopic::Predefined(_) => TOPIC_PREDEFINED,
+            SubscribeTopic::Short(_) => TOPIC_SHORT,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+/// Connect packet content
+pub struct Connect {
+    /// client requests will topic and will message prompting
+    pub will: bool,
+    /// the handling of the Session state
+    pub clean_session: bool,
+    /// keep-alive duration in seconds
+    pub duration: u16,
+    /// identifies the Client to the Gateway
+    pub client_id: ByteString,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Publish message
+pub struct Publish {
+    /// this might be re-delivery of an earlier attempt to send the message
+    pub dup: bool,
+    pub retain: bool,
+    /// the level of assurance for delivery, QoS -1 is not supported
+    pub qos: QoS,
+    /// the information channel to which payload data is published
+    pub topic: TopicId,
+    /// message id, `0` for QoS 0 messages
+    pub msg_id: u16,
+    /// the Application Message that is being published
+    pub payload: Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// MQTT-SN packet
+pub enum Packet {
+    /// Gateway advertisement
+    Advertise { gw_id: u8, duration: u16 },
+    /// Gateway search request
+    SearchGw { radius: u8 },
+    /// Gateway info, address is set if message is sent by client
+    GwInfo { gw_id: u8, gw_addr: Bytes },
+    /// Client request to connect
+    Connect(Connect),
+    /// Connect acknowledgment
+    ConnectAck { return_code: ReturnCode },
+    /// Will topic request
+    WillTopicRequest,
+    /// Will topic, empty topic removes will
+    WillTopic { qos: QoS, retain: bool, topic: ByteString },
+    /// Will message request
+    WillMessageRequest,
+    /// Will message
+    WillMessage { message: Bytes },
+    /// Topic name registration
+    Register { topic_id: u16, msg_id: u16, topic: ByteString },
+    /// Topic name registration acknowledgment
+    RegisterAck { topic_id: u16, msg_id: u16, return_code: ReturnCode },
+    /// Publish message
+    Publish(Publish),
+    /// Publish acknowledgment
+    PublishAck { topic_id: u16, msg_id: u16, return_code: ReturnCode },
+    /// Publish complete (QoS 2 publish received, part 3)
+    PublishComplete { msg_id: u16 },
+    /// Publish received (QoS 2 publish received, part 1)
+    PublishReceived { msg_id: u16 },
+    /// Publish release (QoS 2 publish received, part 2)
+    PublishRelease { msg_id: u16 },
+    /// Client subscribe request
+    Subscribe { dup: bool, qos: QoS, msg_id: u16, topic: SubscribeTopic },
+    /// Subscribe acknowledgment
+    SubscribeAck { qos: QoS, topic_id: u16, msg_id: u16, return_code: ReturnCode },
+    /// Client unsubscribe request
+    Unsubscribe { msg_id: u16, topic: SubscribeTopic },
+    /// Unsubscribe acknowledgment
+    UnsubscribeAck { msg_id: u16 },
+    /// Ping request, client id is set by sleeping client
+    PingRequest { client_id: Option<ByteString> },
+    /// Ping response
+    PingResponse,
+    /// Disconnect, duration is set by client that goes to sleep
+    Disconnect { duration: Option<u16> },
+}
+
+impl Packet {
+    /// Message type of the packet
+    pub fn msg_type(&self) -> u8 {
+        match self {
+            Packet::Advertise { .. } => msg_type::ADVERTISE,
+            Packet::SearchGw { .. } => msg_type::SEARCHGW,
+            Packet::GwInfo { .. } => msg_type::GWINFO,
+            Packet::Connect(_) => msg_type::CONNECT,
+            Packet::ConnectAck { .. } => msg_type::CONNACK,
+            Packet::WillTopicRequest => msg_type::WILLTOPICREQ,
+            Packet::WillTopic { .. } => msg_type::WILLTOPIC,
+            Packet::WillMessageRequest => msg_type::WILLMSGREQ,
+            Packet::WillMessage { .. } => msg_type::WILLMSG,
+            Packet::Register { .. } => msg_type::REGISTER,
+            Packet::RegisterAck { .. } => msg_type::REGACK,
+            Packet::Publish(_) => msg_type::PUBLISH,
+            Packet::PublishAck { .. } => msg_type::PUBACK,
+            Packet::PublishComplete { .. } => msg_type::PUBCOMP,
+            Packet::PublishReceived { .. } => msg_type::PUBREC,
+            Packet::PublishRelease { .. } => msg_type::PUBREL,
+            Packet::Subscribe { .. } => msg_type::SUBSCRIBE,
+            Packet::SubscribeAck { .. } => msg_type::SUBACK,
+            Packet::Unsubscribe { .. } => msg_type::UNSUBSCRIBE,
+            Packet::UnsubscribeAck { .. } => msg_type::UNSUBACK,
+            Packet::PingRequest { .. } => msg_type::PINGREQ,
+            Packet::PingResponse => msg_type::PINGRESP,
+            Packet::Disconnect { .. } => msg_type::DISCONNECT,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// MQTT-SN protocol codec
+///
+/// Each UDP datagram carries exactly one MQTT-SN message.
+pub struct Codec;
+
+impl Codec {
+    /// Create `Codec` instance
+    pub fn new() -> Self {
+        Codec
+    }
+}
+
+impl Decoder for Codec {
+    type Item = Packet;
+    type Error = DecodeError;
+
+    fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, DecodeError> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        let (len, header) = if src[0] == 0x01 {
+            if src.len() < 3 {
+                return Ok(None);
+            }
+            (u16::from_be_bytes([src[1], src[2]]) as usize, 3)
+        } else {
+            (src[0] as usize, 1)
+        };
+        ensure!(len > header, DecodeError::InvalidLength);
+        if src.len() < len {
+            return Ok(None);
+        }
+
+        let mut buf = src.split_to(len).freeze();
+        buf.advance(header);
+        let tp = buf.get_u8();
+        decode_packet(tp, buf).map(Some)
+    }
+}
+
+impl Encoder for Codec {
+    type Item = Packet;
+    type Error = EncodeError;
+
+    fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        let mut body = BytesMut::new();
+        encode_body(&item, &mut body)?;
+
+        // length field covers whole message
+        if body.len() + 2 <= 255 {
+            dst.reserve(body.len() + 2);
+            dst.put_u8((body.len() + 2) as u8);
+        } else {
+            let len = body.len() + 4;
+            ensure!(len <= u16::MAX as usize, EncodeError::InvalidLength);
+            dst.reserve(len);
+            dst.put_u8(0x01);
+            dst.put_u16(len as u16);
+        }
+        dst.put_u8(item.msg_type());
+        dst.extend_from_slice(&body);
+        Ok(())
+    }
+}
+
+fn decode_packet(tp: u8, mut src: Bytes) -> Result<Packet, DecodeError> {
+    let pkt = match tp {
+        msg_type::ADVERTISE => {
+            ensure!(src.remaining() == 3, DecodeError::InvalidLength);
+            Packet::Advertise { gw_id: src.get_u8(), duration: src.get_u16() }
+        }
+        msg_type::SEARCHGW => {
+            ensure!(src.remaining() == 1, DecodeError::InvalidLength);
+            Packet::SearchGw { radius: src.get_u8() }
+        }
+        msg_type::GWINFO => {
+            ensure!(src.has_remaining(), DecodeError::InvalidLength);
+            Packet::GwInfo { gw_id: src.get_u8(), gw_addr: src }
+        }
+        msg_type::CONNECT => {
+            ensure!(src.remaining() >= 4, DecodeError::InvalidLength);
+            let flags = src.get_u8();
+            ensure!(src.get_u8() == PROTOCOL_ID, DecodeError::InvalidProtocol);
+            Packet::Connect(Connect {
+                will: flags & WILL != 0,
+                clean_session: flags & CLEAN_SESSION != 0,
+                duration: src.get_u16(),
+                client_id: ByteString::try_from(src)?,
+            })
+        }
+        msg_type::CONNACK => Packet::ConnectAck { return_code: decode_return_code(&mut src)? },
+        msg_type::WILLTOPICREQ => Packet::WillTopicRequest,
+        msg_type::WILLTOPIC => {
+            if src.has_remaining() {
+                let flags = src.get_u8();
+                Packet::WillTopic {
+                    qos: decode_qos(flags)?,
+                    retain: flags & RETAIN != 0,
+                    topic: ByteString::try_from(src)?,
+                }
+            } else {
+                Packet::WillTopic {
+                    qos: QoS::AtMostOnce,
+                    retain: false,
+                    topic: ByteString::new(),
+                }
+            }
+        }
+        msg_type::WILLMSGREQ => Packet::WillMessageRequest,
+        msg_type::WILLMSG => Packet::WillMessage { message: src },
+        msg_type::REGISTER => {
+            ensure!(src.remaining() >= 4, DecodeError::InvalidLength);
+            Packet::Register {
+                topic_id: src.get_u16(),
+                msg_id: src.get_u16(),
+                topic: ByteString::try_from(src)?,
+            }
+        }
+        msg_type::REGACK => {
+            ensure!(src.remaining() == 5, DecodeError::InvalidLength);
+            Packet::RegisterAck {
+                topic_id: src.get_u16(),
+                msg_id: src.get_u16(),
+                return_code: decode_return_code(&mut src)?,
+            }
+        }
+        msg_type::PUBLISH => {
+            ensure!(src.remaining() >= 5, DecodeError::InvalidLength);
+            let flags = src.get_u8();
+            let id = src.get_u16();
+            let topic = match flags & TOPIC_TYPE_MASK {
+                TOPIC_NORMAL => TopicId::Normal(id),
+                TOPIC_PREDEFINED => TopicId::Predefined(id),
+                TOPIC_SHORT => TopicId::Short(id.to_be_bytes()),
+                _ => return Err(DecodeError::MalformedPacket),
+            };
+            Packet::Publish(Publish {
+                topic,
+                dup: flags & DUP != 0,
+                retain: flags & RETAIN != 0,
+                qos: decode_qos(flags)?,
+                msg_id: src.get_u16(),
+                payload: src,
+            })
+        }
+        msg_type::PUBACK => {
+            ensure!(src.remaining() == 5, DecodeError::InvalidLength);
+            Packet::PublishAck {
+                topic_id: src.get_u16(),
+                msg_id: src.get_u16(),
+                return_code: decode_return_code(&mut src)?,
+            }
+        }
+        msg_type::PUBCOMP => Packet::PublishComplete { msg_id: decode_msg_id(&mut src)? },
+        msg_type::PUBREC => Packet::PublishReceived { msg_id: decode_msg_id(&mut src)? },
+        msg_type::PUBREL => Packet::PublishRelease { msg_id: decode_msg_id(&mut src)? },
+        msg_type::SUBSCRIBE => {
+            ensure!(src.remaining() >= 3, DecodeError::InvalidLength);
+            let flags = src.get_u8();
+            Packet::Subscribe {
+                dup: flags & DUP != 0,
+                qos: decode_qos(flags)?,
+                msg_id: src.get_u16(),
+                topic: decode_subscribe_topic(flags, src)?,
+            }
+        }
+        msg_type::SUBACK => {
+            ensure!(src.remaining() == 6, DecodeError::InvalidLength);
+            let flags = src.get_u8();
+            Packet::SubscribeAck {
+                qos: decode_qos(flags)?,
+                topic_id: src.get_u16(),
+                msg_id: src.get_u16(),
+                return_code: decode_return_code(&mut src)?,
+            }
+        }
+        msg_type::UNSUBSCRIBE => {
+            ensure!(src.remaining() >= 3, DecodeError::InvalidLength);
+            let flags = src.get_u8();
+            Packet::Unsubscribe {
+                msg_id: src.get_u16(),
+                topic: decode_subscribe_topic(flags, src)?,
+            }
+        }
+        msg_type::UNSUBACK => Packet::UnsubscribeAck { msg_id: decode_msg_id(&mut src)? },
+        msg_type::PINGREQ => Packet::PingRequest {
+            client_id: if src.has_remaining() {
+                Some(ByteString::try_from(src)?)
+            } else {
+                None
+            },
+        },
+        msg_type::PINGRESP => Packet::PingResponse,
+        msg_type::DISCONNECT => Packet::Disconnect {
+            duration: if src.has_remaining() {
+                ensure!(src.remaining() == 2, DecodeError::InvalidLength);
+                Some(src.get_u16())
+            } else {
+                None
+            },
+        },
+        _ => return Err(DecodeError::UnsupportedPacketType),
+    };
+    Ok(pkt)
+}
+
+fn decode_qos(flags: u8) -> Result<QoS, DecodeError> {
+    QoS::try_from((flags & QOS_MASK) >> QOS_SHIFT)
+}
+
+fn decode_return_code(src: &mut Bytes) -> Result<ReturnCode, DecodeError> {
+    ensure!(src.remaining() == 1, DecodeError::InvalidLength);
+    ReturnCode::try_from(src.get_u8())
+}
+
+fn decode_msg_id(src: &mut Bytes) -> Result<u16, DecodeError> {
+    ensure!(src.remaining() == 2, DecodeError::InvalidLength);
+    Ok(src.get_u16())
+}
+
+fn decode_subscribe_topic(flags: u8, mut src: Bytes) -> Result<SubscribeTopic, DecodeError> {
+    match flags & TOPIC_TYPE_MASK {
+        TOPIC_NORMAL => Ok(SubscribeTopic::Name(ByteString::try_from(src)?)),
+        TOPIC_PREDEFINED => {
+            ensure!(src.remaining() == 2, DecodeError::InvalidLength);
+            Ok(SubscribeTopic::Predefined(src.get_u16()))
+        }
+        TOPIC_SHORT => {
+            ensure!(src.remaining() == 2, DecodeError::InvalidLength);
+            Ok(SubscribeTopic::Short([src[0], src[1]]))
+        }
+        _ => Err(DecodeError::MalformedPacket),
+    }
+}
+
+fn encode_body(pkt: &Packet, dst: &mut BytesMut) -> Result<(), EncodeError> {
+    match pkt {
+        Packet::Advertise { gw_id, duration } => {
+            dst.put_u8(*gw_id);
+            dst.put_u16(*duration);
+        }
+        Packet::SearchGw { radius } => dst.put_u8(*radius),
+        Packet::GwInfo { gw_id, gw_addr } => {
+            dst.put_u8(*gw_id);
+            dst.extend_from_slice(gw_addr);
+        }
+        Packet::Connect(pkt) => {
+            let mut flags = 0;
+            if pkt.will {
+                flags |= WILL;
+            }
+            if pkt.clean_session {
+                flags |= CLEAN_SESSION;
+            }
+            dst.put_u8(flags);
+            dst.put_u8(PROTOCOL_ID);
+            dst.put_u16(pkt.duration);
+            dst.extend_from_slice(pkt.client_id.as_bytes());
+        }
+        Packet::ConnectAck { return_code } => dst.put_u8((*return_code).into()),
+        Packet::WillTopicRequest | Packet::WillMessageRequest | Packet::PingResponse => (),
+        Packet::WillTopic { qos, retain, topic } => {
+            if !topic.is_empty() {
+                dst.put_u8(flags(false, *qos, *retain, TOPIC_NORMAL));
+                dst.extend_from_slice(topic.as_bytes());
+            }
+        }
+        Packet::WillMessage { message } => dst.extend_from_slice(message),
+        Packet::Register { topic_id, msg_id, topic } => {
+            dst.put_u16(*topic_id);
+            dst.put_u16(*msg_id);
+            dst.extend_from_slice(topic.as_bytes());
+        }
+        Packet::RegisterAck { topic_id, msg_id, return_code }
+        | Packet::PublishAck { topic_id, msg_id, return_code } => {
+            dst.put_u16(*topic_id);
+            dst.put_u16(*msg_id);
+            dst.put_u8((*return_code).into());
+        }
+        Packet::Publish(pkt) => {
+            dst.put_u8(flags(pkt.dup, pkt.qos, pkt.retain, pkt.topic.topic_type()));
+            dst.put_u16(pkt.topic.value());
+            dst.put_u16(pkt.msg_id);
+            dst.extend_from_slice(&pkt.payload);
+        }
+        Packet::PublishComplete { msg_id }
+        | Packet::PublishReceived { msg_id }
+        | Packet::PublishRelease { msg_id }
+        | Packet::UnsubscribeAck { msg_id } => dst.put_u16(*msg_id),
+        Packet::Subscribe { dup, qos, msg_id, topic } => {
+            dst.put_u8(flags(*dup, *qos, false, topic.topic_type()));
+            dst.put_u16(*msg_id);
+            encode_subscribe_topic(topic, dst);
+        }
+        Packet::SubscribeAck { qos, topic_id, msg_id, return_code } => {
+            dst.put_u8(flags(false, *qos, false, TOPIC_NORMAL));
+            dst.put_u16(*topic_id);
+            dst.put_u16(*msg_id);
+            dst.put_u8((*return_code).into());
+        }
+        Packet::Unsubscribe { msg_id, topic } => {
+            dst.put_u8(topic.topic_type());
+            dst.put_u16(*msg_id);
+            encode_subscribe_topic(topic, dst);
+        }
+        Packet::PingRequest { client_id } => {
+            if let Some(ref id) = client_id {
+                dst.extend_from_slice(id.as_bytes());
+            }
+        }
+        Packet::Disconnect { duration } => {
+            if let Some(duration) = duration {
+                dst.put_u16(*duration);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn encode_subscribe_topic(topic: &SubscribeTopic, dst: &mut BytesMut) {
+    match topic {
+        SubscribeTopic::Name(name) => dst.extend_from_slice(name.as_bytes()),
+        SubscribeTopic::Predefined(id) => dst.put_u16(*id),
+        SubscribeTopic::Short(name) => dst.extend_from_slice(name),
+    }
+}
+
+fn flags(dup: bool, qos: QoS, retain: bool, topic_type: u8) -> u8 {
+    let mut flags = u8::from(qos) << QOS_SHIFT | topic_type;
+    if dup {
+        flags |= DUP;
+    }
+    if retain {
+        flags |= RETAIN;
+    }
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(pkt: Packet) {
+        let mut buf = BytesMut::new();
+        Codec.encode(pkt.clone(), &mut buf).unwrap();
+        assert_eq!(Codec.decode(&mut buf).unwrap(), Some(pkt));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        roundtrip(Packet::Advertise { gw_id: 1, duration: 900 });
+        roundtrip(Packet::SearchGw { radius: 1 });
+        roundtrip(Packet::GwInfo { gw_id: 1, gw_addr: Bytes::new() });
+        roundtrip(Packet::Connect(Connect {
+            will: true,
+            clean_session: true,
+            duration: 30,
+            client_id: ByteString::from_static("sensor-1"),
+        }));
+        roundtrip(Packet::ConnectAck { return_code: ReturnCode::Accepted });
+        roundtrip(Packet::WillTopic {
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            topic: ByteString::from_static("will"),
+        });
+        roundtrip(Packet::Register {
+            topic_id: 0,
+            msg_id: 1,
+            topic: ByteString::from_static("sensors/temp"),
+        });
+        roundtrip(Packet::RegisterAck {
+            topic_id: 1,
+            msg_id: 1,
+            return_code: ReturnCode::InvalidTopicId,
+        });
+        roundtrip(Packet::Publish(Publish {
+            dup: false,
+            retain: true,
+            qos: QoS::AtLeastOnce,
+            topic: TopicId::Short(*b"ab"),
+            msg_id: 2,
+            payload: Bytes::from_static(b"21.5"),
+        }));
+        roundtrip(Packet::PublishAck {
+            topic_id: 1,
+            msg_id: 2,
+            return_code: ReturnCode::Congestion,
+        });
+        roundtrip(Packet::Subscribe {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            msg_id: 3,
+            topic: SubscribeTopic::Name(ByteString::from_static("sensors/#")),
+        });
+        roundtrip(Packet::SubscribeAck {
+            qos: QoS::AtMostOnce,
+            topic_id: 0,
+            msg_id: 3,
+            return_code: ReturnCode::Accepted,
+        });
+        roundtrip(Packet::Unsubscribe { msg_id: 4, topic: SubscribeTopic::Predefined(10) });
+        roundtrip(Packet::UnsubscribeAck { msg_id: 4 });
+        roundtrip(Packet::PingRequest { client_id: None });
+        roundtrip(Packet::PingRequest { client_id: Some(ByteString::from_static("sensor")) });
+        roundtrip(Packet::PingResponse);
+        roundtrip(Packet::Disconnect { duration: Some(60) });
+        roundtrip(Packet::Disconnect { duration: None });
+    }
+
+    #[test]
+    fn test_long_message() {
+        let pkt = Packet::Publish(Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: TopicId::Normal(1),
+            msg_id: 0,
+            payload: Bytes::from(vec![b'a'; 300]),
+        });
+        let mut buf = BytesMut::new();
+        Codec.encode(pkt.clone(), &mut buf).unwrap();
+        assert_eq!(&buf[..3], &[0x01, 0x01, 0x35]);
+        assert_eq!(Codec.decode(&mut buf).unwrap(), Some(pkt));
+    }
+
+    #[test]
+    fn test_decode() {
+        // CONNECT, clean session, duration 10, client id "id"
+        let mut buf = BytesMut::from(&b"\x08\x04\x04\x01\x00\x0aid"[..]);
+        assert_eq!(
+            Codec.decode(&mut buf).unwrap(),
+            Some(Packet::Connect(Connect {
+                will: false,
+                clean_session: true,
+                duration: 10,
+                client_id: ByteString::from_static("id"),
+            }))
+        );
+
+        // incomplete message
+        let mut buf = BytesMut::from(&b"\x08\x04\x04"[..]);
+        assert_eq!(Codec.decode(&mut buf).unwrap(), None);
+
+        // unsupported protocol id
+        let mut buf = BytesMut::from(&b"\x06\x04\x04\x02\x00\x0a"[..]);
+        assert_eq!(Codec.decode(&mut buf), Err(DecodeError::InvalidProtocol));
+
+        // QoS -1 is not supported
+        let mut buf = BytesMut::from(&b"\x07\x0c\x60\x00\x01\x00\x00"[..]);
+        assert_eq!(Codec.decode(&mut buf), Err(DecodeError::MalformedPacket));
+
+        let mut buf = BytesMut::from(&b"\x02\x1f"[..]);
+        assert_eq!(Codec.decode(&mut buf), Err(DecodeError::UnsupportedPacketType));
+    }
+}
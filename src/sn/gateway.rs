@@ -0,0 +1,562 @@
+use std::time::{Duration, Instant};
+use std::{cell::RefCell, convert::TryFrom, io, net::SocketAddr, rc::Rc};
+
+use ntex::codec::{Decoder, Encoder};
+use ntex::rt::{net::UdpSocket, time::interval};
+use ntex::util::{select, ByteString, Bytes, BytesMut, Either, HashMap};
+
+use super::codec::{Codec, Connect, Packet, Publish, QoS, ReturnCode, SubscribeTopic, TopicId};
+use crate::{error::SendPacketError, v3::MqttSink, TopicFilter};
+
+/// Period of inactive clients check
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// MQTT-SN gateway
+///
+/// Gateway serves MQTT-SN clients over UDP socket and forwards their
+/// messages to upstream mqtt connection, all clients share the same
+/// upstream connection. Messages from upstream connection are passed
+/// to `publish()` and get delivered to subscribed clients with QoS 0.
+///
+/// Will messages and QoS 2 publishes are not supported. Client is
+/// removed if it is not active for 1.5 keep-alive durations.
+/// Number of connected clients is limited, see `max_clients()`.
+#[derive(Clone)]
+pub struct Gateway(Rc<GatewayInner>);
+
+struct GatewayInner {
+    gw_id: u8,
+    max_clients: usize,
+    sink: MqttSink,
+    codec: Codec,
+    socket: RefCell<Option<Rc<UdpSocket>>>,
+    predefined: HashMap<u16, ByteString>,
+    clients: RefCell<HashMap<SocketAddr, Client>>,
+}
+
+struct Client {
+    client_id: ByteString,
+    keep_alive: Duration,
+    last_seen: Instant,
+    topics: HashMap<u16, ByteString>,
+    next_topic_id: u16,
+    subscriptions: Vec<TopicFilter>,
+}
+
+impl Client {
+    fn new(pkt: Connect) -> Self {
+        Client {
+            client_id: pkt.client_id,
+            keep_alive: Duration::from_secs(pkt.duration as u64),
+            last_seen: Instant::now(),
+            topics: HashMap::default(),
+            next_topic_id: 0,
+            subscriptions: Vec::new(),
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.keep_alive != Duration::from_secs(0)
+            && now.duration_since(self.last_seen) > self.keep_alive * 3 / 2
+    }
+
+    /// Get topic id of topic name, returns `true` if topic is newly registered
+    ///
+    /// Returns `None` if all topic ids are in use.
+    fn register(&mut self, topic: &ByteString) -> Option<(u16, bool)> {
+        if let Some((id, _)) = self.topics.iter().find(|(_, t)| *t == topic) {
+            return Some((*id, false));
+        }
+        if self.topics.len() >= u16::MAX as usize {
+            return None;
+        }
+        // topic ids wrap around, skip ids that are still registered
+        loop {
+            self.next_topic_id = self.next_topic_id.wrapping_add(1).max(1);
+            if !self.topics.contains_key(&self.next_topic_id) {
+                break;
+            }
+        }
+        self.topics.insert(self.next_topic_id, topic.clone());
+        Some((self.next_topic_id, true))
+    }
+}
+
+impl Gateway {
+    /// Create gateway for upstream connection
+    pub fn new(sink: MqttSink) -> Self {
+        Gateway(Rc::new(GatewayInner {
+            sink,
+            gw_id: 1,
+            max_clients: 1024,
+            codec: Codec::new(),
+            socket: RefCell::new(None),
+            predefined: HashMap::default(),
+            clients: RefCell::new(HashMap::default()),
+        }))
+    }
+
+    /// Set gateway id, reported in `GWINFO` responses
+    ///
+    /// By default gateway id is `1`. Panics if gateway is cloned.
+    pub fn gateway_id(mut self, id: u8) -> Self {
+        Rc::get_mut(&mut self.0).expect("Gateway is cloned").gw_id = id;
+        self
+    }
+
+    /// Set max number of connected clients
+    ///
+    /// Connect from new client gets rejected with `Congestion` return code
+    /// if limit is reached. If max number is set to `0`, number of clients
+    /// is unlimited. By default max number is set to `1024`.
+    /// Panics if gateway is cloned.
+    pub fn max_clients(mut self, max: usize) -> Self {
+        Rc::get_mut(&mut self.0).expect("Gateway is cloned").max_clients = max;
+        self
+    }
+
+    /// Add pre-defined topic id
+    ///
+    /// Panics if gateway is cloned.
+    pub fn predefined_topic<T>(mut self, id: u16, topic: T) -> Self
+    where
+        ByteString: From<T>,
+    {
+        Rc::get_mut(&mut self.0)
+            .expect("Gateway is cloned")
+            .predefined
+            .insert(id, topic.into());
+        self
+    }
+
+    /// Number of connected clients
+    pub fn clients(&self) -> usize {
+        self.0.clients.borrow().len()
+    }
+
+    /// Deliver upstream message to subscribed clients
+    ///
+    /// Returns number of clients message is delivered to.
+    pub fn publish(&self, topic: &str, payload: Bytes) -> usize {
+        let topic = ByteString::from(topic);
+        let predefined =
+            self.0.predefined.iter().find(|(_, t)| **t == topic).map(|(id, _)| *id);
+
+        let mut packets = Vec::new();
+        for (addr, client) in self.0.clients.borrow_mut().iter_mut() {
+            if !client.subscriptions.iter().any(|f| f.matches_str(&topic)) {
+                continue;
+            }
+            let topic_id = if let Some(id) = predefined {
+                TopicId::Predefined(id)
+            } else if topic.len() == 2 {
+                let b = topic.as_bytes();
+                TopicId::Short([b[0], b[1]])
+            } else {
+                let (id, new) = if let Some(res) = client.register(&topic) {
+                    res
+                } else {
+                    log::trace!(
+                        "Topic ids of MQTT-SN client {:?} are exhausted",
+                        client.client_id
+                    );
+                    continue;
+                };
+                if new {
+                    packets.push((
+                        *addr,
+                        Packet::Register { topic_id: id, msg_id: 0, topic: topic.clone() },
+                    ));
+                }
+                TopicId::Normal(id)
+            };
+            packets.push((
+                *addr,
+                Packet::Publish(Publish {
+                    dup: false,
+                    retain: false,
+                    qos: QoS::AtMostOnce,
+                    topic: topic_id,
+                    msg_id: 0,
+                    payload: payload.clone(),
+                }),
+            ));
+        }
+
+        let mut delivered = 0;
+        for (addr, pkt) in packets {
+            if std::matches!(pkt, Packet::Publish(_)) {
+                delivered += 1;
+            }
+            self.send(addr, pkt);
+        }
+        delivered
+    }
+
+    /// Serve MQTT-SN clients
+    ///
+    /// Future resolves only if socket fails.
+    pub async fn run(&self, socket: UdpSocket) -> io::Result<()> {
+        let socket = Rc::new(socket);
+        *self.0.socket.borrow_mut() = Some(socket.clone());
+
+        // clients are expired even if no datagrams are received
+        let expire = async {
+            let mut interval = interval(EXPIRE_INTERVAL);
+            loop {
+                interval.tick().await;
+                self.expire();
+            }
+        };
+        match select(self.recv(&socket), expire).await {
+            Either::Left(res) => res,
+            Either::Right(_) => Ok(()),
+        }
+    }
+
+    async fn recv(&self, socket: &UdpSocket) -> io::Result<()> {
+        let mut buf = vec![0; u16::MAX as usize];
+        loop {
+            let (size, addr) = socket.recv_from(&mut buf).await?;
+
+            let mut src = BytesMut::from(&buf[..size]);
+            match self.0.codec.decode(&mut src) {
+                Ok(Some(pkt)) => {
+                    log::trace!("MQTT-SN packet from {}: {:?}", addr, pkt);
+                    self.handle(addr, pkt);
+                }
+                Ok(None) => log::trace!("Truncated MQTT-SN packet from {}", addr),
+                Err(err) => log::trace!("Malformed MQTT-SN packet from {}: {:?}", addr, err),
+            }
+        }
+    }
+
+    fn handle(&self, addr: SocketAddr, pkt: Packet) {
+        let inner = &self.0;
+
+        let pkt = match pkt {
+            Packet::SearchGw { .. } => {
+                return self
+                    .send(addr, Packet::GwInfo { gw_id: inner.gw_id, gw_addr: Bytes::new() })
+            }
+            Packet::Connect(pkt) => {
+                let return_code = if pkt.will {
+                    ReturnCode::NotSupported
+                } else if self.is_full(&addr) {
+                    log::trace!(
+                        "MQTT-SN client {:?} is rejected, too many clients",
+                        pkt.client_id
+                    );
+                    ReturnCode::Congestion
+                } else {
+                    log::trace!(
+                        "MQTT-SN client {:?} is connected from {}",
+                        pkt.client_id,
+                        addr
+                    );
+                    let prev = inner.clients.borrow_mut().insert(addr, Client::new(pkt));
+                    if let Some(client) = prev {
+                        self.release(client);
+                    }
+                    ReturnCode::Accepted
+                };
+                return self.send(addr, Packet::ConnectAck { return_code });
+            }
+            pkt => pkt,
+        };
+
+        let mut clients = inner.clients.borrow_mut();
+        let client = if let Some(client) = clients.get_mut(&addr) {
+            client.last_seen = Instant::now();
+            client
+        } else {
+            log::trace!("MQTT-SN packet from not connected client {}", addr);
+            return;
+        };
+
+        match pkt {
+            Packet::Register { msg_id, topic, .. } => {
+                let (topic_id, return_code) = match client.register(&topic) {
+                    Some((topic_id, _)) => (topic_id, ReturnCode::Accepted),
+                    None => (0, ReturnCode::Congestion),
+                };
+                drop(clients);
+                self.send(addr, Packet::RegisterAck { topic_id, msg_id, return_code });
+            }
+            Packet::Publish(pkt) => {
+                let topic = self.resolve(client, pkt.topic);
+                drop(clients);
+                self.forward(addr, pkt, topic);
+            }
+            Packet::Subscribe { qos, msg_id, topic, .. } => {
+                let filter = match topic {
+                    SubscribeTopic::Name(ref name) => TopicFilter::new(name.clone()).ok(),
+                    SubscribeTopic::Predefined(id) => {
+                        inner.predefined.get(&id).and_then(|t| TopicFilter::new(t.clone()).ok())
+                    }
+                    SubscribeTopic::Short(name) => {
+                        ByteString::try_from(Bytes::copy_from_slice(&name))
+                            .ok()
+                            .and_then(|t| TopicFilter::new(t).ok())
+                    }
+                };
+                let filter = if let Some(filter) = filter {
+                    filter
+                } else {
+                    drop(clients);
+                    return self.send(
+                        addr,
+                        Packet::SubscribeAck {
+                            qos,
+                            msg_id,
+                            topic_id: 0,
+                            return_code: ReturnCode::InvalidTopicId,
+                        },
+                    );
+                };
+
+                let topic_id = match topic {
+                    SubscribeTopic::Name(ref name) if !filter.has_wildcards() => {
+                        if let Some((id, _)) = client.register(name) {
+                            id
+                        } else {
+                            drop(clients);
+                            return self.send(
+                                addr,
+                                Packet::SubscribeAck {
+                                    qos,
+                                    msg_id,
+                                    topic_id: 0,
+                                    return_code: ReturnCode::Congestion,
+                                },
+                            );
+                        }
+                    }
+                    SubscribeTopic::Predefined(id) => id,
+                    _ => 0,
+                };
+                if !client.subscriptions.contains(&filter) {
+                    client.subscriptions.push(filter.clone());
+                }
+                drop(clients);
+
+                let gw = self.clone();
+                ntex::rt::spawn(async move {
+                    let res =
+                        gw.0.sink
+                            .subscribe()
+                            .topic_filter(filter.clone().into_inner(), qos)
+                            .send()
+                            .await;
+                    let return_code = match res {
+                        Ok(res) if res.is_success() => ReturnCode::Accepted,
+                        // topic filter is refused by upstream server
+                        Ok(_) => ReturnCode::NotSupported,
+                        Err(ref err) => return_code(err),
+                    };
+                    if return_code != ReturnCode::Accepted {
+                        if let Some(client) = gw.0.clients.borrow_mut().get_mut(&addr) {
+                            client.subscriptions.retain(|f| *f != filter);
+                        }
+                    }
+                    gw.send(
+                        addr,
+                        Packet::SubscribeAck {
+                            msg_id,
+                            topic_id,
+                            return_code,
+                            qos: QoS::AtMostOnce,
+                        },
+                    );
+                });
+            }
+            Packet::Unsubscribe { msg_id, topic } => {
+                let name = match topic {
+                    SubscribeTopic::Name(name) => Some(name),
+                    SubscribeTopic::Predefined(id) => inner.predefined.get(&id).cloned(),
+                    SubscribeTopic::Short(name) => {
+                        ByteString::try_from(Bytes::copy_from_slice(&name)).ok()
+                    }
+                };
+                let mut removed = Vec::new();
+                if let Some(name) = name {
+                    client.subscriptions.retain(|f| {
+                        if name == f.as_str() {
+                            removed.push(f.clone());
+                            false
+                        } else {
+                            true
+                        }
+                    });
+                }
+                drop(clients);
+                self.unsubscribe_unused(removed);
+                self.send(addr, Packet::UnsubscribeAck { msg_id });
+            }
+            Packet::PingRequest { .. } => {
+                drop(clients);
+                self.send(addr, Packet::PingResponse);
+            }
+            Packet::Disconnect { .. } => {
+                log::trace!("MQTT-SN client {:?} is disconnected", client.client_id);
+                let client = clients.remove(&addr).unwrap();
+                drop(clients);
+                self.release(client);
+                self.send(addr, Packet::Disconnect { duration: None });
+            }
+            Packet::PublishAck { .. } | Packet::RegisterAck { .. } => (),
+            pkt => {
+                drop(clients);
+                log::trace!("Unsupported MQTT-SN packet: {:?}", pkt);
+            }
+        }
+    }
+
+    /// Forward client publish to upstream connection
+    fn forward(&self, addr: SocketAddr, pkt: Publish, topic: Option<ByteString>) {
+        let (topic_id, msg_id) = (pkt.topic.value(), pkt.msg_id);
+        let ack = move |return_code| Packet::PublishAck { topic_id, msg_id, return_code };
+
+        let topic = if let Some(topic) = topic {
+            topic
+        } else {
+            return self.send(addr, ack(ReturnCode::InvalidTopicId));
+        };
+
+        let mut builder = self.0.sink.publish(topic, pkt.payload);
+        if pkt.retain {
+            builder = builder.retain();
+        }
+        match pkt.qos {
+            QoS::AtMostOnce => {
+                if let Err(err) = builder.send_at_most_once() {
+                    log::trace!("Cannot forward MQTT-SN publish: {:?}", err);
+                }
+            }
+            QoS::AtLeastOnce => {
+                let gw = self.clone();
+                ntex::rt::spawn(async move {
+                    let return_code = match builder.send_at_least_once().await {
+                        Ok(_) => ReturnCode::Accepted,
+                        Err(ref err) => return_code(err),
+                    };
+                    gw.send(addr, ack(return_code));
+                });
+            }
+            QoS::ExactlyOnce => self.send(addr, ack(ReturnCode::NotSupported)),
+        }
+    }
+
+    fn resolve(&self, client: &Client, topic: TopicId) -> Option<ByteString> {
+        match topic {
+            TopicId::Normal(id) => client.topics.get(&id).cloned(),
+            TopicId::Predefined(id) => self.0.predefined.get(&id).cloned(),
+            TopicId::Short(name) => ByteString::try_from(Bytes::copy_from_slice(&name)).ok(),
+        }
+    }
+
+    /// Check if connect from new client exceeds max number of clients
+    fn is_full(&self, addr: &SocketAddr) -> bool {
+        let clients = self.0.clients.borrow();
+        self.0.max_clients != 0
+            && clients.len() >= self.0.max_clients
+            && !clients.contains_key(addr)
+    }
+
+    /// Remove clients that are not active for 1.5 keep-alive durations
+    fn expire(&self) {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .0
+            .clients
+            .borrow()
+            .iter()
+            .filter(|(_, c)| c.is_expired(now))
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        for addr in expired {
+            let client = self.0.clients.borrow_mut().remove(&addr);
+            if let Some(client) = client {
+                log::trace!("MQTT-SN client {:?} is expired", client.client_id);
+                self.release(client);
+            }
+        }
+    }
+
+    /// Unsubscribe upstream subscriptions of removed client
+    fn release(&self, client: Client) {
+        self.unsubscribe_unused(client.subscriptions);
+    }
+
+    /// Unsubscribe upstream topic filters that are not used by any client
+    fn unsubscribe_unused(&self, filters: Vec<TopicFilter>) {
+        let clients = self.0.clients.borrow();
+        for filter in filters {
+            if clients.values().any(|c| c.subscriptions.contains(&filter)) {
+                continue;
+            }
+            let sink = self.0.sink.clone();
+            ntex::rt::spawn(async move {
+                if let Err(err) =
+                    sink.unsubscribe().topic_filter(filter.into_inner()).send().await
+                {
+                    log::trace!("Cannot unsubscribe upstream topic filter: {:?}", err);
+                }
+            });
+        }
+    }
+
+    fn send(&self, addr: SocketAddr, pkt: Packet) {
+        let mut buf = BytesMut::new();
+        if let Err(err) = self.0.codec.encode(pkt, &mut buf) {
+            log::error!("Cannot encode MQTT-SN packet: {:?}", err);
+            return;
+        }
+        if let Some(ref socket) = *self.0.socket.borrow() {
+            if let Err(err) = socket.try_send_to(&buf, addr) {
+                log::trace!("Cannot send MQTT-SN packet to {}: {:?}", addr, err);
+            }
+        }
+    }
+}
+
+/// Return code of failed upstream request, only connection failures
+/// could be retried by client
+fn return_code(err: &SendPacketError) -> ReturnCode {
+    match err {
+        SendPacketError::Encode(_) | SendPacketError::NotAllowed => ReturnCode::NotSupported,
+        _ => ReturnCode::Congestion,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let mut client = Client::new(Connect {
+            will: false,
+            clean_session: true,
+            duration: 0,
+            client_id: ByteString::from_static("sensor"),
+        });
+        let t1 = ByteString::from_static("t1");
+        assert_eq!(client.register(&t1), Some((1, true)));
+        assert_eq!(client.register(&t1), Some((1, false)));
+
+        // topic ids in use are skipped after wrap around
+        client.next_topic_id = u16::MAX - 1;
+        assert_eq!(client.register(&ByteString::from_static("t2")), Some((u16::MAX, true)));
+        assert_eq!(client.register(&ByteString::from_static("t3")), Some((2, true)));
+
+        // topic id space is exhausted
+        for id in 3..u16::MAX {
+            client.topics.insert(id, ByteString::from(format!("t{}", id + 1)));
+        }
+        assert_eq!(client.register(&ByteString::from_static("other")), None);
+        client.topics.remove(&100);
+        assert_eq!(client.register(&ByteString::from_static("other")), Some((100, true)));
+    }
+}
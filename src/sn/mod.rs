@@ -0,0 +1,38 @@
+//! MQTT-SN gateway
+//!
+//! MQTT-SN is a variant of MQTT for constrained devices on non-TCP/IP
+//! networks. `Gateway` translates between MQTT-SN clients connected over UDP
+//! and upstream mqtt connection.
+//!
+//! ```rust,no_run
+//! use ntex::rt::net::UdpSocket;
+//! use ntex_mqtt::{sn::Gateway, v3};
+//!
+//! #[ntex::main]
+//! async fn main() -> std::io::Result<()> {
+//!     let client = v3::client::MqttConnector::new("127.0.0.1:1883")
+//!         .client_id("mqtt-sn-gateway")
+//!         .connect()
+//!         .await
+//!         .unwrap();
+//!     let gateway = Gateway::new(client.sink()).predefined_topic(1, "sensors/temp");
+//!
+//!     // deliver upstream messages to MQTT-SN clients
+//!     let gw = gateway.clone();
+//!     ntex::rt::spawn(client.start(move |msg| match msg {
+//!         v3::client::ControlMessage::Publish(p) => {
+//!             gw.publish(&p.packet().topic, p.packet().payload.clone());
+//!             ntex::util::Ready::<_, ()>::Ok(p.ack())
+//!         }
+//!         msg => ntex::util::Ready::Ok(msg.disconnect()),
+//!     }));
+//!
+//!     gateway.run(UdpSocket::bind("0.0.0.0:1884").await?).await
+//! }
+//! ```
+pub mod codec;
+#[cfg(feature = "runtime")]
+mod gateway;
+
+#[cfg(feature = "runtime")]
+pub use self::gateway::Gateway;
@@ -0,0 +1,206 @@
+//! Routing of connections by TLS SNI hostname
+use std::{future::Future, pin::Pin, rc::Rc, task::Context, task::Poll};
+
+use ntex::service::{boxed, IntoServiceFactory, Service, ServiceFactory};
+
+use crate::error::MqttError;
+
+type ServerFactory<Io, Err, InitErr> =
+    boxed::BoxServiceFactory<(), Io, (), MqttError<Err>, InitErr>;
+
+type Server<Io, Err> = boxed::BoxService<Io, (), MqttError<Err>>;
+
+/// Mqtt server router
+///
+/// Router selects mqtt server by hostname that client requested with TLS SNI
+/// extension, so several domains with different handshake services and
+/// configurations could be served on one port. Hostname is extracted from
+/// connection io after TLS handshake, i.e. with `SslRef::servername()`.
+///
+/// Hostnames are case-insensitive, route `*.example.com` matches any
+/// subdomain of `example.com`. Exact routes take precedence over wildcard
+/// routes. Connections without hostname or with unknown hostname are handled
+/// by default server, if default server is not set connection is closed.
+///
+/// ```rust,no_run
+/// # use ntex::rt::net::TcpStream;
+/// # use ntex_mqtt::{v3, SniRouter};
+/// # use openssl::ssl::NameType;
+/// # use tokio_openssl::SslStream;
+/// # async fn handshake(
+/// #     hnd: v3::Handshake<SslStream<TcpStream>>,
+/// # ) -> Result<v3::HandshakeAck<SslStream<TcpStream>, ()>, ()> {
+/// #     Ok(hnd.ack((), false))
+/// # }
+/// # async fn publish(_: v3::Publish) -> Result<(), ()> {
+/// #     Ok(())
+/// # }
+/// let router = SniRouter::new(|io: &SslStream<TcpStream>| {
+///     io.ssl().servername(NameType::HOST_NAME).map(|name| name.to_string())
+/// })
+/// .route("tenant-a.example.com", v3::MqttServer::new(handshake).publish(publish).finish())
+/// .route("*.example.com", v3::MqttServer::new(handshake).publish(publish).finish());
+/// ```
+pub struct SniRouter<Io, Err, InitErr> {
+    hostname: Rc<dyn Fn(&Io) -> Option<String>>,
+    routes: Vec<(String, ServerFactory<Io, Err, InitErr>)>,
+    default: Option<ServerFactory<Io, Err, InitErr>>,
+}
+
+impl<Io, Err, InitErr> SniRouter<Io, Err, InitErr>
+where
+    Io: 'static,
+    Err: 'static,
+    InitErr: 'static,
+{
+    /// Create router with hostname extractor
+    pub fn new<F>(hostname: F) -> Self
+    where
+        F: Fn(&Io) -> Option<String> + 'static,
+    {
+        SniRouter { hostname: Rc::new(hostname), routes: Vec::new(), default: None }
+    }
+
+    /// Add server for hostname
+    pub fn route<F, S>(mut self, hostname: &str, server: F) -> Self
+    where
+        F: IntoServiceFactory<S>,
+        S: ServiceFactory<
+                Config = (),
+                Request = Io,
+                Response = (),
+                Error = MqttError<Err>,
+                InitError = InitErr,
+            > + 'static,
+    {
+        self.routes
+            .push((hostname.to_ascii_lowercase(), boxed::factory(server.into_factory())));
+        self
+    }
+
+    /// Set server for connections with unknown hostname
+    pub fn default_server<F, S>(mut self, server: F) -> Self
+    where
+        F: IntoServiceFactory<S>,
+        S: ServiceFactory<
+                Config = (),
+                Request = Io,
+                Response = (),
+                Error = MqttError<Err>,
+                InitError = InitErr,
+            > + 'static,
+    {
+        self.default = Some(boxed::factory(server.into_factory()));
+        self
+    }
+}
+
+impl<Io, Err, InitErr> ServiceFactory for SniRouter<Io, Err, InitErr>
+where
+    Io: 'static,
+    Err: 'static,
+    InitErr: 'static,
+{
+    type Config = ();
+    type Request = Io;
+    type Response = ();
+    type Error = MqttError<Err>;
+    type InitError = InitErr;
+    type Service = SniRouterService<Io, Err>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Service, Self::InitError>>>>;
+
+    fn new_service(&self, _: ()) -> Self::Future {
+        let futs: Vec<_> =
+            self.routes.iter().map(|(name, srv)| (name.clone(), srv.new_service(()))).collect();
+        let default = self.default.as_ref().map(|srv| srv.new_service(()));
+        let hostname = self.hostname.clone();
+
+        Box::pin(async move {
+            let mut routes = Vec::new();
+            for (name, fut) in futs {
+                routes.push((name, fut.await?));
+            }
+            let default = if let Some(fut) = default { Some(fut.await?) } else { None };
+            Ok(SniRouterService { hostname, routes, default })
+        })
+    }
+}
+
+/// Mqtt server router service
+pub struct SniRouterService<Io, Err> {
+    hostname: Rc<dyn Fn(&Io) -> Option<String>>,
+    routes: Vec<(String, Server<Io, Err>)>,
+    default: Option<Server<Io, Err>>,
+}
+
+impl<Io, Err> SniRouterService<Io, Err> {
+    fn lookup(&self, hostname: &str) -> Option<&Server<Io, Err>> {
+        let hostname = hostname.to_ascii_lowercase();
+        self.routes
+            .iter()
+            .find(|(name, _)| *name == hostname)
+            .or_else(|| {
+                self.routes.iter().find(|(name, _)| {
+                    std::matches!(name.strip_prefix('*'), Some(suffix)
+                        if suffix.starts_with('.') && hostname.ends_with(suffix))
+                })
+            })
+            .map(|(_, srv)| srv)
+    }
+}
+
+impl<Io, Err> Service for SniRouterService<Io, Err>
+where
+    Io: 'static,
+    Err: 'static,
+{
+    type Request = Io;
+    type Response = ();
+    type Error = MqttError<Err>;
+    type Future = Pin<Box<dyn Future<Output = Result<(), MqttError<Err>>>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let mut ready = true;
+        for (_, srv) in self.routes.iter() {
+            ready &= srv.poll_ready(cx)?.is_ready();
+        }
+        if let Some(ref srv) = self.default {
+            ready &= srv.poll_ready(cx)?.is_ready();
+        }
+        if ready {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        let mut ready = true;
+        for (_, srv) in self.routes.iter() {
+            ready &= srv.poll_shutdown(cx, is_error).is_ready();
+        }
+        if let Some(ref srv) = self.default {
+            ready &= srv.poll_shutdown(cx, is_error).is_ready();
+        }
+        if ready {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn call(&self, io: Io) -> Self::Future {
+        let hostname = (*self.hostname)(&io);
+        let srv =
+            hostname.as_ref().and_then(|name| self.lookup(name)).or(self.default.as_ref());
+
+        if let Some(srv) = srv {
+            srv.call(io)
+        } else {
+            log::trace!("Cannot find server for hostname {:?}", hostname);
+            Box::pin(async { Err(MqttError::ServerError("Cannot find server for hostname")) })
+        }
+    }
+}
@@ -0,0 +1,294 @@
+//! Broker statistics for `$SYS` topics
+//!
+//! `BrokerStats` collects counters from server hooks, `SysPublisher`
+//! periodically converts them to standard `$SYS/broker/...` messages.
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use ntex_mqtt::sys::{BrokerStats, SysPublisher};
+//! use ntex_mqtt::v3;
+//!
+//! let stats = BrokerStats::new();
+//! let st = stats.clone();
+//! let server = v3::MqttServer::new(move |h: v3::Handshake<ntex::rt::net::TcpStream>| {
+//!     // client is counted as connected while session state is alive
+//!     let client = st.client_connected();
+//!     async move { Ok::<_, ()>(h.ack(client, false)) }
+//! })
+//! .publish_hook({
+//!     let stats = stats.clone();
+//!     move |m| stats.message_received(m.payload_size)
+//! })
+//! .publish(|_| async { Ok::<_, ()>(()) });
+//!
+//! # let _ = server;
+//! # ntex::rt::System::new("test").block_on(async move {
+//! SysPublisher::new(stats).interval(Duration::from_secs(10)).start(|topic, payload| {
+//!     // deliver message to `$SYS` subscribers
+//!     true
+//! });
+//! # });
+//! ```
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::{fmt, sync::Arc, time::Duration, time::Instant};
+
+use ntex::rt::time::sleep;
+use ntex::util::{ByteString, Bytes};
+
+/// Shared broker statistics
+///
+/// Counters could be updated from any server worker.
+#[derive(Clone)]
+pub struct BrokerStats(Arc<StatsInner>);
+
+struct StatsInner {
+    started: Instant,
+    version: Option<ByteString>,
+    connected: AtomicUsize,
+    maximum: AtomicUsize,
+    total: AtomicU64,
+    msgs_received: AtomicU64,
+    msgs_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+impl Default for BrokerStats {
+    fn default() -> Self {
+        BrokerStats::new()
+    }
+}
+
+impl BrokerStats {
+    /// Create new statistics, uptime starts now
+    pub fn new() -> Self {
+        BrokerStats::build(None)
+    }
+
+    /// Create new statistics with broker version, version is reported
+    /// in `$SYS/broker/version` topic
+    pub fn with_version<T>(version: T) -> Self
+    where
+        ByteString: From<T>,
+    {
+        BrokerStats::build(Some(ByteString::from(version)))
+    }
+
+    fn build(version: Option<ByteString>) -> Self {
+        BrokerStats(Arc::new(StatsInner {
+            version,
+            started: Instant::now(),
+            connected: AtomicUsize::new(0),
+            maximum: AtomicUsize::new(0),
+            total: AtomicU64::new(0),
+            msgs_received: AtomicU64::new(0),
+            msgs_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+        }))
+    }
+
+    /// Register connected client.
+    ///
+    /// Client is counted as connected until returned guard is dropped,
+    /// guard could be stored in session state.
+    pub fn client_connected(&self) -> ClientGuard {
+        let connected = self.0.connected.fetch_add(1, Ordering::Relaxed) + 1;
+        self.0.maximum.fetch_max(connected, Ordering::Relaxed);
+        self.0.total.fetch_add(1, Ordering::Relaxed);
+        ClientGuard(self.clone())
+    }
+
+    /// Register received publish with payload size, could be called from publish hook
+    pub fn message_received(&self, size: usize) {
+        self.0.msgs_received.fetch_add(1, Ordering::Relaxed);
+        self.0.bytes_received.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    /// Register sent publish with payload size
+    pub fn message_sent(&self, size: usize) {
+        self.0.msgs_sent.fetch_add(1, Ordering::Relaxed);
+        self.0.bytes_sent.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    /// Time since statistics are created
+    pub fn uptime(&self) -> Duration {
+        self.0.started.elapsed()
+    }
+
+    /// Number of currently connected clients
+    pub fn clients_connected(&self) -> usize {
+        self.0.connected.load(Ordering::Relaxed)
+    }
+
+    /// Max number of simultaneously connected clients
+    pub fn clients_maximum(&self) -> usize {
+        self.0.maximum.load(Ordering::Relaxed)
+    }
+
+    /// Total number of client connections
+    pub fn clients_total(&self) -> u64 {
+        self.0.total.load(Ordering::Relaxed)
+    }
+
+    /// Number of received publishes
+    pub fn messages_received(&self) -> u64 {
+        self.0.msgs_received.load(Ordering::Relaxed)
+    }
+
+    /// Number of sent publishes
+    pub fn messages_sent(&self) -> u64 {
+        self.0.msgs_sent.load(Ordering::Relaxed)
+    }
+
+    /// Payload bytes of received publishes
+    pub fn bytes_received(&self) -> u64 {
+        self.0.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Payload bytes of sent publishes
+    pub fn bytes_sent(&self) -> u64 {
+        self.0.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Current statistics as `$SYS` topic and payload pairs
+    pub fn messages(&self) -> Vec<(ByteString, Bytes)> {
+        fn msg<T: fmt::Display>(topic: &'static str, val: T) -> (ByteString, Bytes) {
+            (ByteString::from_static(topic), Bytes::from(val.to_string()))
+        }
+
+        let mut msgs = Vec::with_capacity(10);
+        if let Some(ref version) = self.0.version {
+            msgs.push(msg("$SYS/broker/version", version));
+        }
+        msgs.push(msg("$SYS/broker/uptime", format!("{} seconds", self.uptime().as_secs())));
+        msgs.push(msg("$SYS/broker/clients/connected", self.clients_connected()));
+        msgs.push(msg("$SYS/broker/clients/maximum", self.clients_maximum()));
+        msgs.push(msg("$SYS/broker/clients/total", self.clients_total()));
+        msgs.push(msg("$SYS/broker/messages/received", self.messages_received()));
+        msgs.push(msg("$SYS/broker/messages/sent", self.messages_sent()));
+        msgs.push(msg("$SYS/broker/bytes/received", self.bytes_received()));
+        msgs.push(msg("$SYS/broker/bytes/sent", self.bytes_sent()));
+        msgs
+    }
+}
+
+impl fmt::Debug for BrokerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BrokerStats")
+            .field("uptime", &self.uptime())
+            .field("clients_connected", &self.clients_connected())
+            .field("messages_received", &self.messages_received())
+            .field("messages_sent", &self.messages_sent())
+            .finish()
+    }
+}
+
+/// Connected client registration, client is disconnected on drop
+#[derive(Debug)]
+pub struct ClientGuard(BrokerStats);
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        (self.0).0.connected.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Periodic publisher of `$SYS` topics
+#[derive(Debug)]
+pub struct SysPublisher {
+    stats: BrokerStats,
+    interval: Duration,
+}
+
+impl SysPublisher {
+    /// Create publisher for broker statistics
+    ///
+    /// By default statistics are published every 10 seconds.
+    pub fn new(stats: BrokerStats) -> Self {
+        SysPublisher { stats, interval: Duration::from_secs(10) }
+    }
+
+    /// Set publish interval
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Start publishing statistics in background task.
+    ///
+    /// Provided function is called for each `$SYS` message, publishing
+    /// stops if function returns `false`. Must be called within ntex runtime.
+    pub fn start<F>(self, f: F)
+    where
+        F: Fn(ByteString, Bytes) -> bool + 'static,
+    {
+        ntex::rt::spawn(async move {
+            loop {
+                sleep(self.interval).await;
+                for (topic, payload) in self.stats.messages() {
+                    if !f(topic, payload) {
+                        log::trace!("$SYS publisher is stopped");
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let stats = BrokerStats::with_version("ntex-mqtt");
+        let c1 = stats.client_connected();
+        let c2 = stats.client_connected();
+        drop(c1);
+        let _c3 = stats.client_connected();
+        drop(c2);
+        stats.message_received(10);
+        stats.message_received(5);
+        stats.message_sent(7);
+
+        assert_eq!(stats.clients_connected(), 1);
+        assert_eq!(stats.clients_maximum(), 2);
+        assert_eq!(stats.clients_total(), 3);
+        assert_eq!(stats.messages_received(), 2);
+        assert_eq!(stats.bytes_received(), 15);
+        assert_eq!(stats.messages_sent(), 1);
+        assert_eq!(stats.bytes_sent(), 7);
+
+        let msgs = stats.messages();
+        assert_eq!(msgs[0], ("$SYS/broker/version".into(), Bytes::from_static(b"ntex-mqtt")));
+        assert!(msgs.contains(&("$SYS/broker/clients/connected".into(), Bytes::from("1"))));
+        assert!(msgs.contains(&("$SYS/broker/bytes/received".into(), Bytes::from("15"))));
+        assert_eq!(BrokerStats::new().messages().len(), msgs.len() - 1);
+    }
+
+    #[ntex::test]
+    async fn test_publisher() {
+        let stats = BrokerStats::new();
+        let msgs = Rc::new(RefCell::new(Vec::new()));
+        let msgs2 = msgs.clone();
+        SysPublisher::new(stats.clone()).interval(Duration::from_millis(50)).start(
+            move |topic, payload| {
+                msgs2.borrow_mut().push((topic, payload));
+                msgs2.borrow().len() < 10
+            },
+        );
+        assert!(msgs.borrow().is_empty());
+
+        sleep(Duration::from_millis(75)).await;
+        assert_eq!(msgs.borrow().len(), stats.messages().len());
+        assert_eq!(msgs.borrow()[0].0, "$SYS/broker/uptime");
+
+        // publisher is stopped by callback
+        sleep(Duration::from_millis(150)).await;
+        assert_eq!(msgs.borrow().len(), 10);
+    }
+}
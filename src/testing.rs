@@ -0,0 +1,299 @@
+//! Utilities for testing mqtt applications without network
+//!
+//! [`duplex()`] creates pair of interconnected in-memory streams, one end could be used
+//! by mqtt client or server, the other end is driven by [`MockBroker`].
+//!
+//! [`MockBroker::replay()`] feeds packets recorded with [`crate::capture::Recorder`]
+//! to the peer, so sessions captured in production could be replayed against
+//! application handlers.
+use std::{collections::VecDeque, fmt, time::Duration};
+
+use ntex::codec::{Decoder, Encoder};
+use ntex::rt::time::{sleep, timeout};
+use ntex::util::{select, BytesMut, Either};
+
+pub use ntex::testing::Io;
+
+use crate::capture::{Direction, Record};
+use crate::error::{DecodeError, EncodeError};
+use crate::{v3, v5};
+
+/// Max time to wait for incoming packet
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+const CLOSED_CHECK_INTERVAL: Duration = Duration::from_millis(25);
+
+/// Create in-memory duplex transport.
+///
+/// Returns `(client, server)` pair, amount of buffered data is not limited.
+pub fn duplex() -> (Io, Io) {
+    let (client, server) = Io::create();
+    client.remote_buffer_cap(usize::MAX);
+    server.remote_buffer_cap(usize::MAX);
+    (client, server)
+}
+
+enum Step<P> {
+    Expect(Box<dyn FnOnce(&P) -> bool>),
+    Send(P),
+    Sleep(Duration),
+    Close,
+}
+
+/// Scriptable mqtt peer.
+///
+/// Script is a sequence of steps, steps are executed in order by
+/// [`MockBroker::run()`]. Any deviation from the script causes panic.
+///
+/// ```rust,no_run
+/// use ntex_mqtt::{testing, v3::codec};
+///
+/// # async fn test() {
+/// let (client, server) = testing::duplex();
+///
+/// ntex::rt::spawn(
+///     testing::MockBroker::v3(server)
+///         .expect(|pkt| matches!(pkt, codec::Packet::Connect(_)))
+///         .send(codec::Packet::ConnectAck {
+///             session_present: false,
+///             return_code: codec::ConnectAckReason::ConnectionAccepted,
+///         })
+///         .run(),
+/// );
+/// # }
+/// ```
+pub struct MockBroker<C: Decoder> {
+    io: Io,
+    codec: C,
+    buf: BytesMut,
+    steps: VecDeque<Step<C::Item>>,
+}
+
+impl MockBroker<v3::codec::Codec> {
+    /// Create mock broker for mqtt v3.1.1 protocol
+    pub fn v3(io: Io) -> Self {
+        MockBroker::new(io, v3::codec::Codec::new())
+    }
+}
+
+impl MockBroker<v5::codec::Codec> {
+    /// Create mock broker for mqtt v5 protocol
+    pub fn v5(io: Io) -> Self {
+        MockBroker::new(io, v5::codec::Codec::new())
+    }
+}
+
+impl<C, P> MockBroker<C>
+where
+    C: Decoder<Item = P, Error = DecodeError> + Encoder<Item = P, Error = EncodeError>,
+    P: fmt::Debug + 'static,
+{
+    /// Create mock broker with custom codec
+    pub fn new(io: Io, codec: C) -> Self {
+        MockBroker { io, codec, buf: BytesMut::new(), steps: VecDeque::new() }
+    }
+
+    /// Underlying transport
+    pub fn io(&self) -> &Io {
+        &self.io
+    }
+
+    /// Expect next packet, `f` must return `true` for expected packet
+    pub fn expect<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&P) -> bool + 'static,
+    {
+        self.steps.push_back(Step::Expect(Box::new(f)));
+        self
+    }
+
+    /// Expect next packet to be equal to `pkt`
+    pub fn expect_packet(self, pkt: P) -> Self
+    where
+        P: PartialEq,
+    {
+        self.expect(move |p| *p == pkt)
+    }
+
+    /// Send packet to the peer
+    pub fn send(mut self, pkt: P) -> Self {
+        self.steps.push_back(Step::Send(pkt));
+        self
+    }
+
+    /// Close transport
+    pub fn close(mut self) -> Self {
+        self.steps.push_back(Step::Close);
+        self
+    }
+
+    /// Wait before next step
+    pub fn sleep(mut self, dur: Duration) -> Self {
+        self.steps.push_back(Step::Sleep(dur));
+        self
+    }
+
+    /// Send recorded inbound packets to the peer.
+    ///
+    /// Inbound packets of the recording are sent in order without delays,
+    /// outbound packets are ignored. Records must use protocol of the codec.
+    /// Panics if recorded packet cannot be decoded.
+    pub fn replay<I>(self, records: I) -> Self
+    where
+        I: IntoIterator<Item = Record>,
+    {
+        self.replay_records(records, false)
+    }
+
+    /// Send recorded inbound packets to the peer with recorded intervals.
+    ///
+    /// Same as [`MockBroker::replay()`], but time between inbound packets is
+    /// preserved, so keep-alive and idle timeouts behave as in recorded session.
+    pub fn replay_timed<I>(self, records: I) -> Self
+    where
+        I: IntoIterator<Item = Record>,
+    {
+        self.replay_records(records, true)
+    }
+
+    fn replay_records<I>(mut self, records: I, timed: bool) -> Self
+    where
+        I: IntoIterator<Item = Record>,
+    {
+        let mut last_ts = None;
+        for rec in records.into_iter().filter(|rec| rec.direction == Direction::Inbound) {
+            if let (true, Some(last_ts)) = (timed, last_ts) {
+                let delay = Duration::from_micros(rec.ts.saturating_sub(last_ts));
+                self.steps.push_back(Step::Sleep(delay));
+            }
+            last_ts = Some(rec.ts);
+
+            let mut buf = BytesMut::from(&rec.data[..]);
+            let pkt = self
+                .codec
+                .decode(&mut buf)
+                .expect("Cannot decode recorded packet")
+                .expect("Recorded packet is incomplete");
+            self.steps.push_back(Step::Send(pkt));
+        }
+        self
+    }
+
+    /// Execute script.
+    ///
+    /// Returns broker, so it is possible to continue interaction with the peer.
+    pub async fn run(mut self) -> Self {
+        while let Some(step) = self.steps.pop_front() {
+            match step {
+                Step::Expect(f) => match self.recv().await {
+                    Some(pkt) => {
+                        if !f(&pkt) {
+                            panic!("Unexpected packet: {:?}", pkt)
+                        }
+                    }
+                    None => panic!("Connection is closed, packet is expected"),
+                },
+                Step::Send(pkt) => self.write(pkt),
+                Step::Sleep(dur) => sleep(dur).await,
+                Step::Close => self.io.close().await,
+            }
+        }
+        self
+    }
+
+    /// Receive next packet.
+    ///
+    /// Returns `None` if peer disconnected, in that case broker closes its side
+    /// of connection as well. Panics on decode error or if packet is not
+    /// received in 5 seconds.
+    pub async fn recv(&mut self) -> Option<P> {
+        loop {
+            if let Some(pkt) = self.codec.decode(&mut self.buf).expect("Cannot decode packet") {
+                return Some(pkt);
+            }
+            let data = timeout(RECV_TIMEOUT, self.read())
+                .await
+                .expect("Timeout while waiting for packet");
+            if data.is_empty() {
+                self.io.close().await;
+                return None;
+            }
+            self.buf.extend_from_slice(&data);
+        }
+    }
+
+    async fn read(&self) -> BytesMut {
+        loop {
+            // shutdown of peer's write side does not wake reader,
+            // so closed flag has to be checked periodically
+            match select(self.io.read(), sleep(CLOSED_CHECK_INTERVAL)).await {
+                Either::Left(res) => return res.expect("Transport error"),
+                Either::Right(_) => {
+                    if self.io.is_closed() {
+                        return self.io.read_any();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send packet to the peer immediately
+    pub fn write(&self, pkt: P) {
+        let mut buf = BytesMut::new();
+        self.codec.encode(pkt, &mut buf).expect("Cannot encode packet");
+        self.io.write(buf);
+    }
+
+    /// Wait until peer disconnects, panics if peer sends any packet
+    pub async fn expect_closed(&mut self) {
+        if let Some(pkt) = self.recv().await {
+            panic!("Unexpected packet: {:?}, disconnect is expected", pkt)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v3::codec::{self, Connect, ConnectAckReason, Packet};
+
+    #[ntex::test]
+    async fn test_mock_broker() {
+        let (client, server) = duplex();
+
+        let broker = ntex::rt::spawn(
+            MockBroker::v3(server)
+                .expect(|pkt| std::matches!(pkt, Packet::Connect(_)))
+                .send(Packet::ConnectAck {
+                    session_present: false,
+                    return_code: ConnectAckReason::ConnectionAccepted,
+                })
+                .expect_packet(Packet::PingRequest)
+                .send(Packet::PingResponse)
+                .run(),
+        );
+
+        let codec = codec::Codec::new();
+        let mut buf = BytesMut::new();
+        let connect = Connect::default().client_id("test");
+        codec.encode(Packet::Connect(connect), &mut buf).unwrap();
+        codec.encode(Packet::PingRequest, &mut buf).unwrap();
+        client.write(buf);
+
+        let mut buf = BytesMut::new();
+        while buf.len() < 6 {
+            buf.extend_from_slice(&client.read().await.unwrap());
+        }
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Packet::ConnectAck {
+                session_present: false,
+                return_code: ConnectAckReason::ConnectionAccepted
+            })
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Packet::PingResponse));
+
+        let mut broker = broker.await.unwrap();
+        drop(client);
+        broker.expect_closed().await;
+    }
+}
@@ -0,0 +1,171 @@
+//! Publish handler timeout
+use std::task::{Context, Poll};
+use std::{cell::Cell, future::Future, pin::Pin, rc::Rc, time::Duration};
+
+use ntex::rt::time::{sleep, Sleep};
+use ntex::service::Service;
+
+use crate::ack::{self, WithAutoAck};
+
+/// Publish handler timeout, could be overridden by router
+pub(crate) type TimeoutSlot = Rc<Cell<Option<Duration>>>;
+
+pub(crate) trait WithTimeout {
+    fn set_timeout_slot(&mut self, slot: TimeoutSlot);
+}
+
+impl WithTimeout for crate::v3::Publish {
+    fn set_timeout_slot(&mut self, slot: TimeoutSlot) {
+        self.timeout = Some(slot);
+    }
+}
+
+impl WithTimeout for crate::v5::Publish {
+    fn set_timeout_slot(&mut self, slot: TimeoutSlot) {
+        self.timeout = Some(slot);
+    }
+}
+
+/// Publish service wrapper
+///
+/// Timer starts when publish service is called, if service does not complete
+/// in time its future is dropped and wrapper resolves to `None`. Auto-acked
+/// publish is marked as dispatched before service is called.
+pub(crate) struct HandlerTimeout<S> {
+    service: S,
+    timeout: Option<Duration>,
+    slot: TimeoutSlot,
+}
+
+impl<S> HandlerTimeout<S> {
+    /// Create service wrapper, zero timeout disables timeout
+    pub(crate) fn new(service: S, timeout: Duration) -> Self {
+        HandlerTimeout {
+            service,
+            timeout: if timeout.is_zero() { None } else { Some(timeout) },
+            slot: Rc::new(Cell::new(None)),
+        }
+    }
+}
+
+impl<S> Service for HandlerTimeout<S>
+where
+    S: Service,
+    S::Request: WithTimeout + WithAutoAck,
+{
+    type Request = S::Request;
+    type Response = Option<S::Response>;
+    type Error = S::Error;
+    type Future = HandlerTimeoutResponse<S::Future>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, mut req: S::Request) -> Self::Future {
+        // slot is shared by all publishes of the connection, router
+        // could override timeout only during `call()`
+        self.slot.set(self.timeout);
+        req.set_timeout_slot(self.slot.clone());
+        let ack = req.ack_slot();
+        let fut = self.service.call(req);
+        // handler future exists, publish could be acknowledged
+        ack::dispatched(&ack);
+        let timeout = self.slot.take().filter(|t| !t.is_zero());
+
+        HandlerTimeoutResponse { fut, delay: timeout.map(sleep) }
+    }
+}
+
+pin_project_lite::pin_project! {
+    pub(crate) struct HandlerTimeoutResponse<F> {
+        #[pin]
+        fut: F,
+        #[pin]
+        delay: Option<Sleep>,
+    }
+}
+
+impl<F, R, E> Future for HandlerTimeoutResponse<F>
+where
+    F: Future<Output = Result<R, E>>,
+{
+    type Output = Result<Option<R>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        if let Poll::Ready(res) = this.fut.poll(cx) {
+            return Poll::Ready(res.map(Some));
+        }
+        if let Some(delay) = this.delay.as_pin_mut() {
+            if delay.poll(cx).is_ready() {
+                log::trace!("Publish handler timeout");
+                return Poll::Ready(Ok(None));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::service::fn_service;
+    use ntex::util::{ByteString, Bytes};
+
+    use super::*;
+    use crate::v3::{codec, Publish};
+
+    fn publish(topic: &'static str) -> Publish {
+        Publish::new(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static(topic),
+            packet_id: None,
+            payload: Bytes::new(),
+        })
+    }
+
+    #[ntex::test]
+    async fn test_timeout() {
+        let srv = HandlerTimeout::new(
+            fn_service(|mut p: Publish| async move {
+                if p.publish_topic() == "slow" {
+                    p.set_timeout(Duration::from_millis(200));
+                }
+                sleep(Duration::from_millis(100)).await;
+                Ok::<_, ()>(())
+            }),
+            Duration::from_millis(50),
+        );
+        assert_eq!(srv.call(publish("test")).await, Ok(None));
+        assert_eq!(srv.call(publish("slow")).await, Ok(None));
+
+        // timeout is overridden during call
+        let srv = HandlerTimeout::new(
+            fn_service(|mut p: Publish| {
+                if p.publish_topic() == "slow" {
+                    p.set_timeout(Duration::from_millis(200));
+                }
+                async move {
+                    sleep(Duration::from_millis(100)).await;
+                    Ok::<_, ()>(())
+                }
+            }),
+            Duration::from_millis(50),
+        );
+        assert_eq!(srv.call(publish("test")).await, Ok(None));
+        assert_eq!(srv.call(publish("slow")).await, Ok(Some(())));
+
+        let srv =
+            HandlerTimeout::new(fn_service(|_| async { Ok::<_, ()>(()) }), Duration::ZERO);
+        assert_eq!(srv.call(publish("test")).await, Ok(Some(())));
+    }
+}
@@ -1,5 +1,9 @@
 use std::fmt::{self, Write};
-use std::{io, ops, str::FromStr};
+use std::{convert::TryFrom, io, ops, str::FromStr};
+
+#[cfg(feature = "runtime")]
+use ntex::router::IntoPattern;
+use ntex_bytes::ByteString;
 
 fn is_metadata<T: AsRef<str>>(s: T) -> bool {
     s.as_ref().starts_with('$')
@@ -324,10 +328,273 @@ pub(crate) trait WriteTopicExt: io::Write {
 
 impl<W: io::Write + ?Sized> WriteTopicExt for W {}
 
+/// Max length of topic name or topic filter in bytes
+const MAX_TOPIC_LEN: usize = 65_535;
+
+fn check_topic(s: &str) -> Result<(), TopicError> {
+    if s.is_empty() || s.len() > MAX_TOPIC_LEN || s.contains('\0') {
+        Err(TopicError::InvalidTopic)
+    } else {
+        Ok(())
+    }
+}
+
+/// Validated topic name
+///
+/// Topic name is not empty, does not contain wildcards or null characters
+/// and fits into 65535 bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TopicName(ByteString);
+
+impl TopicName {
+    /// Validate and create topic name
+    pub fn new<T>(name: T) -> Result<Self, TopicError>
+    where
+        ByteString: From<T>,
+    {
+        let name = ByteString::from(name);
+        check_topic(&name)?;
+        if name.contains(['+', '#']) {
+            Err(TopicError::InvalidLevel)
+        } else {
+            Ok(TopicName(name))
+        }
+    }
+
+    /// Create topic name from static string
+    ///
+    /// Panics if topic name is not valid.
+    pub fn from_static(name: &'static str) -> Self {
+        TopicName::new(ByteString::from_static(name))
+            .unwrap_or_else(|_| panic!("invalid topic name `{}`", name))
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    #[inline]
+    /// Topic name starts with `$`, such topics are not matched by wildcards
+    /// at first level
+    pub fn is_system(&self) -> bool {
+        is_metadata(&self.0)
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> ByteString {
+        self.0
+    }
+}
+
+/// Validated topic filter
+///
+/// Topic filter is not empty, does not contain null characters and fits
+/// into 65535 bytes. `+` wildcard occupies entire level, `#` wildcard
+/// occupies entire last level.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TopicFilter(ByteString);
+
+impl TopicFilter {
+    /// Validate and create topic filter
+    pub fn new<T>(filter: T) -> Result<Self, TopicError>
+    where
+        ByteString: From<T>,
+    {
+        let filter = ByteString::from(filter);
+        check_topic(&filter)?;
+
+        let mut levels = filter.split('/').peekable();
+        while let Some(level) = levels.next() {
+            let valid = match level {
+                "+" => true,
+                "#" => levels.peek().is_none(),
+                _ => !level.contains(['+', '#']),
+            };
+            if !valid {
+                return Err(TopicError::InvalidLevel);
+            }
+        }
+        Ok(TopicFilter(filter))
+    }
+
+    /// Create topic filter from static string
+    ///
+    /// Panics if topic filter is not valid.
+    pub fn from_static(filter: &'static str) -> Self {
+        TopicFilter::new(ByteString::from_static(filter))
+            .unwrap_or_else(|_| panic!("invalid topic filter `{}`", filter))
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    #[inline]
+    /// Topic filter contains `+` or `#` wildcards
+    pub fn has_wildcards(&self) -> bool {
+        self.0.contains(['+', '#'])
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> ByteString {
+        self.0
+    }
+
+    /// Check if topic name matches filter
+    ///
+    /// Wildcards at first level do not match topic names starting with `$`.
+    pub fn matches(&self, topic: &TopicName) -> bool {
+        self.matches_str(topic.as_str())
+    }
+
+    /// Check if topic matches filter
+    pub fn matches_str(&self, topic: &str) -> bool {
+        if is_metadata(topic) && self.0.starts_with(['+', '#']) {
+            return false;
+        }
+
+        let mut filter = self.0.split('/');
+        let mut topic = topic.split('/');
+        loop {
+            match (filter.next(), topic.next()) {
+                // `#` also matches parent level
+                (Some("#"), _) => return true,
+                (Some("+"), Some(_)) => continue,
+                (Some(f), Some(t)) if f == t => continue,
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+}
+
+macro_rules! topic_impls {
+    ($name:ident) => {
+        impl ops::Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl From<$name> for ByteString {
+            fn from(v: $name) -> Self {
+                v.0
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = TopicError;
+
+            fn from_str(s: &str) -> Result<Self, TopicError> {
+                $name::new(s)
+            }
+        }
+
+        impl TryFrom<ByteString> for $name {
+            type Error = TopicError;
+
+            fn try_from(s: ByteString) -> Result<Self, TopicError> {
+                $name::new(s)
+            }
+        }
+
+        impl TryFrom<&str> for $name {
+            type Error = TopicError;
+
+            fn try_from(s: &str) -> Result<Self, TopicError> {
+                $name::new(s)
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = TopicError;
+
+            fn try_from(s: String) -> Result<Self, TopicError> {
+                $name::new(s)
+            }
+        }
+    };
+}
+
+topic_impls!(TopicName);
+topic_impls!(TopicFilter);
+
+/// Router resource for exact topic name
+#[cfg(feature = "runtime")]
+impl IntoPattern for TopicName {
+    fn patterns(&self) -> Vec<String> {
+        vec![self.0.to_string()]
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_topic_name() {
+        assert!(TopicName::new("a/b").is_ok());
+        assert!(TopicName::new("/").is_ok());
+        assert!(TopicName::new("$SYS/a").unwrap().is_system());
+        assert_eq!(TopicName::new(""), Err(TopicError::InvalidTopic));
+        assert_eq!(TopicName::new("a\0b"), Err(TopicError::InvalidTopic));
+        assert_eq!(TopicName::new("a/+"), Err(TopicError::InvalidLevel));
+        assert_eq!(TopicName::new("a/#"), Err(TopicError::InvalidLevel));
+        assert_eq!(TopicName::new("a".repeat(65_536)), Err(TopicError::InvalidTopic));
+        assert_eq!(TopicName::from_static("a/b").as_str(), "a/b");
+        assert_eq!(ByteString::from(TopicName::from_static("a")), "a");
+        #[cfg(feature = "runtime")]
+        assert_eq!(TopicName::from_static("a/b").patterns(), vec!["a/b".to_string()]);
+    }
+
+    #[test]
+    fn test_topic_filter() {
+        for f in &["#", "+", "a/#", "a/+/b", "+/+", "/+", "$SYS/#", "a//b"] {
+            assert!(TopicFilter::new(*f).is_ok(), "{}", f);
+        }
+        for f in &["a/#/b", "a#", "a/b+", "#/a", "a/+b/c"] {
+            assert_eq!(TopicFilter::new(*f), Err(TopicError::InvalidLevel), "{}", f);
+        }
+        assert_eq!(TopicFilter::new(""), Err(TopicError::InvalidTopic));
+        assert!(TopicFilter::from_static("a/+").has_wildcards());
+        assert!(!TopicFilter::from_static("a/b").has_wildcards());
+    }
+
+    #[test]
+    fn test_topic_filter_matches() {
+        let m = |f: &'static str, t: &'static str| {
+            TopicFilter::from_static(f).matches(&TopicName::from_static(t))
+        };
+        assert!(m("a/b", "a/b"));
+        assert!(!m("a/b", "a/c"));
+        assert!(m("a/+", "a/b"));
+        assert!(!m("a/+", "a/b/c"));
+        assert!(m("a/+", "a/"));
+        assert!(m("a/#", "a"));
+        assert!(m("a/#", "a/b/c"));
+        assert!(m("#", "a/b"));
+        assert!(m("+/+", "/a"));
+        assert!(!m("#", "$SYS/a"));
+        assert!(!m("+/a", "$SYS/a"));
+        assert!(m("$SYS/#", "$SYS/a"));
+    }
+
     #[test]
     fn test_level() {
         assert!(Level::normal("sport").is_normal());
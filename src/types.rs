@@ -1,3 +1,7 @@
+use std::time::Duration;
+
+use ntex_bytes::ByteString;
+
 pub const MQTT: &[u8] = b"MQTT";
 pub const MQTT_LEVEL_3: u8 = 4;
 pub const MQTT_LEVEL_5: u8 = 5;
@@ -30,6 +34,37 @@ prim_enum! {
     }
 }
 
+/// Handling of retain flag of inbound publishes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RetainPolicy {
+    /// Pass retain flag to publish service
+    Accept,
+    /// Clear retain flag before publish is passed to publish service
+    Strip,
+    /// Retained publishes are protocol violation, connection is closed.
+    /// Mqtt v5 connection is closed with `RetainNotSupported` reason
+    Reject,
+}
+
+/// Type of packet waiting for acknowledgement from the peer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InflightType {
+    Publish,
+    Subscribe,
+    Unsubscribe,
+}
+
+/// Packet waiting for acknowledgement from the peer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InflightPacket {
+    pub packet_id: u16,
+    pub packet_type: InflightType,
+    /// Topic of publish packet
+    pub topic: Option<ByteString>,
+    /// Time since packet is sent
+    pub age: Duration,
+}
+
 bitflags::bitflags! {
     pub struct ConnectFlags: u8 {
         const USERNAME    = 0b1000_0000;
@@ -1,11 +1,13 @@
 use std::num::{NonZeroU16, NonZeroU32};
 use std::task::{Context, Poll};
-use std::{convert::TryFrom, future::Future, io::Cursor, pin::Pin};
+use std::{cmp, convert::TryFrom, future::Future, io::Cursor, pin::Pin, time::Duration};
 
-use ntex::service::Service;
-use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut, Either};
+#[cfg(feature = "runtime")]
+use ntex::{service::Service, util::Either};
+use ntex_bytes::{Buf, BufMut, ByteString, Bytes, BytesMut};
 
 use crate::error::{DecodeError, EncodeError};
+use crate::types::FixedHeader;
 
 macro_rules! ensure {
     ($cond:expr, $e:expr) => {
@@ -32,6 +34,7 @@ macro_rules! prim_enum {
         $( #[$enum_attr] )*
         #[repr(u8)]
         #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+        #[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
         pub enum $name {
             $(
                 $( #[$enum_item_attr] )*
@@ -52,6 +55,21 @@ macro_rules! prim_enum {
                 unsafe { ::std::mem::transmute(v) }
             }
         }
+        #[cfg(feature = "arbitrary")]
+        impl<'a> ::arbitrary::Arbitrary<'a> for $name {
+            fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+                u.choose(&[$($name::$var),+]).map(|v| *v)
+            }
+        }
+        #[cfg(any(test, feature = "proptest"))]
+        impl ::proptest::arbitrary::Arbitrary for $name {
+            type Parameters = ();
+            type Strategy = ::proptest::sample::Select<Self>;
+
+            fn arbitrary_with(_: ()) -> Self::Strategy {
+                ::proptest::sample::select(&[$($name::$var),+][..])
+            }
+        }
     };
 }
 
@@ -146,6 +164,49 @@ pub(crate) fn decode_variable_length(src: &[u8]) -> Result<Option<(u32, usize)>,
     }
 }
 
+/// Split fixed header from raw packet, returns packet's first byte.
+///
+/// Remaining length must match size of the rest of the buffer.
+pub(crate) fn split_fixed_header(src: &mut Bytes) -> Result<u8, DecodeError> {
+    ensure!(src.len() >= 2, DecodeError::InvalidLength);
+    let first_byte = src[0];
+    let (remaining_length, consumed) =
+        decode_variable_length(&src[1..])?.ok_or(DecodeError::InvalidLength)?;
+    ensure!(src.len() == consumed + 1 + remaining_length as usize, DecodeError::InvalidLength);
+    src.advance(consumed + 1);
+    Ok(first_byte)
+}
+
+/// Check topic length (in bytes) and number of topic levels, `0` means unlimited
+pub(crate) fn check_topic_limits(topic: &str, max_length: u16, max_levels: u16) -> bool {
+    (max_length == 0 || topic.len() <= max_length as usize)
+        && (max_levels == 0 || topic.split('/').count() <= max_levels as usize)
+}
+
+/// Convert duration to milliseconds, saturating at `u16::MAX` with warning
+///
+/// Fraction of millisecond is rounded up, non-zero duration never converts
+/// to `0` which disables timeout.
+#[cfg(feature = "runtime")]
+pub(crate) fn duration_to_millis(timeout: Duration) -> u16 {
+    let rem = timeout.subsec_nanos() > timeout.subsec_millis() * 1_000_000;
+    let millis = timeout.as_millis() + u128::from(rem);
+    if millis > u16::MAX as u128 {
+        log::warn!("Timeout {:?} is limited to {} milliseconds", timeout, u16::MAX);
+    }
+    cmp::min(millis, u16::MAX as u128) as u16
+}
+
+/// Convert duration to whole seconds, saturating at `u16::MAX`
+///
+/// Fraction of second is rounded up, non-zero duration never converts
+/// to `0` which disables keep-alive.
+#[cfg(any(feature = "runtime", feature = "wasm"))]
+pub(crate) fn duration_to_secs(timeout: Duration) -> u16 {
+    let secs = timeout.as_secs().saturating_add(u64::from(timeout.subsec_nanos() != 0));
+    cmp::min(secs, u16::MAX as u64) as u16
+}
+
 #[allow(clippy::cast_lossless)] // safe: allow cast through `as` because it is type-safe
 pub(crate) fn decode_variable_length_cursor<B: Buf>(src: &mut B) -> Result<u32, DecodeError> {
     let mut shift: u32 = 0;
@@ -280,6 +341,16 @@ impl<'a> Encode for &'a [u8] {
     }
 }
 
+/// Number of bytes required to encode length as variable length integer
+pub(crate) fn variable_length_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    }
+}
+
 pub(crate) fn write_variable_length(len: u32, dst: &mut BytesMut) {
     match len {
         0..=127 => dst.put_u8(len as u8),
@@ -305,15 +376,175 @@ pub(crate) fn write_variable_length(len: u32, dst: &mut BytesMut) {
     }
 }
 
+/// Capture bytes of malformed inbound packet, at most `size` bytes of packet body
+/// are captured. Captured packet is logged with `warn` level.
+pub(crate) fn capture_malformed(
+    err: &DecodeError,
+    fixed: Option<FixedHeader>,
+    buf: &[u8],
+    size: usize,
+) -> Bytes {
+    let mut dump = BytesMut::with_capacity(size + 5);
+    if let Some(fixed) = fixed {
+        dump.put_u8(fixed.first_byte);
+        write_variable_length(fixed.remaining_length, &mut dump);
+    }
+    dump.extend_from_slice(&buf[..std::cmp::min(buf.len(), size)]);
+    log::warn!("Malformed packet: {}, captured {} bytes: {}", err, dump.len(), hexdump(&dump));
+    dump.freeze()
+}
+
+pub(crate) fn hexdump(buf: &[u8]) -> String {
+    let mut s = String::with_capacity(buf.len() * 3);
+    for (idx, b) in buf.iter().enumerate() {
+        if idx != 0 {
+            s.push(if idx % 16 == 0 { '\n' } else { ' ' });
+        }
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+#[cfg(feature = "arbitrary")]
+/// Generators for packet fields that can not implement `Arbitrary` directly
+pub(crate) mod arbitrary {
+    use arbitrary::{Result, Unstructured};
+    use ntex_bytes::{ByteString, Bytes};
+    use std::num::{NonZeroU16, NonZeroU32};
+
+    /// Max value of variable byte integer
+    const MAX_VAR_INT: u32 = 268_435_455;
+
+    /// Utf8 string, fits into u16 length prefix
+    pub(crate) fn string(u: &mut Unstructured<'_>) -> Result<ByteString> {
+        let s: &str = u.arbitrary()?;
+        let mut len = s.len().min(u16::MAX as usize);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        Ok(ByteString::from(&s[..len]))
+    }
+
+    /// Binary data, fits into u16 length prefix
+    pub(crate) fn binary(u: &mut Unstructured<'_>) -> Result<Bytes> {
+        let b: &[u8] = u.arbitrary()?;
+        Ok(Bytes::copy_from_slice(&b[..b.len().min(u16::MAX as usize)]))
+    }
+
+    /// Unbounded binary data, publish payload
+    pub(crate) fn payload(u: &mut Unstructured<'_>) -> Result<Bytes> {
+        let b: &[u8] = u.arbitrary()?;
+        Ok(Bytes::copy_from_slice(b))
+    }
+
+    pub(crate) fn packet_id(u: &mut Unstructured<'_>) -> Result<NonZeroU16> {
+        Ok(NonZeroU16::new(u.int_in_range(1..=u16::MAX)?).unwrap())
+    }
+
+    pub(crate) fn non_zero_u32(u: &mut Unstructured<'_>) -> Result<NonZeroU32> {
+        Ok(NonZeroU32::new(u.int_in_range(1..=u32::MAX)?).unwrap())
+    }
+
+    /// Non zero value that fits into variable byte integer
+    pub(crate) fn var_int(u: &mut Unstructured<'_>) -> Result<NonZeroU32> {
+        Ok(NonZeroU32::new(u.int_in_range(1..=MAX_VAR_INT)?).unwrap())
+    }
+
+    pub(crate) fn option<'a, T, F>(u: &mut Unstructured<'a>, f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&mut Unstructured<'a>) -> Result<T>,
+    {
+        if u.arbitrary()? {
+            Ok(Some(f(u)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Vector of up to `max` items
+    pub(crate) fn vec<'a, T, F>(
+        u: &mut Unstructured<'a>,
+        max: usize,
+        mut f: F,
+    ) -> Result<Vec<T>>
+    where
+        F: FnMut(&mut Unstructured<'a>) -> Result<T>,
+    {
+        let len = u.int_in_range(0..=max)?;
+        let mut items = Vec::with_capacity(len);
+        for _ in 0..len {
+            items.push(f(u)?);
+        }
+        Ok(items)
+    }
+
+    pub(crate) fn user_properties(
+        u: &mut Unstructured<'_>,
+    ) -> Result<Vec<(ByteString, ByteString)>> {
+        vec(u, 4, |u| Ok((string(u)?, string(u)?)))
+    }
+}
+
+#[cfg(any(test, feature = "proptest"))]
+/// Strategies for packet fields that can not implement `Arbitrary` directly
+pub(crate) mod strategy {
+    use ntex_bytes::{ByteString, Bytes};
+    use proptest::{collection, option, prelude::*};
+    use std::num::{NonZeroU16, NonZeroU32};
+
+    /// Max value of variable byte integer
+    const MAX_VAR_INT: u32 = 268_435_455;
+
+    /// Utf8 string, fits into u16 length prefix
+    pub(crate) fn string() -> impl Strategy<Value = ByteString> {
+        "\\PC{0,24}".prop_map(ByteString::from)
+    }
+
+    /// Binary data, fits into u16 length prefix
+    pub(crate) fn binary() -> impl Strategy<Value = Bytes> {
+        collection::vec(any::<u8>(), 0..32).prop_map(Bytes::from)
+    }
+
+    /// Publish payload
+    pub(crate) fn payload() -> impl Strategy<Value = Bytes> {
+        collection::vec(any::<u8>(), 0..512).prop_map(Bytes::from)
+    }
+
+    pub(crate) fn packet_id() -> impl Strategy<Value = NonZeroU16> {
+        (1..=u16::MAX).prop_map(|v| NonZeroU16::new(v).unwrap())
+    }
+
+    pub(crate) fn non_zero_u32() -> impl Strategy<Value = NonZeroU32> {
+        (1..=u32::MAX).prop_map(|v| NonZeroU32::new(v).unwrap())
+    }
+
+    /// Non zero value that fits into variable byte integer
+    pub(crate) fn var_int() -> impl Strategy<Value = NonZeroU32> {
+        (1..=MAX_VAR_INT).prop_map(|v| NonZeroU32::new(v).unwrap())
+    }
+
+    pub(crate) fn opt<S: Strategy>(s: S) -> impl Strategy<Value = Option<S::Value>> {
+        option::of(s)
+    }
+
+    pub(crate) fn user_properties() -> impl Strategy<Value = Vec<(ByteString, ByteString)>> {
+        collection::vec((string(), string()), 0..4)
+    }
+}
+
 /// Check service readiness
+#[cfg(feature = "runtime")]
 pub(crate) fn ready<S>(service: &S) -> Ready<'_, S> {
     Ready(service)
 }
 
+#[cfg(feature = "runtime")]
 pub(crate) struct Ready<'a, S>(&'a S);
 
+#[cfg(feature = "runtime")]
 impl<'a, S> Unpin for Ready<'a, S> {}
 
+#[cfg(feature = "runtime")]
 impl<'a, S: Service> Future for Ready<'a, S> {
     type Output = Result<(), S::Error>;
 
@@ -322,6 +553,7 @@ impl<'a, S: Service> Future for Ready<'a, S> {
     }
 }
 
+#[cfg(feature = "runtime")]
 pub(crate) async fn select<F1, F2>(fut1: F1, fut2: F2) -> Either<F1::Output, F2::Output>
 where
     F1: Future,
@@ -330,6 +562,7 @@ where
     Select { fut1, fut2 }.await
 }
 
+#[cfg(feature = "runtime")]
 pin_project_lite::pin_project! {
     struct Select<F1, F2>{
         #[pin]
@@ -339,6 +572,7 @@ pin_project_lite::pin_project! {
     }
 }
 
+#[cfg(feature = "runtime")]
 impl<F1, F2> Future for Select<F1, F2>
 where
     F1: Future,
@@ -363,6 +597,35 @@ where
 mod tests {
     use super::*;
 
+    #[cfg(feature = "runtime")]
+    #[test]
+    fn test_duration_conversion() {
+        assert_eq!(duration_to_millis(Duration::from_secs(0)), 0);
+        assert_eq!(duration_to_millis(Duration::from_micros(1)), 1);
+        assert_eq!(duration_to_millis(Duration::from_micros(1500)), 2);
+        assert_eq!(duration_to_millis(Duration::from_millis(3000)), 3000);
+        assert_eq!(duration_to_millis(Duration::from_secs(100)), u16::MAX);
+
+        assert_eq!(duration_to_secs(Duration::from_secs(0)), 0);
+        assert_eq!(duration_to_secs(Duration::from_millis(1)), 1);
+        assert_eq!(duration_to_secs(Duration::from_millis(30_500)), 31);
+        assert_eq!(duration_to_secs(Duration::from_secs(30)), 30);
+        assert_eq!(duration_to_secs(Duration::new(u64::MAX, 999_999_999)), u16::MAX);
+    }
+
+    #[test]
+    fn test_capture_malformed() {
+        let fixed = FixedHeader { first_byte: 0x30, remaining_length: 200 };
+        let dump =
+            capture_malformed(&DecodeError::MalformedPacket, Some(fixed), &[0xab; 200], 4);
+        assert_eq!(dump, Bytes::from_static(b"\x30\xc8\x01\xab\xab\xab\xab"));
+        assert_eq!(hexdump(&dump), "30 c8 01 ab ab ab ab");
+
+        let dump = capture_malformed(&DecodeError::InvalidLength, None, &[0x10, 0xff], 16);
+        assert_eq!(dump, Bytes::from_static(b"\x10\xff"));
+        assert_eq!(hexdump(&[0; 17]).lines().count(), 2);
+    }
+
     #[test]
     fn test_decode_variable_length() {
         fn assert_variable_length<B: AsRef<[u8]> + 'static>(bytes: B, res: (u32, usize)) {
@@ -6,13 +6,15 @@ use ntex::rt::time::{delay_until, Instant as RtInstant};
 use ntex::service::{apply_fn, boxed::BoxService, into_service, IntoService, Service};
 use ntex::util::{Either, Ready};
 
+use crate::clock;
 use crate::error::{MqttError, ProtocolError};
 use crate::io::{DispatchItem, Dispatcher, Timer};
+use crate::v3::{codec, ControlResult, Publish};
 use crate::v3::{shared::MqttShared, sink::MqttSink};
-use crate::v3::{ControlResult, Publish};
 
 use super::control::ControlMessage;
 use super::dispatcher::create_dispatcher;
+use super::events::{ClientEvent, ClientEvents, Events};
 
 /// Mqtt client
 pub struct Client<Io> {
@@ -20,8 +22,12 @@ pub struct Client<Io> {
     shared: Rc<MqttShared>,
     keepalive: u16,
     disconnect_timeout: u16,
+    timer: Timer,
+    shutdown_timeout: Duration,
     session_present: bool,
     max_receive: usize,
+    events: Rc<Events>,
+    restored: Vec<codec::SubscribeReturnCode>,
 }
 
 impl<T> Client<T>
@@ -29,23 +35,36 @@ where
     T: AsyncRead + AsyncWrite + Unpin,
 {
     /// Construct new `Dispatcher` instance with outgoing messages stream.
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         io: T,
         shared: Rc<MqttShared>,
         session_present: bool,
         keepalive_timeout: u16,
         disconnect_timeout: u16,
+        timer: Timer,
+        shutdown_timeout: Duration,
         max_receive: usize,
     ) -> Self {
+        shared.keepalive.set(keepalive_timeout);
         Client {
             io,
             shared,
             session_present,
             disconnect_timeout,
+            timer,
+            shutdown_timeout,
             max_receive,
             keepalive: keepalive_timeout,
+            events: Rc::new(Events::default()),
+            restored: Vec::new(),
         }
     }
+
+    pub(super) fn restored(mut self, restored: Vec<codec::SubscribeReturnCode>) -> Self {
+        self.restored = restored;
+        self
+    }
 }
 
 impl<Io> Client<Io>
@@ -64,6 +83,23 @@ where
         self.session_present
     }
 
+    #[inline]
+    /// Results of session subscriptions restored during handshake
+    ///
+    /// Subscriptions are restored if connector has client session and
+    /// server does not have session state, otherwise result is empty.
+    pub fn restored_subscriptions(&self) -> &[codec::SubscribeReturnCode] {
+        &self.restored
+    }
+
+    /// Get stream of client lifecycle events.
+    ///
+    /// First event is always `ClientEvent::Connected`. Only one stream
+    /// is active at a time, previously returned stream ends.
+    pub fn events(&self) -> ClientEvents {
+        self.events.subscribe(ClientEvent::Connected { session_present: self.session_present })
+    }
+
     /// Configure mqtt resource for a specific topic
     pub fn resource<T, F, U, E>(self, address: T, service: F) -> ClientRouter<Io, E, U::Error>
     where
@@ -83,7 +119,10 @@ where
             shared: self.shared,
             keepalive: self.keepalive,
             disconnect_timeout: self.disconnect_timeout,
+            timer: self.timer,
+            shutdown_timeout: self.shutdown_timeout,
             max_receive: self.max_receive,
+            events: self.events,
             _t: PhantomData,
         }
     }
@@ -93,7 +132,11 @@ where
     /// Default handler closes connection on any control message.
     pub async fn start_default(self) {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.events.clone(),
+            ));
         }
 
         let dispatcher = create_dispatcher(
@@ -103,7 +146,12 @@ where
             into_service(|msg: ControlMessage| Ready::<_, MqttError<()>>::Ok(msg.disconnect())),
         );
 
-        let _ = Dispatcher::with(
+        let status = self.shared.shutdown.clone();
+
+        let expired = self.shared.expired.clone();
+
+        let flush = self.shared.flush.clone();
+        let res = Dispatcher::with(
             self.io,
             self.shared.state.clone(),
             self.shared.clone(),
@@ -125,11 +173,16 @@ where
                     Either::Right(Ready::Ok(None))
                 }
             }),
-            Timer::with(Duration::from_secs(1)),
+            self.timer,
         )
         .keepalive_timeout(0)
+        .shutdown_status(status)
+        .keepalive_expired(expired)
+        .flush_waiters(flush)
         .disconnect_timeout(self.disconnect_timeout)
+        .shutdown_timeout(self.shutdown_timeout)
         .await;
+        self.events.disconnected(&res);
     }
 
     /// Run client with provided control messages handler
@@ -140,7 +193,11 @@ where
         S: Service<Request = ControlMessage, Response = ControlResult, Error = E> + 'static,
     {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.events.clone(),
+            ));
         }
 
         let dispatcher = create_dispatcher(
@@ -150,7 +207,12 @@ where
             service.into_service().map_err(MqttError::Service),
         );
 
-        Dispatcher::with(
+        let status = self.shared.shutdown.clone();
+
+        let expired = self.shared.expired.clone();
+
+        let flush = self.shared.flush.clone();
+        let res = Dispatcher::with(
             self.io,
             self.shared.state.clone(),
             self.shared.clone(),
@@ -172,11 +234,17 @@ where
                     Either::Right(Ready::Ok(None))
                 }
             }),
-            Timer::with(Duration::from_secs(1)),
+            self.timer,
         )
         .keepalive_timeout(0)
+        .shutdown_status(status)
+        .keepalive_expired(expired)
+        .flush_waiters(flush)
         .disconnect_timeout(self.disconnect_timeout)
-        .await
+        .shutdown_timeout(self.shutdown_timeout)
+        .await;
+        self.events.disconnected(&res);
+        res
     }
 }
 
@@ -190,7 +258,10 @@ pub struct ClientRouter<Io, Err, PErr> {
     shared: Rc<MqttShared>,
     keepalive: u16,
     disconnect_timeout: u16,
+    timer: Timer,
+    shutdown_timeout: Duration,
     max_receive: usize,
+    events: Rc<Events>,
     _t: PhantomData<Err>,
 }
 
@@ -215,7 +286,11 @@ where
     /// Run client with default control messages handler
     pub async fn start_default(self) {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.events.clone(),
+            ));
         }
 
         let dispatcher = create_dispatcher(
@@ -227,7 +302,12 @@ where
             }),
         );
 
-        let _ = Dispatcher::with(
+        let status = self.shared.shutdown.clone();
+
+        let expired = self.shared.expired.clone();
+
+        let flush = self.shared.flush.clone();
+        let res = Dispatcher::with(
             self.io,
             self.shared.state.clone(),
             self.shared.clone(),
@@ -249,11 +329,16 @@ where
                     Either::Right(Ready::Ok(None))
                 }
             }),
-            Timer::with(Duration::from_secs(1)),
+            self.timer,
         )
         .keepalive_timeout(0)
+        .shutdown_status(status)
+        .keepalive_expired(expired)
+        .flush_waiters(flush)
         .disconnect_timeout(self.disconnect_timeout)
+        .shutdown_timeout(self.shutdown_timeout)
         .await;
+        self.events.disconnected(&res);
     }
 
     /// Run client and handle control messages
@@ -263,7 +348,11 @@ where
         S: Service<Request = ControlMessage, Response = ControlResult, Error = Err> + 'static,
     {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.events.clone(),
+            ));
         }
 
         let dispatcher = create_dispatcher(
@@ -273,7 +362,12 @@ where
             service.into_service().map_err(MqttError::Service),
         );
 
-        Dispatcher::with(
+        let status = self.shared.shutdown.clone();
+
+        let expired = self.shared.expired.clone();
+
+        let flush = self.shared.flush.clone();
+        let res = Dispatcher::with(
             self.io,
             self.shared.state.clone(),
             self.shared.clone(),
@@ -295,11 +389,17 @@ where
                     Either::Right(Ready::Ok(None))
                 }
             }),
-            Timer::with(Duration::from_secs(1)),
+            self.timer,
         )
         .keepalive_timeout(0)
+        .shutdown_status(status)
+        .keepalive_expired(expired)
+        .flush_waiters(flush)
         .disconnect_timeout(self.disconnect_timeout)
-        .await
+        .shutdown_timeout(self.shutdown_timeout)
+        .await;
+        self.events.disconnected(&res);
+        res
     }
 }
 
@@ -340,15 +440,33 @@ where
     }
 }
 
-async fn keepalive(sink: MqttSink, timeout: u16) {
+async fn keepalive(sink: MqttSink, timeout: u16, events: Rc<Events>) {
     log::debug!("start mqtt client keep-alive task");
 
     let keepalive = Duration::from_secs(timeout as u64);
     loop {
-        let expire = RtInstant::from_std(Instant::now() + keepalive);
-        delay_until(expire).await;
+        // server must respond to ping within keep-alive interval
+        let now = clock::now();
+        if let Some(sent) = sink.ping_sent() {
+            if sent + keepalive <= now {
+                log::debug!("mqtt client keep-alive ping timeout, closing connection");
+                events.ping_timeout();
+                sink.force_close();
+                break;
+            }
+        }
+
+        // ping is not needed if connection is not idle
+        let mut expire = sink.last_write() + keepalive;
+        if let Some(sent) = sink.ping_sent() {
+            expire = std::cmp::min(expire, sent + keepalive);
+        }
+        if expire > now {
+            delay_until(RtInstant::from_std(expire)).await;
+            continue;
+        }
 
-        if !sink.ping() {
+        if !sink.send_ping(None) {
             // connection is closed
             log::debug!("mqtt client connection is closed, stopping keep-alive task");
             break;
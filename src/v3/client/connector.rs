@@ -1,10 +1,10 @@
-use std::{future::Future, rc::Rc, time::Duration};
+use std::{future::Future, num::NonZeroU16, rc::Rc, time::Duration};
 
 use ntex::codec::{AsyncRead, AsyncWrite};
-use ntex::connect::{self, Address, Connect, Connector};
+use ntex::connect::{Address, Connect, ConnectError};
 use ntex::rt::time::delay_for;
 use ntex::service::Service;
-use ntex::util::{select, ByteString, Bytes, Either};
+use ntex::util::{select, ByteString, Bytes, Either, Ready};
 
 #[cfg(feature = "openssl")]
 use ntex::connect::openssl::{OpensslConnector, SslConnector};
@@ -12,21 +12,33 @@ use ntex::connect::openssl::{OpensslConnector, SslConnector};
 #[cfg(feature = "rustls")]
 use ntex::connect::rustls::{ClientConfig, RustlsConnector};
 
+#[cfg(feature = "quic")]
+use crate::quic::{quinn, QuicConnector};
+
+use super::session::ClientSession;
 use super::{codec, connection::Client, error::ClientError, error::ProtocolError};
-use crate::io::State;
+use crate::connect::{self, BoxResolver, Proxy, ProxyConnector, TcpConnector};
+use crate::io::{State, Timer};
+use crate::utils::{duration_to_millis, duration_to_secs};
 use crate::v3::shared::{MqttShared, MqttSinkPool};
 
 /// Mqtt client connector
 pub struct MqttConnector<A, T> {
     address: A,
-    connector: T,
+    connector: Rc<T>,
+    resolver: Option<BoxResolver<A>>,
+    connect_timeout: Duration,
     pkt: codec::Connect,
     max_send: usize,
     max_receive: usize,
     max_packet_size: u32,
-    handshake_timeout: u16,
+    handshake_timeout: Duration,
     disconnect_timeout: u16,
+    timer: Timer,
+    shutdown_timeout: Duration,
+    session: Option<ClientSession>,
     pool: Rc<MqttSinkPool>,
+    topic_prefix: Option<ByteString>,
 }
 
 impl<A> MqttConnector<A, ()>
@@ -35,17 +47,23 @@ where
 {
     #[allow(clippy::new_ret_no_self)]
     /// Create new mqtt connector
-    pub fn new(address: A) -> MqttConnector<A, Connector<A>> {
+    pub fn new(address: A) -> MqttConnector<A, TcpConnector<A>> {
         MqttConnector {
             address,
             pkt: codec::Connect::default(),
-            connector: Connector::default(),
+            connector: Rc::new(TcpConnector::default()),
+            resolver: None,
+            connect_timeout: Duration::ZERO,
             max_send: 16,
             max_receive: 16,
             max_packet_size: 64 * 1024,
-            handshake_timeout: 0,
+            handshake_timeout: Duration::ZERO,
             disconnect_timeout: 3000,
+            timer: Timer::default(),
+            shutdown_timeout: Duration::ZERO,
+            session: None,
             pool: Rc::new(MqttSinkPool::default()),
+            topic_prefix: None,
         }
     }
 }
@@ -53,7 +71,7 @@ where
 impl<A, T> MqttConnector<A, T>
 where
     A: Address + Clone,
-    T: Service<Request = Connect<A>, Error = connect::ConnectError>,
+    T: Service<Request = Connect<A>, Error = ConnectError>,
     T::Response: AsyncRead + AsyncWrite + Unpin + 'static,
 {
     #[inline]
@@ -74,11 +92,12 @@ where
     }
 
     #[inline]
-    /// A time interval measured in seconds.
+    /// Set keep-alive interval.
     ///
+    /// Interval is rounded up to whole seconds and is limited to `u16::MAX` seconds.
     /// keep-alive is set to 30 seconds by default.
-    pub fn keep_alive(mut self, val: u16) -> Self {
-        self.pkt.keep_alive = val;
+    pub fn keep_alive(mut self, val: Duration) -> Self {
+        self.pkt.keep_alive = duration_to_secs(val);
         self
     }
 
@@ -147,36 +166,135 @@ where
         self
     }
 
-    /// Set handshake timeout in milliseconds.
+    /// Set handshake timeout.
     ///
     /// Handshake includes `connect` packet and response `connect-ack`.
-    /// By default handshake timeuot is disabled.
-    pub fn handshake_timeout(mut self, timeout: u16) -> Self {
-        self.handshake_timeout = timeout as u16;
+    /// By default handshake timeout is disabled.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
         self
     }
 
-    /// Set client connection disconnect timeout in milliseconds.
+    /// Set client connection disconnect timeout.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
-    /// within this time, the connection get dropped.
+    /// within this time, the connection get dropped. Timeout is rounded up to millisecond
+    /// precision and is limited to `u16::MAX` milliseconds (about 65 seconds), larger
+    /// values are clamped with a warning.
     ///
     /// To disable timeout set value to 0.
     ///
     /// By default disconnect timeout is set to 3 seconds.
-    pub fn disconnect_timeout(mut self, timeout: u16) -> Self {
-        self.disconnect_timeout = timeout as u16;
+    pub fn disconnect_timeout(mut self, timeout: Duration) -> Self {
+        self.disconnect_timeout = duration_to_millis(timeout);
+        self
+    }
+
+    /// Set in-flight handlers shutdown timeout.
+    ///
+    /// Defines how long client connection waits for in-flight publish and
+    /// control handlers to complete after connection has been closed. Handlers
+    /// that do not complete within this time get dropped.
+    ///
+    /// To disable timeout set value to 0. By default timeout is disabled.
+    pub fn inflight_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Set resolution of connection timer.
+    ///
+    /// Disconnect timeout is checked with this granularity.
+    ///
+    /// By default resolution is set to 1 second.
+    pub fn timer_resolution(mut self, resolution: Duration) -> Self {
+        self.timer = Timer::with(resolution);
+        self
+    }
+
+    /// Use shared timer for client connections.
+    ///
+    /// Overrides `timer_resolution()` setting.
+    pub fn timer(mut self, timer: Timer) -> Self {
+        self.timer = timer;
+        self
+    }
+
+    /// Set client session state
+    ///
+    /// Subscriptions recorded in session are restored during handshake
+    /// if server does not have session state for the client.
+    pub fn session(mut self, session: ClientSession) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Set prefix of topics of publishes created by client sink
+    ///
+    /// See `MqttSink::set_topic_prefix()`.
+    pub fn topic_prefix<U>(mut self, prefix: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.topic_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Use custom address resolver
+    ///
+    /// Resolver must set addresses of `Connect` message, transport connector
+    /// does not resolve such messages. Resolver readiness is not checked.
+    pub fn resolver<R>(mut self, resolver: R) -> Self
+    where
+        R: Service<Request = Connect<A>, Response = Connect<A>, Error = ConnectError> + 'static,
+        R::Future: 'static,
+    {
+        self.resolver = Some(connect::boxed_resolver(resolver));
+        self
+    }
+
+    /// Set transport connect timeout
+    ///
+    /// Timeout includes tls handshake if transport connector performs one,
+    /// it does not include address resolution. By default timeout is disabled.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
         self
     }
 
     /// Use custom connector
     pub fn connector<U>(self, connector: U) -> MqttConnector<A, U>
     where
-        U: Service<Request = Connect<A>, Error = connect::ConnectError>,
+        U: Service<Request = Connect<A>, Error = ConnectError>,
         U::Response: AsyncRead + AsyncWrite + Unpin + 'static,
     {
         MqttConnector {
-            connector,
+            connector: Rc::new(connector),
+            resolver: self.resolver,
+            connect_timeout: self.connect_timeout,
+            pkt: self.pkt,
+            address: self.address,
+            max_send: self.max_send,
+            max_receive: self.max_receive,
+            max_packet_size: self.max_packet_size,
+            handshake_timeout: self.handshake_timeout,
+            disconnect_timeout: self.disconnect_timeout,
+            timer: self.timer,
+            shutdown_timeout: self.shutdown_timeout,
+            session: self.session,
+            pool: self.pool,
+            topic_prefix: self.topic_prefix,
+        }
+    }
+
+    /// Connect through SOCKS5 or HTTP `CONNECT` proxy
+    ///
+    /// Use `ProxyConnector` with custom connector to start tls over proxy tunnel.
+    pub fn proxy(self, proxy: Proxy) -> MqttConnector<A, ProxyConnector<A>> {
+        MqttConnector {
+            connector: Rc::new(ProxyConnector::new(proxy)),
+            resolver: self.resolver,
+            connect_timeout: self.connect_timeout,
             pkt: self.pkt,
             address: self.address,
             max_send: self.max_send,
@@ -184,7 +302,11 @@ where
             max_packet_size: self.max_packet_size,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            timer: self.timer,
+            shutdown_timeout: self.shutdown_timeout,
+            session: self.session,
             pool: self.pool,
+            topic_prefix: self.topic_prefix,
         }
     }
 
@@ -197,10 +319,16 @@ where
             max_send: self.max_send,
             max_receive: self.max_receive,
             max_packet_size: self.max_packet_size,
-            connector: OpensslConnector::new(connector),
+            connector: Rc::new(OpensslConnector::new(connector)),
+            resolver: self.resolver,
+            connect_timeout: self.connect_timeout,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            timer: self.timer,
+            shutdown_timeout: self.shutdown_timeout,
+            session: self.session,
             pool: self.pool,
+            topic_prefix: self.topic_prefix,
         }
     }
 
@@ -215,41 +343,100 @@ where
             max_send: self.max_send,
             max_receive: self.max_receive,
             max_packet_size: self.max_packet_size,
-            connector: RustlsConnector::new(Arc::new(config)),
+            connector: Rc::new(RustlsConnector::new(Arc::new(config))),
+            resolver: self.resolver,
+            connect_timeout: self.connect_timeout,
+            handshake_timeout: self.handshake_timeout,
+            disconnect_timeout: self.disconnect_timeout,
+            timer: self.timer,
+            shutdown_timeout: self.shutdown_timeout,
+            session: self.session,
+            pool: self.pool,
+            topic_prefix: self.topic_prefix,
+        }
+    }
+
+    #[cfg(feature = "quic")]
+    /// Use QUIC transport (experimental)
+    pub fn quic(self, endpoint: quinn::Endpoint) -> MqttConnector<A, QuicConnector<A>> {
+        MqttConnector {
+            pkt: self.pkt,
+            address: self.address,
+            max_send: self.max_send,
+            max_receive: self.max_receive,
+            max_packet_size: self.max_packet_size,
+            connector: Rc::new(QuicConnector::new(endpoint)),
+            resolver: self.resolver,
+            connect_timeout: self.connect_timeout,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            timer: self.timer,
+            shutdown_timeout: self.shutdown_timeout,
+            session: self.session,
             pool: self.pool,
+            topic_prefix: self.topic_prefix,
         }
     }
 
     /// Connect to mqtt server
     pub fn connect(&self) -> impl Future<Output = Result<Client<T::Response>, ClientError>> {
-        if self.handshake_timeout > 0 {
-            let fut = select(
-                delay_for(Duration::from_millis(self.handshake_timeout as u64)),
-                self._connect(),
-            );
+        let fut = connect::connect(
+            Connect::new(self.address.clone()),
+            self.resolver.clone(),
+            self.connector.clone(),
+            self.connect_timeout,
+        );
+        self.with_timeout(self._connect(async move { Ok(fut.await?) }))
+    }
+
+    /// Perform mqtt handshake over provided io object.
+    ///
+    /// Address and connector are not used, io must be already connected to mqtt server.
+    /// Could be used with in-memory transports or with transports managed by application.
+    pub fn connect_io<Io>(
+        &self,
+        io: Io,
+    ) -> impl Future<Output = Result<Client<Io>, ClientError>>
+    where
+        Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        self.with_timeout(self._connect(Ready::Ok(io)))
+    }
+
+    fn with_timeout<F, R>(&self, fut: F) -> impl Future<Output = Result<R, ClientError>>
+    where
+        F: Future<Output = Result<R, ClientError>>,
+    {
+        if self.handshake_timeout != Duration::ZERO {
+            let fut = select(delay_for(self.handshake_timeout), fut);
             Either::Left(async move {
                 let result = fut.await;
                 match result {
                     Either::Left(_) => Err(ClientError::HandshakeTimeout),
-                    Either::Right(res) => res.map_err(From::from),
+                    Either::Right(res) => res,
                 }
             })
         } else {
-            Either::Right(self._connect())
+            Either::Right(fut)
         }
     }
 
-    fn _connect(&self) -> impl Future<Output = Result<Client<T::Response>, ClientError>> {
-        let fut = self.connector.call(Connect::new(self.address.clone()));
+    fn _connect<F, Io>(&self, fut: F) -> impl Future<Output = Result<Client<Io>, ClientError>>
+    where
+        F: Future<Output = Result<Io, ClientError>>,
+        Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
         let pkt = self.pkt.clone();
         let max_send = self.max_send;
         let max_receive = self.max_receive;
         let max_packet_size = self.max_packet_size;
         let keepalive_timeout = pkt.keep_alive;
         let disconnect_timeout = self.disconnect_timeout;
+        let timer = self.timer.clone();
+        let shutdown_timeout = self.shutdown_timeout;
+        let session = self.session.clone();
         let pool = self.pool.clone();
+        let topic_prefix = self.topic_prefix.clone();
 
         async move {
             let mut io = fut.await?;
@@ -269,19 +456,31 @@ where
                     })
                 })?;
             let shared = Rc::new(MqttShared::new(state.clone(), codec, max_send, pool));
+            shared.client.set(true);
+            *shared.topic_prefix.borrow_mut() = topic_prefix;
 
             match packet {
                 codec::Packet::ConnectAck { session_present, return_code } => {
                     log::trace!("Connect ack response from server: session: present: {:?}, return code: {:?}", session_present, return_code);
                     if return_code == codec::ConnectAckReason::ConnectionAccepted {
+                        let restored = match session {
+                            Some(session) if !session_present && !session.is_empty() => {
+                                restore(&mut io, &state, &shared, session.subscriptions())
+                                    .await?
+                            }
+                            _ => Vec::new(),
+                        };
                         Ok(Client::new(
                             io,
                             shared,
                             session_present,
                             keepalive_timeout,
                             disconnect_timeout,
+                            timer,
+                            shutdown_timeout,
                             max_receive,
-                        ))
+                        )
+                        .restored(restored))
                     } else {
                         Err(ClientError::Ack { session_present, return_code })
                     }
@@ -295,3 +494,31 @@ where
         }
     }
 }
+
+/// Restore session subscriptions
+async fn restore<Io>(
+    io: &mut Io,
+    state: &State,
+    shared: &MqttShared,
+    topic_filters: Vec<(ByteString, codec::QoS)>,
+) -> Result<Vec<codec::SubscribeReturnCode>, ClientError>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    log::trace!("Restoring session subscriptions: {:?}", topic_filters);
+    let packet_id = NonZeroU16::new(shared.next_id()).unwrap();
+    state
+        .send(io, &shared.codec, codec::Packet::Subscribe { packet_id, topic_filters })
+        .await?;
+
+    match state.next(io, &shared.codec).await.map_err(ProtocolError::from)? {
+        Some(codec::Packet::SubscribeAck { packet_id: id, status }) if id == packet_id => {
+            Ok(status)
+        }
+        Some(p) => {
+            Err(ProtocolError::Unexpected(p.packet_type(), "Expected SUBSCRIBE-ACK packet")
+                .into())
+        }
+        None => Err(ClientError::Disconnected),
+    }
+}
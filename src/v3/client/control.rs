@@ -1,11 +1,14 @@
 pub use crate::v3::control::{Closed, ControlResult, Disconnect};
 use crate::v3::{codec, control::ControlResultKind};
+use crate::{io::ShutdownStatus, session::SessionEnd};
 
 pub enum ControlMessage {
     /// Unhandled publish packet
     Publish(Publish),
     /// Disconnect packet
     Disconnect(Disconnect),
+    /// Packet that does not correspond to any in-flight request
+    Unsolicited(Unsolicited),
     /// Connection closed
     Closed(Closed),
 }
@@ -19,8 +22,16 @@ impl ControlMessage {
         ControlMessage::Disconnect(Disconnect)
     }
 
-    pub(super) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(super) fn unsolicited(pkt: codec::Packet) -> Self {
+        ControlMessage::Unsolicited(Unsolicited(pkt))
+    }
+
+    pub(super) fn closed(
+        is_error: bool,
+        shutdown: Option<ShutdownStatus>,
+        session_end: SessionEnd,
+    ) -> Self {
+        ControlMessage::Closed(Closed::new(is_error, false, shutdown, session_end))
     }
 
     pub fn disconnect(&self) -> ControlResult {
@@ -49,3 +60,26 @@ impl Publish {
         }
     }
 }
+
+/// Unsolicited packet
+///
+/// Acknowledgement for unknown packet id or subscribe/unsubscribe packet
+/// sent by server. Default control service closes connection.
+pub struct Unsolicited(codec::Packet);
+
+impl Unsolicited {
+    /// Returns reference to packet
+    pub fn packet(&self) -> &codec::Packet {
+        &self.0
+    }
+
+    /// Returns packet
+    pub fn into_inner(self) -> codec::Packet {
+        self.0
+    }
+
+    /// Ignore packet and keep connection open
+    pub fn ack(self) -> ControlResult {
+        ControlResult { result: ControlResultKind::Nothing }
+    }
+}
@@ -5,9 +5,9 @@ use std::{future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc
 use ntex::service::Service;
 use ntex::util::{inflight::InFlightService, Either, HashSet, Ready};
 
+use crate::error::MqttError;
 use crate::v3::shared::Ack;
 use crate::v3::{codec, control::ControlResultKind, publish::Publish, sink::MqttSink};
-use crate::{error::MqttError, error::ProtocolError, types::packet_type};
 
 use super::control::{ControlMessage, ControlResult};
 
@@ -99,7 +99,11 @@ where
         if !self.shutdown.get() {
             self.inner.sink.close();
             self.shutdown.set(true);
-            let fut = self.inner.control.call(ControlMessage::closed(is_error));
+            let fut = self.inner.control.call(ControlMessage::closed(
+                is_error,
+                self.inner.sink.shutdown_status(),
+                self.inner.sink.session_end(false),
+            ));
             ntex::rt::spawn(async move {
                 let _ = fut.await;
             });
@@ -131,7 +135,9 @@ where
                     _t: PhantomData,
                 })
             }
-            codec::Packet::PublishAck { packet_id } => {
+            codec::Packet::PublishAck { packet_id }
+                if self.sink.is_inflight(packet_id.get()) =>
+            {
                 if let Err(e) = self.sink.pkt_ack(Ack::Publish(packet_id)) {
                     Either::Right(Either::Left(Ready::Err(MqttError::Protocol(e))))
                 } else {
@@ -141,38 +147,43 @@ where
             codec::Packet::PingRequest => {
                 Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PingResponse))))
             }
+            codec::Packet::PingResponse => {
+                self.sink.pong();
+                Either::Right(Either::Left(Ready::Ok(None)))
+            }
             codec::Packet::Disconnect => Either::Right(Either::Right(ControlResponse::new(
                 self.inner.control.call(ControlMessage::dis()),
                 &self.inner,
             ))),
-            codec::Packet::SubscribeAck { packet_id, status } => {
+            codec::Packet::SubscribeAck { packet_id, status }
+                if self.sink.is_inflight(packet_id.get()) =>
+            {
                 if let Err(e) = self.sink.pkt_ack(Ack::Subscribe { packet_id, status }) {
                     Either::Right(Either::Left(Ready::Err(MqttError::Protocol(e))))
                 } else {
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
-            codec::Packet::UnsubscribeAck { packet_id } => {
+            codec::Packet::UnsubscribeAck { packet_id }
+                if self.sink.is_inflight(packet_id.get()) =>
+            {
                 if let Err(e) = self.sink.pkt_ack(Ack::Unsubscribe(packet_id)) {
                     Either::Right(Either::Left(Ready::Err(MqttError::Protocol(e))))
                 } else {
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
-            codec::Packet::Subscribe { .. } => Either::Right(Either::Left(Ready::Err(
-                ProtocolError::Unexpected(
-                    packet_type::SUBSCRIBE,
-                    "Subscribe packet is not supported",
-                )
-                .into(),
-            ))),
-            codec::Packet::Unsubscribe { .. } => Either::Right(Either::Left(Ready::Err(
-                ProtocolError::Unexpected(
-                    packet_type::UNSUBSCRIBE,
-                    "Unsubscribe packet is not supported",
-                )
-                .into(),
-            ))),
+            pkt @ codec::Packet::PublishAck { .. }
+            | pkt @ codec::Packet::SubscribeAck { .. }
+            | pkt @ codec::Packet::UnsubscribeAck { .. }
+            | pkt @ codec::Packet::Subscribe { .. }
+            | pkt @ codec::Packet::Unsubscribe { .. } => {
+                log::trace!("Unsolicited packet: {:?}", pkt);
+                Either::Right(Either::Right(ControlResponse::new(
+                    self.inner.control.call(ControlMessage::unsolicited(pkt)),
+                    &self.inner,
+                )))
+            }
             _ => Either::Right(Either::Left(Ready::Ok(None))),
         }
     }
@@ -0,0 +1,94 @@
+//! Client lifecycle events
+use std::{cell::Cell, cell::RefCell, pin::Pin, task::Context, task::Poll};
+
+use ntex::{channel::mpsc, Stream};
+
+use crate::error::MqttError;
+
+/// Client lifecycle event
+#[derive(Debug)]
+pub enum ClientEvent {
+    /// Client is connected to the server
+    Connected {
+        /// Session present flag of CONNACK packet
+        session_present: bool,
+    },
+    /// Server did not respond to keep-alive ping in time, connection gets closed
+    PingTimeout,
+    /// Connection is closed
+    Disconnected(DisconnectCause),
+}
+
+/// Cause of connection close
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectCause {
+    /// Connection is closed without error
+    Closed,
+    /// Server did not respond to keep-alive ping
+    PingTimeout,
+    /// Protocol error, contains error description
+    Protocol(String),
+    /// Publish or control service error
+    Service,
+}
+
+/// Stream of client lifecycle events
+///
+/// Stream ends when connection is closed.
+#[derive(Debug)]
+pub struct ClientEvents(mpsc::Receiver<ClientEvent>);
+
+impl Stream for ClientEvents {
+    type Item = ClientEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ClientEvent>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Events {
+    tx: RefCell<Option<mpsc::Sender<ClientEvent>>>,
+    ping_timeout: Cell<bool>,
+}
+
+impl Events {
+    /// Create new events stream, previous stream gets closed
+    pub(super) fn subscribe(&self, connected: ClientEvent) -> ClientEvents {
+        let (tx, rx) = mpsc::channel();
+        let _ = tx.send(connected);
+        *self.tx.borrow_mut() = Some(tx);
+        ClientEvents(rx)
+    }
+
+    pub(super) fn ping_timeout(&self) {
+        self.ping_timeout.set(true);
+        self.emit(ClientEvent::PingTimeout);
+    }
+
+    pub(super) fn disconnected<E>(&self, res: &Result<(), MqttError<E>>) {
+        let cause = if self.ping_timeout.get() {
+            DisconnectCause::PingTimeout
+        } else {
+            match res {
+                Ok(_)
+                | Err(MqttError::Disconnected)
+                | Err(MqttError::HandshakeTimeout)
+                | Err(MqttError::Upgraded) => DisconnectCause::Closed,
+                Err(MqttError::Service(_)) => DisconnectCause::Service,
+                Err(MqttError::Protocol(e)) => DisconnectCause::Protocol(e.to_string()),
+                Err(MqttError::ServerError(e)) => DisconnectCause::Protocol(e.to_string()),
+            }
+        };
+        self.emit(ClientEvent::Disconnected(cause));
+
+        // stream ends after disconnect
+        self.tx.borrow_mut().take();
+    }
+
+    fn emit(&self, ev: ClientEvent) {
+        if let Some(ref tx) = *self.tx.borrow() {
+            let _ = tx.send(ev);
+        }
+    }
+}
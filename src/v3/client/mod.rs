@@ -3,10 +3,14 @@ mod connection;
 mod connector;
 pub mod control;
 mod dispatcher;
+mod events;
+mod session;
 
 pub use self::connection::{Client, ClientRouter};
 pub use self::connector::MqttConnector;
 pub use self::control::{ControlMessage, ControlResult};
+pub use self::events::{ClientEvent, ClientEvents, DisconnectCause};
+pub use self::session::ClientSession;
 
 pub use crate::topic::Topic;
 pub use crate::types::QoS;
@@ -0,0 +1,81 @@
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use ntex::util::ByteString;
+
+use crate::types::QoS;
+
+/// Client session state
+///
+/// Session records subscriptions made by application. If server does not
+/// have session state for the client (`session_present` flag of `CONNACK`
+/// is not set), connector restores recorded subscriptions during handshake.
+#[derive(Clone, Default)]
+pub struct ClientSession(Rc<RefCell<Vec<(ByteString, QoS)>>>);
+
+impl ClientSession {
+    /// Create empty session
+    pub fn new() -> Self {
+        ClientSession::default()
+    }
+
+    /// Record subscription, replaces QoS of existing subscription
+    pub fn subscribe<U>(&self, filter: U, qos: QoS)
+    where
+        ByteString: From<U>,
+    {
+        let filter = ByteString::from(filter);
+        let mut subs = self.0.borrow_mut();
+        if let Some(item) = subs.iter_mut().find(|(f, _)| *f == filter) {
+            item.1 = qos;
+        } else {
+            subs.push((filter, qos));
+        }
+    }
+
+    /// Remove subscription
+    pub fn unsubscribe(&self, filter: &str) {
+        self.0.borrow_mut().retain(|(f, _)| f != filter);
+    }
+
+    /// Recorded subscriptions
+    pub fn subscriptions(&self) -> Vec<(ByteString, QoS)> {
+        self.0.borrow().clone()
+    }
+
+    /// Check if session has no subscriptions
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+}
+
+impl fmt::Debug for ClientSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ClientSession").field(&self.0.borrow()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session() {
+        let session = ClientSession::new();
+        assert!(session.is_empty());
+        session.subscribe("a/b", QoS::AtMostOnce);
+        session.subscribe("a/c", QoS::AtLeastOnce);
+        session.subscribe("a/b", QoS::ExactlyOnce);
+        assert_eq!(
+            session.subscriptions(),
+            vec![
+                (ByteString::from_static("a/b"), QoS::ExactlyOnce),
+                (ByteString::from_static("a/c"), QoS::AtLeastOnce)
+            ]
+        );
+        session.clone().unsubscribe("a/b");
+        assert_eq!(
+            session.subscriptions(),
+            vec![(ByteString::from_static("a/c"), QoS::AtLeastOnce)]
+        );
+    }
+}
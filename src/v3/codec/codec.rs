@@ -1,18 +1,33 @@
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
 
-use ntex::codec::{Decoder, Encoder};
-use ntex::util::{Buf, BytesMut};
+use ntex_bytes::{Buf, Bytes, BytesMut};
+use ntex_codec::{Decoder, Encoder};
 
 use super::{decode, encode, Packet, Publish};
+use crate::budget::{DecodeGuard, DecodeStats};
+use crate::capture::{Direction, Recorder};
 use crate::error::{DecodeError, EncodeError};
 use crate::types::{FixedHeader, QoS};
-use crate::utils::decode_variable_length;
+use crate::utils::{
+    capture_malformed, check_topic_limits, decode_variable_length, variable_length_size,
+};
+
+/// Protocol version of captured packets
+const VERSION: u8 = 3;
 
 #[derive(Debug)]
 /// Mqtt v3.1.1 protocol codec
 pub struct Codec {
     state: Cell<DecodeState>,
     max_size: Cell<u32>,
+    max_topic_length: Cell<u16>,
+    max_topic_levels: Cell<u16>,
+    max_client_id_length: Cell<u16>,
+    capture_size: Cell<usize>,
+    malformed: RefCell<Option<Bytes>>,
+    recorder: RefCell<Option<Recorder>>,
+    guard: DecodeGuard,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -24,7 +39,17 @@ enum DecodeState {
 impl Codec {
     /// Create `Codec` instance
     pub fn new() -> Self {
-        Codec { state: Cell::new(DecodeState::FrameHeader), max_size: Cell::new(0) }
+        Codec {
+            state: Cell::new(DecodeState::FrameHeader),
+            max_size: Cell::new(0),
+            max_topic_length: Cell::new(0),
+            max_topic_levels: Cell::new(0),
+            max_client_id_length: Cell::new(0),
+            capture_size: Cell::new(0),
+            malformed: RefCell::new(None),
+            recorder: RefCell::new(None),
+            guard: DecodeGuard::default(),
+        }
     }
 
     /// Set max inbound frame size.
@@ -43,6 +68,188 @@ impl Codec {
     pub fn set_max_size(&self, size: u32) {
         self.max_size.set(size);
     }
+
+    /// Set max length of inbound topic names and topic filters in bytes.
+    ///
+    /// If max length is set to `0`, length is unlimited.
+    /// By default max length is set to `0`
+    pub fn max_topic_length(self, len: u16) -> Self {
+        self.max_topic_length.set(len);
+        self
+    }
+
+    /// Set max number of levels of inbound topic names and topic filters.
+    ///
+    /// If max levels is set to `0`, number of levels is unlimited.
+    /// By default max levels is set to `0`
+    pub fn max_topic_levels(self, levels: u16) -> Self {
+        self.max_topic_levels.set(levels);
+        self
+    }
+
+    /// Set max length of client id in `Connect` packet.
+    ///
+    /// If max length is set to `0`, length is unlimited.
+    /// By default max length is set to `0`
+    pub fn max_client_id_length(self, len: u16) -> Self {
+        self.max_client_id_length.set(len);
+        self
+    }
+
+    /// Set max length of inbound topic names and topic filters in bytes.
+    ///
+    /// If max length is set to `0`, length is unlimited.
+    /// By default max length is set to `0`
+    pub fn set_max_topic_length(&self, len: u16) {
+        self.max_topic_length.set(len);
+    }
+
+    /// Set max number of levels of inbound topic names and topic filters.
+    ///
+    /// If max levels is set to `0`, number of levels is unlimited.
+    /// By default max levels is set to `0`
+    pub fn set_max_topic_levels(&self, levels: u16) {
+        self.max_topic_levels.set(levels);
+    }
+
+    /// Set max length of client id in `Connect` packet.
+    ///
+    /// If max length is set to `0`, length is unlimited.
+    /// By default max length is set to `0`
+    pub fn set_max_client_id_length(&self, len: u16) {
+        self.max_client_id_length.set(len);
+    }
+
+    /// Capture bytes of inbound packets that fail to decode.
+    ///
+    /// Fixed header and at most `size` bytes of packet body are captured
+    /// and logged, last captured packet is available via `malformed_packet()`.
+    /// If size is set to `0`, capture is disabled.
+    /// By default capture is disabled
+    pub fn capture_malformed(self, size: usize) -> Self {
+        self.capture_size.set(size);
+        self
+    }
+
+    /// Capture bytes of inbound packets that fail to decode.
+    ///
+    /// If size is set to `0`, capture is disabled.
+    /// By default capture is disabled
+    pub fn set_capture_malformed(&self, size: usize) {
+        self.capture_size.set(size);
+    }
+
+    /// Returns captured bytes of last malformed inbound packet
+    pub fn malformed_packet(&self) -> Option<Bytes> {
+        self.malformed.borrow().clone()
+    }
+
+    /// Set decode time budget for inbound packets.
+    ///
+    /// Decoder fails with `DecodeError::DecodeBudgetExceeded` after `max_violations`
+    /// consecutive packets took longer than `budget` to decode.
+    /// If budget is set to `0`, check is disabled. By default check is disabled
+    pub fn set_decode_budget(&self, budget: Duration, max_violations: u32) {
+        self.guard.set_budget(budget, max_violations);
+    }
+
+    /// Set inbound packet decode statistics.
+    ///
+    /// By default statistics are not collected
+    pub fn set_decode_stats(&self, stats: Option<DecodeStats>) {
+        self.guard.set_stats(stats);
+    }
+
+    /// Set packet recorder.
+    ///
+    /// Decoded inbound and encoded outbound packets are written to recorder.
+    /// By default recording is disabled
+    pub fn set_recorder(&self, recorder: Option<Recorder>) {
+        *self.recorder.borrow_mut() = recorder;
+    }
+
+    fn record(&self, direction: Direction, packet: &[u8]) {
+        if let Some(ref recorder) = *self.recorder.borrow() {
+            recorder.record(VERSION, direction, packet);
+        }
+    }
+
+    fn malformed(
+        &self,
+        err: DecodeError,
+        fixed: Option<FixedHeader>,
+        buf: &[u8],
+    ) -> DecodeError {
+        let size = self.capture_size.get();
+        if size != 0 {
+            *self.malformed.borrow_mut() = Some(capture_malformed(&err, fixed, buf, size));
+        }
+        err
+    }
+
+    /// Returns size of encoded packet, including fixed header
+    pub fn encoded_size(&self, item: &Packet) -> usize {
+        let content_size = encode::get_encoded_size(item);
+        1 + variable_length_size(content_size) + content_size
+    }
+
+    /// Encode packet into provided buffer.
+    ///
+    /// Buffer is extended to fit encoded packet.
+    pub fn encode_into(&self, item: &Packet, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        if let Packet::Publish(publish) = item {
+            if publish.qos == QoS::AtMostOnce {
+                // small qos0 publish packets dominate telemetry traffic
+                let start = dst.len();
+                if encode::encode_small_publish(publish, dst) {
+                    self.record(Direction::Outbound, &dst[start..]);
+                    return Ok(());
+                }
+            } else if publish.packet_id.is_none() {
+                return Err(EncodeError::PacketIdRequired);
+            }
+        }
+        let content_size = encode::get_encoded_size(item);
+        dst.reserve(content_size + 5);
+        let start = dst.len();
+        encode::encode(item, dst, content_size as u32)?;
+        self.record(Direction::Outbound, &dst[start..]);
+        Ok(())
+    }
+
+    fn check_limits(&self, packet: &Packet) -> Result<(), DecodeError> {
+        let max_len = self.max_topic_length.get();
+        let max_levels = self.max_topic_levels.get();
+
+        match packet {
+            Packet::Connect(pkt) => {
+                let max = self.max_client_id_length.get() as usize;
+                ensure!(max == 0 || pkt.client_id.len() <= max, DecodeError::InvalidClientId);
+            }
+            Packet::Publish(pkt) => {
+                ensure!(
+                    check_topic_limits(&pkt.topic, max_len, max_levels),
+                    DecodeError::InvalidTopic
+                );
+            }
+            Packet::Subscribe { topic_filters, .. } => {
+                ensure!(
+                    topic_filters
+                        .iter()
+                        .all(|(f, _)| check_topic_limits(f, max_len, max_levels)),
+                    DecodeError::InvalidTopicFilter
+                );
+            }
+            Packet::Unsubscribe { topic_filters, .. } => {
+                ensure!(
+                    topic_filters.iter().all(|f| check_topic_limits(f, max_len, max_levels)),
+                    DecodeError::InvalidTopicFilter
+                );
+            }
+            _ => (),
+        }
+        Ok(())
+    }
 }
 
 impl Default for Codec {
@@ -64,12 +271,18 @@ impl Decoder for Codec {
                     }
                     let src_slice = src.as_ref();
                     let first_byte = src_slice[0];
-                    match decode_variable_length(&src_slice[1..])? {
+                    match decode_variable_length(&src_slice[1..])
+                        .map_err(|err| self.malformed(err, None, src_slice))?
+                    {
                         Some((remaining_length, consumed)) => {
                             // check max message size
                             let max_size = self.max_size.get();
                             if max_size != 0 && max_size < remaining_length {
-                                return Err(DecodeError::MaxSizeExceeded);
+                                return Err(self.malformed(
+                                    DecodeError::MaxSizeExceeded,
+                                    None,
+                                    src_slice,
+                                ));
                             }
                             src.advance(consumed + 1);
                             self.state.set(DecodeState::Frame(FixedHeader {
@@ -93,10 +306,20 @@ impl Decoder for Codec {
                     if src.len() < fixed.remaining_length as usize {
                         return Ok(None);
                     }
-                    let packet_buf = src.split_to(fixed.remaining_length as usize);
-                    let packet = decode::decode_packet(packet_buf.freeze(), fixed.first_byte)?;
+                    let packet_buf = src.split_to(fixed.remaining_length as usize).freeze();
+                    let started = self.guard.start();
+                    let packet = decode::decode_packet(packet_buf.clone(), fixed.first_byte)
+                        .map_err(|err| self.malformed(err, Some(fixed), &packet_buf))?;
                     self.state.set(DecodeState::FrameHeader);
                     src.reserve(2);
+                    self.check_limits(&packet)
+                        .map_err(|err| self.malformed(err, Some(fixed), &packet_buf))?;
+                    if !self.guard.finish(started, fixed.remaining_length) {
+                        return Err(DecodeError::DecodeBudgetExceeded);
+                    }
+                    if let Some(ref recorder) = *self.recorder.borrow() {
+                        recorder.record_inbound(VERSION, fixed.first_byte, &packet_buf);
+                    }
                     return Ok(Some(packet));
                 }
             }
@@ -109,22 +332,14 @@ impl Encoder for Codec {
     type Error = EncodeError;
 
     fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), EncodeError> {
-        if let Packet::Publish(Publish { qos, packet_id, .. }) = item {
-            if (qos == QoS::AtLeastOnce || qos == QoS::ExactlyOnce) && packet_id.is_none() {
-                return Err(EncodeError::PacketIdRequired);
-            }
-        }
-        let content_size = encode::get_encoded_size(&item);
-        dst.reserve(content_size + 5);
-        encode::encode(&item, dst, content_size as u32)?;
-        Ok(())
+        self.encode_into(&item, dst)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ntex::util::{ByteString, Bytes};
+    use ntex_bytes::{ByteString, Bytes};
 
     #[test]
     fn test_max_size() {
@@ -135,6 +350,88 @@ mod tests {
         assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
     }
 
+    #[test]
+    fn test_capture_malformed() {
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x30\x01\xff");
+        assert!(codec.decode(&mut buf).is_err());
+        assert_eq!(codec.malformed_packet(), None);
+
+        let codec = Codec::new().capture_malformed(2);
+        let mut buf = BytesMut::new();
+        // publish with truncated topic
+        buf.extend_from_slice(b"\x30\x04\x00\x08ab");
+        assert!(codec.decode(&mut buf).is_err());
+        assert_eq!(codec.malformed_packet(), Some(Bytes::from_static(b"\x30\x04\x00\x08")));
+    }
+
+    #[test]
+    fn test_topic_limits() {
+        let codec = Codec::new().max_topic_length(8).max_topic_levels(2);
+
+        let pkt = |topic| {
+            Packet::Publish(Publish {
+                dup: false,
+                retain: false,
+                qos: QoS::AtMostOnce,
+                topic: ByteString::from_static(topic),
+                packet_id: None,
+                payload: Bytes::new(),
+            })
+        };
+
+        let mut buf = BytesMut::new();
+        codec.encode(pkt("a/b"), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(pkt("a/b")));
+
+        codec.encode(pkt("a/b/c"), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::InvalidTopic));
+
+        codec.encode(pkt("topic/long"), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::InvalidTopic));
+
+        let subs = Packet::Subscribe {
+            packet_id: std::num::NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(ByteString::from_static("a/+/#"), QoS::AtMostOnce)],
+        };
+        codec.encode(subs, &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::InvalidTopicFilter));
+    }
+
+    #[test]
+    fn test_client_id_limit() {
+        let codec = Codec::new().max_client_id_length(4);
+
+        let mut buf = BytesMut::new();
+        let pkt = super::super::Connect::default().client_id("client-1");
+        codec.encode(Packet::Connect(pkt), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::InvalidClientId));
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let codec = Codec::new();
+        let pkt = Packet::Publish(Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: ByteString::from_static("/test"),
+            packet_id: std::num::NonZeroU16::new(1),
+            payload: Bytes::from(Vec::from("a".repeat(200))),
+        });
+
+        let mut buf = BytesMut::new();
+        codec.encode_into(&pkt, &mut buf).unwrap();
+        assert_eq!(codec.encoded_size(&pkt), buf.len());
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(pkt));
+
+        let mut buf = BytesMut::new();
+        codec.encode_into(&Packet::PingRequest, &mut buf).unwrap();
+        assert_eq!(codec.encoded_size(&Packet::PingRequest), 2);
+        assert_eq!(buf.len(), 2);
+    }
+
     #[test]
     fn test_packet() {
         let codec = Codec::new();
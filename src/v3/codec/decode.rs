@@ -1,8 +1,9 @@
 use std::{convert::TryFrom, convert::TryInto, num::NonZeroU16};
 
-use ntex::util::{Buf, ByteString, Bytes};
+use ntex_bytes::{Buf, ByteString, Bytes};
 
 use crate::error::DecodeError;
+use crate::reason;
 use crate::types::{packet_type, QoS, MQTT, MQTT_LEVEL_3, WILL_QOS_SHIFT};
 use crate::utils::Decode;
 
@@ -144,7 +145,7 @@ fn decode_subscribe_ack_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
     let packet_id = NonZeroU16::decode(src)?;
     let mut status = Vec::with_capacity(src.len());
     for code in src.as_ref().iter() {
-        status.push(if *code == 0x80 {
+        status.push(if *code == reason::v3::SUBSCRIBE_FAILURE {
             SubscribeReturnCode::Failure
         } else {
             SubscribeReturnCode::Success(QoS::try_from(*code)?)
@@ -1,6 +1,7 @@
-use ntex::util::{BufMut, BytesMut};
+use ntex_bytes::{BufMut, BytesMut};
 
 use crate::error::EncodeError;
+use crate::reason;
 use crate::types::{packet_type, ConnectFlags, QoS, MQTT, MQTT_LEVEL_3, WILL_QOS_SHIFT};
 use crate::utils::{write_variable_length, Encode};
 
@@ -62,6 +63,33 @@ pub(crate) fn get_encoded_size(packet: &Packet) -> usize {
     }
 }
 
+/// Max remaining length of publish packet handled by fast path,
+/// remaining length fits into a single byte
+const SMALL_PUBLISH_SIZE: usize = 127;
+
+/// Encode small QoS 0 publish packet without generic size calculation.
+///
+/// Returns `false` if packet is not eligible for fast path.
+pub(crate) fn encode_small_publish(publish: &Publish, dst: &mut BytesMut) -> bool {
+    let size = 2 + publish.topic.len() + publish.payload.len();
+    if publish.qos != QoS::AtMostOnce
+        || publish.packet_id.is_some()
+        || size > SMALL_PUBLISH_SIZE
+    {
+        return false;
+    }
+
+    dst.reserve(size + 2);
+    dst.put_slice(&[
+        packet_type::PUBLISH_START | ((publish.dup as u8) << 3) | (publish.retain as u8),
+        size as u8,
+    ]);
+    dst.put_u16(publish.topic.len() as u16);
+    dst.put_slice(publish.topic.as_bytes());
+    dst.put_slice(publish.payload.as_ref());
+    true
+}
+
 pub(crate) fn encode(
     packet: &Packet,
     dst: &mut BytesMut,
@@ -136,7 +164,7 @@ pub(crate) fn encode(
                 .iter()
                 .map(|s| match *s {
                     SubscribeReturnCode::Success(qos) => qos.into(),
-                    _ => 0x80u8,
+                    _ => reason::v3::SUBSCRIBE_FAILURE,
                 })
                 .collect();
             dst.put_slice(&buf);
@@ -220,7 +248,7 @@ fn encode_connect(connect: &Connect, dst: &mut BytesMut) -> Result<(), EncodeErr
 
 #[cfg(test)]
 mod tests {
-    use ntex::util::{ByteString, Bytes};
+    use ntex_bytes::{ByteString, Bytes};
     use std::num::NonZeroU16;
 
     use super::*;
@@ -324,6 +352,41 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_encode_small_publish() {
+        let publish = |qos, packet_id, payload: Bytes| Publish {
+            dup: true,
+            retain: true,
+            qos,
+            topic: ByteString::from_static("topic"),
+            packet_id,
+            payload,
+        };
+
+        for size in [0, 4, 120, 121, 122, 300] {
+            let p = publish(QoS::AtMostOnce, None, vec![b'x'; size].into());
+            let mut fast = BytesMut::new();
+            let eligible = encode_small_publish(&p, &mut fast);
+            assert_eq!(eligible, size <= 120);
+
+            let p = Packet::Publish(p);
+            let mut v = BytesMut::with_capacity(512);
+            encode(&p, &mut v, get_encoded_size(&p) as u32).unwrap();
+            if eligible {
+                assert_eq!(fast, v);
+            } else {
+                assert!(fast.is_empty());
+            }
+        }
+
+        let mut v = BytesMut::new();
+        let p = publish(QoS::AtLeastOnce, Some(packet_id(1)), Bytes::from_static(b"data"));
+        assert!(!encode_small_publish(&p, &mut v));
+        let p = publish(QoS::AtMostOnce, Some(packet_id(1)), Bytes::from_static(b"data"));
+        assert!(!encode_small_publish(&p, &mut v));
+        assert!(v.is_empty());
+    }
+
     #[test]
     fn test_encode_subscribe_packets() {
         assert_encode_packet(
@@ -0,0 +1,147 @@
+//! Fuzzing support for mqtt v3.1.1 codec
+//!
+//! `Arbitrary` implementations always produce packets that are valid
+//! for the codec, so every generated packet must survive encode/decode.
+use arbitrary::{Arbitrary, Result, Unstructured};
+use ntex_bytes::BytesMut;
+use ntex_codec::Decoder;
+
+use super::{Codec, Connect, LastWill, Packet, Publish, SubscribeReturnCode};
+use crate::types::QoS;
+use crate::utils::arbitrary::*;
+
+impl<'a> Arbitrary<'a> for LastWill {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(LastWill {
+            qos: u.arbitrary()?,
+            retain: u.arbitrary()?,
+            topic: string(u)?,
+            message: binary(u)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Connect {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let client_id = string(u)?;
+        Ok(Connect {
+            // empty client id is allowed only for clean session
+            clean_session: client_id.is_empty() || u.arbitrary()?,
+            keep_alive: u.arbitrary()?,
+            last_will: u.arbitrary()?,
+            client_id,
+            username: option(u, string)?,
+            password: option(u, binary)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Publish {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let qos = u.arbitrary()?;
+        Ok(Publish {
+            dup: u.arbitrary()?,
+            retain: u.arbitrary()?,
+            qos,
+            topic: string(u)?,
+            packet_id: if qos == QoS::AtMostOnce { None } else { Some(packet_id(u)?) },
+            payload: payload(u)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for SubscribeReturnCode {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        if u.arbitrary()? {
+            Ok(SubscribeReturnCode::Success(u.arbitrary()?))
+        } else {
+            Ok(SubscribeReturnCode::Failure)
+        }
+    }
+}
+
+impl<'a> Arbitrary<'a> for Packet {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=13)? {
+            0 => Packet::Connect(u.arbitrary()?),
+            1 => Packet::ConnectAck {
+                session_present: u.arbitrary()?,
+                return_code: u.arbitrary()?,
+            },
+            2 => Packet::Publish(u.arbitrary()?),
+            3 => Packet::PublishAck { packet_id: packet_id(u)? },
+            4 => Packet::PublishReceived { packet_id: packet_id(u)? },
+            5 => Packet::PublishRelease { packet_id: packet_id(u)? },
+            6 => Packet::PublishComplete { packet_id: packet_id(u)? },
+            7 => Packet::Subscribe {
+                packet_id: packet_id(u)?,
+                topic_filters: vec(u, 8, |u| Ok((string(u)?, u.arbitrary()?)))?,
+            },
+            8 => Packet::SubscribeAck {
+                packet_id: packet_id(u)?,
+                status: vec(u, 8, |u| u.arbitrary())?,
+            },
+            9 => Packet::Unsubscribe {
+                packet_id: packet_id(u)?,
+                topic_filters: vec(u, 8, string)?,
+            },
+            10 => Packet::UnsubscribeAck { packet_id: packet_id(u)? },
+            11 => Packet::PingRequest,
+            12 => Packet::PingResponse,
+            _ => Packet::Disconnect,
+        })
+    }
+}
+
+/// Encode packet, decode it back and check that result matches original packet.
+///
+/// Panics on mismatch.
+pub fn check_round_trip(pkt: &Packet) {
+    let codec = Codec::new();
+    let mut buf = BytesMut::new();
+    codec.encode_into(pkt, &mut buf).expect("valid packet must be encodable");
+    assert_eq!(codec.encoded_size(pkt), buf.len(), "encoded size mismatch for {:?}", pkt);
+
+    let decoded = codec.decode(&mut buf).expect("encoded packet must be decodable");
+    assert_eq!(decoded.as_ref(), Some(pkt));
+    assert!(buf.is_empty());
+}
+
+/// Round-trip harness for fuzz targets.
+///
+/// Input is decoded as a stream of raw packets, every successfully decoded packet
+/// must survive `check_round_trip()`. After that the same input is used to
+/// generate arbitrary packet, which is checked the same way.
+pub fn round_trip(data: &[u8]) {
+    let codec = Codec::new();
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(pkt)) = codec.decode(&mut buf) {
+        check_round_trip(&pkt);
+    }
+
+    if let Ok(pkt) = Packet::arbitrary(&mut Unstructured::new(data)) {
+        check_round_trip(&pkt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut seed = 0x2545_f491_u32;
+        let mut data = vec![0u8; 512];
+
+        for _ in 0..2000 {
+            for b in data.iter_mut() {
+                // xorshift, deterministic input
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                *b = seed as u8;
+            }
+            round_trip(&data);
+        }
+    }
+}
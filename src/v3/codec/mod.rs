@@ -6,9 +6,14 @@ mod decode;
 mod encode;
 mod packet;
 
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+#[cfg(any(test, feature = "proptest"))]
+pub mod strategy;
+
 pub use self::codec::Codec;
 pub use self::packet::{
-    Connect, ConnectAckReason, LastWill, Packet, Publish, SubscribeReturnCode,
+    Connect, ConnectAck, ConnectAckReason, LastWill, Packet, Publish, SubscribeReturnCode,
 };
 pub use crate::topic::{Level, Topic, TopicError};
 pub use crate::types::{ConnectAckFlags, ConnectFlags, QoS};
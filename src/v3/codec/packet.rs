@@ -1,8 +1,11 @@
 use std::{fmt, num::NonZeroU16};
 
-use ntex::util::{ByteString, Bytes};
+use ntex_bytes::{ByteString, Bytes, BytesMut};
 
+use super::{decode::decode_packet, Codec};
+use crate::error::{DecodeError, EncodeError};
 use crate::types::{packet_type, QoS};
+use crate::utils::split_fixed_header;
 
 prim_enum! {
     /// Connect Return Code
@@ -43,6 +46,7 @@ impl ConnectAckReason {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 /// Connection Will
 pub struct LastWill {
     /// the QoS level to be used when publishing the Will Message.
@@ -55,7 +59,36 @@ pub struct LastWill {
     pub message: Bytes,
 }
 
+impl LastWill {
+    /// Create will message with `QoS 0` and retain flag unset
+    pub fn new<T, M>(topic: T, message: M) -> Self
+    where
+        ByteString: From<T>,
+        Bytes: From<M>,
+    {
+        LastWill {
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: topic.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Set will message QoS
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Set will message retain flag
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+}
+
 #[derive(Default, Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 /// Connect packet content
 pub struct Connect {
     /// the handling of the Session state.
@@ -73,6 +106,14 @@ pub struct Connect {
 }
 
 impl Connect {
+    /// Create connect packet with client id, other fields are set to defaults
+    pub fn new<T>(client_id: T) -> Self
+    where
+        ByteString: From<T>,
+    {
+        Connect { client_id: client_id.into(), ..Default::default() }
+    }
+
     /// Set client_id value
     pub fn client_id<T>(mut self, client_id: T) -> Self
     where
@@ -81,9 +122,102 @@ impl Connect {
         self.client_id = client_id.into();
         self
     }
+
+    /// Set clean_session value
+    pub fn clean_session(mut self, clean: bool) -> Self {
+        self.clean_session = clean;
+        self
+    }
+
+    /// Set keep_alive value in seconds
+    pub fn keep_alive(mut self, secs: u16) -> Self {
+        self.keep_alive = secs;
+        self
+    }
+
+    /// Set will message
+    pub fn last_will(mut self, will: LastWill) -> Self {
+        self.last_will = Some(will);
+        self
+    }
+
+    /// Set username value
+    pub fn username<T>(mut self, username: T) -> Self
+    where
+        ByteString: From<T>,
+    {
+        self.username = Some(username.into());
+        self
+    }
+
+    /// Set password value
+    pub fn password<T>(mut self, password: T) -> Self
+    where
+        Bytes: From<T>,
+    {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Will message, if set
+    pub fn will(&self) -> Option<&LastWill> {
+        self.last_will.as_ref()
+    }
+
+    /// Username and password, if set
+    pub fn credentials(&self) -> (Option<&ByteString>, Option<&Bytes>) {
+        (self.username.as_ref(), self.password.as_ref())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
+/// Connect acknowledgment
+///
+/// Convenience type for constructing `Packet::ConnectAck`
+pub struct ConnectAck {
+    session_present: bool,
+    return_code: ConnectAckReason,
+}
+
+impl ConnectAck {
+    /// Connection accepted
+    pub fn accepted(session_present: bool) -> Self {
+        ConnectAck { session_present, return_code: ConnectAckReason::ConnectionAccepted }
+    }
+
+    /// Connection refused, session present flag is unset
+    pub fn rejected(return_code: ConnectAckReason) -> Self {
+        ConnectAck { session_present: false, return_code }
+    }
+
+    /// Session present flag
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    /// Connect return code
+    pub fn return_code(&self) -> ConnectAckReason {
+        self.return_code
+    }
+
+    /// Returns true if connection is accepted
+    pub fn is_accepted(&self) -> bool {
+        self.return_code == ConnectAckReason::ConnectionAccepted
+    }
+}
+
+impl From<ConnectAck> for Packet {
+    fn from(ack: ConnectAck) -> Packet {
+        Packet::ConnectAck {
+            session_present: ack.session_present,
+            return_code: ack.return_code,
+        }
+    }
 }
 
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 /// Publish message
 pub struct Publish {
     /// this might be re-delivery of an earlier attempt to send the Packet.
@@ -113,6 +247,7 @@ impl fmt::Debug for Publish {
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 /// Subscribe Return Code
 pub enum SubscribeReturnCode {
     Success(QoS),
@@ -120,6 +255,7 @@ pub enum SubscribeReturnCode {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 /// MQTT Control Packets
 pub enum Packet {
     /// Client request to connect to Server
@@ -205,6 +341,19 @@ impl From<Publish> for Packet {
 }
 
 impl Packet {
+    /// Decode packet from raw bytes, including fixed header
+    pub fn from_bytes(mut src: Bytes) -> Result<Packet, DecodeError> {
+        let first_byte = split_fixed_header(&mut src)?;
+        decode_packet(src, first_byte)
+    }
+
+    /// Encode packet to raw bytes, including fixed header
+    pub fn to_bytes(&self) -> Result<Bytes, EncodeError> {
+        let mut buf = BytesMut::new();
+        Codec::new().encode_into(self, &mut buf)?;
+        Ok(buf.freeze())
+    }
+
     pub fn packet_type(&self) -> u8 {
         match self {
             Packet::Connect(_) => packet_type::CONNECT,
@@ -253,4 +402,83 @@ mod tests {
             "Connection Refused, not authorized"
         );
     }
+
+    #[test]
+    fn test_raw_bytes() {
+        let pkt = Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(ByteString::from_static("a/b"), QoS::AtLeastOnce)],
+        };
+        let raw = pkt.to_bytes().unwrap();
+        assert_eq!(raw, Bytes::from_static(b"\x82\x08\x00\x01\x00\x03a/b\x01"));
+        assert_eq!(Packet::from_bytes(raw.clone()).unwrap(), pkt);
+
+        assert_eq!(Packet::from_bytes(raw.slice(..5)), Err(DecodeError::InvalidLength));
+        assert_eq!(
+            Packet::from_bytes(Bytes::from_static(b"\xc0")),
+            Err(DecodeError::InvalidLength)
+        );
+        assert_eq!(
+            Packet::from_bytes(Bytes::from_static(b"\xc0\x00")),
+            Ok(Packet::PingRequest)
+        );
+    }
+
+    #[test]
+    fn test_connect_constructors() {
+        let pkt = Connect::new("client")
+            .clean_session(true)
+            .keep_alive(30)
+            .last_will(LastWill::new("will", "gone").qos(QoS::AtLeastOnce).retain(true))
+            .username("user")
+            .password("pass");
+        assert_eq!(pkt.client_id, "client");
+        assert!(pkt.clean_session);
+        assert_eq!(pkt.keep_alive, 30);
+        assert_eq!(pkt.will().unwrap().qos, QoS::AtLeastOnce);
+        assert!(pkt.will().unwrap().retain);
+        assert_eq!(
+            pkt.credentials(),
+            (Some(&ByteString::from_static("user")), Some(&Bytes::from_static(b"pass")))
+        );
+
+        let pkt = Packet::Connect(pkt);
+        assert_eq!(Packet::from_bytes(pkt.to_bytes().unwrap()).unwrap(), pkt);
+
+        let ack = ConnectAck::accepted(true);
+        assert!(ack.is_accepted());
+        assert!(ack.session_present());
+        assert_eq!(
+            Packet::from(ack),
+            Packet::ConnectAck {
+                session_present: true,
+                return_code: ConnectAckReason::ConnectionAccepted
+            }
+        );
+        let ack = ConnectAck::rejected(ConnectAckReason::NotAuthorized);
+        assert!(!ack.is_accepted());
+        assert_eq!(ack.return_code(), ConnectAckReason::NotAuthorized);
+    }
+
+    #[cfg(feature = "with-serde")]
+    #[test]
+    fn test_serde() {
+        let pkt = Packet::Publish(Publish {
+            dup: false,
+            retain: true,
+            qos: QoS::AtLeastOnce,
+            topic: ByteString::from_static("a/b"),
+            packet_id: NonZeroU16::new(1),
+            payload: Bytes::from_static(b"data"),
+        });
+        let json = serde_json::to_string(&pkt).unwrap();
+        assert_eq!(serde_json::from_str::<Packet>(&json).unwrap(), pkt);
+
+        let pkt = Packet::ConnectAck {
+            session_present: false,
+            return_code: ConnectAckReason::NotAuthorized,
+        };
+        let json = serde_json::to_string(&pkt).unwrap();
+        assert_eq!(serde_json::from_str::<Packet>(&json).unwrap(), pkt);
+    }
 }
@@ -0,0 +1,106 @@
+//! Proptest strategies for mqtt v3.1.1 packets
+//!
+//! Strategies always produce packets that are valid for the codec, so
+//! downstream property tests could feed them to encoder and decoder.
+use proptest::{collection, prelude::*};
+
+use super::{Connect, LastWill, Packet, Publish, SubscribeReturnCode};
+use crate::types::QoS;
+use crate::utils::strategy::*;
+
+/// Strategy for connection will
+pub fn last_will() -> impl Strategy<Value = LastWill> {
+    (any::<QoS>(), any::<bool>(), string(), binary())
+        .prop_map(|(qos, retain, topic, message)| LastWill { qos, retain, topic, message })
+}
+
+/// Strategy for `CONNECT` packet
+pub fn connect() -> impl Strategy<Value = Connect> {
+    (any::<bool>(), any::<u16>(), opt(last_will()), string(), opt(string()), opt(binary()))
+        .prop_map(|(clean_session, keep_alive, last_will, client_id, username, password)| {
+            Connect {
+                // empty client id is allowed only for clean session
+                clean_session: clean_session || client_id.is_empty(),
+                keep_alive,
+                last_will,
+                client_id,
+                username,
+                password,
+            }
+        })
+}
+
+/// Strategy for `PUBLISH` packet
+pub fn publish() -> impl Strategy<Value = Publish> {
+    (any::<bool>(), any::<bool>(), any::<QoS>(), string(), packet_id(), payload()).prop_map(
+        |(dup, retain, qos, topic, packet_id, payload)| Publish {
+            dup,
+            retain,
+            qos,
+            topic,
+            packet_id: if qos == QoS::AtMostOnce { None } else { Some(packet_id) },
+            payload,
+        },
+    )
+}
+
+/// Strategy for subscription return code
+pub fn subscribe_return_code() -> impl Strategy<Value = SubscribeReturnCode> {
+    prop_oneof![
+        any::<QoS>().prop_map(SubscribeReturnCode::Success),
+        Just(SubscribeReturnCode::Failure),
+    ]
+}
+
+/// Strategy for any packet
+pub fn packet() -> impl Strategy<Value = Packet> {
+    prop_oneof![
+        connect().prop_map(Packet::Connect),
+        (any::<bool>(), any::<super::ConnectAckReason>()).prop_map(
+            |(session_present, return_code)| Packet::ConnectAck {
+                session_present,
+                return_code
+            }
+        ),
+        publish().prop_map(Packet::Publish),
+        packet_id().prop_map(|packet_id| Packet::PublishAck { packet_id }),
+        packet_id().prop_map(|packet_id| Packet::PublishReceived { packet_id }),
+        packet_id().prop_map(|packet_id| Packet::PublishRelease { packet_id }),
+        packet_id().prop_map(|packet_id| Packet::PublishComplete { packet_id }),
+        (packet_id(), collection::vec((string(), any::<QoS>()), 0..8)).prop_map(
+            |(packet_id, topic_filters)| Packet::Subscribe { packet_id, topic_filters }
+        ),
+        (packet_id(), collection::vec(subscribe_return_code(), 0..8))
+            .prop_map(|(packet_id, status)| Packet::SubscribeAck { packet_id, status }),
+        (packet_id(), collection::vec(string(), 0..8)).prop_map(
+            |(packet_id, topic_filters)| Packet::Unsubscribe { packet_id, topic_filters }
+        ),
+        packet_id().prop_map(|packet_id| Packet::UnsubscribeAck { packet_id }),
+        Just(Packet::PingRequest),
+        Just(Packet::PingResponse),
+        Just(Packet::Disconnect),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex_bytes::BytesMut;
+    use ntex_codec::Decoder;
+
+    use super::*;
+    use crate::v3::codec::Codec;
+
+    proptest! {
+        #[test]
+        fn test_round_trip(pkt in packet()) {
+            let codec = Codec::new();
+            let mut buf = BytesMut::new();
+            codec.encode_into(&pkt, &mut buf).unwrap();
+            prop_assert_eq!(codec.encoded_size(&pkt), buf.len());
+
+            let decoded = codec.decode(&mut buf).unwrap();
+            prop_assert_eq!(decoded, Some(pkt));
+            prop_assert!(buf.is_empty());
+        }
+    }
+}
@@ -2,13 +2,13 @@ use ntex::util::ByteString;
 use std::{marker::PhantomData, num::NonZeroU16};
 
 use super::codec;
-use crate::types::QoS;
+use crate::{io::ShutdownStatus, session::SessionEnd, types::QoS};
 
 #[derive(Debug)]
 pub enum ControlMessage {
     /// Ping packet
     Ping(Ping),
-    /// Disconnect packet
+    /// Disconnect packet, client is about to close connection
     Disconnect(Disconnect),
     /// Subscribe packet
     Subscribe(Subscribe),
@@ -43,8 +43,13 @@ impl ControlMessage {
         ControlMessage::Disconnect(Disconnect)
     }
 
-    pub(crate) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(crate) fn closed(
+        is_error: bool,
+        is_clean: bool,
+        shutdown: Option<ShutdownStatus>,
+        session_end: SessionEnd,
+    ) -> Self {
+        ControlMessage::Closed(Closed::new(is_error, is_clean, shutdown, session_end))
     }
 
     pub fn disconnect(&self) -> ControlResult {
@@ -61,6 +66,9 @@ impl Ping {
     }
 }
 
+/// Disconnect message
+///
+/// Client sent `DISCONNECT` packet, last will must not be published.
 #[derive(Debug)]
 pub struct Disconnect;
 
@@ -233,11 +241,19 @@ impl Unsubscribe {
 #[derive(Debug)]
 pub struct Closed {
     is_error: bool,
+    is_clean: bool,
+    shutdown: Option<ShutdownStatus>,
+    session_end: SessionEnd,
 }
 
 impl Closed {
-    pub(crate) fn new(is_error: bool) -> Self {
-        Self { is_error }
+    pub(crate) fn new(
+        is_error: bool,
+        is_clean: bool,
+        shutdown: Option<ShutdownStatus>,
+        session_end: SessionEnd,
+    ) -> Self {
+        Self { is_error, is_clean, shutdown, session_end }
     }
 
     /// Returns error state on connection close
@@ -245,6 +261,25 @@ impl Closed {
         self.is_error
     }
 
+    /// Returns `true` if connection is closed after `DISCONNECT` packet from the peer.
+    ///
+    /// Last will should be published only if connection is not closed cleanly.
+    pub fn is_clean(&self) -> bool {
+        self.is_clean
+    }
+
+    /// Returns transport shutdown status.
+    ///
+    /// Status is `None` if io has not been closed by dispatcher.
+    pub fn shutdown_status(&self) -> Option<ShutdownStatus> {
+        self.shutdown
+    }
+
+    /// Returns reason of session end.
+    pub fn session_end(&self) -> SessionEnd {
+        self.session_end
+    }
+
     #[inline]
     /// convert packet to a result
     pub fn ack(self) -> ControlResult {
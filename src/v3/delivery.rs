@@ -0,0 +1,116 @@
+//! Delivery of publishes to subscribed clients
+use super::{codec, error::SendPacketError, sink::MqttSink};
+use crate::topic::TopicFilter;
+
+/// Subscriptions of a connected client
+///
+/// Subscriptions are maintained by application, i.e. in control service on
+/// subscribe and unsubscribe messages.
+///
+/// ```rust,no_run
+/// # use ntex_mqtt::v3::{codec, MqttSink, Subscriptions};
+/// # async fn forward(sink: MqttSink, subs: Subscriptions, publish: codec::Publish) {
+/// match subs.deliver(&sink, &publish).await {
+///     Ok(true) => log::trace!("Publish is delivered"),
+///     Ok(false) => log::trace!("Client is not subscribed to {:?}", publish.topic),
+///     Err(e) => log::warn!("Cannot deliver publish: {}", e),
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Subscriptions(Vec<(TopicFilter, codec::QoS)>);
+
+impl Subscriptions {
+    /// Create empty subscriptions set
+    pub fn new() -> Self {
+        Subscriptions::default()
+    }
+
+    /// Add subscription with granted QoS, replaces existing subscription
+    /// with the same filter
+    pub fn subscribe(&mut self, filter: TopicFilter, qos: codec::QoS) {
+        if let Some(item) = self.0.iter_mut().find(|(f, _)| *f == filter) {
+            item.1 = qos;
+        } else {
+            self.0.push((filter, qos));
+        }
+    }
+
+    /// Remove subscription, returns `false` if subscription does not exist
+    pub fn unsubscribe(&mut self, filter: &str) -> bool {
+        let len = self.0.len();
+        self.0.retain(|(f, _)| f.as_str() != filter);
+        len != self.0.len()
+    }
+
+    /// Number of subscriptions
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Check if client has no subscriptions
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterate over subscriptions
+    pub fn iter(&self) -> impl Iterator<Item = (&TopicFilter, codec::QoS)> {
+        self.0.iter().map(|(f, qos)| (f, *qos))
+    }
+
+    /// Match topic against subscriptions, returns maximum granted QoS
+    /// of matched subscriptions
+    pub fn matches(&self, topic: &str) -> Option<codec::QoS> {
+        self.0
+            .iter()
+            .filter(|(f, _)| f.matches_str(topic))
+            .map(|(_, qos)| *qos)
+            .max_by_key(|qos| u8::from(*qos))
+    }
+
+    /// Deliver publish to the client if it is subscribed to publish topic
+    ///
+    /// Publish is sent with lowest of publish QoS and granted QoS, QoS 2 is
+    /// delivered as QoS 1. Retain flag is cleared. Returns `false` if no
+    /// subscription matches publish.
+    pub async fn deliver(
+        &self,
+        sink: &MqttSink,
+        publish: &codec::Publish,
+    ) -> Result<bool, SendPacketError> {
+        let qos = if let Some(qos) = self.matches(&publish.topic) {
+            qos
+        } else {
+            return Ok(false);
+        };
+
+        let builder = sink.publish(publish.topic.clone(), publish.payload.clone());
+        if publish.qos == codec::QoS::AtMostOnce || qos == codec::QoS::AtMostOnce {
+            builder.send_at_most_once()?;
+        } else {
+            builder.send_at_least_once().await?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let mut subs = Subscriptions::new();
+        subs.subscribe(TopicFilter::from_static("a/+"), codec::QoS::AtMostOnce);
+        subs.subscribe(TopicFilter::from_static("a/#"), codec::QoS::AtLeastOnce);
+        assert_eq!(subs.matches("a/b"), Some(codec::QoS::AtLeastOnce));
+        assert_eq!(subs.matches("b"), None);
+
+        subs.subscribe(TopicFilter::from_static("a/#"), codec::QoS::AtMostOnce);
+        assert_eq!(subs.len(), 2);
+        assert_eq!(subs.matches("a/b"), Some(codec::QoS::AtMostOnce));
+        assert!(subs.unsubscribe("a/+"));
+        assert!(!subs.unsubscribe("a/+"));
+        assert_eq!(subs.matches("a"), Some(codec::QoS::AtMostOnce));
+    }
+}
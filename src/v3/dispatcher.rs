@@ -1,22 +1,56 @@
 use std::cell::{Cell, RefCell};
 use std::task::{Context, Poll};
+use std::time::Duration;
 use std::{future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc};
 
 use ntex::service::{fn_factory_with_config, Service, ServiceFactory};
-use ntex::util::{inflight::InFlightService, join, Either, HashSet, Ready};
+use ntex::util::{inflight::InFlightService, join, ByteString, Either, HashSet, Ready};
 
-use crate::error::MqttError;
+use crate::ack::{self, AckSlot, AckState};
+use crate::backlog::Watermark;
+use crate::config::ConfigHandle;
+use crate::dedup::{Dedup, DedupWindow, InflightControl};
+use crate::error::{MqttError, ProtocolError};
+use crate::ordered::Ordered;
+use crate::quota::{SubscriptionLimits, SubscriptionQuota};
+use crate::registry::Registration;
+use crate::rewrite::{Tenant, TopicRewrite};
+use crate::timeout::HandlerTimeout;
+use crate::types::{packet_type, QoS, RetainPolicy};
 
 use super::control::{
     ControlMessage, ControlResult, ControlResultKind, Subscribe, Unsubscribe,
 };
-use super::{codec, publish::Publish, shared::Ack, sink::MqttSink, Session};
+use super::publish::{DeadLetterHook, Publish, PublishFailure, PublishHook, PublishTrace};
+use super::{codec, shared::Ack, sink::MqttSink, Session, SinkRegistry};
+
+/// Tenant namespace of authenticated session
+pub(super) type TenantFn<St> = Rc<dyn Fn(&Session<St>) -> Option<ByteString>>;
+
+/// Dispatcher settings, built by server builder
+pub(super) struct DispatcherConfig<St, E> {
+    pub(super) inflight: usize,
+    pub(super) handle: ConfigHandle,
+    pub(super) dedup: Option<Rc<DedupWindow>>,
+    pub(super) watermark: Option<Watermark>,
+    pub(super) hook: Option<PublishHook>,
+    pub(super) ordered_lanes: usize,
+    pub(super) dead_letter: Option<DeadLetterHook<E>>,
+    pub(super) publish_timeout: Duration,
+    pub(super) registry: Option<SinkRegistry>,
+    pub(super) subscription_limits: SubscriptionLimits,
+    pub(super) retain_policy: RetainPolicy,
+    pub(super) topic_rewrite: Option<Rc<TopicRewrite>>,
+    pub(super) tenant: Option<TenantFn<St>>,
+    pub(super) max_qos: Option<QoS>,
+    pub(super) auto_ack: bool,
+}
 
 /// mqtt3 protocol dispatcher
 pub(super) fn factory<St, T, C, E>(
     publish: T,
     control: C,
-    inflight: usize,
+    config: DispatcherConfig<St, E>,
 ) -> impl ServiceFactory<
     Config = Session<St>,
     Request = codec::Packet,
@@ -42,18 +76,41 @@ where
             InitError = MqttError<E>,
         > + 'static,
 {
+    let config = Rc::new(config);
+
     fn_factory_with_config(move |cfg: Session<St>| {
         // create services
         let fut = join(publish.new_service(cfg.clone()), control.new_service(cfg.clone()));
+        cfg.sink().set_watermark(config.watermark);
+        let tenant = match config.tenant.as_ref().and_then(|f| f(&cfg)) {
+            Some(namespace) => match Tenant::new(&namespace) {
+                Some(tenant) => {
+                    cfg.sink().set_tenant(tenant.clone());
+                    Ok(Some(tenant))
+                }
+                None => {
+                    log::error!("Invalid tenant namespace: {:?}", namespace);
+                    Err(MqttError::ServerError("Invalid tenant namespace"))
+                }
+            },
+            None => Ok(None),
+        };
+        let inflight = config.handle.max_inflight().map(usize::from).unwrap_or(config.inflight);
+        let config = config.clone();
 
         async move {
             let (publish, control) = fut.await;
+            let tenant = tenant?;
+            let publish = Ordered::new(
+                HandlerTimeout::new(publish?, config.publish_timeout),
+                config.ordered_lanes,
+            );
 
             Ok(
                 // limit number of in-flight messages
                 InFlightService::new(
                     inflight,
-                    Dispatcher::<_, _, _, E>::new(cfg, publish?, control?),
+                    Dispatcher::<_, _, _, E>::new(cfg, publish, control?, tenant, &config),
                 ),
             )
         }
@@ -66,20 +123,67 @@ pub(crate) struct Dispatcher<St, T: Service<Error = MqttError<E>>, C, E> {
     publish: T,
     control: C,
     shutdown: Cell<bool>,
+    disconnected: Cell<bool>,
+    dead_letter: Option<DeadLetterHook<E>>,
+    retain_policy: RetainPolicy,
+    topic_rewrite: Option<Rc<TopicRewrite>>,
+    tenant: Option<Tenant>,
+    _registration: Option<Registration<MqttSink>>,
     inner: Rc<Inner>,
 }
 
 struct Inner {
     sink: MqttSink,
     inflight: RefCell<HashSet<NonZeroU16>>,
+    inflight_control: InflightControl,
+    quota: Option<SubscriptionQuota>,
+    max_qos: Option<QoS>,
+    auto_ack: bool,
+    dedup: Option<Dedup>,
+    hook: Option<PublishHook>,
+}
+
+impl Inner {
+    /// Acknowledge publish before publish service completes
+    fn ack_publish(&self, packet_id: NonZeroU16) {
+        self.inflight.borrow_mut().remove(&packet_id);
+        if let Some(ref dedup) = self.dedup {
+            dedup.record(packet_id);
+        }
+        self.sink.send(codec::Packet::PublishAck { packet_id });
+    }
+
+    /// Acknowledge retransmissions of handled control packet
+    fn ack_retransmits(&self, packet_id: NonZeroU16, ack: &codec::Packet) {
+        for _ in 0..self.inflight_control.complete(packet_id) {
+            self.sink.send(ack.clone());
+        }
+    }
 }
 
 impl<St, T, C, E> Dispatcher<St, T, C, E>
 where
-    T: Service<Request = Publish, Response = (), Error = MqttError<E>>,
+    T: Service<Request = Publish, Response = Option<()>, Error = MqttError<E>>,
     C: Service<Request = ControlMessage, Response = ControlResult, Error = MqttError<E>>,
 {
-    pub(crate) fn new(session: Session<St>, publish: T, control: C) -> Self {
+    pub(crate) fn new(
+        session: Session<St>,
+        publish: T,
+        control: C,
+        tenant: Option<Tenant>,
+        config: &DispatcherConfig<St, E>,
+    ) -> Self {
+        let dedup = config
+            .dedup
+            .clone()
+            .filter(|_| !session.client_id().is_empty())
+            .map(|window| Dedup::new(window, session.client_id().clone()));
+        let registration =
+            config.registry.as_ref().filter(|_| !session.client_id().is_empty()).map(
+                |registry| {
+                    registry.register(session.client_id().clone(), session.sink().clone())
+                },
+            );
         let sink = session.sink().clone();
 
         Self {
@@ -87,14 +191,29 @@ where
             publish,
             control,
             shutdown: Cell::new(false),
-            inner: Rc::new(Inner { sink, inflight: RefCell::new(HashSet::default()) }),
+            disconnected: Cell::new(false),
+            dead_letter: config.dead_letter.clone(),
+            retain_policy: config.retain_policy,
+            topic_rewrite: config.topic_rewrite.clone(),
+            tenant,
+            _registration: registration,
+            inner: Rc::new(Inner {
+                sink,
+                dedup,
+                hook: config.hook.clone(),
+                inflight: RefCell::new(HashSet::default()),
+                inflight_control: InflightControl::default(),
+                quota: config.subscription_limits.quota(),
+                max_qos: config.max_qos,
+                auto_ack: config.auto_ack,
+            }),
         }
     }
 }
 
 impl<St, T, C, E> Service for Dispatcher<St, T, C, E>
 where
-    T: Service<Request = Publish, Response = (), Error = MqttError<E>>,
+    T: Service<Request = Publish, Response = Option<()>, Error = MqttError<E>>,
     C: Service<Request = ControlMessage, Response = ControlResult, Error = MqttError<E>>,
     C::Future: 'static,
     E: 'static,
@@ -103,7 +222,7 @@ where
     type Response = Option<codec::Packet>;
     type Error = MqttError<E>;
     type Future = Either<
-        PublishResponse<T::Future, MqttError<E>>,
+        PublishResponse<T::Future, E>,
         Either<Ready<Self::Response, MqttError<E>>, ControlResponse<C::Future, E>>,
     >;
 
@@ -118,25 +237,64 @@ where
         }
     }
 
-    fn poll_shutdown(&self, _: &mut Context<'_>, is_error: bool) -> Poll<()> {
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
         if !self.shutdown.get() {
             self.inner.sink.close();
             self.shutdown.set(true);
-            let fut = self.control.call(ControlMessage::closed(is_error));
+            self.session.session_tasks().close();
+            let fut = self.control.call(ControlMessage::closed(
+                is_error,
+                self.disconnected.get(),
+                self.inner.sink.shutdown_status(),
+                self.inner.sink.session_end(self.disconnected.get()),
+            ));
             ntex::rt::spawn(async move {
                 let _ = fut.await;
             });
         }
-        Poll::Ready(())
+        // wait for cancelled session tasks
+        self.session.session_tasks().poll_completed(cx)
     }
 
     fn call(&self, packet: codec::Packet) -> Self::Future {
         log::trace!("Dispatch packet: {:#?}", packet);
         match packet {
-            codec::Packet::Publish(publish) => {
+            codec::Packet::Publish(mut publish) => {
                 let inner = self.inner.clone();
                 let packet_id = publish.packet_id;
 
+                if publish.retain {
+                    match self.retain_policy {
+                        RetainPolicy::Accept => (),
+                        RetainPolicy::Strip => publish.retain = false,
+                        RetainPolicy::Reject => {
+                            log::trace!("Retained publish is rejected: {:?}", publish.topic);
+                            return Either::Right(Either::Left(Ready::Err(
+                                MqttError::Protocol(ProtocolError::RetainNotSupported),
+                            )));
+                        }
+                    }
+                }
+
+                if let Some(ref rewrite) = self.topic_rewrite {
+                    publish.topic = rewrite.topic(&publish.topic);
+                }
+                if let Some(ref tenant) = self.tenant {
+                    if let Some(topic) = tenant.topic(&publish.topic) {
+                        publish.topic = topic;
+                    } else {
+                        log::trace!(
+                            "Publish topic is outside of namespace: {:?}",
+                            publish.topic
+                        );
+                        return Either::Right(Either::Left(Ready::Err(
+                            MqttError::ServerError(
+                                "Publish topic is outside of tenant namespace",
+                            ),
+                        )));
+                    }
+                }
+
                 // check for duplicated packet id
                 if let Some(pid) = packet_id {
                     if !inner.inflight.borrow_mut().insert(pid) {
@@ -146,10 +304,32 @@ where
                         )));
                     }
                 }
+
+                // check for redelivery of acknowledged publish
+                let duplicate = match (&inner.dedup, packet_id) {
+                    (Some(dedup), Some(pid)) => dedup.check(pid, publish.dup),
+                    _ => false,
+                };
+
+                // publish is acknowledged once publish service is called
+                let ack = if inner.auto_ack && publish.qos == QoS::AtLeastOnce {
+                    Some(ack::slot())
+                } else {
+                    None
+                };
+
                 Either::Left(PublishResponse {
                     packet_id,
+                    ack: ack.clone(),
+                    dead_letter: self
+                        .dead_letter
+                        .as_ref()
+                        .map(|hook| (publish.clone(), hook.clone())),
+                    trace: inner.hook.as_ref().map(|hook| PublishTrace::new(hook, &publish)),
                     inner,
-                    fut: self.publish.call(Publish::new(publish)),
+                    fut: self.publish.call(
+                        Publish::new(publish).with_duplicate(duplicate).with_auto_ack(ack),
+                    ),
                     _t: PhantomData,
                 })
             }
@@ -160,22 +340,83 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
-            codec::Packet::PingRequest => Either::Right(Either::Right(ControlResponse::new(
-                self.control.call(ControlMessage::ping()),
-                &self.inner,
-            ))),
-            codec::Packet::Disconnect => Either::Right(Either::Right(ControlResponse::new(
-                self.control.call(ControlMessage::pkt_disconnect()),
-                &self.inner,
-            ))),
-            codec::Packet::Subscribe { packet_id, topic_filters } => {
+            codec::Packet::PingRequest => {
+                if self.inner.auto_ack {
+                    Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PingResponse))))
+                } else {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        self.control.call(ControlMessage::ping()),
+                        &self.inner,
+                    )))
+                }
+            }
+            codec::Packet::Disconnect => {
+                self.disconnected.set(true);
+                Either::Right(Either::Right(ControlResponse::new(
+                    self.control.call(ControlMessage::pkt_disconnect()),
+                    &self.inner,
+                )))
+            }
+            codec::Packet::Subscribe { packet_id, mut topic_filters } => {
                 if !self.inner.inflight.borrow_mut().insert(packet_id) {
+                    // retransmission is acknowledged after original packet
+                    if self.inner.inflight_control.retransmit(packet_id, packet_type::SUBSCRIBE)
+                    {
+                        log::trace!("Retransmitted subscribe packet: {:?}", packet_id);
+                        return Either::Right(Either::Left(Ready::Ok(None)));
+                    }
                     log::trace!("Duplicated packet id for unsubscribe packet: {:?}", packet_id);
                     return Either::Right(Either::Left(Ready::Err(MqttError::ServerError(
                         "Duplicated packet id for unsubscribe packet",
                     ))));
                 }
 
+                if let Some(ref rewrite) = self.topic_rewrite {
+                    for (filter, _) in topic_filters.iter_mut() {
+                        *filter = rewrite.filter(filter);
+                    }
+                }
+
+                // reject filters that do not belong to tenant namespace
+                if let Some(ref tenant) = self.tenant {
+                    let filters: Option<Vec<_>> = topic_filters
+                        .iter()
+                        .map(|(filter, qos)| tenant.filter(filter).map(|f| (f, *qos)))
+                        .collect();
+                    if let Some(filters) = filters {
+                        topic_filters = filters;
+                    } else {
+                        self.inner.inflight.borrow_mut().remove(&packet_id);
+                        return Either::Right(Either::Left(Ready::Ok(Some(
+                            codec::Packet::SubscribeAck {
+                                packet_id,
+                                status: topic_filters
+                                    .iter()
+                                    .map(|_| codec::SubscribeReturnCode::Failure)
+                                    .collect(),
+                            },
+                        ))));
+                    }
+                }
+
+                // check subscription limits
+                if let Some(ref quota) = self.inner.quota {
+                    if !quota.subscribe(packet_id, topic_filters.iter().map(|(f, _)| f)) {
+                        self.inner.inflight.borrow_mut().remove(&packet_id);
+                        return Either::Right(Either::Left(Ready::Ok(Some(
+                            codec::Packet::SubscribeAck {
+                                packet_id,
+                                status: topic_filters
+                                    .iter()
+                                    .map(|_| codec::SubscribeReturnCode::Failure)
+                                    .collect(),
+                            },
+                        ))));
+                    }
+                }
+
+                self.inner.inflight_control.insert(packet_id, packet_type::SUBSCRIBE);
+
                 Either::Right(Either::Right(ControlResponse::new(
                     self.control.call(ControlMessage::Subscribe(Subscribe::new(
                         packet_id,
@@ -184,14 +425,44 @@ where
                     &self.inner,
                 )))
             }
-            codec::Packet::Unsubscribe { packet_id, topic_filters } => {
+            codec::Packet::Unsubscribe { packet_id, mut topic_filters } => {
                 if !self.inner.inflight.borrow_mut().insert(packet_id) {
+                    // retransmission is acknowledged after original packet
+                    if self
+                        .inner
+                        .inflight_control
+                        .retransmit(packet_id, packet_type::UNSUBSCRIBE)
+                    {
+                        log::trace!("Retransmitted unsubscribe packet: {:?}", packet_id);
+                        return Either::Right(Either::Left(Ready::Ok(None)));
+                    }
                     log::trace!("Duplicated packet id for unsubscribe packet: {:?}", packet_id);
                     return Either::Right(Either::Left(Ready::Err(MqttError::ServerError(
                         "Duplicated packet id for unsubscribe packet",
                     ))));
                 }
 
+                if let Some(ref rewrite) = self.topic_rewrite {
+                    for filter in topic_filters.iter_mut() {
+                        *filter = rewrite.filter(filter);
+                    }
+                }
+                // drop filters that do not belong to tenant namespace
+                if let Some(ref tenant) = self.tenant {
+                    topic_filters =
+                        topic_filters.iter().filter_map(|f| tenant.filter(f)).collect();
+                    if topic_filters.is_empty() {
+                        self.inner.inflight.borrow_mut().remove(&packet_id);
+                        return Either::Right(Either::Left(Ready::Ok(Some(
+                            codec::Packet::UnsubscribeAck { packet_id },
+                        ))));
+                    }
+                }
+                if let Some(ref quota) = self.inner.quota {
+                    quota.unsubscribe(topic_filters.iter());
+                }
+                self.inner.inflight_control.insert(packet_id, packet_type::UNSUBSCRIBE);
+
                 Either::Right(Either::Right(ControlResponse::new(
                     self.control.call(ControlMessage::Unsubscribe(Unsubscribe::new(
                         packet_id,
@@ -211,6 +482,9 @@ pin_project_lite::pin_project! {
         #[pin]
         fut: T,
         packet_id: Option<NonZeroU16>,
+        ack: Option<AckSlot>,
+        trace: Option<PublishTrace>,
+        dead_letter: Option<(codec::Publish, DeadLetterHook<E>)>,
         inner: Rc<Inner>,
         _t: PhantomData<E>,
     }
@@ -218,15 +492,48 @@ pin_project_lite::pin_project! {
 
 impl<T, E> Future for PublishResponse<T, E>
 where
-    T: Future<Output = Result<(), E>>,
+    T: Future<Output = Result<Option<()>, MqttError<E>>>,
 {
-    type Output = Result<Option<codec::Packet>, E>;
+    type Output = Result<Option<codec::Packet>, MqttError<E>>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
+        let result = this.fut.poll(cx);
 
-        match this.fut.poll(cx) {
-            Poll::Ready(result) => result?,
+        // publish service is called and did not opt out of auto-ack
+        if let Some(ref ack) = this.ack {
+            if ack.get() == AckState::Auto {
+                ack.set(AckState::Sent);
+                if let Some(packet_id) = this.packet_id.take() {
+                    this.inner.ack_publish(packet_id);
+                }
+            }
+        }
+
+        match result {
+            Poll::Ready(result) => {
+                if let Some(trace) = this.trace {
+                    trace.finish(std::matches!(result, Ok(Some(_))));
+                }
+                if let Some((publish, hook)) = this.dead_letter.take() {
+                    match result {
+                        Ok(None) => (*hook)(publish, PublishFailure::Timeout),
+                        Err(MqttError::Service(ref e)) => {
+                            (*hook)(publish, PublishFailure::Error(e))
+                        }
+                        _ => (),
+                    }
+                }
+                if result?.is_none() {
+                    // handler timeout, QoS 0 publish is discarded
+                    if this.packet_id.is_some() {
+                        return Poll::Ready(Err(MqttError::ServerError(
+                            "Publish handler timeout",
+                        )));
+                    }
+                    return Poll::Ready(Ok(None));
+                }
+            }
             Poll::Pending => return Poll::Pending,
         };
 
@@ -234,6 +541,9 @@ where
 
         if let Some(packet_id) = this.packet_id {
             this.inner.inflight.borrow_mut().remove(packet_id);
+            if let Some(ref dedup) = this.inner.dedup {
+                dedup.record(*packet_id);
+            }
             Poll::Ready(Ok(Some(codec::Packet::PublishAck { packet_id: *packet_id })))
         } else {
             Poll::Ready(Ok(None))
@@ -274,16 +584,36 @@ where
         let packet = match this.fut.poll(cx)? {
             Poll::Ready(item) => match item.result {
                 ControlResultKind::Ping => Some(codec::Packet::PingResponse),
-                ControlResultKind::Subscribe(res) => {
+                ControlResultKind::Subscribe(mut res) => {
                     this.inner.inflight.borrow_mut().remove(&res.packet_id);
-                    Some(codec::Packet::SubscribeAck {
+                    if let Some(ref quota) = this.inner.quota {
+                        quota.subscribed(
+                            res.packet_id,
+                            res.codes.iter().map(|c| c != &codec::SubscribeReturnCode::Failure),
+                        );
+                    }
+                    // downgrade granted qos
+                    if let Some(max) = this.inner.max_qos {
+                        for code in &mut res.codes {
+                            if let codec::SubscribeReturnCode::Success(ref mut qos) = code {
+                                if u8::from(*qos) > u8::from(max) {
+                                    *qos = max;
+                                }
+                            }
+                        }
+                    }
+                    let ack = codec::Packet::SubscribeAck {
                         status: res.codes,
                         packet_id: res.packet_id,
-                    })
+                    };
+                    this.inner.ack_retransmits(res.packet_id, &ack);
+                    Some(ack)
                 }
                 ControlResultKind::Unsubscribe(res) => {
                     this.inner.inflight.borrow_mut().remove(&res.packet_id);
-                    Some(codec::Packet::UnsubscribeAck { packet_id: res.packet_id })
+                    let ack = codec::Packet::UnsubscribeAck { packet_id: res.packet_id };
+                    this.inner.ack_retransmits(res.packet_id, &ack);
+                    Some(ack)
                 }
                 ControlResultKind::Disconnect
                 | ControlResultKind::Closed
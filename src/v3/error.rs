@@ -1,6 +1,8 @@
 use derive_more::{Display, From};
 use ntex::util::Either;
 
+use crate::connect::TransportError;
+
 pub use crate::{error::*, v3::codec};
 
 /// Errors which can occur when attempting to handle mqtt client connection.
@@ -20,10 +22,51 @@ pub enum ClientError {
     Disconnected,
     /// Connect error
     #[display(fmt = "Connect error: {}", _0)]
+    #[from(ignore)]
     Connect(ntex::connect::ConnectError),
+    /// Address resolution failed
+    #[display(fmt = "Resolve error: {}", _0)]
+    #[from(ignore)]
+    Resolve(ntex::connect::ConnectError),
+    /// Transport connect timeout
+    #[display(fmt = "Connect timeout")]
+    ConnectTimeout,
+    /// Tls handshake failed
+    #[display(fmt = "Tls error: {}", _0)]
+    #[from(ignore)]
+    Tls(std::io::Error),
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Protocol(e) => Some(e),
+            ClientError::Connect(ntex::connect::ConnectError::Resolver(e))
+            | ClientError::Connect(ntex::connect::ConnectError::Io(e))
+            | ClientError::Resolve(ntex::connect::ConnectError::Resolver(e))
+            | ClientError::Resolve(ntex::connect::ConnectError::Io(e))
+            | ClientError::Tls(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ntex::connect::ConnectError> for ClientError {
+    fn from(err: ntex::connect::ConnectError) -> Self {
+        TransportError::from(err).into()
+    }
 }
 
-impl std::error::Error for ClientError {}
+impl From<TransportError> for ClientError {
+    fn from(err: TransportError) -> Self {
+        match err {
+            TransportError::Resolve(e) => ClientError::Resolve(e),
+            TransportError::Timeout => ClientError::ConnectTimeout,
+            TransportError::Tls(e) => ClientError::Tls(e),
+            TransportError::Connect(e) => ClientError::Connect(e),
+        }
+    }
+}
 
 impl From<Either<EncodeError, std::io::Error>> for ClientError {
     fn from(err: Either<EncodeError, std::io::Error>) -> Self {
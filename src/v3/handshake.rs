@@ -1,9 +1,41 @@
-use std::{fmt, rc::Rc};
+use std::{fmt, future::Future, rc::Rc, time::Duration};
+
+use ntex::codec::FramedParts;
+
+use crate::{client_id::ClientIdPolicy, utils::duration_to_secs, CodecExtension};
 
 use super::codec as mqtt;
 use super::shared::MqttShared;
 use super::sink::MqttSink;
 
+/// Connect packet filter
+pub(crate) type ConnectFilter<Io> =
+    Rc<dyn Fn(&mut mqtt::Connect, &Io) -> Result<(), mqtt::ConnectAckReason>>;
+
+/// Chain client id policy and connect filter, client id is checked first
+pub(crate) fn chain_filter<Io: 'static>(
+    filter: Option<ConnectFilter<Io>>,
+    policy: Option<ClientIdPolicy>,
+) -> Option<ConnectFilter<Io>> {
+    let policy = match policy {
+        Some(policy) => policy,
+        None => return filter,
+    };
+    Some(Rc::new(move |pkt: &mut mqtt::Connect, io: &Io| {
+        match policy(&pkt.client_id) {
+            Some(id) => pkt.client_id = id,
+            None => {
+                log::trace!("Client id is rejected by policy: {:?}", pkt.client_id);
+                return Err(mqtt::ConnectAckReason::IdentifierRejected);
+            }
+        }
+        match filter {
+            Some(ref filter) => filter(pkt, io),
+            None => Ok(()),
+        }
+    }))
+}
+
 /// Connect message
 pub struct Handshake<Io> {
     io: Io,
@@ -29,6 +61,14 @@ impl<Io> Handshake<Io> {
         &mut self.io
     }
 
+    /// Apply connect filter, filter could modify connect packet
+    pub(crate) fn filter(
+        &mut self,
+        f: &ConnectFilter<Io>,
+    ) -> Result<(), mqtt::ConnectAckReason> {
+        f(&mut self.pkt, &self.io)
+    }
+
     /// Returns mqtt server sink
     pub fn sink(&self) -> MqttSink {
         MqttSink::new(self.shared.clone())
@@ -45,10 +85,60 @@ impl<Io> Handshake<Io> {
             read_hw: 4 * 1024,
             write_hw: 4 * 1024,
             keepalive: 30,
+            upgrade: None,
+            extension: None,
             return_code: mqtt::ConnectAckReason::ConnectionAccepted,
         }
     }
 
+    /// Take ownership of connection transport.
+    ///
+    /// Mqtt processing stops without sending `CONNACK` packet, transport parts
+    /// with already received but not decoded data get passed to provided function
+    /// and returned future is spawned. Could be used for handing connection
+    /// to different protocol or for transport upgrades.
+    pub fn upgrade<St, F, R>(self, f: F) -> HandshakeAck<Io, St>
+    where
+        F: FnOnce(FramedParts<Io, mqtt::Codec>) -> R + 'static,
+        R: Future<Output = ()> + 'static,
+    {
+        HandshakeAck {
+            io: self.io,
+            shared: self.shared,
+            session: None,
+            session_present: false,
+            lw: 256,
+            read_hw: 4 * 1024,
+            write_hw: 4 * 1024,
+            keepalive: 30,
+            extension: None,
+            upgrade: Some(Box::new(move |parts| {
+                ntex::rt::spawn(f(parts));
+            })),
+            return_code: mqtt::ConnectAckReason::ServiceUnavailable,
+        }
+    }
+
+    /// Create connect ack object with failed return code
+    pub(crate) fn failed<St>(
+        self,
+        return_code: mqtt::ConnectAckReason,
+    ) -> HandshakeAck<Io, St> {
+        HandshakeAck {
+            io: self.io,
+            shared: self.shared,
+            session: None,
+            session_present: false,
+            lw: 256,
+            read_hw: 4 * 1024,
+            write_hw: 4 * 1024,
+            keepalive: 30,
+            upgrade: None,
+            extension: None,
+            return_code,
+        }
+    }
+
     /// Create connect ack object with `identifier rejected` return code
     pub fn identifier_rejected<St>(self) -> HandshakeAck<Io, St> {
         HandshakeAck {
@@ -60,6 +150,8 @@ impl<Io> Handshake<Io> {
             read_hw: 4 * 1024,
             write_hw: 4 * 1024,
             keepalive: 30,
+            upgrade: None,
+            extension: None,
             return_code: mqtt::ConnectAckReason::IdentifierRejected,
         }
     }
@@ -75,6 +167,8 @@ impl<Io> Handshake<Io> {
             read_hw: 4 * 1024,
             write_hw: 4 * 1024,
             keepalive: 30,
+            upgrade: None,
+            extension: None,
             return_code: mqtt::ConnectAckReason::BadUserNameOrPassword,
         }
     }
@@ -90,6 +184,8 @@ impl<Io> Handshake<Io> {
             read_hw: 4 * 1024,
             write_hw: 4 * 1024,
             keepalive: 30,
+            upgrade: None,
+            extension: None,
             return_code: mqtt::ConnectAckReason::NotAuthorized,
         }
     }
@@ -105,6 +201,8 @@ impl<Io> Handshake<Io> {
             read_hw: 4 * 1024,
             write_hw: 4 * 1024,
             keepalive: 30,
+            upgrade: None,
+            extension: None,
             return_code: mqtt::ConnectAckReason::ServiceUnavailable,
         }
     }
@@ -127,14 +225,17 @@ pub struct HandshakeAck<Io, St> {
     pub(crate) lw: u16,
     pub(crate) read_hw: u16,
     pub(crate) write_hw: u16,
+    pub(crate) upgrade: Option<Box<dyn FnOnce(FramedParts<Io, mqtt::Codec>)>>,
+    pub(crate) extension: Option<Box<dyn CodecExtension<mqtt::Packet>>>,
 }
 
 impl<Io, St> HandshakeAck<Io, St> {
-    /// Set idle time-out for the connection in seconds
+    /// Set idle time-out for the connection
     ///
+    /// Time-out is rounded up to whole seconds.
     /// By default idle time-out is set to 30 seconds.
-    pub fn idle_timeout(mut self, timeout: u16) -> Self {
-        self.keepalive = timeout;
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.keepalive = duration_to_secs(timeout);
         self
     }
 
@@ -155,6 +256,17 @@ impl<Io, St> HandshakeAck<Io, St> {
         self
     }
 
+    /// Set codec extension for the connection
+    ///
+    /// Extension is applied to all packets after `CONNACK` is sent.
+    pub fn codec_extension<T>(mut self, ext: T) -> Self
+    where
+        T: CodecExtension<mqtt::Packet> + 'static,
+    {
+        self.extension = Some(Box::new(ext));
+        self
+    }
+
     #[doc(hidden)]
     #[deprecated(since = "0.6.3")]
     /// Set buffer low watermark size
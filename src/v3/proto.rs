@@ -0,0 +1,585 @@
+//! Sans-IO client protocol state machine
+//!
+//! `Connection` implements mqtt v3.1.1 client protocol logic without doing
+//! any io, so it can be driven by any runtime. Bytes received from the
+//! transport are passed to `Connection::receive()`, protocol events are
+//! available via `Connection::poll_event()` and bytes that must be sent to
+//! the server via `Connection::transmit()`. Connection never reads system
+//! clock, current time is provided by caller.
+//!
+//! ```rust
+//! use std::time::Instant;
+//! use ntex_bytes::Bytes;
+//! use ntex_mqtt::v3::{codec, proto::{Connection, Event}};
+//!
+//! let mut conn = Connection::new(codec::Connect::default().client_id("client"));
+//! // CONNECT packet
+//! let data = conn.transmit(Instant::now());
+//! assert!(!data.is_empty());
+//!
+//! // CONNACK from server
+//! conn.receive(Instant::now(), &[0x20, 0x02, 0x00, 0x00]);
+//! assert_eq!(conn.poll_event().unwrap(), Some(Event::Connected { session_present: false }));
+//!
+//! conn.publish("topic", Bytes::from_static(b"data"), codec::QoS::AtMostOnce).unwrap();
+//! assert!(!conn.transmit(Instant::now()).is_empty());
+//! ```
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::{num::NonZeroU16, time::Duration, time::Instant};
+
+use ntex_bytes::{ByteString, Bytes, BytesMut};
+use ntex_codec::{Decoder, Encoder};
+
+use super::codec::{self, Codec, Packet, QoS};
+use crate::error::{EncodeError, ProtocolError, SendPacketError};
+
+/// Protocol events
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// Server accepted connection
+    Connected { session_present: bool },
+    /// Server refused connection, connection is closed
+    Refused(codec::ConnectAckReason),
+    /// Publish from server
+    ///
+    /// Acknowledgement packets are generated automatically.
+    Publish(codec::Publish),
+    /// Publish with QoS 1 or 2 is acknowledged by server
+    PublishAck(NonZeroU16),
+    /// Subscribe is acknowledged by server
+    SubscribeAck { packet_id: NonZeroU16, status: Vec<codec::SubscribeReturnCode> },
+    /// Unsubscribe is acknowledged by server
+    UnsubscribeAck(NonZeroU16),
+    /// Connection is closed
+    Disconnected,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Inflight {
+    Publish,
+    Subscribe,
+    Unsubscribe,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    Connecting,
+    Connected,
+    Closed,
+}
+
+/// Sans-IO mqtt v3.1.1 client connection
+pub struct Connection {
+    codec: Codec,
+    state: State,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    events: VecDeque<Event>,
+    next_id: u16,
+    inflight: HashMap<u16, Inflight>,
+    pending_release: HashSet<u16>,
+    received: HashSet<u16>,
+    keep_alive: Option<Duration>,
+    last_received: Option<Instant>,
+    last_sent: Option<Instant>,
+    ping_sent: Option<Instant>,
+}
+
+impl Connection {
+    /// Create connection, `CONNECT` packet is queued for transmit
+    pub fn new(connect: codec::Connect) -> Self {
+        let keep_alive = if connect.keep_alive == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(connect.keep_alive as u64))
+        };
+        let mut conn = Connection {
+            keep_alive,
+            codec: Codec::new(),
+            state: State::Connecting,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            events: VecDeque::new(),
+            next_id: 0,
+            inflight: HashMap::new(),
+            pending_release: HashSet::new(),
+            received: HashSet::new(),
+            last_received: None,
+            last_sent: None,
+            ping_sent: None,
+        };
+        // connect packet is always encodable
+        let _ = conn.send(Packet::Connect(connect));
+        conn
+    }
+
+    /// Set max inbound packet size
+    pub fn max_packet_size(self, size: u32) -> Self {
+        self.codec.set_max_size(size);
+        self
+    }
+
+    /// Check if connection is acknowledged by server
+    pub fn is_connected(&self) -> bool {
+        self.state == State::Connected
+    }
+
+    /// Check if connection is closed
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed
+    }
+
+    /// Number of outgoing packets waiting for acknowledgement
+    ///
+    /// Includes `SUBSCRIBE` and `UNSUBSCRIBE` packets.
+    pub fn inflight(&self) -> usize {
+        self.inflight.len() + self.pending_release.len()
+    }
+
+    /// Feed bytes received from transport at `now`
+    pub fn receive(&mut self, now: Instant, data: &[u8]) {
+        if self.state != State::Closed {
+            self.last_received = Some(now);
+            self.read_buf.extend_from_slice(data);
+        }
+    }
+
+    /// Transport is closed by peer
+    pub fn receive_eof(&mut self) {
+        if self.state != State::Closed {
+            self.close();
+        }
+    }
+
+    /// Get next protocol event
+    ///
+    /// Protocol error closes connection.
+    pub fn poll_event(&mut self) -> Result<Option<Event>, ProtocolError> {
+        loop {
+            if let Some(ev) = self.events.pop_front() {
+                return Ok(Some(ev));
+            }
+            if self.state == State::Closed {
+                return Ok(None);
+            }
+
+            match self.codec.decode(&mut self.read_buf) {
+                Ok(Some(pkt)) => {
+                    if let Err(e) = self.handle(pkt) {
+                        self.close();
+                        return Err(e);
+                    }
+                }
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    self.close();
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    /// Take bytes that must be written to transport at `now`
+    pub fn transmit(&mut self, now: Instant) -> Bytes {
+        if !self.write_buf.is_empty() {
+            self.last_sent = Some(now);
+        }
+        self.write_buf.split().freeze()
+    }
+
+    /// Check if there are bytes to write
+    pub fn has_transmit(&self) -> bool {
+        !self.write_buf.is_empty()
+    }
+
+    /// Publish message
+    ///
+    /// Returns packet id for QoS 1 and 2 messages, `PublishAck` event is
+    /// generated when server acknowledges message. Returns
+    /// `SendPacketError::PacketIdInUse` if all packet ids are in use.
+    pub fn publish<T>(
+        &mut self,
+        topic: T,
+        payload: Bytes,
+        qos: QoS,
+    ) -> Result<Option<NonZeroU16>, SendPacketError>
+    where
+        ByteString: From<T>,
+    {
+        let packet_id = if qos == QoS::AtMostOnce { None } else { Some(self.next_id()?) };
+        self.send(Packet::Publish(codec::Publish {
+            qos,
+            packet_id,
+            payload,
+            dup: false,
+            retain: false,
+            topic: topic.into(),
+        }))
+        .map_err(SendPacketError::Encode)?;
+        if let Some(id) = packet_id {
+            self.inflight.insert(id.get(), Inflight::Publish);
+        }
+        Ok(packet_id)
+    }
+
+    /// Subscribe to topic filters
+    pub fn subscribe(
+        &mut self,
+        topic_filters: Vec<(ByteString, QoS)>,
+    ) -> Result<NonZeroU16, SendPacketError> {
+        let packet_id = self.next_id()?;
+        self.send(Packet::Subscribe { packet_id, topic_filters })
+            .map_err(SendPacketError::Encode)?;
+        self.inflight.insert(packet_id.get(), Inflight::Subscribe);
+        Ok(packet_id)
+    }
+
+    /// Unsubscribe from topic filters
+    pub fn unsubscribe(
+        &mut self,
+        topic_filters: Vec<ByteString>,
+    ) -> Result<NonZeroU16, SendPacketError> {
+        let packet_id = self.next_id()?;
+        self.send(Packet::Unsubscribe { packet_id, topic_filters })
+            .map_err(SendPacketError::Encode)?;
+        self.inflight.insert(packet_id.get(), Inflight::Unsubscribe);
+        Ok(packet_id)
+    }
+
+    /// Send `DISCONNECT` packet and close connection
+    pub fn disconnect(&mut self) {
+        if self.state != State::Closed {
+            let _ = self.send(Packet::Disconnect);
+            self.close();
+        }
+    }
+
+    /// Time when `handle_timeout()` must be called
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        if self.state != State::Connected {
+            return None;
+        }
+        let keep_alive = self.keep_alive?;
+        match self.ping_sent {
+            Some(sent) => Some(sent + keep_alive),
+            None => self.last_sent.map(|t| t + keep_alive),
+        }
+    }
+
+    /// Handle keep-alive timer
+    ///
+    /// Sends `PINGREQ` if nothing was sent within keep-alive interval,
+    /// returns error if server does not respond in time.
+    pub fn handle_timeout(&mut self, now: Instant) -> Result<(), ProtocolError> {
+        if let Some(timeout) = self.poll_timeout() {
+            if now >= timeout {
+                if self.ping_sent.is_some() {
+                    self.close();
+                    return Err(ProtocolError::KeepAliveTimeout);
+                }
+                let _ = self.send(Packet::PingRequest);
+                self.ping_sent = Some(now);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, pkt: Packet) -> Result<(), ProtocolError> {
+        match (self.state, pkt) {
+            (State::Connecting, Packet::ConnectAck { session_present, return_code }) => {
+                if return_code == codec::ConnectAckReason::ConnectionAccepted {
+                    self.state = State::Connected;
+                    // keep-alive interval starts when connection is acknowledged
+                    self.last_sent = self.last_received;
+                    self.events.push_back(Event::Connected { session_present });
+                } else {
+                    self.events.push_back(Event::Refused(return_code));
+                    self.close();
+                }
+            }
+            (State::Connecting, pkt) => {
+                return Err(ProtocolError::Unexpected(
+                    pkt.packet_type(),
+                    "Expected CONNECT-ACK packet",
+                ))
+            }
+            (_, Packet::Publish(publish)) => match (publish.qos, publish.packet_id) {
+                (QoS::AtLeastOnce, Some(packet_id)) => {
+                    self.events.push_back(Event::Publish(publish));
+                    self.send(Packet::PublishAck { packet_id })?;
+                }
+                (QoS::ExactlyOnce, Some(packet_id)) => {
+                    // re-delivered publish is not reported twice
+                    if self.received.insert(packet_id.get()) {
+                        self.events.push_back(Event::Publish(publish));
+                    }
+                    self.send(Packet::PublishReceived { packet_id })?;
+                }
+                _ => self.events.push_back(Event::Publish(publish)),
+            },
+            (_, Packet::PublishRelease { packet_id }) => {
+                self.received.remove(&packet_id.get());
+                self.send(Packet::PublishComplete { packet_id })?;
+            }
+            (_, Packet::PublishAck { packet_id }) => {
+                self.ack(packet_id, Inflight::Publish)?;
+                self.events.push_back(Event::PublishAck(packet_id));
+            }
+            (_, Packet::PublishReceived { packet_id }) => {
+                if self.inflight.get(&packet_id.get()) == Some(&Inflight::Publish) {
+                    self.inflight.remove(&packet_id.get());
+                    self.pending_release.insert(packet_id.get());
+                } else if !self.pending_release.contains(&packet_id.get()) {
+                    return Err(ProtocolError::PacketIdMismatch);
+                }
+                self.send(Packet::PublishRelease { packet_id })?;
+            }
+            (_, Packet::PublishComplete { packet_id }) => {
+                if !self.pending_release.remove(&packet_id.get()) {
+                    return Err(ProtocolError::PacketIdMismatch);
+                }
+                self.events.push_back(Event::PublishAck(packet_id));
+            }
+            (_, Packet::SubscribeAck { packet_id, status }) => {
+                self.ack(packet_id, Inflight::Subscribe)?;
+                self.events.push_back(Event::SubscribeAck { packet_id, status })
+            }
+            (_, Packet::UnsubscribeAck { packet_id }) => {
+                self.ack(packet_id, Inflight::Unsubscribe)?;
+                self.events.push_back(Event::UnsubscribeAck(packet_id))
+            }
+            (_, Packet::PingResponse) => self.ping_sent = None,
+            (_, pkt) => {
+                return Err(ProtocolError::Unexpected(
+                    pkt.packet_type(),
+                    "Unexpected packet from server",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, pkt: Packet) -> Result<(), EncodeError> {
+        self.codec.encode(pkt, &mut self.write_buf)
+    }
+
+    fn ack(&mut self, packet_id: NonZeroU16, tp: Inflight) -> Result<(), ProtocolError> {
+        if self.inflight.get(&packet_id.get()) == Some(&tp) {
+            self.inflight.remove(&packet_id.get());
+            Ok(())
+        } else {
+            Err(ProtocolError::PacketIdMismatch)
+        }
+    }
+
+    fn next_id(&mut self) -> Result<NonZeroU16, SendPacketError> {
+        let mut idx = self.next_id;
+        for _ in 0..u16::MAX {
+            idx = if idx == u16::MAX { 1 } else { idx + 1 };
+            if !self.inflight.contains_key(&idx) && !self.pending_release.contains(&idx) {
+                self.next_id = idx;
+                return Ok(NonZeroU16::new(idx).unwrap());
+            }
+        }
+        Err(SendPacketError::PacketIdInUse(idx))
+    }
+
+    fn close(&mut self) {
+        self.state = State::Closed;
+        self.read_buf.clear();
+        self.events.push_back(Event::Disconnected);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(pkt: Packet) -> BytesMut {
+        let mut buf = BytesMut::new();
+        Codec::new().encode(pkt, &mut buf).unwrap();
+        buf
+    }
+
+    fn decode(data: Bytes) -> Vec<Packet> {
+        let codec = Codec::new();
+        let mut buf = BytesMut::from(&data[..]);
+        let mut pkts = Vec::new();
+        while let Some(pkt) = codec.decode(&mut buf).unwrap() {
+            pkts.push(pkt);
+        }
+        pkts
+    }
+
+    fn connected(now: Instant) -> Connection {
+        let mut conn = Connection::new(codec::Connect::default().client_id("test"));
+        assert!(std::matches!(decode(conn.transmit(now))[..], [Packet::Connect(_)]));
+        conn.receive(
+            now,
+            &encode(Packet::ConnectAck {
+                session_present: true,
+                return_code: codec::ConnectAckReason::ConnectionAccepted,
+            }),
+        );
+        assert_eq!(
+            conn.poll_event().unwrap(),
+            Some(Event::Connected { session_present: true })
+        );
+        assert!(conn.is_connected());
+        conn
+    }
+
+    #[test]
+    fn test_refused() {
+        let now = Instant::now();
+        let mut conn = Connection::new(codec::Connect::default());
+        conn.receive(
+            now,
+            &encode(Packet::ConnectAck {
+                session_present: false,
+                return_code: codec::ConnectAckReason::NotAuthorized,
+            }),
+        );
+        assert_eq!(
+            conn.poll_event().unwrap(),
+            Some(Event::Refused(codec::ConnectAckReason::NotAuthorized))
+        );
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::Disconnected));
+        assert_eq!(conn.poll_event().unwrap(), None);
+        assert!(conn.is_closed());
+
+        let mut conn = Connection::new(codec::Connect::default());
+        conn.receive(now, &encode(Packet::PingResponse));
+        assert!(conn.poll_event().is_err());
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn test_publish() {
+        let now = Instant::now();
+        let mut conn = connected(now);
+        let id =
+            conn.publish("t", Bytes::from_static(b"1"), QoS::AtLeastOnce).unwrap().unwrap();
+        let id2 =
+            conn.publish("t", Bytes::from_static(b"2"), QoS::ExactlyOnce).unwrap().unwrap();
+        assert_ne!(id, id2);
+        assert_eq!(decode(conn.transmit(now)).len(), 2);
+        assert_eq!(conn.inflight(), 2);
+
+        conn.receive(now, &encode(Packet::PublishAck { packet_id: id }));
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::PublishAck(id)));
+
+        conn.receive(now, &encode(Packet::PublishReceived { packet_id: id2 }));
+        assert_eq!(conn.poll_event().unwrap(), None);
+        assert_eq!(decode(conn.transmit(now)), vec![Packet::PublishRelease { packet_id: id2 }]);
+        conn.receive(now, &encode(Packet::PublishComplete { packet_id: id2 }));
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::PublishAck(id2)));
+        assert_eq!(conn.inflight(), 0);
+
+        // unknown packet id
+        conn.receive(now, &encode(Packet::PublishAck { packet_id: id }));
+        assert!(std::matches!(conn.poll_event(), Err(ProtocolError::PacketIdMismatch)));
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::Disconnected));
+    }
+
+    #[test]
+    fn test_inbound_publish() {
+        let now = Instant::now();
+        let mut conn = connected(now);
+        let packet_id = NonZeroU16::new(7).unwrap();
+        let publish = codec::Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::ExactlyOnce,
+            topic: ByteString::from_static("t"),
+            packet_id: Some(packet_id),
+            payload: Bytes::from_static(b"data"),
+        };
+        conn.receive(now, &encode(Packet::Publish(publish.clone())));
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::Publish(publish.clone())));
+
+        // re-delivery is acknowledged but not reported
+        conn.receive(now, &encode(Packet::Publish(codec::Publish { dup: true, ..publish })));
+        assert_eq!(conn.poll_event().unwrap(), None);
+        conn.receive(now, &encode(Packet::PublishRelease { packet_id }));
+        assert_eq!(conn.poll_event().unwrap(), None);
+        assert_eq!(
+            decode(conn.transmit(now)),
+            vec![
+                Packet::PublishReceived { packet_id },
+                Packet::PublishReceived { packet_id },
+                Packet::PublishComplete { packet_id }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_packet_ids() {
+        let now = Instant::now();
+        let mut conn = connected(now);
+        let id = conn.subscribe(vec![("t".into(), QoS::AtLeastOnce)]).unwrap();
+        let id2 =
+            conn.publish("t", Bytes::from_static(b"1"), QoS::AtLeastOnce).unwrap().unwrap();
+        assert_ne!(id, id2);
+        assert_eq!(conn.inflight(), 2);
+
+        // ack of other packet type
+        conn.receive(now, &encode(Packet::PublishAck { packet_id: id }));
+        assert!(std::matches!(conn.poll_event(), Err(ProtocolError::PacketIdMismatch)));
+
+        let mut conn = connected(now);
+        for _ in 0..u16::MAX {
+            conn.unsubscribe(vec!["t".into()]).unwrap();
+        }
+        conn.transmit(now);
+        assert_eq!(
+            conn.publish("t", Bytes::new(), QoS::AtLeastOnce),
+            Err(SendPacketError::PacketIdInUse(u16::MAX))
+        );
+        assert!(conn.publish("t", Bytes::new(), QoS::AtMostOnce).unwrap().is_none());
+
+        let packet_id = NonZeroU16::new(10).unwrap();
+        conn.receive(now, &encode(Packet::UnsubscribeAck { packet_id }));
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::UnsubscribeAck(packet_id)));
+        assert_eq!(conn.subscribe(vec![("t".into(), QoS::AtLeastOnce)]), Ok(packet_id));
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let now = Instant::now();
+        let mut conn = Connection::new(codec::Connect { keep_alive: 10, ..Default::default() });
+        conn.transmit(now);
+        assert_eq!(conn.poll_timeout(), None);
+
+        let now = now + Duration::from_secs(1);
+        conn.receive(
+            now,
+            &encode(Packet::ConnectAck {
+                session_present: false,
+                return_code: codec::ConnectAckReason::ConnectionAccepted,
+            }),
+        );
+        conn.poll_event().unwrap();
+        assert_eq!(conn.poll_timeout(), Some(now + Duration::from_secs(10)));
+
+        conn.handle_timeout(now + Duration::from_secs(9)).unwrap();
+        assert!(!conn.has_transmit());
+
+        let now = now + Duration::from_secs(10);
+        conn.handle_timeout(now).unwrap();
+        assert_eq!(decode(conn.transmit(now)), vec![Packet::PingRequest]);
+        conn.receive(now, &encode(Packet::PingResponse));
+        assert_eq!(conn.poll_event().unwrap(), None);
+        assert_eq!(conn.poll_timeout(), Some(now + Duration::from_secs(10)));
+
+        let now = now + Duration::from_secs(10);
+        conn.handle_timeout(now).unwrap();
+        assert_eq!(decode(conn.transmit(now)), vec![Packet::PingRequest]);
+        assert_eq!(conn.poll_timeout(), Some(now + Duration::from_secs(10)));
+        assert!(std::matches!(
+            conn.handle_timeout(now + Duration::from_secs(10)),
+            Err(ProtocolError::KeepAliveTimeout)
+        ));
+        assert!(conn.is_closed());
+    }
+}
@@ -1,21 +1,43 @@
-use std::{convert::TryFrom, mem, num::NonZeroU16};
+use std::{convert::TryFrom, mem, num::NonZeroU16, rc::Rc, time::Duration, time::Instant};
 
 use ntex::router::Path;
 use ntex::util::{ByteString, Bytes};
 use serde::de::DeserializeOwned;
 use serde_json::Error as JsonError;
 
+use crate::ack::{self, AckSlot};
+use crate::clock;
+use crate::timeout::TimeoutSlot;
 use crate::v3::codec;
 
 /// Publish message
 pub struct Publish {
     publish: codec::Publish,
     topic: Path<ByteString>,
+    duplicate: bool,
+    pub(crate) timeout: Option<TimeoutSlot>,
+    pub(crate) auto_ack: Option<AckSlot>,
 }
 
 impl Publish {
     pub(crate) fn new(publish: codec::Publish) -> Self {
-        Self { topic: Path::new(publish.topic.clone()), publish }
+        Self {
+            topic: Path::new(publish.topic.clone()),
+            publish,
+            duplicate: false,
+            timeout: None,
+            auto_ack: None,
+        }
+    }
+
+    pub(crate) fn with_duplicate(mut self, duplicate: bool) -> Self {
+        self.duplicate = duplicate;
+        self
+    }
+
+    pub(crate) fn with_auto_ack(mut self, ack: Option<AckSlot>) -> Self {
+        self.auto_ack = ack;
+        self
     }
 
     #[inline]
@@ -24,6 +46,34 @@ impl Publish {
         self.publish.dup
     }
 
+    #[inline]
+    /// packet is redelivery of already acknowledged publish.
+    ///
+    /// Detection is enabled by server's `dedup_window()` setting,
+    /// router does not call handlers for duplicates.
+    pub fn is_duplicate(&self) -> bool {
+        self.duplicate
+    }
+
+    /// Override publish handler timeout.
+    ///
+    /// Takes effect only if called before publish handler is invoked,
+    /// i.e. by router or by publish service's `call()`. Zero duration
+    /// disables timeout.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        if let Some(ref slot) = self.timeout {
+            slot.set(Some(timeout));
+        }
+    }
+
+    /// Acknowledge publish after publish service completes.
+    ///
+    /// Opts publish out of server's `auto_ack()`, takes effect only if called
+    /// by router or by publish service's `call()`.
+    pub fn set_manual_ack(&mut self) {
+        ack::set_manual(&self.auto_ack);
+    }
+
     #[inline]
     pub fn retain(&self) -> bool {
         self.publish.retain
@@ -93,3 +143,61 @@ impl std::fmt::Debug for Publish {
         self.publish.fmt(f)
     }
 }
+
+/// Publish handling metric, passed to server's publish hook
+#[derive(Debug)]
+pub struct PublishMetric<'a> {
+    /// Publish topic
+    pub topic: &'a ByteString,
+    /// Size of publish payload in bytes
+    pub payload_size: usize,
+    pub qos: codec::QoS,
+    /// Time spent in publish handler
+    pub duration: Duration,
+    /// Publish handler completed successfully, QoS 1 publish is acknowledged
+    pub success: bool,
+}
+
+pub(crate) type PublishHook = Rc<dyn Fn(&PublishMetric<'_>)>;
+
+/// Publish handling failure, passed to server's dead letter hook
+#[derive(Debug)]
+pub enum PublishFailure<'a, E> {
+    /// Publish service error
+    Error(&'a E),
+    /// Publish service did not complete within publish timeout
+    Timeout,
+}
+
+pub(crate) type DeadLetterHook<E> = Rc<dyn Fn(codec::Publish, PublishFailure<'_, E>)>;
+
+/// Publish in progress, reported to publish hook on completion
+pub(crate) struct PublishTrace {
+    hook: PublishHook,
+    topic: ByteString,
+    payload_size: usize,
+    qos: codec::QoS,
+    start: Instant,
+}
+
+impl PublishTrace {
+    pub(crate) fn new(hook: &PublishHook, publish: &codec::Publish) -> Self {
+        PublishTrace {
+            hook: hook.clone(),
+            topic: publish.topic.clone(),
+            payload_size: publish.payload.len(),
+            qos: publish.qos,
+            start: clock::now(),
+        }
+    }
+
+    pub(crate) fn finish(&self, success: bool) {
+        (*self.hook)(&PublishMetric {
+            topic: &self.topic,
+            payload_size: self.payload_size,
+            qos: self.qos,
+            duration: clock::elapsed(self.start),
+            success,
+        })
+    }
+}
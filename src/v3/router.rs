@@ -1,20 +1,40 @@
 use std::task::{Context, Poll};
-use std::{future::Future, pin::Pin, rc::Rc};
+use std::{future::Future, pin::Pin, rc::Rc, time::Duration};
 
 use ntex::router::{IntoPattern, RouterBuilder};
 use ntex::service::boxed::{self, BoxService, BoxServiceFactory};
 use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
+use ntex::util::Ready;
 
 use super::publish::Publish;
 
 type Handler<S, E> = BoxServiceFactory<S, Publish, (), E, E>;
 type HandlerService<E> = BoxService<Publish, (), E>;
 
+/// Per-resource publish settings
+#[derive(Copy, Clone, Default)]
+struct ResourceOptions {
+    timeout: Option<Duration>,
+    manual_ack: bool,
+}
+
+impl ResourceOptions {
+    fn apply(&self, req: &mut Publish) {
+        if let Some(timeout) = self.timeout {
+            req.set_timeout(timeout);
+        }
+        if self.manual_ack {
+            req.set_manual_ack();
+        }
+    }
+}
+
 /// Router - structure that follows the builder pattern
 /// for building publish packet router instances for mqtt server.
 pub struct Router<S, Err> {
     router: RouterBuilder<usize>,
     handlers: Vec<Handler<S, Err>>,
+    options: Vec<ResourceOptions>,
     default: Handler<S, Err>,
 }
 
@@ -40,6 +60,7 @@ where
         Router {
             router: ntex::router::Router::build(),
             handlers: Vec::new(),
+            options: Vec::new(),
             default: boxed::factory(default_service.into_factory()),
         }
     }
@@ -54,8 +75,49 @@ where
     {
         self.router.path(address, self.handlers.len());
         self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        self.options.push(ResourceOptions::default());
         self
     }
+
+    /// Configure mqtt resource for a specific topic with publish handler timeout.
+    ///
+    /// Timeout overrides server's publish timeout for the resource.
+    pub fn resource_with_timeout<T, F, U>(
+        self,
+        address: T,
+        timeout: Duration,
+        service: F,
+    ) -> Self
+    where
+        T: IntoPattern,
+        F: IntoServiceFactory<U>,
+        U: ServiceFactory<Config = S, Request = Publish, Response = (), Error = Err> + 'static,
+        Err: From<U::InitError>,
+    {
+        let mut slf = self.resource(address, service);
+        if let Some(item) = slf.options.last_mut() {
+            item.timeout = Some(timeout);
+        }
+        slf
+    }
+
+    /// Configure mqtt resource for a specific topic that acknowledges
+    /// publishes after resource handler completes.
+    ///
+    /// Resource opts out of server's `auto_ack()` setting.
+    pub fn resource_with_manual_ack<T, F, U>(self, address: T, service: F) -> Self
+    where
+        T: IntoPattern,
+        F: IntoServiceFactory<U>,
+        U: ServiceFactory<Config = S, Request = Publish, Response = (), Error = Err> + 'static,
+        Err: From<U::InitError>,
+    {
+        let mut slf = self.resource(address, service);
+        if let Some(item) = slf.options.last_mut() {
+            item.manual_ack = true;
+        }
+        slf
+    }
 }
 
 impl<S, Err> IntoServiceFactory<RouterFactory<S, Err>> for Router<S, Err>
@@ -67,6 +129,7 @@ where
         RouterFactory {
             router: Rc::new(self.router.finish()),
             handlers: self.handlers,
+            options: Rc::new(self.options),
             default: self.default,
         }
     }
@@ -75,6 +138,7 @@ where
 pub struct RouterFactory<S, Err> {
     router: Rc<ntex::router::Router<usize>>,
     handlers: Vec<Handler<S, Err>>,
+    options: Rc<Vec<ResourceOptions>>,
     default: Handler<S, Err>,
 }
 
@@ -96,6 +160,7 @@ where
             self.handlers.iter().map(|h| h.new_service(session.clone())).collect();
         let default_fut = self.default.new_service(session);
         let router = self.router.clone();
+        let options = self.options.clone();
 
         Box::pin(async move {
             let mut handlers = Vec::new();
@@ -103,7 +168,7 @@ where
                 handlers.push(handler.await?);
             }
 
-            Ok(RouterService { router, handlers, default: default_fut.await? })
+            Ok(RouterService { router, handlers, options, default: default_fut.await? })
         })
     }
 }
@@ -111,10 +176,11 @@ where
 pub struct RouterService<Err> {
     router: Rc<ntex::router::Router<usize>>,
     handlers: Vec<HandlerService<Err>>,
+    options: Rc<Vec<ResourceOptions>>,
     default: HandlerService<Err>,
 }
 
-impl<Err> Service for RouterService<Err> {
+impl<Err: 'static> Service for RouterService<Err> {
     type Request = Publish;
     type Response = ();
     type Error = Err;
@@ -140,7 +206,13 @@ impl<Err> Service for RouterService<Err> {
     }
 
     fn call(&self, mut req: Self::Request) -> Self::Future {
+        // publish is already handled
+        if req.is_duplicate() {
+            return Box::pin(Ready::Ok(()));
+        }
+
         if let Some((idx, _info)) = self.router.recognize(req.topic_mut()) {
+            self.options[*idx].apply(&mut req);
             self.handlers[*idx].call(req)
         } else {
             self.default.call(req)
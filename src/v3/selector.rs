@@ -34,7 +34,7 @@ type Server<Io, Err> =
 pub struct Selector<Io, Err, InitErr> {
     servers: Vec<ServerFactory<Io, Err, InitErr>>,
     max_size: u32,
-    handshake_timeout: u16,
+    handshake_timeout: time::Duration,
     pool: Rc<MqttSinkPool>,
     _t: marker::PhantomData<(Io, Err, InitErr)>,
 }
@@ -45,7 +45,7 @@ impl<Io, Err, InitErr> Selector<Io, Err, InitErr> {
         Selector {
             servers: Vec::new(),
             max_size: 0,
-            handshake_timeout: 0,
+            handshake_timeout: time::Duration::ZERO,
             pool: Default::default(),
             _t: marker::PhantomData,
         }
@@ -58,11 +58,11 @@ where
     Err: 'static,
     InitErr: 'static,
 {
-    /// Set handshake timeout in millis.
+    /// Set handshake timeout.
     ///
     /// Handshake includes `connect` packet and response `connect-ack`.
-    /// By default handshake timeuot is disabled.
-    pub fn handshake_timeout(mut self, timeout: u16) -> Self {
+    /// By default handshake timeout is disabled.
+    pub fn handshake_timeout(mut self, timeout: time::Duration) -> Self {
         self.handshake_timeout = timeout;
         self
     }
@@ -162,7 +162,7 @@ where
 pub struct SelectorService<Io, Err> {
     servers: Rc<Vec<Server<Io, Err>>>,
     max_size: u32,
-    handshake_timeout: u16,
+    handshake_timeout: time::Duration,
     pool: Rc<MqttSinkPool>,
 }
 
@@ -212,8 +212,8 @@ where
             16,
             self.pool.clone(),
         ));
-        let delay = if self.handshake_timeout > 0 {
-            Some(Box::pin(sleep(time::Duration::from_secs(self.handshake_timeout as u64))))
+        let delay = if self.handshake_timeout != time::Duration::ZERO {
+            Some(Box::pin(sleep(self.handshake_timeout)))
         } else {
             None
         };
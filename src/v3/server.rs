@@ -1,21 +1,39 @@
 use std::task::{Context, Poll};
-use std::{fmt, future::Future, marker::PhantomData, pin::Pin, rc::Rc, time::Duration};
+use std::{
+    cell::Cell, fmt, future::Future, marker::PhantomData, pin::Pin, rc::Rc, time::Duration,
+};
 
+use ntex::channel::mpsc;
 use ntex::codec::{AsyncRead, AsyncWrite, Decoder, Encoder};
 use ntex::rt::time::Sleep;
 use ntex::service::{apply_fn_factory, IntoServiceFactory, Service, ServiceFactory};
-use ntex::util::{timeout::Timeout, timeout::TimeoutError, Either, Ready};
+use ntex::util::timeout::{Timeout, TimeoutError, TimeoutService};
+use ntex::util::{ByteString, Either, Ready};
 
-use crate::error::{MqttError, ProtocolError};
-use crate::io::{DispatchItem, Dispatcher, State, Timer};
+use crate::backlog::{SlowConsumerPolicy, Watermark};
+use crate::budget::DecodeStats;
+use crate::client_id::ClientIdPolicy;
+use crate::config::{ConfigHandle, ListenerConfig};
+use crate::dedup::DedupWindow;
+use crate::error::{DecodeError, MqttError, ProtocolError};
+use crate::io::{
+    BufferLimits, DispatchItem, Dispatcher, FlushWaiters, ShutdownStatus, State, Timer,
+};
+use crate::quota::SubscriptionLimits;
+use crate::rewrite::TopicRewrite;
 use crate::service::{FramedService, FramedService2};
+use crate::types::{QoS, RetainPolicy};
+use crate::utils::duration_to_millis;
 
+use super::channel::{ControlChannel, ControlRequest};
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
-use super::handshake::{Handshake, HandshakeAck};
+use super::dispatcher::{factory, DispatcherConfig, TenantFn};
+use super::handshake::{chain_filter, ConnectFilter, Handshake, HandshakeAck};
+use super::publish::{DeadLetterHook, PublishFailure, PublishHook, PublishMetric};
 use super::selector::SelectItem;
 use super::shared::{MqttShared, MqttSinkPool};
-use super::{codec as mqtt, dispatcher::factory, MqttSink, Publish, Session};
+use super::{codec as mqtt, MqttSink, Publish, Session, SinkRegistry};
 
 /// Mqtt v3.1.1 Server
 pub struct MqttServer<Io, St, C: ServiceFactory, Cn: ServiceFactory, P: ServiceFactory> {
@@ -23,9 +41,34 @@ pub struct MqttServer<Io, St, C: ServiceFactory, Cn: ServiceFactory, P: ServiceF
     control: Cn,
     publish: P,
     max_size: u32,
+    max_topic_length: u16,
+    max_topic_levels: u16,
+    max_client_id_length: u16,
+    capture_malformed: usize,
+    decode_budget: (Duration, u32),
+    decode_stats: Option<DecodeStats>,
     inflight: usize,
-    handshake_timeout: u16,
+    dedup: Option<Rc<DedupWindow>>,
+    watermark: Option<Watermark>,
+    publish_hook: Option<PublishHook>,
+    ordered_lanes: usize,
+    dead_letter: Option<DeadLetterHook<C::Error>>,
+    publish_timeout: Duration,
+    registry: Option<SinkRegistry>,
+    subscription_limits: SubscriptionLimits,
+    retain_policy: RetainPolicy,
+    topic_rewrite: Option<Rc<TopicRewrite>>,
+    tenant: Option<TenantFn<St>>,
+    max_qos: Option<QoS>,
+    auto_ack: bool,
+    handshake_timeout: Duration,
     disconnect_timeout: u16,
+    shutdown_timeout: Duration,
+    buffer_limits: BufferLimits,
+    timer: Timer,
+    config: ConfigHandle,
+    connect_filter: Option<ConnectFilter<Io>>,
+    client_id_policy: Option<ClientIdPolicy>,
     pub(super) pool: Rc<MqttSinkPool>,
     _t: PhantomData<(Io, St)>,
 }
@@ -54,9 +97,34 @@ where
             control: DefaultControlService::default(),
             publish: DefaultPublishService::default(),
             max_size: 0,
+            max_topic_length: 0,
+            max_topic_levels: 0,
+            max_client_id_length: 0,
+            capture_malformed: 0,
+            decode_budget: (Duration::ZERO, 0),
+            decode_stats: None,
             inflight: 16,
-            handshake_timeout: 0,
+            dedup: None,
+            watermark: None,
+            publish_hook: None,
+            ordered_lanes: 0,
+            dead_letter: None,
+            publish_timeout: Duration::ZERO,
+            registry: None,
+            subscription_limits: SubscriptionLimits::default(),
+            retain_policy: RetainPolicy::Accept,
+            topic_rewrite: None,
+            tenant: None,
+            max_qos: None,
+            auto_ack: false,
+            handshake_timeout: Duration::ZERO,
             disconnect_timeout: 3000,
+            shutdown_timeout: Duration::ZERO,
+            buffer_limits: BufferLimits::default(),
+            timer: Timer::with(Duration::from_secs(1)),
+            config: ConfigHandle::default(),
+            connect_filter: None,
+            client_id_policy: None,
             pool: Default::default(),
             _t: PhantomData,
         }
@@ -79,25 +147,114 @@ where
         + From<P::InitError>
         + fmt::Debug,
 {
-    /// Set handshake timeout in millis.
+    /// Set handshake timeout.
     ///
     /// Handshake includes `connect` packet and response `connect-ack`.
-    /// By default handshake timeuot is disabled.
-    pub fn handshake_timeout(mut self, timeout: u16) -> Self {
+    /// By default handshake timeout is disabled.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
         self.handshake_timeout = timeout;
         self
     }
 
-    /// Set server connection disconnect timeout in milliseconds.
+    /// Set server connection disconnect timeout.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
-    /// within this time, the connection get dropped.
+    /// within this time, the connection get dropped. Timeout is rounded up to millisecond
+    /// precision and is limited to `u16::MAX` milliseconds (about 65 seconds), larger
+    /// values are clamped with a warning.
+    ///
+    /// Disconnect procedure flushes outstanding data, closes write side of the connection
+    /// and waits until peer closes connection. Result is reported by
+    /// `ControlMessage::Closed` message.
     ///
     /// To disable timeout set value to 0.
     ///
     /// By default disconnect timeout is set to 3 seconds.
-    pub fn disconnect_timeout(mut self, val: u16) -> Self {
-        self.disconnect_timeout = val;
+    pub fn disconnect_timeout(mut self, timeout: Duration) -> Self {
+        self.disconnect_timeout = duration_to_millis(timeout);
+        self
+    }
+
+    /// Set in-flight handlers shutdown timeout.
+    ///
+    /// Defines how long connection waits for in-flight publish and control
+    /// handlers to complete after connection has been closed. Handlers that
+    /// do not complete within this time get dropped without sending response.
+    ///
+    /// To disable timeout set value to 0. By default timeout is disabled.
+    pub fn inflight_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Set resolution of connection timers.
+    ///
+    /// Keep-alive and disconnect timeouts of all connections are checked
+    /// by shared timer with this granularity. Smaller values give more
+    /// precise timeouts at the cost of more frequent wakeups.
+    ///
+    /// By default resolution is set to 1 second.
+    pub fn timer_resolution(mut self, resolution: Duration) -> Self {
+        self.timer = Timer::with(resolution);
+        self
+    }
+
+    /// Use shared timer for server connections.
+    ///
+    /// Same timer could be used by multiple servers and user code, timer
+    /// provides low resolution timestamps via `Timer::now()`. Overrides
+    /// `timer_resolution()` setting.
+    pub fn timer(mut self, timer: Timer) -> Self {
+        self.timer = timer;
+        self
+    }
+
+    /// Use runtime configuration handle.
+    ///
+    /// Values set on the handle override server builder settings for
+    /// new connections.
+    pub fn config(mut self, config: ConfigHandle) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Set max size of connection's read buffer in bytes.
+    ///
+    /// Connection gets closed if size of received but not yet decoded data
+    /// exceeds the limit. If max size is set to `0`, size is unlimited.
+    /// By default max size is set to `0`
+    pub fn max_read_buf_size(mut self, size: usize) -> Self {
+        self.buffer_limits.max_read_buf = size;
+        self
+    }
+
+    /// Set per-connection memory budget in bytes.
+    ///
+    /// Budget covers read buffer, write buffer and queued handler responses.
+    /// Connection gets closed if budget is exceeded. If budget is set to `0`,
+    /// memory usage is unlimited. By default budget is set to `0`
+    pub fn memory_budget(mut self, size: usize) -> Self {
+        self.buffer_limits.memory_budget = size;
+        self
+    }
+
+    /// Set transport read idle timeout.
+    ///
+    /// Connection gets closed if no bytes are read from the transport
+    /// for the specified time, regardless of mqtt keep-alive. Timeout
+    /// set to `0` disables check. By default timeout is disabled.
+    pub fn read_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.buffer_limits.read_timeout = timeout;
+        self
+    }
+
+    /// Set transport write idle timeout.
+    ///
+    /// Connection gets closed if outbound data is pending but peer does not
+    /// accept any bytes for the specified time. Timeout set to `0` disables
+    /// check. By default timeout is disabled.
+    pub fn write_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.buffer_limits.write_timeout = timeout;
         self
     }
 
@@ -110,6 +267,145 @@ where
         self
     }
 
+    /// Set max length of topic names and topic filters in bytes.
+    ///
+    /// Packets with longer topics are treated as protocol violation.
+    /// If max length is set to `0`, length is unlimited.
+    /// By default max length is set to `0`
+    pub fn max_topic_length(mut self, len: u16) -> Self {
+        self.max_topic_length = len;
+        self
+    }
+
+    /// Set max number of topic levels for topic names and topic filters.
+    ///
+    /// If max levels is set to `0`, number of levels is unlimited.
+    /// By default max levels is set to `0`
+    pub fn max_topic_levels(mut self, levels: u16) -> Self {
+        self.max_topic_levels = levels;
+        self
+    }
+
+    /// Set max length of client id.
+    ///
+    /// Connections with longer client id get rejected with
+    /// `IdentifierRejected` return code.
+    /// If max length is set to `0`, length is unlimited.
+    /// By default max length is set to `0`
+    pub fn max_client_id_length(mut self, len: u16) -> Self {
+        self.max_client_id_length = len;
+        self
+    }
+
+    /// Set max number of topic filters in one `SUBSCRIBE` packet.
+    ///
+    /// Subscribe packets with more topic filters get rejected with
+    /// failure return code for each topic filter, control service is not called.
+    /// If max number is set to `0`, number of topic filters is unlimited.
+    /// By default max number is set to `0`
+    pub fn max_topic_filters(mut self, max: u16) -> Self {
+        self.subscription_limits.max_topic_filters = max;
+        self
+    }
+
+    /// Set max number of active subscriptions per session.
+    ///
+    /// Subscribe packets that would exceed the limit get rejected with
+    /// failure return code for each topic filter, control service is not called.
+    /// Subscriptions are counted per distinct topic filter, unsubscribe
+    /// releases them.
+    /// If max number is set to `0`, number of subscriptions is unlimited.
+    /// By default max number is set to `0`
+    pub fn max_subscriptions(mut self, max: u32) -> Self {
+        self.subscription_limits.max_subscriptions = max;
+        self
+    }
+
+    /// Set policy for retain flag of inbound publishes.
+    ///
+    /// With `RetainPolicy::Reject` retained publish closes connection.
+    /// By default retain flag is accepted
+    pub fn retain_policy(mut self, policy: RetainPolicy) -> Self {
+        self.retain_policy = policy;
+        self
+    }
+
+    /// Set topic rewrite rules.
+    ///
+    /// Rules are applied to topics of inbound publishes and to topic filters
+    /// of subscribe and unsubscribe packets before packets are passed to services.
+    pub fn topic_rewrite(mut self, rewrite: TopicRewrite) -> Self {
+        self.topic_rewrite = if rewrite.is_empty() { None } else { Some(Rc::new(rewrite)) };
+        self
+    }
+
+    /// Isolate tenants by topic namespace.
+    ///
+    /// Function derives tenant namespace from authenticated session, for example
+    /// from username or client certificate stored in session state. Namespace is
+    /// prepended to topics of inbound publishes and to subscription filters, and is
+    /// removed from topics of publishes sent by the session sink. Topics reserved
+    /// for server (starting with `$`) are rejected. Sessions without namespace
+    /// are not isolated. Namespace must be a single topic level, connection
+    /// with invalid namespace is closed.
+    pub fn tenant_isolation<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Session<St>) -> Option<ByteString> + 'static,
+    {
+        self.tenant = Some(Rc::new(f));
+        self
+    }
+
+    /// Set server max qos setting.
+    ///
+    /// Qos of subscriptions granted by control service is downgraded
+    /// to max qos. By default max qos is not set
+    pub fn max_qos(mut self, qos: QoS) -> Self {
+        self.max_qos = Some(qos);
+        self
+    }
+
+    /// Acknowledge QoS 1 publishes and answer ping requests in dispatcher.
+    ///
+    /// `PUBACK` is sent as soon as publish service is called, its result does
+    /// not affect acknowledgment. Publish service errors still close connection.
+    /// Router resources could opt out with `Router::resource_with_manual_ack()`.
+    /// `PINGREQ` is answered without calling control service.
+    /// By default auto-ack is disabled
+    pub fn auto_ack(mut self, enabled: bool) -> Self {
+        self.auto_ack = enabled;
+        self
+    }
+
+    /// Capture bytes of inbound packets that fail to decode.
+    ///
+    /// Fixed header and at most `size` bytes of malformed packet are logged
+    /// as hexdump with `warn` level. If size is set to `0`, capture is disabled.
+    /// By default capture is disabled
+    pub fn capture_malformed(mut self, size: usize) -> Self {
+        self.capture_malformed = size;
+        self
+    }
+
+    /// Set decode time budget for inbound packets.
+    ///
+    /// Connection gets closed if `max_violations` consecutive packets took
+    /// longer than `budget` to decode, this protects server from peers that
+    /// send packets that are expensive to parse. If budget is set to `0`,
+    /// check is disabled. By default check is disabled
+    pub fn decode_budget(mut self, budget: Duration, max_violations: u32) -> Self {
+        self.decode_budget = (budget, max_violations);
+        self
+    }
+
+    /// Collect inbound packet size histogram and decode time statistics.
+    ///
+    /// Statistics could be shared by several servers.
+    pub fn decode_stats(mut self, stats: DecodeStats) -> Self {
+        self.decode_stats = Some(stats);
+        self
+    }
+
     /// Number of in-flight concurrent messages.
     ///
     /// By default in-flight is set to 16 messages
@@ -118,6 +414,177 @@ where
         self
     }
 
+    /// Set size of inbound duplicate detection window.
+    ///
+    /// Server remembers packet ids of last `size` acknowledged QoS 1 publishes,
+    /// redelivered publish with `dup` flag and remembered client id and packet id
+    /// is marked as duplicate, see `Publish::is_duplicate()`. Window is shared
+    /// by all connections of a worker.
+    /// If size is set to `0`, detection is disabled.
+    /// By default detection is disabled.
+    pub fn dedup_window(mut self, size: u16) -> Self {
+        self.dedup = if size == 0 { None } else { Some(Rc::new(DedupWindow::new(size))) };
+        self
+    }
+
+    /// Set outbound queue watermark for slow consumers.
+    ///
+    /// Watermark is exceeded if size of outbound queue is greater than `max_size`
+    /// bytes or queue is not drained for `max_age`, `policy` is applied
+    /// on next publish to connection then. `0` disables corresponding check.
+    /// By default watermark is not set.
+    pub fn slow_consumer(
+        mut self,
+        max_size: usize,
+        max_age: Duration,
+        policy: SlowConsumerPolicy,
+    ) -> Self {
+        self.watermark = Some(Watermark { max_size, max_age, policy });
+        self
+    }
+
+    /// Set hook for publish handling metrics.
+    ///
+    /// Hook is called after each publish is handled by publish service, with
+    /// topic, payload size, handler duration and handler result.
+    pub fn publish_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&PublishMetric<'_>) + 'static,
+    {
+        self.publish_hook = Some(Rc::new(hook));
+        self
+    }
+
+    /// Serialize publish handling per topic.
+    ///
+    /// By default publish service is called for each received publish
+    /// packet as soon as it arrives, up to `inflight` publishes are handled
+    /// concurrently and could complete in any order. Acknowledgements are
+    /// always sent in order of received packets.
+    ///
+    /// With ordering enabled, topics are hashed to `lanes` ordered lanes.
+    /// Publishes of the same lane are handled one after another in order of
+    /// arrival, while publishes of different lanes are handled concurrently.
+    /// Unrelated topics could share a lane, larger number of lanes reduces
+    /// such contention. `0` disables ordering. By default ordering is disabled.
+    pub fn ordered_lanes(mut self, lanes: usize) -> Self {
+        self.ordered_lanes = lanes;
+        self
+    }
+
+    /// Set dead letter hook for failed publishes.
+    ///
+    /// Hook is called with original publish packet if publish service fails
+    /// to handle publish or does not complete within publish timeout, so the
+    /// message could be persisted or republished elsewhere.
+    pub fn dead_letter<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(mqtt::Publish, PublishFailure<'_, C::Error>) + 'static,
+    {
+        self.dead_letter = Some(Rc::new(hook));
+        self
+    }
+
+    /// Set publish handler timeout.
+    ///
+    /// If publish service does not complete within timeout, handler future
+    /// is dropped. QoS 0 publish is discarded, for QoS 1 publish connection
+    /// is closed. Timeout could be overridden per resource with `Router` or
+    /// with `Publish::set_timeout()`.
+    /// By default timeout is disabled.
+    pub fn publish_timeout(mut self, timeout: Duration) -> Self {
+        self.publish_timeout = timeout;
+        self
+    }
+
+    /// Set registry of connected clients.
+    ///
+    /// Sink of the client is inserted to registry after successful handshake
+    /// and removed when connection is closed, so application could send
+    /// messages to specific connected clients.
+    /// By default clients are not registered.
+    pub fn sink_registry(mut self, registry: SinkRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Set connect packet filter.
+    ///
+    /// Filter is called with decoded `CONNECT` packet and connection io before
+    /// handshake service, so peer address could be checked against ban list,
+    /// i.e. with `TcpStream::peer_addr()`. If filter returns error, connection
+    /// is rejected with returned code and handshake service is not called.
+    pub fn connect_filter<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mqtt::Connect, &Io) -> Result<(), mqtt::ConnectAckReason> + 'static,
+    {
+        self.connect_filter = Some(Rc::new(move |pkt: &mut mqtt::Connect, io: &Io| f(pkt, io)));
+        self
+    }
+
+    /// Set client id policy.
+    ///
+    /// Policy is called before connect filter and handshake service, it returns
+    /// normalized client id or `None` to reject connection with `IdentifierRejected`
+    /// return code. Handshake service and session get normalized client id.
+    /// See [`client_id`](crate::client_id) module for predefined policies.
+    /// By default any client id accepted by codec is allowed.
+    pub fn client_id_policy<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ByteString) -> Option<ByteString> + 'static,
+    {
+        self.client_id_policy = Some(Rc::new(f));
+        self
+    }
+
+    fn codec_limits(&self) -> CodecLimits {
+        CodecLimits {
+            max_size: self.max_size,
+            max_topic_length: self.max_topic_length,
+            max_topic_levels: self.max_topic_levels,
+            max_client_id_length: self.max_client_id_length,
+            capture_malformed: self.capture_malformed,
+            decode_budget: self.decode_budget,
+            decode_stats: self.decode_stats.clone(),
+        }
+    }
+
+    fn dispatcher_config(&self) -> DispatcherConfig<St, C::Error> {
+        DispatcherConfig {
+            inflight: self.inflight,
+            handle: self.config.clone(),
+            dedup: self.dedup.clone(),
+            watermark: self.watermark,
+            hook: self.publish_hook.clone(),
+            ordered_lanes: self.ordered_lanes,
+            dead_letter: self.dead_letter.clone(),
+            publish_timeout: self.publish_timeout,
+            registry: self.registry.clone(),
+            subscription_limits: self.subscription_limits,
+            retain_policy: self.retain_policy,
+            topic_rewrite: self.topic_rewrite.clone(),
+            tenant: self.tenant.clone(),
+            max_qos: self.max_qos,
+            auto_ack: self.auto_ack,
+        }
+    }
+
+    /// Deliver control packets to a channel instead of a service
+    ///
+    /// Function is called for each connection with receiving end of the channel,
+    /// application consumes control requests at its own pace and responds to each
+    /// of them. Connection gets closed if request is dropped without response
+    /// or if channel is closed.
+    pub fn control_channel<F>(
+        self,
+        f: F,
+    ) -> MqttServer<Io, St, C, ControlChannel<St, C::Error>, P>
+    where
+        F: Fn(&Session<St>, mpsc::Receiver<ControlRequest>) + 'static,
+    {
+        self.control(ControlChannel::new(Rc::new(f)))
+    }
+
     /// Service to handle control packets
     ///
     /// All control packets are processed sequentially, max buffered
@@ -137,9 +604,34 @@ where
             publish: self.publish,
             control: service.into_factory(),
             max_size: self.max_size,
+            max_topic_length: self.max_topic_length,
+            max_topic_levels: self.max_topic_levels,
+            max_client_id_length: self.max_client_id_length,
+            capture_malformed: self.capture_malformed,
+            decode_budget: self.decode_budget,
+            decode_stats: self.decode_stats,
             inflight: self.inflight,
+            dedup: self.dedup,
+            watermark: self.watermark,
+            publish_hook: self.publish_hook,
+            ordered_lanes: self.ordered_lanes,
+            dead_letter: self.dead_letter,
+            publish_timeout: self.publish_timeout,
+            registry: self.registry,
+            subscription_limits: self.subscription_limits,
+            retain_policy: self.retain_policy,
+            topic_rewrite: self.topic_rewrite,
+            tenant: self.tenant,
+            max_qos: self.max_qos,
+            auto_ack: self.auto_ack,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            shutdown_timeout: self.shutdown_timeout,
+            buffer_limits: self.buffer_limits,
+            timer: self.timer,
+            config: self.config,
+            connect_filter: self.connect_filter,
+            client_id_policy: self.client_id_policy,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -157,9 +649,34 @@ where
             publish: publish.into_factory(),
             control: self.control,
             max_size: self.max_size,
+            max_topic_length: self.max_topic_length,
+            max_topic_levels: self.max_topic_levels,
+            max_client_id_length: self.max_client_id_length,
+            capture_malformed: self.capture_malformed,
+            decode_budget: self.decode_budget,
+            decode_stats: self.decode_stats,
             inflight: self.inflight,
+            dedup: self.dedup,
+            watermark: self.watermark,
+            publish_hook: self.publish_hook,
+            ordered_lanes: self.ordered_lanes,
+            dead_letter: self.dead_letter,
+            publish_timeout: self.publish_timeout,
+            registry: self.registry,
+            subscription_limits: self.subscription_limits,
+            retain_policy: self.retain_policy,
+            topic_rewrite: self.topic_rewrite,
+            tenant: self.tenant,
+            max_qos: self.max_qos,
+            auto_ack: self.auto_ack,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            shutdown_timeout: self.shutdown_timeout,
+            buffer_limits: self.buffer_limits,
+            timer: self.timer,
+            config: self.config,
+            connect_filter: self.connect_filter,
+            client_id_policy: self.client_id_policy,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -168,8 +685,33 @@ where
     /// Finish server configuration and create mqtt server factory
     pub fn finish(
         self,
-    ) -> impl ServiceFactory<Config = (), Request = Io, Response = (), Error = MqttError<C::Error>>
-    {
+    ) -> impl ServiceFactory<
+        Config = (),
+        Request = Io,
+        Response = (),
+        Error = MqttError<C::Error>,
+        InitError = C::InitError,
+    > {
+        ntex::map_config(self.finish_with_config(), |_: ()| ListenerConfig::default())
+    }
+
+    /// Finish server configuration and create mqtt server factory with
+    /// per-listener configuration.
+    ///
+    /// Settings of `ListenerConfig` override server builder settings for
+    /// connections accepted by the listener, use `ntex::map_config()` to
+    /// provide config for each listener.
+    pub fn finish_with_config(
+        self,
+    ) -> impl ServiceFactory<
+        Config = ListenerConfig,
+        Request = Io,
+        Response = (),
+        Error = MqttError<C::Error>,
+        InitError = C::InitError,
+    > {
+        let limits = self.codec_limits();
+        let config = self.dispatcher_config();
         let handshake = self.handshake;
         let publish = self
             .publish
@@ -184,12 +726,14 @@ where
         FramedService::new(
             handshake_service_factory(
                 handshake,
-                self.max_size,
+                limits,
                 self.handshake_timeout,
+                self.config.clone(),
+                chain_filter(self.connect_filter, self.client_id_policy),
                 self.pool,
             ),
             apply_fn_factory(
-                factory(publish, control, self.inflight),
+                factory(publish, control, config),
                 |req: DispatchItem<Rc<MqttShared>>, srv| match req {
                     DispatchItem::Item(req) => Either::Left(srv.call(req)),
                     DispatchItem::KeepAliveTimeout => Either::Right(Ready::Err(
@@ -210,6 +754,9 @@ where
             ),
             self.disconnect_timeout,
         )
+        .buffer_limits(self.buffer_limits)
+        .timer(self.timer)
+        .shutdown_timeout(self.shutdown_timeout)
     }
 
     /// Set service to handle publish packets and create mqtt server factory
@@ -222,6 +769,8 @@ where
         Error = MqttError<C::Error>,
         InitError = C::InitError,
     > {
+        let limits = self.codec_limits();
+        let config = self.dispatcher_config();
         let handshake = self.handshake;
         let publish = self
             .publish
@@ -236,12 +785,14 @@ where
         FramedService2::new(
             handshake_service_factory2(
                 handshake,
-                self.max_size,
+                limits,
                 self.handshake_timeout,
+                self.config.clone(),
+                chain_filter(self.connect_filter, self.client_id_policy),
                 self.pool,
             ),
             apply_fn_factory(
-                factory(publish, control, self.inflight),
+                factory(publish, control, config),
                 |req: DispatchItem<Rc<MqttShared>>, srv| match req {
                     DispatchItem::Item(req) => Either::Left(srv.call(req)),
                     DispatchItem::KeepAliveTimeout => Either::Right(Ready::Err(
@@ -262,6 +813,9 @@ where
             ),
             self.disconnect_timeout,
         )
+        .buffer_limits(self.buffer_limits)
+        .timer(self.timer)
+        .shutdown_timeout(self.shutdown_timeout)
     }
 
     /// Set service to handle publish packets and create mqtt server factory
@@ -279,6 +833,8 @@ where
         F: Fn(&Handshake<Io>) -> R + 'static,
         R: Future<Output = Result<bool, C::Error>> + 'static,
     {
+        let limits = self.codec_limits();
+        let config = self.dispatcher_config();
         let publish = self
             .publish
             .map_err(|e| MqttError::Service(e.into()))
@@ -289,7 +845,7 @@ where
             .map_init_err(|e| MqttError::Service(e.into()));
 
         let handler = apply_fn_factory(
-            factory(publish, control, self.inflight),
+            factory(publish, control, config),
             |req: DispatchItem<Rc<MqttShared>>, srv| match req {
                 DispatchItem::Item(req) => Either::Left(srv.call(req)),
                 DispatchItem::KeepAliveTimeout => Either::Right(Ready::Err(
@@ -314,60 +870,136 @@ where
             check: Rc::new(check),
             connect: self.handshake,
             handler: Rc::new(handler),
-            max_size: self.max_size,
+            limits,
             disconnect_timeout: self.disconnect_timeout,
-            time: Timer::with(Duration::from_secs(1)),
+            shutdown_timeout: self.shutdown_timeout,
+            buffer_limits: self.buffer_limits,
+            time: self.timer,
+            config: self.config,
+            connect_filter: chain_filter(self.connect_filter, self.client_id_policy),
             _t: PhantomData,
         }
     }
 }
 
+#[derive(Clone)]
+struct CodecLimits {
+    max_size: u32,
+    max_topic_length: u16,
+    max_topic_levels: u16,
+    max_client_id_length: u16,
+    capture_malformed: usize,
+    decode_budget: (Duration, u32),
+    decode_stats: Option<DecodeStats>,
+}
+
+impl CodecLimits {
+    fn with_handle(mut self, config: &ConfigHandle) -> Self {
+        self.max_size = config.max_size_or(self.max_size);
+        self
+    }
+
+    fn with_config(mut self, cfg: &ListenerConfig) -> Self {
+        if let Some(max_size) = cfg.max_size {
+            self.max_size = max_size;
+        }
+        self
+    }
+
+    fn codec(&self) -> mqtt::Codec {
+        let codec = mqtt::Codec::default();
+        self.apply(&codec);
+        codec
+    }
+
+    fn apply(&self, codec: &mqtt::Codec) {
+        codec.set_max_size(self.max_size);
+        codec.set_max_topic_length(self.max_topic_length);
+        codec.set_max_topic_levels(self.max_topic_levels);
+        codec.set_max_client_id_length(self.max_client_id_length);
+        codec.set_capture_malformed(self.capture_malformed);
+        codec.set_decode_budget(self.decode_budget.0, self.decode_budget.1);
+        codec.set_decode_stats(self.decode_stats.clone());
+    }
+}
+
 fn handshake_service_factory<Io, St, C>(
     factory: C,
-    max_size: u32,
-    handshake_timeout: u16,
+    limits: CodecLimits,
+    handshake_timeout: Duration,
+    config: ConfigHandle,
+    connect_filter: Option<ConnectFilter<Io>>,
     pool: Rc<MqttSinkPool>,
 ) -> impl ServiceFactory<
-    Config = (),
+    Config = ListenerConfig,
     Request = Io,
-    Response = (Io, State, Rc<MqttShared>, Session<St>, u16),
+    Response = (
+        Io,
+        State,
+        Rc<MqttShared>,
+        Session<St>,
+        Rc<Cell<u16>>,
+        Rc<Cell<Option<ShutdownStatus>>>,
+        Rc<Cell<bool>>,
+        Rc<FlushWaiters>,
+    ),
     Error = MqttError<C::Error>,
+    InitError = C::InitError,
 >
 where
     Io: AsyncRead + AsyncWrite + Unpin,
     C: ServiceFactory<Config = (), Request = Handshake<Io>, Response = HandshakeAck<Io, St>>,
     C::Error: fmt::Debug,
 {
-    ntex::apply(
-        Timeout::new(Duration::from_millis(handshake_timeout as u64)),
-        ntex::fn_factory(move || {
-            let pool = pool.clone();
-            let fut = factory.new_service(());
-            async move {
-                let service = fut.await?;
-                let pool = pool.clone();
-                let service = Rc::new(service.map_err(MqttError::Service));
-                Ok::<_, C::InitError>(ntex::apply_fn(service, move |conn: Io, service| {
-                    handshake(conn, None, service.clone(), max_size, pool.clone())
-                }))
-            }
-        }),
-    )
-    .map_err(|e| match e {
-        TimeoutError::Service(e) => e,
-        TimeoutError::Timeout => MqttError::HandshakeTimeout,
+    ntex::fn_factory_with_config(move |cfg: ListenerConfig| {
+        let pool = pool.clone();
+        let config = config.clone();
+        let connect_filter = connect_filter.clone();
+        let limits = limits.clone().with_config(&cfg);
+        let timeout = cfg.handshake_timeout.unwrap_or(handshake_timeout);
+        let fut = factory.new_service(());
+        async move {
+            let service = fut.await?;
+            let service = Rc::new(service.map_err(MqttError::Service));
+            let service = ntex::apply_fn(service, move |conn: Io, service| {
+                handshake(
+                    conn,
+                    None,
+                    service.clone(),
+                    limits.clone(),
+                    config.clone(),
+                    connect_filter.clone(),
+                    pool.clone(),
+                )
+            });
+            Ok::<_, C::InitError>(TimeoutService::new(timeout, service).map_err(|e| match e {
+                TimeoutError::Service(e) => e,
+                TimeoutError::Timeout => MqttError::HandshakeTimeout,
+            }))
+        }
     })
 }
 
 fn handshake_service_factory2<Io, St, C>(
     factory: C,
-    max_size: u32,
-    handshake_timeout: u16,
+    limits: CodecLimits,
+    handshake_timeout: Duration,
+    config: ConfigHandle,
+    connect_filter: Option<ConnectFilter<Io>>,
     pool: Rc<MqttSinkPool>,
 ) -> impl ServiceFactory<
     Config = (),
     Request = (Io, State),
-    Response = (Io, State, Rc<MqttShared>, Session<St>, u16),
+    Response = (
+        Io,
+        State,
+        Rc<MqttShared>,
+        Session<St>,
+        Rc<Cell<u16>>,
+        Rc<Cell<Option<ShutdownStatus>>>,
+        Rc<Cell<bool>>,
+        Rc<FlushWaiters>,
+    ),
     Error = MqttError<C::Error>,
     InitError = C::InitError,
 >
@@ -377,16 +1009,27 @@ where
     C::Error: fmt::Debug,
 {
     ntex::apply(
-        Timeout::new(Duration::from_millis(handshake_timeout as u64)),
+        Timeout::new(handshake_timeout),
         ntex::fn_factory(move || {
             let pool = pool.clone();
+            let config = config.clone();
+            let connect_filter = connect_filter.clone();
             let fut = factory.new_service(());
+            let limits = limits.clone();
             async move {
                 let service = fut.await?;
                 let pool = pool.clone();
                 let service = Rc::new(service.map_err(MqttError::Service));
                 Ok(ntex::apply_fn(service, move |(io, state), service| {
-                    handshake(io, Some(state), service.clone(), max_size, pool.clone())
+                    handshake(
+                        io,
+                        Some(state),
+                        service.clone(),
+                        limits.clone(),
+                        config.clone(),
+                        connect_filter.clone(),
+                        pool.clone(),
+                    )
                 }))
             }
         }),
@@ -401,27 +1044,49 @@ async fn handshake<Io, S, St, E>(
     mut io: Io,
     state: Option<State>,
     service: S,
-    max_size: u32,
+    limits: CodecLimits,
+    config: ConfigHandle,
+    connect_filter: Option<ConnectFilter<Io>>,
     pool: Rc<MqttSinkPool>,
-) -> Result<(Io, State, Rc<MqttShared>, Session<St>, u16), S::Error>
+) -> Result<
+    (
+        Io,
+        State,
+        Rc<MqttShared>,
+        Session<St>,
+        Rc<Cell<u16>>,
+        Rc<Cell<Option<ShutdownStatus>>>,
+        Rc<Cell<bool>>,
+        Rc<FlushWaiters>,
+    ),
+    S::Error,
+>
 where
     Io: AsyncRead + AsyncWrite + Unpin,
     S: Service<Request = Handshake<Io>, Response = HandshakeAck<Io, St>, Error = MqttError<E>>,
 {
     log::trace!("Starting mqtt handshake");
 
+    let limits = limits.with_handle(&config);
     let state = state.unwrap_or_else(State::new);
-    let shared = Rc::new(MqttShared::new(
-        state.clone(),
-        mqtt::Codec::default().max_size(max_size),
-        16,
-        pool,
-    ));
+    let shared = Rc::new(MqttShared::new(state.clone(), limits.codec(), 16, pool));
 
     // read first packet
-    let packet = state
-        .next(&mut io, &shared.codec)
-        .await
+    let packet = match state.next(&mut io, &shared.codec).await {
+        Err(Either::Left(DecodeError::InvalidClientId)) => {
+            log::trace!("Client id is rejected during mqtt handshake");
+            let pkt = mqtt::Packet::ConnectAck {
+                session_present: false,
+                return_code: mqtt::ConnectAckReason::IdentifierRejected,
+            };
+            state.send(&mut io, &shared.codec, pkt).await?;
+            return Err(MqttError::Protocol(ProtocolError::Decode(
+                DecodeError::InvalidClientId,
+            )));
+        }
+        res => res,
+    };
+    let packet = packet
         .map_err(|err| {
             log::trace!("Error is received during mqtt handshake: {:?}", err);
             MqttError::from(err)
@@ -435,8 +1100,29 @@ where
 
     match packet {
         mqtt::Packet::Connect(connect) => {
+            let mut hnd = Handshake::new(connect, io, shared);
+            let rejected = connect_filter.and_then(|f| hnd.filter(&f).err());
+            let client_id = hnd.packet().client_id.clone();
+
             // authenticate mqtt connection
-            let mut ack = service.call(Handshake::new(connect, io, shared)).await?;
+            let mut ack = if let Some(code) = rejected {
+                log::trace!("Connection is rejected by connect filter: {:?}", code);
+                hnd.failed(code)
+            } else {
+                service.call(hnd).await?
+            };
+
+            if let Some(upgrade) = ack.upgrade.take() {
+                log::trace!("Connection is upgraded by handshake service");
+                upgrade(
+                    ack.shared
+                        .state
+                        .clone()
+                        .into_framed(ack.io, mqtt::Codec::new())
+                        .into_parts(),
+                );
+                return Err(MqttError::Upgraded);
+            }
 
             match ack.session {
                 Some(session) => {
@@ -449,13 +1135,18 @@ where
 
                     state.set_buffer_params(ack.read_hw, ack.write_hw, ack.lw);
                     state.send(&mut ack.io, &ack.shared.codec, pkt).await?;
+                    ack.shared.keepalive.set(config.keep_alive_or(ack.keepalive));
+                    ack.shared.set_extension(ack.extension.take());
 
                     Ok((
                         ack.io,
                         ack.shared.state.clone(),
                         ack.shared.clone(),
-                        Session::new(session, MqttSink::new(ack.shared)),
-                        ack.keepalive,
+                        Session::new(session, MqttSink::new(ack.shared.clone()), client_id),
+                        ack.shared.keepalive.clone(),
+                        ack.shared.shutdown.clone(),
+                        ack.shared.expired.clone(),
+                        ack.shared.flush.clone(),
                     ))
                 }
                 None => {
@@ -485,9 +1176,13 @@ pub(crate) struct ServerSelector<St, C, T, Io, F, R> {
     connect: C,
     handler: Rc<T>,
     disconnect_timeout: u16,
+    shutdown_timeout: Duration,
+    buffer_limits: BufferLimits,
     time: Timer,
     check: Rc<F>,
-    max_size: u32,
+    limits: CodecLimits,
+    config: ConfigHandle,
+    connect_filter: Option<ConnectFilter<Io>>,
     _t: PhantomData<(St, Io, R)>,
 }
 
@@ -519,18 +1214,26 @@ where
         let fut = self.connect.new_service(());
         let handler = self.handler.clone();
         let disconnect_timeout = self.disconnect_timeout;
+        let shutdown_timeout = self.shutdown_timeout;
+        let buffer_limits = self.buffer_limits;
         let time = self.time.clone();
         let check = self.check.clone();
-        let max_size = self.max_size;
+        let limits = self.limits.clone();
+        let config = self.config.clone();
+        let connect_filter = self.connect_filter.clone();
 
         // create connect service and then create service impl
         Box::pin(async move {
             Ok(ServerSelectorImpl {
                 handler,
                 disconnect_timeout,
+                shutdown_timeout,
+                buffer_limits,
                 time,
                 check,
-                max_size,
+                limits,
+                config,
+                connect_filter,
                 connect: Rc::new(fut.await?),
                 _t: PhantomData,
             })
@@ -543,8 +1246,12 @@ pub(crate) struct ServerSelectorImpl<St, C, T, Io, F, R> {
     connect: Rc<C>,
     handler: Rc<T>,
     disconnect_timeout: u16,
+    shutdown_timeout: Duration,
+    buffer_limits: BufferLimits,
     time: Timer,
-    max_size: u32,
+    limits: CodecLimits,
+    config: ConfigHandle,
+    connect_filter: Option<ConnectFilter<Io>>,
     _t: PhantomData<(St, Io, R)>,
 }
 
@@ -586,8 +1293,12 @@ where
         let connect = self.connect.clone();
         let handler = self.handler.clone();
         let timeout = self.disconnect_timeout;
+        let shutdown_timeout = self.shutdown_timeout;
+        let buffer_limits = self.buffer_limits;
         let time = self.time.clone();
-        let max_size = self.max_size;
+        let limits = self.limits.clone().with_handle(&self.config);
+        let config = self.config.clone();
+        let connect_filter = self.connect_filter.clone();
 
         Box::pin(async move {
             let (hnd, state, mut delay) = req;
@@ -605,8 +1316,15 @@ where
             if !result.map_err(MqttError::Service)? {
                 Ok(Either::Left((hnd, state, delay)))
             } else {
+                let mut hnd = hnd;
+                let rejected = connect_filter.and_then(|f| hnd.filter(&f).err());
+                let client_id = hnd.packet().client_id.clone();
+
                 // authenticate mqtt connection
-                let mut ack = if let Some(ref mut delay) = delay {
+                let mut ack = if let Some(code) = rejected {
+                    log::trace!("Connection is rejected by connect filter: {:?}", code);
+                    hnd.failed(code)
+                } else if let Some(ref mut delay) = delay {
                     let fut = connect.call(hnd);
                     match crate::utils::select(fut, delay).await {
                         Either::Left(res) => res.map_err(|e| {
@@ -622,6 +1340,18 @@ where
                     })?
                 };
 
+                if let Some(upgrade) = ack.upgrade.take() {
+                    log::trace!("Connection is upgraded by handshake service");
+                    upgrade(
+                        ack.shared
+                            .state
+                            .clone()
+                            .into_framed(ack.io, mqtt::Codec::new())
+                            .into_parts(),
+                    );
+                    return Err(MqttError::Upgraded);
+                }
+
                 match ack.session {
                     Some(session) => {
                         let pkt = mqtt::Packet::ConnectAck {
@@ -633,17 +1363,24 @@ where
                             pkt
                         );
 
-                        ack.shared.codec.set_max_size(max_size);
+                        limits.apply(&ack.shared.codec);
                         state.set_buffer_params(ack.read_hw, ack.write_hw, ack.lw);
                         state
                             .send(&mut ack.io, &ack.shared.codec, pkt)
                             .await
                             .map_err(MqttError::from)?;
 
-                        let session = Session::new(session, MqttSink::new(ack.shared.clone()));
+                        ack.shared.keepalive.set(config.keep_alive_or(ack.keepalive));
+                        ack.shared.set_extension(ack.extension.take());
+                        let session =
+                            Session::new(session, MqttSink::new(ack.shared.clone()), client_id);
                         let handler = handler.new_service(session).await?;
                         log::trace!("Connection handler is created, starting dispatcher");
 
+                        let keepalive = ack.shared.keepalive.clone();
+                        let status = ack.shared.shutdown.clone();
+                        let expired = ack.shared.expired.clone();
+                        let flush = ack.shared.flush.clone();
                         Dispatcher::with(
                             ack.io,
                             ack.shared.state.clone(),
@@ -651,8 +1388,13 @@ where
                             handler,
                             time,
                         )
-                        .keepalive_timeout(ack.keepalive as u16)
+                        .keepalive(keepalive)
+                        .shutdown_status(status)
+                        .keepalive_expired(expired)
+                        .flush_waiters(flush)
                         .disconnect_timeout(timeout)
+                        .shutdown_timeout(shutdown_timeout)
+                        .buffer_limits(buffer_limits)
                         .await?;
                         Ok(Either::Right(()))
                     }
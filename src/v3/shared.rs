@@ -1,11 +1,18 @@
+use std::time::{Duration, Instant};
 use std::{cell::Cell, cell::RefCell, collections::VecDeque, num::NonZeroU16, rc::Rc};
 
-use ntex::channel::pool;
+use ntex::channel::{oneshot, pool};
 use ntex::codec::{Decoder, Encoder};
-use ntex::util::{BytesMut, HashMap};
+use ntex::util::{ByteString, BytesMut, HashMap};
 
+use crate::backlog::{Backlog, SlowConsumerPolicy};
+use crate::clock;
 use crate::error::{DecodeError, EncodeError};
-use crate::{io::State, types::packet_type, v3::codec};
+use crate::io::{FlushWaiters, ShutdownStatus, State};
+use crate::rewrite::Tenant;
+use crate::types::{packet_type, InflightPacket, InflightType};
+use crate::CodecExtension;
+use crate::{semaphore::Semaphore, v3::codec};
 
 pub(super) enum Ack {
     Publish(NonZeroU16),
@@ -20,30 +27,72 @@ pub(super) enum AckType {
     Unsubscribe,
 }
 
+/// Ack channel, ack type, send time and publish topic of in-flight packet
+pub(super) type InflightEntry =
+    (Option<pool::Sender<Option<Ack>>>, AckType, Instant, Option<ByteString>);
+
 pub(super) struct MqttSinkPool {
-    pub(super) queue: pool::Pool<Ack>,
-    pub(super) waiters: pool::Pool<()>,
+    pub(super) queue: pool::Pool<Option<Ack>>,
 }
 
 impl Default for MqttSinkPool {
     fn default() -> Self {
-        Self { queue: pool::new(), waiters: pool::new() }
+        Self { queue: pool::new() }
     }
 }
 
 pub(crate) struct MqttShared {
-    pub(super) cap: Cell<usize>,
+    /// in-flight window
+    pub(super) permits: Rc<Semaphore>,
     queues: RefCell<MqttSharedQueues>,
     pub(super) inflight_idx: Cell<u16>,
+    /// time of last encoded outgoing packet
+    pub(super) last_write: Cell<Instant>,
+    /// keep-alive timeout in seconds
+    pub(super) keepalive: Rc<Cell<u16>>,
+    /// smoothed ping round-trip time
+    pub(super) rtt: Cell<Option<Duration>>,
+    /// transport shutdown status, set after io tasks are completed
+    pub(super) shutdown: Rc<Cell<Option<ShutdownStatus>>>,
+    /// connection is stopped because of keep-alive timeout
+    pub(super) expired: Rc<Cell<bool>>,
+    /// waiters for outbound buffer flush
+    pub(super) flush: Rc<FlushWaiters>,
+    /// publishes sent when connection is closed by sink
+    pub(super) on_close: RefCell<Vec<codec::Publish>>,
+    /// prefix of topics of publishes created by sink
+    pub(super) topic_prefix: RefCell<Option<ByteString>>,
+    /// namespace of isolated tenant, removed from topics of publishes
+    pub(super) tenant: RefCell<Option<Tenant>>,
+    /// connection is closed because session is taken over
+    pub(super) takeover: Cell<bool>,
+    /// client side of connection
+    pub(super) client: Cell<bool>,
+    /// codec extension, set after handshake
+    extension: RefCell<Option<Box<dyn CodecExtension<codec::Packet>>>>,
+    /// outbound queue watermark
+    pub(super) backlog: Backlog,
     pub(super) pool: Rc<MqttSinkPool>,
     pub(super) state: State,
     pub(super) codec: codec::Codec,
 }
 
 pub(super) struct MqttSharedQueues {
-    pub(super) inflight: HashMap<u16, (pool::Sender<Ack>, AckType)>,
+    /// in-flight packets by packet id.
+    ///
+    /// Channel is taken when waiter is expired, `None` is sent to expired waiter.
+    pub(super) inflight: HashMap<u16, InflightEntry>,
     pub(super) inflight_order: VecDeque<u16>,
-    pub(super) waiters: VecDeque<pool::Sender<()>>,
+    /// send time and waiter of unanswered pings, in order of sending
+    pub(super) pings: VecDeque<(Instant, Option<oneshot::Sender<Duration>>)>,
+}
+
+impl MqttSharedQueues {
+    /// Drop waiters of in-flight packets and pings
+    pub(super) fn clear(&mut self) {
+        self.inflight.clear();
+        self.pings.clear();
+    }
 }
 
 impl MqttShared {
@@ -57,34 +106,123 @@ impl MqttShared {
             state,
             pool,
             codec,
-            cap: Cell::new(cap),
+            permits: Rc::new(Semaphore::new(cap)),
             queues: RefCell::new(MqttSharedQueues {
                 inflight: HashMap::default(),
                 inflight_order: VecDeque::with_capacity(8),
-                waiters: VecDeque::new(),
+                pings: VecDeque::new(),
             }),
             inflight_idx: Cell::new(0),
+            last_write: Cell::new(clock::now()),
+            keepalive: Rc::new(Cell::new(0)),
+            rtt: Cell::new(None),
+            takeover: Cell::new(false),
+            client: Cell::new(false),
+            shutdown: Rc::default(),
+            expired: Rc::default(),
+            flush: Rc::default(),
+            on_close: RefCell::new(Vec::new()),
+            topic_prefix: RefCell::new(None),
+            tenant: RefCell::new(None),
+            extension: RefCell::new(None),
+            backlog: Backlog::new(),
         }
     }
 
+    /// Set codec extension
+    pub(super) fn set_extension(&self, ext: Option<Box<dyn CodecExtension<codec::Packet>>>) {
+        *self.extension.borrow_mut() = ext;
+    }
+
+    /// Check outbound queue watermark
+    pub(super) fn check_backlog(&self) -> Option<SlowConsumerPolicy> {
+        self.backlog.check(|| self.state.write().with_buf(|buf| buf.len()))
+    }
+
     pub(super) fn with_queues<R>(&self, f: impl FnOnce(&mut MqttSharedQueues) -> R) -> R {
         let mut queues = self.queues.borrow_mut();
         f(&mut queues)
     }
 
-    pub(super) fn has_credit(&self) -> bool {
-        self.cap.get() - self.queues.borrow().inflight.len() > 0
+    /// Packet id and send time of the oldest in-flight packet
+    pub(super) fn oldest_inflight(&self) -> Option<(u16, Instant)> {
+        self.queues
+            .borrow()
+            .inflight
+            .iter()
+            .map(|(idx, (_, _, sent, _))| (*idx, *sent))
+            .min_by_key(|(_, sent)| *sent)
+    }
+
+    /// Topic of publish created by sink, with topic prefix.
+    ///
+    /// Tenant namespace is removed before prefix is applied, `None` if
+    /// topic is outside of tenant namespace.
+    pub(super) fn publish_topic(&self, topic: ByteString) -> Option<ByteString> {
+        let topic = match *self.tenant.borrow() {
+            Some(ref tenant) => tenant.strip(topic)?,
+            None => topic,
+        };
+        Some(match *self.topic_prefix.borrow() {
+            Some(ref prefix) if !topic.is_empty() => {
+                ByteString::from(format!("{}{}", prefix, topic))
+            }
+            _ => topic,
+        })
+    }
+
+    /// Write publishes registered for connection close
+    pub(super) fn send_on_close(&self) {
+        for pkt in self.on_close.take() {
+            let _ = self.state.write().encode(codec::Packet::Publish(pkt), self);
+        }
+    }
+
+    /// In-flight packets in order of sending
+    pub(super) fn inflight_packets(&self) -> Vec<InflightPacket> {
+        let queues = self.queues.borrow();
+        queues
+            .inflight_order
+            .iter()
+            .filter_map(|idx| {
+                queues.inflight.get(idx).map(|(_, tp, sent, topic)| InflightPacket {
+                    packet_id: *idx,
+                    packet_type: tp.kind(),
+                    topic: topic.clone(),
+                    age: clock::elapsed(*sent),
+                })
+            })
+            .collect()
+    }
+
+    /// Remove in-flight packet and release its slot in in-flight window
+    pub(super) fn remove_inflight(&self, idx: u16) -> Option<InflightEntry> {
+        let entry = self.with_queues(|queues| {
+            let entry = queues.inflight.remove(&idx)?;
+            queues.inflight_order.retain(|i| *i != idx);
+            Some(entry)
+        });
+        if entry.is_some() {
+            self.permits.release();
+        }
+        entry
     }
 
+    /// Allocate packet id.
+    ///
+    /// Ids are allocated sequentially from `1..=65535` range, ids
+    /// of in-flight packets are skipped.
     pub(super) fn next_id(&self) -> u16 {
-        let idx = self.inflight_idx.get() + 1;
-        if idx == u16::max_value() {
-            self.inflight_idx.set(0);
-            u16::max_value()
-        } else {
-            self.inflight_idx.set(idx);
-            idx
+        let queues = self.queues.borrow();
+        let mut idx = self.inflight_idx.get();
+        for _ in 0..u16::MAX {
+            idx = if idx == u16::MAX { 1 } else { idx + 1 };
+            if !queues.inflight.contains_key(&idx) {
+                break;
+            }
         }
+        self.inflight_idx.set(idx);
+        idx
     }
 }
 impl Encoder for MqttShared {
@@ -93,7 +231,13 @@ impl Encoder for MqttShared {
 
     #[inline]
     fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        self.codec.encode(item, dst)
+        self.last_write.set(clock::now());
+        self.backlog.on_write(dst.len());
+        if let Some(ref ext) = *self.extension.borrow() {
+            self.codec.encode(ext.encode(item)?, dst)
+        } else {
+            self.codec.encode(item, dst)
+        }
     }
 }
 
@@ -103,7 +247,16 @@ impl Decoder for MqttShared {
 
     #[inline]
     fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        self.codec.decode(src)
+        match self.codec.decode(src)? {
+            Some(pkt) => {
+                if let Some(ref ext) = *self.extension.borrow() {
+                    ext.decoded(pkt).map(Some)
+                } else {
+                    Ok(Some(pkt))
+                }
+            }
+            None => Ok(None),
+        }
     }
 }
 
@@ -124,6 +277,16 @@ impl Ack {
         }
     }
 
+    pub(super) fn into_packet(self) -> codec::Packet {
+        match self {
+            Ack::Publish(packet_id) => codec::Packet::PublishAck { packet_id },
+            Ack::Subscribe { packet_id, status } => {
+                codec::Packet::SubscribeAck { packet_id, status }
+            }
+            Ack::Unsubscribe(packet_id) => codec::Packet::UnsubscribeAck { packet_id },
+        }
+    }
+
     pub(super) fn subscribe(self) -> Vec<codec::SubscribeReturnCode> {
         if let Ack::Subscribe { status, .. } = self {
             status
@@ -150,4 +313,38 @@ impl AckType {
             AckType::Unsubscribe => "UnsubscribeAck",
         }
     }
+
+    pub(super) fn kind(&self) -> InflightType {
+        match self {
+            AckType::Publish => InflightType::Publish,
+            AckType::Subscribe => InflightType::Subscribe,
+            AckType::Unsubscribe => InflightType::Unsubscribe,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_id() {
+        let shared = MqttShared::new(State::new(), codec::Codec::new(), 16, Rc::default());
+        assert_eq!(shared.next_id(), 1);
+        assert_eq!(shared.next_id(), 2);
+
+        // in-flight ids are skipped
+        shared.with_queues(|q| {
+            for idx in &[3, 4, 1] {
+                let (tx, _) = shared.pool.queue.channel();
+                q.inflight.insert(*idx, (Some(tx), AckType::Publish, clock::now(), None));
+            }
+        });
+        assert_eq!(shared.next_id(), 5);
+
+        // wrap around
+        shared.inflight_idx.set(u16::MAX - 1);
+        assert_eq!(shared.next_id(), u16::MAX);
+        assert_eq!(shared.next_id(), 2);
+    }
 }
@@ -0,0 +1,428 @@
+//! Mqtt v3.1.1 client for plain tokio runtime
+//!
+//! Client drives `proto::Connection` state machine in a task spawned with
+//! `tokio::spawn`, it does not require ntex runtime.
+//!
+//! ```rust,no_run
+//! use ntex_bytes::{ByteString, Bytes};
+//! use ntex_mqtt::v3::{codec::QoS, tokio::MqttConnector};
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let (sink, mut publishes) =
+//!     MqttConnector::new("127.0.0.1:1883").client_id("tokio-client").connect().await?;
+//!
+//! sink.subscribe(vec![(ByteString::from("topic/#"), QoS::AtLeastOnce)]).await?;
+//! sink.publish("topic/1", Bytes::from_static(b"data"), QoS::AtLeastOnce).await?;
+//!
+//! while let Some(publish) = publishes.recv().await {
+//!     println!("{:?}", publish);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::{fmt, io, num::NonZeroU16, sync::Arc, time::Duration};
+
+use ::tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use ::tokio::net::{TcpStream, ToSocketAddrs};
+use ::tokio::sync::{mpsc, oneshot, Notify};
+use ::tokio::time::{sleep_until, timeout, Instant};
+use derive_more::{Display, From};
+use ntex_bytes::{ByteString, Bytes};
+
+use super::codec::{self, QoS, SubscribeReturnCode};
+use super::proto::{Connection, Event};
+use crate::error::{ProtocolError, SendPacketError};
+
+/// Errors which can occur when attempting to establish client connection
+#[derive(Debug, Display, From)]
+pub enum ClientError {
+    /// Connect negotiation failed
+    #[display(fmt = "Connect ack failed: {:?}", _0)]
+    Ack(codec::ConnectAckReason),
+    /// Protocol error
+    #[display(fmt = "Protocol error: {}", _0)]
+    Protocol(ProtocolError),
+    /// Handshake timeout
+    #[display(fmt = "Handshake timeout")]
+    HandshakeTimeout,
+    /// Peer disconnected
+    #[display(fmt = "Peer disconnected")]
+    Disconnected,
+    /// Transport io error
+    #[display(fmt = "Io error: {}", _0)]
+    Io(io::Error),
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Protocol(e) => Some(e),
+            ClientError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Mqtt client connector for tokio runtime
+pub struct MqttConnector<A> {
+    address: A,
+    pkt: codec::Connect,
+    max_send: usize,
+    max_receive: usize,
+    max_packet_size: u32,
+    handshake_timeout: Duration,
+}
+
+impl<A> MqttConnector<A>
+where
+    A: ToSocketAddrs,
+{
+    /// Create new mqtt connector
+    pub fn new(address: A) -> Self {
+        MqttConnector {
+            address,
+            pkt: codec::Connect::default(),
+            max_send: 16,
+            max_receive: 16,
+            max_packet_size: 0,
+            handshake_timeout: Duration::from_secs(0),
+        }
+    }
+
+    /// Set client identifier
+    pub fn client_id<U>(mut self, client_id: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.pkt.client_id = client_id.into();
+        self
+    }
+
+    /// Set clean session flag, by default it is set to `false`
+    pub fn clean_session(mut self) -> Self {
+        self.pkt.clean_session = true;
+        self
+    }
+
+    /// Set keep alive interval in seconds, by default it is disabled
+    pub fn keep_alive(mut self, val: u16) -> Self {
+        self.pkt.keep_alive = val;
+        self
+    }
+
+    /// Set last will
+    pub fn last_will(mut self, val: codec::LastWill) -> Self {
+        self.pkt.last_will = Some(val);
+        self
+    }
+
+    /// Set username
+    pub fn username<U>(mut self, val: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.pkt.username = Some(val.into());
+        self
+    }
+
+    /// Set password
+    pub fn password(mut self, val: Bytes) -> Self {
+        self.pkt.password = Some(val);
+        self
+    }
+
+    /// Set max send packets number
+    ///
+    /// Number of in-flight outgoing packets, sink requests wait until server
+    /// acknowledges previous packets. By default max send is set to 16 packets.
+    /// To disable in-flight limit set value to 0.
+    pub fn max_send(mut self, val: u16) -> Self {
+        self.max_send = val as usize;
+        self
+    }
+
+    /// Set max receive packets number
+    ///
+    /// Number of received publishes buffered until they are consumed from
+    /// `Publishes` stream, connection stops reading from transport when buffer
+    /// is full. By default max receive is set to 16 packets.
+    pub fn max_receive(mut self, val: u16) -> Self {
+        self.max_receive = std::cmp::max(val as usize, 1);
+        self
+    }
+
+    /// Set max inbound packet size, by default it is unlimited
+    pub fn max_packet_size(mut self, val: u32) -> Self {
+        self.max_packet_size = val;
+        self
+    }
+
+    /// Set handshake timeout, by default it is disabled
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Connect to mqtt server over tcp
+    pub async fn connect(self) -> Result<(MqttSink, Publishes), ClientError> {
+        let io = TcpStream::connect(&self.address).await?;
+        self.connect_io(io).await
+    }
+
+    /// Connect to mqtt server over provided transport
+    pub async fn connect_io<Io>(self, io: Io) -> Result<(MqttSink, Publishes), ClientError>
+    where
+        Io: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let conn = Connection::new(self.pkt).max_packet_size(self.max_packet_size);
+        let fut = handshake(conn, io, self.max_send, self.max_receive);
+        if self.handshake_timeout == Duration::ZERO {
+            fut.await
+        } else {
+            timeout(self.handshake_timeout, fut)
+                .await
+                .map_err(|_| ClientError::HandshakeTimeout)?
+        }
+    }
+}
+
+async fn handshake<Io>(
+    mut conn: Connection,
+    mut io: Io,
+    max_send: usize,
+    max_receive: usize,
+) -> Result<(MqttSink, Publishes), ClientError>
+where
+    Io: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    io.write_all(&conn.transmit(Instant::now().into_std())).await?;
+
+    let mut buf = vec![0; 4096];
+    let session_present = loop {
+        match conn.poll_event()? {
+            Some(Event::Connected { session_present }) => break session_present,
+            Some(Event::Refused(reason)) => return Err(ClientError::Ack(reason)),
+            Some(_) => return Err(ClientError::Disconnected),
+            None => {
+                let size = io.read(&mut buf).await?;
+                if size == 0 {
+                    return Err(ClientError::Disconnected);
+                }
+                conn.receive(Instant::now().into_std(), &buf[..size]);
+            }
+        }
+    };
+
+    let (cmd_tx, cmd_rx) = mpsc::channel(std::cmp::max(max_send, 1));
+    let (pub_tx, pub_rx) = mpsc::channel(max_receive);
+    let close = Arc::new(Notify::new());
+    ::tokio::spawn(
+        Driver {
+            conn,
+            io,
+            buf,
+            cmd_rx,
+            pub_tx,
+            max_send,
+            close: close.clone(),
+            waiters: HashMap::new(),
+        }
+        .run(),
+    );
+
+    Ok((MqttSink { tx: cmd_tx, close, session_present }, Publishes(pub_rx)))
+}
+
+/// Stream of publishes received from server
+#[derive(Debug)]
+pub struct Publishes(mpsc::Receiver<codec::Publish>);
+
+impl Publishes {
+    /// Receive next publish, returns `None` when connection is closed
+    pub async fn recv(&mut self) -> Option<codec::Publish> {
+        self.0.recv().await
+    }
+}
+
+#[derive(Clone)]
+/// Mqtt client sink for tokio runtime
+pub struct MqttSink {
+    tx: mpsc::Sender<Command>,
+    close: Arc<Notify>,
+    session_present: bool,
+}
+
+impl fmt::Debug for MqttSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttSink")
+            .field("open", &self.is_open())
+            .field("session_present", &self.session_present)
+            .finish()
+    }
+}
+
+impl MqttSink {
+    /// Check if connection is open
+    pub fn is_open(&self) -> bool {
+        !self.tx.is_closed()
+    }
+
+    /// Indicates whether there is already stored Session state
+    ///
+    /// If session state is not present, application must restore
+    /// subscriptions.
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    /// Publish message
+    ///
+    /// For QoS 1 and 2 messages, future resolves when server acknowledges message.
+    /// Future waits if number of in-flight packets reached max send limit.
+    pub async fn publish<T>(
+        &self,
+        topic: T,
+        payload: Bytes,
+        qos: QoS,
+    ) -> Result<(), SendPacketError>
+    where
+        ByteString: From<T>,
+    {
+        self.request(|tx| Command::Publish(topic.into(), payload, qos, tx)).await.map(|_| ())
+    }
+
+    /// Subscribe to topic filters, returns return codes from `SUBACK` packet
+    pub async fn subscribe(
+        &self,
+        topic_filters: Vec<(ByteString, QoS)>,
+    ) -> Result<Vec<SubscribeReturnCode>, SendPacketError> {
+        self.request(|tx| Command::Subscribe(topic_filters, tx)).await
+    }
+
+    /// Unsubscribe from topic filters
+    pub async fn unsubscribe(
+        &self,
+        topic_filters: Vec<ByteString>,
+    ) -> Result<(), SendPacketError> {
+        self.request(|tx| Command::Unsubscribe(topic_filters, tx)).await.map(|_| ())
+    }
+
+    /// Send `DISCONNECT` packet and close connection
+    pub fn close(&self) {
+        self.close.notify_one();
+    }
+
+    async fn request<F>(&self, f: F) -> Result<Vec<SubscribeReturnCode>, SendPacketError>
+    where
+        F: FnOnce(Waiter) -> Command,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(f(tx)).await.map_err(|_| SendPacketError::Disconnected)?;
+        rx.await.map_err(|_| SendPacketError::Disconnected)?
+    }
+}
+
+type Waiter = oneshot::Sender<Result<Vec<SubscribeReturnCode>, SendPacketError>>;
+
+enum Command {
+    Publish(ByteString, Bytes, QoS, Waiter),
+    Subscribe(Vec<(ByteString, QoS)>, Waiter),
+    Unsubscribe(Vec<ByteString>, Waiter),
+}
+
+struct Driver<Io> {
+    conn: Connection,
+    io: Io,
+    buf: Vec<u8>,
+    cmd_rx: mpsc::Receiver<Command>,
+    pub_tx: mpsc::Sender<codec::Publish>,
+    max_send: usize,
+    close: Arc<Notify>,
+    waiters: HashMap<NonZeroU16, Waiter>,
+}
+
+impl<Io> Driver<Io>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn run(mut self) {
+        if let Err(e) = self.process().await {
+            log::trace!("Mqtt connection is closed: {:?}", e);
+        }
+        let _ = self.io.shutdown().await;
+    }
+
+    async fn process(&mut self) -> Result<(), ClientError> {
+        loop {
+            while let Some(ev) = self.conn.poll_event()? {
+                match ev {
+                    Event::Publish(publish) => {
+                        // wait for free slot, connection is not read meanwhile
+                        let _ = self.pub_tx.send(publish).await;
+                    }
+                    Event::PublishAck(id) | Event::UnsubscribeAck(id) => {
+                        self.complete(id, Vec::new())
+                    }
+                    Event::SubscribeAck { packet_id, status } => {
+                        self.complete(packet_id, status)
+                    }
+                    Event::Disconnected => {
+                        self.io
+                            .write_all(&self.conn.transmit(Instant::now().into_std()))
+                            .await?;
+                        return Ok(());
+                    }
+                    Event::Connected { .. } | Event::Refused(_) => (),
+                }
+            }
+            if self.conn.has_transmit() {
+                self.io.write_all(&self.conn.transmit(Instant::now().into_std())).await?;
+            }
+
+            let deadline = self.conn.poll_timeout().map(Instant::from_std);
+            let ready = self.max_send == 0 || self.conn.inflight() < self.max_send;
+            ::tokio::select! {
+                res = self.io.read(&mut self.buf) => match res? {
+                    0 => self.conn.receive_eof(),
+                    size => self.conn.receive(Instant::now().into_std(), &self.buf[..size]),
+                },
+                cmd = self.cmd_rx.recv(), if ready => match cmd {
+                    Some(cmd) => self.command(cmd),
+                    None => self.conn.disconnect(),
+                },
+                _ = self.close.notified() => self.conn.disconnect(),
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.conn.handle_timeout(Instant::now().into_std())?;
+                }
+            }
+        }
+    }
+
+    fn command(&mut self, cmd: Command) {
+        let (res, tx) = match cmd {
+            Command::Publish(topic, payload, qos, tx) => {
+                (self.conn.publish(topic, payload, qos), tx)
+            }
+            Command::Subscribe(filters, tx) => (self.conn.subscribe(filters).map(Some), tx),
+            Command::Unsubscribe(filters, tx) => (self.conn.unsubscribe(filters).map(Some), tx),
+        };
+        match res {
+            Ok(Some(id)) => {
+                self.waiters.insert(id, tx);
+            }
+            Ok(None) => {
+                let _ = tx.send(Ok(Vec::new()));
+            }
+            Err(e) => {
+                let _ = tx.send(Err(e));
+            }
+        }
+    }
+
+    fn complete(&mut self, id: NonZeroU16, status: Vec<SubscribeReturnCode>) {
+        if let Some(tx) = self.waiters.remove(&id) {
+            let _ = tx.send(Ok(status));
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::task::{Context, Poll};
+use std::{fmt, future::Future, marker::PhantomData, pin::Pin, rc::Rc};
+
+use ntex::channel::{mpsc, oneshot};
+use ntex::service::{Service, ServiceFactory};
+use ntex::util::Ready;
+
+use super::control::{ControlMessage, ControlResult};
+use super::{codec, Session};
+
+pub(super) type ControlChannelFn<St, E> =
+    Rc<dyn Fn(&Session<St>, mpsc::Receiver<ControlRequest<E>>)>;
+
+/// Control message delivered to control channel
+///
+/// Dispatcher waits for the response, connection gets closed
+/// if request is dropped without response.
+pub struct ControlRequest<E> {
+    msg: ControlMessage<E>,
+    tx: oneshot::Sender<ControlResult>,
+}
+
+impl<E> ControlRequest<E> {
+    /// Control message
+    pub fn message(&self) -> &ControlMessage<E> {
+        &self.msg
+    }
+
+    /// Split request into control message and responder
+    pub fn into_parts(self) -> (ControlMessage<E>, ControlResponder) {
+        (self.msg, ControlResponder(self.tx))
+    }
+
+    /// Respond with result created from control message
+    pub fn respond<F>(self, f: F)
+    where
+        F: FnOnce(ControlMessage<E>) -> ControlResult,
+    {
+        let _ = self.tx.send(f(self.msg));
+    }
+}
+
+impl<E: fmt::Debug> fmt::Debug for ControlRequest<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ControlRequest").field("msg", &self.msg).finish()
+    }
+}
+
+/// Sends result of control message to dispatcher
+pub struct ControlResponder(oneshot::Sender<ControlResult>);
+
+impl ControlResponder {
+    /// Send control message result
+    pub fn send(self, result: ControlResult) {
+        let _ = self.0.send(result);
+    }
+}
+
+impl fmt::Debug for ControlResponder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ControlResponder").finish()
+    }
+}
+
+/// Control service that delivers control messages to a channel
+pub struct ControlChannel<St, E> {
+    f: ControlChannelFn<St, E>,
+}
+
+impl<St, E> ControlChannel<St, E> {
+    pub(super) fn new(f: ControlChannelFn<St, E>) -> Self {
+        ControlChannel { f }
+    }
+}
+
+impl<St, E: 'static> ServiceFactory for ControlChannel<St, E> {
+    type Config = Session<St>;
+    type Request = ControlMessage<E>;
+    type Response = ControlResult;
+    type Error = E;
+    type InitError = E;
+    type Service = ControlChannelService<E>;
+    type Future = Ready<Self::Service, Self::InitError>;
+
+    fn new_service(&self, session: Session<St>) -> Self::Future {
+        let (tx, rx) = mpsc::channel();
+        (*self.f)(&session, rx);
+        Ready::Ok(ControlChannelService { tx, _t: PhantomData })
+    }
+}
+
+pub struct ControlChannelService<E> {
+    tx: mpsc::Sender<ControlRequest<E>>,
+    _t: PhantomData<E>,
+}
+
+impl<E: 'static> Service for ControlChannelService<E> {
+    type Request = ControlMessage<E>;
+    type Response = ControlResult;
+    type Error = E;
+    type Future = Pin<Box<dyn Future<Output = Result<ControlResult, E>>>>;
+
+    #[inline]
+    fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&self, msg: ControlMessage<E>) -> Self::Future {
+        let (tx, rx) = oneshot::channel();
+
+        if self.tx.send(ControlRequest { msg, tx }).is_err() {
+            log::trace!("Control channel is closed, disconnecting");
+            return Box::pin(Ready::Ok(disconnect()));
+        }
+        Box::pin(async move {
+            Ok(rx.await.unwrap_or_else(|_| {
+                log::trace!("Control request is dropped, disconnecting");
+                disconnect()
+            }))
+        })
+    }
+}
+
+fn disconnect() -> ControlResult {
+    let pkt = codec::Disconnect {
+        reason_code: codec::DisconnectReasonCode::UnspecifiedError,
+        session_expiry_interval_secs: None,
+        server_reference: None,
+        reason_string: None,
+        user_properties: Default::default(),
+    };
+    ControlResult { packet: Some(codec::Packet::Disconnect(pkt)), disconnect: true }
+}
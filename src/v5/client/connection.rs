@@ -8,6 +8,7 @@ use ntex::service::boxed::BoxService;
 use ntex::service::{into_service, IntoService, Service};
 use ntex::util::{ByteString, Either, HashMap, Ready};
 
+use crate::clock;
 use crate::error::MqttError;
 use crate::io::{Dispatcher, Timer};
 use crate::v5::publish::{Publish, PublishAck};
@@ -15,6 +16,7 @@ use crate::v5::{codec, shared::MqttShared, sink::MqttSink, ControlResult};
 
 use super::control::ControlMessage;
 use super::dispatcher::create_dispatcher;
+use super::events::{ClientEvent, ClientEvents, Events};
 
 /// Mqtt client
 pub struct Client<Io> {
@@ -22,8 +24,12 @@ pub struct Client<Io> {
     shared: Rc<MqttShared>,
     keepalive: u16,
     disconnect_timeout: u16,
+    timer: Timer,
+    shutdown_timeout: Duration,
     max_receive: usize,
+    max_topic_alias: u16,
     pkt: codec::ConnectAck,
+    events: Rc<Events>,
 }
 
 impl<T> Client<T>
@@ -31,21 +37,30 @@ where
     T: AsyncRead + AsyncWrite + Unpin,
 {
     /// Construct new `Dispatcher` instance with outgoing messages stream.
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         io: T,
         shared: Rc<MqttShared>,
         pkt: codec::ConnectAck,
         max_receive: u16,
+        max_topic_alias: u16,
         keepalive: u16,
         disconnect_timeout: u16,
+        timer: Timer,
+        shutdown_timeout: Duration,
     ) -> Self {
+        shared.keepalive.set(keepalive);
         Client {
             io,
             pkt,
             shared,
             keepalive,
             disconnect_timeout,
+            timer,
+            shutdown_timeout,
             max_receive: max_receive as usize,
+            max_topic_alias,
+            events: Rc::new(Events::default()),
         }
     }
 }
@@ -78,6 +93,14 @@ where
         &mut self.pkt
     }
 
+    /// Get stream of client lifecycle events.
+    ///
+    /// First event is always `ClientEvent::Connected`. Only one stream
+    /// is active at a time, previously returned stream ends.
+    pub fn events(&self) -> ClientEvents {
+        self.events.subscribe(ClientEvent::Connected(Box::new(self.pkt.clone())))
+    }
+
     /// Configure mqtt resource for a specific topic
     pub fn resource<T, F, U, E>(self, address: T, service: F) -> ClientRouter<Io, E, U::Error>
     where
@@ -98,7 +121,11 @@ where
             shared: self.shared,
             keepalive: self.keepalive,
             disconnect_timeout: self.disconnect_timeout,
+            timer: self.timer,
+            shutdown_timeout: self.shutdown_timeout,
             max_receive: self.max_receive,
+            max_topic_alias: self.max_topic_alias,
+            events: self.events,
             _t: marker::PhantomData,
         }
     }
@@ -108,29 +135,43 @@ where
     /// Default handler closes connection on any control message.
     pub async fn start_default(self) {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.events.clone(),
+            ));
         }
 
         let dispatcher = create_dispatcher(
             MqttSink::new(self.shared.clone()),
             self.max_receive,
-            16,
+            self.max_topic_alias,
             into_service(|pkt| Ready::Ok(Either::Left(pkt))),
             into_service(|msg: ControlMessage<()>| {
                 Ready::Ok(msg.disconnect(codec::Disconnect::default()))
             }),
         );
 
-        let _ = Dispatcher::with(
+        let status = self.shared.shutdown.clone();
+
+        let expired = self.shared.expired.clone();
+
+        let flush = self.shared.flush.clone();
+        let res = Dispatcher::with(
             self.io,
             self.shared.state.clone(),
             self.shared,
             dispatcher,
-            Timer::with(Duration::from_secs(1)),
+            self.timer,
         )
         .keepalive_timeout(0)
+        .shutdown_status(status)
+        .keepalive_expired(expired)
+        .flush_waiters(flush)
         .disconnect_timeout(self.disconnect_timeout)
+        .shutdown_timeout(self.shutdown_timeout)
         .await;
+        self.events.disconnected(&res);
     }
 
     /// Run client with provided control messages handler
@@ -141,27 +182,42 @@ where
         S: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E> + 'static,
     {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.events.clone(),
+            ));
         }
 
         let dispatcher = create_dispatcher(
             MqttSink::new(self.shared.clone()),
             self.max_receive,
-            16,
+            self.max_topic_alias,
             into_service(|pkt| Ready::Ok(Either::Left(pkt))),
             service.into_service(),
         );
 
-        Dispatcher::with(
+        let status = self.shared.shutdown.clone();
+
+        let expired = self.shared.expired.clone();
+
+        let flush = self.shared.flush.clone();
+        let res = Dispatcher::with(
             self.io,
             self.shared.state.clone(),
             self.shared,
             dispatcher,
-            Timer::with(Duration::from_secs(1)),
+            self.timer,
         )
         .keepalive_timeout(0)
+        .shutdown_status(status)
+        .keepalive_expired(expired)
+        .flush_waiters(flush)
         .disconnect_timeout(self.disconnect_timeout)
-        .await
+        .shutdown_timeout(self.shutdown_timeout)
+        .await;
+        self.events.disconnected(&res);
+        res
     }
 }
 
@@ -175,7 +231,11 @@ pub struct ClientRouter<Io, Err, PErr> {
     shared: Rc<MqttShared>,
     keepalive: u16,
     disconnect_timeout: u16,
+    timer: Timer,
+    shutdown_timeout: Duration,
     max_receive: usize,
+    max_topic_alias: u16,
+    events: Rc<Events>,
     _t: marker::PhantomData<Err>,
 }
 
@@ -201,29 +261,43 @@ where
     /// Run client with default control messages handler
     pub async fn start_default(self) {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.events.clone(),
+            ));
         }
 
         let dispatcher = create_dispatcher(
             MqttSink::new(self.shared.clone()),
             self.max_receive,
-            16,
+            self.max_topic_alias,
             dispatch(self.builder.finish(), self.handlers),
             into_service(|msg: ControlMessage<Err>| {
                 Ready::Ok(msg.disconnect(codec::Disconnect::default()))
             }),
         );
 
-        let _ = Dispatcher::with(
+        let status = self.shared.shutdown.clone();
+
+        let expired = self.shared.expired.clone();
+
+        let flush = self.shared.flush.clone();
+        let res = Dispatcher::with(
             self.io,
             self.shared.state.clone(),
             self.shared,
             dispatcher,
-            Timer::with(Duration::from_secs(1)),
+            self.timer,
         )
         .keepalive_timeout(0)
+        .shutdown_status(status)
+        .keepalive_expired(expired)
+        .flush_waiters(flush)
         .disconnect_timeout(self.disconnect_timeout)
+        .shutdown_timeout(self.shutdown_timeout)
         .await;
+        self.events.disconnected(&res);
     }
 
     /// Run client and handle control messages
@@ -234,27 +308,42 @@ where
             + 'static,
     {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.events.clone(),
+            ));
         }
 
         let dispatcher = create_dispatcher(
             MqttSink::new(self.shared.clone()),
             self.max_receive,
-            16,
+            self.max_topic_alias,
             dispatch(self.builder.finish(), self.handlers),
             service.into_service(),
         );
 
-        Dispatcher::with(
+        let status = self.shared.shutdown.clone();
+
+        let expired = self.shared.expired.clone();
+
+        let flush = self.shared.flush.clone();
+        let res = Dispatcher::with(
             self.io,
             self.shared.state.clone(),
             self.shared,
             dispatcher,
-            Timer::with(Duration::from_secs(1)),
+            self.timer,
         )
         .keepalive_timeout(0)
+        .shutdown_status(status)
+        .keepalive_expired(expired)
+        .flush_waiters(flush)
         .disconnect_timeout(self.disconnect_timeout)
-        .await
+        .shutdown_timeout(self.shutdown_timeout)
+        .await;
+        self.events.disconnected(&res);
+        res
     }
 }
 
@@ -317,15 +406,36 @@ where
     }
 }
 
-async fn keepalive(sink: MqttSink, timeout: u16) {
+async fn keepalive(sink: MqttSink, timeout: u16, events: Rc<Events>) {
     log::debug!("start mqtt client keep-alive task");
 
     let keepalive = Duration::from_secs(timeout as u64);
     loop {
-        let expire = RtInstant::from_std(Instant::now() + keepalive);
-        delay_until(expire).await;
+        // server must respond to ping within keep-alive interval
+        let now = clock::now();
+        if let Some(sent) = sink.ping_sent() {
+            if sent + keepalive <= now {
+                log::debug!("mqtt client keep-alive ping timeout, closing connection");
+                events.ping_timeout();
+                sink.close_with_reason(codec::Disconnect {
+                    reason_code: codec::DisconnectReasonCode::KeepAliveTimeout,
+                    ..Default::default()
+                });
+                break;
+            }
+        }
+
+        // ping is not needed if connection is not idle
+        let mut expire = sink.last_write() + keepalive;
+        if let Some(sent) = sink.ping_sent() {
+            expire = std::cmp::min(expire, sent + keepalive);
+        }
+        if expire > now {
+            delay_until(RtInstant::from_std(expire)).await;
+            continue;
+        }
 
-        if !sink.ping() {
+        if !sink.send_ping(None) {
             // connection is closed
             log::debug!("mqtt client connection is closed, stopping keep-alive task");
             break;
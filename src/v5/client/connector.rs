@@ -1,10 +1,10 @@
 use std::{future::Future, num::NonZeroU16, num::NonZeroU32, rc::Rc, time::Duration};
 
 use ntex::codec::{AsyncRead, AsyncWrite};
-use ntex::connect::{self, Address, Connect, Connector};
+use ntex::connect::{Address, Connect, ConnectError};
 use ntex::rt::time::delay_for;
 use ntex::service::Service;
-use ntex::util::{select, ByteString, Bytes, Either};
+use ntex::util::{select, ByteString, Bytes, Either, Ready};
 
 #[cfg(feature = "openssl")]
 use ntex::connect::openssl::{OpensslConnector, SslConnector};
@@ -12,34 +12,59 @@ use ntex::connect::openssl::{OpensslConnector, SslConnector};
 #[cfg(feature = "rustls")]
 use ntex::connect::rustls::{ClientConfig, RustlsConnector};
 
+#[cfg(feature = "quic")]
+use crate::quic::{quinn, QuicConnector};
+
 use super::{codec, connection::Client, error::ClientError, error::ProtocolError};
-use crate::io::State;
+use crate::connect::{self, BoxResolver, Proxy, ProxyConnector, TcpConnector};
+use crate::io::{State, Timer};
+use crate::utils::{duration_to_millis, duration_to_secs};
 use crate::v5::shared::{MqttShared, MqttSinkPool};
+use crate::CodecExtension;
 
 /// Mqtt client connector
 pub struct MqttConnector<A, T> {
     address: A,
-    connector: T,
+    connector: Rc<T>,
+    resolver: Option<BoxResolver<A>>,
+    connect_timeout: Duration,
     pkt: codec::Connect,
-    handshake_timeout: u16,
+    handshake_timeout: Duration,
     disconnect_timeout: u16,
+    timer: Timer,
+    shutdown_timeout: Duration,
+    max_inflight: u16,
+    extension: Option<Rc<ExtensionFactory>>,
     pool: Rc<MqttSinkPool>,
+    topic_prefix: Option<ByteString>,
+    publish_properties: Option<codec::PublishProperties>,
 }
 
+type ExtensionFactory =
+    dyn Fn(&codec::ConnectAck) -> Option<Box<dyn CodecExtension<codec::Packet>>>;
+
 impl<A> MqttConnector<A, ()>
 where
     A: Address + Clone,
 {
     #[allow(clippy::new_ret_no_self)]
     /// Create new mqtt connector
-    pub fn new(address: A) -> MqttConnector<A, Connector<A>> {
+    pub fn new(address: A) -> MqttConnector<A, TcpConnector<A>> {
         MqttConnector {
             address,
             pkt: codec::Connect::default(),
-            connector: Connector::default(),
-            handshake_timeout: 0,
+            connector: Rc::new(TcpConnector::default()),
+            resolver: None,
+            connect_timeout: Duration::ZERO,
+            handshake_timeout: Duration::ZERO,
             disconnect_timeout: 3000,
+            timer: Timer::default(),
+            shutdown_timeout: Duration::ZERO,
+            max_inflight: 0,
+            extension: None,
             pool: Rc::new(MqttSinkPool::default()),
+            topic_prefix: None,
+            publish_properties: None,
         }
     }
 }
@@ -47,7 +72,7 @@ where
 impl<A, T> MqttConnector<A, T>
 where
     A: Address + Clone,
-    T: Service<Request = Connect<A>, Error = connect::ConnectError>,
+    T: Service<Request = Connect<A>, Error = ConnectError>,
     T::Response: AsyncRead + AsyncWrite + Unpin + 'static,
 {
     #[inline]
@@ -68,11 +93,12 @@ where
     }
 
     #[inline]
-    /// A time interval measured in seconds.
+    /// Set keep-alive interval.
     ///
+    /// Interval is rounded up to whole seconds and is limited to `u16::MAX` seconds.
     /// keep-alive is set to 30 seconds by default.
-    pub fn keep_alive(mut self, val: u16) -> Self {
-        self.pkt.keep_alive = val;
+    pub fn keep_alive(mut self, val: Duration) -> Self {
+        self.pkt.keep_alive = duration_to_secs(val);
         self
     }
 
@@ -134,6 +160,55 @@ where
         self
     }
 
+    #[inline]
+    /// Set session expiry interval.
+    ///
+    /// Interval is truncated to whole seconds. Session ends when network connection
+    /// is closed if interval is set to 0, `u32::MAX` seconds means session never expires.
+    ///
+    /// By default session expiry interval is not set.
+    pub fn session_expiry(mut self, val: Duration) -> Self {
+        let secs = std::cmp::min(val.as_secs(), u32::MAX as u64) as u32;
+        self.pkt.session_expiry_interval_secs = if secs == 0 { None } else { Some(secs) };
+        self
+    }
+
+    #[inline]
+    /// Set max number of topic aliases server could use for publishes sent to client.
+    ///
+    /// By default topic aliases are not accepted.
+    pub fn topic_alias_max(mut self, val: u16) -> Self {
+        self.pkt.topic_alias_max = val;
+        self
+    }
+
+    #[inline]
+    /// Request response information in connect-ack packet
+    pub fn request_response_info(mut self, val: bool) -> Self {
+        self.pkt.request_response_info = val;
+        self
+    }
+
+    #[inline]
+    /// Request reason string and user properties in failure responses.
+    ///
+    /// By default problem information is requested.
+    pub fn request_problem_info(mut self, val: bool) -> Self {
+        self.pkt.request_problem_info = val;
+        self
+    }
+
+    #[inline]
+    /// Add user property to connect packet
+    pub fn user_property<K, V>(mut self, key: K, value: V) -> Self
+    where
+        ByteString: From<K>,
+        ByteString: From<V>,
+    {
+        self.pkt.user_properties.push((key.into(), value.into()));
+        self
+    }
+
     #[inline]
     /// Update connect user properties
     pub fn properties<F>(mut self, f: F) -> Self
@@ -154,41 +229,190 @@ where
         self
     }
 
-    /// Set handshake timeout in milliseconds.
+    /// Set handshake timeout.
     ///
     /// Handshake includes `connect` packet and response `connect-ack`.
-    /// By default handshake timeuot is disabled.
-    pub fn handshake_timeout(mut self, timeout: u16) -> Self {
-        self.handshake_timeout = timeout as u16;
+    /// By default handshake timeout is disabled.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
         self
     }
 
-    /// Set client connection disconnect timeout in milliseconds.
+    /// Set client connection disconnect timeout.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
-    /// within this time, the connection get dropped.
+    /// within this time, the connection get dropped. Timeout is rounded up to millisecond
+    /// precision and is limited to `u16::MAX` milliseconds (about 65 seconds), larger
+    /// values are clamped with a warning.
     ///
     /// To disable timeout set value to 0.
     ///
     /// By default disconnect timeout is set to 3 seconds.
-    pub fn disconnect_timeout(mut self, timeout: u16) -> Self {
-        self.disconnect_timeout = timeout as u16;
+    pub fn disconnect_timeout(mut self, timeout: Duration) -> Self {
+        self.disconnect_timeout = duration_to_millis(timeout);
+        self
+    }
+
+    /// Set in-flight handlers shutdown timeout.
+    ///
+    /// Defines how long client connection waits for in-flight publish and
+    /// control handlers to complete after connection has been closed. Handlers
+    /// that do not complete within this time get dropped.
+    ///
+    /// To disable timeout set value to 0. By default timeout is disabled.
+    pub fn inflight_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Set max number of outgoing in-flight publishes.
+    ///
+    /// Client never sends more publishes than server's `receive max` allows,
+    /// this setting additionally limits number of in-flight publishes locally.
+    /// To disable local limit set value to 0.
+    ///
+    /// By default local limit is disabled.
+    pub fn max_inflight(mut self, val: u16) -> Self {
+        self.max_inflight = val;
+        self
+    }
+
+    /// Set resolution of connection timer.
+    ///
+    /// Disconnect timeout is checked with this granularity.
+    ///
+    /// By default resolution is set to 1 second.
+    pub fn timer_resolution(mut self, resolution: Duration) -> Self {
+        self.timer = Timer::with(resolution);
+        self
+    }
+
+    /// Use shared timer for client connections.
+    ///
+    /// Overrides `timer_resolution()` setting.
+    pub fn timer(mut self, timer: Timer) -> Self {
+        self.timer = timer;
+        self
+    }
+
+    /// Set codec extension factory.
+    ///
+    /// Factory is called with server's `CONNACK` packet after successful
+    /// handshake, returned extension is applied to all following packets.
+    pub fn codec_extension<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&codec::ConnectAck) -> Option<Box<dyn CodecExtension<codec::Packet>>> + 'static,
+    {
+        self.extension = Some(Rc::new(f));
+        self
+    }
+
+    #[cfg(feature = "compress")]
+    /// Request publish payload compression.
+    ///
+    /// Compression is enabled if server accepts it.
+    pub fn compression(mut self, compression: crate::v5::compress::Compression) -> Self {
+        use crate::v5::compress::Compression;
+
+        Compression::request(&mut self.pkt);
+        self.codec_extension(move |ack| {
+            if Compression::is_accepted(ack) {
+                Some(Box::new(compression))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Set prefix of topics of publishes created by client sink
+    ///
+    /// See `MqttSink::set_topic_prefix()`.
+    pub fn topic_prefix<U>(mut self, prefix: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.topic_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Set default properties of publishes created by client sink
+    ///
+    /// See `MqttSink::set_publish_properties()`.
+    pub fn publish_properties<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut codec::PublishProperties),
+    {
+        let mut properties = self.publish_properties.take().unwrap_or_default();
+        f(&mut properties);
+        self.publish_properties = Some(properties);
+        self
+    }
+
+    /// Use custom address resolver
+    ///
+    /// Resolver must set addresses of `Connect` message, transport connector
+    /// does not resolve such messages. Resolver readiness is not checked.
+    pub fn resolver<R>(mut self, resolver: R) -> Self
+    where
+        R: Service<Request = Connect<A>, Response = Connect<A>, Error = ConnectError> + 'static,
+        R::Future: 'static,
+    {
+        self.resolver = Some(connect::boxed_resolver(resolver));
+        self
+    }
+
+    /// Set transport connect timeout
+    ///
+    /// Timeout includes tls handshake if transport connector performs one,
+    /// it does not include address resolution. By default timeout is disabled.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
         self
     }
 
     /// Use custom connector
     pub fn connector<U>(self, connector: U) -> MqttConnector<A, U>
     where
-        U: Service<Request = Connect<A>, Error = connect::ConnectError>,
+        U: Service<Request = Connect<A>, Error = ConnectError>,
         U::Response: AsyncRead + AsyncWrite + Unpin + 'static,
     {
         MqttConnector {
-            connector,
+            connector: Rc::new(connector),
+            resolver: self.resolver,
+            connect_timeout: self.connect_timeout,
+            pkt: self.pkt,
+            address: self.address,
+            handshake_timeout: self.handshake_timeout,
+            disconnect_timeout: self.disconnect_timeout,
+            timer: self.timer,
+            shutdown_timeout: self.shutdown_timeout,
+            max_inflight: self.max_inflight,
+            extension: self.extension,
+            pool: self.pool,
+            topic_prefix: self.topic_prefix,
+            publish_properties: self.publish_properties,
+        }
+    }
+
+    /// Connect through SOCKS5 or HTTP `CONNECT` proxy
+    ///
+    /// Use `ProxyConnector` with custom connector to start tls over proxy tunnel.
+    pub fn proxy(self, proxy: Proxy) -> MqttConnector<A, ProxyConnector<A>> {
+        MqttConnector {
+            connector: Rc::new(ProxyConnector::new(proxy)),
+            resolver: self.resolver,
+            connect_timeout: self.connect_timeout,
             pkt: self.pkt,
             address: self.address,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            timer: self.timer,
+            shutdown_timeout: self.shutdown_timeout,
+            max_inflight: self.max_inflight,
+            extension: self.extension,
             pool: self.pool,
+            topic_prefix: self.topic_prefix,
+            publish_properties: self.publish_properties,
         }
     }
 
@@ -198,10 +422,18 @@ where
         MqttConnector {
             pkt: self.pkt,
             address: self.address,
-            connector: OpensslConnector::new(connector),
+            connector: Rc::new(OpensslConnector::new(connector)),
+            resolver: self.resolver,
+            connect_timeout: self.connect_timeout,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            timer: self.timer,
+            shutdown_timeout: self.shutdown_timeout,
+            max_inflight: self.max_inflight,
+            extension: self.extension,
             pool: self.pool,
+            topic_prefix: self.topic_prefix,
+            publish_properties: self.publish_properties,
         }
     }
 
@@ -213,40 +445,103 @@ where
         MqttConnector {
             pkt: self.pkt,
             address: self.address,
-            connector: RustlsConnector::new(Arc::new(config)),
+            connector: Rc::new(RustlsConnector::new(Arc::new(config))),
+            resolver: self.resolver,
+            connect_timeout: self.connect_timeout,
+            handshake_timeout: self.handshake_timeout,
+            disconnect_timeout: self.disconnect_timeout,
+            timer: self.timer,
+            shutdown_timeout: self.shutdown_timeout,
+            max_inflight: self.max_inflight,
+            extension: self.extension,
+            pool: self.pool,
+            topic_prefix: self.topic_prefix,
+            publish_properties: self.publish_properties,
+        }
+    }
+
+    #[cfg(feature = "quic")]
+    /// Use QUIC transport (experimental)
+    pub fn quic(self, endpoint: quinn::Endpoint) -> MqttConnector<A, QuicConnector<A>> {
+        MqttConnector {
+            pkt: self.pkt,
+            address: self.address,
+            connector: Rc::new(QuicConnector::new(endpoint)),
+            resolver: self.resolver,
+            connect_timeout: self.connect_timeout,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            timer: self.timer,
+            shutdown_timeout: self.shutdown_timeout,
+            max_inflight: self.max_inflight,
+            extension: self.extension,
             pool: self.pool,
+            topic_prefix: self.topic_prefix,
+            publish_properties: self.publish_properties,
         }
     }
 
     /// Connect to mqtt server
     pub fn connect(&self) -> impl Future<Output = Result<Client<T::Response>, ClientError>> {
-        if self.handshake_timeout > 0 {
-            let fut = select(
-                delay_for(Duration::from_millis(self.handshake_timeout as u64)),
-                self._connect(),
-            );
+        let fut = connect::connect(
+            Connect::new(self.address.clone()),
+            self.resolver.clone(),
+            self.connector.clone(),
+            self.connect_timeout,
+        );
+        self.with_timeout(self._connect(async move { Ok(fut.await?) }))
+    }
+
+    /// Perform mqtt handshake over provided io object.
+    ///
+    /// Address and connector are not used, io must be already connected to mqtt server.
+    /// Could be used with in-memory transports or with transports managed by application.
+    pub fn connect_io<Io>(
+        &self,
+        io: Io,
+    ) -> impl Future<Output = Result<Client<Io>, ClientError>>
+    where
+        Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        self.with_timeout(self._connect(Ready::Ok(io)))
+    }
+
+    fn with_timeout<F, R>(&self, fut: F) -> impl Future<Output = Result<R, ClientError>>
+    where
+        F: Future<Output = Result<R, ClientError>>,
+    {
+        if self.handshake_timeout != Duration::ZERO {
+            let fut = select(delay_for(self.handshake_timeout), fut);
             Either::Left(async move {
                 let result = fut.await;
                 match result {
                     Either::Left(_) => Err(ClientError::HandshakeTimeout),
-                    Either::Right(res) => res.map_err(From::from),
+                    Either::Right(res) => res,
                 }
             })
         } else {
-            Either::Right(self._connect())
+            Either::Right(fut)
         }
     }
 
-    fn _connect(&self) -> impl Future<Output = Result<Client<T::Response>, ClientError>> {
-        let fut = self.connector.call(Connect::new(self.address.clone()));
+    fn _connect<F, Io>(&self, fut: F) -> impl Future<Output = Result<Client<Io>, ClientError>>
+    where
+        F: Future<Output = Result<Io, ClientError>>,
+        Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
         let pkt = self.pkt.clone();
         let keep_alive = pkt.keep_alive;
-        let max_packet_size = pkt.max_packet_size.map(|v| v.get()).unwrap_or(0);
+        let max_packet_size = pkt.maximum_packet_size();
         let max_receive = pkt.receive_max.map(|v| v.get()).unwrap_or(0);
+        let max_topic_alias = pkt.topic_alias_max;
         let disconnect_timeout = self.disconnect_timeout;
+        let timer = self.timer.clone();
+        let shutdown_timeout = self.shutdown_timeout;
+        let max_inflight = self.max_inflight;
+        let extension = self.extension.clone();
         let pool = self.pool.clone();
+        let topic_prefix = self.topic_prefix.clone();
+        let publish_properties = self.publish_properties.clone();
 
         async move {
             let mut io = fut.await?;
@@ -266,6 +561,12 @@ where
                     })
                 })?;
             let shared = Rc::new(MqttShared::new(state.clone(), codec, 0, pool));
+            shared.client.set(true);
+            *shared.topic_prefix.borrow_mut() = topic_prefix;
+            *shared.publish_properties.borrow_mut() = publish_properties;
+            if max_inflight != 0 {
+                shared.set_max_inflight(max_inflight as usize);
+            }
 
             match packet {
                 codec::Packet::ConnectAck(pkt) => {
@@ -276,17 +577,23 @@ where
                             shared.codec.set_max_outbound_size(size);
                         }
                         // server keep-alive
-                        let keep_alive = pkt.server_keepalive_sec.unwrap_or(keep_alive);
+                        let keep_alive = pkt.server_keep_alive(keep_alive);
 
-                        shared.cap.set(pkt.receive_max.map(|v| v.get()).unwrap_or(0) as usize);
+                        shared.set_receive_max(pkt.receive_maximum() as usize);
+                        if let Some(ref f) = extension {
+                            shared.set_extension((*f)(&pkt));
+                        }
 
                         Ok(Client::new(
                             io,
                             shared,
                             pkt,
                             max_receive,
+                            max_topic_alias,
                             keep_alive,
                             disconnect_timeout,
+                            timer,
+                            shutdown_timeout,
                         ))
                     } else {
                         Err(ClientError::Ack(pkt))
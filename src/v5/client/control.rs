@@ -1,4 +1,4 @@
-use crate::{error, v5::codec};
+use crate::{error, io::ShutdownStatus, session::SessionEnd, v5::codec};
 
 pub use crate::v5::control::{Closed, ControlResult, Disconnect, Error, ProtocolError};
 
@@ -11,6 +11,8 @@ pub enum ControlMessage<E> {
     Error(Error<E>),
     /// Protocol level error
     ProtocolError(ProtocolError),
+    /// Packet that does not correspond to any in-flight request
+    Unsolicited(Unsolicited),
     /// Connection closed
     Closed(Closed),
 }
@@ -24,8 +26,17 @@ impl<E> ControlMessage<E> {
         ControlMessage::Disconnect(Disconnect(pkt))
     }
 
-    pub(super) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(super) fn unsolicited(pkt: codec::Packet) -> Self {
+        ControlMessage::Unsolicited(Unsolicited(pkt))
+    }
+
+    pub(super) fn closed(
+        is_error: bool,
+        disconnect: Option<codec::Disconnect>,
+        shutdown: Option<ShutdownStatus>,
+        session_end: SessionEnd,
+    ) -> Self {
+        ControlMessage::Closed(Closed::new(is_error, disconnect, shutdown, session_end))
     }
 
     pub(super) fn error(err: E) -> Self {
@@ -58,3 +69,26 @@ impl Publish {
         ControlResult { packet: response.map(codec::Packet::PublishAck), disconnect: false }
     }
 }
+
+/// Unsolicited packet
+///
+/// Acknowledgement for unknown packet id or subscribe/unsubscribe packet
+/// sent by server. Default control service closes connection.
+pub struct Unsolicited(codec::Packet);
+
+impl Unsolicited {
+    /// Returns reference to packet
+    pub fn packet(&self) -> &codec::Packet {
+        &self.0
+    }
+
+    /// Returns packet
+    pub fn into_inner(self) -> codec::Packet {
+        self.0
+    }
+
+    /// Ignore packet and keep connection open
+    pub fn ack(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: false }
+    }
+}
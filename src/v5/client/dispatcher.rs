@@ -40,6 +40,7 @@ where
 pub(crate) struct Dispatcher<T, C, E> {
     publish: T,
     shutdown: Cell<bool>,
+    disconnect: RefCell<Option<codec::Disconnect>>,
     max_receive: usize,
     max_topic_alias: u16,
     inner: Rc<Inner<C>>,
@@ -78,6 +79,7 @@ where
             max_receive,
             max_topic_alias,
             shutdown: Cell::new(false),
+            disconnect: RefCell::new(None),
             inner: Rc::new(Inner {
                 control,
                 sink,
@@ -124,7 +126,17 @@ where
         if !self.shutdown.get() {
             self.inner.sink.drop_sink();
             self.shutdown.set(true);
-            let fut = self.inner.control.call(ControlMessage::closed(is_error));
+            let disconnect = self.disconnect.borrow_mut().take();
+            let clean = std::matches!(
+                disconnect,
+                Some(ref pkt) if pkt.reason_code == codec::DisconnectReasonCode::NormalDisconnection
+            );
+            let fut = self.inner.control.call(ControlMessage::closed(
+                is_error,
+                disconnect,
+                self.inner.sink.shutdown_status(),
+                self.inner.sink.session_end(clean),
+            ));
             ntex::rt::spawn(async move {
                 let _ = fut.await;
             });
@@ -161,13 +173,10 @@ where
 
                         // check for duplicated packet id
                         if !inner.inflight.insert(pid) {
-                            self.inner.sink.send(codec::Packet::PublishAck(
-                                codec::PublishAck {
-                                    packet_id: pid,
-                                    reason_code: codec::PublishAckReason::PacketIdentifierInUse,
-                                    ..Default::default()
-                                },
-                            ));
+                            self.inner.sink.send(
+                                PublishAck::new(codec::PublishAckReason::PacketIdentifierInUse)
+                                    .into_packet(pid, publish.qos),
+                            );
                             return Either::Right(Either::Left(Ready::Ok(None)));
                         }
                     }
@@ -200,6 +209,7 @@ where
 
                 Either::Left(PublishResponse {
                     packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
+                    qos: publish.qos,
                     inner: info,
                     state: PublishResponseState::Publish {
                         fut: self.publish.call(Publish::new(publish)),
@@ -207,7 +217,25 @@ where
                     _t: PhantomData,
                 })
             }
-            DispatchItem::Item(codec::Packet::PublishAck(packet)) => {
+            DispatchItem::Item(codec::Packet::PublishRelease(packet)) => {
+                let reason_code =
+                    if self.inner.info.borrow_mut().inflight.remove(&packet.packet_id) {
+                        codec::PublishAck2Reason::Success
+                    } else {
+                        codec::PublishAck2Reason::PacketIdNotFound
+                    };
+                Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PublishComplete(
+                    codec::PublishAck2 {
+                        packet_id: packet.packet_id,
+                        reason_code,
+                        properties: codec::UserProperties::new(),
+                        reason_string: None,
+                    },
+                )))))
+            }
+            DispatchItem::Item(codec::Packet::PublishAck(packet))
+                if self.inner.sink.is_inflight(packet.packet_id.get()) =>
+            {
                 if let Err(err) = self.inner.sink.pkt_ack(Ack::Publish(packet)) {
                     Either::Right(Either::Right(ControlResponse::new(
                         ControlMessage::proto_error(err),
@@ -217,7 +245,9 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
-            DispatchItem::Item(codec::Packet::SubscribeAck(packet)) => {
+            DispatchItem::Item(codec::Packet::SubscribeAck(packet))
+                if self.inner.sink.is_inflight(packet.packet_id.get()) =>
+            {
                 if let Err(err) = self.inner.sink.pkt_ack(Ack::Subscribe(packet)) {
                     Either::Right(Either::Right(ControlResponse::new(
                         ControlMessage::proto_error(err),
@@ -227,7 +257,9 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
-            DispatchItem::Item(codec::Packet::UnsubscribeAck(packet)) => {
+            DispatchItem::Item(codec::Packet::UnsubscribeAck(packet))
+                if self.inner.sink.is_inflight(packet.packet_id.get()) =>
+            {
                 if let Err(err) = self.inner.sink.pkt_ack(Ack::Unsubscribe(packet)) {
                     Either::Right(Either::Right(ControlResponse::new(
                         ControlMessage::proto_error(err),
@@ -240,37 +272,37 @@ where
             DispatchItem::Item(codec::Packet::PingRequest) => {
                 Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PingResponse))))
             }
-            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::dis(pkt), &self.inner),
-            )),
-            DispatchItem::Item(codec::Packet::Auth(_)) => {
+            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => {
+                *self.disconnect.borrow_mut() = Some(pkt.clone());
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::Unexpected(
-                        packet_type::AUTH,
-                        "Auth packet is not supported",
-                    )),
+                    ControlMessage::dis(pkt),
                     &self.inner,
                 )))
             }
-            DispatchItem::Item(codec::Packet::Subscribe(_)) => {
+            DispatchItem::Item(codec::Packet::Auth(_)) => {
                 Either::Right(Either::Right(ControlResponse::new(
                     ControlMessage::proto_error(ProtocolError::Unexpected(
-                        packet_type::SUBSCRIBE,
-                        "Subscribe packet is not supported",
+                        packet_type::AUTH,
+                        "Auth packet is not supported",
                     )),
                     &self.inner,
                 )))
             }
-            DispatchItem::Item(codec::Packet::Unsubscribe(_)) => {
+            DispatchItem::Item(
+                pkt @ codec::Packet::PublishAck(_)
+                | pkt @ codec::Packet::SubscribeAck(_)
+                | pkt @ codec::Packet::UnsubscribeAck(_)
+                | pkt @ codec::Packet::Subscribe(_)
+                | pkt @ codec::Packet::Unsubscribe(_),
+            ) => {
+                log::trace!("Unsolicited packet: {:?}", pkt);
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::Unexpected(
-                        packet_type::UNSUBSCRIBE,
-                        "Unsubscribe packet is not supported",
-                    )),
+                    ControlMessage::unsolicited(pkt),
                     &self.inner,
                 )))
             }
             DispatchItem::Item(codec::Packet::PingResponse) => {
+                self.inner.sink.pong();
                 Either::Right(Either::Left(Ready::Ok(None)))
             }
             DispatchItem::Item(pkt) => {
@@ -312,6 +344,7 @@ pin_project_lite::pin_project! {
         #[pin]
         state: PublishResponseState<T, C, E>,
         packet_id: u16,
+        qos: codec::QoS,
         inner: Rc<Inner<C>>,
         _t: PhantomData<E>,
     }
@@ -364,14 +397,11 @@ where
                     Poll::Pending => return Poll::Pending,
                 };
                 if let Some(id) = NonZeroU16::new(*this.packet_id) {
-                    this.inner.info.borrow_mut().inflight.remove(&id);
-                    let ack = codec::PublishAck {
-                        packet_id: id,
-                        reason_code: ack.reason_code,
-                        reason_string: ack.reason_string,
-                        properties: ack.properties,
-                    };
-                    Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    // qos2 packet id is in use until PUBREL is received
+                    if *this.qos != codec::QoS::ExactlyOnce || ack.is_error() {
+                        this.inner.info.borrow_mut().inflight.remove(&id);
+                    }
+                    Poll::Ready(Ok(Some(ack.into_packet(id, *this.qos))))
                 } else {
                     Poll::Ready(Ok(None))
                 }
@@ -1,12 +1,18 @@
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
 
-use ntex::codec::{Decoder, Encoder};
-use ntex::util::{Buf, BytesMut};
+use ntex_bytes::{Buf, Bytes, BytesMut};
+use ntex_codec::{Decoder, Encoder};
 
-use super::{decode::decode_packet, encode::EncodeLtd, Packet};
+use super::{decode::decode_packet, encode::var_int_len, encode::EncodeLtd, Packet};
+use crate::budget::{DecodeGuard, DecodeStats};
+use crate::capture::{Direction, Recorder};
 use crate::error::{DecodeError, EncodeError};
 use crate::types::{FixedHeader, MAX_PACKET_SIZE};
-use crate::utils::decode_variable_length;
+use crate::utils::{capture_malformed, check_topic_limits, decode_variable_length};
+
+/// Protocol version of captured packets
+const VERSION: u8 = 5;
 
 #[derive(Debug)]
 pub struct Codec {
@@ -14,6 +20,13 @@ pub struct Codec {
     max_in_size: Cell<u32>,
     max_out_size: Cell<u32>,
     flags: Cell<CodecFlags>,
+    max_topic_length: Cell<u16>,
+    max_topic_levels: Cell<u16>,
+    max_client_id_length: Cell<u16>,
+    capture_size: Cell<usize>,
+    malformed: RefCell<Option<Bytes>>,
+    recorder: RefCell<Option<Recorder>>,
+    guard: DecodeGuard,
 }
 
 bitflags::bitflags! {
@@ -36,6 +49,13 @@ impl Codec {
             max_in_size: Cell::new(0),
             max_out_size: Cell::new(0),
             flags: Cell::new(CodecFlags::empty()),
+            max_topic_length: Cell::new(0),
+            max_topic_levels: Cell::new(0),
+            max_client_id_length: Cell::new(0),
+            capture_size: Cell::new(0),
+            malformed: RefCell::new(None),
+            recorder: RefCell::new(None),
+            guard: DecodeGuard::default(),
         }
     }
 
@@ -76,6 +96,208 @@ impl Codec {
     pub fn set_max_outbound_size(&self, size: u32) {
         self.max_out_size.set(size);
     }
+
+    /// Set max length of inbound topic names and topic filters in bytes.
+    ///
+    /// If max length is set to `0`, length is unlimited.
+    /// By default max length is set to `0`
+    pub fn max_topic_length(self, len: u16) -> Self {
+        self.max_topic_length.set(len);
+        self
+    }
+
+    /// Set max number of levels of inbound topic names and topic filters.
+    ///
+    /// If max levels is set to `0`, number of levels is unlimited.
+    /// By default max levels is set to `0`
+    pub fn max_topic_levels(self, levels: u16) -> Self {
+        self.max_topic_levels.set(levels);
+        self
+    }
+
+    /// Set max length of client id in `Connect` packet.
+    ///
+    /// If max length is set to `0`, length is unlimited.
+    /// By default max length is set to `0`
+    pub fn max_client_id_length(self, len: u16) -> Self {
+        self.max_client_id_length.set(len);
+        self
+    }
+
+    /// Set max length of inbound topic names and topic filters in bytes.
+    ///
+    /// If max length is set to `0`, length is unlimited.
+    /// By default max length is set to `0`
+    pub fn set_max_topic_length(&self, len: u16) {
+        self.max_topic_length.set(len);
+    }
+
+    /// Set max number of levels of inbound topic names and topic filters.
+    ///
+    /// If max levels is set to `0`, number of levels is unlimited.
+    /// By default max levels is set to `0`
+    pub fn set_max_topic_levels(&self, levels: u16) {
+        self.max_topic_levels.set(levels);
+    }
+
+    /// Set max length of client id in `Connect` packet.
+    ///
+    /// If max length is set to `0`, length is unlimited.
+    /// By default max length is set to `0`
+    pub fn set_max_client_id_length(&self, len: u16) {
+        self.max_client_id_length.set(len);
+    }
+
+    /// Capture bytes of inbound packets that fail to decode.
+    ///
+    /// Fixed header and at most `size` bytes of packet body are captured
+    /// and logged, last captured packet is available via `malformed_packet()`.
+    /// If size is set to `0`, capture is disabled.
+    /// By default capture is disabled
+    pub fn capture_malformed(self, size: usize) -> Self {
+        self.capture_size.set(size);
+        self
+    }
+
+    /// Capture bytes of inbound packets that fail to decode.
+    ///
+    /// If size is set to `0`, capture is disabled.
+    /// By default capture is disabled
+    pub fn set_capture_malformed(&self, size: usize) {
+        self.capture_size.set(size);
+    }
+
+    /// Returns captured bytes of last malformed inbound packet
+    pub fn malformed_packet(&self) -> Option<Bytes> {
+        self.malformed.borrow().clone()
+    }
+
+    /// Set decode time budget for inbound packets.
+    ///
+    /// Decoder fails with `DecodeError::DecodeBudgetExceeded` after `max_violations`
+    /// consecutive packets took longer than `budget` to decode.
+    /// If budget is set to `0`, check is disabled. By default check is disabled
+    pub fn set_decode_budget(&self, budget: Duration, max_violations: u32) {
+        self.guard.set_budget(budget, max_violations);
+    }
+
+    /// Set inbound packet decode statistics.
+    ///
+    /// By default statistics are not collected
+    pub fn set_decode_stats(&self, stats: Option<DecodeStats>) {
+        self.guard.set_stats(stats);
+    }
+
+    /// Set packet recorder.
+    ///
+    /// Decoded inbound and encoded outbound packets are written to recorder.
+    /// By default recording is disabled
+    pub fn set_recorder(&self, recorder: Option<Recorder>) {
+        *self.recorder.borrow_mut() = recorder;
+    }
+
+    fn record(&self, direction: Direction, packet: &[u8]) {
+        if let Some(ref recorder) = *self.recorder.borrow() {
+            recorder.record(VERSION, direction, packet);
+        }
+    }
+
+    fn malformed(
+        &self,
+        err: DecodeError,
+        fixed: Option<FixedHeader>,
+        buf: &[u8],
+    ) -> DecodeError {
+        let size = self.capture_size.get();
+        if size != 0 {
+            *self.malformed.borrow_mut() = Some(capture_malformed(&err, fixed, buf, size));
+        }
+        err
+    }
+
+    /// Returns size of encoded packet, including fixed header
+    pub fn encoded_size(&self, item: &Packet) -> usize {
+        let content_size = if self.flags.get().contains(CodecFlags::NO_PROBLEM_INFO) {
+            let mut item = item.clone();
+            strip_problem_info(&mut item);
+            item.encoded_size(self.max_out_size())
+        } else {
+            item.encoded_size(self.max_out_size())
+        };
+        1 + var_int_len(content_size) as usize + content_size
+    }
+
+    /// Encode packet into provided buffer.
+    ///
+    /// Buffer is extended to fit encoded packet.
+    pub fn encode_into(&self, item: &Packet, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        // handle [MQTT 3.1.2.11.7]
+        if self.flags.get().contains(CodecFlags::NO_PROBLEM_INFO) {
+            let mut item = item.clone();
+            strip_problem_info(&mut item);
+            self.encode_packet(&item, dst)
+        } else {
+            self.encode_packet(item, dst)
+        }
+    }
+
+    fn max_out_size(&self) -> u32 {
+        let max_out_size = self.max_out_size.get();
+        if max_out_size != 0 {
+            max_out_size
+        } else {
+            MAX_PACKET_SIZE
+        }
+    }
+
+    fn encode_packet(&self, item: &Packet, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        let max_size = self.max_out_size();
+        let content_size = item.encoded_size(max_size);
+        if content_size > max_size as usize {
+            return Err(EncodeError::InvalidLength); // todo: separate error code
+        }
+        dst.reserve(content_size + 5);
+        let start = dst.len();
+        item.encode(dst, content_size as u32)?; // safe: max_size <= u32 max value
+        self.record(Direction::Outbound, &dst[start..]);
+        Ok(())
+    }
+
+    fn check_limits(&self, packet: &Packet) -> Result<(), DecodeError> {
+        let max_len = self.max_topic_length.get();
+        let max_levels = self.max_topic_levels.get();
+
+        match packet {
+            Packet::Connect(pkt) => {
+                let max = self.max_client_id_length.get() as usize;
+                ensure!(max == 0 || pkt.client_id.len() <= max, DecodeError::InvalidClientId);
+            }
+            Packet::Publish(pkt) => {
+                ensure!(
+                    check_topic_limits(&pkt.topic, max_len, max_levels),
+                    DecodeError::InvalidTopic
+                );
+            }
+            Packet::Subscribe(pkt) => {
+                ensure!(
+                    pkt.topic_filters
+                        .iter()
+                        .all(|(f, _)| check_topic_limits(f, max_len, max_levels)),
+                    DecodeError::InvalidTopicFilter
+                );
+            }
+            Packet::Unsubscribe(pkt) => {
+                ensure!(
+                    pkt.topic_filters
+                        .iter()
+                        .all(|f| check_topic_limits(f, max_len, max_levels)),
+                    DecodeError::InvalidTopicFilter
+                );
+            }
+            _ => (),
+        }
+        Ok(())
+    }
 }
 
 impl Default for Codec {
@@ -97,7 +319,9 @@ impl Decoder for Codec {
                     }
                     let src_slice = src.as_ref();
                     let first_byte = src_slice[0];
-                    match decode_variable_length(&src_slice[1..])? {
+                    match decode_variable_length(&src_slice[1..])
+                        .map_err(|err| self.malformed(err, None, src_slice))?
+                    {
                         Some((remaining_length, consumed)) => {
                             // check max message size
                             let max_in_size = self.max_in_size.get();
@@ -107,7 +331,11 @@ impl Decoder for Codec {
                                     max_in_size,
                                     remaining_length
                                 );
-                                return Err(DecodeError::MaxSizeExceeded);
+                                return Err(self.malformed(
+                                    DecodeError::MaxSizeExceeded,
+                                    None,
+                                    src_slice,
+                                ));
                             }
                             src.advance(consumed + 1);
                             self.state.set(DecodeState::Frame(FixedHeader {
@@ -132,7 +360,9 @@ impl Decoder for Codec {
                         return Ok(None);
                     }
                     let packet_buf = src.split_to(fixed.remaining_length as usize).freeze();
-                    let packet = decode_packet(packet_buf, fixed.first_byte)?;
+                    let started = self.guard.start();
+                    let packet = decode_packet(packet_buf.clone(), fixed.first_byte)
+                        .map_err(|err| self.malformed(err, Some(fixed), &packet_buf))?;
                     self.state.set(DecodeState::FrameHeader);
                     src.reserve(5); // enough to fix 1 fixed header byte + 4 bytes max variable packet length
 
@@ -141,6 +371,14 @@ impl Decoder for Codec {
                         flags.set(CodecFlags::NO_PROBLEM_INFO, !pkt.request_problem_info);
                         self.flags.set(flags);
                     }
+                    self.check_limits(&packet)
+                        .map_err(|err| self.malformed(err, Some(fixed), &packet_buf))?;
+                    if !self.guard.finish(started, fixed.remaining_length) {
+                        return Err(DecodeError::DecodeBudgetExceeded);
+                    }
+                    if let Some(ref recorder) = *self.recorder.borrow() {
+                        recorder.record_inbound(VERSION, fixed.first_byte, &packet_buf);
+                    }
                     return Ok(Some(packet));
                 }
             }
@@ -155,46 +393,41 @@ impl Encoder for Codec {
     fn encode(&self, mut item: Self::Item, dst: &mut BytesMut) -> Result<(), EncodeError> {
         // handle [MQTT 3.1.2.11.7]
         if self.flags.get().contains(CodecFlags::NO_PROBLEM_INFO) {
-            match item {
-                Packet::PublishAck(ref mut pkt) | Packet::PublishReceived(ref mut pkt) => {
-                    pkt.properties.clear();
-                    let _ = pkt.reason_string.take();
-                }
-                Packet::PublishRelease(ref mut pkt) | Packet::PublishComplete(ref mut pkt) => {
-                    pkt.properties.clear();
-                    let _ = pkt.reason_string.take();
-                }
-                Packet::Subscribe(ref mut pkt) => {
-                    pkt.user_properties.clear();
-                }
-                Packet::SubscribeAck(ref mut pkt) => {
-                    pkt.properties.clear();
-                    let _ = pkt.reason_string.take();
-                }
-                Packet::Unsubscribe(ref mut pkt) => {
-                    pkt.user_properties.clear();
-                }
-                Packet::UnsubscribeAck(ref mut pkt) => {
-                    pkt.properties.clear();
-                    let _ = pkt.reason_string.take();
-                }
-                Packet::Auth(ref mut pkt) => {
-                    pkt.user_properties.clear();
-                    let _ = pkt.reason_string.take();
-                }
-                _ => (),
-            }
+            strip_problem_info(&mut item);
         }
+        self.encode_packet(&item, dst)
+    }
+}
 
-        let max_out_size = self.max_out_size.get();
-        let max_size = if max_out_size != 0 { max_out_size } else { MAX_PACKET_SIZE };
-        let content_size = item.encoded_size(max_size);
-        if content_size > max_size as usize {
-            return Err(EncodeError::InvalidLength); // todo: separate error code
+fn strip_problem_info(item: &mut Packet) {
+    match item {
+        Packet::PublishAck(pkt) | Packet::PublishReceived(pkt) => {
+            pkt.properties.clear();
+            let _ = pkt.reason_string.take();
         }
-        dst.reserve(content_size + 5);
-        item.encode(dst, content_size as u32)?; // safe: max_size <= u32 max value
-        Ok(())
+        Packet::PublishRelease(pkt) | Packet::PublishComplete(pkt) => {
+            pkt.properties.clear();
+            let _ = pkt.reason_string.take();
+        }
+        Packet::Subscribe(pkt) => {
+            pkt.user_properties.clear();
+        }
+        Packet::SubscribeAck(pkt) => {
+            pkt.properties.clear();
+            let _ = pkt.reason_string.take();
+        }
+        Packet::Unsubscribe(pkt) => {
+            pkt.user_properties.clear();
+        }
+        Packet::UnsubscribeAck(pkt) => {
+            pkt.properties.clear();
+            let _ = pkt.reason_string.take();
+        }
+        Packet::Auth(pkt) => {
+            pkt.user_properties.clear();
+            let _ = pkt.reason_string.take();
+        }
+        _ => (),
     }
 }
 
@@ -209,4 +442,61 @@ mod tests {
         buf.extend_from_slice(b"\0\x09");
         assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
     }
+
+    #[test]
+    fn test_encode_into() {
+        use ntex_bytes::ByteString;
+
+        let codec = Codec::new();
+        let pkt = Packet::PublishAck(super::super::PublishAck {
+            packet_id: std::num::NonZeroU16::new(1).unwrap(),
+            reason_code: super::super::PublishAckReason::Success,
+            properties: Vec::new(),
+            reason_string: Some(ByteString::from_static("reason")),
+        });
+
+        let mut buf = BytesMut::new();
+        codec.encode_into(&pkt, &mut buf).unwrap();
+        let size = buf.len();
+        assert_eq!(codec.encoded_size(&pkt), size);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(pkt.clone()));
+
+        // problem info is stripped
+        let mut flags = codec.flags.get();
+        flags.insert(CodecFlags::NO_PROBLEM_INFO);
+        codec.flags.set(flags);
+
+        let mut buf = BytesMut::new();
+        codec.encode_into(&pkt, &mut buf).unwrap();
+        assert_eq!(codec.encoded_size(&pkt), buf.len());
+        assert!(buf.len() < size);
+    }
+
+    #[test]
+    fn test_topic_limits() {
+        use ntex_bytes::{ByteString, Bytes};
+
+        let codec = Codec::new().max_topic_length(8).max_topic_levels(2);
+        let pkt = |topic| {
+            Packet::Publish(super::super::Publish {
+                dup: false,
+                retain: false,
+                qos: crate::types::QoS::AtMostOnce,
+                topic: ByteString::from_static(topic),
+                packet_id: None,
+                payload: Bytes::new(),
+                properties: Default::default(),
+            })
+        };
+
+        let mut buf = BytesMut::new();
+        codec.encode(pkt("a/b"), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(pkt("a/b")));
+
+        codec.encode(pkt("a/b/c"), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::InvalidTopic));
+
+        codec.encode(pkt("topic/long"), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::InvalidTopic));
+    }
 }
@@ -1,4 +1,4 @@
-use ntex::util::{ByteString, Bytes};
+use ntex_bytes::{ByteString, Bytes};
 
 use super::{packet::*, UserProperty};
 use crate::error::DecodeError;
@@ -38,8 +38,8 @@ impl Decode for UserProperty {
 
 #[cfg(test)]
 mod tests {
-    use ntex::util::{Bytes, BytesMut};
-    use std::num::NonZeroU16;
+    use ntex_bytes::{Bytes, BytesMut};
+    use std::num::{NonZeroU16, NonZeroU32};
 
     use super::*;
     use crate::types::QoS;
@@ -56,12 +56,8 @@ mod tests {
         let (_len, consumed) = decode_variable_length(&bytes[1..]).unwrap().unwrap();
         let cur = Bytes::copy_from_slice(&bytes[consumed + 1..]);
         let mut tmp = BytesMut::with_capacity(4096);
-        ntex::codec::Encoder::encode(
-            &mut crate::v5::codec::Codec::new(),
-            res.clone(),
-            &mut tmp,
-        )
-        .unwrap();
+        ntex_codec::Encoder::encode(&mut crate::v5::codec::Codec::new(), res.clone(), &mut tmp)
+            .unwrap();
         let decoded = decode_packet(cur, fixed);
         let res = Ok(res);
         if decoded != res {
@@ -336,4 +332,134 @@ mod tests {
         assert_decode_packet(b"\xc0\x00", Packet::PingRequest);
         assert_decode_packet(b"\xd0\x00", Packet::PingResponse);
     }
+
+    #[test]
+    fn test_encode_properties() {
+        let user_properties =
+            vec![(ByteString::from_static("key"), ByteString::from_static("value"))];
+
+        let p = Packet::Connect(Connect {
+            last_will: Some(LastWill {
+                qos: QoS::AtLeastOnce,
+                retain: false,
+                topic: ByteString::from_static("will"),
+                message: Bytes::from_static(b"data"),
+                will_delay_interval_sec: Some(10),
+                correlation_data: Some(Bytes::from_static(b"corr")),
+                message_expiry_interval: NonZeroU32::new(20),
+                content_type: Some(ByteString::from_static("text/plain")),
+                user_properties: user_properties.clone(),
+                is_utf8_payload: Some(true),
+                response_topic: Some(ByteString::from_static("resp")),
+            }),
+            ..Connect::default().client_id("client")
+        });
+        assert_eq!(Packet::from_bytes(p.to_bytes().unwrap()).unwrap(), p);
+
+        let mut publish = default_test_publish();
+        publish.qos = QoS::AtLeastOnce;
+        publish.properties.subscription_ids =
+            Some(vec![NonZeroU32::new(1).unwrap(), NonZeroU32::new(0x0F_FF_FF).unwrap()]);
+        let p = Packet::Publish(publish);
+        assert_eq!(Packet::from_bytes(p.to_bytes().unwrap()).unwrap(), p);
+
+        let p = Packet::Subscribe(Subscribe {
+            packet_id: packet_id(1),
+            id: NonZeroU32::new(300),
+            user_properties: user_properties.clone(),
+            topic_filters: vec![(
+                ByteString::from_static("a/b"),
+                SubscriptionOptions {
+                    qos: QoS::AtLeastOnce,
+                    no_local: true,
+                    retain_as_published: false,
+                    retain_handling: RetainHandling::NoAtSubscribe,
+                },
+            )],
+        });
+        assert_eq!(Packet::from_bytes(p.to_bytes().unwrap()).unwrap(), p);
+
+        let p = Packet::Unsubscribe(Unsubscribe {
+            packet_id: packet_id(1),
+            user_properties,
+            topic_filters: vec![ByteString::from_static("a/b")],
+        });
+        assert_eq!(Packet::from_bytes(p.to_bytes().unwrap()).unwrap(), p);
+    }
+
+    #[test]
+    fn test_raw_bytes() {
+        let pkt = Packet::Publish(Publish {
+            qos: QoS::AtLeastOnce,
+            topic: ByteString::from_static("topic"),
+            payload: Bytes::from_static(b"data"),
+            ..default_test_publish()
+        });
+        let raw = pkt.to_bytes().unwrap();
+        assert_eq!(Packet::from_bytes(raw.clone()).unwrap(), pkt);
+        assert_eq!(
+            Packet::from_bytes(raw.slice(..raw.len() - 1)),
+            Err(DecodeError::InvalidLength)
+        );
+
+        assert_eq!(
+            Packet::from_bytes(Bytes::from_static(b"\xd0\x00")),
+            Ok(Packet::PingResponse)
+        );
+    }
+
+    #[test]
+    fn test_property_defaults() {
+        let ack = ConnectAck::default();
+        assert_eq!(ack.receive_maximum(), 65535);
+        assert_eq!(ack.topic_alias_maximum(), 0);
+        assert_eq!(ack.maximum_qos(), QoS::ExactlyOnce);
+        assert_eq!(ack.maximum_packet_size(), 0);
+        assert!(ack.is_retain_available());
+        assert!(ack.is_shared_subscription_available());
+        assert_eq!(ack.server_keep_alive(30), 30);
+
+        let ack = ConnectAck {
+            receive_max: NonZeroU16::new(10),
+            max_qos: Some(QoS::AtLeastOnce),
+            retain_available: Some(false),
+            server_keepalive_sec: Some(5),
+            ..ConnectAck::default()
+        };
+        assert_eq!(ack.receive_maximum(), 10);
+        assert_eq!(ack.maximum_qos(), QoS::AtLeastOnce);
+        assert!(!ack.is_retain_available());
+        assert_eq!(ack.server_keep_alive(30), 5);
+
+        let connect = Connect::default();
+        assert_eq!(connect.session_expiry(), 0);
+        assert_eq!(connect.receive_maximum(), 65535);
+        assert_eq!(connect.maximum_packet_size(), 0);
+        let connect = Connect {
+            session_expiry_interval_secs: Some(60),
+            max_packet_size: NonZeroU32::new(1024),
+            ..Connect::default()
+        };
+        assert_eq!(connect.session_expiry(), 60);
+        assert_eq!(connect.maximum_packet_size(), 1024);
+
+        assert!(!PublishProperties::default().utf8_payload());
+    }
+
+    #[cfg(feature = "with-serde")]
+    #[test]
+    fn test_serde() {
+        let mut publish = default_test_publish();
+        publish.topic = ByteString::from_static("a/b");
+        publish.properties.content_type = Some(ByteString::from_static("text/plain"));
+        publish.properties.user_properties =
+            vec![(ByteString::from_static("key"), ByteString::from_static("value"))];
+        let pkt = Packet::Publish(publish);
+        let json = serde_json::to_string(&pkt).unwrap();
+        assert_eq!(serde_json::from_str::<Packet>(&json).unwrap(), pkt);
+
+        let pkt = Packet::Disconnect(Disconnect::new(DisconnectReasonCode::TopicNameInvalid));
+        let json = serde_json::to_string(&pkt).unwrap();
+        assert_eq!(serde_json::from_str::<Packet>(&json).unwrap(), pkt);
+    }
 }
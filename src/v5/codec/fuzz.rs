@@ -0,0 +1,290 @@
+//! Fuzzing support for mqtt v5 codec
+//!
+//! `Arbitrary` implementations always produce packets that are valid
+//! for the codec, so every generated packet must survive encode/decode.
+use arbitrary::{Arbitrary, Result, Unstructured};
+use ntex_bytes::BytesMut;
+use ntex_codec::Decoder;
+
+use super::*;
+use crate::types::QoS;
+use crate::utils::arbitrary::*;
+
+impl<'a> Arbitrary<'a> for LastWill {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(LastWill {
+            qos: u.arbitrary()?,
+            retain: u.arbitrary()?,
+            topic: string(u)?,
+            message: binary(u)?,
+            will_delay_interval_sec: u.arbitrary()?,
+            correlation_data: option(u, binary)?,
+            message_expiry_interval: option(u, non_zero_u32)?,
+            content_type: option(u, string)?,
+            user_properties: user_properties(u)?,
+            is_utf8_payload: u.arbitrary()?,
+            response_topic: option(u, string)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Connect {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let client_id = string(u)?;
+        Ok(Connect {
+            // empty client id is allowed only for clean start
+            clean_start: client_id.is_empty() || u.arbitrary()?,
+            keep_alive: u.arbitrary()?,
+            session_expiry_interval_secs: u.arbitrary()?,
+            auth_method: option(u, string)?,
+            auth_data: option(u, binary)?,
+            request_problem_info: u.arbitrary()?,
+            request_response_info: u.arbitrary()?,
+            receive_max: option(u, packet_id)?,
+            topic_alias_max: u.arbitrary()?,
+            user_properties: user_properties(u)?,
+            max_packet_size: option(u, non_zero_u32)?,
+            last_will: u.arbitrary()?,
+            client_id,
+            username: option(u, string)?,
+            password: option(u, binary)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for ConnectAck {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(ConnectAck {
+            session_present: u.arbitrary()?,
+            reason_code: u.arbitrary()?,
+            session_expiry_interval_secs: u.arbitrary()?,
+            receive_max: option(u, packet_id)?,
+            max_qos: u.arbitrary()?,
+            retain_available: u.arbitrary()?,
+            max_packet_size: u.arbitrary()?,
+            assigned_client_id: option(u, string)?,
+            topic_alias_max: u.arbitrary()?,
+            reason_string: option(u, string)?,
+            user_properties: user_properties(u)?,
+            wildcard_subscription_available: u.arbitrary()?,
+            subscription_identifiers_available: u.arbitrary()?,
+            shared_subscription_available: u.arbitrary()?,
+            server_keepalive_sec: u.arbitrary()?,
+            response_info: option(u, string)?,
+            server_reference: option(u, string)?,
+            auth_method: option(u, string)?,
+            auth_data: option(u, binary)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for PublishProperties {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        // empty list of subscription ids is not distinguishable from `None`
+        let subscription_ids = vec(u, 4, var_int)?;
+        Ok(PublishProperties {
+            topic_alias: option(u, packet_id)?,
+            correlation_data: option(u, binary)?,
+            message_expiry_interval: option(u, non_zero_u32)?,
+            content_type: option(u, string)?,
+            user_properties: user_properties(u)?,
+            is_utf8_payload: u.arbitrary()?,
+            response_topic: option(u, string)?,
+            subscription_ids: if subscription_ids.is_empty() {
+                None
+            } else {
+                Some(subscription_ids)
+            },
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Publish {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let qos = u.arbitrary()?;
+        Ok(Publish {
+            dup: u.arbitrary()?,
+            retain: u.arbitrary()?,
+            qos,
+            packet_id: if qos == QoS::AtMostOnce { None } else { Some(packet_id(u)?) },
+            topic: string(u)?,
+            properties: u.arbitrary()?,
+            payload: payload(u)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for PublishAck {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(PublishAck {
+            packet_id: packet_id(u)?,
+            reason_code: u.arbitrary()?,
+            properties: user_properties(u)?,
+            reason_string: option(u, string)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for PublishAck2 {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(PublishAck2 {
+            packet_id: packet_id(u)?,
+            reason_code: u.arbitrary()?,
+            properties: user_properties(u)?,
+            reason_string: option(u, string)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for SubscriptionOptions {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(SubscriptionOptions {
+            qos: u.arbitrary()?,
+            no_local: u.arbitrary()?,
+            retain_as_published: u.arbitrary()?,
+            retain_handling: u.arbitrary()?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Subscribe {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Subscribe {
+            packet_id: packet_id(u)?,
+            id: option(u, var_int)?,
+            user_properties: user_properties(u)?,
+            topic_filters: vec(u, 8, |u| Ok((string(u)?, u.arbitrary()?)))?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for SubscribeAck {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(SubscribeAck {
+            packet_id: packet_id(u)?,
+            properties: user_properties(u)?,
+            reason_string: option(u, string)?,
+            status: vec(u, 8, |u| u.arbitrary())?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Unsubscribe {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Unsubscribe {
+            packet_id: packet_id(u)?,
+            user_properties: user_properties(u)?,
+            topic_filters: vec(u, 8, string)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for UnsubscribeAck {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(UnsubscribeAck {
+            packet_id: packet_id(u)?,
+            properties: user_properties(u)?,
+            reason_string: option(u, string)?,
+            status: vec(u, 8, |u| u.arbitrary())?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Disconnect {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Disconnect {
+            reason_code: u.arbitrary()?,
+            session_expiry_interval_secs: u.arbitrary()?,
+            server_reference: option(u, string)?,
+            reason_string: option(u, string)?,
+            user_properties: user_properties(u)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Auth {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Auth {
+            reason_code: u.arbitrary()?,
+            auth_method: option(u, string)?,
+            auth_data: option(u, binary)?,
+            reason_string: option(u, string)?,
+            user_properties: user_properties(u)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Packet {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=14)? {
+            0 => Packet::Connect(u.arbitrary()?),
+            1 => Packet::ConnectAck(u.arbitrary()?),
+            2 => Packet::Publish(u.arbitrary()?),
+            3 => Packet::PublishAck(u.arbitrary()?),
+            4 => Packet::PublishReceived(u.arbitrary()?),
+            5 => Packet::PublishRelease(u.arbitrary()?),
+            6 => Packet::PublishComplete(u.arbitrary()?),
+            7 => Packet::Subscribe(u.arbitrary()?),
+            8 => Packet::SubscribeAck(u.arbitrary()?),
+            9 => Packet::Unsubscribe(u.arbitrary()?),
+            10 => Packet::UnsubscribeAck(u.arbitrary()?),
+            11 => Packet::PingRequest,
+            12 => Packet::PingResponse,
+            13 => Packet::Disconnect(u.arbitrary()?),
+            _ => Packet::Auth(u.arbitrary()?),
+        })
+    }
+}
+
+/// Encode packet, decode it back and check that result matches original packet.
+///
+/// Panics on mismatch.
+pub fn check_round_trip(pkt: &Packet) {
+    let codec = Codec::new();
+    let mut buf = BytesMut::new();
+    codec.encode_into(pkt, &mut buf).expect("valid packet must be encodable");
+    assert_eq!(codec.encoded_size(pkt), buf.len(), "encoded size mismatch for {:?}", pkt);
+
+    let decoded = codec.decode(&mut buf).expect("encoded packet must be decodable");
+    assert_eq!(decoded.as_ref(), Some(pkt));
+    assert!(buf.is_empty());
+}
+
+/// Round-trip harness for fuzz targets.
+///
+/// Input is decoded as a stream of raw packets, every successfully decoded packet
+/// must survive `check_round_trip()`. After that the same input is used to
+/// generate arbitrary packet, which is checked the same way.
+pub fn round_trip(data: &[u8]) {
+    let codec = Codec::new();
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(pkt)) = codec.decode(&mut buf) {
+        check_round_trip(&pkt);
+    }
+
+    if let Ok(pkt) = Packet::arbitrary(&mut Unstructured::new(data)) {
+        check_round_trip(&pkt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut seed = 0x2545_f491_u32;
+        let mut data = vec![0u8; 1024];
+
+        for _ in 0..2000 {
+            for b in data.iter_mut() {
+                // xorshift, deterministic input
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                *b = seed as u8;
+            }
+            round_trip(&data);
+        }
+    }
+}
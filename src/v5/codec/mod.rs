@@ -1,6 +1,6 @@
 //! MQTT v5 Protocol codec
 
-use ntex::util::ByteString;
+use ntex_bytes::ByteString;
 
 #[allow(clippy::module_inception)]
 mod codec;
@@ -8,8 +8,14 @@ mod decode;
 mod encode;
 mod packet;
 
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+#[cfg(any(test, feature = "proptest"))]
+pub mod strategy;
+
 pub use self::codec::Codec;
 pub use self::packet::*;
+pub use crate::reason::ReasonCode;
 
 pub type UserProperty = (ByteString, ByteString);
 pub type UserProperties = Vec<UserProperty>;
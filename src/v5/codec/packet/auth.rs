@@ -1,4 +1,4 @@
-use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
+use ntex_bytes::{Buf, BufMut, ByteString, Bytes, BytesMut};
 use std::convert::TryInto;
 
 use crate::error::{DecodeError, EncodeError};
@@ -7,6 +7,7 @@ use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserPrope
 
 /// AUTH message
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Auth {
     pub reason_code: AuthReasonCode,
     pub auth_method: Option<ByteString>,
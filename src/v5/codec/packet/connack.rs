@@ -1,4 +1,4 @@
-use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
+use ntex_bytes::{Buf, BufMut, ByteString, Bytes, BytesMut};
 use std::{convert::TryInto, num::NonZeroU16};
 
 use crate::error::{DecodeError, EncodeError};
@@ -8,6 +8,7 @@ use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserPrope
 
 /// Connect acknowledgment packet
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConnectAck {
     /// enables a Client to establish whether the Client and Server have a consistent view
     /// about whether there is already stored Session state.
@@ -102,6 +103,51 @@ impl ConnectAckReason {
 }
 
 impl ConnectAck {
+    /// Receive maximum, absent property means `65535`
+    pub fn receive_maximum(&self) -> u16 {
+        self.receive_max.map(|v| v.get()).unwrap_or(u16::MAX)
+    }
+
+    /// Topic alias maximum, `0` means topic aliases are not accepted
+    pub fn topic_alias_maximum(&self) -> u16 {
+        self.topic_alias_max
+    }
+
+    /// Maximum QoS, absent property means `QoS 2`
+    pub fn maximum_qos(&self) -> QoS {
+        self.max_qos.unwrap_or(QoS::ExactlyOnce)
+    }
+
+    /// Maximum packet size, absent property means no limit and is reported as `0`
+    pub fn maximum_packet_size(&self) -> u32 {
+        self.max_packet_size.unwrap_or(0)
+    }
+
+    /// Retained messages are supported, absent property means `true`
+    pub fn is_retain_available(&self) -> bool {
+        self.retain_available.unwrap_or(true)
+    }
+
+    /// Wildcard subscriptions are supported, absent property means `true`
+    pub fn is_wildcard_subscription_available(&self) -> bool {
+        self.wildcard_subscription_available.unwrap_or(true)
+    }
+
+    /// Subscription identifiers are supported, absent property means `true`
+    pub fn is_subscription_identifiers_available(&self) -> bool {
+        self.subscription_identifiers_available.unwrap_or(true)
+    }
+
+    /// Shared subscriptions are supported, absent property means `true`
+    pub fn is_shared_subscription_available(&self) -> bool {
+        self.shared_subscription_available.unwrap_or(true)
+    }
+
+    /// Keep alive to use, absent property means client's keep alive is accepted
+    pub fn server_keep_alive(&self, client_keep_alive: u16) -> u16 {
+        self.server_keepalive_sec.unwrap_or(client_keep_alive)
+    }
+
     pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
         ensure!(src.remaining() >= 2, DecodeError::InvalidLength);
         let flags = ConnectAckFlags::from_bits(src.get_u8())
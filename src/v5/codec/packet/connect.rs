@@ -1,4 +1,4 @@
-use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
+use ntex_bytes::{Buf, BufMut, ByteString, Bytes, BytesMut};
 use std::convert::TryFrom;
 use std::num::{NonZeroU16, NonZeroU32};
 
@@ -8,6 +8,7 @@ use crate::utils::{self, Decode, Encode, Property};
 use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserProperty};
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 /// Connect packet content
 pub struct Connect {
     /// the handling of the Session state.
@@ -36,6 +37,7 @@ pub struct Connect {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 /// Connection Will
 pub struct LastWill {
     /// the QoS level to be used when publishing the Will Message.
@@ -57,6 +59,16 @@ pub struct LastWill {
 }
 
 impl LastWill {
+    /// Will delay interval in seconds, absent property means `0`
+    pub fn will_delay(&self) -> u32 {
+        self.will_delay_interval_sec.unwrap_or(0)
+    }
+
+    /// Payload is UTF-8 encoded character data, absent property means unspecified bytes
+    pub fn utf8_payload(&self) -> bool {
+        self.is_utf8_payload.unwrap_or(false)
+    }
+
     fn properties_len(&self) -> usize {
         encoded_property_size(&self.will_delay_interval_sec)
             + encoded_property_size(&self.correlation_data)
@@ -88,6 +100,26 @@ impl Connect {
         self
     }
 
+    /// Session expiry interval in seconds, absent property means `0`
+    pub fn session_expiry(&self) -> u32 {
+        self.session_expiry_interval_secs.unwrap_or(0)
+    }
+
+    /// Receive maximum, absent property means `65535`
+    pub fn receive_maximum(&self) -> u16 {
+        self.receive_max.map(|v| v.get()).unwrap_or(u16::MAX)
+    }
+
+    /// Topic alias maximum, `0` means topic aliases are not accepted
+    pub fn topic_alias_maximum(&self) -> u16 {
+        self.topic_alias_max
+    }
+
+    /// Maximum packet size, absent property means no limit and is reported as `0`
+    pub fn maximum_packet_size(&self) -> u32 {
+        self.max_packet_size.map(|v| v.get()).unwrap_or(0)
+    }
+
     fn properties_len(&self) -> usize {
         let mut prop_len = encoded_property_size(&self.session_expiry_interval_secs)
             + encoded_property_size(&self.auth_method)
@@ -319,6 +351,13 @@ impl EncodeLtd for Connect {
         if let Some(will) = self.last_will.as_ref() {
             let prop_len = will.properties_len();
             utils::write_variable_length(prop_len as u32, buf); // safe: whole message size is checked for max already
+            encode_property(&will.will_delay_interval_sec, pt::WILL_DELAY_INT, buf)?;
+            encode_property(&will.correlation_data, pt::CORR_DATA, buf)?;
+            encode_property(&will.message_expiry_interval, pt::MSG_EXPIRY_INT, buf)?;
+            encode_property(&will.content_type, pt::CONTENT_TYPE, buf)?;
+            encode_property(&will.is_utf8_payload, pt::UTF8_PAYLOAD, buf)?;
+            encode_property(&will.response_topic, pt::RESP_TOPIC, buf)?;
+            will.user_properties.encode(buf)?;
 
             will.topic.encode(buf)?;
             will.message.encode(buf)?;
@@ -1,12 +1,14 @@
 use derive_more::From;
-use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
+use ntex_bytes::{Buf, BufMut, ByteString, Bytes, BytesMut};
 
 pub use crate::types::{ConnectAckFlags, ConnectFlags, QoS};
 
-use super::{encode::*, property_type as pt, UserProperties};
+use super::{decode::decode_packet, encode::*, property_type as pt, Codec, UserProperties};
 use crate::error::{DecodeError, EncodeError};
 use crate::types::packet_type;
-use crate::utils::{take_properties, write_variable_length, Decode, Property};
+use crate::utils::{
+    split_fixed_header, take_properties, write_variable_length, Decode, Property,
+};
 
 mod auth;
 mod connack;
@@ -25,6 +27,7 @@ pub use publish::*;
 pub use subscribe::*;
 
 #[derive(Debug, PartialEq, Clone, From)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 /// MQTT Control Packets
 pub enum Packet {
     /// Client request to connect to Server
@@ -62,6 +65,19 @@ pub enum Packet {
 }
 
 impl Packet {
+    /// Decode packet from raw bytes, including fixed header
+    pub fn from_bytes(mut src: Bytes) -> Result<Packet, DecodeError> {
+        let first_byte = split_fixed_header(&mut src)?;
+        decode_packet(src, first_byte)
+    }
+
+    /// Encode packet to raw bytes, including fixed header
+    pub fn to_bytes(&self) -> Result<Bytes, EncodeError> {
+        let mut buf = BytesMut::new();
+        Codec::new().encode_into(self, &mut buf)?;
+        Ok(buf.freeze())
+    }
+
     pub fn packet_type(&self) -> u8 {
         match self {
             Packet::Connect(_) => packet_type::CONNECT,
@@ -1,4 +1,4 @@
-use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
+use ntex_bytes::{Buf, BufMut, ByteString, Bytes, BytesMut};
 use std::{convert::TryInto, num::NonZeroU16};
 
 use super::ack_props;
@@ -10,6 +10,7 @@ const HEADER_LEN: u32 = 2 + 1; // packet id + reason code
 
 /// PUBACK/PUBREC message content
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PublishAck {
     /// Packet Identifier
     pub packet_id: NonZeroU16,
@@ -20,6 +21,7 @@ pub struct PublishAck {
 
 /// PUBREL/PUBCOMP message content
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PublishAck2 {
     /// Packet Identifier
     pub packet_id: NonZeroU16,
@@ -1,4 +1,4 @@
-use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
+use ntex_bytes::{Buf, BufMut, ByteString, Bytes, BytesMut};
 use std::{convert::TryFrom, fmt, num::NonZeroU16, num::NonZeroU32};
 
 use crate::error::{DecodeError, EncodeError};
@@ -8,6 +8,7 @@ use crate::v5::codec::{encode::*, property_type as pt, UserProperties};
 
 /// PUBLISH message
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Publish {
     /// this might be re-delivery of an earlier attempt to send the Packet.
     pub dup: bool,
@@ -36,6 +37,7 @@ impl fmt::Debug for Publish {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PublishProperties {
     pub topic_alias: Option<NonZeroU16>,
     pub correlation_data: Option<Bytes>,
@@ -62,6 +64,13 @@ impl Default for PublishProperties {
     }
 }
 
+impl PublishProperties {
+    /// Payload is UTF-8 encoded character data, absent property means unspecified bytes
+    pub fn utf8_payload(&self) -> bool {
+        self.is_utf8_payload.unwrap_or(false)
+    }
+}
+
 impl Publish {
     pub(crate) fn decode(mut src: Bytes, packet_flags: u8) -> Result<Self, DecodeError> {
         let topic = ByteString::decode(&mut src)?;
@@ -183,7 +192,7 @@ impl EncodeLtd for PublishProperties {
         if let Some(sub_ids) = self.subscription_ids.as_ref() {
             for sub_id in sub_ids.iter() {
                 buf.put_u8(pt::SUB_ID);
-                sub_id.encode(buf)?;
+                utils::write_variable_length(sub_id.get(), buf);
             }
         }
         self.user_properties.encode(buf)
@@ -1,4 +1,4 @@
-use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
+use ntex_bytes::{Buf, BufMut, ByteString, Bytes, BytesMut};
 use std::convert::TryInto;
 use std::num::{NonZeroU16, NonZeroU32};
 
@@ -10,6 +10,7 @@ use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserPrope
 
 /// Represents SUBSCRIBE packet
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Subscribe {
     /// Packet Identifier
     pub packet_id: NonZeroU16,
@@ -21,6 +22,7 @@ pub struct Subscribe {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubscriptionOptions {
     pub qos: QoS,
     pub no_local: bool,
@@ -36,8 +38,54 @@ prim_enum! {
     }
 }
 
+impl SubscriptionOptions {
+    /// Create subscription options with specified maximum QoS
+    ///
+    /// Retained messages are sent at subscribe, messages published by
+    /// the client are forwarded back to it.
+    pub fn new(qos: QoS) -> Self {
+        SubscriptionOptions {
+            qos,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::AtSubscribe,
+        }
+    }
+
+    /// Do not forward messages published by this client back to it
+    pub fn no_local(mut self, val: bool) -> Self {
+        self.no_local = val;
+        self
+    }
+
+    /// Keep retain flag of forwarded messages as published
+    pub fn retain_as_published(mut self, val: bool) -> Self {
+        self.retain_as_published = val;
+        self
+    }
+
+    /// Set retained messages handling at subscribe time
+    pub fn retain_handling(mut self, val: RetainHandling) -> Self {
+        self.retain_handling = val;
+        self
+    }
+}
+
+impl Default for SubscriptionOptions {
+    fn default() -> Self {
+        SubscriptionOptions::new(QoS::AtMostOnce)
+    }
+}
+
+impl From<QoS> for SubscriptionOptions {
+    fn from(qos: QoS) -> Self {
+        SubscriptionOptions::new(qos)
+    }
+}
+
 /// Represents SUBACK packet
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubscribeAck {
     pub packet_id: NonZeroU16,
     pub properties: UserProperties,
@@ -48,6 +96,7 @@ pub struct SubscribeAck {
 
 /// Represents UNSUBSCRIBE packet
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Unsubscribe {
     /// Packet Identifier
     pub packet_id: NonZeroU16,
@@ -58,6 +107,7 @@ pub struct Unsubscribe {
 
 /// Represents UNSUBACK packet
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UnsubscribeAck {
     /// Packet Identifier
     pub packet_id: NonZeroU16,
@@ -176,7 +226,7 @@ impl UnsubscribeAck {
 
 impl EncodeLtd for Subscribe {
     fn encoded_size(&self, _limit: u32) -> usize {
-        let prop_len = self.id.map_or(0, |v| var_int_len(v.get() as usize) as usize)
+        let prop_len = self.id.map_or(0, |v| 1 + var_int_len(v.get() as usize) as usize)
             + self.user_properties.encoded_size();
         let payload_len = self
             .topic_filters
@@ -188,10 +238,14 @@ impl EncodeLtd for Subscribe {
     fn encode(&self, buf: &mut BytesMut, _: u32) -> Result<(), EncodeError> {
         self.packet_id.encode(buf)?;
 
-        let prop_len = self.id.map_or(0, |v| var_int_len(v.get() as usize))
+        let prop_len = self.id.map_or(0, |v| 1 + var_int_len(v.get() as usize))
             + self.user_properties.encoded_size() as u32; // safe: size was already checked against maximum
         utils::write_variable_length(prop_len, buf);
-        encode_property(&self.id, pt::SUB_ID, buf)?;
+        if let Some(id) = self.id {
+            buf.put_u8(pt::SUB_ID);
+            utils::write_variable_length(id.get(), buf);
+        }
+        self.user_properties.encode(buf)?;
         for (filter, opts) in self.topic_filters.iter() {
             filter.encode(buf)?;
             opts.encode(buf)?;
@@ -268,6 +322,7 @@ impl EncodeLtd for Unsubscribe {
         self.packet_id.encode(buf)?;
         let prop_len = self.user_properties.encoded_size();
         utils::write_variable_length(prop_len as u32, buf); // safe: max size check is done already
+        self.user_properties.encode(buf)?;
         for filter in self.topic_filters.iter() {
             filter.encode(buf)?;
         }
@@ -0,0 +1,420 @@
+//! Proptest strategies for mqtt v5 packets
+//!
+//! Strategies always produce packets that are valid for the codec, so
+//! downstream property tests could feed them to encoder and decoder.
+use proptest::{collection, prelude::*};
+
+use super::*;
+use crate::types::QoS;
+use crate::utils::strategy::*;
+
+/// Strategy for connection will
+pub fn last_will() -> impl Strategy<Value = LastWill> {
+    (
+        (any::<QoS>(), any::<bool>(), string(), binary(), any::<Option<u32>>()),
+        (
+            opt(binary()),
+            opt(non_zero_u32()),
+            opt(string()),
+            user_properties(),
+            any::<Option<bool>>(),
+            opt(string()),
+        ),
+    )
+        .prop_map(
+            |(
+                (qos, retain, topic, message, will_delay_interval_sec),
+                (
+                    correlation_data,
+                    message_expiry_interval,
+                    content_type,
+                    user_properties,
+                    is_utf8_payload,
+                    response_topic,
+                ),
+            )| LastWill {
+                qos,
+                retain,
+                topic,
+                message,
+                will_delay_interval_sec,
+                correlation_data,
+                message_expiry_interval,
+                content_type,
+                user_properties,
+                is_utf8_payload,
+                response_topic,
+            },
+        )
+}
+
+/// Strategy for `CONNECT` packet
+pub fn connect() -> impl Strategy<Value = Connect> {
+    (
+        (
+            any::<bool>(),
+            any::<u16>(),
+            any::<Option<u32>>(),
+            opt(string()),
+            opt(binary()),
+            any::<bool>(),
+            any::<bool>(),
+            opt(packet_id()),
+        ),
+        (
+            any::<u16>(),
+            user_properties(),
+            opt(non_zero_u32()),
+            opt(last_will()),
+            string(),
+            opt(string()),
+            opt(binary()),
+        ),
+    )
+        .prop_map(
+            |(
+                (
+                    clean_start,
+                    keep_alive,
+                    session_expiry_interval_secs,
+                    auth_method,
+                    auth_data,
+                    request_problem_info,
+                    request_response_info,
+                    receive_max,
+                ),
+                (
+                    topic_alias_max,
+                    user_properties,
+                    max_packet_size,
+                    last_will,
+                    client_id,
+                    username,
+                    password,
+                ),
+            )| Connect {
+                // empty client id is allowed only for clean start
+                clean_start: clean_start || client_id.is_empty(),
+                keep_alive,
+                session_expiry_interval_secs,
+                auth_method,
+                auth_data,
+                request_problem_info,
+                request_response_info,
+                receive_max,
+                topic_alias_max,
+                user_properties,
+                max_packet_size,
+                last_will,
+                client_id,
+                username,
+                password,
+            },
+        )
+}
+
+/// Strategy for `CONNACK` packet
+pub fn connect_ack() -> impl Strategy<Value = ConnectAck> {
+    (
+        (
+            any::<bool>(),
+            any::<ConnectAckReason>(),
+            any::<Option<u32>>(),
+            opt(packet_id()),
+            any::<Option<QoS>>(),
+            any::<Option<bool>>(),
+            any::<Option<u32>>(),
+            opt(string()),
+            any::<u16>(),
+            opt(string()),
+        ),
+        (
+            user_properties(),
+            any::<Option<bool>>(),
+            any::<Option<bool>>(),
+            any::<Option<bool>>(),
+            any::<Option<u16>>(),
+            opt(string()),
+            opt(string()),
+            opt(string()),
+            opt(binary()),
+        ),
+    )
+        .prop_map(
+            |(
+                (
+                    session_present,
+                    reason_code,
+                    session_expiry_interval_secs,
+                    receive_max,
+                    max_qos,
+                    retain_available,
+                    max_packet_size,
+                    assigned_client_id,
+                    topic_alias_max,
+                    reason_string,
+                ),
+                (
+                    user_properties,
+                    wildcard_subscription_available,
+                    subscription_identifiers_available,
+                    shared_subscription_available,
+                    server_keepalive_sec,
+                    response_info,
+                    server_reference,
+                    auth_method,
+                    auth_data,
+                ),
+            )| ConnectAck {
+                session_present,
+                reason_code,
+                session_expiry_interval_secs,
+                receive_max,
+                max_qos,
+                retain_available,
+                max_packet_size,
+                assigned_client_id,
+                topic_alias_max,
+                reason_string,
+                user_properties,
+                wildcard_subscription_available,
+                subscription_identifiers_available,
+                shared_subscription_available,
+                server_keepalive_sec,
+                response_info,
+                server_reference,
+                auth_method,
+                auth_data,
+            },
+        )
+}
+
+/// Strategy for publish properties
+pub fn publish_properties() -> impl Strategy<Value = PublishProperties> {
+    (
+        opt(packet_id()),
+        opt(binary()),
+        opt(non_zero_u32()),
+        opt(string()),
+        user_properties(),
+        any::<Option<bool>>(),
+        opt(string()),
+        // empty list of subscription ids is not distinguishable from `None`
+        opt(collection::vec(var_int(), 1..4)),
+    )
+        .prop_map(
+            |(
+                topic_alias,
+                correlation_data,
+                message_expiry_interval,
+                content_type,
+                user_properties,
+                is_utf8_payload,
+                response_topic,
+                subscription_ids,
+            )| PublishProperties {
+                topic_alias,
+                correlation_data,
+                message_expiry_interval,
+                content_type,
+                user_properties,
+                is_utf8_payload,
+                response_topic,
+                subscription_ids,
+            },
+        )
+}
+
+/// Strategy for `PUBLISH` packet
+pub fn publish() -> impl Strategy<Value = Publish> {
+    (
+        any::<bool>(),
+        any::<bool>(),
+        any::<QoS>(),
+        packet_id(),
+        string(),
+        publish_properties(),
+        payload(),
+    )
+        .prop_map(|(dup, retain, qos, packet_id, topic, properties, payload)| Publish {
+            dup,
+            retain,
+            qos,
+            packet_id: if qos == QoS::AtMostOnce { None } else { Some(packet_id) },
+            topic,
+            properties,
+            payload,
+        })
+}
+
+/// Strategy for `PUBACK` and `PUBREC` packets
+pub fn publish_ack() -> impl Strategy<Value = PublishAck> {
+    (packet_id(), any::<PublishAckReason>(), user_properties(), opt(string())).prop_map(
+        |(packet_id, reason_code, properties, reason_string)| PublishAck {
+            packet_id,
+            reason_code,
+            properties,
+            reason_string,
+        },
+    )
+}
+
+/// Strategy for `PUBREL` and `PUBCOMP` packets
+pub fn publish_ack2() -> impl Strategy<Value = PublishAck2> {
+    (packet_id(), any::<PublishAck2Reason>(), user_properties(), opt(string())).prop_map(
+        |(packet_id, reason_code, properties, reason_string)| PublishAck2 {
+            packet_id,
+            reason_code,
+            properties,
+            reason_string,
+        },
+    )
+}
+
+/// Strategy for subscription options
+pub fn subscription_options() -> impl Strategy<Value = SubscriptionOptions> {
+    (any::<QoS>(), any::<bool>(), any::<bool>(), any::<RetainHandling>()).prop_map(
+        |(qos, no_local, retain_as_published, retain_handling)| SubscriptionOptions {
+            qos,
+            no_local,
+            retain_as_published,
+            retain_handling,
+        },
+    )
+}
+
+/// Strategy for `SUBSCRIBE` packet
+pub fn subscribe() -> impl Strategy<Value = Subscribe> {
+    (
+        packet_id(),
+        opt(var_int()),
+        user_properties(),
+        collection::vec((string(), subscription_options()), 0..8),
+    )
+        .prop_map(|(packet_id, id, user_properties, topic_filters)| Subscribe {
+            packet_id,
+            id,
+            user_properties,
+            topic_filters,
+        })
+}
+
+/// Strategy for `SUBACK` packet
+pub fn subscribe_ack() -> impl Strategy<Value = SubscribeAck> {
+    (
+        packet_id(),
+        user_properties(),
+        opt(string()),
+        collection::vec(any::<SubscribeAckReason>(), 0..8),
+    )
+        .prop_map(|(packet_id, properties, reason_string, status)| SubscribeAck {
+            packet_id,
+            properties,
+            reason_string,
+            status,
+        })
+}
+
+/// Strategy for `UNSUBSCRIBE` packet
+pub fn unsubscribe() -> impl Strategy<Value = Unsubscribe> {
+    (packet_id(), user_properties(), collection::vec(string(), 0..8)).prop_map(
+        |(packet_id, user_properties, topic_filters)| Unsubscribe {
+            packet_id,
+            user_properties,
+            topic_filters,
+        },
+    )
+}
+
+/// Strategy for `UNSUBACK` packet
+pub fn unsubscribe_ack() -> impl Strategy<Value = UnsubscribeAck> {
+    (
+        packet_id(),
+        user_properties(),
+        opt(string()),
+        collection::vec(any::<UnsubscribeAckReason>(), 0..8),
+    )
+        .prop_map(|(packet_id, properties, reason_string, status)| UnsubscribeAck {
+            packet_id,
+            properties,
+            reason_string,
+            status,
+        })
+}
+
+/// Strategy for `DISCONNECT` packet
+pub fn disconnect() -> impl Strategy<Value = Disconnect> {
+    (
+        any::<DisconnectReasonCode>(),
+        any::<Option<u32>>(),
+        opt(string()),
+        opt(string()),
+        user_properties(),
+    )
+        .prop_map(
+            |(
+                reason_code,
+                session_expiry_interval_secs,
+                server_reference,
+                reason_string,
+                user_properties,
+            )| Disconnect {
+                reason_code,
+                session_expiry_interval_secs,
+                server_reference,
+                reason_string,
+                user_properties,
+            },
+        )
+}
+
+/// Strategy for `AUTH` packet
+pub fn auth() -> impl Strategy<Value = Auth> {
+    (any::<AuthReasonCode>(), opt(string()), opt(binary()), opt(string()), user_properties())
+        .prop_map(|(reason_code, auth_method, auth_data, reason_string, user_properties)| {
+            Auth { reason_code, auth_method, auth_data, reason_string, user_properties }
+        })
+}
+
+/// Strategy for any packet
+pub fn packet() -> impl Strategy<Value = Packet> {
+    prop_oneof![
+        connect().prop_map(Packet::Connect),
+        connect_ack().prop_map(Packet::ConnectAck),
+        publish().prop_map(Packet::Publish),
+        publish_ack().prop_map(Packet::PublishAck),
+        publish_ack().prop_map(Packet::PublishReceived),
+        publish_ack2().prop_map(Packet::PublishRelease),
+        publish_ack2().prop_map(Packet::PublishComplete),
+        subscribe().prop_map(Packet::Subscribe),
+        subscribe_ack().prop_map(Packet::SubscribeAck),
+        unsubscribe().prop_map(Packet::Unsubscribe),
+        unsubscribe_ack().prop_map(Packet::UnsubscribeAck),
+        Just(Packet::PingRequest),
+        Just(Packet::PingResponse),
+        disconnect().prop_map(Packet::Disconnect),
+        auth().prop_map(Packet::Auth),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex_bytes::BytesMut;
+    use ntex_codec::Decoder;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn test_round_trip(pkt in packet()) {
+            let codec = Codec::new();
+            let mut buf = BytesMut::new();
+            codec.encode_into(&pkt, &mut buf).unwrap();
+            prop_assert_eq!(codec.encoded_size(&pkt), buf.len());
+
+            let decoded = codec.decode(&mut buf).unwrap();
+            prop_assert_eq!(decoded, Some(pkt));
+            prop_assert!(buf.is_empty());
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Publish payload compression
+//!
+//! Compression is negotiated with `compression` user property of `CONNECT` and
+//! `CONNACK` packets. Client requests compression, server enables it by adding
+//! the same property to `CONNACK`. After handshake, publish payloads larger than
+//! threshold are compressed with deflate and marked with `content-encoding`
+//! publish user property, other payloads are sent as is.
+//!
+//! ```rust,no_run
+//! use ntex_mqtt::v5::{self, compress::Compression};
+//!
+//! // server
+//! let server = v5::MqttServer::new(|h: v5::Handshake<ntex::rt::net::TcpStream>| async move {
+//!     Ok::<_, ()>(Compression::new().threshold(512).ack(h, ()))
+//! });
+//!
+//! // client
+//! let connector =
+//!     v5::client::MqttConnector::new("127.0.0.1:1883").compression(Compression::new());
+//! # let _ = (server, connector);
+//! ```
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use ntex::util::{ByteString, Bytes};
+
+use super::codec::{self, Packet, UserProperties};
+use super::{Handshake, HandshakeAck};
+use crate::error::{DecodeError, EncodeError};
+use crate::CodecExtension;
+
+const COMPRESSION: &str = "compression";
+const CONTENT_ENCODING: &str = "content-encoding";
+const DEFLATE: &str = "deflate";
+
+/// Deflate compression of publish payloads
+#[derive(Copy, Clone, Debug)]
+pub struct Compression {
+    threshold: usize,
+    level: u32,
+    max_size: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new()
+    }
+}
+
+impl Compression {
+    /// Create new compression settings
+    ///
+    /// By default payloads larger than 1kb are compressed with level 6.
+    pub fn new() -> Self {
+        Compression { threshold: 1024, level: 6, max_size: 0 }
+    }
+
+    /// Set min size of payload that gets compressed
+    pub fn threshold(mut self, size: usize) -> Self {
+        self.threshold = size;
+        self
+    }
+
+    /// Set compression level, from 0 to 9
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = std::cmp::min(level, 9);
+        self
+    }
+
+    /// Set max size of decompressed payload
+    ///
+    /// Publish with larger payload is treated as protocol error.
+    /// By default size is not limited.
+    pub fn max_size(mut self, size: usize) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    /// Check if compression is requested by `CONNECT` packet
+    pub fn is_requested(pkt: &codec::Connect) -> bool {
+        has_deflate(&pkt.user_properties, COMPRESSION)
+    }
+
+    /// Check if compression is accepted by `CONNACK` packet
+    pub fn is_accepted(pkt: &codec::ConnectAck) -> bool {
+        has_deflate(&pkt.user_properties, COMPRESSION)
+    }
+
+    /// Add compression request to `CONNECT` packet
+    pub fn request(pkt: &mut codec::Connect) {
+        pkt.user_properties.push((COMPRESSION.into(), DEFLATE.into()));
+    }
+
+    /// Add compression acceptance to `CONNACK` packet
+    pub fn accept(pkt: &mut codec::ConnectAck) {
+        pkt.user_properties.push((COMPRESSION.into(), DEFLATE.into()));
+    }
+
+    /// Ack handshake, compression is enabled if client requested it
+    pub fn ack<Io, St>(self, h: Handshake<Io>, st: St) -> HandshakeAck<Io, St> {
+        if Compression::is_requested(h.packet()) {
+            h.ack(st).with(Compression::accept).codec_extension(self)
+        } else {
+            h.ack(st)
+        }
+    }
+
+    fn compress(&self, payload: &[u8]) -> Result<Bytes, EncodeError> {
+        let mut enc = DeflateEncoder::new(
+            Vec::with_capacity(payload.len() / 2),
+            flate2::Compression::new(self.level),
+        );
+        enc.write_all(payload)
+            .and_then(|_| enc.finish())
+            .map(Bytes::from)
+            .map_err(|_| EncodeError::MalformedPacket)
+    }
+
+    fn decompress(&self, payload: &[u8]) -> Result<Bytes, DecodeError> {
+        let mut buf = Vec::with_capacity(payload.len() * 2);
+        let limit = if self.max_size == 0 { u64::MAX } else { self.max_size as u64 + 1 };
+        DeflateDecoder::new(payload)
+            .take(limit)
+            .read_to_end(&mut buf)
+            .map_err(|_| DecodeError::MalformedPacket)?;
+
+        if self.max_size != 0 && buf.len() > self.max_size {
+            Err(DecodeError::MaxSizeExceeded)
+        } else {
+            Ok(Bytes::from(buf))
+        }
+    }
+}
+
+impl CodecExtension<Packet> for Compression {
+    fn decoded(&self, pkt: Packet) -> Result<Packet, DecodeError> {
+        match pkt {
+            Packet::Publish(mut pkt) => {
+                let props = &mut pkt.properties.user_properties;
+                if let Some(idx) =
+                    props.iter().position(|(k, v)| k == CONTENT_ENCODING && v == DEFLATE)
+                {
+                    props.remove(idx);
+                    pkt.payload = self.decompress(&pkt.payload)?;
+                }
+                Ok(Packet::Publish(pkt))
+            }
+            pkt => Ok(pkt),
+        }
+    }
+
+    fn encode(&self, pkt: Packet) -> Result<Packet, EncodeError> {
+        match pkt {
+            Packet::Publish(mut pkt) if pkt.payload.len() >= self.threshold => {
+                pkt.payload = self.compress(&pkt.payload)?;
+                pkt.properties
+                    .user_properties
+                    .push((ByteString::from_static(CONTENT_ENCODING), DEFLATE.into()));
+                Ok(Packet::Publish(pkt))
+            }
+            pkt => Ok(pkt),
+        }
+    }
+}
+
+fn has_deflate(props: &UserProperties, key: &str) -> bool {
+    props.iter().any(|(k, v)| k == key && v == DEFLATE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish(payload: &'static [u8]) -> Packet {
+        Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: None,
+            payload: Bytes::from_static(payload),
+            properties: codec::PublishProperties::default(),
+        })
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let comp = Compression::new().threshold(16);
+
+        // small payloads are not compressed
+        assert_eq!(comp.encode(publish(b"data")).unwrap(), publish(b"data"));
+
+        static DATA: [u8; 1024] = [b'a'; 1024];
+        let pkt = comp.encode(publish(&DATA)).unwrap();
+        match pkt {
+            Packet::Publish(ref p) => {
+                assert!(p.payload.len() < DATA.len());
+                assert_eq!(
+                    p.properties.user_properties,
+                    vec![(CONTENT_ENCODING.into(), DEFLATE.into())]
+                );
+            }
+            _ => panic!(),
+        }
+        assert_eq!(comp.decoded(pkt.clone()).unwrap(), publish(&DATA));
+
+        // decompressed size limit
+        assert_eq!(comp.max_size(512).decoded(pkt).unwrap_err(), DecodeError::MaxSizeExceeded);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let mut connect = codec::Connect::default();
+        assert!(!Compression::is_requested(&connect));
+        Compression::request(&mut connect);
+        assert!(Compression::is_requested(&connect));
+
+        let mut ack = codec::ConnectAck::default();
+        assert!(!Compression::is_accepted(&ack));
+        Compression::accept(&mut ack);
+        assert!(Compression::is_accepted(&ack));
+    }
+}
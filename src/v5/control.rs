@@ -1,9 +1,9 @@
 use std::marker::PhantomData;
 
-use ntex::util::ByteString;
+use ntex::util::{ByteString, Bytes};
 
 use super::codec::{self, DisconnectReasonCode, QoS, UserProperties};
-use crate::error;
+use crate::{error, io::ShutdownStatus, session::SessionEnd};
 
 /// Control plain messages
 #[derive(Debug)]
@@ -38,8 +38,13 @@ impl<E> ControlMessage<E> {
         ControlMessage::Disconnect(Disconnect(pkt))
     }
 
-    pub(super) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(super) fn closed(
+        is_error: bool,
+        disconnect: Option<codec::Disconnect>,
+        shutdown: Option<ShutdownStatus>,
+        session_end: SessionEnd,
+    ) -> Self {
+        ControlMessage::Closed(Closed::new(is_error, disconnect, shutdown, session_end))
     }
 
     pub(super) fn error(err: E) -> Self {
@@ -98,6 +103,26 @@ impl Disconnect {
         &self.0
     }
 
+    /// Disconnect reason code
+    pub fn reason_code(&self) -> codec::ReasonCode {
+        self.0.reason_code.into()
+    }
+
+    /// Human readable reason of disconnect
+    pub fn reason_string(&self) -> Option<&ByteString> {
+        self.0.reason_string.as_ref()
+    }
+
+    /// Another server to use, sent by server only
+    pub fn server_reference(&self) -> Option<&ByteString> {
+        self.0.server_reference.as_ref()
+    }
+
+    /// Session expiry interval in seconds
+    pub fn session_expiry_interval(&self) -> Option<u32> {
+        self.0.session_expiry_interval_secs
+    }
+
     /// Ack disconnect message
     pub fn ack(self) -> ControlResult {
         ControlResult { packet: None, disconnect: true }
@@ -229,6 +254,30 @@ impl<'a> Subscription<'a> {
         self.options
     }
 
+    #[inline]
+    /// maximum qos requested by client
+    pub fn qos(&self) -> QoS {
+        self.options.qos
+    }
+
+    #[inline]
+    /// client does not want to receive its own publications
+    pub fn no_local(&self) -> bool {
+        self.options.no_local
+    }
+
+    #[inline]
+    /// retain flag of forwarded messages should be kept as published
+    pub fn retain_as_published(&self) -> bool {
+        self.options.retain_as_published
+    }
+
+    #[inline]
+    /// whether retained messages are sent when subscription is established
+    pub fn retain_handling(&self) -> codec::RetainHandling {
+        self.options.retain_handling
+    }
+
     #[inline]
     /// fail to subscribe to the topic
     pub fn fail(&mut self, status: codec::SubscribeAckReason) {
@@ -397,11 +446,19 @@ impl<'a> UnsubscribeItem<'a> {
 #[derive(Debug)]
 pub struct Closed {
     is_error: bool,
+    disconnect: Option<codec::Disconnect>,
+    shutdown: Option<ShutdownStatus>,
+    session_end: SessionEnd,
 }
 
 impl Closed {
-    pub(crate) fn new(is_error: bool) -> Self {
-        Self { is_error }
+    pub(crate) fn new(
+        is_error: bool,
+        disconnect: Option<codec::Disconnect>,
+        shutdown: Option<ShutdownStatus>,
+        session_end: SessionEnd,
+    ) -> Self {
+        Self { is_error, disconnect, shutdown, session_end }
     }
 
     /// Returns error state on connection close
@@ -409,6 +466,23 @@ impl Closed {
         self.is_error
     }
 
+    /// Returns `DISCONNECT` packet received from the peer before connection got closed
+    pub fn disconnect(&self) -> Option<&codec::Disconnect> {
+        self.disconnect.as_ref()
+    }
+
+    /// Returns transport shutdown status.
+    ///
+    /// Status is `None` if io has not been closed by dispatcher.
+    pub fn shutdown_status(&self) -> Option<ShutdownStatus> {
+        self.shutdown
+    }
+
+    /// Returns reason of session end.
+    pub fn session_end(&self) -> SessionEnd {
+        self.session_end
+    }
+
     #[inline]
     /// convert packet to a result
     pub fn ack(self) -> ControlResult {
@@ -490,6 +564,7 @@ impl<E> Error<E> {
 pub struct ProtocolError {
     err: error::ProtocolError,
     pkt: codec::Disconnect,
+    packet: Option<Bytes>,
 }
 
 impl ProtocolError {
@@ -507,6 +582,12 @@ impl ProtocolError {
                     error::ProtocolError::Decode(error::DecodeError::MaxSizeExceeded) => {
                         DisconnectReasonCode::PacketTooLarge
                     }
+                    error::ProtocolError::Decode(error::DecodeError::InvalidTopic) => {
+                        DisconnectReasonCode::TopicNameInvalid
+                    }
+                    error::ProtocolError::Decode(error::DecodeError::InvalidTopicFilter) => {
+                        DisconnectReasonCode::TopicFilterInvalid
+                    }
                     error::ProtocolError::Unexpected(_, _) => {
                         DisconnectReasonCode::ProtocolError
                     }
@@ -519,6 +600,12 @@ impl ProtocolError {
                     error::ProtocolError::UnknownTopicAlias => {
                         DisconnectReasonCode::TopicAliasInvalid
                     }
+                    error::ProtocolError::RetainNotSupported => {
+                        DisconnectReasonCode::RetainNotSupported
+                    }
+                    error::ProtocolError::QosNotSupported => {
+                        DisconnectReasonCode::QosNotSupported
+                    }
                     error::ProtocolError::Encode(_) => {
                         DisconnectReasonCode::ImplementationSpecificError
                     }
@@ -526,15 +613,30 @@ impl ProtocolError {
                 },
             },
             err,
+            packet: None,
         }
     }
 
+    pub(super) fn with_packet(mut self, packet: Option<Bytes>) -> Self {
+        self.packet = packet;
+        self
+    }
+
     #[inline]
     /// Returns reference to a protocol error
     pub fn get_ref(&self) -> &error::ProtocolError {
         &self.err
     }
 
+    #[inline]
+    /// Returns captured bytes of malformed packet
+    ///
+    /// Bytes are captured for decode errors if server's `capture_malformed()`
+    /// is enabled.
+    pub fn packet(&self) -> Option<&Bytes> {
+        self.packet.as_ref()
+    }
+
     #[inline]
     /// Set reason code for disconnect packet
     pub fn reason_code(mut self, reason: DisconnectReasonCode) -> Self {
@@ -0,0 +1,218 @@
+//! Delivery of publishes to subscribed clients
+use std::num::NonZeroU32;
+
+use super::error::PublishQos1Error;
+use super::{codec, sink::MqttSink, QoS};
+use crate::topic::TopicFilter;
+
+/// Subscriptions of a connected client
+///
+/// Subscriptions are maintained by application, i.e. in control service on
+/// subscribe and unsubscribe messages. Publishes forwarded to the client with
+/// `Subscriptions::deliver()` follow delivery rules of MQTT 5 specification.
+///
+/// ```rust,no_run
+/// # use ntex_mqtt::v5::{codec, MqttSink, Subscriptions};
+/// # async fn forward(sink: MqttSink, subs: Subscriptions, publish: codec::Publish) {
+/// match subs.deliver(&sink, &publish, false).await {
+///     Ok(true) => log::trace!("Publish is delivered"),
+///     Ok(false) => log::trace!("Client is not subscribed to {:?}", publish.topic),
+///     Err(e) => log::warn!("Cannot deliver publish: {}", e),
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Subscriptions(Vec<(TopicFilter, codec::SubscriptionOptions, Option<NonZeroU32>)>);
+
+/// Matched subscriptions of a publish
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    qos: QoS,
+    retain_as_published: bool,
+    subscription_ids: Vec<NonZeroU32>,
+}
+
+impl Delivery {
+    /// Maximum QoS granted by matched subscriptions
+    pub fn qos(&self) -> QoS {
+        self.qos
+    }
+
+    /// Retain flag of publish is kept for at least one of matched subscriptions
+    pub fn retain_as_published(&self) -> bool {
+        self.retain_as_published
+    }
+
+    /// Identifiers of matched subscriptions
+    pub fn subscription_ids(&self) -> &[NonZeroU32] {
+        &self.subscription_ids
+    }
+}
+
+impl Subscriptions {
+    /// Create empty subscriptions set
+    pub fn new() -> Self {
+        Subscriptions::default()
+    }
+
+    /// Add subscription, replaces existing subscription with the same filter
+    ///
+    /// QoS of subscription options is QoS granted to the client.
+    pub fn subscribe(
+        &mut self,
+        filter: TopicFilter,
+        options: codec::SubscriptionOptions,
+        id: Option<NonZeroU32>,
+    ) {
+        if let Some(item) = self.0.iter_mut().find(|(f, _, _)| *f == filter) {
+            item.1 = options;
+            item.2 = id;
+        } else {
+            self.0.push((filter, options, id));
+        }
+    }
+
+    /// Remove subscription, returns `false` if subscription does not exist
+    pub fn unsubscribe(&mut self, filter: &str) -> bool {
+        let len = self.0.len();
+        self.0.retain(|(f, _, _)| f.as_str() != filter);
+        len != self.0.len()
+    }
+
+    /// Number of subscriptions
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Check if client has no subscriptions
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterate over subscriptions
+    pub fn iter(&self) -> impl Iterator<Item = (&TopicFilter, &codec::SubscriptionOptions)> {
+        self.0.iter().map(|(f, opts, _)| (f, opts))
+    }
+
+    /// Match topic against subscriptions
+    ///
+    /// `local` is set if publish is published by the client itself,
+    /// subscriptions with `no_local` option do not match such publishes.
+    /// Overlapping subscriptions are combined into one delivery.
+    pub fn matches(&self, topic: &str, local: bool) -> Option<Delivery> {
+        let mut delivery: Option<Delivery> = None;
+
+        for (filter, opts, id) in &self.0 {
+            if (local && opts.no_local) || !filter.matches_str(topic) {
+                continue;
+            }
+            let d = delivery.get_or_insert_with(|| Delivery {
+                qos: opts.qos,
+                retain_as_published: false,
+                subscription_ids: Vec::new(),
+            });
+            if u8::from(opts.qos) > u8::from(d.qos) {
+                d.qos = opts.qos;
+            }
+            d.retain_as_published |= opts.retain_as_published;
+            d.subscription_ids.extend(*id);
+        }
+        delivery
+    }
+
+    /// Deliver publish to the client if it is subscribed to publish topic
+    ///
+    /// Publish is sent with lowest of publish QoS and granted QoS, QoS 2 is
+    /// delivered as QoS 1. Retain flag is cleared unless subscription has
+    /// `retain_as_published` option, topic alias is not forwarded.
+    /// Returns `false` if no subscription matches publish.
+    pub async fn deliver(
+        &self,
+        sink: &MqttSink,
+        publish: &codec::Publish,
+        local: bool,
+    ) -> Result<bool, PublishQos1Error> {
+        let Delivery { qos, retain_as_published, subscription_ids } =
+            if let Some(delivery) = self.matches(&publish.topic, local) {
+                delivery
+            } else {
+                return Ok(false);
+            };
+
+        let mut builder = sink.publish(publish.topic.clone(), publish.payload.clone());
+        builder.set_properties(|props| {
+            *props = publish.properties.clone();
+            props.topic_alias = None;
+            props.subscription_ids =
+                if subscription_ids.is_empty() { None } else { Some(subscription_ids) };
+        });
+        if publish.retain && retain_as_published {
+            builder = builder.retain();
+        }
+
+        if publish.qos == QoS::AtMostOnce || qos == QoS::AtMostOnce {
+            builder.send_at_most_once()?;
+        } else {
+            builder.send_at_least_once().await?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(qos: QoS, no_local: bool, retain_as_published: bool) -> codec::SubscriptionOptions {
+        codec::SubscriptionOptions {
+            qos,
+            no_local,
+            retain_as_published,
+            retain_handling: codec::RetainHandling::AtSubscribe,
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let mut subs = Subscriptions::new();
+        subs.subscribe(
+            TopicFilter::from_static("a/+"),
+            opts(QoS::AtMostOnce, false, false),
+            NonZeroU32::new(1),
+        );
+        subs.subscribe(
+            TopicFilter::from_static("a/#"),
+            opts(QoS::AtLeastOnce, true, true),
+            NonZeroU32::new(2),
+        );
+        subs.subscribe(
+            TopicFilter::from_static("b"),
+            opts(QoS::AtMostOnce, false, false),
+            None,
+        );
+
+        let d = subs.matches("a/b", false).unwrap();
+        assert_eq!(d.qos(), QoS::AtLeastOnce);
+        assert!(d.retain_as_published());
+        assert_eq!(
+            d.subscription_ids(),
+            &[NonZeroU32::new(1).unwrap(), NonZeroU32::new(2).unwrap()]
+        );
+
+        // no local subscription does not match own publishes
+        let d = subs.matches("a/b", true).unwrap();
+        assert_eq!(d.qos(), QoS::AtMostOnce);
+        assert!(!d.retain_as_published());
+        assert_eq!(d.subscription_ids(), &[NonZeroU32::new(1).unwrap()]);
+        assert!(subs.matches("a", true).is_none());
+
+        let d = subs.matches("b", false).unwrap();
+        assert!(d.subscription_ids().is_empty());
+        assert!(subs.matches("c", false).is_none());
+
+        assert!(subs.unsubscribe("a/#"));
+        assert!(!subs.unsubscribe("a/#"));
+        assert!(subs.matches("a", false).is_none());
+        assert_eq!(subs.len(), 2);
+    }
+}
@@ -1,23 +1,58 @@
 use std::cell::{Cell, RefCell};
 use std::task::{Context, Poll};
-use std::{convert::TryFrom, future::Future, marker, num, pin::Pin, rc::Rc};
+use std::{
+    cmp, convert::TryFrom, future::Future, marker, mem, num, pin::Pin, rc::Rc, time::Duration,
+};
 
 use ntex::service::{fn_factory_with_config, Service, ServiceFactory};
-use ntex::util::{join, Either, HashSet, Ready};
+use ntex::util::{join, ByteString, Either, HashSet, Ready};
 
+use crate::ack::{self, AckSlot, AckState};
+use crate::backlog::Watermark;
+use crate::dedup::{Dedup, DedupWindow, InflightControl};
 use crate::error::{MqttError, ProtocolError};
 use crate::io::DispatchItem;
+use crate::ordered::Ordered;
+use crate::quota::{SubscriptionLimits, SubscriptionQuota};
+use crate::registry::Registration;
+use crate::rewrite::{Tenant, TopicRewrite};
+use crate::session::SessionTasks;
+use crate::timeout::HandlerTimeout;
+use crate::types::{packet_type, RetainPolicy};
 
 use super::control::{self, ControlMessage, ControlResult};
-use super::publish::{Publish, PublishAck};
-use super::shared::{Ack, MqttShared};
+use super::publish::{DeadLetterHook, Publish, PublishAck, PublishErrorReason};
+use super::publish::{PublishFailure, PublishHook, PublishTrace};
+use super::shared::{Ack, Capabilities, MqttShared};
 use super::sink::MqttSink;
-use super::{codec, Session};
+use super::{codec, Session, SinkRegistry};
+
+/// Tenant namespace of authenticated session
+pub(super) type TenantFn<St> = Rc<dyn Fn(&Session<St>) -> Option<ByteString>>;
+
+/// Dispatcher settings, built by server builder
+pub(super) struct DispatcherConfig<St, E> {
+    pub(super) error_reason: Option<Rc<dyn PublishErrorReason<E>>>,
+    pub(super) dedup: Option<Rc<DedupWindow>>,
+    pub(super) watermark: Option<Watermark>,
+    pub(super) hook: Option<PublishHook>,
+    pub(super) ordered_lanes: usize,
+    pub(super) dead_letter: Option<DeadLetterHook<E>>,
+    pub(super) publish_timeout: Duration,
+    pub(super) timeout_reason: Option<codec::PublishAckReason>,
+    pub(super) registry: Option<SinkRegistry>,
+    pub(super) subscription_limits: SubscriptionLimits,
+    pub(super) retain_policy: RetainPolicy,
+    pub(super) topic_rewrite: Option<Rc<TopicRewrite>>,
+    pub(super) tenant: Option<TenantFn<St>>,
+    pub(super) auto_ack: bool,
+}
 
 /// mqtt3 protocol dispatcher
 pub(super) fn factory<St, T, C, E>(
     publish: T,
     control: C,
+    config: DispatcherConfig<St, E>,
 ) -> impl ServiceFactory<
     Config = Session<St>,
     Request = DispatchItem<Rc<MqttShared>>,
@@ -43,22 +78,36 @@ where
         > + 'static,
     PublishAck: TryFrom<T::Error, Error = E>,
 {
+    let config = Rc::new(config);
+
     fn_factory_with_config(move |cfg: Session<St>| {
         // create services
         let fut = join(publish.new_service(cfg.clone()), control.new_service(cfg.clone()));
-
-        let (max_receive, max_topic_alias) = cfg.params();
+        cfg.sink().set_watermark(config.watermark);
+        let tenant = match config.tenant.as_ref().and_then(|f| f(&cfg)) {
+            Some(namespace) => match Tenant::new(&namespace) {
+                Some(tenant) => {
+                    cfg.sink().set_tenant(tenant.clone());
+                    Ok(Some(tenant))
+                }
+                None => {
+                    log::error!("Invalid tenant namespace: {:?}", namespace);
+                    Err(MqttError::ServerError("Invalid tenant namespace"))
+                }
+            },
+            None => Ok(None),
+        };
+        let config = config.clone();
 
         async move {
             let (publish, control) = fut.await;
+            let tenant = tenant?;
+            let publish = Ordered::new(
+                HandlerTimeout::new(publish?, config.publish_timeout),
+                config.ordered_lanes,
+            );
 
-            Ok(Dispatcher::<_, _, E, T::Error>::new(
-                cfg.sink().clone(),
-                max_receive as usize,
-                max_topic_alias,
-                publish?,
-                control?,
-            ))
+            Ok(Dispatcher::<_, _, E, T::Error>::new(&cfg, publish, control?, tenant, &config))
         }
     })
 }
@@ -68,8 +117,19 @@ pub(crate) struct Dispatcher<T, C, E, E2> {
     sink: MqttSink,
     publish: T,
     shutdown: Cell<bool>,
+    disconnect: RefCell<Option<codec::Disconnect>>,
     max_receive: usize,
     max_topic_alias: u16,
+    capabilities: Capabilities,
+    retain_policy: RetainPolicy,
+    topic_rewrite: Option<Rc<TopicRewrite>>,
+    tenant: Option<Tenant>,
+    auto_ack: bool,
+    tasks: Rc<SessionTasks>,
+    error_reason: Option<Rc<dyn PublishErrorReason<E>>>,
+    dead_letter: Option<DeadLetterHook<E>>,
+    timeout_reason: Option<codec::PublishAckReason>,
+    _registration: Option<Registration<MqttSink>>,
     inner: Rc<Inner<C>>,
     _t: marker::PhantomData<(E, E2)>,
 }
@@ -78,39 +138,98 @@ struct Inner<C> {
     control: C,
     sink: MqttSink,
     info: RefCell<PublishInfo>,
+    inflight_control: InflightControl,
+    quota: Option<SubscriptionQuota>,
+    dedup: Option<Dedup>,
+    hook: Option<PublishHook>,
+}
+
+impl<C> Inner<C> {
+    /// Acknowledge QoS 1 publish before publish service completes
+    fn ack_publish(&self, packet_id: num::NonZeroU16) {
+        self.info.borrow_mut().inflight.remove(&packet_id);
+        if let Some(ref dedup) = self.dedup {
+            dedup.record(packet_id);
+        }
+        self.sink.send(
+            PublishAck::new(codec::PublishAckReason::Success)
+                .into_packet(packet_id, codec::QoS::AtLeastOnce),
+        );
+    }
+
+    /// Acknowledge retransmissions of handled control packet
+    fn ack_retransmits(&self, packet_id: num::NonZeroU16, ack: Option<&codec::Packet>) {
+        let count = self.inflight_control.complete(packet_id);
+        if let Some(ack) = ack {
+            for _ in 0..count {
+                self.sink.send(ack.clone());
+            }
+        }
+    }
 }
 
 struct PublishInfo {
     inflight: HashSet<num::NonZeroU16>,
+    /// qos2 publishes acknowledged with PUBREC, waiting for PUBREL
+    received: HashSet<num::NonZeroU16>,
     aliases: HashSet<num::NonZeroU16>,
 }
 
 impl<T, C, E, E2> Dispatcher<T, C, E, E2>
 where
-    T: Service<Request = Publish, Response = PublishAck, Error = E2>,
+    T: Service<Request = Publish, Response = Option<PublishAck>, Error = E2>,
     PublishAck: TryFrom<E2, Error = E>,
     C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E>,
 {
-    fn new(
-        sink: MqttSink,
-        max_receive: usize,
-        max_topic_alias: u16,
+    fn new<St>(
+        session: &Session<St>,
         publish: T,
         control: C,
+        tenant: Option<Tenant>,
+        config: &DispatcherConfig<St, E>,
     ) -> Self {
+        let sink = session.sink().clone();
+        let (max_receive, max_topic_alias) = session.params();
+        let dedup = config
+            .dedup
+            .clone()
+            .filter(|_| !session.client_id().is_empty())
+            .map(|window| Dedup::new(window, session.client_id().clone()));
+        let registration = config
+            .registry
+            .as_ref()
+            .filter(|_| !session.client_id().is_empty())
+            .map(|registry| registry.register(session.client_id().clone(), sink.clone()));
+
         Self {
             publish,
-            max_receive,
+            max_receive: max_receive as usize,
             max_topic_alias,
+            error_reason: config.error_reason.clone(),
+            dead_letter: config.dead_letter.clone(),
+            timeout_reason: config.timeout_reason,
+            _registration: registration,
+            capabilities: sink.capabilities(),
+            retain_policy: config.retain_policy,
+            topic_rewrite: config.topic_rewrite.clone(),
+            tenant,
+            auto_ack: config.auto_ack,
+            tasks: session.session_tasks(),
             sink: sink.clone(),
             shutdown: Cell::new(false),
+            disconnect: RefCell::new(None),
             inner: Rc::new(Inner {
                 control,
                 sink,
+                dedup,
+                hook: config.hook.clone(),
                 info: RefCell::new(PublishInfo {
                     aliases: HashSet::default(),
                     inflight: HashSet::default(),
+                    received: HashSet::default(),
                 }),
+                inflight_control: InflightControl::default(),
+                quota: config.subscription_limits.quota(),
             }),
             _t: marker::PhantomData,
         }
@@ -119,7 +238,7 @@ where
 
 impl<T, C, E, E2> Service for Dispatcher<T, C, E, E2>
 where
-    T: Service<Request = Publish, Response = PublishAck, Error = E2>,
+    T: Service<Request = Publish, Response = Option<PublishAck>, Error = E2>,
     PublishAck: TryFrom<E2, Error = E>,
     C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E>,
     C::Future: 'static,
@@ -144,26 +263,60 @@ where
         }
     }
 
-    fn poll_shutdown(&self, _: &mut Context<'_>, is_error: bool) -> Poll<()> {
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
         if !self.shutdown.get() {
             self.inner.sink.drop_sink();
             self.shutdown.set(true);
-            let fut = self.inner.control.call(ControlMessage::closed(is_error));
+            self.tasks.close();
+            let disconnect = self.disconnect.borrow_mut().take();
+            let clean = std::matches!(
+                disconnect,
+                Some(ref pkt) if pkt.reason_code == codec::DisconnectReasonCode::NormalDisconnection
+            );
+            let fut = self.inner.control.call(ControlMessage::closed(
+                is_error,
+                disconnect,
+                self.inner.sink.shutdown_status(),
+                self.inner.sink.session_end(clean),
+            ));
             ntex::rt::spawn(async move {
                 let _ = fut.await;
             });
         }
-        Poll::Ready(())
+        // wait for cancelled session tasks
+        self.tasks.poll_completed(cx)
     }
 
     fn call(&self, request: Self::Request) -> Self::Future {
         log::trace!("Dispatch packet: {:#?}", request);
 
         match request {
-            DispatchItem::Item(codec::Packet::Publish(publish)) => {
+            DispatchItem::Item(codec::Packet::Publish(mut publish)) => {
                 let info = self.inner.clone();
                 let packet_id = publish.packet_id;
 
+                if !self.capabilities.check_qos(publish.qos) {
+                    log::trace!("QoS is not supported: {:?}", publish.qos);
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::QosNotSupported),
+                        &self.inner,
+                    )));
+                }
+
+                if publish.retain {
+                    if self.retain_policy == RetainPolicy::Strip {
+                        publish.retain = false;
+                    } else if self.retain_policy == RetainPolicy::Reject
+                        || !self.capabilities.retain
+                    {
+                        log::trace!("Retain is not supported: {:?}", publish.topic);
+                        return Either::Right(Either::Right(ControlResponse::new(
+                            ControlMessage::proto_error(ProtocolError::RetainNotSupported),
+                            &self.inner,
+                        )));
+                    }
+                }
+
                 {
                     let mut inner = info.info.borrow_mut();
 
@@ -185,15 +338,55 @@ where
 
                         // check for duplicated packet id
                         if !inner.inflight.insert(pid) {
-                            self.sink.send(codec::Packet::PublishAck(codec::PublishAck {
-                                packet_id: pid,
-                                reason_code: codec::PublishAckReason::PacketIdentifierInUse,
-                                ..Default::default()
-                            }));
+                            if publish.qos == codec::QoS::ExactlyOnce
+                                && publish.dup
+                                && inner.received.contains(&pid)
+                            {
+                                // PUBREC is lost, re-delivered publish is acknowledged again
+                                log::trace!("Re-delivered qos2 publish: {:?}", pid);
+                                self.sink.send(
+                                    PublishAck::new(codec::PublishAckReason::Success)
+                                        .into_packet(pid, codec::QoS::ExactlyOnce),
+                                );
+                            } else {
+                                self.sink.send(
+                                    PublishAck::new(
+                                        codec::PublishAckReason::PacketIdentifierInUse,
+                                    )
+                                    .into_packet(pid, publish.qos),
+                                );
+                            }
                             return Either::Right(Either::Left(Ready::Ok(None)));
                         }
                     }
 
+                    // aliased publishes carry topic only in first packet
+                    if let Some(ref rewrite) = self.topic_rewrite {
+                        if !publish.topic.is_empty() {
+                            publish.topic = rewrite.topic(&publish.topic);
+                        }
+                    }
+                    if let Some(ref tenant) = self.tenant {
+                        if !publish.topic.is_empty() {
+                            if let Some(topic) = tenant.topic(&publish.topic) {
+                                publish.topic = topic;
+                            } else {
+                                log::trace!(
+                                    "Publish topic is outside of namespace: {:?}",
+                                    publish.topic
+                                );
+                                if let Some(pid) = packet_id {
+                                    inner.inflight.remove(&pid);
+                                    self.sink.send(
+                                        PublishAck::new(codec::PublishAckReason::NotAuthorized)
+                                            .into_packet(pid, publish.qos),
+                                    );
+                                }
+                                return Either::Right(Either::Left(Ready::Ok(None)));
+                            }
+                        }
+                    }
+
                     // handle topic aliases
                     if let Some(alias) = publish.properties.topic_alias {
                         // check existing topic
@@ -220,15 +413,60 @@ where
                     }
                 }
 
+                // check for redelivery of acknowledged publish
+                let duplicate = match (&info.dedup, packet_id) {
+                    (Some(dedup), Some(pid)) if publish.qos == codec::QoS::AtLeastOnce => {
+                        dedup.check(pid, publish.dup)
+                    }
+                    _ => false,
+                };
+
+                // publish is acknowledged once publish service is called
+                let ack = if self.auto_ack && publish.qos == codec::QoS::AtLeastOnce {
+                    Some(ack::slot())
+                } else {
+                    None
+                };
+
                 Either::Left(PublishResponse {
                     packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
+                    ack: ack.clone(),
+                    qos: publish.qos,
+                    error_reason: self.error_reason.clone(),
+                    dead_letter: self
+                        .dead_letter
+                        .as_ref()
+                        .map(|hook| (publish.clone(), hook.clone())),
+                    timeout_reason: self.timeout_reason,
+                    trace: info.hook.as_ref().map(|hook| PublishTrace::new(hook, &publish)),
                     inner: info,
                     state: PublishResponseState::Publish {
-                        fut: self.publish.call(Publish::new(publish)),
+                        fut: self.publish.call(
+                            Publish::new(publish).with_duplicate(duplicate).with_auto_ack(ack),
+                        ),
                     },
                     _t: marker::PhantomData,
                 })
             }
+            DispatchItem::Item(codec::Packet::PublishRelease(packet)) => {
+                let reason_code = {
+                    let mut info = self.inner.info.borrow_mut();
+                    info.received.remove(&packet.packet_id);
+                    if info.inflight.remove(&packet.packet_id) {
+                        codec::PublishAck2Reason::Success
+                    } else {
+                        codec::PublishAck2Reason::PacketIdNotFound
+                    }
+                };
+                Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PublishComplete(
+                    codec::PublishAck2 {
+                        packet_id: packet.packet_id,
+                        reason_code,
+                        properties: codec::UserProperties::new(),
+                        reason_string: None,
+                    },
+                )))))
+            }
             DispatchItem::Item(codec::Packet::PublishAck(packet)) => {
                 if let Err(err) = self.sink.pkt_ack(Ack::Publish(packet)) {
                     Either::Right(Either::Right(ControlResponse::new(
@@ -242,15 +480,35 @@ where
             DispatchItem::Item(codec::Packet::Auth(pkt)) => Either::Right(Either::Right(
                 ControlResponse::new(ControlMessage::auth(pkt), &self.inner),
             )),
-            DispatchItem::Item(codec::Packet::PingRequest) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::ping(), &self.inner),
-            )),
-            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::dis(pkt), &self.inner),
-            )),
-            DispatchItem::Item(codec::Packet::Subscribe(pkt)) => {
+            DispatchItem::Item(codec::Packet::PingRequest) => {
+                if self.auto_ack {
+                    Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PingResponse))))
+                } else {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::ping(),
+                        &self.inner,
+                    )))
+                }
+            }
+            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => {
+                *self.disconnect.borrow_mut() = Some(pkt.clone());
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::dis(pkt),
+                    &self.inner,
+                )))
+            }
+            DispatchItem::Item(codec::Packet::Subscribe(mut pkt)) => {
                 // register inflight packet id
                 if !self.inner.info.borrow_mut().inflight.insert(pkt.packet_id) {
+                    // retransmission is acknowledged after original packet
+                    if self
+                        .inner
+                        .inflight_control
+                        .retransmit(pkt.packet_id, packet_type::SUBSCRIBE)
+                    {
+                        log::trace!("Retransmitted subscribe packet: {:?}", pkt.packet_id);
+                        return Either::Right(Either::Left(Ready::Ok(None)));
+                    }
                     // duplicated packet id
                     self.sink.send(codec::Packet::SubscribeAck(codec::SubscribeAck {
                         packet_id: pkt.packet_id,
@@ -264,15 +522,81 @@ where
                     }));
                     return Either::Right(Either::Left(Ready::Ok(None)));
                 }
+                if let Some(ref rewrite) = self.topic_rewrite {
+                    for (filter, _) in pkt.topic_filters.iter_mut() {
+                        *filter = rewrite.filter(filter);
+                    }
+                }
+                // reject topic filters that use unsupported features
+                let sub_id = pkt.id.is_some();
+                let tenant = self.tenant.as_ref();
+                let rejected: Vec<_> = pkt
+                    .topic_filters
+                    .iter_mut()
+                    .enumerate()
+                    .filter_map(|(idx, (filter, _))| {
+                        // filters that do not belong to tenant namespace
+                        if let Some(tenant) = tenant {
+                            match tenant.filter(filter) {
+                                Some(f) => *filter = f,
+                                None => {
+                                    return Some((
+                                        idx,
+                                        codec::SubscribeAckReason::NotAuthorized,
+                                    ))
+                                }
+                            }
+                        }
+                        self.capabilities.check_filter(filter, sub_id).map(|r| (idx, r))
+                    })
+                    .collect();
+                for (idx, _) in rejected.iter().rev() {
+                    pkt.topic_filters.remove(*idx);
+                }
+
+                // check subscription limits
+                let exceeded = match self.inner.quota {
+                    Some(ref quota) => !quota
+                        .subscribe(pkt.packet_id, pkt.topic_filters.iter().map(|(f, _)| f)),
+                    None => false,
+                };
+                if exceeded || (pkt.topic_filters.is_empty() && !rejected.is_empty()) {
+                    self.inner.info.borrow_mut().inflight.remove(&pkt.packet_id);
+                    let mut status: Vec<_> = pkt
+                        .topic_filters
+                        .iter()
+                        .map(|_| codec::SubscribeAckReason::QuotaExceeded)
+                        .collect();
+                    insert_rejected(&mut status, &rejected);
+                    return Either::Right(Either::Left(Ready::Ok(Some(
+                        codec::Packet::SubscribeAck(codec::SubscribeAck {
+                            status,
+                            packet_id: pkt.packet_id,
+                            properties: codec::UserProperties::new(),
+                            reason_string: None,
+                        }),
+                    ))));
+                }
                 let id = pkt.packet_id;
+                self.inner.inflight_control.insert(id, packet_type::SUBSCRIBE);
                 Either::Right(Either::Right(
                     ControlResponse::new(control::Subscribe::create(pkt), &self.inner)
-                        .packet_id(id),
+                        .packet_id(id)
+                        .rejected(rejected),
                 ))
             }
-            DispatchItem::Item(codec::Packet::Unsubscribe(pkt)) => {
+            DispatchItem::Item(codec::Packet::Unsubscribe(mut pkt)) => {
                 // register inflight packet id
                 if !self.inner.info.borrow_mut().inflight.insert(pkt.packet_id) {
+                    // retransmission is acknowledged after original packet
+                    if self
+                        .inner
+                        .inflight_control
+                        .retransmit(pkt.packet_id, packet_type::UNSUBSCRIBE)
+                    {
+                        log::trace!("Retransmitted unsubscribe packet: {:?}", pkt.packet_id);
+                        return Either::Right(Either::Left(Ready::Ok(None)));
+                    }
                     // duplicated packet id
                     self.sink.send(codec::Packet::UnsubscribeAck(codec::UnsubscribeAck {
                         packet_id: pkt.packet_id,
@@ -286,10 +610,50 @@ where
                     }));
                     return Either::Right(Either::Left(Ready::Ok(None)));
                 }
+                if let Some(ref rewrite) = self.topic_rewrite {
+                    for filter in pkt.topic_filters.iter_mut() {
+                        *filter = rewrite.filter(filter);
+                    }
+                }
+                // reject filters that do not belong to tenant namespace
+                let rejected: Vec<_> = match self.tenant {
+                    Some(ref tenant) => pkt
+                        .topic_filters
+                        .iter_mut()
+                        .enumerate()
+                        .filter_map(|(idx, filter)| match tenant.filter(filter) {
+                            Some(f) => {
+                                *filter = f;
+                                None
+                            }
+                            None => Some((idx, codec::UnsubscribeAckReason::NotAuthorized)),
+                        })
+                        .collect(),
+                    None => Vec::new(),
+                };
+                for (idx, _) in rejected.iter().rev() {
+                    pkt.topic_filters.remove(*idx);
+                }
+                if pkt.topic_filters.is_empty() && !rejected.is_empty() {
+                    self.inner.info.borrow_mut().inflight.remove(&pkt.packet_id);
+                    return Either::Right(Either::Left(Ready::Ok(Some(
+                        codec::Packet::UnsubscribeAck(codec::UnsubscribeAck {
+                            packet_id: pkt.packet_id,
+                            status: rejected.into_iter().map(|(_, r)| r).collect(),
+                            properties: codec::UserProperties::new(),
+                            reason_string: None,
+                        }),
+                    ))));
+                }
+                if let Some(ref quota) = self.inner.quota {
+                    quota.unsubscribe(pkt.topic_filters.iter());
+                }
                 let id = pkt.packet_id;
+                self.inner.inflight_control.insert(id, packet_type::UNSUBSCRIBE);
                 Either::Right(Either::Right(
                     ControlResponse::new(control::Unsubscribe::create(pkt), &self.inner)
-                        .packet_id(id),
+                        .packet_id(id)
+                        .rejected_unsubscribe(rejected),
                 ))
             }
             DispatchItem::Item(_) => Either::Right(Either::Left(Ready::Ok(None))),
@@ -307,7 +671,10 @@ where
             }
             DispatchItem::DecoderError(err) => {
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::Decode(err)),
+                    ControlMessage::ProtocolError(
+                        control::ProtocolError::new(ProtocolError::Decode(err))
+                            .with_packet(self.sink.malformed_packet()),
+                    ),
                     &self.inner,
                 )))
             }
@@ -328,6 +695,12 @@ pin_project_lite::pin_project! {
         #[pin]
         state: PublishResponseState<T, C, E>,
         packet_id: u16,
+        ack: Option<AckSlot>,
+        qos: codec::QoS,
+        error_reason: Option<Rc<dyn PublishErrorReason<E>>>,
+        dead_letter: Option<(codec::Publish, DeadLetterHook<E>)>,
+        timeout_reason: Option<codec::PublishAckReason>,
+        trace: Option<PublishTrace>,
         inner: Rc<Inner<C>>,
         _t: marker::PhantomData<(E, E2)>,
     }
@@ -344,7 +717,7 @@ pin_project_lite::pin_project! {
 impl<T, C, E, E2> Future for PublishResponse<T, C, E, E2>
 where
     E: From<E2>,
-    T: Service<Request = Publish, Response = PublishAck, Error = E2>,
+    T: Service<Request = Publish, Response = Option<PublishAck>, Error = E2>,
     PublishAck: TryFrom<E2, Error = E>,
     C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E>,
 {
@@ -355,44 +728,102 @@ where
 
         match this.state.as_mut().project() {
             PublishResponseStateProject::Publish { fut } => {
-                let ack = match fut.poll(cx) {
-                    Poll::Ready(Ok(ack)) => ack,
-                    Poll::Ready(Err(e)) => {
-                        if *this.packet_id != 0 {
-                            match PublishAck::try_from(e) {
-                                Ok(ack) => ack,
-                                Err(e) => {
-                                    this.state.set(PublishResponseState::Control {
-                                        fut: ControlResponse::new(
-                                            ControlMessage::error(e),
-                                            this.inner,
-                                        ),
-                                    });
-                                    return self.poll(cx);
+                let result = fut.poll(cx);
+
+                // publish service is called and did not opt out of auto-ack
+                if let Some(ref ack) = this.ack {
+                    if ack.get() == AckState::Auto {
+                        ack.set(AckState::Sent);
+                        if let Some(id) = num::NonZeroU16::new(*this.packet_id) {
+                            this.inner.ack_publish(id);
+                            *this.packet_id = 0;
+                        }
+                    }
+                }
+
+                let mut ack = match result {
+                    Poll::Ready(Ok(Some(ack))) => ack,
+                    Poll::Ready(Ok(None)) => {
+                        // handler timeout, QoS 0 publish is discarded
+                        if let Some((publish, hook)) = this.dead_letter.take() {
+                            (*hook)(publish, PublishFailure::Timeout);
+                        }
+                        match this.timeout_reason {
+                            Some(reason) if *this.packet_id != 0 => PublishAck::new(*reason),
+                            _ => {
+                                if let Some(trace) = this.trace.take() {
+                                    trace.finish(None);
                                 }
+                                return Poll::Ready(if *this.packet_id == 0 {
+                                    Ok(None)
+                                } else {
+                                    Err(MqttError::ServerError("Publish handler timeout"))
+                                });
                             }
+                        }
+                    }
+                    Poll::Ready(Err(e)) => {
+                        let res = if *this.packet_id == 0 {
+                            Err(e.into())
+                        } else if let Some(mapping) = this.error_reason {
+                            let e = E::from(e);
+                            mapping.reason(&e).map(PublishAck::new).ok_or(e)
                         } else {
-                            this.state.set(PublishResponseState::Control {
-                                fut: ControlResponse::new(
-                                    ControlMessage::error(e.into()),
-                                    this.inner,
-                                ),
-                            });
-                            return self.poll(cx);
+                            PublishAck::try_from(e)
+                        };
+                        if let Some((publish, hook)) = this.dead_letter.take() {
+                            match res {
+                                Ok(ref ack) => (*hook)(publish, PublishFailure::Ack(ack)),
+                                Err(ref e) => (*hook)(publish, PublishFailure::Error(e)),
+                            }
+                        }
+                        match res {
+                            Ok(ack) => ack,
+                            Err(e) => {
+                                if let Some(trace) = this.trace.take() {
+                                    trace.finish(None);
+                                }
+                                this.state.set(PublishResponseState::Control {
+                                    fut: ControlResponse::new(
+                                        ControlMessage::error(e),
+                                        this.inner,
+                                    ),
+                                });
+                                return self.poll(cx);
+                            }
                         }
                     }
                     Poll::Pending => return Poll::Pending,
                 };
+                if let Some(trace) = this.trace.take() {
+                    trace.finish(Some(ack.reason_code));
+                }
                 if let Some(id) = num::NonZeroU16::new(*this.packet_id) {
-                    this.inner.info.borrow_mut().inflight.remove(&id);
-                    let ack = codec::PublishAck {
-                        packet_id: id,
-                        reason_code: ack.reason_code,
-                        reason_string: ack.reason_string,
-                        properties: ack.properties,
-                    };
-                    Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    // qos2 packet id is in use until PUBREL is received
+                    if *this.qos != codec::QoS::ExactlyOnce || ack.is_error() {
+                        this.inner.info.borrow_mut().inflight.remove(&id);
+                    } else {
+                        this.inner.info.borrow_mut().received.insert(id);
+                    }
+                    if let Some(ref dedup) = this.inner.dedup {
+                        if *this.qos == codec::QoS::AtLeastOnce && !ack.is_error() {
+                            dedup.record(id);
+                        }
+                    }
+                    let publishes = mem::take(&mut ack.publishes);
+                    if publishes.is_empty() {
+                        return Poll::Ready(Ok(Some(ack.into_packet(id, *this.qos))));
+                    }
+                    // handler publishes are sent after ack
+                    this.inner.sink.send(ack.into_packet(id, *this.qos));
+                    for publish in publishes {
+                        this.inner.sink.send_publish(publish);
+                    }
+                    Poll::Ready(Ok(None))
                 } else {
+                    for publish in ack.publishes {
+                        this.inner.sink.send_publish(publish);
+                    }
                     Poll::Ready(Ok(None))
                 }
             }
@@ -410,6 +841,8 @@ pin_project_lite::pin_project! {
         inner: Rc<Inner<C>>,
         error: bool,
         packet_id: u16,
+        rejected: Vec<(usize, codec::SubscribeAckReason)>,
+        rejected_unsubscribe: Vec<(usize, codec::UnsubscribeAckReason)>,
         _t: marker::PhantomData<E>,
     }
 }
@@ -430,6 +863,8 @@ where
             fut: inner.control.call(pkt),
             inner: inner.clone(),
             packet_id: 0,
+            rejected: Vec::new(),
+            rejected_unsubscribe: Vec::new(),
             _t: marker::PhantomData,
         }
     }
@@ -438,6 +873,21 @@ where
         self.packet_id = id.get();
         self
     }
+
+    /// Topic filters rejected by dispatcher, reason codes are inserted to subscribe ack
+    fn rejected(mut self, rejected: Vec<(usize, codec::SubscribeAckReason)>) -> Self {
+        self.rejected = rejected;
+        self
+    }
+
+    /// Topic filters rejected by dispatcher, reason codes are inserted to unsubscribe ack
+    fn rejected_unsubscribe(
+        mut self,
+        rejected: Vec<(usize, codec::UnsubscribeAckReason)>,
+    ) -> Self {
+        self.rejected_unsubscribe = rejected;
+        self
+    }
 }
 
 impl<C, E> Future for ControlResponse<C, E>
@@ -450,9 +900,22 @@ where
         let this = self.as_mut().project();
 
         let result = match this.fut.poll(cx) {
-            Poll::Ready(Ok(result)) => {
+            Poll::Ready(Ok(mut result)) => {
                 if let Some(id) = num::NonZeroU16::new(self.packet_id) {
                     self.inner.info.borrow_mut().inflight.remove(&id);
+                    if let (Some(quota), Some(codec::Packet::SubscribeAck(ack))) =
+                        (&self.inner.quota, &result.packet)
+                    {
+                        quota.subscribed(id, ack.status.iter().map(|s| u8::from(*s) < 0x80));
+                    }
+                    if let Some(codec::Packet::SubscribeAck(ref mut ack)) = result.packet {
+                        self.inner.sink.capabilities().clamp_granted(&mut ack.status);
+                        insert_rejected(&mut ack.status, &self.rejected);
+                    }
+                    if let Some(codec::Packet::UnsubscribeAck(ref mut ack)) = result.packet {
+                        insert_rejected(&mut ack.status, &self.rejected_unsubscribe);
+                    }
+                    self.inner.ack_retransmits(id, result.packet.as_ref());
                 }
                 result
             }
@@ -487,3 +950,10 @@ where
         }
     }
 }
+
+/// Insert reason codes of rejected topic filters to their positions in (un)subscribe ack
+fn insert_rejected<T: Copy>(status: &mut Vec<T>, rejected: &[(usize, T)]) {
+    for (idx, reason) in rejected {
+        status.insert(cmp::min(*idx, status.len()), *reason);
+    }
+}
@@ -1,6 +1,8 @@
 use derive_more::{Display, From};
 use ntex::util::Either;
 
+use crate::connect::TransportError;
+
 pub use crate::error::*;
 pub use crate::v5::codec;
 
@@ -21,10 +23,51 @@ pub enum ClientError {
     Disconnected,
     /// Connect error
     #[display(fmt = "Connect error: {}", _0)]
+    #[from(ignore)]
     Connect(ntex::connect::ConnectError),
+    /// Address resolution failed
+    #[display(fmt = "Resolve error: {}", _0)]
+    #[from(ignore)]
+    Resolve(ntex::connect::ConnectError),
+    /// Transport connect timeout
+    #[display(fmt = "Connect timeout")]
+    ConnectTimeout,
+    /// Tls handshake failed
+    #[display(fmt = "Tls error: {}", _0)]
+    #[from(ignore)]
+    Tls(std::io::Error),
 }
 
-impl std::error::Error for ClientError {}
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Protocol(e) => Some(e),
+            ClientError::Connect(ntex::connect::ConnectError::Resolver(e))
+            | ClientError::Connect(ntex::connect::ConnectError::Io(e))
+            | ClientError::Resolve(ntex::connect::ConnectError::Resolver(e))
+            | ClientError::Resolve(ntex::connect::ConnectError::Io(e))
+            | ClientError::Tls(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ntex::connect::ConnectError> for ClientError {
+    fn from(err: ntex::connect::ConnectError) -> Self {
+        TransportError::from(err).into()
+    }
+}
+
+impl From<TransportError> for ClientError {
+    fn from(err: TransportError) -> Self {
+        match err {
+            TransportError::Resolve(e) => ClientError::Resolve(e),
+            TransportError::Timeout => ClientError::ConnectTimeout,
+            TransportError::Tls(e) => ClientError::Tls(e),
+            TransportError::Connect(e) => ClientError::Connect(e),
+        }
+    }
+}
 
 impl From<Either<EncodeError, std::io::Error>> for ClientError {
     fn from(err: Either<EncodeError, std::io::Error>) -> Self {
@@ -36,6 +79,7 @@ impl From<Either<EncodeError, std::io::Error>> for ClientError {
 }
 
 #[derive(Debug, Display, PartialEq)]
+#[non_exhaustive]
 pub enum PublishQos1Error {
     /// Negative ack from peer
     #[display(fmt = "Negative ack: {:?}", _0)]
@@ -48,4 +92,41 @@ pub enum PublishQos1Error {
     /// Peer disconnected
     #[display(fmt = "Peer disconnected")]
     Disconnected,
+    /// Publish is not acknowledged in time, waiter is expired with `expire_inflight()`
+    #[display(fmt = "Publish acknowledgement is expired")]
+    Expired,
+    /// Packet is not allowed for connection role
+    #[display(fmt = "Packet is not allowed")]
+    NotAllowed,
+}
+
+impl std::error::Error for PublishQos1Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PublishQos1Error::Encode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl PublishQos1Error {
+    /// Reason code of negative ack, `None` for local errors
+    pub fn reason_code(&self) -> Option<codec::ReasonCode> {
+        match self {
+            PublishQos1Error::Fail(ack) => Some(ack.reason_code.into()),
+            _ => None,
+        }
+    }
+}
+
+impl From<SendPacketError> for PublishQos1Error {
+    fn from(err: SendPacketError) -> Self {
+        match err {
+            SendPacketError::Encode(e) => PublishQos1Error::Encode(e),
+            SendPacketError::PacketIdInUse(id) => PublishQos1Error::PacketIdInUse(id),
+            SendPacketError::Disconnected => PublishQos1Error::Disconnected,
+            SendPacketError::Expired => PublishQos1Error::Expired,
+            SendPacketError::NotAllowed => PublishQos1Error::NotAllowed,
+        }
+    }
 }
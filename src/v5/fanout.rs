@@ -0,0 +1,132 @@
+//! Publish fan-out
+use ntex::util::{join_all, ByteString, Bytes};
+
+use super::error::PublishQos1Error;
+use super::{codec, sink::MqttSink, QoS};
+
+/// Delivery of one publish to many sinks
+///
+/// Topic and payload buffers are shared between all delivered publishes.
+/// Each sink is paired with its QoS, i.e. granted QoS of subscription,
+/// publish is sent with lowest of fan-out and sink QoS.
+///
+/// ```rust,no_run
+/// # use ntex::util::Bytes;
+/// # use ntex_mqtt::v5::{Fanout, MqttSink, QoS};
+/// # async fn broadcast(sinks: Vec<(MqttSink, QoS)>) {
+/// let res = Fanout::new("topic", Bytes::from_static(b"data"))
+///     .qos(QoS::AtLeastOnce)
+///     .properties(|props| props.is_utf8_payload = Some(true))
+///     .send(sinks.iter().map(|(sink, qos)| (sink, *qos)))
+///     .await;
+/// for (idx, err) in res.failed() {
+///     log::warn!("Cannot deliver publish to {:?}: {}", sinks[*idx].0, err);
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Fanout {
+    topic: ByteString,
+    payload: Bytes,
+    qos: QoS,
+    retain: bool,
+    properties: codec::PublishProperties,
+}
+
+impl Fanout {
+    /// Create fan-out for topic and payload, by default QoS 0 is used
+    pub fn new<U>(topic: U, payload: Bytes) -> Self
+    where
+        ByteString: From<U>,
+    {
+        Fanout {
+            topic: topic.into(),
+            payload,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            properties: codec::PublishProperties::default(),
+        }
+    }
+
+    /// Set maximum QoS of delivered publishes
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Set retain flag of delivered publishes
+    pub fn retain(mut self) -> Self {
+        self.retain = true;
+        self
+    }
+
+    /// Set properties of delivered publishes
+    pub fn properties<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut codec::PublishProperties),
+    {
+        f(&mut self.properties);
+        self
+    }
+
+    /// Deliver publish to sinks
+    ///
+    /// QoS 0 publishes are written immediately, future resolves when all
+    /// QoS 1 publishes are acknowledged or failed.
+    pub async fn send<'a, I>(&self, sinks: I) -> FanoutResult
+    where
+        I: IntoIterator<Item = (&'a MqttSink, QoS)>,
+    {
+        let mut result = FanoutResult::default();
+        let mut pending = Vec::new();
+
+        for (idx, (sink, qos)) in sinks.into_iter().enumerate() {
+            let mut builder = sink.publish(self.topic.clone(), self.payload.clone());
+            builder.set_properties(|props| *props = self.properties.clone());
+            if self.retain {
+                builder = builder.retain();
+            }
+            if self.qos == QoS::AtMostOnce || qos == QoS::AtMostOnce {
+                match builder.send_at_most_once() {
+                    Ok(_) => result.delivered += 1,
+                    Err(e) => result.failed.push((idx, e.into())),
+                }
+            } else {
+                pending.push(async move { (idx, builder.send_at_least_once().await) });
+            }
+        }
+
+        for (idx, res) in join_all(pending).await {
+            match res {
+                Ok(_) => result.delivered += 1,
+                Err(e) => result.failed.push((idx, e)),
+            }
+        }
+        result.failed.sort_by_key(|(idx, _)| *idx);
+        result
+    }
+}
+
+/// Result of publish fan-out
+#[derive(Debug, Default)]
+pub struct FanoutResult {
+    delivered: usize,
+    failed: Vec<(usize, PublishQos1Error)>,
+}
+
+impl FanoutResult {
+    /// Number of delivered publishes
+    pub fn delivered(&self) -> usize {
+        self.delivered
+    }
+
+    /// Failed deliveries, index of sink and error
+    pub fn failed(&self) -> &[(usize, PublishQos1Error)] {
+        &self.failed
+    }
+
+    /// Check if publish is delivered to all sinks
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
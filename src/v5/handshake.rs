@@ -1,7 +1,39 @@
-use std::{fmt, num::NonZeroU16, rc::Rc};
+use std::{fmt, future::Future, num::NonZeroU16, rc::Rc, time::Duration};
+
+use ntex::codec::FramedParts;
+
+use crate::{client_id::ClientIdPolicy, utils::duration_to_secs, CodecExtension};
 
 use super::{codec, shared::MqttShared, sink::MqttSink};
 
+/// Connect packet filter
+pub(crate) type ConnectFilter<Io> =
+    Rc<dyn Fn(&mut codec::Connect, &Io) -> Result<(), codec::ConnectAckReason>>;
+
+/// Chain client id policy and connect filter, client id is checked first
+pub(crate) fn chain_filter<Io: 'static>(
+    filter: Option<ConnectFilter<Io>>,
+    policy: Option<ClientIdPolicy>,
+) -> Option<ConnectFilter<Io>> {
+    let policy = match policy {
+        Some(policy) => policy,
+        None => return filter,
+    };
+    Some(Rc::new(move |pkt: &mut codec::Connect, io: &Io| {
+        match policy(&pkt.client_id) {
+            Some(id) => pkt.client_id = id,
+            None => {
+                log::trace!("Client id is rejected by policy: {:?}", pkt.client_id);
+                return Err(codec::ConnectAckReason::ClientIdentifierNotValid);
+            }
+        }
+        match filter {
+            Some(ref filter) => filter(pkt, io),
+            None => Ok(()),
+        }
+    }))
+}
+
 /// Handshake message
 pub struct Handshake<Io> {
     io: Io,
@@ -39,6 +71,14 @@ impl<Io> Handshake<Io> {
         &mut self.io
     }
 
+    /// Apply connect filter, filter could modify connect packet
+    pub(crate) fn filter(
+        &mut self,
+        f: &ConnectFilter<Io>,
+    ) -> Result<(), codec::ConnectAckReason> {
+        f(&mut self.pkt, &self.io)
+    }
+
     #[inline]
     /// Returns mqtt server sink
     pub fn sink(&self) -> MqttSink {
@@ -68,10 +108,39 @@ impl<Io> Handshake<Io> {
             read_hw: 4 * 1024,
             write_hw: 4 * 1024,
             keepalive: 30,
+            upgrade: None,
+            extension: None,
             packet,
         }
     }
 
+    /// Take ownership of connection transport.
+    ///
+    /// Mqtt processing stops without sending `CONNACK` packet, transport parts
+    /// with already received but not decoded data get passed to provided function
+    /// and returned future is spawned. Could be used for handing connection
+    /// to different protocol or for transport upgrades.
+    pub fn upgrade<St, F, R>(self, f: F) -> HandshakeAck<Io, St>
+    where
+        F: FnOnce(FramedParts<Io, codec::Codec>) -> R + 'static,
+        R: Future<Output = ()> + 'static,
+    {
+        HandshakeAck {
+            io: self.io,
+            shared: self.shared,
+            session: None,
+            lw: 256,
+            read_hw: 4 * 1024,
+            write_hw: 4 * 1024,
+            keepalive: 30,
+            extension: None,
+            upgrade: Some(Box::new(move |parts| {
+                ntex::rt::spawn(f(parts));
+            })),
+            packet: codec::ConnectAck::default(),
+        }
+    }
+
     #[inline]
     /// Create handshake ack object with error
     pub fn failed<St>(self, reason_code: codec::ConnectAckReason) -> HandshakeAck<Io, St> {
@@ -80,6 +149,8 @@ impl<Io> Handshake<Io> {
             shared: self.shared,
             session: None,
             keepalive: 30,
+            upgrade: None,
+            extension: None,
             lw: 256,
             read_hw: 4 * 1024,
             write_hw: 4 * 1024,
@@ -99,6 +170,8 @@ impl<Io> Handshake<Io> {
             read_hw: 4 * 1024,
             write_hw: 4 * 1024,
             keepalive: 30,
+            upgrade: None,
+            extension: None,
         }
     }
 }
@@ -119,16 +192,20 @@ pub struct HandshakeAck<Io, St> {
     pub(crate) lw: u16,
     pub(crate) read_hw: u16,
     pub(crate) write_hw: u16,
+    pub(crate) upgrade: Option<Box<dyn FnOnce(FramedParts<Io, codec::Codec>)>>,
+    pub(crate) extension: Option<Box<dyn CodecExtension<codec::Packet>>>,
 }
 
 impl<Io, St> HandshakeAck<Io, St> {
     #[inline]
-    /// Set idle keep-alive for the connection in seconds.
+    /// Set idle keep-alive for the connection.
     /// This method sets `server_keepalive_sec` property for `ConnectAck`
-    /// response packet.
+    /// response packet, timeout is rounded up to whole seconds.
     ///
-    /// By default idle keep-alive is set to 30 seconds. Panics if timeout is `0`.
-    pub fn keep_alive(mut self, timeout: u16) -> Self {
+    /// By default idle keep-alive is set to 30 seconds. Panics if timeout
+    /// is zero.
+    pub fn keep_alive(mut self, timeout: Duration) -> Self {
+        let timeout = duration_to_secs(timeout);
         if timeout == 0 {
             panic!("Timeout must be greater than 0")
         }
@@ -153,6 +230,29 @@ impl<Io, St> HandshakeAck<Io, St> {
         self
     }
 
+    /// Set codec extension for the connection
+    ///
+    /// Extension is applied to all packets after `CONNACK` is sent.
+    pub fn codec_extension<T>(mut self, ext: T) -> Self
+    where
+        T: CodecExtension<codec::Packet> + 'static,
+    {
+        self.extension = Some(Box::new(ext));
+        self
+    }
+
+    #[inline]
+    /// Set max number of outgoing in-flight publishes.
+    ///
+    /// Sink never sends more publishes than peer's `receive max` allows,
+    /// this setting additionally limits number of in-flight publishes locally.
+    /// If value is `0`, only peer's `receive max` is used.
+    /// By default local limit is not set.
+    pub fn max_inflight(self, val: u16) -> Self {
+        self.shared.set_max_inflight(if val == 0 { u16::MAX } else { val } as usize);
+        self
+    }
+
     #[doc(hidden)]
     #[deprecated(since = "0.6.3")]
     pub fn low_watermark(mut self, lw: u16) -> Self {
@@ -1,28 +1,73 @@
 //! MQTT5 Client/Server framework
 
+#[cfg(feature = "runtime")]
+mod channel;
+#[cfg(feature = "runtime")]
 pub mod client;
 pub mod codec;
+#[cfg(feature = "compress")]
+#[cfg(feature = "runtime")]
+pub mod compress;
+#[cfg(feature = "runtime")]
 pub mod control;
+#[cfg(feature = "runtime")]
 mod default;
+#[cfg(feature = "runtime")]
+mod delivery;
+#[cfg(feature = "runtime")]
 mod dispatcher;
+#[cfg(feature = "runtime")]
 pub mod error;
+#[cfg(feature = "runtime")]
+mod fanout;
+#[cfg(feature = "runtime")]
 mod handshake;
+pub mod prelude;
+pub mod proto;
+#[cfg(feature = "runtime")]
 mod publish;
+#[cfg(feature = "runtime")]
 mod router;
+#[cfg(feature = "runtime")]
 mod selector;
+#[cfg(feature = "runtime")]
 mod server;
+#[cfg(feature = "runtime")]
 mod shared;
+#[cfg(feature = "runtime")]
 mod sink;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
+#[cfg(feature = "runtime")]
 pub type Session<St> = crate::Session<MqttSink, St>;
+#[cfg(feature = "runtime")]
+pub type SinkRegistry = crate::SinkRegistry<MqttSink>;
 
+#[cfg(feature = "runtime")]
+pub use self::channel::{ControlRequest, ControlResponder};
+#[cfg(feature = "runtime")]
 pub use self::control::{ControlMessage, ControlResult};
+#[cfg(feature = "runtime")]
+pub use self::delivery::{Delivery, Subscriptions};
+#[cfg(feature = "runtime")]
+pub use self::fanout::{Fanout, FanoutResult};
+#[cfg(feature = "runtime")]
 pub use self::handshake::{Handshake, HandshakeAck};
-pub use self::publish::{Publish, PublishAck};
-pub use self::router::Router;
+#[cfg(feature = "runtime")]
+pub use self::publish::{
+    Publish, PublishAck, PublishErrorReason, PublishFailure, PublishMetric,
+};
+#[cfg(feature = "runtime")]
+pub use self::router::{HandlerResponse, Router};
+#[cfg(feature = "runtime")]
 pub use self::selector::Selector;
+#[cfg(feature = "runtime")]
 pub use self::server::MqttServer;
-pub use self::sink::{MqttSink, PublishBuilder, SubscribeBuilder, UnsubscribeBuilder};
+#[cfg(feature = "runtime")]
+pub use self::sink::{
+    MqttSink, PublishBuilder, SendPermit, SubscribeBuilder, SubscribeResult, UnsubscribeBuilder,
+};
 
 pub use crate::topic::Topic;
 pub use crate::types::QoS;
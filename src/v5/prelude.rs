@@ -0,0 +1,36 @@
+//! Commonly used mqtt v5 types
+//!
+//! Client control types are exported as `ClientControlMessage` and
+//! `ClientControlResult` to avoid conflict with server control types.
+//!
+//! ```rust
+//! use ntex_mqtt::v5::prelude::*;
+//!
+//! async fn handshake(
+//!     handshake: Handshake<ntex::rt::net::TcpStream>,
+//! ) -> Result<HandshakeAck<ntex::rt::net::TcpStream, ()>, ()> {
+//!     Ok(handshake.ack(()))
+//! }
+//!
+//! async fn publish(publish: Publish) -> Result<PublishAck, ()> {
+//!     log::info!("incoming publish: {:?} {:?}", publish.topic(), publish.qos());
+//!     Ok(publish.ack())
+//! }
+//! ```
+#[cfg(feature = "runtime")]
+pub use super::client::{
+    Client, ClientRouter, ControlMessage as ClientControlMessage,
+    ControlResult as ClientControlResult, MqttConnector,
+};
+pub use super::codec;
+#[cfg(feature = "runtime")]
+pub use super::error::ClientError;
+#[cfg(feature = "runtime")]
+pub use super::{
+    ControlMessage, ControlResult, HandlerResponse, Handshake, HandshakeAck, MqttServer,
+    MqttSink, Publish, PublishAck, PublishBuilder, Router, Selector, Session, SubscribeBuilder,
+    UnsubscribeBuilder,
+};
+pub use crate::error::{MqttError, ProtocolError};
+pub use crate::topic::Topic;
+pub use crate::types::QoS;
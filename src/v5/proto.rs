@@ -0,0 +1,642 @@
+//! Sans-IO client protocol state machine
+//!
+//! `Connection` implements mqtt v5 client protocol logic without doing any
+//! io and without reading system clock, so it can be driven by any runtime,
+//! including `wasm32` targets. Bytes received from the transport are passed
+//! to `Connection::receive()`, protocol events are available via
+//! `Connection::poll_event()` and bytes that must be sent to the server via
+//! `Connection::transmit()`. Driver must call `Connection::handle_timeout()`
+//! at time returned by `Connection::poll_timeout()`.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::{num::NonZeroU16, time::Duration};
+
+#[cfg(not(feature = "wasm"))]
+use std::time::Instant;
+#[cfg(feature = "wasm")]
+use web_time::Instant;
+
+use ntex_bytes::{Bytes, BytesMut};
+use ntex_codec::{Decoder, Encoder};
+
+use super::codec::{self, Codec, Packet, QoS};
+use crate::error::{EncodeError, ProtocolError, SendPacketError};
+
+/// Protocol events
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// Server accepted connection
+    Connected(Box<codec::ConnectAck>),
+    /// Server refused connection, connection is closed
+    Refused(Box<codec::ConnectAck>),
+    /// Publish from server
+    ///
+    /// Acknowledgement packets are generated automatically.
+    Publish(codec::Publish),
+    /// Publish with QoS 1 is acknowledged, or QoS 2 publish is rejected by server
+    PublishAck(codec::PublishAck),
+    /// Publish with QoS 2 is completed
+    PublishComplete(codec::PublishAck2),
+    /// Subscribe is acknowledged by server
+    SubscribeAck(codec::SubscribeAck),
+    /// Unsubscribe is acknowledged by server
+    UnsubscribeAck(codec::UnsubscribeAck),
+    /// Connection is closed, server's `DISCONNECT` packet if any
+    Disconnected(Option<codec::Disconnect>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Request {
+    Subscribe,
+    Unsubscribe,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    Connecting,
+    Connected,
+    Closed,
+}
+
+/// Sans-IO mqtt v5 client connection
+pub struct Connection {
+    codec: Codec,
+    state: State,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    events: VecDeque<Event>,
+    next_id: u16,
+    inflight: HashSet<u16>,
+    pending_release: HashSet<u16>,
+    requests: HashMap<u16, Request>,
+    received: HashSet<u16>,
+    receive_max: u16,
+    keep_alive: u16,
+    last_received: Option<Instant>,
+    last_sent: Option<Instant>,
+    ping_sent: Option<Instant>,
+}
+
+impl Connection {
+    /// Create connection, `CONNECT` packet is queued for transmit
+    pub fn new(connect: codec::Connect) -> Self {
+        let mut conn = Connection {
+            codec: Codec::new(),
+            state: State::Connecting,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            events: VecDeque::new(),
+            next_id: 0,
+            inflight: HashSet::new(),
+            pending_release: HashSet::new(),
+            requests: HashMap::new(),
+            received: HashSet::new(),
+            receive_max: u16::MAX,
+            keep_alive: connect.keep_alive,
+            last_received: None,
+            last_sent: None,
+            ping_sent: None,
+        };
+        // connect packet is always encodable
+        let _ = conn.send(Packet::Connect(connect));
+        conn
+    }
+
+    /// Set max inbound packet size
+    pub fn max_packet_size(self, size: u32) -> Self {
+        self.codec.set_max_inbound_size(size);
+        self
+    }
+
+    /// Check if connection is acknowledged by server
+    pub fn is_connected(&self) -> bool {
+        self.state == State::Connected
+    }
+
+    /// Check if connection is closed
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed
+    }
+
+    /// Check if connection can send QoS 1 and 2 publishes
+    ///
+    /// Number of in-flight publishes is limited by server's receive maximum.
+    pub fn is_ready(&self) -> bool {
+        self.state == State::Connected && self.inflight() < self.receive_max as usize
+    }
+
+    /// Number of outgoing publishes waiting for acknowledgement
+    pub fn inflight(&self) -> usize {
+        self.inflight.len() + self.pending_release.len()
+    }
+
+    /// Keep-alive interval, negotiated with server
+    pub fn keep_alive(&self) -> Option<Duration> {
+        if self.keep_alive == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(self.keep_alive as u64))
+        }
+    }
+
+    /// Feed bytes received from transport at `now`
+    pub fn receive(&mut self, now: Instant, data: &[u8]) {
+        if self.state != State::Closed {
+            self.last_received = Some(now);
+            self.read_buf.extend_from_slice(data);
+        }
+    }
+
+    /// Transport is closed by peer
+    pub fn receive_eof(&mut self) {
+        if self.state != State::Closed {
+            self.close(None);
+        }
+    }
+
+    /// Get next protocol event
+    ///
+    /// Protocol error closes connection.
+    pub fn poll_event(&mut self) -> Result<Option<Event>, ProtocolError> {
+        loop {
+            if let Some(ev) = self.events.pop_front() {
+                return Ok(Some(ev));
+            }
+            if self.state == State::Closed {
+                return Ok(None);
+            }
+
+            match self.codec.decode(&mut self.read_buf) {
+                Ok(Some(pkt)) => {
+                    if let Err(e) = self.handle(pkt) {
+                        self.close(None);
+                        return Err(e);
+                    }
+                }
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    self.close(None);
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    /// Take bytes that must be written to transport at `now`
+    pub fn transmit(&mut self, now: Instant) -> Bytes {
+        if !self.write_buf.is_empty() {
+            self.last_sent = Some(now);
+        }
+        self.write_buf.split().freeze()
+    }
+
+    /// Check if there are bytes to write
+    pub fn has_transmit(&self) -> bool {
+        !self.write_buf.is_empty()
+    }
+
+    /// Publish message
+    ///
+    /// Packet id is assigned to QoS 1 and 2 messages, `PublishAck` or
+    /// `PublishComplete` event is generated when server acknowledges message.
+    /// Returns `SendPacketError::PacketIdInUse` if all packet ids are in use.
+    pub fn publish(
+        &mut self,
+        mut publish: codec::Publish,
+    ) -> Result<Option<NonZeroU16>, SendPacketError> {
+        publish.packet_id =
+            if publish.qos == QoS::AtMostOnce { None } else { Some(self.next_id()?) };
+        let packet_id = publish.packet_id;
+        self.send(Packet::Publish(publish)).map_err(SendPacketError::Encode)?;
+        if let Some(id) = packet_id {
+            self.inflight.insert(id.get());
+        }
+        Ok(packet_id)
+    }
+
+    /// Subscribe to topic filters, packet id is assigned by connection
+    pub fn subscribe(
+        &mut self,
+        mut subscribe: codec::Subscribe,
+    ) -> Result<NonZeroU16, SendPacketError> {
+        subscribe.packet_id = self.next_id()?;
+        let packet_id = subscribe.packet_id;
+        self.send(Packet::Subscribe(subscribe)).map_err(SendPacketError::Encode)?;
+        self.requests.insert(packet_id.get(), Request::Subscribe);
+        Ok(packet_id)
+    }
+
+    /// Unsubscribe from topic filters, packet id is assigned by connection
+    pub fn unsubscribe(
+        &mut self,
+        mut unsubscribe: codec::Unsubscribe,
+    ) -> Result<NonZeroU16, SendPacketError> {
+        unsubscribe.packet_id = self.next_id()?;
+        let packet_id = unsubscribe.packet_id;
+        self.send(Packet::Unsubscribe(unsubscribe)).map_err(SendPacketError::Encode)?;
+        self.requests.insert(packet_id.get(), Request::Unsubscribe);
+        Ok(packet_id)
+    }
+
+    /// Send `DISCONNECT` packet and close connection
+    pub fn disconnect(&mut self, pkt: codec::Disconnect) {
+        if self.state != State::Closed {
+            let _ = self.send(Packet::Disconnect(pkt));
+            self.close(None);
+        }
+    }
+
+    /// Time when `handle_timeout()` must be called
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        if self.state != State::Connected {
+            return None;
+        }
+        let keep_alive = self.keep_alive()?;
+        match self.ping_sent {
+            Some(sent) => Some(sent + keep_alive),
+            None => self.last_sent.map(|t| t + keep_alive),
+        }
+    }
+
+    /// Handle keep-alive timer
+    ///
+    /// Sends `PINGREQ` if nothing was sent within keep-alive interval,
+    /// returns error if server does not respond in time.
+    pub fn handle_timeout(&mut self, now: Instant) -> Result<(), ProtocolError> {
+        if let Some(timeout) = self.poll_timeout() {
+            if now >= timeout {
+                if self.ping_sent.is_some() {
+                    self.close(None);
+                    return Err(ProtocolError::KeepAliveTimeout);
+                }
+                let _ = self.send(Packet::PingRequest);
+                self.ping_sent = Some(now);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, pkt: Packet) -> Result<(), ProtocolError> {
+        match (self.state, pkt) {
+            (State::Connecting, Packet::ConnectAck(ack)) => {
+                if ack.reason_code == codec::ConnectAckReason::Success {
+                    self.state = State::Connected;
+                    // keep-alive interval starts when connection is acknowledged
+                    self.last_sent = self.last_received;
+                    if let Some(val) = ack.receive_max {
+                        self.receive_max = val.get();
+                    }
+                    if let Some(val) = ack.server_keepalive_sec {
+                        self.keep_alive = val;
+                    }
+                    if let Some(val) = ack.max_packet_size {
+                        self.codec.set_max_outbound_size(val);
+                    }
+                    self.events.push_back(Event::Connected(Box::new(ack)));
+                } else {
+                    self.events.push_back(Event::Refused(Box::new(ack)));
+                    self.close(None);
+                }
+            }
+            (State::Connecting, pkt) => {
+                return Err(ProtocolError::Unexpected(
+                    pkt.packet_type(),
+                    "Expected CONNECT-ACK packet",
+                ))
+            }
+            (_, Packet::Publish(publish)) => match (publish.qos, publish.packet_id) {
+                (QoS::AtLeastOnce, Some(packet_id)) => {
+                    self.events.push_back(Event::Publish(publish));
+                    self.send(Packet::PublishAck(codec::PublishAck {
+                        packet_id,
+                        ..Default::default()
+                    }))?;
+                }
+                (QoS::ExactlyOnce, Some(packet_id)) => {
+                    // re-delivered publish is not reported twice
+                    if self.received.insert(packet_id.get()) {
+                        self.events.push_back(Event::Publish(publish));
+                    }
+                    self.send(Packet::PublishReceived(codec::PublishAck {
+                        packet_id,
+                        ..Default::default()
+                    }))?;
+                }
+                _ => self.events.push_back(Event::Publish(publish)),
+            },
+            (_, Packet::PublishRelease(ack)) => {
+                self.received.remove(&ack.packet_id.get());
+                self.send(Packet::PublishComplete(codec::PublishAck2 {
+                    packet_id: ack.packet_id,
+                    reason_code: codec::PublishAck2Reason::Success,
+                    properties: codec::UserProperties::default(),
+                    reason_string: None,
+                }))?;
+            }
+            (_, Packet::PublishAck(ack)) => {
+                if !self.inflight.remove(&ack.packet_id.get()) {
+                    return Err(ProtocolError::PacketIdMismatch);
+                }
+                self.events.push_back(Event::PublishAck(ack));
+            }
+            (_, Packet::PublishReceived(ack)) => {
+                let id = ack.packet_id.get();
+                if !self.inflight.remove(&id) && !self.pending_release.contains(&id) {
+                    return Err(ProtocolError::PacketIdMismatch);
+                }
+                if u8::from(ack.reason_code) >= 0x80 {
+                    // publish is rejected, exchange is complete
+                    self.pending_release.remove(&id);
+                    self.events.push_back(Event::PublishAck(ack));
+                } else {
+                    self.pending_release.insert(id);
+                    self.send(Packet::PublishRelease(codec::PublishAck2 {
+                        packet_id: ack.packet_id,
+                        reason_code: codec::PublishAck2Reason::Success,
+                        properties: codec::UserProperties::default(),
+                        reason_string: None,
+                    }))?;
+                }
+            }
+            (_, Packet::PublishComplete(ack)) => {
+                if !self.pending_release.remove(&ack.packet_id.get()) {
+                    return Err(ProtocolError::PacketIdMismatch);
+                }
+                self.events.push_back(Event::PublishComplete(ack));
+            }
+            (_, Packet::SubscribeAck(ack)) => {
+                self.ack(ack.packet_id, Request::Subscribe)?;
+                self.events.push_back(Event::SubscribeAck(ack))
+            }
+            (_, Packet::UnsubscribeAck(ack)) => {
+                self.ack(ack.packet_id, Request::Unsubscribe)?;
+                self.events.push_back(Event::UnsubscribeAck(ack))
+            }
+            (_, Packet::PingResponse) => self.ping_sent = None,
+            (_, Packet::Disconnect(pkt)) => self.close(Some(pkt)),
+            (_, pkt) => {
+                return Err(ProtocolError::Unexpected(
+                    pkt.packet_type(),
+                    "Unexpected packet from server",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, pkt: Packet) -> Result<(), EncodeError> {
+        self.codec.encode(pkt, &mut self.write_buf)
+    }
+
+    fn ack(&mut self, packet_id: NonZeroU16, tp: Request) -> Result<(), ProtocolError> {
+        if self.requests.get(&packet_id.get()) == Some(&tp) {
+            self.requests.remove(&packet_id.get());
+            Ok(())
+        } else {
+            Err(ProtocolError::PacketIdMismatch)
+        }
+    }
+
+    fn next_id(&mut self) -> Result<NonZeroU16, SendPacketError> {
+        let mut idx = self.next_id;
+        for _ in 0..u16::MAX {
+            idx = if idx == u16::MAX { 1 } else { idx + 1 };
+            if !self.inflight.contains(&idx)
+                && !self.pending_release.contains(&idx)
+                && !self.requests.contains_key(&idx)
+            {
+                self.next_id = idx;
+                return Ok(NonZeroU16::new(idx).unwrap());
+            }
+        }
+        Err(SendPacketError::PacketIdInUse(idx))
+    }
+
+    fn close(&mut self, pkt: Option<codec::Disconnect>) {
+        self.state = State::Closed;
+        self.read_buf.clear();
+        self.events.push_back(Event::Disconnected(pkt));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex_bytes::ByteString;
+
+    use super::*;
+
+    fn encode(pkt: Packet) -> BytesMut {
+        let mut buf = BytesMut::new();
+        Codec::new().encode(pkt, &mut buf).unwrap();
+        buf
+    }
+
+    fn decode(data: Bytes) -> Vec<Packet> {
+        let codec = Codec::new();
+        let mut buf = BytesMut::from(&data[..]);
+        let mut pkts = Vec::new();
+        while let Some(pkt) = codec.decode(&mut buf).unwrap() {
+            pkts.push(pkt);
+        }
+        pkts
+    }
+
+    fn publish(qos: QoS) -> codec::Publish {
+        codec::Publish {
+            qos,
+            dup: false,
+            retain: false,
+            topic: ByteString::from_static("t"),
+            packet_id: None,
+            payload: Bytes::from_static(b"data"),
+            properties: codec::PublishProperties::default(),
+        }
+    }
+
+    fn connect() -> codec::Connect {
+        codec::Connect {
+            client_id: ByteString::from_static("client"),
+            keep_alive: 30,
+            ..Default::default()
+        }
+    }
+
+    fn connected(now: Instant, ack: codec::ConnectAck) -> Connection {
+        let mut conn = Connection::new(connect());
+        assert!(std::matches!(decode(conn.transmit(now))[..], [Packet::Connect(_)]));
+        conn.receive(now, &encode(Packet::ConnectAck(ack.clone())));
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::Connected(Box::new(ack))));
+        conn
+    }
+
+    #[test]
+    fn test_connect() {
+        let now = Instant::now();
+        let conn = connected(
+            now,
+            codec::ConnectAck {
+                receive_max: NonZeroU16::new(1),
+                server_keepalive_sec: Some(10),
+                ..Default::default()
+            },
+        );
+        assert!(conn.is_connected());
+        assert_eq!(conn.keep_alive(), Some(Duration::from_secs(10)));
+
+        let mut conn = Connection::new(connect());
+        let ack = codec::ConnectAck {
+            reason_code: codec::ConnectAckReason::NotAuthorized,
+            ..Default::default()
+        };
+        conn.receive(now, &encode(Packet::ConnectAck(ack.clone())));
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::Refused(Box::new(ack))));
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::Disconnected(None)));
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn test_publish() {
+        let now = Instant::now();
+        let mut conn = connected(
+            now,
+            codec::ConnectAck { receive_max: NonZeroU16::new(2), ..Default::default() },
+        );
+        assert_eq!(conn.publish(publish(QoS::AtMostOnce)).unwrap(), None);
+        let id = conn.publish(publish(QoS::AtLeastOnce)).unwrap().unwrap();
+        let id2 = conn.publish(publish(QoS::ExactlyOnce)).unwrap().unwrap();
+        assert!(!conn.is_ready());
+        assert_eq!(decode(conn.transmit(now)).len(), 3);
+
+        let ack = codec::PublishAck { packet_id: id, ..Default::default() };
+        conn.receive(now, &encode(Packet::PublishAck(ack.clone())));
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::PublishAck(ack)));
+        assert!(conn.is_ready());
+
+        conn.receive(
+            now,
+            &encode(Packet::PublishReceived(codec::PublishAck {
+                packet_id: id2,
+                ..Default::default()
+            })),
+        );
+        assert_eq!(conn.poll_event().unwrap(), None);
+        assert!(std::matches!(
+            decode(conn.transmit(now))[..],
+            [Packet::PublishRelease(codec::PublishAck2 { packet_id, .. })] if packet_id == id2
+        ));
+        let ack = codec::PublishAck2 {
+            packet_id: id2,
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: Vec::new(),
+            reason_string: None,
+        };
+        conn.receive(now, &encode(Packet::PublishComplete(ack.clone())));
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::PublishComplete(ack)));
+        assert_eq!(conn.inflight(), 0);
+
+        // rejected QoS 2 publish
+        let id3 = conn.publish(publish(QoS::ExactlyOnce)).unwrap().unwrap();
+        let ack = codec::PublishAck {
+            packet_id: id3,
+            reason_code: codec::PublishAckReason::QuotaExceeded,
+            ..Default::default()
+        };
+        conn.receive(now, &encode(Packet::PublishReceived(ack.clone())));
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::PublishAck(ack)));
+        assert_eq!(conn.inflight(), 0);
+    }
+
+    #[test]
+    fn test_inbound_publish() {
+        let now = Instant::now();
+        let mut conn = connected(now, codec::ConnectAck::default());
+        conn.transmit(now);
+        let packet_id = NonZeroU16::new(3).unwrap();
+        let pkt = codec::Publish { packet_id: Some(packet_id), ..publish(QoS::AtLeastOnce) };
+        conn.receive(now, &encode(Packet::Publish(pkt.clone())));
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::Publish(pkt)));
+        assert_eq!(
+            decode(conn.transmit(now)),
+            vec![Packet::PublishAck(codec::PublishAck { packet_id, ..Default::default() })]
+        );
+
+        let disconnect = codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::ServerShuttingDown,
+            ..Default::default()
+        };
+        conn.receive(now, &encode(Packet::Disconnect(disconnect.clone())));
+        assert_eq!(conn.poll_event().unwrap(), Some(Event::Disconnected(Some(disconnect))));
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn test_packet_ids() {
+        let now = Instant::now();
+        let mut conn = connected(now, codec::ConnectAck::default());
+        let id = conn
+            .subscribe(codec::Subscribe {
+                packet_id: NonZeroU16::new(1).unwrap(),
+                topic_filters: Vec::new(),
+                id: None,
+                user_properties: Vec::new(),
+            })
+            .unwrap();
+        let id2 = conn.publish(publish(QoS::AtLeastOnce)).unwrap().unwrap();
+        assert_ne!(id, id2);
+
+        // ack of other packet type
+        conn.receive(
+            now,
+            &encode(Packet::UnsubscribeAck(codec::UnsubscribeAck {
+                packet_id: id,
+                properties: Vec::new(),
+                reason_string: None,
+                status: Vec::new(),
+            })),
+        );
+        assert!(std::matches!(conn.poll_event(), Err(ProtocolError::PacketIdMismatch)));
+
+        let mut conn = connected(now, codec::ConnectAck::default());
+        for _ in 0..u16::MAX {
+            conn.publish(publish(QoS::ExactlyOnce)).unwrap();
+        }
+        conn.transmit(now);
+        assert_eq!(
+            conn.publish(publish(QoS::AtLeastOnce)),
+            Err(SendPacketError::PacketIdInUse(u16::MAX))
+        );
+        assert_eq!(conn.publish(publish(QoS::AtMostOnce)), Ok(None));
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let now = Instant::now();
+        let mut conn = Connection::new(connect());
+        conn.transmit(now);
+        assert_eq!(conn.poll_timeout(), None);
+
+        let now = now + Duration::from_secs(1);
+        let ack = codec::ConnectAck { server_keepalive_sec: Some(10), ..Default::default() };
+        conn.receive(now, &encode(Packet::ConnectAck(ack)));
+        conn.poll_event().unwrap();
+        assert_eq!(conn.poll_timeout(), Some(now + Duration::from_secs(10)));
+
+        conn.handle_timeout(now + Duration::from_secs(9)).unwrap();
+        assert!(!conn.has_transmit());
+
+        let now = now + Duration::from_secs(10);
+        conn.handle_timeout(now).unwrap();
+        assert_eq!(decode(conn.transmit(now)), vec![Packet::PingRequest]);
+        conn.receive(now, &encode(Packet::PingResponse));
+        assert_eq!(conn.poll_event().unwrap(), None);
+        assert_eq!(conn.poll_timeout(), Some(now + Duration::from_secs(10)));
+
+        let now = now + Duration::from_secs(10);
+        conn.handle_timeout(now).unwrap();
+        assert_eq!(decode(conn.transmit(now)), vec![Packet::PingRequest]);
+        assert!(std::matches!(
+            conn.handle_timeout(now + Duration::from_secs(10)),
+            Err(ProtocolError::KeepAliveTimeout)
+        ));
+        assert!(conn.is_closed());
+    }
+}
@@ -1,21 +1,44 @@
-use std::{mem, num::NonZeroU16};
+use std::{mem, num::NonZeroU16, rc::Rc, str, time::Duration, time::Instant};
 
 use ntex::router::Path;
 use ntex::util::{ByteString, Bytes};
 use serde::de::DeserializeOwned;
 use serde_json::Error as JsonError;
 
+use crate::ack::{self, AckSlot};
+use crate::clock;
+use crate::timeout::TimeoutSlot;
+
 use super::codec;
 
 /// Publish message
 pub struct Publish {
     publish: codec::Publish,
     topic: Path<ByteString>,
+    duplicate: bool,
+    pub(crate) timeout: Option<TimeoutSlot>,
+    pub(crate) auto_ack: Option<AckSlot>,
 }
 
 impl Publish {
     pub(crate) fn new(publish: codec::Publish) -> Self {
-        Self { topic: Path::new(publish.topic.clone()), publish }
+        Self {
+            topic: Path::new(publish.topic.clone()),
+            publish,
+            duplicate: false,
+            timeout: None,
+            auto_ack: None,
+        }
+    }
+
+    pub(crate) fn with_duplicate(mut self, duplicate: bool) -> Self {
+        self.duplicate = duplicate;
+        self
+    }
+
+    pub(crate) fn with_auto_ack(mut self, ack: Option<AckSlot>) -> Self {
+        self.auto_ack = ack;
+        self
     }
 
     #[inline]
@@ -24,6 +47,34 @@ impl Publish {
         self.publish.dup
     }
 
+    #[inline]
+    /// packet is redelivery of already acknowledged publish.
+    ///
+    /// Detection is enabled by server's `dedup_window()` setting,
+    /// router does not call handlers for duplicates.
+    pub fn is_duplicate(&self) -> bool {
+        self.duplicate
+    }
+
+    /// Override publish handler timeout.
+    ///
+    /// Takes effect only if called before publish handler is invoked,
+    /// i.e. by router or by publish service's `call()`. Zero duration
+    /// disables timeout.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        if let Some(ref slot) = self.timeout {
+            slot.set(Some(timeout));
+        }
+    }
+
+    /// Acknowledge publish after publish service completes.
+    ///
+    /// Opts publish out of server's `auto_ack()`, takes effect only if called
+    /// by router or by publish service's `call()`.
+    pub fn set_manual_ack(&mut self) {
+        ack::set_manual(&self.auto_ack);
+    }
+
     #[inline]
     pub fn retain(&self) -> bool {
         self.publish.retain
@@ -73,6 +124,30 @@ impl Publish {
         &self.publish.payload
     }
 
+    #[inline]
+    /// Payload is UTF-8 encoded character data, as indicated by payload format indicator.
+    pub fn is_utf8_payload(&self) -> bool {
+        self.publish.properties.utf8_payload()
+    }
+
+    #[inline]
+    /// Content type of the payload
+    pub fn content_type(&self) -> Option<&str> {
+        self.publish.properties.content_type.as_deref()
+    }
+
+    /// Payload as string slice if payload format indicator is set.
+    ///
+    /// Returns `Ok(None)` for unspecified (binary) payloads, and error
+    /// if payload is marked as UTF-8 but is not valid UTF-8.
+    pub fn payload_str(&self) -> Result<Option<&str>, str::Utf8Error> {
+        if self.is_utf8_payload() {
+            str::from_utf8(&self.publish.payload).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
     /// Replace packet'a payload with empty bytes, returns existing payload.
     pub fn take_payload(&mut self) -> Bytes {
         mem::take(&mut self.publish.payload)
@@ -85,11 +160,7 @@ impl Publish {
 
     /// Create acknowledgement for this packet
     pub fn ack(self) -> PublishAck {
-        PublishAck {
-            reason_code: codec::PublishAckReason::Success,
-            properties: codec::UserProperties::default(),
-            reason_string: None,
-        }
+        PublishAck::new(codec::PublishAckReason::Success)
     }
 
     pub(crate) fn into_inner(self) -> codec::Publish {
@@ -104,10 +175,14 @@ impl std::fmt::Debug for Publish {
 }
 
 /// Publish ack
+///
+/// Dispatcher sends it as `PUBACK` for QoS 1 and as `PUBREC` for QoS 2 publish.
+#[derive(Debug, Clone)]
 pub struct PublishAck {
     pub(crate) reason_code: codec::PublishAckReason,
     pub(crate) properties: codec::UserProperties,
     pub(crate) reason_string: Option<ByteString>,
+    pub(crate) publishes: Vec<codec::Publish>,
 }
 
 impl PublishAck {
@@ -117,6 +192,7 @@ impl PublishAck {
             reason_code: code,
             properties: codec::UserProperties::default(),
             reason_string: None,
+            publishes: Vec::new(),
         }
     }
 
@@ -137,10 +213,189 @@ impl PublishAck {
         self
     }
 
+    /// Add user property
+    #[inline]
+    pub fn property<K, V>(mut self, key: K, value: V) -> Self
+    where
+        ByteString: From<K> + From<V>,
+    {
+        self.properties.push((ByteString::from(key), ByteString::from(value)));
+        self
+    }
+
     /// Set ack reason string
     #[inline]
-    pub fn reason(mut self, reason: ByteString) -> Self {
-        self.reason_string = Some(reason);
+    pub fn reason<R>(mut self, reason: R) -> Self
+    where
+        ByteString: From<R>,
+    {
+        self.reason_string = Some(ByteString::from(reason));
         self
     }
+
+    /// Check if reason code indicates failure
+    #[inline]
+    pub fn is_error(&self) -> bool {
+        self.reason_code.is_error()
+    }
+
+    /// Convert to `PUBACK` or `PUBREC` packet depending on qos of publish packet
+    pub(crate) fn into_packet(self, packet_id: NonZeroU16, qos: codec::QoS) -> codec::Packet {
+        let ack = codec::PublishAck {
+            packet_id,
+            reason_code: self.reason_code,
+            reason_string: self.reason_string,
+            properties: self.properties,
+        };
+        if qos == codec::QoS::ExactlyOnce {
+            codec::Packet::PublishReceived(ack)
+        } else {
+            codec::Packet::PublishAck(ack)
+        }
+    }
+}
+
+/// Map publish handler errors to acknowledgement reason codes
+///
+/// If mapping is configured on a server, failed QoS 1 and QoS 2 publishes get
+/// acknowledged with returned reason code. `None` means error is not recoverable,
+/// it is passed to control service.
+pub trait PublishErrorReason<E> {
+    /// Reason code for publish handler error
+    fn reason(&self, err: &E) -> Option<codec::PublishAckReason>;
+}
+
+impl<E, F> PublishErrorReason<E> for F
+where
+    F: Fn(&E) -> Option<codec::PublishAckReason>,
+{
+    fn reason(&self, err: &E) -> Option<codec::PublishAckReason> {
+        (*self)(err)
+    }
+}
+
+/// Use same reason code for all errors
+impl<E> PublishErrorReason<E> for codec::PublishAckReason {
+    fn reason(&self, _: &E) -> Option<codec::PublishAckReason> {
+        Some(*self)
+    }
+}
+
+/// Publish handling metric, passed to server's publish hook
+#[derive(Debug)]
+pub struct PublishMetric<'a> {
+    /// Publish topic, empty if publish uses existing topic alias
+    pub topic: &'a ByteString,
+    /// Size of publish payload in bytes
+    pub payload_size: usize,
+    pub qos: codec::QoS,
+    /// Time spent in publish handler
+    pub duration: Duration,
+    /// Ack reason code, `None` if handler error is passed to control service
+    pub reason_code: Option<codec::PublishAckReason>,
+}
+
+pub(crate) type PublishHook = Rc<dyn Fn(&PublishMetric<'_>)>;
+
+/// Publish handling failure, passed to server's dead letter hook
+#[derive(Debug)]
+pub enum PublishFailure<'a, E> {
+    /// Publish service error is converted to acknowledgement
+    Ack(&'a PublishAck),
+    /// Publish service error is passed to control service, connection is closed
+    Error(&'a E),
+    /// Publish service did not complete within publish timeout
+    Timeout,
+}
+
+pub(crate) type DeadLetterHook<E> = Rc<dyn Fn(codec::Publish, PublishFailure<'_, E>)>;
+
+/// Publish in progress, reported to publish hook on completion
+pub(crate) struct PublishTrace {
+    hook: PublishHook,
+    topic: ByteString,
+    payload_size: usize,
+    qos: codec::QoS,
+    start: Instant,
+}
+
+impl PublishTrace {
+    pub(crate) fn new(hook: &PublishHook, publish: &codec::Publish) -> Self {
+        PublishTrace {
+            hook: hook.clone(),
+            topic: publish.topic.clone(),
+            payload_size: publish.payload.len(),
+            qos: publish.qos,
+            start: clock::now(),
+        }
+    }
+
+    pub(crate) fn finish(&self, reason_code: Option<codec::PublishAckReason>) {
+        (*self.hook)(&PublishMetric {
+            topic: &self.topic,
+            payload_size: self.payload_size,
+            qos: self.qos,
+            duration: clock::elapsed(self.start),
+            reason_code,
+        })
+    }
+}
+
+impl Default for PublishAck {
+    fn default() -> Self {
+        PublishAck::new(codec::PublishAckReason::Success)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_str() {
+        let mut pkt = codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: None,
+            payload: Bytes::from_static(b"\xff"),
+            properties: codec::PublishProperties::default(),
+        };
+        assert_eq!(Publish::new(pkt.clone()).payload_str(), Ok(None));
+
+        pkt.properties.is_utf8_payload = Some(true);
+        assert!(Publish::new(pkt.clone()).payload_str().is_err());
+
+        pkt.payload = Bytes::from_static(b"{}");
+        pkt.properties.content_type = Some(ByteString::from_static("application/json"));
+        let publish = Publish::new(pkt);
+        assert_eq!(publish.payload_str(), Ok(Some("{}")));
+        assert_eq!(publish.content_type(), Some("application/json"));
+    }
+
+    #[test]
+    fn test_ack_builder() {
+        let ack = PublishAck::new(codec::PublishAckReason::NotAuthorized)
+            .reason("denied")
+            .property("key", "value");
+        assert!(ack.is_error());
+        assert!(!PublishAck::default().is_error());
+
+        let id = NonZeroU16::new(1).unwrap();
+        let pkt = codec::PublishAck {
+            packet_id: id,
+            reason_code: codec::PublishAckReason::NotAuthorized,
+            reason_string: Some(ByteString::from_static("denied")),
+            properties: vec![("key".into(), "value".into())],
+        };
+        assert_eq!(
+            ack.clone().into_packet(id, codec::QoS::AtLeastOnce),
+            codec::Packet::PublishAck(pkt.clone())
+        );
+        assert_eq!(
+            ack.into_packet(id, codec::QoS::ExactlyOnce),
+            codec::Packet::PublishReceived(pkt)
+        );
+    }
 }
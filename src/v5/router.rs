@@ -1,22 +1,76 @@
 use std::task::{Context, Poll};
+use std::time::Duration;
 use std::{cell::Cell, cell::RefCell, future::Future, num::NonZeroU16, pin::Pin, rc::Rc};
 
 use ntex::router::{IntoPattern, Path, RouterBuilder};
 use ntex::service::boxed::{self, BoxService, BoxServiceFactory};
 use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
 use ntex::task::LocalWaker;
-use ntex::util::{ByteString, HashMap};
+use ntex::util::{ByteString, HashMap, Ready};
 
+use super::codec;
 use super::publish::{Publish, PublishAck};
 
 type Handler<S, E> = BoxServiceFactory<S, Publish, PublishAck, E, E>;
 type HandlerService<E> = BoxService<Publish, PublishAck, E>;
 
+/// Per-resource publish settings
+#[derive(Copy, Clone, Default)]
+struct ResourceOptions {
+    timeout: Option<Duration>,
+    manual_ack: bool,
+}
+
+impl ResourceOptions {
+    fn apply(&self, req: &mut Publish) {
+        if let Some(timeout) = self.timeout {
+            req.set_timeout(timeout);
+        }
+        if self.manual_ack {
+            req.set_manual_ack();
+        }
+    }
+}
+
+/// Response of router resource handler
+#[derive(Debug)]
+pub enum HandlerResponse {
+    /// Acknowledge publish
+    Ack(PublishAck),
+    /// Acknowledge publish and send publishes on the same connection.
+    ///
+    /// Publishes are sent after acknowledgement, publish with QoS 2
+    /// is sent with QoS 1.
+    AckAndPublish(Vec<codec::Publish>),
+}
+
+impl From<PublishAck> for HandlerResponse {
+    fn from(ack: PublishAck) -> Self {
+        HandlerResponse::Ack(ack)
+    }
+}
+
+impl From<HandlerResponse> for PublishAck {
+    fn from(res: HandlerResponse) -> Self {
+        match res {
+            HandlerResponse::Ack(ack) => ack,
+            HandlerResponse::AckAndPublish(publishes) => {
+                PublishAck { publishes, ..PublishAck::default() }
+            }
+        }
+    }
+}
+
+fn into_ack<R: Into<HandlerResponse>>(res: R) -> PublishAck {
+    PublishAck::from(res.into())
+}
+
 /// Router - structure that follows the builder pattern
 /// for building publish packet router instances for mqtt server.
 pub struct Router<S, Err> {
     router: RouterBuilder<usize>,
     handlers: Vec<Handler<S, Err>>,
+    options: Vec<ResourceOptions>,
     default: Handler<S, Err>,
 }
 
@@ -42,22 +96,70 @@ where
         Router {
             router: ntex::router::Router::build(),
             handlers: Vec::new(),
+            options: Vec::new(),
             default: boxed::factory(default_service.into_factory()),
         }
     }
 
     /// Configure mqtt resource for a specific topic.
+    ///
+    /// Resource handler responds with `PublishAck` or `HandlerResponse`.
     pub fn resource<T, F, U: 'static>(mut self, address: T, service: F) -> Self
     where
         T: IntoPattern,
         F: IntoServiceFactory<U>,
-        U: ServiceFactory<Config = S, Request = Publish, Response = PublishAck, Error = Err>,
+        U: ServiceFactory<Config = S, Request = Publish, Error = Err>,
+        U::Response: Into<HandlerResponse>,
         Err: From<U::InitError>,
     {
         self.router.path(address, self.handlers.len());
-        self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        self.handlers
+            .push(boxed::factory(service.into_factory().map(into_ack).map_init_err(Err::from)));
+        self.options.push(ResourceOptions::default());
         self
     }
+
+    /// Configure mqtt resource for a specific topic with publish handler timeout.
+    ///
+    /// Timeout overrides server's publish timeout for the resource.
+    pub fn resource_with_timeout<T, F, U>(
+        self,
+        address: T,
+        timeout: Duration,
+        service: F,
+    ) -> Self
+    where
+        T: IntoPattern,
+        F: IntoServiceFactory<U>,
+        U: ServiceFactory<Config = S, Request = Publish, Error = Err> + 'static,
+        U::Response: Into<HandlerResponse>,
+        Err: From<U::InitError>,
+    {
+        let mut slf = self.resource(address, service);
+        if let Some(item) = slf.options.last_mut() {
+            item.timeout = Some(timeout);
+        }
+        slf
+    }
+
+    /// Configure mqtt resource for a specific topic that acknowledges
+    /// publishes with ack returned by resource handler.
+    ///
+    /// Resource opts out of server's `auto_ack()` setting.
+    pub fn resource_with_manual_ack<T, F, U>(self, address: T, service: F) -> Self
+    where
+        T: IntoPattern,
+        F: IntoServiceFactory<U>,
+        U: ServiceFactory<Config = S, Request = Publish, Error = Err> + 'static,
+        U::Response: Into<HandlerResponse>,
+        Err: From<U::InitError>,
+    {
+        let mut slf = self.resource(address, service);
+        if let Some(item) = slf.options.last_mut() {
+            item.manual_ack = true;
+        }
+        slf
+    }
 }
 
 impl<S, Err> IntoServiceFactory<RouterFactory<S, Err>> for Router<S, Err>
@@ -69,6 +171,7 @@ where
         RouterFactory {
             router: self.router.finish(),
             handlers: Rc::new(self.handlers),
+            options: Rc::new(self.options),
             default: self.default,
         }
     }
@@ -77,6 +180,7 @@ where
 pub struct RouterFactory<S, Err> {
     router: ntex::router::Router<usize>,
     handlers: Rc<Vec<Handler<S, Err>>>,
+    options: Rc<Vec<ResourceOptions>>,
     default: Handler<S, Err>,
 }
 
@@ -96,6 +200,7 @@ where
     fn new_service(&self, session: S) -> Self::Future {
         let router = self.router.clone();
         let factories = self.handlers.clone();
+        let options = self.options.clone();
         let default_fut = self.default.new_service(session.clone());
 
         Box::pin(async move {
@@ -104,6 +209,7 @@ where
 
             Ok(RouterService {
                 router,
+                options,
                 default,
                 inner: Rc::new(Inner {
                     session,
@@ -121,6 +227,7 @@ where
 pub struct RouterService<S, Err> {
     inner: Rc<Inner<S, Err>>,
     router: ntex::router::Router<usize>,
+    options: Rc<Vec<ResourceOptions>>,
     default: HandlerService<Err>,
 }
 
@@ -191,12 +298,18 @@ impl<S: Clone + 'static, Err: 'static> Service for RouterService<S, Err> {
     }
 
     fn call(&self, mut req: Self::Request) -> Self::Future {
+        // publish is already handled
+        if req.is_duplicate() {
+            return Box::pin(Ready::Ok(req.ack()));
+        }
+
         if !req.publish_topic().is_empty() {
             if let Some((idx, _info)) = self.router.recognize(req.topic_mut()) {
                 // save info for topic alias
                 if let Some(alias) = req.packet().properties.topic_alias {
                     self.inner.aliases.borrow_mut().insert(alias, (*idx, req.topic().clone()));
                 }
+                self.options[*idx].apply(&mut req);
                 if let Some(hnd) = &self.inner.handlers.borrow()[*idx] {
                     return hnd.call(req);
                 } else {
@@ -209,6 +322,7 @@ impl<S: Clone + 'static, Err: 'static> Service for RouterService<S, Err> {
             let aliases = self.inner.aliases.borrow();
             if let Some(item) = aliases.get(alias) {
                 *req.topic_mut() = item.1.clone();
+                self.options[item.0].apply(&mut req);
                 if let Some(hnd) = &self.inner.handlers.borrow()[item.0] {
                     return hnd.call(req);
                 } else {
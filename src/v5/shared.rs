@@ -1,35 +1,177 @@
-use std::{cell::Cell, cell::RefCell, collections::VecDeque, rc::Rc};
+use std::time::{Duration, Instant};
+use std::{cell::Cell, cell::RefCell, cmp, collections::VecDeque, rc::Rc};
 
-use ntex::channel::pool;
+use ntex::channel::{oneshot, pool};
 use ntex::codec::{Decoder, Encoder};
-use ntex::util::{BytesMut, HashMap};
+use ntex::util::{ByteString, BytesMut, HashMap};
 
 use super::codec;
-use crate::{error, io::State, types::packet_type};
+use crate::backlog::{Backlog, SlowConsumerPolicy};
+use crate::clock;
+use crate::io::{FlushWaiters, ShutdownStatus, State};
+use crate::rewrite::Tenant;
+use crate::types::{packet_type, InflightPacket, InflightType, QoS};
+use crate::CodecExtension;
+use crate::{error, semaphore::Semaphore};
 
 pub(crate) struct MqttShared {
-    pub(super) cap: Cell<usize>,
+    /// receive maximum of the peer
+    receive_max: Cell<usize>,
+    /// local limit of outgoing in-flight packets
+    max_inflight: Cell<usize>,
+    /// in-flight window
+    pub(super) permits: Rc<Semaphore>,
     queues: RefCell<MqttSharedQueues>,
     pub(super) inflight_idx: Cell<u16>,
+    /// time of last encoded outgoing packet
+    pub(super) last_write: Cell<Instant>,
+    /// keep-alive timeout in seconds
+    pub(super) keepalive: Rc<Cell<u16>>,
+    /// smoothed ping round-trip time
+    pub(super) rtt: Cell<Option<Duration>>,
+    /// transport shutdown status, set after io tasks are completed
+    pub(super) shutdown: Rc<Cell<Option<ShutdownStatus>>>,
+    /// connection is stopped because of keep-alive timeout
+    pub(super) expired: Rc<Cell<bool>>,
+    /// waiters for outbound buffer flush
+    pub(super) flush: Rc<FlushWaiters>,
+    /// publishes sent when connection is closed by sink
+    pub(super) on_close: RefCell<Vec<codec::Publish>>,
+    /// prefix of topics of publishes created by sink
+    pub(super) topic_prefix: RefCell<Option<ByteString>>,
+    /// namespace of isolated tenant, removed from topics of publishes
+    pub(super) tenant: RefCell<Option<Tenant>>,
+    /// default properties of publishes created by sink
+    pub(super) publish_properties: RefCell<Option<codec::PublishProperties>>,
+    /// connection is closed because session is taken over
+    pub(super) takeover: Cell<bool>,
+    /// client side of connection
+    pub(super) client: Cell<bool>,
+    /// optional features supported by server, set after handshake
+    pub(super) capabilities: Cell<Capabilities>,
+    /// codec extension, set after handshake
+    extension: RefCell<Option<Box<dyn CodecExtension<codec::Packet>>>>,
+    /// outbound queue watermark
+    pub(super) backlog: Backlog,
     pub(super) pool: Rc<MqttSinkPool>,
     pub(super) state: State,
     pub(super) codec: codec::Codec,
 }
 
+/// Optional features of the server, advertised with `CONNACK` packet
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Capabilities {
+    pub(super) retain: bool,
+    pub(super) wildcard_subscription: bool,
+    pub(super) subscription_identifiers: bool,
+    pub(super) shared_subscription: bool,
+    pub(super) max_qos: QoS,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            retain: true,
+            wildcard_subscription: true,
+            subscription_identifiers: true,
+            shared_subscription: true,
+            max_qos: QoS::ExactlyOnce,
+        }
+    }
+}
+
+impl Capabilities {
+    /// Advertise unsupported features, values set by handshake service are preserved
+    pub(super) fn apply(&self, ack: &mut codec::ConnectAck) {
+        fn set(val: &mut Option<bool>, available: bool) {
+            if val.is_none() && !available {
+                *val = Some(false);
+            }
+        }
+        set(&mut ack.retain_available, self.retain);
+        set(&mut ack.wildcard_subscription_available, self.wildcard_subscription);
+        set(&mut ack.subscription_identifiers_available, self.subscription_identifiers);
+        set(&mut ack.shared_subscription_available, self.shared_subscription);
+    }
+
+    /// Features advertised by `CONNACK` packet, absent property means available
+    pub(super) fn from_ack(ack: &codec::ConnectAck) -> Self {
+        Capabilities {
+            retain: ack.is_retain_available(),
+            wildcard_subscription: ack.is_wildcard_subscription_available(),
+            subscription_identifiers: ack.is_subscription_identifiers_available(),
+            shared_subscription: ack.is_shared_subscription_available(),
+            max_qos: ack.maximum_qos(),
+        }
+    }
+
+    /// Check if publish qos is not above server maximum
+    pub(super) fn check_qos(&self, qos: QoS) -> bool {
+        u8::from(qos) <= u8::from(self.max_qos)
+    }
+
+    /// Downgrade granted qos of subscriptions to server maximum
+    pub(super) fn clamp_granted(&self, status: &mut [codec::SubscribeAckReason]) {
+        let max = u8::from(self.max_qos);
+        for reason in status {
+            let val = u8::from(*reason);
+            if val < 0x80 && val > max {
+                *reason = match self.max_qos {
+                    QoS::AtMostOnce => codec::SubscribeAckReason::GrantedQos0,
+                    QoS::AtLeastOnce => codec::SubscribeAckReason::GrantedQos1,
+                    QoS::ExactlyOnce => codec::SubscribeAckReason::GrantedQos2,
+                };
+            }
+        }
+    }
+
+    /// Reason code for topic filter that uses unsupported feature
+    pub(super) fn check_filter(
+        &self,
+        filter: &str,
+        sub_id: bool,
+    ) -> Option<codec::SubscribeAckReason> {
+        if sub_id && !self.subscription_identifiers {
+            Some(codec::SubscribeAckReason::SubscriptionIdentifiersNotSupported)
+        } else if !self.shared_subscription && filter.starts_with("$share/") {
+            Some(codec::SubscribeAckReason::SharedSubsriptionNotSupported)
+        } else if !self.wildcard_subscription && filter.contains(['+', '#']) {
+            Some(codec::SubscribeAckReason::WildcardSubscriptionsNotSupported)
+        } else {
+            None
+        }
+    }
+}
+
+/// Ack channel, ack type, send time and publish topic of in-flight packet
+pub(super) type InflightEntry =
+    (Option<pool::Sender<Option<Ack>>>, AckType, Instant, Option<ByteString>);
+
 pub(super) struct MqttSharedQueues {
-    pub(super) inflight: HashMap<u16, (pool::Sender<Ack>, AckType)>,
+    /// in-flight packets by packet id.
+    ///
+    /// Channel is taken when waiter is expired, `None` is sent to expired waiter.
+    pub(super) inflight: HashMap<u16, InflightEntry>,
     pub(super) inflight_order: VecDeque<u16>,
-    pub(super) waiters: VecDeque<pool::Sender<()>>,
+    /// send time and waiter of unanswered pings, in order of sending
+    pub(super) pings: VecDeque<(Instant, Option<oneshot::Sender<Duration>>)>,
+}
+
+impl MqttSharedQueues {
+    /// Drop waiters of in-flight packets and pings
+    pub(super) fn clear(&mut self) {
+        self.inflight.clear();
+        self.pings.clear();
+    }
 }
 
 pub(super) struct MqttSinkPool {
-    pub(super) queue: pool::Pool<Ack>,
-    pub(super) waiters: pool::Pool<()>,
+    pub(super) queue: pool::Pool<Option<Ack>>,
 }
 
 impl Default for MqttSinkPool {
     fn default() -> Self {
-        Self { queue: pool::new(), waiters: pool::new() }
+        Self { queue: pool::new() }
     }
 }
 
@@ -37,42 +179,158 @@ impl MqttShared {
     pub(super) fn new(
         state: State,
         codec: codec::Codec,
-        cap: usize,
+        receive_max: usize,
         pool: Rc<MqttSinkPool>,
     ) -> Self {
+        let max_inflight = u16::MAX as usize;
         Self {
             state,
             pool,
             codec,
-            cap: Cell::new(cap),
+            receive_max: Cell::new(receive_max),
+            max_inflight: Cell::new(max_inflight),
+            permits: Rc::new(Semaphore::new(cmp::min(receive_max, max_inflight))),
             queues: RefCell::new(MqttSharedQueues {
                 inflight: HashMap::default(),
                 inflight_order: VecDeque::with_capacity(8),
-                waiters: VecDeque::new(),
+                pings: VecDeque::new(),
             }),
             inflight_idx: Cell::new(0),
+            last_write: Cell::new(clock::now()),
+            keepalive: Rc::new(Cell::new(0)),
+            rtt: Cell::new(None),
+            takeover: Cell::new(false),
+            client: Cell::new(false),
+            shutdown: Rc::default(),
+            expired: Rc::default(),
+            flush: Rc::default(),
+            on_close: RefCell::new(Vec::new()),
+            topic_prefix: RefCell::new(None),
+            tenant: RefCell::new(None),
+            publish_properties: RefCell::new(None),
+            capabilities: Cell::new(Capabilities::default()),
+            extension: RefCell::new(None),
+            backlog: Backlog::new(),
         }
     }
 
+    /// Set codec extension
+    pub(super) fn set_extension(&self, ext: Option<Box<dyn CodecExtension<codec::Packet>>>) {
+        *self.extension.borrow_mut() = ext;
+    }
+
+    /// Check outbound queue watermark
+    pub(super) fn check_backlog(&self) -> Option<SlowConsumerPolicy> {
+        self.backlog.check(|| self.state.write().with_buf(|buf| buf.len()))
+    }
+
     pub(super) fn with_queues<R>(&self, f: impl FnOnce(&mut MqttSharedQueues) -> R) -> R {
         let mut queues = self.queues.borrow_mut();
         f(&mut queues)
     }
 
-    pub(super) fn has_credit(&self) -> bool {
-        self.cap.get() - self.queues.borrow().inflight.len() > 0
+    /// Set receive maximum of the peer
+    pub(super) fn set_receive_max(&self, val: usize) {
+        self.receive_max.set(val);
+        self.permits.set_capacity(self.cap());
+    }
+
+    /// Set local limit of outgoing in-flight packets
+    pub(super) fn set_max_inflight(&self, val: usize) {
+        self.max_inflight.set(val);
+        self.permits.set_capacity(self.cap());
+    }
+
+    /// Size of send window, smaller of peer's receive maximum and local limit
+    pub(super) fn cap(&self) -> usize {
+        cmp::min(self.receive_max.get(), self.max_inflight.get())
     }
 
+    /// Packet id and send time of the oldest in-flight packet
+    pub(super) fn oldest_inflight(&self) -> Option<(u16, Instant)> {
+        self.queues
+            .borrow()
+            .inflight
+            .iter()
+            .map(|(idx, (_, _, sent, _))| (*idx, *sent))
+            .min_by_key(|(_, sent)| *sent)
+    }
+
+    /// Topic of publish created by sink, with topic prefix.
+    ///
+    /// Tenant namespace is removed before prefix is applied, `None` if
+    /// topic is outside of tenant namespace. Empty topic of publish with
+    /// topic alias is not changed.
+    pub(super) fn publish_topic(&self, topic: ByteString) -> Option<ByteString> {
+        let topic = match *self.tenant.borrow() {
+            Some(ref tenant) if !topic.is_empty() => tenant.strip(topic)?,
+            _ => topic,
+        };
+        Some(match *self.topic_prefix.borrow() {
+            Some(ref prefix) if !topic.is_empty() => {
+                ByteString::from(format!("{}{}", prefix, topic))
+            }
+            _ => topic,
+        })
+    }
+
+    /// Properties of publish created by sink
+    pub(super) fn default_properties(&self) -> codec::PublishProperties {
+        self.publish_properties.borrow().clone().unwrap_or_default()
+    }
+
+    /// Write publishes registered for connection close
+    pub(super) fn send_on_close(&self) {
+        for pkt in self.on_close.take() {
+            let _ = self.state.write().encode(codec::Packet::Publish(pkt), self);
+        }
+    }
+
+    /// In-flight packets in order of sending
+    pub(super) fn inflight_packets(&self) -> Vec<InflightPacket> {
+        let queues = self.queues.borrow();
+        queues
+            .inflight_order
+            .iter()
+            .filter_map(|idx| {
+                queues.inflight.get(idx).map(|(_, tp, sent, topic)| InflightPacket {
+                    packet_id: *idx,
+                    packet_type: tp.kind(),
+                    topic: topic.clone(),
+                    age: clock::elapsed(*sent),
+                })
+            })
+            .collect()
+    }
+
+    /// Remove in-flight packet and release its slot in in-flight window
+    pub(super) fn remove_inflight(&self, idx: u16) -> Option<InflightEntry> {
+        let entry = self.with_queues(|queues| {
+            let entry = queues.inflight.remove(&idx)?;
+            queues.inflight_order.retain(|i| *i != idx);
+            Some(entry)
+        });
+        if entry.is_some() {
+            self.permits.release();
+        }
+        entry
+    }
+
+    /// Allocate packet id.
+    ///
+    /// Ids are allocated sequentially from `1..=65535` range, ids
+    /// of in-flight packets are skipped.
     pub(super) fn next_id(&self) -> u16 {
-        let idx = self.inflight_idx.get() + 1;
-        self.inflight_idx.set(idx);
-        if idx == u16::max_value() {
-            self.inflight_idx.set(0);
-            u16::max_value()
-        } else {
-            self.inflight_idx.set(idx);
-            idx
+        let queues = self.queues.borrow();
+        let mut idx = self.inflight_idx.get();
+        for _ in 0..u16::MAX {
+            idx = if idx == u16::MAX { 1 } else { idx + 1 };
+            if !queues.inflight.contains_key(&idx) {
+                break;
+            }
         }
+        self.inflight_idx.set(idx);
+        idx
     }
 }
 
@@ -82,7 +340,13 @@ impl Encoder for MqttShared {
 
     #[inline]
     fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        self.codec.encode(item, dst)
+        self.last_write.set(clock::now());
+        self.backlog.on_write(dst.len());
+        if let Some(ref ext) = *self.extension.borrow() {
+            self.codec.encode(ext.encode(item)?, dst)
+        } else {
+            self.codec.encode(item, dst)
+        }
     }
 }
 
@@ -92,7 +356,16 @@ impl Decoder for MqttShared {
 
     #[inline]
     fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        self.codec.decode(src)
+        match self.codec.decode(src)? {
+            Some(pkt) => {
+                if let Some(ref ext) = *self.extension.borrow() {
+                    ext.decoded(pkt).map(Some)
+                } else {
+                    Ok(Some(pkt))
+                }
+            }
+            None => Ok(None),
+        }
     }
 }
 
@@ -126,6 +399,14 @@ impl Ack {
         }
     }
 
+    pub(super) fn into_packet(self) -> codec::Packet {
+        match self {
+            Ack::Publish(pkt) => codec::Packet::PublishAck(pkt),
+            Ack::Subscribe(pkt) => codec::Packet::SubscribeAck(pkt),
+            Ack::Unsubscribe(pkt) => codec::Packet::UnsubscribeAck(pkt),
+        }
+    }
+
     pub(super) fn publish(self) -> codec::PublishAck {
         if let Ack::Publish(pkt) = self {
             pkt
@@ -168,4 +449,39 @@ impl AckType {
             AckType::Unsubscribe => "UnsubscribeAck",
         }
     }
+
+    pub(super) fn kind(&self) -> InflightType {
+        match self {
+            AckType::Publish => InflightType::Publish,
+            AckType::Subscribe => InflightType::Subscribe,
+            AckType::Unsubscribe => InflightType::Unsubscribe,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_window() {
+        let shared = MqttShared::new(State::new(), codec::Codec::new(), 4, Rc::default());
+        assert_eq!(shared.cap(), 4);
+        assert_eq!(shared.permits.available(), 4);
+
+        // local limit is smaller than peer's receive maximum
+        shared.set_max_inflight(2);
+        assert_eq!(shared.cap(), 2);
+        let p1 = shared.permits.try_acquire().unwrap();
+        assert_eq!(shared.permits.available(), 1);
+        let _p2 = shared.permits.try_acquire().unwrap();
+        assert!(shared.permits.try_acquire().is_none());
+
+        // peer's receive maximum is smaller than local limit
+        shared.set_max_inflight(16);
+        shared.set_receive_max(1);
+        assert_eq!(shared.cap(), 1);
+        drop(p1);
+        assert_eq!(shared.permits.available(), 0);
+    }
 }
@@ -1,12 +1,19 @@
 use std::future::{ready, Future};
-use std::{fmt, num::NonZeroU16, num::NonZeroU32, rc::Rc};
+use std::{fmt, num::NonZeroU16, num::NonZeroU32, rc::Rc, time::Duration, time::Instant};
 
-use ntex::util::{ByteString, Bytes, Either, Ready};
+use ntex::channel::oneshot;
+use ntex::rt::time::sleep;
+use ntex::util::{poll_fn, ByteString, Bytes, Either, Ready};
 
 use super::codec;
 use super::error::{ProtocolError, PublishQos1Error, SendPacketError};
-use super::shared::{Ack, AckType, MqttShared};
-use crate::types::QoS;
+use super::shared::{Ack, AckType, Capabilities, MqttShared};
+use crate::backlog::{SlowConsumerPolicy, Watermark};
+use crate::capture::Recorder;
+use crate::clock;
+use crate::rewrite::Tenant;
+use crate::types::{InflightPacket, QoS};
+use crate::{io::ShutdownStatus, semaphore::Permit, session::SessionEnd};
 
 pub struct MqttSink(Rc<MqttShared>);
 
@@ -28,8 +35,24 @@ impl MqttSink {
 
     /// Get client's receive credit
     pub fn credit(&self) -> usize {
-        let cap = self.0.cap.get();
-        cap - self.0.with_queues(|q| q.inflight.len())
+        self.0.permits.available()
+    }
+
+    /// Get effective keep-alive timeout in seconds.
+    ///
+    /// For server connections it is keep-alive timeout enforced by dispatcher,
+    /// for client connections it is negotiated ping interval. `0` means keep-alive is disabled.
+    pub fn keep_alive(&self) -> u16 {
+        self.0.keepalive.get()
+    }
+
+    /// Change keep-alive timeout of server connection in seconds.
+    ///
+    /// Dispatcher restarts keep-alive timer with new timeout.
+    /// To disable keep-alive timeout set value to `0`. Has no effect for client connections.
+    pub fn set_keep_alive(&self, timeout: u16) {
+        self.0.keepalive.set(timeout);
+        self.0.state.wake_dispatcher();
     }
 
     /// Get notification when packet could be send to the peer.
@@ -37,68 +60,458 @@ impl MqttSink {
     /// Result indicates if connection is alive
     pub fn ready(&self) -> impl Future<Output = bool> {
         if self.0.state.is_open() {
-            self.0
-                .with_queues(|q| {
-                    if q.inflight.len() >= self.0.cap.get() {
-                        let (tx, rx) = self.0.pool.waiters.channel();
-                        self.0.with_queues(move |q| q.waiters.push_back(tx));
-                        return Some(rx);
-                    }
-                    None
-                })
-                .map(|rx| Either::Right(async move { rx.await.is_ok() }))
-                .unwrap_or_else(|| Either::Left(ready(true)))
+            let acquire = self.0.permits.acquire();
+            Either::Right(async move { acquire.await.is_some() })
         } else {
             Either::Left(ready(false))
         }
     }
 
+    /// Wait until queued packets are written to the transport.
+    ///
+    /// Returned future resolves when outbound buffer is drained and io stream
+    /// is flushed, packets queued after the call are flushed as well. Future fails
+    /// with `Disconnected` error if connection is closed before data is written.
+    pub fn flush(&self) -> impl Future<Output = Result<(), SendPacketError>> {
+        let shared = self.0.clone();
+        poll_fn(move |cx| {
+            shared.flush.poll_flushed(&shared.state, cx).map(|flushed| {
+                if flushed {
+                    Ok(())
+                } else {
+                    Err(SendPacketError::Disconnected)
+                }
+            })
+        })
+    }
+
+    /// Reserve slot in the in-flight window.
+    ///
+    /// Permits are granted in the order of calls. Reserved slot is used by
+    /// publish created with `SendPermit::publish()`, dropped permit releases the slot.
+    pub fn acquire_send_permit(
+        &self,
+    ) -> impl Future<Output = Result<SendPermit, SendPacketError>> {
+        let shared = self.0.clone();
+        let acquire = self.0.permits.acquire();
+
+        async move {
+            if shared.state.is_open() {
+                let permit = acquire.await.ok_or(SendPacketError::Disconnected)?;
+                Ok(SendPermit { permit, shared })
+            } else {
+                Err(SendPacketError::Disconnected)
+            }
+        }
+    }
+
+    /// Number of sent packets waiting for acknowledgement from the peer
+    pub fn inflight(&self) -> usize {
+        self.0.with_queues(|q| q.inflight.len())
+    }
+
+    /// Age of the oldest packet waiting for acknowledgement from the peer
+    pub fn inflight_age(&self) -> Option<Duration> {
+        self.0.oldest_inflight().map(|(_, sent)| clock::elapsed(sent))
+    }
+
+    /// Fail waiters of packets that are not acknowledged within `age`.
+    ///
+    /// Waiters get `Expired` error. Packets keep their slots in the in-flight
+    /// window until the peer acknowledges them. Returns number of expired waiters.
+    pub fn expire_inflight(&self, age: Duration) -> usize {
+        self.0.with_queues(|q| {
+            let mut expired = 0;
+            for (tx, _, sent, _) in q.inflight.values_mut() {
+                if clock::elapsed(*sent) >= age {
+                    if let Some(tx) = tx.take() {
+                        let _ = tx.send(None);
+                        expired += 1;
+                    }
+                }
+            }
+            expired
+        })
+    }
+
+    /// Packets waiting for acknowledgement from the peer, in order of sending
+    pub fn inflight_packets(&self) -> Vec<InflightPacket> {
+        self.0.inflight_packets()
+    }
+
+    /// Complete in-flight packet without acknowledgement from the peer.
+    ///
+    /// Packet is removed from in-flight window and its waiter is resolved as if
+    /// acknowledgement is received. Subscribe acknowledgement does not contain
+    /// return codes, so topic filters are reported as failed. Acknowledgement
+    /// for removed packet received later is protocol error.
+    /// Returns `false` if packet is not in-flight.
+    pub fn complete_inflight(&self, packet_id: u16) -> bool {
+        match (self.0.remove_inflight(packet_id), NonZeroU16::new(packet_id)) {
+            (Some((tx, tp, _, _)), Some(packet_id)) => {
+                if let Some(tx) = tx {
+                    let _ = tx.send(Some(match tp {
+                        AckType::Publish => {
+                            Ack::Publish(codec::PublishAck { packet_id, ..Default::default() })
+                        }
+                        AckType::Subscribe => Ack::Subscribe(codec::SubscribeAck {
+                            packet_id,
+                            properties: Default::default(),
+                            reason_string: None,
+                            status: Vec::new(),
+                        }),
+                        AckType::Unsubscribe => Ack::Unsubscribe(codec::UnsubscribeAck {
+                            packet_id,
+                            properties: Default::default(),
+                            reason_string: None,
+                            status: Vec::new(),
+                        }),
+                    }));
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Fail in-flight packet.
+    ///
+    /// Packet is removed from in-flight window, waiter gets `Expired` error.
+    /// Acknowledgement for removed packet received later is protocol error.
+    /// Returns `false` if packet is not in-flight.
+    pub fn fail_inflight(&self, packet_id: u16) -> bool {
+        if let Some((tx, _, _, _)) = self.0.remove_inflight(packet_id) {
+            if let Some(tx) = tx {
+                let _ = tx.send(None);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Call `f` if the oldest in-flight packet is not acknowledged within `threshold`.
+    ///
+    /// Callback receives packet id and its age, it is called once per packet.
+    /// Hook runs until the connection is closed. Must be called within ntex runtime,
+    /// zero threshold disables hook.
+    pub fn on_stuck_ack<F>(&self, threshold: Duration, f: F)
+    where
+        F: Fn(u16, Duration) + 'static,
+    {
+        if threshold == Duration::from_secs(0) {
+            return;
+        }
+        let shared = self.0.clone();
+        ntex::rt::spawn(async move {
+            let mut reported = None;
+            while shared.state.is_open() {
+                let expire = match shared.oldest_inflight() {
+                    Some((idx, sent)) if reported != Some(idx) => {
+                        let age = clock::elapsed(sent);
+                        if age >= threshold {
+                            log::warn!("Packet {} is not acknowledged for {:?}", idx, age);
+                            reported = Some(idx);
+                            f(idx, age);
+                            threshold
+                        } else {
+                            threshold - age
+                        }
+                    }
+                    _ => threshold,
+                };
+                sleep(expire).await;
+            }
+        });
+    }
+
+    /// Enable or disable recording of connection packets
+    ///
+    /// Packets are written to recorder until recording is disabled
+    /// or connection is closed.
+    pub fn set_recorder(&self, recorder: Option<Recorder>) {
+        self.0.codec.set_recorder(recorder);
+    }
+
+    /// Set prefix of topics of publishes created by the sink.
+    ///
+    /// Prefix is prepended to topics of `publish()` and `publish_on_close()`
+    /// publishes, packets sent with `send_packet()` are not changed.
+    pub fn set_topic_prefix(&self, prefix: Option<ByteString>) {
+        *self.0.topic_prefix.borrow_mut() = prefix;
+    }
+
+    pub(super) fn set_tenant(&self, tenant: Tenant) {
+        *self.0.tenant.borrow_mut() = Some(tenant);
+    }
+
+    /// Set default properties of publishes created by the sink.
+    ///
+    /// Publish builders start with default properties, properties could be
+    /// changed with `PublishBuilder::properties()`.
+    pub fn set_publish_properties(&self, properties: Option<codec::PublishProperties>) {
+        *self.0.publish_properties.borrow_mut() = properties;
+    }
+
+    /// Register publish that is sent when connection is closed by the sink.
+    ///
+    /// QoS 0 publish is written before connection is closed with `close()`,
+    /// `close_with_reason()` or `takeover()`. Delivery is best-effort, publishes are
+    /// not sent if connection is closed by the peer or by io error.
+    /// Publishes are sent in order of registration, publishes outside of
+    /// tenant namespace are dropped.
+    pub fn publish_on_close<U>(&self, topic: U, payload: Bytes)
+    where
+        ByteString: From<U>,
+    {
+        let topic = match self.0.publish_topic(topic.into()) {
+            Some(topic) => topic,
+            None => {
+                log::trace!("Drop publish outside of tenant namespace");
+                return;
+            }
+        };
+        self.0.on_close.borrow_mut().push(codec::Publish {
+            topic,
+            payload,
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            packet_id: None,
+            properties: self.0.default_properties(),
+        });
+    }
+
     /// Close mqtt connection with default Disconnect message
     pub fn close(&self) {
         if self.is_open() {
+            self.0.send_on_close();
             let _ = self
                 .0
                 .state
                 .write()
-                .encode(codec::Packet::Disconnect(codec::Disconnect::default()), &self.0.codec);
+                .encode(codec::Packet::Disconnect(codec::Disconnect::default()), &*self.0);
             self.0.state.close();
         }
-        self.0.with_queues(|q| {
-            q.inflight.clear();
-            q.waiters.clear();
-        });
+        self.0.with_queues(|q| q.clear());
+        self.0.permits.close();
     }
 
     /// Close mqtt connection
     pub fn close_with_reason(&self, pkt: codec::Disconnect) {
+        if pkt.reason_code == codec::DisconnectReasonCode::SessionTakenOver {
+            self.0.takeover.set(true);
+        }
         if self.is_open() {
-            let _ = self.0.state.write().encode(codec::Packet::Disconnect(pkt), &self.0.codec);
+            self.0.send_on_close();
+            let _ = self.0.state.write().encode(codec::Packet::Disconnect(pkt), &*self.0);
             self.0.state.close();
         }
-        self.0.with_queues(|q| {
-            q.inflight.clear();
-            q.waiters.clear();
-        });
+        self.0.with_queues(|q| q.clear());
+        self.0.permits.close();
+    }
+
+    /// Close mqtt connection with `DisconnectWithWillMessage` reason.
+    ///
+    /// Server publishes will message of the client connection.
+    pub fn disconnect_with_will(&self) {
+        self.close_with_reason(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::DisconnectWithWillMessage,
+            ..codec::Disconnect::default()
+        })
+    }
+
+    /// Close mqtt connection with `SessionTakenOver` reason.
+    ///
+    /// Session is taken over by new connection of the same client, session
+    /// end of the connection is reported as `SessionEnd::Takeover`.
+    pub fn takeover(&self) {
+        self.close_with_reason(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::SessionTakenOver,
+            ..codec::Disconnect::default()
+        })
     }
 
     pub(super) fn send(&self, pkt: codec::Packet) {
-        let _ = self.0.state.write().encode(pkt, &self.0.codec);
+        let _ = self.0.state.write().encode(pkt, &*self.0);
     }
 
-    /// Send ping
-    pub(super) fn ping(&self) -> bool {
-        self.0.state.write().encode(codec::Packet::PingRequest, &self.0.codec).is_ok()
+    /// Send ping to the peer and measure round-trip time.
+    ///
+    /// Returned future resolves with time between `PINGREQ` and `PINGRESP` packets,
+    /// measured time updates `rtt()` estimate. Only servers respond to pings,
+    /// so ping is allowed for client connections only, server connections
+    /// fail with `SendPacketError::NotAllowed`.
+    pub fn ping(&self) -> impl Future<Output = Result<Duration, SendPacketError>> {
+        let (tx, rx) = oneshot::channel();
+        let client = self.0.client.get();
+        let sent = client && self.send_ping(Some(tx));
+
+        async move {
+            if !client {
+                Err(SendPacketError::NotAllowed)
+            } else if sent {
+                rx.await.map_err(|_| SendPacketError::Disconnected)
+            } else {
+                Err(SendPacketError::Disconnected)
+            }
+        }
+    }
+
+    /// Smoothed ping round-trip time, `None` if no ping is answered yet
+    pub fn rtt(&self) -> Option<Duration> {
+        self.0.rtt.get()
+    }
+
+    /// Transport shutdown status, `None` until connection io is closed
+    pub fn shutdown_status(&self) -> Option<ShutdownStatus> {
+        self.0.shutdown.get()
+    }
+
+    /// Reason of session end, `clean` is set if `DISCONNECT` packet is received
+    pub(super) fn session_end(&self, clean: bool) -> SessionEnd {
+        if clean {
+            SessionEnd::Clean
+        } else if self.0.takeover.get() {
+            SessionEnd::Takeover
+        } else if self.0.expired.get() {
+            SessionEnd::KeepAliveTimeout
+        } else {
+            SessionEnd::Abnormal
+        }
+    }
+
+    /// Send ping, waiter gets notified with round-trip time
+    pub(super) fn send_ping(&self, tx: Option<oneshot::Sender<Duration>>) -> bool {
+        if self.0.state.is_open()
+            && self.0.state.write().encode(codec::Packet::PingRequest, &*self.0).is_ok()
+        {
+            self.0.with_queues(|q| q.pings.push_back((clock::now(), tx)));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Ping response is received, response answers the oldest ping
+    pub(super) fn pong(&self) {
+        if let Some((sent, tx)) = self.0.with_queues(|q| q.pings.pop_front()) {
+            let sample = clock::elapsed(sent);
+            let rtt = match self.0.rtt.get() {
+                Some(rtt) => (rtt * 7 + sample) / 8,
+                None => sample,
+            };
+            self.0.rtt.set(Some(rtt));
+            if let Some(tx) = tx {
+                let _ = tx.send(sample);
+            }
+        }
+    }
+
+    /// Time of the oldest unanswered ping
+    pub(super) fn ping_sent(&self) -> Option<Instant> {
+        self.0.with_queues(|q| q.pings.front().map(|(sent, _)| *sent))
+    }
+
+    /// Set outbound queue watermark
+    pub(super) fn set_watermark(&self, watermark: Option<Watermark>) {
+        self.0.backlog.set_watermark(watermark);
+    }
+
+    /// Captured bytes of last malformed inbound packet
+    pub(super) fn malformed_packet(&self) -> Option<Bytes> {
+        self.0.codec.malformed_packet()
+    }
+
+    /// Optional features supported by server
+    pub(super) fn capabilities(&self) -> Capabilities {
+        self.0.capabilities.get()
+    }
+
+    /// Time of last outgoing packet
+    pub(super) fn last_write(&self) -> Instant {
+        self.0.last_write.get()
     }
 
     /// Close mqtt connection, dont send disconnect message
     pub(super) fn drop_sink(&self) {
-        self.0.with_queues(|q| {
-            q.waiters.clear();
-            q.inflight.clear();
-        });
+        self.0.with_queues(|q| q.clear());
+        self.0.permits.close();
         self.0.state.close();
     }
 
+    /// Send pre-built packet
+    ///
+    /// Packet is sent as is, without any validation. QoS 1 publish, subscribe
+    /// and unsubscribe packets occupy slot in in-flight window and returned future
+    /// resolves with acknowledgement packet from peer, for other packets
+    /// future resolves with `None` right after packet is written to the buffer.
+    pub async fn send_packet(
+        &self,
+        packet: codec::Packet,
+    ) -> Result<Option<codec::Packet>, SendPacketError> {
+        let shared = &self.0;
+        if !shared.state.is_open() {
+            return Err(SendPacketError::Disconnected);
+        }
+
+        let tracked = match packet {
+            codec::Packet::Publish(ref pkt) if pkt.qos == QoS::AtLeastOnce => {
+                pkt.packet_id.map(|id| (id.get(), AckType::Publish, Some(pkt.topic.clone())))
+            }
+            codec::Packet::Subscribe(ref pkt) => {
+                Some((pkt.packet_id.get(), AckType::Subscribe, None))
+            }
+            codec::Packet::Unsubscribe(ref pkt) => {
+                Some((pkt.packet_id.get(), AckType::Unsubscribe, None))
+            }
+            _ => None,
+        };
+
+        if let Some((idx, tp, topic)) = tracked {
+            // wait for slot in in-flight window
+            let permit = shared.permits.acquire().await.ok_or(SendPacketError::Disconnected)?;
+            let rx = shared.with_queues(|queues| {
+                // ack channel
+                let (tx, rx) = shared.pool.queue.channel();
+
+                if queues.inflight.contains_key(&idx) {
+                    return Err(SendPacketError::PacketIdInUse(idx));
+                }
+                queues.inflight.insert(idx, (Some(tx), tp, clock::now(), topic));
+                queues.inflight_order.push_back(idx);
+                Ok(rx)
+            })?;
+            permit.detach();
+
+            log::trace!("Sending packet {:?}", packet);
+            if let Err(err) = shared.state.write().encode(packet, &**shared) {
+                // free packet id and in-flight slot
+                shared.remove_inflight(idx);
+                return Err(SendPacketError::Encode(err));
+            }
+
+            // wait ack from peer
+            rx.await
+                .map_err(|_| SendPacketError::Disconnected)?
+                .ok_or(SendPacketError::Expired)
+                .map(|ack| Some(ack.into_packet()))
+        } else {
+            log::trace!("Sending packet {:?}", packet);
+            shared
+                .state
+                .write()
+                .encode(packet, &**shared)
+                .map_err(SendPacketError::Encode)
+                .map(|_| None)
+        }
+    }
+
+    /// Check if packet id is used by in-flight packet
+    pub(super) fn is_inflight(&self, packet_id: u16) -> bool {
+        self.0.with_queues(|queues| queues.inflight.contains_key(&packet_id))
+    }
+
     pub(super) fn pkt_ack(&self, pkt: Ack) -> Result<(), ProtocolError> {
         self.0.with_queues(|queues| loop {
             // check ack order
@@ -118,7 +531,7 @@ impl MqttSink {
                     // get publish ack channel
                     log::trace!("Ack packet with id: {}", pkt.packet_id());
                     let idx = pkt.packet_id();
-                    if let Some((tx, tp)) = queues.inflight.remove(&idx) {
+                    if let Some((tx, tp, _, _)) = queues.inflight.remove(&idx) {
                         // cleanup ack queue
                         if !pkt.is_match(tp) {
                             log::trace!("MQTT protocol error, unexpeted packet");
@@ -127,14 +540,13 @@ impl MqttSink {
                                 tp.name(),
                             ));
                         }
-                        let _ = tx.send(pkt);
-
-                        // wake up queued request (receive max limit)
-                        while let Some(tx) = queues.waiters.pop_front() {
-                            if tx.send(()).is_ok() {
-                                break;
-                            }
+                        // expired waiter is failed already
+                        if let Some(tx) = tx {
+                            let _ = tx.send(Some(pkt));
                         }
+
+                        // release in-flight window slot
+                        self.0.permits.release();
                         return Ok(());
                     } else {
                         log::error!("In-flight state inconsistency")
@@ -152,17 +564,50 @@ impl MqttSink {
     where
         ByteString: From<U>,
     {
+        let topic = self.0.publish_topic(topic.into());
         PublishBuilder {
+            foreign: topic.is_none(),
             packet: codec::Publish {
                 payload,
                 dup: false,
                 retain: false,
-                topic: topic.into(),
+                topic: topic.unwrap_or_default(),
                 qos: QoS::AtMostOnce,
                 packet_id: None,
-                properties: codec::PublishProperties::default(),
+                properties: self.0.default_properties(),
             },
             shared: self.0.clone(),
+            permit: None,
+        }
+    }
+
+    /// Send publish returned by publish handler.
+    ///
+    /// Publish with QoS 2 is sent with QoS 1.
+    /// Publish with topic outside of tenant namespace is dropped.
+    pub(super) fn send_publish(&self, mut packet: codec::Publish) {
+        packet.topic = match self.0.publish_topic(packet.topic) {
+            Some(topic) => topic,
+            None => {
+                log::trace!("Drop handler publish outside of tenant namespace");
+                return;
+            }
+        };
+        let qos = packet.qos;
+        let builder =
+            PublishBuilder { packet, shared: self.0.clone(), permit: None, foreign: false };
+
+        if qos == QoS::AtMostOnce {
+            if let Err(e) = builder.send_at_most_once() {
+                log::trace!("Cannot send handler publish: {:?}", e);
+            }
+        } else {
+            let fut = builder.send_at_least_once();
+            ntex::rt::spawn(async move {
+                if let Err(e) = fut.await {
+                    log::trace!("Cannot send handler publish: {:?}", e);
+                }
+            });
         }
     }
 
@@ -200,9 +645,50 @@ impl fmt::Debug for MqttSink {
     }
 }
 
+/// Reserved slot in the sink's in-flight window
+///
+/// Slot is released if permit gets dropped without sending publish.
+pub struct SendPermit {
+    permit: Permit,
+    shared: Rc<MqttShared>,
+}
+
+impl SendPermit {
+    /// Create publish packet builder, QoS 1 publish uses reserved slot
+    pub fn publish<U>(self, topic: U, payload: Bytes) -> PublishBuilder
+    where
+        ByteString: From<U>,
+    {
+        let topic = self.shared.publish_topic(topic.into());
+        PublishBuilder {
+            foreign: topic.is_none(),
+            packet: codec::Publish {
+                payload,
+                dup: false,
+                retain: false,
+                topic: topic.unwrap_or_default(),
+                qos: QoS::AtMostOnce,
+                packet_id: None,
+                properties: self.shared.default_properties(),
+            },
+            shared: self.shared,
+            permit: Some(self.permit),
+        }
+    }
+}
+
+impl fmt::Debug for SendPermit {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("SendPermit").finish()
+    }
+}
+
 pub struct PublishBuilder {
     shared: Rc<MqttShared>,
     packet: codec::Publish,
+    permit: Option<Permit>,
+    /// topic is outside of tenant namespace, publish is not sent
+    foreign: bool,
 }
 
 impl PublishBuilder {
@@ -231,6 +717,21 @@ impl PublishBuilder {
         self
     }
 
+    /// Mark payload as UTF-8 encoded character data
+    pub fn format_utf8(mut self) -> Self {
+        self.packet.properties.is_utf8_payload = Some(true);
+        self
+    }
+
+    /// Set content type of the payload
+    pub fn content_type<U>(mut self, content_type: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.packet.properties.content_type = Some(content_type.into());
+        self
+    }
+
     /// Set publish packet properties
     pub fn properties<F>(mut self, f: F) -> Self
     where
@@ -249,15 +750,25 @@ impl PublishBuilder {
     }
 
     /// Send publish packet with QoS 0
+    ///
+    /// Publish with topic outside of tenant namespace fails with `NotAllowed` error.
     pub fn send_at_most_once(self) -> Result<(), SendPacketError> {
+        if self.foreign {
+            return Err(SendPacketError::NotAllowed);
+        }
         let packet = self.packet;
 
+        if !apply_backlog_policy(&self.shared, true) {
+            log::trace!("Drop QoS-0 publish to {:?}, peer is slow", packet.topic);
+            return Ok(());
+        }
+
         if self.shared.state.is_open() {
             log::trace!("Publish (QoS-0) to {:?}", packet.topic);
             self.shared
                 .state
                 .write()
-                .encode(codec::Packet::Publish(packet), &self.shared.codec)
+                .encode(codec::Packet::Publish(packet), &*self.shared)
                 .map_err(SendPacketError::Encode)
                 .map(|_| ())
         } else {
@@ -267,27 +778,37 @@ impl PublishBuilder {
     }
 
     /// Send publish packet with QoS 1
+    ///
+    /// Publish with topic outside of tenant namespace fails with `NotAllowed` error.
     pub fn send_at_least_once(
         self,
     ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
+        if self.foreign {
+            return Either::Left(Either::Left(Ready::Err(PublishQos1Error::NotAllowed)));
+        }
         let shared = self.shared;
         let mut packet = self.packet;
         packet.qos = QoS::AtLeastOnce;
+        apply_backlog_policy(&shared, false);
 
         if shared.state.is_open() {
-            // handle client receive maximum
-            if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.with_queues(|q| q.waiters.push_back(tx));
-
-                return Either::Left(Either::Right(async move {
-                    if rx.await.is_err() {
-                        return Err(PublishQos1Error::Disconnected);
-                    }
-                    Self::send_at_least_once_inner(packet, shared).await
-                }));
+            // wait for slot in in-flight window
+            match self.permit.or_else(|| shared.permits.try_acquire()) {
+                Some(permit) => {
+                    Either::Right(Self::send_at_least_once_inner(packet, shared, permit))
+                }
+                None => {
+                    let acquire = shared.permits.acquire();
+                    Either::Left(Either::Right(async move {
+                        match acquire.await {
+                            Some(permit) => {
+                                Self::send_at_least_once_inner(packet, shared, permit).await
+                            }
+                            None => Err(PublishQos1Error::Disconnected),
+                        }
+                    }))
+                }
             }
-            Either::Right(Self::send_at_least_once_inner(packet, shared))
         } else {
             Either::Left(Either::Left(Ready::Err(PublishQos1Error::Disconnected)))
         }
@@ -296,6 +817,7 @@ impl PublishBuilder {
     fn send_at_least_once_inner(
         mut packet: codec::Publish,
         shared: Rc<MqttShared>,
+        permit: Permit,
     ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
         // packet id
         let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
@@ -311,10 +833,15 @@ impl PublishBuilder {
             if queues.inflight.contains_key(&idx) {
                 return Err(PublishQos1Error::PacketIdInUse(idx));
             }
-            queues.inflight.insert(idx, (tx, AckType::Publish));
+            let topic = Some(packet.topic.clone());
+            queues.inflight.insert(idx, (Some(tx), AckType::Publish, clock::now(), topic));
             queues.inflight_order.push_back(idx);
             Ok(rx)
         });
+        if rx.is_ok() {
+            // slot is released when ack is received
+            permit.detach();
+        }
 
         let rx = match rx {
             Ok(rx) => rx,
@@ -324,15 +851,17 @@ impl PublishBuilder {
         // send publish to client
         log::trace!("Publish (QoS1) to {:#?}", packet);
 
-        match shared.state.write().encode(codec::Packet::Publish(packet), &shared.codec) {
+        match shared.state.write().encode(codec::Packet::Publish(packet), &*shared) {
             Ok(_) => {
                 // wait ack from peer
                 Either::Right(async move {
-                    rx.await.map_err(|_| PublishQos1Error::Disconnected).and_then(|pkt| {
+                    let pkt = rx.await.map_err(|_| PublishQos1Error::Disconnected)?;
+                    pkt.ok_or(PublishQos1Error::Expired).and_then(|pkt| {
                         let pkt = pkt.publish();
-                        match pkt.reason_code {
-                            codec::PublishAckReason::Success => Ok(pkt),
-                            _ => Err(PublishQos1Error::Fail(pkt)),
+                        if pkt.reason_code.is_error() {
+                            Err(PublishQos1Error::Fail(pkt))
+                        } else {
+                            Ok(pkt)
                         }
                     })
                 })
@@ -362,12 +891,14 @@ impl SubscribeBuilder {
     }
 
     /// Add topic filter
-    pub fn topic_filter(
-        mut self,
-        filter: ByteString,
-        opts: codec::SubscriptionOptions,
-    ) -> Self {
-        self.packet.topic_filters.push((filter, opts));
+    ///
+    /// Options could be specified as `QoS` or as `SubscriptionOptions`
+    /// with no-local, retain-as-published and retain handling flags.
+    pub fn topic_filter<O>(mut self, filter: ByteString, opts: O) -> Self
+    where
+        O: Into<codec::SubscriptionOptions>,
+    {
+        self.packet.topic_filters.push((filter, opts.into()));
         self
     }
 
@@ -379,20 +910,14 @@ impl SubscribeBuilder {
 
     #[allow(clippy::await_holding_refcell_ref)]
     /// Send subscribe packet
-    pub async fn send(self) -> Result<codec::SubscribeAck, SendPacketError> {
+    pub async fn send(self) -> Result<SubscribeResult, SendPacketError> {
         let shared = self.shared;
         let mut packet = self.packet;
+        let names: Vec<_> = packet.topic_filters.iter().map(|(f, _)| f.clone()).collect();
 
         if shared.state.is_open() {
-            // handle client receive maximum
-            if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.with_queues(|q| q.waiters.push_back(tx));
-
-                if rx.await.is_err() {
-                    return Err(SendPacketError::Disconnected);
-                }
-            }
+            // wait for slot in in-flight window
+            let permit = shared.permits.acquire().await.ok_or(SendPacketError::Disconnected)?;
             // allocate packet id
             let idx = if self.id == 0 { shared.next_id() } else { self.id };
             packet.packet_id = NonZeroU16::new(idx).unwrap();
@@ -403,20 +928,22 @@ impl SubscribeBuilder {
                 if queues.inflight.contains_key(&idx) {
                     return Err(SendPacketError::PacketIdInUse(idx));
                 }
-                queues.inflight.insert(idx, (tx, AckType::Subscribe));
+                queues.inflight.insert(idx, (Some(tx), AckType::Subscribe, clock::now(), None));
                 queues.inflight_order.push_back(idx);
                 Ok(rx)
             })?;
+            permit.detach();
 
             // send subscribe to client
             log::trace!("Sending subscribe packet {:#?}", packet);
 
-            match shared.state.write().encode(codec::Packet::Subscribe(packet), &shared.codec) {
+            match shared.state.write().encode(codec::Packet::Subscribe(packet), &*shared) {
                 Ok(_) => {
                     // wait ack from peer
                     rx.await
-                        .map_err(|_| SendPacketError::Disconnected)
-                        .map(|pkt| pkt.subscribe())
+                        .map_err(|_| SendPacketError::Disconnected)?
+                        .ok_or(SendPacketError::Expired)
+                        .map(|pkt| SubscribeResult::new(names, pkt.subscribe()))
                 }
                 Err(err) => Err(SendPacketError::Encode(err)),
             }
@@ -426,6 +953,92 @@ impl SubscribeBuilder {
     }
 }
 
+/// Result of subscribe request
+///
+/// Contains outcome for each topic filter, in order of subscribe request,
+/// and properties of SUBACK packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscribeResult {
+    filters: Vec<(ByteString, codec::SubscribeAckReason)>,
+    properties: codec::UserProperties,
+    reason_string: Option<ByteString>,
+}
+
+impl SubscribeResult {
+    fn new(names: Vec<ByteString>, ack: codec::SubscribeAck) -> Self {
+        let mut status = ack.status;
+        if status.len() != names.len() {
+            log::warn!(
+                "Number of reason codes {} does not match number of topic filters {}",
+                status.len(),
+                names.len()
+            );
+            status.resize(names.len(), codec::SubscribeAckReason::UnspecifiedError);
+        }
+        SubscribeResult {
+            filters: names.into_iter().zip(status).collect(),
+            properties: ack.properties,
+            reason_string: ack.reason_string,
+        }
+    }
+
+    /// Number of topic filters
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    /// Check if subscribe request did not contain any topic filters
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Check if all topic filters are granted
+    pub fn is_success(&self) -> bool {
+        self.filters.iter().all(|(_, reason)| !reason.is_error())
+    }
+
+    /// Outcome for topic filter, `None` if topic filter is not part of request
+    pub fn get(&self, filter: &str) -> Option<Result<codec::QoS, codec::SubscribeAckReason>> {
+        self.iter().find(|(f, _)| *f == filter).map(|(_, res)| res)
+    }
+
+    /// Iterate over topic filters with granted QoS or failure reason
+    pub fn iter(
+        &self,
+    ) -> impl Iterator<Item = (&ByteString, Result<codec::QoS, codec::SubscribeAckReason>)>
+    {
+        self.filters.iter().map(|(f, reason)| {
+            let res = match reason {
+                codec::SubscribeAckReason::GrantedQos0 => Ok(codec::QoS::AtMostOnce),
+                codec::SubscribeAckReason::GrantedQos1 => Ok(codec::QoS::AtLeastOnce),
+                codec::SubscribeAckReason::GrantedQos2 => Ok(codec::QoS::ExactlyOnce),
+                reason => Err(*reason),
+            };
+            (f, res)
+        })
+    }
+
+    /// Reason codes, in order of topic filters
+    pub fn reason_codes(&self) -> Vec<codec::ReasonCode> {
+        self.filters.iter().map(|(_, reason)| (*reason).into()).collect()
+    }
+
+    /// SUBACK user properties
+    pub fn properties(&self) -> &codec::UserProperties {
+        &self.properties
+    }
+
+    /// SUBACK reason string
+    pub fn reason_string(&self) -> Option<&ByteString> {
+        self.reason_string.as_ref()
+    }
+
+    /// Consume result and return topic filters with reason codes
+    pub fn into_inner(self) -> Vec<(ByteString, codec::SubscribeAckReason)> {
+        self.filters
+    }
+}
+
 /// Unsubscribe packet builder
 pub struct UnsubscribeBuilder {
     id: u16,
@@ -459,20 +1072,14 @@ impl UnsubscribeBuilder {
 
     #[allow(clippy::await_holding_refcell_ref)]
     /// Send unsubscribe packet
-    pub async fn send(self) -> Result<codec::UnsubscribeAck, SendPacketError> {
+    pub async fn send(self) -> Result<UnsubscribeResult, SendPacketError> {
         let shared = self.shared;
         let mut packet = self.packet;
+        let names = packet.topic_filters.clone();
 
         if shared.state.is_open() {
-            // handle client receive maximum
-            if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.with_queues(|q| q.waiters.push_back(tx));
-
-                if rx.await.is_err() {
-                    return Err(SendPacketError::Disconnected);
-                }
-            }
+            // wait for slot in in-flight window
+            let permit = shared.permits.acquire().await.ok_or(SendPacketError::Disconnected)?;
             // allocate packet id
             let idx = if self.id == 0 { shared.next_id() } else { self.id };
             let rx = shared.with_queues(|queues| {
@@ -482,22 +1089,25 @@ impl UnsubscribeBuilder {
                 if queues.inflight.contains_key(&idx) {
                     return Err(SendPacketError::PacketIdInUse(idx));
                 }
-                queues.inflight.insert(idx, (tx, AckType::Unsubscribe));
+                queues
+                    .inflight
+                    .insert(idx, (Some(tx), AckType::Unsubscribe, clock::now(), None));
                 queues.inflight_order.push_back(idx);
                 Ok(rx)
             })?;
+            permit.detach();
             packet.packet_id = NonZeroU16::new(idx).unwrap();
 
             // send unsubscribe to client
             log::trace!("Sending unsubscribe packet {:#?}", packet);
 
-            match shared.state.write().encode(codec::Packet::Unsubscribe(packet), &shared.codec)
-            {
+            match shared.state.write().encode(codec::Packet::Unsubscribe(packet), &*shared) {
                 Ok(_) => {
                     // wait ack from peer
                     rx.await
-                        .map_err(|_| SendPacketError::Disconnected)
-                        .map(|pkt| pkt.unsubscribe())
+                        .map_err(|_| SendPacketError::Disconnected)?
+                        .ok_or(SendPacketError::Expired)
+                        .map(|pkt| UnsubscribeResult::new(names, pkt.unsubscribe()))
                 }
                 Err(err) => Err(SendPacketError::Encode(err)),
             }
@@ -506,3 +1116,105 @@ impl UnsubscribeBuilder {
         }
     }
 }
+
+/// Result of unsubscribe request
+///
+/// Contains reason code for each topic filter, in order of unsubscribe request,
+/// and properties of UNSUBACK packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsubscribeResult {
+    filters: Vec<(ByteString, codec::UnsubscribeAckReason)>,
+    properties: codec::UserProperties,
+    reason_string: Option<ByteString>,
+}
+
+impl UnsubscribeResult {
+    fn new(names: Vec<ByteString>, ack: codec::UnsubscribeAck) -> Self {
+        let mut status = ack.status;
+        if status.len() != names.len() {
+            log::warn!(
+                "Number of reason codes {} does not match number of topic filters {}",
+                status.len(),
+                names.len()
+            );
+            status.resize(names.len(), codec::UnsubscribeAckReason::UnspecifiedError);
+        }
+        UnsubscribeResult {
+            filters: names.into_iter().zip(status).collect(),
+            properties: ack.properties,
+            reason_string: ack.reason_string,
+        }
+    }
+
+    /// Number of topic filters
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    /// Check if unsubscribe request did not contain any topic filters
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Check if server did not fail any topic filter
+    ///
+    /// `NoSubscriptionExisted` is not considered as failure.
+    pub fn is_success(&self) -> bool {
+        self.filters.iter().all(|(_, reason)| !reason.is_error())
+    }
+
+    /// Reason code for topic filter, `None` if topic filter is not part of request
+    pub fn get(&self, filter: &str) -> Option<codec::UnsubscribeAckReason> {
+        self.filters.iter().find(|(f, _)| f == filter).map(|(_, reason)| *reason)
+    }
+
+    /// Iterate over topic filters with reason codes
+    pub fn iter(&self) -> impl Iterator<Item = (&ByteString, codec::UnsubscribeAckReason)> {
+        self.filters.iter().map(|(f, reason)| (f, *reason))
+    }
+
+    /// Reason codes, in order of topic filters
+    pub fn reason_codes(&self) -> Vec<codec::ReasonCode> {
+        self.filters.iter().map(|(_, reason)| (*reason).into()).collect()
+    }
+
+    /// UNSUBACK user properties
+    pub fn properties(&self) -> &codec::UserProperties {
+        &self.properties
+    }
+
+    /// UNSUBACK reason string
+    pub fn reason_string(&self) -> Option<&ByteString> {
+        self.reason_string.as_ref()
+    }
+
+    /// Consume result and return topic filters with reason codes
+    pub fn into_inner(self) -> Vec<(ByteString, codec::UnsubscribeAckReason)> {
+        self.filters
+    }
+}
+
+/// Apply slow consumer policy, returns `false` if packet must be dropped.
+///
+/// Connection is closed by `Disconnect` policy.
+fn apply_backlog_policy(shared: &Rc<MqttShared>, qos0: bool) -> bool {
+    if !shared.state.is_open() {
+        return true;
+    }
+    match shared.check_backlog() {
+        None => true,
+        Some(SlowConsumerPolicy::Warn) => {
+            log::warn!("Outbound queue is over watermark, peer is slow");
+            true
+        }
+        Some(SlowConsumerPolicy::DropQos0) => !qos0,
+        Some(SlowConsumerPolicy::Disconnect) => {
+            log::warn!("Outbound queue is over watermark, closing connection");
+            MqttSink(shared.clone()).close_with_reason(codec::Disconnect {
+                reason_code: codec::DisconnectReasonCode::QuotaExceeded,
+                ..Default::default()
+            });
+            true
+        }
+    }
+}
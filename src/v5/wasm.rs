@@ -0,0 +1,621 @@
+//! Mqtt v5 client for browsers
+//!
+//! Client uses browser `WebSocket` api as transport and drives
+//! `proto::Connection` state machine from websocket callbacks, keep-alive
+//! is driven by `setTimeout` timer. Build for `wasm32-unknown-unknown`
+//! target with `default-features = false, features = ["wasm"]`.
+//!
+//! ```rust,ignore
+//! use ntex_bytes::Bytes;
+//! use ntex_mqtt::v5::{codec::QoS, wasm::MqttConnector};
+//!
+//! let (sink, mut publishes) = MqttConnector::new("wss://broker.example.com/mqtt")
+//!     .client_id("browser-client")
+//!     .connect()
+//!     .await?;
+//!
+//! sink.subscribe(None).topic_filter("topic/#", QoS::AtLeastOnce).send().await?;
+//! sink.publish("topic/1", Bytes::from_static(b"data")).send_at_least_once().await?;
+//!
+//! while let Some(publish) = publishes.recv().await {
+//!     log::info!("{:?}", publish);
+//! }
+//! ```
+use std::rc::{Rc, Weak};
+use std::task::{Context, Poll};
+use std::{cell::RefCell, collections::HashMap, fmt, future::poll_fn, pin::Pin};
+use std::{num::NonZeroU16, num::NonZeroU32, time::Duration};
+
+use derive_more::{Display, From};
+use futures_channel::{mpsc, oneshot};
+use futures_core::Stream;
+use js_sys::{ArrayBuffer, Uint8Array};
+use ntex_bytes::{ByteString, Bytes};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, MessageEvent, WebSocket};
+use web_time::Instant;
+
+use super::codec::{self, QoS};
+use super::proto::{Connection, Event};
+use crate::error::{ProtocolError, SendPacketError};
+use crate::utils::duration_to_secs;
+
+/// Errors which can occur when attempting to establish client connection
+#[derive(Debug, Display, From)]
+pub enum ClientError {
+    /// Connect negotiation failed
+    #[display(fmt = "Connect ack failed: {:?}", _0)]
+    Ack(Box<codec::ConnectAck>),
+    /// Protocol error
+    #[display(fmt = "Protocol error: {}", _0)]
+    Protocol(ProtocolError),
+    /// Peer disconnected
+    #[display(fmt = "Peer disconnected")]
+    Disconnected,
+    /// WebSocket error
+    #[display(fmt = "WebSocket error: {}", _0)]
+    #[from(ignore)]
+    WebSocket(String),
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Protocol(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Mqtt client connector for browsers
+pub struct MqttConnector {
+    url: String,
+    pkt: codec::Connect,
+    max_packet_size: u32,
+}
+
+impl MqttConnector {
+    /// Create new mqtt connector for websocket url
+    pub fn new<U: Into<String>>(url: U) -> Self {
+        MqttConnector {
+            url: url.into(),
+            pkt: codec::Connect { keep_alive: 30, ..Default::default() },
+            max_packet_size: 0,
+        }
+    }
+
+    /// Set client identifier
+    pub fn client_id<U>(mut self, client_id: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.pkt.client_id = client_id.into();
+        self
+    }
+
+    /// The handling of the Session state.
+    pub fn clean_start(mut self) -> Self {
+        self.pkt.clean_start = true;
+        self
+    }
+
+    /// Set keep-alive interval.
+    ///
+    /// Interval is rounded up to whole seconds and is limited to `u16::MAX` seconds.
+    /// keep-alive is set to 30 seconds by default.
+    pub fn keep_alive(mut self, val: Duration) -> Self {
+        self.pkt.keep_alive = duration_to_secs(val);
+        self
+    }
+
+    /// Will Message be stored on the Server and associated with the Network Connection.
+    pub fn last_will(mut self, val: codec::LastWill) -> Self {
+        self.pkt.last_will = Some(val);
+        self
+    }
+
+    /// Username can be used by the Server for authentication and authorization.
+    pub fn username(mut self, val: ByteString) -> Self {
+        self.pkt.username = Some(val);
+        self
+    }
+
+    /// Password can be used by the Server for authentication and authorization.
+    pub fn password(mut self, val: Bytes) -> Self {
+        self.pkt.password = Some(val);
+        self
+    }
+
+    /// Max incoming packet size.
+    ///
+    /// To disable max size limit set value to 0.
+    pub fn max_packet_size(mut self, val: u32) -> Self {
+        self.max_packet_size = val;
+        self.pkt.max_packet_size = NonZeroU32::new(val);
+        self
+    }
+
+    /// Update connect packet
+    pub fn packet<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut codec::Connect),
+    {
+        f(&mut self.pkt);
+        self
+    }
+
+    /// Open websocket connection and perform mqtt handshake
+    pub async fn connect(self) -> Result<(MqttSink, Publishes), ClientError> {
+        let ws = WebSocket::new_with_str(&self.url, "mqtt").map_err(js_error)?;
+        ws.set_binary_type(BinaryType::Arraybuffer);
+
+        let (connected_tx, connected_rx) = oneshot::channel();
+        let (pub_tx, pub_rx) = mpsc::unbounded();
+        let inner = Rc::new(RefCell::new(Inner {
+            conn: Connection::new(self.pkt).max_packet_size(self.max_packet_size),
+            ws,
+            connected: Some(connected_tx),
+            waiters: HashMap::new(),
+            ready: Vec::new(),
+            pub_tx,
+            timer: None,
+            callbacks: None,
+            weak: Weak::new(),
+        }));
+        Inner::register(&inner);
+
+        connected_rx.await.map_err(|_| ClientError::Disconnected)??;
+        Ok((MqttSink(inner), Publishes(pub_rx)))
+    }
+}
+
+/// Stream of publishes received from server
+#[derive(Debug)]
+pub struct Publishes(mpsc::UnboundedReceiver<codec::Publish>);
+
+impl Publishes {
+    /// Receive next publish, returns `None` when connection is closed
+    pub async fn recv(&mut self) -> Option<codec::Publish> {
+        poll_fn(|cx| Pin::new(&mut self.0).poll_next(cx)).await
+    }
+}
+
+impl Stream for Publishes {
+    type Item = codec::Publish;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
+
+#[derive(Clone)]
+/// Mqtt client sink for browsers
+pub struct MqttSink(Rc<RefCell<Inner>>);
+
+impl fmt::Debug for MqttSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttSink").field("open", &self.is_open()).finish()
+    }
+}
+
+impl MqttSink {
+    /// Check if connection is open
+    pub fn is_open(&self) -> bool {
+        !self.0.borrow().conn.is_closed()
+    }
+
+    /// Get notification when QoS 1 publish could be sent
+    ///
+    /// Number of in-flight publishes is limited by server's receive maximum.
+    pub async fn ready(&self) -> bool {
+        let rx = {
+            let mut inner = self.0.borrow_mut();
+            if inner.conn.is_ready() || inner.conn.is_closed() {
+                return !inner.conn.is_closed();
+            }
+            let (tx, rx) = oneshot::channel();
+            inner.ready.push(tx);
+            rx
+        };
+        rx.await.is_ok()
+    }
+
+    /// Close connection with default `DISCONNECT` packet
+    pub fn close(&self) {
+        self.close_with_reason(codec::Disconnect::default())
+    }
+
+    /// Close connection with custom `DISCONNECT` packet
+    pub fn close_with_reason(&self, pkt: codec::Disconnect) {
+        let mut inner = self.0.borrow_mut();
+        inner.conn.disconnect(pkt);
+        inner.process();
+    }
+
+    /// Create publish packet builder
+    pub fn publish<U>(&self, topic: U, payload: Bytes) -> PublishBuilder
+    where
+        ByteString: From<U>,
+    {
+        PublishBuilder {
+            inner: self.0.clone(),
+            packet: codec::Publish {
+                payload,
+                dup: false,
+                retain: false,
+                topic: topic.into(),
+                qos: QoS::AtMostOnce,
+                packet_id: None,
+                properties: codec::PublishProperties::default(),
+            },
+        }
+    }
+
+    /// Create subscribe packet builder
+    pub fn subscribe(&self, id: Option<NonZeroU32>) -> SubscribeBuilder {
+        SubscribeBuilder {
+            inner: self.0.clone(),
+            packet: codec::Subscribe {
+                id,
+                packet_id: NonZeroU16::new(1).unwrap(),
+                user_properties: Vec::new(),
+                topic_filters: Vec::new(),
+            },
+        }
+    }
+
+    /// Create unsubscribe packet builder
+    pub fn unsubscribe(&self) -> UnsubscribeBuilder {
+        UnsubscribeBuilder {
+            inner: self.0.clone(),
+            packet: codec::Unsubscribe {
+                packet_id: NonZeroU16::new(1).unwrap(),
+                user_properties: Vec::new(),
+                topic_filters: Vec::new(),
+            },
+        }
+    }
+}
+
+/// Publish packet builder
+pub struct PublishBuilder {
+    inner: Rc<RefCell<Inner>>,
+    packet: codec::Publish,
+}
+
+impl PublishBuilder {
+    /// Set retain flag
+    pub fn retain(mut self) -> Self {
+        self.packet.retain = true;
+        self
+    }
+
+    /// Set publish packet properties
+    pub fn properties<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut codec::PublishProperties),
+    {
+        f(&mut self.packet.properties);
+        self
+    }
+
+    /// Send publish packet with QoS 0
+    pub fn send_at_most_once(self) -> Result<(), SendPacketError> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.conn.is_closed() {
+            return Err(SendPacketError::Disconnected);
+        }
+        inner.conn.publish(self.packet)?;
+        inner.process();
+        Ok(())
+    }
+
+    /// Send publish packet with QoS 1
+    pub async fn send_at_least_once(mut self) -> Result<codec::PublishAck, SendPacketError> {
+        // wait for slot in in-flight window
+        if !MqttSink(self.inner.clone()).ready().await {
+            return Err(SendPacketError::Disconnected);
+        }
+        self.packet.qos = QoS::AtLeastOnce;
+        let packet = self.packet;
+
+        match Inner::request(&self.inner, |conn| conn.publish(packet).map(Option::unwrap))
+            .await?
+        {
+            Ack::Publish(ack) => Ok(ack),
+            _ => Err(SendPacketError::Disconnected),
+        }
+    }
+}
+
+/// Subscribe packet builder
+pub struct SubscribeBuilder {
+    inner: Rc<RefCell<Inner>>,
+    packet: codec::Subscribe,
+}
+
+impl SubscribeBuilder {
+    /// Add topic filter
+    pub fn topic_filter<U, O>(mut self, filter: U, opts: O) -> Self
+    where
+        ByteString: From<U>,
+        O: Into<codec::SubscriptionOptions>,
+    {
+        self.packet.topic_filters.push((filter.into(), opts.into()));
+        self
+    }
+
+    /// Add user property
+    pub fn property(mut self, key: ByteString, value: ByteString) -> Self {
+        self.packet.user_properties.push((key, value));
+        self
+    }
+
+    /// Send subscribe packet
+    pub async fn send(self) -> Result<codec::SubscribeAck, SendPacketError> {
+        let packet = self.packet;
+        match Inner::request(&self.inner, |conn| conn.subscribe(packet)).await? {
+            Ack::Subscribe(ack) => Ok(ack),
+            _ => Err(SendPacketError::Disconnected),
+        }
+    }
+}
+
+/// Unsubscribe packet builder
+pub struct UnsubscribeBuilder {
+    inner: Rc<RefCell<Inner>>,
+    packet: codec::Unsubscribe,
+}
+
+impl UnsubscribeBuilder {
+    /// Add topic filter
+    pub fn topic_filter<U>(mut self, filter: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.packet.topic_filters.push(filter.into());
+        self
+    }
+
+    /// Add user property
+    pub fn property(mut self, key: ByteString, value: ByteString) -> Self {
+        self.packet.user_properties.push((key, value));
+        self
+    }
+
+    /// Send unsubscribe packet
+    pub async fn send(self) -> Result<codec::UnsubscribeAck, SendPacketError> {
+        let packet = self.packet;
+        match Inner::request(&self.inner, |conn| conn.unsubscribe(packet)).await? {
+            Ack::Unsubscribe(ack) => Ok(ack),
+            _ => Err(SendPacketError::Disconnected),
+        }
+    }
+}
+
+enum Ack {
+    Publish(codec::PublishAck),
+    Subscribe(codec::SubscribeAck),
+    Unsubscribe(codec::UnsubscribeAck),
+}
+
+type Callback<T> = Closure<dyn FnMut(T)>;
+
+struct Callbacks {
+    _open: Callback<JsValue>,
+    _message: Callback<MessageEvent>,
+    _close: Callback<CloseEvent>,
+    _error: Callback<JsValue>,
+    keep_alive: Closure<dyn FnMut()>,
+}
+
+struct Inner {
+    conn: Connection,
+    ws: WebSocket,
+    connected: Option<oneshot::Sender<Result<(), ClientError>>>,
+    waiters: HashMap<NonZeroU16, oneshot::Sender<Ack>>,
+    ready: Vec<oneshot::Sender<()>>,
+    pub_tx: mpsc::UnboundedSender<codec::Publish>,
+    /// keep-alive timer handle and deadline
+    timer: Option<(i32, Instant)>,
+    callbacks: Option<Callbacks>,
+    weak: Weak<RefCell<Inner>>,
+}
+
+impl Inner {
+    fn register(this: &Rc<RefCell<Inner>>) {
+        let inner = this.clone();
+        let open = Closure::wrap(Box::new(move |_: JsValue| {
+            inner.borrow_mut().process();
+        }) as Box<dyn FnMut(JsValue)>);
+
+        let inner = this.clone();
+        let message = Closure::wrap(Box::new(move |ev: MessageEvent| {
+            if let Ok(buf) = ev.data().dyn_into::<ArrayBuffer>() {
+                let mut inner = inner.borrow_mut();
+                inner.conn.receive(Instant::now(), &Uint8Array::new(&buf).to_vec());
+                inner.process();
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
+
+        let inner = this.clone();
+        let close = Closure::wrap(Box::new(move |_: CloseEvent| {
+            let mut inner = inner.borrow_mut();
+            inner.conn.receive_eof();
+            inner.process();
+        }) as Box<dyn FnMut(CloseEvent)>);
+
+        let inner = this.clone();
+        let error = Closure::wrap(Box::new(move |err: JsValue| {
+            let mut inner = inner.borrow_mut();
+            if let Some(tx) = inner.connected.take() {
+                let _ = tx.send(Err(js_error(err)));
+            }
+            inner.conn.receive_eof();
+            inner.process();
+        }) as Box<dyn FnMut(JsValue)>);
+
+        let weak = Rc::downgrade(this);
+        let keep_alive = Closure::wrap(Box::new(move || {
+            if let Some(inner) = weak.upgrade() {
+                inner.borrow_mut().keep_alive();
+            }
+        }) as Box<dyn FnMut()>);
+
+        let mut inner = this.borrow_mut();
+        inner.weak = Rc::downgrade(this);
+        inner.ws.set_onopen(Some(open.as_ref().unchecked_ref()));
+        inner.ws.set_onmessage(Some(message.as_ref().unchecked_ref()));
+        inner.ws.set_onclose(Some(close.as_ref().unchecked_ref()));
+        inner.ws.set_onerror(Some(error.as_ref().unchecked_ref()));
+        inner.callbacks = Some(Callbacks {
+            _open: open,
+            _message: message,
+            _close: close,
+            _error: error,
+            keep_alive,
+        });
+    }
+
+    async fn request<F>(this: &Rc<RefCell<Inner>>, f: F) -> Result<Ack, SendPacketError>
+    where
+        F: FnOnce(&mut Connection) -> Result<NonZeroU16, SendPacketError>,
+    {
+        let rx = {
+            let mut inner = this.borrow_mut();
+            if inner.conn.is_closed() {
+                return Err(SendPacketError::Disconnected);
+            }
+            let id = f(&mut inner.conn)?;
+            let (tx, rx) = oneshot::channel();
+            inner.waiters.insert(id, tx);
+            inner.process();
+            rx
+        };
+        rx.await.map_err(|_| SendPacketError::Disconnected)
+    }
+
+    /// Handle protocol events and write pending data to websocket
+    fn process(&mut self) {
+        loop {
+            match self.conn.poll_event() {
+                Ok(Some(ev)) => self.event(ev),
+                Ok(None) => break,
+                Err(e) => {
+                    log::trace!("Mqtt protocol error: {:?}", e);
+                    if let Some(tx) = self.connected.take() {
+                        let _ = tx.send(Err(e.into()));
+                    }
+                }
+            }
+        }
+
+        if self.conn.has_transmit() && self.ws.ready_state() == WebSocket::OPEN {
+            let data = self.conn.transmit(Instant::now());
+            if let Err(e) = self.ws.send_with_u8_array(&data) {
+                log::trace!("WebSocket send failed: {:?}", e);
+            }
+        }
+        if self.conn.is_closed() {
+            self.shutdown();
+        } else {
+            self.schedule();
+        }
+    }
+
+    fn event(&mut self, ev: Event) {
+        match ev {
+            Event::Connected(_) => {
+                if let Some(tx) = self.connected.take() {
+                    let _ = tx.send(Ok(()));
+                }
+            }
+            Event::Refused(ack) => {
+                if let Some(tx) = self.connected.take() {
+                    let _ = tx.send(Err(ClientError::Ack(ack)));
+                }
+            }
+            Event::Publish(publish) => {
+                let _ = self.pub_tx.unbounded_send(publish);
+            }
+            Event::PublishAck(ack) => {
+                self.complete(ack.packet_id, Ack::Publish(ack));
+                for tx in self.ready.drain(..) {
+                    let _ = tx.send(());
+                }
+            }
+            Event::PublishComplete(_) => (),
+            Event::SubscribeAck(ack) => self.complete(ack.packet_id, Ack::Subscribe(ack)),
+            Event::UnsubscribeAck(ack) => self.complete(ack.packet_id, Ack::Unsubscribe(ack)),
+            Event::Disconnected(pkt) => {
+                log::trace!("Mqtt connection is closed: {:?}", pkt);
+                if let Some(tx) = self.connected.take() {
+                    let _ = tx.send(Err(ClientError::Disconnected));
+                }
+            }
+        }
+    }
+
+    fn complete(&mut self, id: NonZeroU16, ack: Ack) {
+        if let Some(tx) = self.waiters.remove(&id) {
+            let _ = tx.send(ack);
+        }
+    }
+
+    /// Re-arm keep-alive timer if connection's timeout is changed
+    fn schedule(&mut self) {
+        let deadline = self.conn.poll_timeout();
+        if self.timer.map(|(_, t)| t) == deadline {
+            return;
+        }
+        let window = match web_sys::window() {
+            Some(window) => window,
+            None => return,
+        };
+        if let Some((id, _)) = self.timer.take() {
+            window.clear_timeout_with_handle(id);
+        }
+        if let (Some(deadline), Some(callbacks)) = (deadline, self.callbacks.as_ref()) {
+            let timeout = deadline.saturating_duration_since(Instant::now()).as_millis();
+            match window.set_timeout_with_callback_and_timeout_and_arguments_0(
+                callbacks.keep_alive.as_ref().unchecked_ref(),
+                std::cmp::min(timeout, i32::MAX as u128) as i32,
+            ) {
+                Ok(id) => self.timer = Some((id, deadline)),
+                Err(e) => log::error!("Cannot start keep-alive timer: {:?}", e),
+            }
+        }
+    }
+
+    fn keep_alive(&mut self) {
+        self.timer = None;
+        if let Err(e) = self.conn.handle_timeout(Instant::now()) {
+            log::trace!("Mqtt keep-alive failed: {:?}", e);
+        }
+        self.process();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some((id, _)) = self.timer.take() {
+            if let Some(window) = web_sys::window() {
+                window.clear_timeout_with_handle(id);
+            }
+        }
+        if self.ws.ready_state() == WebSocket::OPEN {
+            let _ = self.ws.close();
+        }
+        self.waiters.clear();
+        self.ready.clear();
+        self.pub_tx.close_channel();
+        self.ws.set_onopen(None);
+        self.ws.set_onmessage(None);
+        self.ws.set_onclose(None);
+        self.ws.set_onerror(None);
+        // drops references to shared state, breaks reference cycle
+        self.callbacks.take();
+    }
+}
+
+fn js_error(err: JsValue) -> ClientError {
+    ClientError::WebSocket(err.as_string().unwrap_or_else(|| format!("{:?}", err)))
+}
@@ -1,14 +1,23 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
-use std::{num::NonZeroU16, time::Duration};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::{num::NonZeroU16, time::Duration, time::Instant};
 
-use futures::{future::ok, FutureExt, SinkExt, StreamExt};
-use ntex::codec::Framed;
+use futures::{future::ok, future::ready, FutureExt, SinkExt, StreamExt};
+use ntex::codec::{Decoder, Framed};
 use ntex::rt::time::sleep;
 use ntex::server;
 use ntex::util::{poll_fn, ByteString, Bytes};
+use ntex::{fn_factory_with_config, fn_service};
 
 use ntex_mqtt::v3::{
-    client, codec, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish, Session,
+    client, codec, error::SendPacketError, ControlMessage, Fanout, Handshake, HandshakeAck,
+    MqttServer, Publish, PublishFailure, Router, Session, SinkRegistry,
+};
+use ntex_mqtt::{
+    capture::Recorder, connect, testing, ConfigHandle, ListenerConfig, MqttError, SessionEnd,
+};
+use ntex_mqtt::{
+    AlpnRouter, InflightType, RetainPolicy, ShutdownStatus, SniRouter, TopicRewrite,
 };
 
 struct St;
@@ -18,7 +27,7 @@ async fn handshake<Io>(mut packet: Handshake<Io>) -> Result<HandshakeAck<Io, St>
     packet.packet_mut();
     packet.io();
     packet.sink();
-    Ok(packet.ack(St, false).idle_timeout(16))
+    Ok(packet.ack(St, false).idle_timeout(Duration::from_secs(16)))
 }
 
 #[ntex::test]
@@ -217,6 +226,65 @@ async fn test_ack_order() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_ordered_lanes() -> std::io::Result<()> {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let log2 = log.clone();
+
+    let srv = server::test_server(move || {
+        let log = log2.clone();
+        MqttServer::new(handshake)
+            .ordered_lanes(16)
+            .publish(move |p: Publish| {
+                let log = log.clone();
+                let delay = if p.payload().as_ref() == b"1" { 100 } else { 0 };
+                async move {
+                    sleep(Duration::from_millis(delay)).await;
+                    log.lock().unwrap().push(p.payload().clone());
+                    Ok::<_, ()>(())
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    for (id, topic, payload) in [(1, "a", "1"), (2, "a", "2"), (3, "b", "3")] {
+        framed
+            .send(
+                codec::Publish {
+                    dup: false,
+                    retain: false,
+                    qos: codec::QoS::AtLeastOnce,
+                    topic: ByteString::from(topic),
+                    packet_id: NonZeroU16::new(id),
+                    payload: Bytes::from_static(payload.as_bytes()),
+                }
+                .into(),
+            )
+            .await
+            .unwrap();
+    }
+    for id in 1..4 {
+        let pkt = framed.next().await.unwrap().unwrap();
+        assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(id).unwrap() });
+    }
+
+    // publishes of the same topic are handled in order, other topics are not blocked
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec![Bytes::from_static(b"3"), Bytes::from_static(b"1"), Bytes::from_static(b"2")]
+    );
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_ack_order_sink() -> std::io::Result<()> {
     let srv = server::test_server(move || {
@@ -276,6 +344,145 @@ async fn test_disconnect() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_disconnect_packet() -> std::io::Result<()> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+
+    let srv = server::test_server(move || {
+        let events = events2.clone();
+        MqttServer::new(handshake)
+            .publish(|_| ok(()))
+            .control(move |msg| {
+                let mut events = events.lock().unwrap();
+                match msg {
+                    ControlMessage::Disconnect(msg) => {
+                        events.push("disconnect");
+                        ok(msg.ack())
+                    }
+                    ControlMessage::Closed(msg) => {
+                        assert_eq!(msg.shutdown_status(), Some(ShutdownStatus::Clean));
+                        events.push(if msg.is_clean() { "closed clean" } else { "closed" });
+                        ok(msg.ack())
+                    }
+                    _ => ok(msg.disconnect()),
+                }
+            })
+            .finish()
+    });
+
+    // clean disconnect
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed.send(codec::Packet::Disconnect).await.unwrap();
+    assert!(framed.next().await.is_none());
+    drop(framed);
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(*events.lock().unwrap(), vec!["disconnect", "closed clean"]);
+
+    // network drop
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    drop(framed);
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(*events.lock().unwrap(), vec!["disconnect", "closed clean", "closed"]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_end() -> std::io::Result<()> {
+    let ends = Arc::new(Mutex::new(Vec::new()));
+    let ends2 = ends.clone();
+
+    let srv = server::test_server(move || {
+        let ends = ends2.clone();
+        MqttServer::new(handshake)
+            .publish(fn_factory_with_config(|session: Session<St>| {
+                ok::<_, ()>(fn_service(move |p: Publish| {
+                    match p.publish_topic() {
+                        "takeover" => session.sink().takeover(),
+                        _ => session.sink().set_keep_alive(1),
+                    }
+                    ok::<_, ()>(())
+                }))
+            }))
+            .control(move |msg| match msg {
+                ControlMessage::Closed(msg) => {
+                    ends.lock().unwrap().push(msg.session_end());
+                    ok(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    async fn connect(
+        srv: &server::TestServer,
+    ) -> Framed<ntex::rt::net::TcpStream, codec::Codec> {
+        let io = srv.connect().await.unwrap();
+        let mut framed = Framed::new(io, codec::Codec::default());
+        framed
+            .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+            .await
+            .unwrap();
+        let _ = framed.next().await.unwrap().unwrap();
+        framed
+    }
+
+    fn publish(topic: &'static str) -> codec::Packet {
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static(topic),
+            packet_id: None,
+            payload: Bytes::new(),
+        })
+    }
+
+    let mut framed = connect(&srv).await;
+    framed.send(codec::Packet::Disconnect).await.unwrap();
+    assert!(framed.next().await.is_none());
+    drop(framed);
+
+    let framed = connect(&srv).await;
+    drop(framed);
+    sleep(Duration::from_millis(50)).await;
+
+    let mut framed = connect(&srv).await;
+    framed.send(publish("takeover")).await.unwrap();
+    assert!(framed.next().await.is_none());
+    drop(framed);
+
+    let mut framed = connect(&srv).await;
+    framed.send(publish("keepalive")).await.unwrap();
+    assert!(framed.next().await.is_none());
+    drop(framed);
+    sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(
+        *ends.lock().unwrap(),
+        vec![
+            SessionEnd::Clean,
+            SessionEnd::Abnormal,
+            SessionEnd::Takeover,
+            SessionEnd::KeepAliveTimeout
+        ]
+    );
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_handle_incoming() -> std::io::Result<()> {
     let publish = Arc::new(AtomicBool::new(false));
@@ -330,3 +537,2755 @@ async fn test_handle_incoming() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_recorder() -> std::io::Result<()> {
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buf = Buf::default();
+    let recorder = Recorder::new(buf.clone());
+    let srv = server::test_server(move || {
+        let recorder = recorder.clone();
+        MqttServer::new(move |conn: Handshake<_>| {
+            conn.sink().set_recorder(Some(recorder.clone()));
+            ok::<_, ()>(conn.ack(St, false))
+        })
+        .publish(|_t| ok(()))
+        .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.publish(ByteString::from_static("test"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+
+    let data = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+    let records: Vec<(String, String)> = data
+        .lines()
+        .map(|line| {
+            let rec: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(rec["v"], 3);
+            (
+                rec["dir"].as_str().unwrap().to_string(),
+                rec["data"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        records,
+        vec![
+            ("out".to_string(), "20020000".to_string()),
+            ("in".to_string(), "32080004746573740001".to_string()),
+            ("out".to_string(), "40020001".to_string()),
+        ]
+    );
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_replay() {
+    use ntex::codec::Encoder;
+    use ntex::util::BytesMut;
+    use ntex::{Service, ServiceFactory};
+
+    fn record(ts: u64, dir: &str, pkt: codec::Packet) -> String {
+        let mut buf = BytesMut::new();
+        codec::Codec::default().encode(pkt, &mut buf).unwrap();
+        let data: String = buf.iter().map(|b| format!("{:02x}", b)).collect();
+        format!(r#"{{"ts":{},"v":3,"dir":"{}","data":"{}"}}"#, ts, dir, data)
+    }
+    let connect = codec::Connect::default().client_id("user");
+    let script = [
+        record(1_000_000, "in", codec::Packet::Connect(connect)),
+        record(1_000_100, "out", codec::Packet::PingResponse),
+        record(
+            1_000_200,
+            "in",
+            codec::Packet::Publish(codec::Publish {
+                dup: false,
+                retain: false,
+                qos: codec::QoS::AtLeastOnce,
+                topic: ByteString::from_static("test"),
+                packet_id: Some(NonZeroU16::new(1).unwrap()),
+                payload: Bytes::new(),
+            }),
+        ),
+        String::new(),
+        record(1_300_000, "in", codec::Packet::PingRequest),
+    ]
+    .join("\n");
+    let records = ntex_mqtt::capture::Record::read(script.as_bytes()).unwrap();
+    assert_eq!(records.len(), 4);
+
+    let publishes = Arc::new(Mutex::new(Vec::new()));
+    let publishes2 = publishes.clone();
+    let (client, server) = testing::duplex();
+    let srv = MqttServer::new(handshake)
+        .publish(move |p: Publish| {
+            publishes2.lock().unwrap().push(p.topic().path().to_string());
+            ok(())
+        })
+        .finish()
+        .new_service(())
+        .await
+        .ok()
+        .unwrap();
+    ntex::rt::spawn(async move { srv.call(server).await });
+
+    let start = Instant::now();
+    testing::MockBroker::v3(client)
+        .replay_timed(records)
+        .expect(|pkt| std::matches!(pkt, codec::Packet::ConnectAck { .. }))
+        .expect_packet(codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() })
+        .expect_packet(codec::Packet::PingResponse)
+        .run()
+        .await;
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    assert_eq!(*publishes.lock().unwrap(), vec!["test".to_string()]);
+}
+
+#[ntex::test]
+async fn test_max_client_id_length() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake).max_client_id_length(4).publish(|_t| ok(())).finish()
+    });
+
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("long-user")
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    if let client::ClientError::Ack { session_present, return_code } = err {
+        assert!(!session_present);
+        assert_eq!(return_code, codec::ConnectAckReason::IdentifierRejected);
+    } else {
+        panic!("Expected ack error, got: {:?}", err);
+    }
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    client.sink().close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_connect_filter() -> std::io::Result<()> {
+    let called = Arc::new(AtomicBool::new(false));
+    let called2 = called.clone();
+
+    let srv = server::test_server(move || {
+        let called = called2.clone();
+        MqttServer::new(move |conn: Handshake<_>| {
+            called.store(true, Relaxed);
+            ok::<_, ()>(conn.ack(St, false))
+        })
+        .connect_filter(|pkt, io: &ntex::rt::net::TcpStream| {
+            assert!(io.peer_addr().is_ok());
+            if pkt.client_id == "banned" {
+                Err(codec::ConnectAckReason::NotAuthorized)
+            } else {
+                Ok(())
+            }
+        })
+        .publish(|_t| ok(()))
+        .finish()
+    });
+
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("banned")
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    if let client::ClientError::Ack { session_present, return_code } = err {
+        assert!(!session_present);
+        assert_eq!(return_code, codec::ConnectAckReason::NotAuthorized);
+    } else {
+        panic!("Expected ack error, got: {:?}", err);
+    }
+    assert!(!called.load(Relaxed));
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert!(called.load(Relaxed));
+    client.sink().close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_keepalive_update() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(fn_factory_with_config(|session: Session<St>| {
+                ok::<_, ()>(fn_service(move |_: Publish| {
+                    assert_eq!(session.sink().keep_alive(), 16);
+                    session.sink().set_keep_alive(1);
+                    ok::<_, ()>(())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed
+        .send(codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: None,
+            payload: Bytes::new(),
+        }))
+        .await
+        .unwrap();
+
+    // server drops idle connection with new keep-alive timeout
+    let start = Instant::now();
+    assert!(framed.next().await.is_none());
+    assert!(start.elapsed() < Duration::from_secs(5));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_dedup_window() -> std::io::Result<()> {
+    let dups = Arc::new(Mutex::new(Vec::new()));
+    let dups2 = dups.clone();
+
+    let srv = server::test_server(move || {
+        let dups = dups2.clone();
+        MqttServer::new(handshake)
+            .dedup_window(16)
+            .publish(move |p: Publish| {
+                dups.lock().unwrap().push(p.is_duplicate());
+                ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let publish = |dup| {
+        codec::Packet::Publish(codec::Publish {
+            dup,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: Some(NonZeroU16::new(1).unwrap()),
+            payload: Bytes::new(),
+        })
+    };
+    let ack = codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() };
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed.send(publish(false)).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack);
+    drop(framed);
+
+    // redelivery after reconnect
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed.send(publish(true)).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack);
+
+    // new publish with same packet id
+    framed.send(publish(false)).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack);
+
+    assert_eq!(*dups.lock().unwrap(), vec![false, true, false]);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_connect_io() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v3(server)
+            .expect(|pkt| match pkt {
+                codec::Packet::Connect(pkt) => pkt.client_id == "user",
+                _ => false,
+            })
+            .send(codec::Packet::ConnectAck {
+                session_present: true,
+                return_code: codec::ConnectAckReason::ConnectionAccepted,
+            })
+            .run(),
+    );
+
+    let client =
+        client::MqttConnector::new("localhost").client_id("user").connect_io(io).await.unwrap();
+    assert!(client.session_present());
+
+    let mut broker = broker.await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish(ByteString::from_static("topic"), Bytes::from_static(b"data"))
+        .send_at_most_once()
+        .unwrap();
+    let pkt = broker.recv().await.unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(ref p) if p.topic == "topic"));
+
+    sink.close();
+    broker.expect_closed().await;
+}
+
+#[ntex::test]
+async fn test_connect_resolver() {
+    let srv = server::test_server(|| MqttServer::new(handshake).publish(|_t| ok(())).finish());
+    let addr = format!("mqtt.test:{}", srv.addr().port());
+    let resolver = || {
+        connect::StaticResolver::new()
+            .host("mqtt.test", vec![srv.addr().ip()])
+            .host("none.test", Vec::new())
+    };
+
+    let client = client::MqttConnector::new(addr)
+        .client_id("user")
+        .resolver(resolver())
+        .connect()
+        .await
+        .unwrap();
+    assert!(!client.session_present());
+
+    let err = client::MqttConnector::new("none.test:1883".to_string())
+        .resolver(resolver())
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(err, client::ClientError::Resolve(connect::ConnectError::NoRecords)));
+
+    // transport connector never completes
+    let err = client::MqttConnector::new("mqtt.test:1883".to_string())
+        .resolver(resolver())
+        .connector(fn_service(|_| {
+            futures::future::pending::<Result<ntex::rt::net::TcpStream, connect::ConnectError>>(
+            )
+        }))
+        .connect_timeout(Duration::from_millis(50))
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(err, client::ClientError::ConnectTimeout));
+}
+
+/// Single connection SOCKS5 or HTTP proxy, returns requested target
+fn start_proxy(
+    kind: connect::ProxyKind,
+    upstream: std::net::SocketAddr,
+) -> (std::net::SocketAddr, std::thread::JoinHandle<Vec<u8>>) {
+    use std::io::{Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = std::thread::spawn(move || {
+        let (mut io, _) = listener.accept().unwrap();
+        let target = match kind {
+            connect::ProxyKind::Socks5 => {
+                let mut buf = [0; 512];
+                io.read_exact(&mut buf[..4]).unwrap();
+                assert_eq!(&buf[..4], &[5, 2, 0, 2]);
+                io.write_all(&[5, 2]).unwrap();
+                io.read_exact(&mut buf[..2]).unwrap();
+                let len = buf[1] as usize;
+                io.read_exact(&mut buf[..len + 1]).unwrap();
+                let user = buf[..len].to_vec();
+                let plen = buf[len] as usize;
+                io.read_exact(&mut buf[..plen]).unwrap();
+                assert_eq!((&user[..], &buf[..plen]), (&b"user"[..], &b"pass"[..]));
+                io.write_all(&[1, 0]).unwrap();
+
+                io.read_exact(&mut buf[..5]).unwrap();
+                assert_eq!(&buf[..4], &[5, 1, 0, 3]);
+                let len = buf[4] as usize;
+                io.read_exact(&mut buf[..len + 2]).unwrap();
+                io.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+                buf[..len + 2].to_vec()
+            }
+            connect::ProxyKind::Http => {
+                let mut req = Vec::new();
+                let mut b = [0; 1];
+                while !req.ends_with(b"\r\n\r\n") {
+                    io.read_exact(&mut b).unwrap();
+                    req.push(b[0]);
+                }
+                io.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+                req
+            }
+        };
+
+        let upstream = std::net::TcpStream::connect(upstream).unwrap();
+        let (mut r1, mut w1) = (io.try_clone().unwrap(), upstream.try_clone().unwrap());
+        let (mut r2, mut w2) = (upstream, io);
+        std::thread::spawn(move || std::io::copy(&mut r1, &mut w1));
+        std::thread::spawn(move || std::io::copy(&mut r2, &mut w2));
+        target
+    });
+    (addr, handle)
+}
+
+#[ntex::test]
+async fn test_connect_proxy() {
+    let srv = server::test_server(|| MqttServer::new(handshake).publish(|_t| ok(())).finish());
+
+    // socks5 proxy with auth, target host is resolved by proxy
+    let (addr, proxy) = start_proxy(connect::ProxyKind::Socks5, srv.addr());
+    let url = format!("socks5://user:pass@{}", addr);
+    let client = client::MqttConnector::new("broker.test:1883")
+        .client_id("user")
+        .proxy(connect::Proxy::from_url(&url).unwrap())
+        .connect()
+        .await
+        .unwrap();
+    let mut target = b"broker.test".to_vec();
+    target.extend_from_slice(&1883u16.to_be_bytes());
+    assert_eq!(proxy.join().unwrap(), target);
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    // http proxy
+    let (addr, proxy) = start_proxy(connect::ProxyKind::Http, srv.addr());
+    let client = client::MqttConnector::new("broker.test:1883")
+        .client_id("user")
+        .proxy(connect::Proxy::http(addr.to_string()).auth("user", "pass"))
+        .connect()
+        .await
+        .unwrap();
+    let req = String::from_utf8(proxy.join().unwrap()).unwrap();
+    assert!(req.starts_with("CONNECT broker.test:1883 HTTP/1.1\r\n"));
+    assert!(req.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+    client.sink().close();
+}
+
+#[ntex::test]
+async fn test_keepalive_idle() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v3(server)
+            .expect(|pkt| matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck {
+                session_present: false,
+                return_code: codec::ConnectAckReason::ConnectionAccepted,
+            })
+            .run(),
+    );
+
+    let client = client::MqttConnector::new("localhost")
+        .client_id("user")
+        .keep_alive(Duration::from_secs(1))
+        .connect_io(io)
+        .await
+        .unwrap();
+    let mut broker = broker.await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // no pings while client sends packets
+    for _ in 0..4 {
+        sleep(Duration::from_millis(400)).await;
+        sink.publish(ByteString::from_static("topic"), Bytes::new())
+            .send_at_most_once()
+            .unwrap();
+        let pkt = broker.recv().await.unwrap();
+        assert!(matches!(pkt, codec::Packet::Publish(_)));
+    }
+
+    // idle connection, sink stays usable after ping
+    assert_eq!(broker.recv().await.unwrap(), codec::Packet::PingRequest);
+    broker.write(codec::Packet::PingResponse);
+    assert_eq!(broker.recv().await.unwrap(), codec::Packet::PingRequest);
+    sink.publish(ByteString::from_static("topic"), Bytes::new()).send_at_most_once().unwrap();
+    assert!(matches!(broker.recv().await.unwrap(), codec::Packet::Publish(_)));
+
+    sink.close();
+    broker.expect_closed().await;
+}
+
+#[ntex::test]
+async fn test_send_permit() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v3(server)
+            .expect(|pkt| matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck {
+                session_present: false,
+                return_code: codec::ConnectAckReason::ConnectionAccepted,
+            })
+            .run(),
+    );
+
+    let client = client::MqttConnector::new("localhost")
+        .client_id("user")
+        .max_send(1)
+        .connect_io(io)
+        .await
+        .unwrap();
+    let mut broker = broker.await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let permit = sink.acquire_send_permit().await.unwrap();
+    assert_eq!(sink.credit(), 0);
+
+    // cancelled waiter does not block the queue
+    let cancelled = sink.acquire_send_permit();
+    let queued = ntex::rt::spawn(
+        sink.publish(ByteString::from_static("topic"), Bytes::from_static(b"2"))
+            .send_at_least_once(),
+    );
+    drop(cancelled);
+
+    let first = ntex::rt::spawn(
+        permit
+            .publish(ByteString::from_static("topic"), Bytes::from_static(b"1"))
+            .send_at_least_once(),
+    );
+    let pkt = broker.recv().await.unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(ref p) if p.payload == "1"));
+    broker.write(codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() });
+    assert!(first.await.unwrap().is_ok());
+
+    let pkt = broker.recv().await.unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(ref p) if p.payload == "2"));
+    broker.write(codec::Packet::PublishAck { packet_id: NonZeroU16::new(2).unwrap() });
+    assert!(queued.await.unwrap().is_ok());
+    assert_eq!(sink.credit(), 1);
+
+    sink.close();
+    assert!(sink.acquire_send_permit().await.is_err());
+}
+
+#[ntex::test]
+async fn test_client_events_ping_timeout() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v3(server)
+            .expect(|pkt| matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck {
+                session_present: true,
+                return_code: codec::ConnectAckReason::ConnectionAccepted,
+            })
+            .run(),
+    );
+
+    let client = client::MqttConnector::new("localhost")
+        .client_id("user")
+        .keep_alive(Duration::from_secs(1))
+        .connect_io(io)
+        .await
+        .unwrap();
+    let mut broker = broker.await.unwrap();
+    let mut events = client.events();
+    ntex::rt::spawn(client.start_default());
+
+    assert!(matches!(
+        events.next().await,
+        Some(client::ClientEvent::Connected { session_present: true })
+    ));
+
+    // answered ping keeps connection open
+    assert_eq!(broker.recv().await.unwrap(), codec::Packet::PingRequest);
+    broker.write(codec::Packet::PingResponse);
+
+    // unanswered ping closes connection
+    assert_eq!(broker.recv().await.unwrap(), codec::Packet::PingRequest);
+    assert!(matches!(events.next().await, Some(client::ClientEvent::PingTimeout)));
+    assert!(matches!(
+        events.next().await,
+        Some(client::ClientEvent::Disconnected(client::DisconnectCause::PingTimeout))
+    ));
+    assert!(events.next().await.is_none());
+    broker.expect_closed().await;
+}
+
+#[ntex::test]
+async fn test_max_read_buf_size() {
+    use ntex::codec::Encoder;
+    use ntex::util::BytesMut;
+    use ntex::{Service, ServiceFactory};
+
+    let (client, server) = testing::duplex();
+    let srv = MqttServer::new(handshake)
+        .max_read_buf_size(64)
+        .publish(|_| ok(()))
+        .finish()
+        .new_service(())
+        .await
+        .ok()
+        .unwrap();
+    let srv = ntex::rt::spawn(async move { srv.call(server).await });
+
+    let codec = codec::Codec::default();
+    let mut buf = BytesMut::new();
+    codec
+        .encode(codec::Packet::Connect(codec::Connect::default().client_id("user")), &mut buf)
+        .unwrap();
+    client.write(buf);
+    client.read().await.unwrap();
+
+    // incomplete packet, bigger than read buffer limit
+    let mut buf = BytesMut::new();
+    codec
+        .encode(
+            codec::Packet::Publish(codec::Publish {
+                dup: false,
+                retain: false,
+                qos: codec::QoS::AtMostOnce,
+                topic: ByteString::from_static("test"),
+                packet_id: None,
+                payload: Bytes::from(vec![0; 1024]),
+            }),
+            &mut buf,
+        )
+        .unwrap();
+    client.write(buf.split_to(128));
+
+    let err = srv.await.unwrap().err().unwrap();
+    assert!(format!("{:?}", err).contains("Read buffer size limit is exceeded"));
+}
+
+#[ntex::test]
+async fn test_read_idle_timeout() {
+    use ntex::codec::Encoder;
+    use ntex::util::BytesMut;
+    use ntex::{Service, ServiceFactory};
+
+    let (client, server) = testing::duplex();
+    let srv = MqttServer::new(handshake)
+        .read_idle_timeout(Duration::from_millis(100))
+        .publish(|_| ok(()))
+        .finish()
+        .new_service(())
+        .await
+        .ok()
+        .unwrap();
+    let srv = ntex::rt::spawn(async move { srv.call(server).await });
+
+    let codec = codec::Codec::default();
+    let mut buf = BytesMut::new();
+    codec
+        .encode(codec::Packet::Connect(codec::Connect::default().client_id("user")), &mut buf)
+        .unwrap();
+    client.write(buf);
+    client.read().await.unwrap();
+
+    // peer is silent, connection is closed before keep-alive expires
+    let err = srv.await.unwrap().err().unwrap();
+    assert!(format!("{:?}", err).contains("Read idle timeout"));
+}
+
+#[ntex::test]
+async fn test_handshake_upgrade() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|h: Handshake<_>| async move {
+            Ok::<_, ()>(h.upgrade::<St, _, _>(|mut parts| async move {
+                // data received after CONNECT is available to new owner
+                let pkt = parts.codec.decode(&mut parts.read_buf).unwrap();
+                assert_eq!(pkt, Some(codec::Packet::PingRequest));
+                let mut framed = Framed::new(parts.io, parts.codec);
+                framed.send(codec::Packet::PingResponse).await.unwrap();
+            }))
+        })
+        .publish(|_| ok(()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .feed(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    framed.send(codec::Packet::PingRequest).await.unwrap();
+
+    // no CONNACK is sent
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+
+    Ok(())
+}
+
+/// Reverses publish payloads in both directions
+struct Reverse;
+
+impl ntex_mqtt::CodecExtension<codec::Packet> for Reverse {
+    fn decoded(
+        &self,
+        pkt: codec::Packet,
+    ) -> Result<codec::Packet, ntex_mqtt::error::DecodeError> {
+        Ok(reverse(pkt))
+    }
+
+    fn encode(
+        &self,
+        pkt: codec::Packet,
+    ) -> Result<codec::Packet, ntex_mqtt::error::EncodeError> {
+        Ok(reverse(pkt))
+    }
+}
+
+fn reverse(pkt: codec::Packet) -> codec::Packet {
+    match pkt {
+        codec::Packet::Publish(mut publish) => {
+            let mut payload = publish.payload.to_vec();
+            payload.reverse();
+            publish.payload = Bytes::from(payload);
+            codec::Packet::Publish(publish)
+        }
+        pkt => pkt,
+    }
+}
+
+#[ntex::test]
+async fn test_codec_extension() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|h: Handshake<_>| {
+            ok::<_, ()>(h.ack(St, false).codec_extension(Reverse))
+        })
+        .publish(fn_factory_with_config(|session: Session<St>| {
+            ok::<_, ()>(fn_service(move |p: Publish| {
+                assert_eq!(p.payload(), &Bytes::from_static(b"data"));
+                session
+                    .sink()
+                    .publish(ByteString::from_static("echo"), p.payload().clone())
+                    .send_at_most_once()
+                    .unwrap();
+                ok::<_, ()>(())
+            }))
+        }))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    assert!(matches!(framed.next().await.unwrap().unwrap(), codec::Packet::ConnectAck { .. }));
+
+    framed
+        .send(codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: None,
+            payload: Bytes::from_static(b"atad"),
+        }))
+        .await
+        .unwrap();
+
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::Publish(publish) => {
+            assert_eq!(publish.topic, "echo");
+            assert_eq!(publish.payload, Bytes::from_static(b"atad"));
+        }
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    }
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_slow_consumer() {
+    use std::{cell::RefCell, rc::Rc};
+
+    use ntex::{Service, ServiceFactory};
+    use ntex_mqtt::{v3::MqttSink, SlowConsumerPolicy};
+
+    let (client, server) = testing::Io::create();
+    // peer reads connect ack only
+    client.remote_buffer_cap(4);
+
+    let sink: Rc<RefCell<Option<MqttSink>>> = Rc::new(RefCell::new(None));
+    let sink2 = sink.clone();
+    let srv = MqttServer::new(fn_service(move |h: Handshake<_>| {
+        *sink2.borrow_mut() = Some(h.sink());
+        ok::<_, ()>(h.ack(St, false))
+    }))
+    .slow_consumer(16, Duration::ZERO, SlowConsumerPolicy::DropQos0)
+    .publish(|_| ok(()))
+    .finish()
+    .new_service(())
+    .await
+    .ok()
+    .unwrap();
+    ntex::rt::spawn(async move { srv.call(server).await });
+
+    let mut broker = testing::MockBroker::v3(client)
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .expect(|pkt| matches!(pkt, codec::Packet::ConnectAck { .. }))
+        .run()
+        .await;
+
+    let sink = sink.borrow_mut().take().unwrap();
+    let payload = Bytes::from(vec![0; 32]);
+    sink.publish(ByteString::from_static("t1"), payload.clone()).send_at_most_once().unwrap();
+    // outbound queue is over watermark, publish is dropped
+    sink.publish(ByteString::from_static("t2"), payload).send_at_most_once().unwrap();
+
+    broker.io().remote_buffer_cap(usize::MAX);
+    let pkt = broker.recv().await.unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(ref p) if p.topic == "t1"));
+
+    sink.close();
+    broker.expect_closed().await;
+}
+
+#[ntex::test]
+async fn test_publish_hook() -> std::io::Result<()> {
+    let metrics = Arc::new(Mutex::new(Vec::new()));
+    let metrics2 = metrics.clone();
+
+    let srv = server::test_server(move || {
+        let metrics = metrics2.clone();
+        MqttServer::new(handshake)
+            .publish_hook(move |m| {
+                metrics.lock().unwrap().push((
+                    m.topic.clone(),
+                    m.payload_size,
+                    m.qos,
+                    m.success,
+                ))
+            })
+            .publish(|_| ok::<_, ()>(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed
+        .send(codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: Some(NonZeroU16::new(1).unwrap()),
+            payload: Bytes::from_static(b"data"),
+        }))
+        .await
+        .unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() }
+    );
+
+    assert_eq!(
+        *metrics.lock().unwrap(),
+        vec![(ByteString::from_static("test"), 4, codec::QoS::AtLeastOnce, true)]
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_dead_letter() -> std::io::Result<()> {
+    let letters = Arc::new(Mutex::new(Vec::new()));
+    let letters2 = letters.clone();
+
+    let srv =
+        server::test_server(move || {
+            let letters = letters2.clone();
+            MqttServer::new(handshake)
+                .dead_letter(move |pkt, failure: PublishFailure<'_, ()>| {
+                    assert!(std::matches!(failure, PublishFailure::Error(_)));
+                    letters.lock().unwrap().push(pkt)
+                })
+                .publish(|p: Publish| {
+                    if p.publish_topic() == "fail" {
+                        ready(Err(()))
+                    } else {
+                        ready(Ok(()))
+                    }
+                })
+                .finish()
+        });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let mut publish = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtLeastOnce,
+        topic: ByteString::from_static("test"),
+        packet_id: Some(NonZeroU16::new(1).unwrap()),
+        payload: Bytes::from_static(b"data"),
+    };
+    framed.send(codec::Packet::Publish(publish.clone())).await.unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() }
+    );
+    assert!(letters.lock().unwrap().is_empty());
+
+    // failed publish is passed to hook, connection is closed
+    publish.topic = ByteString::from_static("fail");
+    framed.send(codec::Packet::Publish(publish.clone())).await.unwrap();
+    assert!(framed.next().await.is_none());
+    assert_eq!(*letters.lock().unwrap(), vec![publish]);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_timeout() -> std::io::Result<()> {
+    let letters = Arc::new(Mutex::new(Vec::new()));
+    let letters2 = letters.clone();
+
+    let srv = server::test_server(move || {
+        let letters = letters2.clone();
+        let slow = |_: Publish| sleep(Duration::from_millis(200)).map(|_| Ok::<_, ()>(()));
+        MqttServer::new(handshake)
+            .publish_timeout(Duration::from_millis(50))
+            .dead_letter(move |pkt, failure: PublishFailure<'_, ()>| {
+                assert!(std::matches!(failure, PublishFailure::Timeout));
+                letters.lock().unwrap().push(pkt.topic)
+            })
+            .publish(Router::new(slow).resource_with_timeout(
+                "long",
+                Duration::from_millis(500),
+                slow,
+            ))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // resource timeout overrides server timeout
+    let mut publish = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtLeastOnce,
+        topic: ByteString::from_static("long"),
+        packet_id: Some(NonZeroU16::new(1).unwrap()),
+        payload: Bytes::new(),
+    };
+    framed.send(codec::Packet::Publish(publish.clone())).await.unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() }
+    );
+
+    // QoS 0 publish is discarded
+    publish.topic = ByteString::from_static("test");
+    publish.qos = codec::QoS::AtMostOnce;
+    publish.packet_id = None;
+    framed.send(codec::Packet::Publish(publish.clone())).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    framed.send(codec::Packet::PingRequest).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), codec::Packet::PingResponse);
+
+    // QoS 1 publish closes connection
+    publish.qos = codec::QoS::AtLeastOnce;
+    publish.packet_id = NonZeroU16::new(2);
+    framed.send(codec::Packet::Publish(publish)).await.unwrap();
+    assert!(framed.next().await.is_none());
+    assert_eq!(
+        *letters.lock().unwrap(),
+        vec![ByteString::from_static("test"), ByteString::from_static("test")]
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_fanout() -> std::io::Result<()> {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                received.lock().unwrap().push((p.qos(), p.payload().clone()));
+                ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let mut sinks = Vec::new();
+    for _ in 0..3 {
+        let client =
+            client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+        sinks.push(client.sink());
+        ntex::rt::spawn(client.start_default());
+    }
+    sinks[2].close();
+
+    let res = Fanout::new("topic", Bytes::from_static(b"data"))
+        .qos(codec::QoS::AtLeastOnce)
+        .send(vec![
+            (&sinks[0], codec::QoS::AtLeastOnce),
+            (&sinks[1], codec::QoS::AtMostOnce),
+            (&sinks[2], codec::QoS::AtLeastOnce),
+        ])
+        .await;
+    assert!(!res.is_success());
+    assert_eq!(res.delivered(), 2);
+    assert_eq!(res.failed(), &[(2, SendPacketError::Disconnected)]);
+
+    sleep(Duration::from_millis(50)).await;
+    let mut received = received.lock().unwrap().clone();
+    received.sort_by_key(|(qos, _)| u8::from(*qos));
+    assert_eq!(
+        received,
+        vec![
+            (codec::QoS::AtMostOnce, Bytes::from_static(b"data")),
+            (codec::QoS::AtLeastOnce, Bytes::from_static(b"data"))
+        ]
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_registry() -> std::io::Result<()> {
+    let registered = Arc::new(Mutex::new(Vec::new()));
+    let registered2 = registered.clone();
+
+    let srv = server::test_server(move || {
+        let registered = registered2.clone();
+        let registry = SinkRegistry::new();
+        MqttServer::new(handshake)
+            .sink_registry(registry.clone())
+            .publish(move |p: Publish| {
+                let mut ids = registry.client_ids();
+                ids.sort();
+                registered.lock().unwrap().push(ids);
+                if let Some(sink) = registry.get("c2") {
+                    sink.publish(ByteString::from_static("hello"), p.payload().clone())
+                        .send_at_most_once()
+                        .unwrap();
+                }
+                ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let c1 = client::MqttConnector::new(srv.addr()).client_id("c1").connect().await.unwrap();
+    let sink1 = c1.sink();
+    ntex::rt::spawn(c1.start_default());
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    let c2 = client::MqttConnector::new(srv.addr()).client_id("c2").connect().await.unwrap();
+    let sink2 = c2.sink();
+    ntex::rt::spawn(c2.start(move |msg| match msg {
+        client::ControlMessage::Publish(p) => {
+            received2.lock().unwrap().push(p.packet().payload.clone());
+            ok::<_, ()>(p.ack())
+        }
+        msg => ok(msg.disconnect()),
+    }));
+
+    sink1
+        .publish(ByteString::from_static("to/c2"), Bytes::from_static(b"data"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(*received.lock().unwrap(), vec![Bytes::from_static(b"data")]);
+
+    // disconnected client is removed from registry
+    sink2.close();
+    sleep(Duration::from_millis(50)).await;
+    sink1
+        .publish(ByteString::from_static("to/c2"), Bytes::from_static(b"data2"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    assert_eq!(
+        *registered.lock().unwrap(),
+        vec![
+            vec![ByteString::from_static("c1"), ByteString::from_static("c2")],
+            vec![ByteString::from_static("c1")]
+        ]
+    );
+    assert_eq!(received.lock().unwrap().len(), 1);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_result() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| ok::<_, ()>(()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        if sub.topic() == "denied" {
+                            sub.fail();
+                        } else {
+                            sub.confirm(sub.qos());
+                        }
+                    }
+                    ok(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .subscribe()
+        .topic_filter("topic1".into(), codec::QoS::AtLeastOnce)
+        .topic_filter("denied".into(), codec::QoS::AtLeastOnce)
+        .topic_filter("topic2".into(), codec::QoS::AtMostOnce)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.len(), 3);
+    assert!(!res.is_success());
+    assert_eq!(res.granted("topic1"), Some(codec::QoS::AtLeastOnce));
+    assert_eq!(res.granted("denied"), None);
+    assert_eq!(
+        res.iter().map(|(f, qos)| (f.as_ref(), qos)).collect::<Vec<_>>(),
+        vec![
+            ("topic1", Some(codec::QoS::AtLeastOnce)),
+            ("denied", None),
+            ("topic2", Some(codec::QoS::AtMostOnce)),
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_retransmit() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+
+    let srv = server::test_server(move || {
+        let calls = calls2.clone();
+        MqttServer::new(handshake)
+            .publish(|_| ok::<_, ()>(()))
+            .control(move |msg| {
+                let calls = calls.clone();
+                async move {
+                    match msg {
+                        ControlMessage::Unsubscribe(msg) => {
+                            calls.fetch_add(1, Relaxed);
+                            sleep(Duration::from_millis(100)).await;
+                            Ok::<_, ()>(msg.ack())
+                        }
+                        _ => Ok(msg.disconnect()),
+                    }
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let unsubscribe = codec::Packet::Unsubscribe {
+        packet_id: NonZeroU16::new(1).unwrap(),
+        topic_filters: vec![ByteString::from("topic1")],
+    };
+    framed.send(unsubscribe.clone()).await.unwrap();
+    framed.send(unsubscribe).await.unwrap();
+
+    // both packets are acknowledged, control service is called once
+    let ack = codec::Packet::UnsubscribeAck { packet_id: NonZeroU16::new(1).unwrap() };
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack);
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack);
+    assert_eq!(calls.load(Relaxed), 1);
+}
+
+#[ntex::test]
+async fn test_subscription_limits() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+
+    let srv = server::test_server(move || {
+        let calls = calls2.clone();
+        MqttServer::new(handshake)
+            .max_topic_filters(2)
+            .max_subscriptions(2)
+            .publish(|_| ok::<_, ()>(()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    calls.fetch_add(1, Relaxed);
+                    for mut sub in &mut msg {
+                        sub.confirm(sub.qos());
+                    }
+                    ok::<_, ()>(msg.ack())
+                }
+                ControlMessage::Unsubscribe(msg) => ok(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let subscribe = |id, topics: &[&'static str]| codec::Packet::Subscribe {
+        packet_id: NonZeroU16::new(id).unwrap(),
+        topic_filters: topics
+            .iter()
+            .map(|t| (ByteString::from_static(t), codec::QoS::AtMostOnce))
+            .collect(),
+    };
+    let ack = |id, status: Vec<codec::SubscribeReturnCode>| codec::Packet::SubscribeAck {
+        packet_id: NonZeroU16::new(id).unwrap(),
+        status,
+    };
+    let granted = codec::SubscribeReturnCode::Success(codec::QoS::AtMostOnce);
+    let failure = codec::SubscribeReturnCode::Failure;
+
+    // too many topic filters in one packet
+    framed.send(subscribe(1, &["t1", "t2", "t3"])).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack(1, vec![failure; 3]));
+    assert_eq!(calls.load(Relaxed), 0);
+
+    framed.send(subscribe(2, &["t1", "t2"])).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack(2, vec![granted; 2]));
+
+    // max subscriptions per session
+    framed.send(subscribe(3, &["t3"])).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack(3, vec![failure]));
+    assert_eq!(calls.load(Relaxed), 1);
+
+    framed
+        .send(codec::Packet::Unsubscribe {
+            packet_id: NonZeroU16::new(4).unwrap(),
+            topic_filters: vec![ByteString::from_static("t1")],
+        })
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed.send(subscribe(5, &["t3"])).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack(5, vec![granted]));
+    assert_eq!(calls.load(Relaxed), 2);
+}
+
+#[ntex::test]
+async fn test_retain_policy() {
+    let retained = Arc::new(AtomicBool::new(true));
+    let retained2 = retained.clone();
+
+    let srv = server::test_server(move || {
+        let retained = retained2.clone();
+        MqttServer::new(handshake)
+            .retain_policy(RetainPolicy::Strip)
+            .publish(move |p: Publish| {
+                retained.store(p.retain(), Relaxed);
+                ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let publish = codec::Publish {
+        dup: false,
+        retain: true,
+        qos: codec::QoS::AtLeastOnce,
+        topic: ByteString::from_static("test"),
+        packet_id: NonZeroU16::new(1),
+        payload: Bytes::new(),
+    };
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed.send(publish.clone().into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    assert!(!retained.load(Relaxed));
+
+    // retained publish closes connection
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .retain_policy(RetainPolicy::Reject)
+            .publish(|_| ok::<_, ()>(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed.send(publish.into()).await.unwrap();
+    assert!(framed.next().await.is_none());
+}
+
+#[ntex::test]
+async fn test_topic_rewrite() {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+
+    let srv = server::test_server(move || {
+        let topics = topics2.clone();
+        let topics2 = topics2.clone();
+        MqttServer::new(handshake)
+            .topic_rewrite(TopicRewrite::new().prefix("legacy/", "devices/"))
+            .publish(move |p: Publish| {
+                topics.lock().unwrap().push(p.publish_topic().to_string());
+                ok::<_, ()>(())
+            })
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        topics2.lock().unwrap().push(sub.topic().to_string());
+                        sub.confirm(sub.qos());
+                    }
+                    ok::<_, ()>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![
+                (ByteString::from_static("legacy/+/temp"), codec::QoS::AtLeastOnce),
+                (ByteString::from_static("$share/g1/legacy/#"), codec::QoS::AtLeastOnce),
+                (ByteString::from_static("other/#"), codec::QoS::AtLeastOnce),
+            ],
+        })
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(
+            codec::Publish {
+                dup: false,
+                retain: false,
+                qos: codec::QoS::AtLeastOnce,
+                topic: ByteString::from_static("legacy/dev1/temp"),
+                packet_id: NonZeroU16::new(2),
+                payload: Bytes::new(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    assert_eq!(
+        &*topics.lock().unwrap(),
+        &["devices/+/temp", "$share/g1/devices/#", "other/#", "devices/dev1/temp"]
+    );
+}
+
+#[ntex::test]
+async fn test_tenant_unsubscribe() {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+
+    let srv = server::test_server(move || {
+        let topics = topics2.clone();
+        MqttServer::new(handshake)
+            .tenant_isolation(|session: &Session<St>| Some(session.client_id().clone()))
+            .publish(|_| ok::<_, ()>(()))
+            .control(move |msg| match msg {
+                ControlMessage::Unsubscribe(msg) => {
+                    for topic in msg.iter() {
+                        topics.lock().unwrap().push(topic.to_string());
+                    }
+                    ok::<_, ()>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("acme").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // reserved topics are outside of namespace
+    let packet_id = NonZeroU16::new(1).unwrap();
+    framed
+        .send(codec::Packet::Unsubscribe {
+            packet_id,
+            topic_filters: vec![ByteString::from("$SYS/#"), ByteString::from("a/#")],
+        })
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::UnsubscribeAck { packet_id });
+
+    let packet_id = NonZeroU16::new(2).unwrap();
+    framed
+        .send(codec::Packet::Unsubscribe {
+            packet_id,
+            topic_filters: vec![ByteString::from("$SYS/#")],
+        })
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::UnsubscribeAck { packet_id });
+
+    assert_eq!(&*topics.lock().unwrap(), &["acme/a/#"]);
+}
+
+#[ntex::test]
+async fn test_max_qos() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_qos(codec::QoS::AtMostOnce)
+            .publish(|_| ok::<_, ()>(()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.confirm(sub.qos());
+                    }
+                    ok::<_, ()>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(ByteString::from_static("topic1"), codec::QoS::ExactlyOnce)],
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::Packet::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![codec::SubscribeReturnCode::Success(codec::QoS::AtMostOnce)],
+        }
+    );
+}
+
+#[ntex::test]
+async fn test_auto_ack() {
+    let handled = Arc::new(AtomicUsize::new(0));
+    let handled2 = handled.clone();
+
+    let srv = server::test_server(move || {
+        let handled = handled2.clone();
+        MqttServer::new(handshake)
+            .auto_ack(true)
+            .publish(move |_: Publish| {
+                let handled = handled.clone();
+                sleep(Duration::from_millis(100)).map(move |_| {
+                    handled.fetch_add(1, Relaxed);
+                    Ok::<_, ()>(())
+                })
+            })
+            .control(|msg| match msg {
+                ControlMessage::Ping(_) => panic!("ping is answered by dispatcher"),
+                _ => ok::<_, ()>(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // packet id could be reused right after ack
+    let publish = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtLeastOnce,
+        topic: ByteString::from_static("test"),
+        packet_id: NonZeroU16::new(1),
+        payload: Bytes::new(),
+    };
+    for _ in 0..2 {
+        framed.send(publish.clone().into()).await.unwrap();
+        assert_eq!(
+            framed.next().await.unwrap().unwrap(),
+            codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() }
+        );
+    }
+    assert_eq!(handled.load(Relaxed), 0);
+
+    framed.send(codec::Packet::PingRequest).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), codec::Packet::PingResponse);
+
+    sleep(Duration::from_millis(300)).await;
+    assert_eq!(handled.load(Relaxed), 2);
+}
+
+#[ntex::test]
+async fn test_auto_ack_manual_resource() {
+    for lanes in [0, 1] {
+        let persisted = Arc::new(AtomicBool::new(false));
+        let persisted2 = persisted.clone();
+
+        let srv = server::test_server(move || {
+            let persisted = persisted2.clone();
+            MqttServer::new(handshake)
+                .auto_ack(true)
+                .ordered_lanes(lanes)
+                .publish(Router::new(|_: Publish| ok::<_, ()>(())).resource_with_manual_ack(
+                    "persist",
+                    move |_: Publish| {
+                        let persisted = persisted.clone();
+                        sleep(Duration::from_millis(100)).map(move |_| {
+                            persisted.store(true, Relaxed);
+                            Ok::<_, ()>(())
+                        })
+                    },
+                ))
+                .finish()
+        });
+
+        let io = srv.connect().await.unwrap();
+        let mut framed = Framed::new(io, codec::Codec::default());
+        framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+        let _ = framed.next().await.unwrap().unwrap();
+
+        let publish = |topic, id| codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from_static(topic),
+            packet_id: NonZeroU16::new(id),
+            payload: Bytes::new(),
+        };
+
+        // manual resource acknowledges after handler completes
+        framed.send(publish("persist", 1).into()).await.unwrap();
+        assert_eq!(
+            framed.next().await.unwrap().unwrap(),
+            codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() }
+        );
+        assert!(persisted.load(Relaxed));
+
+        framed.send(publish("test", 2).into()).await.unwrap();
+        assert_eq!(
+            framed.next().await.unwrap().unwrap(),
+            codec::Packet::PublishAck { packet_id: NonZeroU16::new(2).unwrap() }
+        );
+    }
+}
+
+#[ntex::test]
+async fn test_auto_ack_blocked_lane() {
+    let started = Arc::new(AtomicUsize::new(0));
+    let started2 = started.clone();
+
+    let srv = server::test_server(move || {
+        let started = started2.clone();
+        MqttServer::new(handshake)
+            .auto_ack(true)
+            .ordered_lanes(1)
+            .publish(move |p: Publish| {
+                started.fetch_add(1, Relaxed);
+                let delay = if p.payload().as_ref() == b"1" { 200 } else { 0 };
+                sleep(Duration::from_millis(delay)).map(|_| Ok::<_, ()>(()))
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    for (id, payload) in [(1, "1"), (2, "2")] {
+        let publish = codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: NonZeroU16::new(id),
+            payload: Bytes::from_static(payload.as_bytes()),
+        };
+        framed.send(publish.into()).await.unwrap();
+    }
+
+    // first publish is acked as soon as its handler is called
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() }
+    );
+    assert_eq!(started.load(Relaxed), 1);
+
+    // second publish waits in the lane, it is not acked before its handler is called
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::Packet::PublishAck { packet_id: NonZeroU16::new(2).unwrap() }
+    );
+    assert_eq!(started.load(Relaxed), 2);
+}
+
+#[ntex::test]
+async fn test_client_id_policy() {
+    let ids = Arc::new(Mutex::new(Vec::new()));
+    let ids2 = ids.clone();
+
+    let srv = server::test_server(move || {
+        let ids = ids2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            ids.lock().unwrap().push(con.packet().client_id.clone());
+            ok::<_, ()>(con.ack(St, false))
+        })
+        .client_id_policy(|id| ntex_mqtt::client_id::lowercase(id).filter(|id| id != "banned"))
+        .publish(|_| ok::<_, ()>(()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("Device-01").into()).await.unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted
+        }
+    );
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("Banned").into()).await.unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::IdentifierRejected
+        }
+    );
+    assert_eq!(*ids.lock().unwrap(), vec!["device-01"]);
+}
+
+#[ntex::test]
+async fn test_client_session() -> std::io::Result<()> {
+    let subs = Arc::new(Mutex::new(Vec::new()));
+    let subs2 = subs.clone();
+
+    let srv = server::test_server(move || {
+        let subs = subs2.clone();
+        MqttServer::new(|packet: Handshake<_>| {
+            let present = packet.packet().client_id == "present";
+            ok::<_, ()>(packet.ack(St, present))
+        })
+        .publish(|_| ok::<_, ()>(()))
+        .control(move |msg| match msg {
+            ControlMessage::Subscribe(mut msg) => {
+                for mut sub in &mut msg {
+                    subs.lock().unwrap().push(sub.topic().to_string());
+                    sub.confirm(sub.qos());
+                }
+                ok(msg.ack())
+            }
+            _ => ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let session = client::ClientSession::new();
+    session.subscribe("topic1", codec::QoS::AtLeastOnce);
+    session.subscribe("topic2", codec::QoS::AtMostOnce);
+
+    // server does not have session state, subscriptions are restored
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .session(session.clone())
+        .connect()
+        .await
+        .unwrap();
+    assert!(!client.session_present());
+    assert_eq!(
+        client.restored_subscriptions(),
+        &[
+            codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce),
+            codec::SubscribeReturnCode::Success(codec::QoS::AtMostOnce)
+        ]
+    );
+    assert_eq!(*subs.lock().unwrap(), vec!["topic1".to_string(), "topic2".to_string()]);
+    client.sink().close();
+
+    // session is present on server
+    subs.lock().unwrap().clear();
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("present")
+        .session(session)
+        .connect()
+        .await
+        .unwrap();
+    assert!(client.session_present());
+    assert!(client.restored_subscriptions().is_empty());
+    assert!(subs.lock().unwrap().is_empty());
+    client.sink().close();
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_send_packet() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                assert!(p.retain());
+                ok::<_, ()>(())
+            })
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.confirm(sub.qos());
+                    }
+                    ok(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let packet_id = NonZeroU16::new(10).unwrap();
+    let ack = sink
+        .send_packet(codec::Packet::Subscribe {
+            packet_id,
+            topic_filters: vec![(ByteString::from("topic"), codec::QoS::AtLeastOnce)],
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        ack,
+        Some(codec::Packet::SubscribeAck {
+            packet_id,
+            status: vec![codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)],
+        })
+    );
+
+    let publish = codec::Publish {
+        dup: false,
+        retain: true,
+        qos: codec::QoS::AtLeastOnce,
+        topic: ByteString::from("topic"),
+        packet_id: Some(packet_id),
+        payload: Bytes::new(),
+    };
+    let ack = sink.send_packet(codec::Packet::Publish(publish.clone())).await.unwrap();
+    assert_eq!(ack, Some(codec::Packet::PublishAck { packet_id }));
+
+    let publish = codec::Publish { qos: codec::QoS::AtMostOnce, packet_id: None, ..publish };
+    let ack = sink.send_packet(codec::Packet::Publish(publish)).await.unwrap();
+    assert_eq!(ack, None);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_unsolicited() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v3(server)
+            .expect(|pkt| matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck {
+                session_present: false,
+                return_code: codec::ConnectAckReason::ConnectionAccepted,
+            })
+            .run(),
+    );
+
+    let client =
+        client::MqttConnector::new("localhost").client_id("user").connect_io(io).await.unwrap();
+    let mut broker = broker.await.unwrap();
+    let sink = client.sink();
+
+    let packets = Arc::new(Mutex::new(Vec::new()));
+    let packets2 = packets.clone();
+    ntex::rt::spawn(client.start(move |msg| match msg {
+        client::ControlMessage::Unsolicited(pkt) => {
+            packets2.lock().unwrap().push(pkt.packet().clone());
+            ok::<_, ()>(pkt.ack())
+        }
+        msg => ok(msg.disconnect()),
+    }));
+
+    let packet_id = NonZeroU16::new(100).unwrap();
+    broker.write(codec::Packet::PublishAck { packet_id });
+    broker.write(codec::Packet::UnsubscribeAck { packet_id });
+
+    // connection is still open
+    sink.publish(ByteString::from_static("topic"), Bytes::new()).send_at_most_once().unwrap();
+    let pkt = broker.recv().await.unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(ref p) if p.topic == "topic"));
+    assert_eq!(
+        *packets.lock().unwrap(),
+        vec![
+            codec::Packet::PublishAck { packet_id },
+            codec::Packet::UnsubscribeAck { packet_id }
+        ]
+    );
+
+    sink.close();
+    broker.expect_closed().await;
+}
+
+#[ntex::test]
+async fn test_timer_resolution() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|packet: Handshake<_>| {
+            ok::<_, ()>(packet.ack(St, false).idle_timeout(Duration::from_secs(1)))
+        })
+        .timer_resolution(Duration::from_millis(50))
+        .publish(|_| ok::<_, ()>(()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // idle connection is dropped close to keep-alive timeout
+    let start = Instant::now();
+    assert!(framed.next().await.is_none());
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(900) && elapsed < Duration::from_millis(1500));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_listener_config() -> std::io::Result<()> {
+    fn start(cfg: ListenerConfig) -> server::TestServer {
+        server::test_server(move || {
+            let cfg = cfg.clone();
+            ntex::map_config(
+                MqttServer::new(handshake).publish(|_| ok(())).finish_with_config(),
+                move |_: ()| cfg.clone(),
+            )
+        })
+    }
+    let limited = start(ListenerConfig::new().max_size(64));
+    let unlimited = start(ListenerConfig::new());
+    let payload = Bytes::from(vec![0u8; 128]);
+
+    // max size is overridden by listener config
+    let client =
+        client::MqttConnector::new(limited.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res = sink
+        .publish(ByteString::from_static("test"), payload.clone())
+        .send_at_least_once()
+        .await;
+    assert!(res.is_err());
+
+    // same server factory without overrides
+    let client =
+        client::MqttConnector::new(unlimited.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res = sink.publish(ByteString::from_static("test"), payload).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_config_handle() -> std::io::Result<()> {
+    let config = ConfigHandle::new();
+    let cfg = config.clone();
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake).config(cfg.clone()).publish(|_| ok(())).finish()
+    });
+    let payload = Bytes::from(vec![0u8; 128]);
+
+    // new connections use updated max size
+    config.set_max_size(64);
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res = sink
+        .publish(ByteString::from_static("test"), payload.clone())
+        .send_at_least_once()
+        .await;
+    assert!(res.is_err());
+
+    // builder settings are used after reset
+    config.reset();
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res = sink.publish(ByteString::from_static("test"), payload).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_stuck_ack() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v3(server)
+            .expect(|pkt| matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck {
+                session_present: false,
+                return_code: codec::ConnectAckReason::ConnectionAccepted,
+            })
+            .run(),
+    );
+
+    let client =
+        client::MqttConnector::new("localhost").client_id("user").connect_io(io).await.unwrap();
+    let mut broker = broker.await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let stuck = Arc::new(Mutex::new(Vec::new()));
+    let stuck2 = stuck.clone();
+    sink.on_stuck_ack(Duration::from_millis(50), move |idx, age| {
+        assert!(age >= Duration::from_millis(50));
+        stuck2.lock().unwrap().push(idx);
+    });
+    assert_eq!(sink.inflight(), 0);
+    assert_eq!(sink.inflight_age(), None);
+
+    let publish = ntex::rt::spawn(
+        sink.publish(ByteString::from_static("topic"), Bytes::from_static(b"1"))
+            .send_at_least_once(),
+    );
+    let pkt = broker.recv().await.unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(_)));
+    assert_eq!(sink.inflight(), 1);
+    assert!(sink.inflight_age().is_some());
+
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(*stuck.lock().unwrap(), vec![1]);
+
+    // waiter fails, packet keeps in-flight slot
+    assert_eq!(sink.expire_inflight(Duration::from_secs(10)), 0);
+    assert_eq!(sink.expire_inflight(Duration::from_millis(50)), 1);
+    assert_eq!(publish.await.unwrap(), Err(SendPacketError::Expired));
+    assert_eq!(sink.inflight(), 1);
+
+    // late ack releases slot
+    broker.write(codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() });
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(sink.inflight(), 0);
+    assert_eq!(sink.inflight_age(), None);
+    sink.close();
+}
+
+#[ntex::test]
+async fn test_inflight_packets() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v3(server)
+            .expect(|pkt| matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck {
+                session_present: false,
+                return_code: codec::ConnectAckReason::ConnectionAccepted,
+            })
+            .run(),
+    );
+
+    let client =
+        client::MqttConnector::new("localhost").client_id("user").connect_io(io).await.unwrap();
+    let mut broker = broker.await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let publish1 = ntex::rt::spawn(
+        sink.publish(ByteString::from_static("topic1"), Bytes::from_static(b"1"))
+            .send_at_least_once(),
+    );
+    let publish2 = ntex::rt::spawn(
+        sink.publish(ByteString::from_static("topic2"), Bytes::from_static(b"2"))
+            .send_at_least_once(),
+    );
+    let subscribe = ntex::rt::spawn(
+        sink.subscribe()
+            .topic_filter(ByteString::from_static("#"), codec::QoS::AtLeastOnce)
+            .send(),
+    );
+    for _ in 0..3 {
+        broker.recv().await.unwrap();
+    }
+
+    let packets = sink.inflight_packets();
+    assert_eq!(packets.len(), 3);
+    assert_eq!(packets[0].packet_id, 1);
+    assert_eq!(packets[0].packet_type, InflightType::Publish);
+    assert_eq!(packets[0].topic, Some(ByteString::from_static("topic1")));
+    assert_eq!(packets[1].topic, Some(ByteString::from_static("topic2")));
+    assert_eq!(packets[2].packet_type, InflightType::Subscribe);
+    assert_eq!(packets[2].topic, None);
+
+    assert!(sink.complete_inflight(1));
+    assert!(!sink.complete_inflight(1));
+    assert_eq!(publish1.await.unwrap(), Ok(()));
+    assert!(sink.fail_inflight(2));
+    assert_eq!(publish2.await.unwrap(), Err(SendPacketError::Expired));
+    assert!(sink.complete_inflight(3));
+    assert!(!subscribe.await.unwrap().unwrap().is_success());
+    assert_eq!(sink.inflight(), 0);
+    assert_eq!(sink.credit(), 16);
+    sink.close();
+}
+
+#[ntex::test]
+async fn test_sink_flush() {
+    let (io, server) = testing::duplex();
+    let peer = server.clone();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v3(server)
+            .expect(|pkt| matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck {
+                session_present: false,
+                return_code: codec::ConnectAckReason::ConnectionAccepted,
+            })
+            .run(),
+    );
+
+    let client =
+        client::MqttConnector::new("localhost").client_id("user").connect_io(io).await.unwrap();
+    let mut broker = broker.await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // nothing is queued
+    assert_eq!(sink.flush().await, Ok(()));
+
+    // peer does not accept data
+    peer.remote_buffer_cap(0);
+    sink.publish(ByteString::from_static("topic"), Bytes::from_static(b"1"))
+        .send_at_most_once()
+        .unwrap();
+    let flushed = Arc::new(AtomicBool::new(false));
+    let flushed2 = flushed.clone();
+    let flush = sink.flush();
+    ntex::rt::spawn(async move {
+        assert_eq!(flush.await, Ok(()));
+        flushed2.store(true, Relaxed);
+    });
+    sleep(Duration::from_millis(50)).await;
+    assert!(!flushed.load(Relaxed));
+
+    peer.remote_buffer_cap(usize::MAX);
+    sleep(Duration::from_millis(50)).await;
+    assert!(flushed.load(Relaxed));
+    assert!(matches!(broker.recv().await.unwrap(), codec::Packet::Publish(_)));
+
+    // connection is closed with pending data
+    peer.remote_buffer_cap(0);
+    sink.publish(ByteString::from_static("topic"), Bytes::from_static(b"2"))
+        .send_at_most_once()
+        .unwrap();
+    let flush = sink.flush();
+    sink.force_close();
+    assert_eq!(flush.await, Err(SendPacketError::Disconnected));
+}
+
+#[ntex::test]
+async fn test_ping_rtt() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v3(server)
+            .expect(|pkt| matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck {
+                session_present: false,
+                return_code: codec::ConnectAckReason::ConnectionAccepted,
+            })
+            .run(),
+    );
+
+    let client =
+        client::MqttConnector::new("localhost").client_id("user").connect_io(io).await.unwrap();
+    let mut broker = broker.await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    assert_eq!(sink.rtt(), None);
+
+    let ping = ntex::rt::spawn(sink.ping());
+    assert_eq!(broker.recv().await.unwrap(), codec::Packet::PingRequest);
+    sleep(Duration::from_millis(50)).await;
+    broker.write(codec::Packet::PingResponse);
+
+    let rtt = ping.await.unwrap().unwrap();
+    assert!(rtt >= Duration::from_millis(50));
+    assert_eq!(sink.rtt(), Some(rtt));
+
+    // pending ping fails on disconnect
+    let ping = ntex::rt::spawn(sink.ping());
+    assert_eq!(broker.recv().await.unwrap(), codec::Packet::PingRequest);
+    sink.close();
+    assert_eq!(ping.await.unwrap(), Err(SendPacketError::Disconnected));
+    assert!(sink.ping().await.is_err());
+}
+
+#[ntex::test]
+async fn test_server_ping_not_allowed() {
+    let refused = Arc::new(AtomicBool::new(false));
+    let refused2 = refused.clone();
+
+    let srv = server::test_server(move || {
+        let refused = refused2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            let refused = refused.clone();
+            async move {
+                // servers must not send PINGREQ
+                let res = con.sink().ping().await;
+                refused.store(res == Err(SendPacketError::NotAllowed), Relaxed);
+                Ok::<_, ()>(con.ack(St, false))
+            }
+        })
+        .publish(|_| ok::<_, ()>(()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    assert!(refused.load(Relaxed));
+}
+
+#[ntex::test]
+async fn test_session_spawn() {
+    struct Guard(Arc<AtomicBool>);
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            self.0.store(true, Relaxed);
+        }
+    }
+
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let ticks2 = ticks.clone();
+    let dropped = Arc::new(AtomicBool::new(false));
+    let dropped2 = dropped.clone();
+
+    let srv = server::test_server(move || {
+        let ticks = ticks2.clone();
+        let dropped = dropped2.clone();
+        MqttServer::new(handshake)
+            .publish(fn_factory_with_config(move |session: Session<St>| {
+                let ticks = ticks.clone();
+                let guard = Guard(dropped.clone());
+                session.spawn(async move {
+                    let _guard = guard;
+                    loop {
+                        ticks.fetch_add(1, Relaxed);
+                        sleep(Duration::from_millis(10)).await;
+                    }
+                });
+                assert_eq!(session.tasks(), 1);
+                ok::<_, ()>(fn_service(|_: Publish| ok::<_, ()>(())))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    sleep(Duration::from_millis(50)).await;
+    assert!(ticks.load(Relaxed) > 0);
+    assert!(!dropped.load(Relaxed));
+
+    // task is cancelled with connection
+    framed.send(codec::Packet::Disconnect).await.unwrap();
+    drop(framed);
+    sleep(Duration::from_millis(50)).await;
+    assert!(dropped.load(Relaxed));
+    let count = ticks.load(Relaxed);
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(ticks.load(Relaxed), count);
+}
+
+#[ntex::test]
+async fn test_session_actor() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(fn_factory_with_config(|session: Session<St>| {
+                let addr = session.start_actor(|session: Session<St>, msg: Bytes| {
+                    session
+                        .sink()
+                        .publish(ByteString::from_static("reply"), msg)
+                        .send_at_most_once()
+                        .unwrap();
+                    ready(())
+                });
+                ok::<_, ()>(fn_service(move |p: Publish| {
+                    // address could be used from other threads
+                    let addr = addr.clone();
+                    let payload = p.payload().clone();
+                    std::thread::spawn(move || addr.send(payload).unwrap()).join().unwrap();
+                    ok::<_, ()>(())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let publish = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtMostOnce,
+        topic: ByteString::from_static("cmd"),
+        packet_id: None,
+        payload: Bytes::from_static(b"data"),
+    };
+    framed.send(publish.into()).await.unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => {
+            assert_eq!(pkt.topic, "reply");
+            assert_eq!(pkt.payload, Bytes::from_static(b"data"));
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+}
+
+#[ntex::test]
+async fn test_control_channel() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| ok::<_, ()>(()))
+            .control_channel(|_: &Session<St>, mut rx| {
+                ntex::rt::spawn(async move {
+                    while let Some(req) = rx.next().await {
+                        let (msg, tx) = req.into_parts();
+                        match msg {
+                            ControlMessage::Subscribe(mut msg) => {
+                                for mut sub in &mut msg {
+                                    sub.confirm(sub.qos());
+                                }
+                                tx.send(msg.ack());
+                            }
+                            // unsubscribe is dropped without response
+                            ControlMessage::Unsubscribe(_) => (),
+                            msg => tx.send(msg.disconnect()),
+                        }
+                    }
+                });
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(ByteString::from_static("topic1"), codec::QoS::AtLeastOnce)],
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::Packet::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)],
+        }
+    );
+
+    framed
+        .send(codec::Packet::Unsubscribe {
+            packet_id: NonZeroU16::new(2).unwrap(),
+            topic_filters: vec![ByteString::from_static("topic1")],
+        })
+        .await
+        .unwrap();
+    assert!(framed.next().await.is_none());
+}
+
+/// tls acceptor with self-signed certificate
+fn tls_acceptor() -> openssl::ssl::SslAcceptorBuilder {
+    use openssl::ssl::{SslAcceptor, SslMethod};
+    use openssl::{asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509};
+
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = x509::X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "example.com").unwrap();
+    let name = name.build();
+    let mut builder = x509::X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    acceptor.set_certificate(&builder.build()).unwrap();
+    acceptor
+}
+
+#[ntex::test]
+async fn test_decode_budget() {
+    let stats = ntex_mqtt::DecodeStats::new();
+    let st = stats.clone();
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .decode_budget(Duration::from_nanos(1), 2)
+            .decode_stats(st.clone())
+            .publish(|_| ok::<_, ()>(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let publish = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtMostOnce,
+        topic: ByteString::from_static("a/b/c/d/e/f"),
+        packet_id: None,
+        payload: Bytes::from_static(b"data"),
+    };
+    framed.send(publish.into()).await.unwrap();
+    assert!(framed.next().await.is_none());
+
+    assert_eq!(stats.packets(), 2);
+    assert_eq!(stats.size_histogram()[0].1, 1);
+    assert_eq!(stats.slow_packets(), 2);
+    assert_eq!(stats.disconnects(), 1);
+}
+
+#[ntex::test]
+async fn test_sni_router() -> std::io::Result<()> {
+    use ntex::{pipeline_factory, rt::net::TcpStream, server::openssl::Acceptor};
+    use openssl::ssl::{NameType, SslConnector, SslMethod, SslVerifyMode};
+    use tokio_openssl::SslStream;
+
+    let acceptor = tls_acceptor().build();
+
+    let srv = server::test_server(move || {
+        pipeline_factory(Acceptor::new(acceptor.clone()))
+            .map_err(|_| MqttError::Service(()))
+            .and_then(
+                SniRouter::new(|io: &SslStream<TcpStream>| {
+                    io.ssl().servername(NameType::HOST_NAME).map(|name| name.to_string())
+                })
+                .route(
+                    "tenant-a.example.com",
+                    MqttServer::new(|hnd: Handshake<_>| ok::<_, ()>(hnd.ack(St, false)))
+                        .publish(|_t| ok(()))
+                        .finish(),
+                )
+                .route(
+                    "*.example.com",
+                    MqttServer::new(|hnd: Handshake<_>| {
+                        ok::<_, ()>(hnd.not_authorized::<St>())
+                    })
+                    .publish(|_t| ok(()))
+                    .finish(),
+                ),
+            )
+    });
+
+    async fn connect(
+        srv: &server::TestServer,
+        hostname: &str,
+    ) -> Framed<SslStream<TcpStream>, codec::Codec> {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let ssl = connector.build().configure().unwrap().into_ssl(hostname).unwrap();
+        let mut io = SslStream::new(ssl, srv.connect().await.unwrap()).unwrap();
+        std::pin::Pin::new(&mut io).connect().await.unwrap();
+
+        let mut framed = Framed::new(io, codec::Codec::default());
+        framed
+            .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+            .await
+            .unwrap();
+        framed
+    }
+
+    let mut framed = connect(&srv, "TENANT-A.example.com").await;
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted
+        }
+    );
+    drop(framed);
+
+    let mut framed = connect(&srv, "tenant-b.example.com").await;
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::NotAuthorized
+        }
+    );
+    drop(framed);
+
+    // unknown hostname, connection is closed
+    let mut framed = connect(&srv, "example.org").await;
+    assert!(!std::matches!(framed.next().await, Some(Ok(_))));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_alpn_router() -> std::io::Result<()> {
+    use ntex::{pipeline_factory, rt::net::TcpStream, server::openssl::Acceptor};
+    use openssl::ssl::{select_next_proto, AlpnError, SslConnector, SslMethod, SslVerifyMode};
+    use tokio_openssl::SslStream;
+
+    let mut builder = tls_acceptor();
+    builder.set_alpn_select_callback(|_, client| {
+        select_next_proto(b"\x04mqtt\x08http/1.1", client).ok_or(AlpnError::NOACK)
+    });
+    let acceptor = builder.build();
+
+    let websocket = Arc::new(AtomicBool::new(false));
+    let websocket2 = websocket.clone();
+    let srv = server::test_server(move || {
+        let websocket = websocket2.clone();
+        pipeline_factory(Acceptor::new(acceptor.clone()))
+            .map_err(|_| MqttError::Service(()))
+            .and_then(
+                AlpnRouter::new(
+                    |io: &SslStream<TcpStream>| {
+                        io.ssl().selected_alpn_protocol().map(|p| p.to_vec())
+                    },
+                    MqttServer::new(handshake).publish(|_t| ok(())).finish(),
+                )
+                .websocket(fn_service(move |_: SslStream<TcpStream>| {
+                    websocket.store(true, Relaxed);
+                    ok::<_, MqttError<()>>(())
+                })),
+            )
+    });
+
+    async fn connect(srv: &server::TestServer, protos: &[u8]) -> SslStream<TcpStream> {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        connector.set_alpn_protos(protos).unwrap();
+        let ssl = connector.build().configure().unwrap().into_ssl("example.com").unwrap();
+        let mut io = SslStream::new(ssl, srv.connect().await.unwrap()).unwrap();
+        std::pin::Pin::new(&mut io).connect().await.unwrap();
+        io
+    }
+
+    // raw mqtt
+    let io = connect(&srv, b"\x04mqtt").await;
+    assert_eq!(io.ssl().selected_alpn_protocol(), Some(ntex_mqtt::ALPN_MQTT));
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted
+        }
+    );
+    drop(framed);
+    assert!(!websocket.load(Relaxed));
+
+    // websocket
+    let io = connect(&srv, b"\x08http/1.1").await;
+    assert_eq!(io.ssl().selected_alpn_protocol(), Some(ntex_mqtt::ALPN_HTTP11));
+    // adapter drops connection
+    let mut framed = Framed::new(io, codec::Codec::default());
+    assert!(!std::matches!(framed.next().await, Some(Ok(_))));
+    assert!(websocket.load(Relaxed));
+    Ok(())
+}
+
+#[cfg(feature = "quic")]
+#[ntex::test]
+async fn test_quic() -> std::io::Result<()> {
+    use ntex_mqtt::quic::{self, quinn};
+    use openssl::{asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509};
+
+    // self-signed certificate
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = x509::X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+    let mut builder = x509::X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    let san = x509::extension::SubjectAlternativeName::new()
+        .dns("localhost")
+        .build(&builder.x509v3_context(None, None))
+        .unwrap();
+    builder.append_extension(san).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    let cert = quinn::Certificate::from_der(&builder.build().to_der().unwrap()).unwrap();
+    let key = quinn::PrivateKey::from_der(&key.private_key_to_der().unwrap()).unwrap();
+
+    // server
+    let mut server_config = quinn::ServerConfigBuilder::default();
+    server_config
+        .certificate(quinn::CertificateChain::from_certs(vec![cert.clone()]), key)
+        .unwrap();
+    let mut endpoint = quinn::Endpoint::builder();
+    endpoint.listen(server_config.build());
+    let (server, incoming) = endpoint.bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = server.local_addr()?;
+
+    let publishes = Arc::new(Mutex::new(Vec::new()));
+    let publishes2 = publishes.clone();
+    ntex::rt::spawn(quic::serve(
+        incoming,
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                publishes2.lock().unwrap().push(p.payload().clone());
+                ok::<_, ()>(())
+            })
+            .finish(),
+    ));
+
+    // client
+    let mut client_config = quinn::ClientConfigBuilder::default();
+    client_config.add_certificate_authority(cert).unwrap();
+    let mut endpoint = quinn::Endpoint::builder();
+    endpoint.default_client_config(client_config.build());
+    let (endpoint, _) = endpoint.bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+    let client = client::MqttConnector::new(format!("localhost:{}", addr.port()))
+        .client_id("user")
+        .quic(endpoint)
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish(ByteString::from_static("test"), Bytes::from_static(b"data"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    assert_eq!(*publishes.lock().unwrap(), vec![Bytes::from_static(b"data")]);
+
+    sink.close();
+    server.close(0u32.into(), b"");
+    Ok(())
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_tokio_client() {
+    use ntex_mqtt::v3::tokio::MqttConnector;
+
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(fn_factory_with_config(|session: Session<St>| {
+                ok::<_, ()>(fn_service(move |p: Publish| {
+                    // echo publish back to client
+                    session
+                        .sink()
+                        .publish(ByteString::from_static("echo"), p.payload().clone())
+                        .send_at_most_once()
+                        .unwrap();
+                    ok::<_, ()>(())
+                }))
+            }))
+            .control(|msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.confirm(codec::QoS::AtLeastOnce);
+                    }
+                    ok::<_, ()>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let (sink, mut publishes) = MqttConnector::new(srv.addr())
+        .client_id("tokio")
+        .keep_alive(10)
+        .connect()
+        .await
+        .unwrap();
+
+    let status = sink
+        .subscribe(vec![(ByteString::from_static("echo"), codec::QoS::AtLeastOnce)])
+        .await
+        .unwrap();
+    assert_eq!(status, vec![codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)]);
+
+    sink.publish("test", Bytes::from_static(b"data"), codec::QoS::AtLeastOnce).await.unwrap();
+    let publish = publishes.recv().await.unwrap();
+    assert_eq!(publish.topic, "echo");
+    assert_eq!(publish.payload, Bytes::from_static(b"data"));
+
+    sink.close();
+    assert!(publishes.recv().await.is_none());
+    assert!(!sink.is_open());
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_tokio_client_max_send() {
+    use ntex_mqtt::v3::tokio::MqttConnector;
+
+    let inflight = Arc::new(AtomicUsize::new(0));
+    let max_inflight = Arc::new(AtomicUsize::new(0));
+    let (inflight2, max_inflight2) = (inflight.clone(), max_inflight.clone());
+
+    let srv = server::test_server(move || {
+        let inflight = inflight2.clone();
+        let max_inflight = max_inflight2.clone();
+        MqttServer::new(handshake)
+            .publish(move |_| {
+                let inflight = inflight.clone();
+                let max_inflight = max_inflight.clone();
+                async move {
+                    let count = inflight.fetch_add(1, Relaxed) + 1;
+                    max_inflight.fetch_max(count, Relaxed);
+                    sleep(Duration::from_millis(50)).await;
+                    inflight.fetch_sub(1, Relaxed);
+                    Ok::<_, ()>(())
+                }
+            })
+            .finish()
+    });
+
+    let (sink, _publishes) =
+        MqttConnector::new(srv.addr()).client_id("tokio").max_send(1).connect().await.unwrap();
+
+    let results =
+        futures::future::join_all((0..3).map(|_| {
+            sink.publish("test", Bytes::from_static(b"data"), codec::QoS::AtLeastOnce)
+        }))
+        .await;
+    assert!(results.iter().all(|res| res.is_ok()));
+    assert_eq!(max_inflight.load(Relaxed), 1);
+}
+
+#[ntex::test]
+async fn test_conformance() {
+    let srv = MqttServer::new(|con: Handshake<_>| {
+        let keep_alive = Duration::from_secs(con.packet().keep_alive as u64);
+        ok::<_, ()>(con.ack(St, false).idle_timeout(keep_alive))
+    })
+    .publish(|_| ok(()))
+    .finish();
+    let report = ntex_mqtt::conformance::v3::check(
+        srv,
+        codec::Connect::default().client_id("conformance"),
+    )
+    .await;
+
+    // server does not validate these packets, handlers are responsible
+    let failed: Vec<_> = report.failed().map(|o| o.rule).collect();
+    assert_eq!(
+        failed,
+        vec!["MQTT-3.1.0-2", "MQTT-3.3.2-2", "MQTT-3.8.3-3", "MQTT-3.10.3-2"],
+        "{}",
+        report
+    );
+    assert!(report.get("MQTT-3.1.2-24").unwrap().is_passed());
+}
@@ -1,10 +1,11 @@
-use std::convert::TryFrom;
+use std::{convert::TryFrom, time::Duration, time::Instant};
 
-use futures::future::ok;
+use futures::{future::ok, SinkExt, StreamExt};
+use ntex::codec::Framed;
 use ntex::server;
 use ntex::util::{ByteString, Bytes};
 
-use ntex_mqtt::{v3, v5, MqttServer};
+use ntex_mqtt::{v3, v5, MqttServer, Timer};
 
 struct St;
 
@@ -61,3 +62,76 @@ async fn test_simple() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_shared_timer() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        let timer = Timer::with(Duration::from_millis(50));
+        MqttServer::new()
+            .v3(v3::MqttServer::new(|con: v3::Handshake<_>| {
+                ok::<_, TestError>(con.ack(St, false).idle_timeout(Duration::from_secs(1)))
+            })
+            .timer(timer.clone())
+            .publish(|_| ok::<_, TestError>(())))
+            .v5(v5::MqttServer::new(|con: v5::Handshake<_>| {
+                ok::<_, TestError>(con.ack(St).keep_alive(Duration::from_secs(1)))
+            })
+            .timer(timer)
+            .publish(|p: v5::Publish| ok::<_, TestError>(p.ack())))
+    });
+
+    // both servers drop idle connections with shared timer resolution
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, v3::codec::Codec::default());
+    framed
+        .send(v3::codec::Packet::Connect(v3::codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    let start = Instant::now();
+    assert!(framed.next().await.is_none());
+    assert!(start.elapsed() < Duration::from_millis(1500));
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, v5::codec::Codec::default());
+    framed
+        .send(v5::codec::Packet::Connect(v5::codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    let start = Instant::now();
+    loop {
+        match framed.next().await {
+            Some(Ok(v5::codec::Packet::Disconnect(_))) => continue,
+            None => break,
+            pkt => panic!("Unexpected packet: {:?}", pkt),
+        }
+    }
+    assert!(start.elapsed() < Duration::from_millis(1500));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_timeout() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new()
+            .handshake_timeout(Duration::from_millis(200))
+            .v3(v3::MqttServer::new(|con: v3::Handshake<_>| {
+                ok::<_, TestError>(con.ack(St, false))
+            })
+            .publish(|_| ok::<_, TestError>(())))
+            .v5(v5::MqttServer::new(|con: v5::Handshake<_>| ok::<_, TestError>(con.ack(St)))
+                .publish(|p: v5::Publish| ok::<_, TestError>(p.ack())))
+    });
+
+    // connection without connect packet gets dropped
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, v5::codec::Codec::default());
+    let start = Instant::now();
+    assert!(framed.next().await.is_none());
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    assert!(start.elapsed() < Duration::from_millis(1000));
+
+    Ok(())
+}
@@ -1,16 +1,19 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
-use std::{convert::TryFrom, num::NonZeroU16, time::Duration};
+use std::num::{NonZeroU16, NonZeroU32};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::{cell::RefCell, convert::TryFrom, time::Duration};
 
-use futures::{future::ok, FutureExt, SinkExt, StreamExt};
+use futures::{future::ok, future::ready, FutureExt, SinkExt, StreamExt};
 use ntex::codec::Framed;
 use ntex::rt::time::sleep;
-use ntex::server;
 use ntex::util::{poll_fn, ByteString, Bytes};
+use ntex::{fn_factory_with_config, fn_service, server, ServiceFactory};
 
 use ntex_mqtt::v5::{
-    client, codec, error, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish,
-    PublishAck, Session,
+    client, codec, error, ControlMessage, HandlerResponse, Handshake, HandshakeAck, MqttServer,
+    Publish, PublishAck, PublishFailure, Router, Session, Subscriptions,
 };
+use ntex_mqtt::{testing, ConfigHandle, RetainPolicy, SessionEnd, ShutdownStatus};
 
 struct St;
 
@@ -367,116 +370,356 @@ async fn test_dups() {
 }
 
 #[ntex::test]
-async fn test_max_receive() {
+async fn test_subscribe_retransmit() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+
     let srv = server::test_server(move || {
+        let calls = calls2.clone();
         MqttServer::new(handshake)
-            .receive_max(1)
-            .max_qos(codec::QoS::AtLeastOnce)
-            .publish(|p: Publish| {
-                sleep(Duration::from_millis(10000)).map(move |_| Ok::<_, TestError>(p.ack()))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| {
+                let calls = calls.clone();
+                async move {
+                    match msg {
+                        ControlMessage::Subscribe(mut msg) => {
+                            calls.fetch_add(1, Relaxed);
+                            sleep(Duration::from_millis(100)).await;
+                            for mut sub in &mut msg {
+                                sub.confirm(sub.qos());
+                            }
+                            Ok::<_, TestError>(msg.ack())
+                        }
+                        _ => Ok(msg.disconnect()),
+                    }
+                }
             })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let subscribe = codec::Subscribe {
+        id: None,
+        packet_id: NonZeroU16::new(1).unwrap(),
+        user_properties: Default::default(),
+        topic_filters: vec![(
+            ByteString::from("topic1"),
+            codec::SubscriptionOptions::new(codec::QoS::AtLeastOnce),
+        )],
+    };
+    framed.send(subscribe.clone().into()).await.unwrap();
+    framed.send(subscribe.into()).await.unwrap();
+
+    // both packets are acknowledged with the same ack
+    let ack: codec::Packet = codec::SubscribeAck {
+        packet_id: NonZeroU16::new(1).unwrap(),
+        properties: Default::default(),
+        reason_string: None,
+        status: vec![codec::SubscribeAckReason::GrantedQos1],
+    }
+    .into();
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack);
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack);
+    assert_eq!(calls.load(Relaxed), 1);
+}
+
+#[ntex::test]
+async fn test_subscription_limits() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+
+    let srv = server::test_server(move || {
+        let calls = calls2.clone();
+        MqttServer::new(handshake)
+            .max_topic_filters(2)
+            .max_subscriptions(2)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
             .control(move |msg| match msg {
-                ControlMessage::ProtocolError(msg) => ok::<_, TestError>(msg.ack()),
+                ControlMessage::Subscribe(mut msg) => {
+                    calls.fetch_add(1, Relaxed);
+                    for mut sub in &mut msg {
+                        sub.confirm(sub.qos());
+                    }
+                    ok::<_, TestError>(msg.ack())
+                }
                 _ => ok(msg.disconnect()),
             })
             .finish()
     });
+
     let io = srv.connect().await.unwrap();
     let mut framed = Framed::new(io, codec::Codec::default());
-
     framed
         .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
         .await
         .unwrap();
-    let ack = framed.next().await.unwrap().unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let subscribe = |id, topics: &[&'static str]| -> codec::Packet {
+        codec::Subscribe {
+            id: None,
+            packet_id: NonZeroU16::new(id).unwrap(),
+            user_properties: Default::default(),
+            topic_filters: topics
+                .iter()
+                .map(|t| {
+                    (
+                        ByteString::from_static(t),
+                        codec::SubscriptionOptions::new(codec::QoS::AtLeastOnce),
+                    )
+                })
+                .collect(),
+        }
+        .into()
+    };
+    let ack = |id, status: Vec<codec::SubscribeAckReason>| -> codec::Packet {
+        codec::SubscribeAck {
+            packet_id: NonZeroU16::new(id).unwrap(),
+            properties: Default::default(),
+            reason_string: None,
+            status,
+        }
+        .into()
+    };
+    let quota = codec::SubscribeAckReason::QuotaExceeded;
+
+    // too many topic filters in one packet
+    framed.send(subscribe(1, &["t1", "t2", "t3"])).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack(1, vec![quota; 3]));
+    assert_eq!(calls.load(Relaxed), 0);
+
+    framed.send(subscribe(2, &["t1", "t2"])).await.unwrap();
     assert_eq!(
-        ack,
-        codec::Packet::ConnectAck(codec::ConnectAck {
-            receive_max: Some(NonZeroU16::new(1).unwrap()),
-            max_qos: Some(codec::QoS::AtLeastOnce),
-            reason_code: codec::ConnectAckReason::Success,
-            topic_alias_max: 32,
-            ..Default::default()
-        })
+        framed.next().await.unwrap().unwrap(),
+        ack(2, vec![codec::SubscribeAckReason::GrantedQos1; 2])
     );
 
-    framed
-        .send(
-            codec::Publish { packet_id: Some(NonZeroU16::new(1).unwrap()), ..pkt_publish() }
-                .into(),
-        )
-        .await
-        .unwrap();
-    framed
-        .send(
-            codec::Publish { packet_id: Some(NonZeroU16::new(2).unwrap()), ..pkt_publish() }
-                .into(),
-        )
-        .await
-        .unwrap();
-    let pkt = framed.next().await.unwrap().unwrap();
+    // max subscriptions per session, resubscribe is allowed
+    framed.send(subscribe(3, &["t1", "t3"])).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack(3, vec![quota; 2]));
+    framed.send(subscribe(4, &["t1"])).await.unwrap();
     assert_eq!(
-        pkt,
-        codec::Packet::Disconnect(codec::Disconnect {
-            reason_code: codec::DisconnectReasonCode::ReceiveMaximumExceeded,
-            session_expiry_interval_secs: None,
-            server_reference: None,
-            reason_string: None,
-            user_properties: Default::default(),
-        })
+        framed.next().await.unwrap().unwrap(),
+        ack(4, vec![codec::SubscribeAckReason::GrantedQos1])
     );
+    assert_eq!(calls.load(Relaxed), 2);
 }
 
 #[ntex::test]
-async fn test_keepalive() {
-    let ka = Arc::new(AtomicBool::new(false));
-    let ka2 = ka.clone();
+async fn test_capabilities() {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
 
     let srv = server::test_server(move || {
-        let ka = ka2.clone();
-
-        MqttServer::new(|con: Handshake<_>| async move { Ok(con.ack(St).keep_alive(1)) })
-            .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
+        let topics = topics2.clone();
+        MqttServer::new(handshake)
+            .retain_available(false)
+            .wildcard_subscription_available(false)
+            .subscription_identifiers_available(false)
+            .shared_subscription_available(false)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
             .control(move |msg| match msg {
-                ControlMessage::ProtocolError(msg) => {
-                    if let &error::ProtocolError::KeepAliveTimeout = msg.get_ref() {
-                        ka.store(true, Relaxed);
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        topics.lock().unwrap().push(sub.topic().clone());
+                        sub.confirm(sub.qos());
                     }
                     ok::<_, TestError>(msg.ack())
                 }
+                ControlMessage::ProtocolError(msg) => ok::<_, TestError>(msg.ack()),
                 _ => ok(msg.disconnect()),
             })
             .finish()
     });
 
-    // connect to server
-    let client =
-        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let ack = match framed.next().await.unwrap().unwrap() {
+        codec::Packet::ConnectAck(ack) => ack,
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    };
+    assert_eq!(ack.retain_available, Some(false));
+    assert_eq!(ack.wildcard_subscription_available, Some(false));
+    assert_eq!(ack.subscription_identifiers_available, Some(false));
+    assert_eq!(ack.shared_subscription_available, Some(false));
+
+    let subscribe = |id, sub_id, topics: &[&'static str]| -> codec::Packet {
+        codec::Subscribe {
+            id: sub_id,
+            packet_id: NonZeroU16::new(id).unwrap(),
+            user_properties: Default::default(),
+            topic_filters: topics
+                .iter()
+                .map(|t| {
+                    (
+                        ByteString::from_static(t),
+                        codec::SubscriptionOptions::new(codec::QoS::AtLeastOnce),
+                    )
+                })
+                .collect(),
+        }
+        .into()
+    };
+    let ack = |id, status: Vec<codec::SubscribeAckReason>| -> codec::Packet {
+        codec::SubscribeAck {
+            packet_id: NonZeroU16::new(id).unwrap(),
+            properties: Default::default(),
+            reason_string: None,
+            status,
+        }
+        .into()
+    };
 
-    let sink = client.sink();
+    // unsupported topic filters are rejected, rest is passed to control service
+    framed.send(subscribe(1, None, &["a/+", "a/b", "$share/g/a", "a/#"])).await.unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        ack(
+            1,
+            vec![
+                codec::SubscribeAckReason::WildcardSubscriptionsNotSupported,
+                codec::SubscribeAckReason::GrantedQos1,
+                codec::SubscribeAckReason::SharedSubsriptionNotSupported,
+                codec::SubscribeAckReason::WildcardSubscriptionsNotSupported,
+            ]
+        )
+    );
+    assert_eq!(*topics.lock().unwrap(), vec!["a/b"]);
 
-    ntex::rt::spawn(client.start_default());
+    framed.send(subscribe(2, NonZeroU32::new(1), &["a/c"])).await.unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        ack(2, vec![codec::SubscribeAckReason::SubscriptionIdentifiersNotSupported])
+    );
+    assert_eq!(topics.lock().unwrap().len(), 1);
 
-    assert!(sink.is_open());
-    sleep(Duration::from_millis(2500)).await;
-    assert!(!sink.is_open());
-    assert!(ka.load(Relaxed));
+    // retained publish closes connection
+    let publish = codec::Publish {
+        dup: false,
+        retain: true,
+        qos: codec::QoS::AtMostOnce,
+        topic: ByteString::from_static("a/b"),
+        packet_id: None,
+        payload: Bytes::new(),
+        properties: Default::default(),
+    };
+    framed.send(publish.into()).await.unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::Disconnect(pkt) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::RetainNotSupported)
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
 }
 
 #[ntex::test]
-async fn test_keepalive2() {
-    let ka = Arc::new(AtomicBool::new(false));
-    let ka2 = ka.clone();
+async fn test_retain_policy() {
+    let retained = Arc::new(AtomicBool::new(true));
+    let retained2 = retained.clone();
 
     let srv = server::test_server(move || {
-        let ka = ka2.clone();
+        let retained = retained2.clone();
+        MqttServer::new(handshake)
+            .retain_policy(RetainPolicy::Strip)
+            .publish(move |p: Publish| {
+                retained.store(p.retain(), Relaxed);
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::ConnectAck(ack) => assert_eq!(ack.retain_available, None),
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+    framed
+        .send(
+            codec::Publish {
+                dup: false,
+                retain: true,
+                qos: codec::QoS::AtLeastOnce,
+                topic: ByteString::from_static("test"),
+                packet_id: NonZeroU16::new(1),
+                payload: Bytes::new(),
+                properties: Default::default(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    assert!(!retained.load(Relaxed));
+
+    // reject policy is advertised
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .retain_policy(RetainPolicy::Reject)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::ConnectAck(ack) => assert_eq!(ack.retain_available, Some(false)),
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+}
 
-        MqttServer::new(|con: Handshake<_>| async move { Ok(con.ack(St).keep_alive(1)) })
-            .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
+#[ntex::test]
+async fn test_tenant_isolation() {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+
+    let srv = server::test_server(move || {
+        let topics = topics2.clone();
+        let topics2 = topics2.clone();
+        MqttServer::new(handshake)
+            .tenant_isolation(|session: &Session<St>| Some(session.client_id().clone()))
+            .publish(fn_factory_with_config(move |session: Session<St>| {
+                let topics = topics.clone();
+                ok::<_, TestError>(fn_service(move |p: Publish| {
+                    topics.lock().unwrap().push(p.publish_topic().to_string());
+                    session
+                        .sink()
+                        .publish(ByteString::from_static("acme/out"), Bytes::new())
+                        .send_at_most_once()
+                        .unwrap();
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
             .control(move |msg| match msg {
-                ControlMessage::ProtocolError(msg) => {
-                    if let &error::ProtocolError::KeepAliveTimeout = msg.get_ref() {
-                        ka.store(true, Relaxed);
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        topics2.lock().unwrap().push(sub.topic().to_string());
+                        sub.confirm(sub.qos());
+                    }
+                    ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Unsubscribe(msg) => {
+                    for topic in msg.iter() {
+                        topics2.lock().unwrap().push(topic.to_string());
                     }
                     ok::<_, TestError>(msg.ack())
                 }
@@ -485,258 +728,2399 @@ async fn test_keepalive2() {
             .finish()
     });
 
-    // connect to server
-    let client =
-        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("acme")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
 
-    let sink = client.sink();
+    // reserved topics are outside of namespace
+    framed
+        .send(
+            codec::Subscribe {
+                id: None,
+                packet_id: NonZeroU16::new(1).unwrap(),
+                user_properties: Default::default(),
+                topic_filters: ["a/#", "$SYS/#", "$share/g/b"]
+                    .iter()
+                    .map(|t| {
+                        (
+                            ByteString::from_static(t),
+                            codec::SubscriptionOptions::new(codec::QoS::AtLeastOnce),
+                        )
+                    })
+                    .collect(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            properties: Default::default(),
+            reason_string: None,
+            status: vec![
+                codec::SubscribeAckReason::GrantedQos1,
+                codec::SubscribeAckReason::NotAuthorized,
+                codec::SubscribeAckReason::GrantedQos1,
+            ],
+        }
+        .into()
+    );
 
-    ntex::rt::spawn(client.start_default());
+    // namespace is removed from outbound publishes
+    let mut publish = pkt_publish();
+    publish.topic = ByteString::from_static("a/b");
+    framed.send(publish.into()).await.unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => assert_eq!(pkt.topic, "out"),
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+    let _ = framed.next().await.unwrap().unwrap();
 
-    assert!(sink.is_open());
-    let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
-    assert!(res.is_ok());
-    sleep(Duration::from_millis(1200)).await;
-    let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
-    assert!(res.is_ok());
-    sleep(Duration::from_millis(2500)).await;
+    let mut publish = pkt_publish();
+    publish.topic = ByteString::from_static("$SYS/b");
+    publish.packet_id = NonZeroU16::new(2);
+    framed.send(publish.into()).await.unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::PublishAck(ack) => {
+            assert_eq!(ack.reason_code, codec::PublishAckReason::NotAuthorized)
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
 
-    assert!(!sink.is_open());
-    assert!(ka.load(Relaxed));
-}
+    // reserved topics are not unsubscribed
+    framed
+        .send(
+            codec::Unsubscribe {
+                packet_id: NonZeroU16::new(3).unwrap(),
+                user_properties: Default::default(),
+                topic_filters: vec![ByteString::from("$SYS/#"), ByteString::from("a/#")],
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::UnsubscribeAck {
+            packet_id: NonZeroU16::new(3).unwrap(),
+            properties: Default::default(),
+            reason_string: None,
+            status: vec![
+                codec::UnsubscribeAckReason::NotAuthorized,
+                codec::UnsubscribeAckReason::Success,
+            ],
+        }
+        .into()
+    );
 
-#[ntex::test]
-async fn test_sink_encoder_error_pub_qos1() {
-    let srv = server::test_server(move || {
-        MqttServer::new(|con: Handshake<_>| async move {
-            let builder = con.sink().publish("test", Bytes::new()).properties(|props| {
-                props.user_properties.push((
-                    "ssssssssssssssssssssssssssssssssssss".into(),
-                    "ssssssssssssssssssssssssssssssssssss".into(),
-                ));
-            });
-            ntex::rt::spawn(async move {
-                let res = builder.send_at_least_once().await;
-                assert_eq!(
-                    res,
-                    Err(error::PublishQos1Error::Encode(error::EncodeError::InvalidLength))
-                );
-            });
-            Ok(con.ack(St))
+    framed
+        .send(
+            codec::Unsubscribe {
+                packet_id: NonZeroU16::new(4).unwrap(),
+                user_properties: Default::default(),
+                topic_filters: vec![ByteString::from("$SYS/#")],
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::UnsubscribeAck {
+            packet_id: NonZeroU16::new(4).unwrap(),
+            properties: Default::default(),
+            reason_string: None,
+            status: vec![codec::UnsubscribeAckReason::NotAuthorized],
+        }
+        .into()
+    );
+
+    assert_eq!(
+        &*topics.lock().unwrap(),
+        &["acme/a/#", "$share/g/acme/b", "acme/a/b", "acme/a/#"]
+    );
+}
+
+#[ntex::test]
+async fn test_max_qos() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_qos(codec::QoS::AtLeastOnce)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.confirm(sub.qos());
+                    }
+                    ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::ProtocolError(msg) => ok::<_, TestError>(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.max_qos, Some(codec::QoS::AtLeastOnce))
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+
+    // granted qos is downgraded
+    framed
+        .send(
+            codec::Subscribe {
+                id: None,
+                packet_id: NonZeroU16::new(1).unwrap(),
+                user_properties: Default::default(),
+                topic_filters: vec![(
+                    ByteString::from_static("topic1"),
+                    codec::SubscriptionOptions::new(codec::QoS::ExactlyOnce),
+                )],
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::SubscribeAck(ack) => {
+            assert_eq!(ack.status, vec![codec::SubscribeAckReason::GrantedQos1])
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+
+    // publish above max qos closes connection
+    framed
+        .send(
+            codec::Publish {
+                dup: false,
+                retain: false,
+                qos: codec::QoS::ExactlyOnce,
+                topic: ByteString::from_static("topic1"),
+                packet_id: NonZeroU16::new(1),
+                payload: Bytes::new(),
+                properties: Default::default(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::Disconnect(pkt) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::QosNotSupported)
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+}
+
+#[ntex::test]
+async fn test_client_id_policy() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .client_id_policy(ntex_mqtt::client_id::strict)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("device-01")))
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::ClientIdentifierNotValid)
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("device01")))
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::Success)
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+}
+
+#[ntex::test]
+async fn test_max_receive() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .receive_max(1)
+            .max_qos(codec::QoS::AtLeastOnce)
+            .publish(|p: Publish| {
+                sleep(Duration::from_millis(10000)).map(move |_| Ok::<_, TestError>(p.ack()))
+            })
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => ok::<_, TestError>(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        ack,
+        codec::Packet::ConnectAck(codec::ConnectAck {
+            receive_max: Some(NonZeroU16::new(1).unwrap()),
+            max_qos: Some(codec::QoS::AtLeastOnce),
+            reason_code: codec::ConnectAckReason::Success,
+            topic_alias_max: 32,
+            ..Default::default()
         })
-        .publish(|p: Publish| {
-            sleep(Duration::from_millis(50)).map(move |_| Ok::<_, TestError>(p.ack()))
+    );
+
+    framed
+        .send(
+            codec::Publish { packet_id: Some(NonZeroU16::new(1).unwrap()), ..pkt_publish() }
+                .into(),
+        )
+        .await
+        .unwrap();
+    framed
+        .send(
+            codec::Publish { packet_id: Some(NonZeroU16::new(2).unwrap()), ..pkt_publish() }
+                .into(),
+        )
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::ReceiveMaximumExceeded,
+            session_expiry_interval_secs: None,
+            server_reference: None,
+            reason_string: None,
+            user_properties: Default::default(),
+        })
+    );
+}
+
+#[ntex::test]
+async fn test_auto_ack() {
+    let handled = Arc::new(AtomicUsize::new(0));
+    let handled2 = handled.clone();
+
+    let srv = server::test_server(move || {
+        let handled = handled2.clone();
+        MqttServer::new(handshake)
+            .receive_max(1)
+            .auto_ack(true)
+            .publish(move |p: Publish| {
+                let handled = handled.clone();
+                sleep(Duration::from_millis(100)).map(move |_| {
+                    handled.fetch_add(1, Relaxed);
+                    Ok::<_, TestError>(p.ack())
+                })
+            })
+            .control(move |msg| match msg {
+                ControlMessage::Ping(_) => panic!("ping is answered by dispatcher"),
+                _ => ok::<_, TestError>(msg.disconnect()),
+            })
+            .finish()
+    });
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // auto-acked publishes do not count towards receive maximum
+    for id in 1..3 {
+        let packet_id = NonZeroU16::new(id).unwrap();
+        framed
+            .send(codec::Publish { packet_id: Some(packet_id), ..pkt_publish() }.into())
+            .await
+            .unwrap();
+        assert_eq!(
+            framed.next().await.unwrap().unwrap(),
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id,
+                reason_code: codec::PublishAckReason::Success,
+                properties: Default::default(),
+                reason_string: None,
+            })
+        );
+    }
+    assert_eq!(handled.load(Relaxed), 0);
+
+    framed.send(codec::Packet::PingRequest).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), codec::Packet::PingResponse);
+
+    sleep(Duration::from_millis(300)).await;
+    assert_eq!(handled.load(Relaxed), 2);
+}
+
+#[ntex::test]
+async fn test_auto_ack_blocked_lane() {
+    let started = Arc::new(AtomicUsize::new(0));
+    let started2 = started.clone();
+
+    let srv = server::test_server(move || {
+        let started = started2.clone();
+        MqttServer::new(handshake)
+            .auto_ack(true)
+            .ordered_lanes(1)
+            .publish(move |p: Publish| {
+                started.fetch_add(1, Relaxed);
+                let delay = if p.payload().as_ref() == b"1" { 200 } else { 0 };
+                sleep(Duration::from_millis(delay)).map(move |_| Ok::<_, TestError>(p.ack()))
+            })
+            .finish()
+    });
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    for (id, payload) in [(1, "1"), (2, "2")] {
+        let publish = codec::Publish {
+            packet_id: NonZeroU16::new(id),
+            payload: Bytes::from_static(payload.as_bytes()),
+            ..pkt_publish()
+        };
+        framed.send(publish.into()).await.unwrap();
+    }
+
+    // first publish is acked as soon as its handler is called, second publish
+    // waits in the lane and is not acked before its handler is called
+    for id in 1..3 {
+        assert_eq!(
+            framed.next().await.unwrap().unwrap(),
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                reason_code: codec::PublishAckReason::Success,
+                properties: Default::default(),
+                reason_string: None,
+            })
+        );
+        assert_eq!(started.load(Relaxed), id as usize);
+    }
+}
+
+#[ntex::test]
+async fn test_auto_ack_manual_resource() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .auto_ack(true)
+            .publish(
+                Router::new(
+                    fn_service(|p: Publish| {
+                        ok::<_, TestError>(
+                            p.ack().reason_code(codec::PublishAckReason::QuotaExceeded),
+                        )
+                    })
+                    .map_init_err(|_| TestError),
+                )
+                .resource_with_manual_ack(
+                    "persist",
+                    fn_service(|p: Publish| {
+                        sleep(Duration::from_millis(50)).map(move |_| {
+                            Ok::<_, TestError>(p.ack().reason_code(
+                                codec::PublishAckReason::ImplementationSpecificError,
+                            ))
+                        })
+                    }),
+                ),
+            )
+            .finish()
+    });
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // ack of manual resource is returned by handler
+    let packet_id = NonZeroU16::new(1).unwrap();
+    framed
+        .send(
+            codec::Publish {
+                topic: ByteString::from("persist"),
+                packet_id: Some(packet_id),
+                ..pkt_publish()
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(std::matches!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            reason_code: codec::PublishAckReason::ImplementationSpecificError,
+            ..
+        })
+    ));
+
+    // ack of other resources is ignored
+    framed.send(pkt_publish().into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(std::matches!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            reason_code: codec::PublishAckReason::Success,
+            ..
+        })
+    ));
+}
+
+#[ntex::test]
+async fn test_keepalive() {
+    let ka = Arc::new(AtomicBool::new(false));
+    let ka2 = ka.clone();
+
+    let srv = server::test_server(move || {
+        let ka = ka2.clone();
+
+        MqttServer::new(|con: Handshake<_>| async move {
+            Ok(con.ack(St).keep_alive(Duration::from_secs(1)))
         })
+        .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
         .control(move |msg| match msg {
-            ControlMessage::ProtocolError(msg) => ok::<_, TestError>(msg.ack()),
+            ControlMessage::ProtocolError(msg) => {
+                if let &error::ProtocolError::KeepAliveTimeout = msg.get_ref() {
+                    ka.store(true, Relaxed);
+                }
+                ok::<_, TestError>(msg.ack())
+            }
             _ => ok(msg.disconnect()),
         })
         .finish()
     });
 
-    // connect to server
-    let client = client::MqttConnector::new(srv.addr())
-        .client_id("user")
-        .max_packet_size(30)
-        .connect()
-        .await
-        .unwrap();
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    assert!(sink.is_open());
+    sleep(Duration::from_millis(2500)).await;
+    assert!(!sink.is_open());
+    assert!(ka.load(Relaxed));
+}
+
+#[ntex::test]
+async fn test_keepalive2() {
+    let ka = Arc::new(AtomicBool::new(false));
+    let ka2 = ka.clone();
+
+    let srv = server::test_server(move || {
+        let ka = ka2.clone();
+
+        MqttServer::new(|con: Handshake<_>| async move {
+            Ok(con.ack(St).keep_alive(Duration::from_secs(1)))
+        })
+        .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
+        .control(move |msg| match msg {
+            ControlMessage::ProtocolError(msg) => {
+                if let &error::ProtocolError::KeepAliveTimeout = msg.get_ref() {
+                    ka.store(true, Relaxed);
+                }
+                ok::<_, TestError>(msg.ack())
+            }
+            _ => ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    assert!(sink.is_open());
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sleep(Duration::from_millis(1200)).await;
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sleep(Duration::from_millis(2500)).await;
+
+    assert!(!sink.is_open());
+    assert!(ka.load(Relaxed));
+}
+
+#[ntex::test]
+async fn test_sink_encoder_error_pub_qos1() {
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake<_>| async move {
+            let builder = con.sink().publish("test", Bytes::new()).properties(|props| {
+                props.user_properties.push((
+                    "ssssssssssssssssssssssssssssssssssss".into(),
+                    "ssssssssssssssssssssssssssssssssssss".into(),
+                ));
+            });
+            ntex::rt::spawn(async move {
+                let res = builder.send_at_least_once().await;
+                assert_eq!(
+                    res,
+                    Err(error::PublishQos1Error::Encode(error::EncodeError::InvalidLength))
+                );
+            });
+            Ok(con.ack(St))
+        })
+        .publish(|p: Publish| {
+            sleep(Duration::from_millis(50)).map(move |_| Ok::<_, TestError>(p.ack()))
+        })
+        .control(move |msg| match msg {
+            ControlMessage::ProtocolError(msg) => ok::<_, TestError>(msg.ack()),
+            _ => ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    // connect to server
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .max_packet_size(30)
+        .connect()
+        .await
+        .unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+}
+
+#[ntex::test]
+async fn test_sink_encoder_error_pub_qos0() {
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake<_>| async move {
+            let builder = con.sink().publish("test", Bytes::new()).properties(|props| {
+                props.user_properties.push((
+                    "ssssssssssssssssssssssssssssssssssss".into(),
+                    "ssssssssssssssssssssssssssssssssssss".into(),
+                ));
+            });
+            let res = builder.send_at_most_once();
+            assert_eq!(
+                res,
+                Err(error::SendPacketError::Encode(error::EncodeError::InvalidLength))
+            );
+            Ok(con.ack(St))
+        })
+        .publish(|p: Publish| {
+            sleep(Duration::from_millis(50)).map(move |_| Ok::<_, TestError>(p.ack()))
+        })
+        .control(move |msg| match msg {
+            ControlMessage::ProtocolError(msg) => ok::<_, TestError>(msg.ack()),
+            _ => ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    // connect to server
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .max_packet_size(30)
+        .connect()
+        .await
+        .unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+}
+
+#[ntex::test]
+async fn test_request_problem_info() {
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake<_>| async move { Ok(con.ack(St)) })
+            .publish(|p: Publish| async move {
+                Ok::<_, TestError>(
+                    p.ack()
+                        .properties(|props| {
+                            props.push((
+                                "ssssssssssssssssssssssssssssssssssss".into(),
+                                "ssssssssssssssssssssssssssssssssssss".into(),
+                            ))
+                        })
+                        .reason("TEST"),
+                )
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .max_packet_size(30)
+        .packet(|pkt| pkt.request_problem_info = false)
+        .connect()
+        .await
+        .unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("#"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    assert!(res.properties.is_empty());
+    assert!(res.reason_string.is_none());
+}
+
+#[ntex::test]
+async fn test_suback_with_reason() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    msg.iter_mut().for_each(|mut s| {
+                        s.fail(codec::SubscribeAckReason::ImplementationSpecificError)
+                    });
+                    ok::<_, TestError>(msg.ack_reason("some reason".into()).ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(
+                "topic1".into(),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::AtLeastOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::AtSubscribe,
+                },
+            )],
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::SubscribeAck(codec::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![codec::SubscribeAckReason::ImplementationSpecificError],
+            properties: codec::UserProperties::default(),
+            reason_string: Some("some reason".into()),
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handle_incoming() -> std::io::Result<()> {
+    let publish = Arc::new(AtomicBool::new(false));
+    let publish2 = publish.clone();
+    let disconnect = Arc::new(AtomicBool::new(false));
+    let disconnect2 = disconnect.clone();
+
+    let srv = server::test_server(move || {
+        let publish = publish2.clone();
+        let disconnect = disconnect2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                publish.store(true, Relaxed);
+                ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| match msg {
+                ControlMessage::Disconnect(msg) => {
+                    disconnect.store(true, Relaxed);
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.write(codec::Packet::Connect(codec::Connect::default().client_id("user"))).unwrap();
+    framed.write(pkt_publish().into()).unwrap();
+    framed
+        .write(codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::ReceiveMaximumExceeded,
+            session_expiry_interval_secs: None,
+            server_reference: None,
+            reason_string: None,
+            user_properties: Default::default(),
+        }))
+        .unwrap();
+    poll_fn(|cx| framed.flush(cx)).await.unwrap();
+    drop(framed);
+    sleep(Duration::from_millis(500)).await;
+
+    assert!(publish.load(Relaxed));
+    assert!(disconnect.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_topic_length() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .max_topic_length(8)
+            .max_client_id_length(4)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => ok::<_, TestError>(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("long-user")
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    if let error::ClientError::Ack(pkt) = err {
+        assert_eq!(pkt.reason_code, codec::ConnectAckReason::ClientIdentifierNotValid);
+    } else {
+        panic!("Expected ack error, got: {:?}", err);
+    }
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(codec::Packet::Publish(codec::Publish {
+            topic: ByteString::from_static("topic/too/long"),
+            ..pkt_publish()
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::TopicNameInvalid
+        ))
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_connect_filter() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .connect_filter(|pkt, _| {
+                if pkt.client_id == "banned" {
+                    Err(codec::ConnectAckReason::Banned)
+                } else {
+                    Ok(())
+                }
+            })
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("banned")
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    if let error::ClientError::Ack(pkt) = err {
+        assert_eq!(pkt.reason_code, codec::ConnectAckReason::Banned);
+    } else {
+        panic!("Expected ack error, got: {:?}", err);
+    }
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    client.sink().close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_capture_malformed() -> std::io::Result<()> {
+    let captured = Arc::new(std::sync::Mutex::new(None));
+    let captured2 = captured.clone();
+
+    let srv = server::test_server(move || {
+        let captured = captured2.clone();
+        MqttServer::new(handshake)
+            .max_topic_length(10)
+            .capture_malformed(64)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => {
+                    *captured.lock().unwrap() = msg.packet().cloned();
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let publish = codec::Packet::Publish(codec::Publish {
+        topic: ByteString::from_static("topic/too/long"),
+        ..pkt_publish()
+    });
+    let mut buf = ntex::util::BytesMut::new();
+    ntex::codec::Encoder::encode(&codec::Codec::default(), publish.clone(), &mut buf).unwrap();
+
+    framed.send(publish).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(std::matches!(pkt, codec::Packet::Disconnect(_)));
+    assert_eq!(*captured.lock().unwrap(), Some(buf.freeze()));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_ack_qos2() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_: Publish| {
+                ok::<_, TestError>(
+                    PublishAck::new(codec::PublishAckReason::NoMatchingSubscribers)
+                        .reason("no subscribers")
+                        .property("key", "value"),
+                )
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let ack = codec::PublishAck {
+        packet_id: NonZeroU16::new(1).unwrap(),
+        reason_code: codec::PublishAckReason::NoMatchingSubscribers,
+        properties: vec![("key".into(), "value".into())],
+        reason_string: Some("no subscribers".into()),
+    };
+
+    framed.send(pkt_publish().into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishAck(ack.clone()));
+
+    let mut publish = pkt_publish();
+    publish.qos = codec::QoS::ExactlyOnce;
+    framed.send(publish.into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishReceived(ack));
+
+    let rel = codec::PublishAck2 {
+        packet_id: NonZeroU16::new(1).unwrap(),
+        reason_code: codec::PublishAck2Reason::Success,
+        properties: codec::UserProperties::default(),
+        reason_string: None,
+    };
+    framed.send(codec::Packet::PublishRelease(rel.clone())).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishComplete(rel.clone()));
+
+    // packet id is released
+    framed.send(codec::Packet::PublishRelease(rel.clone())).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishComplete(codec::PublishAck2 {
+            reason_code: codec::PublishAck2Reason::PacketIdNotFound,
+            ..rel
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_qos2_redelivery() -> std::io::Result<()> {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+
+    let srv = server::test_server(move || {
+        let calls = calls2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                calls.fetch_add(1, Relaxed);
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let packet_id = NonZeroU16::new(1).unwrap();
+    let publish = codec::Publish { qos: codec::QoS::ExactlyOnce, ..pkt_publish() };
+    framed.send(publish.clone().into()).await.unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        ack,
+        codec::Packet::PublishReceived(codec::PublishAck {
+            packet_id,
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+
+    // PUBREC is lost, client re-delivers publish
+    framed.send(codec::Publish { dup: true, ..publish }.into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, ack);
+    assert_eq!(calls.load(Relaxed), 1);
+
+    let rel = codec::PublishAck2 {
+        packet_id,
+        reason_code: codec::PublishAck2Reason::Success,
+        properties: codec::UserProperties::default(),
+        reason_string: None,
+    };
+    framed.send(codec::Packet::PublishRelease(rel.clone())).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishComplete(rel));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_error_reason() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                if p.publish_topic() == "test" {
+                    ready(Err(TestError))
+                } else {
+                    ready(Ok(p.ack()))
+                }
+            })
+            .publish_error_reason(|_: &TestError| Some(codec::PublishAckReason::NotAuthorized))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed.send(pkt_publish().into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::NotAuthorized,
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        })
+    );
+
+    // connection is still usable
+    let mut publish = pkt_publish();
+    publish.topic = ByteString::from("test2");
+    framed.send(publish.into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_hook() -> std::io::Result<()> {
+    let metrics = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let metrics2 = metrics.clone();
+
+    let srv = server::test_server(move || {
+        let metrics = metrics2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                if p.publish_topic() == "test" {
+                    ready(Err(TestError))
+                } else {
+                    ready(Ok(p.ack()))
+                }
+            })
+            .publish_error_reason(|_: &TestError| Some(codec::PublishAckReason::NotAuthorized))
+            .publish_hook(move |m| {
+                metrics.lock().unwrap().push((m.topic.clone(), m.payload_size, m.reason_code))
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed.send(pkt_publish().into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let mut publish = pkt_publish();
+    publish.topic = ByteString::from("test2");
+    publish.payload = Bytes::from_static(b"data");
+    framed.send(publish.into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    assert_eq!(
+        *metrics.lock().unwrap(),
+        vec![
+            (ByteString::from("test"), 0, Some(codec::PublishAckReason::NotAuthorized)),
+            (ByteString::from("test2"), 4, Some(codec::PublishAckReason::Success)),
+        ]
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_dead_letter() -> std::io::Result<()> {
+    let letters = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let letters2 = letters.clone();
+
+    let srv = server::test_server(move || {
+        let letters = letters2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                if p.publish_topic() == "test" {
+                    ready(Err(TestError))
+                } else {
+                    ready(Ok(p.ack()))
+                }
+            })
+            .publish_error_reason(|_: &TestError| Some(codec::PublishAckReason::NotAuthorized))
+            .dead_letter(move |pkt, failure| {
+                let acked = std::matches!(failure, PublishFailure::Ack(ack) if ack.is_error());
+                letters.lock().unwrap().push((pkt.topic, acked))
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed.send(pkt_publish().into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let mut publish = pkt_publish();
+    publish.topic = ByteString::from("test2");
+    framed.send(publish.into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    assert_eq!(*letters.lock().unwrap(), vec![(ByteString::from("test"), true)]);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_timeout() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish_timeout(Duration::from_millis(50))
+            .publish_timeout_reason(codec::PublishAckReason::ImplementationSpecificError)
+            .publish(|p: Publish| {
+                sleep(Duration::from_millis(200)).map(move |_| Ok::<_, TestError>(p.ack()))
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed.send(pkt_publish().into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::ImplementationSpecificError,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_payload_format() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                assert_eq!(p.payload_str(), Ok(Some("{\"a\": 1}")));
+                assert_eq!(p.content_type(), Some("application/json"));
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish("test", Bytes::from_static(b"{\"a\": 1}"))
+        .format_utf8()
+        .content_type("application/json")
+        .send_at_least_once()
+        .await;
+    assert_eq!(res.unwrap().reason_code, codec::PublishAckReason::Success);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_options() -> std::io::Result<()> {
+    let opts = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let opts2 = opts.clone();
+
+    let srv = server::test_server(move || {
+        let opts = opts2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        opts.lock().unwrap().push((
+                            sub.qos(),
+                            sub.no_local(),
+                            sub.retain_as_published(),
+                            sub.retain_handling(),
+                        ));
+                        sub.confirm(sub.qos());
+                    }
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .subscribe(None)
+        .topic_filter("topic1".into(), codec::QoS::AtLeastOnce)
+        .topic_filter(
+            "topic2".into(),
+            codec::SubscriptionOptions::new(codec::QoS::AtMostOnce)
+                .no_local(true)
+                .retain_as_published(true)
+                .retain_handling(codec::RetainHandling::NoAtSubscribe),
+        )
+        .send()
+        .await
+        .unwrap();
+    assert!(res.is_success());
+    assert_eq!(res.get("topic1"), Some(Ok(codec::QoS::AtLeastOnce)));
+    assert_eq!(res.get("topic2"), Some(Ok(codec::QoS::AtMostOnce)));
+    assert_eq!(res.get("topic3"), None);
+    assert_eq!(
+        *opts.lock().unwrap(),
+        vec![
+            (codec::QoS::AtLeastOnce, false, false, codec::RetainHandling::AtSubscribe),
+            (codec::QoS::AtMostOnce, true, true, codec::RetainHandling::NoAtSubscribe),
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_unsubscribe_result() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Unsubscribe(mut msg) => {
+                    for mut item in msg.iter_mut() {
+                        match item.topic().as_ref() {
+                            "denied" => item.fail(codec::UnsubscribeAckReason::NotAuthorized),
+                            "unknown" => {
+                                item.fail(codec::UnsubscribeAckReason::NoSubscriptionExisted)
+                            }
+                            _ => item.success(),
+                        }
+                    }
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .unsubscribe()
+        .topic_filter("topic1".into())
+        .topic_filter("unknown".into())
+        .send()
+        .await
+        .unwrap();
+    assert!(res.is_success());
+    assert_eq!(res.get("topic1"), Some(codec::UnsubscribeAckReason::Success));
+    assert_eq!(res.get("unknown"), Some(codec::UnsubscribeAckReason::NoSubscriptionExisted));
+
+    let res = sink.unsubscribe().topic_filter("denied".into()).send().await.unwrap();
+    assert!(!res.is_success());
+    assert_eq!(res.reason_codes(), vec![codec::ReasonCode::NotAuthorized]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_send_packet() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Unsubscribe(msg) => ok::<_, TestError>(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let packet_id = NonZeroU16::new(10).unwrap();
+    let ack = sink
+        .send_packet(codec::Packet::Unsubscribe(codec::Unsubscribe {
+            packet_id,
+            user_properties: Default::default(),
+            topic_filters: vec![ByteString::from("topic")],
+        }))
+        .await
+        .unwrap();
+    assert!(std::matches!(
+        ack,
+        Some(codec::Packet::UnsubscribeAck(ref ack)) if ack.packet_id == packet_id
+    ));
+
+    let ack = sink.send_packet(pkt_publish().into()).await.unwrap();
+    assert!(std::matches!(
+        ack,
+        Some(codec::Packet::PublishAck(ref ack))
+            if ack.reason_code == codec::PublishAckReason::Success
+    ));
+
+    let publish =
+        codec::Publish { qos: codec::QoS::AtMostOnce, packet_id: None, ..pkt_publish() };
+    assert!(sink.send_packet(publish.into()).await.unwrap().is_none());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_send_packet_encode_error() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_size(64)
+            .receive_max(1)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg: ControlMessage<TestError>| ok::<_, TestError>(msg.disconnect()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let publish = codec::Publish { payload: Bytes::from(vec![0; 128]), ..pkt_publish() };
+    let res = sink.send_packet(publish.into()).await;
+    assert_eq!(res, Err(error::SendPacketError::Encode(error::EncodeError::InvalidLength)));
+
+    // packet id and in-flight slot are released
+    let ack =
+        ntex::rt::time::timeout(Duration::from_secs(1), sink.send_packet(pkt_publish().into()))
+            .await
+            .unwrap()
+            .unwrap();
+    assert!(std::matches!(
+        ack,
+        Some(codec::Packet::PublishAck(ref ack))
+            if ack.reason_code == codec::PublishAckReason::Success
+    ));
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_dedup_window() -> std::io::Result<()> {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+
+    let srv = server::test_server(move || {
+        let calls = calls2.clone();
+        MqttServer::new(handshake)
+            .dedup_window(16)
+            .publish(Router::new(
+                fn_service(move |p: Publish| {
+                    calls.fetch_add(1, Relaxed);
+                    ok::<_, TestError>(p.ack())
+                })
+                .map_init_err(|_| TestError),
+            ))
+            .finish()
+    });
+
+    let ack = codec::Packet::PublishAck(codec::PublishAck {
+        packet_id: NonZeroU16::new(1).unwrap(),
+        reason_code: codec::PublishAckReason::Success,
+        properties: codec::UserProperties::default(),
+        reason_string: None,
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed.send(pkt_publish().into()).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack);
+
+    // retransmit, handler is not called
+    let mut publish = pkt_publish();
+    publish.dup = true;
+    framed.send(publish.into()).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack);
+    assert_eq!(calls.load(Relaxed), 1);
+
+    // same packet id from other client
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user2")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    let mut publish = pkt_publish();
+    publish.dup = true;
+    framed.send(publish.into()).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), ack);
+    assert_eq!(calls.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_tenant_cross_namespace() {
+    let refused = Arc::new(AtomicBool::new(false));
+    let refused2 = refused.clone();
+
+    let srv = server::test_server(move || {
+        let refused = refused2.clone();
+        MqttServer::new(handshake)
+            .tenant_isolation(|session: &Session<St>| Some(session.client_id().clone()))
+            .publish(fn_factory_with_config(move |session: Session<St>| {
+                let refused = refused.clone();
+                ok::<_, TestError>(fn_service(move |_: Publish| {
+                    // publishes of other tenants are not delivered
+                    let res = session
+                        .sink()
+                        .publish(ByteString::from_static("other/out"), Bytes::new())
+                        .send_at_most_once();
+                    refused.store(res == Err(error::SendPacketError::NotAllowed), Relaxed);
+
+                    let publish = |topic| codec::Publish {
+                        topic: ByteString::from_static(topic),
+                        qos: codec::QoS::AtMostOnce,
+                        packet_id: None,
+                        ..pkt_publish()
+                    };
+                    ok::<_, TestError>(PublishAck::from(HandlerResponse::AckAndPublish(vec![
+                        publish("other/leak"),
+                        publish("acme/out"),
+                    ])))
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("acme")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed.send(pkt_publish().into()).await.unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::PublishAck(ack) => {
+            assert_eq!(ack.reason_code, codec::PublishAckReason::Success)
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => assert_eq!(pkt.topic, "out"),
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+    assert!(refused.load(Relaxed));
+}
+
+#[ntex::test]
+async fn test_router_publish_in_response() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(
+                    fn_service(|p: Publish| ok::<_, TestError>(p.ack()))
+                        .map_init_err(|_| TestError),
+                )
+                .resource(
+                    "request",
+                    fn_service(|p: Publish| {
+                        ok::<_, TestError>(HandlerResponse::AckAndPublish(vec![
+                            codec::Publish {
+                                topic: ByteString::from_static("response"),
+                                qos: codec::QoS::AtMostOnce,
+                                packet_id: None,
+                                payload: p.payload().clone(),
+                                ..pkt_publish()
+                            },
+                        ]))
+                    }),
+                ),
+            )
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let publish = codec::Publish {
+        topic: ByteString::from_static("request"),
+        payload: Bytes::from_static(b"ping"),
+        ..pkt_publish()
+    };
+    framed.send(publish.into()).await.unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::PublishAck(ack) => {
+            assert_eq!(ack.reason_code, codec::PublishAckReason::Success)
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => {
+            assert_eq!(pkt.topic, "response");
+            assert_eq!(pkt.payload, Bytes::from_static(b"ping"));
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+
+    // default service responds with plain ack
+    let publish = codec::Publish { topic: ByteString::from_static("other"), ..pkt_publish() };
+    framed.send(publish.into()).await.unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::PublishAck(_) => (),
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+}
+
+#[ntex::test]
+async fn test_control_channel() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control_channel(|_: &Session<St>, mut rx| {
+                ntex::rt::spawn(async move {
+                    while let Some(req) = rx.next().await {
+                        req.respond(|msg| match msg {
+                            ControlMessage::Subscribe(mut msg) => {
+                                for mut sub in &mut msg {
+                                    sub.confirm(sub.qos());
+                                }
+                                msg.ack()
+                            }
+                            msg => msg.disconnect(),
+                        });
+                    }
+                });
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(
+            codec::Subscribe {
+                id: None,
+                packet_id: NonZeroU16::new(1).unwrap(),
+                user_properties: Default::default(),
+                topic_filters: vec![(
+                    ByteString::from_static("topic1"),
+                    codec::SubscriptionOptions::new(codec::QoS::AtLeastOnce),
+                )],
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::SubscribeAck(ack) => {
+            assert_eq!(ack.status, vec![codec::SubscribeAckReason::GrantedQos1])
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+}
+
+#[ntex::test]
+async fn test_connect_io() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v5(server)
+            .expect(|pkt| match pkt {
+                codec::Packet::Connect(pkt) => pkt.client_id == "user",
+                _ => false,
+            })
+            .send(codec::Packet::ConnectAck(codec::ConnectAck {
+                receive_max: NonZeroU16::new(2),
+                ..Default::default()
+            }))
+            .run(),
+    );
+
+    let client =
+        client::MqttConnector::new("localhost").client_id("user").connect_io(io).await.unwrap();
+    assert_eq!(client.packet().receive_max, NonZeroU16::new(2));
+
+    let mut broker = broker.await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish(ByteString::from_static("topic"), Bytes::from_static(b"data"))
+        .send_at_most_once()
+        .unwrap();
+    let pkt = broker.recv().await.unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(ref p) if p.topic == "topic"));
+
+    sink.close();
+    let pkt = broker.recv().await.unwrap();
+    assert!(matches!(pkt, codec::Packet::Disconnect(_)));
+    broker.expect_closed().await;
+}
+
+#[ntex::test]
+async fn test_publish_on_close() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v5(server)
+            .expect(|pkt| std::matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck(codec::ConnectAck::default()))
+            .run(),
+    );
+
+    let client =
+        client::MqttConnector::new("localhost").client_id("user").connect_io(io).await.unwrap();
+    let mut broker = broker.await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish_on_close(
+        ByteString::from_static("status/user"),
+        Bytes::from_static(b"offline"),
+    );
+    sink.publish_on_close(ByteString::from_static("status/last"), Bytes::from_static(b"1"));
+
+    sink.close();
+    let pkt = broker.recv().await.unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(ref p)
+        if p.topic == "status/user" && p.payload == "offline" && p.qos == codec::QoS::AtMostOnce));
+    let pkt = broker.recv().await.unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(ref p) if p.topic == "status/last"));
+    let pkt = broker.recv().await.unwrap();
+    assert!(matches!(pkt, codec::Packet::Disconnect(_)));
+    broker.expect_closed().await;
+}
+
+#[ntex::test]
+async fn test_default_publish_properties() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v5(server)
+            .expect(|pkt| std::matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck(codec::ConnectAck::default()))
+            .run(),
+    );
+
+    let client = client::MqttConnector::new("localhost")
+        .client_id("user")
+        .topic_prefix("tenant1/device1/")
+        .publish_properties(|props| {
+            props.content_type = Some(ByteString::from_static("application/json"));
+            props.user_properties.push(("tenant".into(), "tenant1".into()));
+        })
+        .connect_io(io)
+        .await
+        .unwrap();
+    let mut broker = broker.await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish(ByteString::from_static("temp"), Bytes::from_static(b"{}"))
+        .properties(|props| props.user_properties.push(("unit".into(), "C".into())))
+        .send_at_most_once()
+        .unwrap();
+    let pkt = broker.recv().await.unwrap();
+    if let codec::Packet::Publish(pkt) = pkt {
+        assert_eq!(pkt.topic, "tenant1/device1/temp");
+        assert_eq!(pkt.properties.content_type.as_deref(), Some("application/json"));
+        assert_eq!(pkt.properties.user_properties.len(), 2);
+        assert_eq!(pkt.properties.user_properties[0].1, "tenant1");
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+
+    sink.set_topic_prefix(None);
+    sink.set_publish_properties(None);
+    sink.publish(ByteString::from_static("temp"), Bytes::from_static(b"{}"))
+        .send_at_most_once()
+        .unwrap();
+    let pkt = broker.recv().await.unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(ref p)
+        if p.topic == "temp" && p.properties.content_type.is_none()));
+    sink.close();
+}
+
+#[ntex::test]
+async fn test_disconnect_from_broker() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v5(server)
+            .expect(|pkt| std::matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck(codec::ConnectAck::default()))
+            .run(),
+    );
+
+    let client =
+        client::MqttConnector::new("localhost").client_id("user").connect_io(io).await.unwrap();
+    let mut broker = broker.await.unwrap();
+
+    let disconnect = Arc::new(AtomicBool::new(false));
+    let closed = Arc::new(AtomicBool::new(false));
+    let disconnect2 = disconnect.clone();
+    let closed2 = closed.clone();
+
+    ntex::rt::spawn(client.start(fn_service(
+        move |msg: client::ControlMessage<()>| match msg {
+            client::ControlMessage::Disconnect(d) => {
+                assert_eq!(d.reason_code(), codec::ReasonCode::ServerMoved);
+                assert_eq!(d.reason_string().unwrap(), "moved");
+                assert_eq!(d.server_reference().unwrap(), "other:1883");
+                disconnect2.store(true, Relaxed);
+                ready(Ok(d.ack()))
+            }
+            client::ControlMessage::Closed(c) => {
+                let pkt = c.disconnect().unwrap();
+                assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::ServerMoved);
+                assert_eq!(c.shutdown_status(), Some(ShutdownStatus::Clean));
+                closed2.store(true, Relaxed);
+                ready(Ok(c.ack()))
+            }
+            _ => panic!("Unexpected control message"),
+        },
+    )));
+
+    broker.write(codec::Packet::Disconnect(codec::Disconnect {
+        reason_code: codec::DisconnectReasonCode::ServerMoved,
+        reason_string: Some(ByteString::from_static("moved")),
+        server_reference: Some(ByteString::from_static("other:1883")),
+        ..Default::default()
+    }));
+    broker.expect_closed().await;
+
+    sleep(Duration::from_millis(100)).await;
+    assert!(disconnect.load(Relaxed));
+    assert!(closed.load(Relaxed));
+}
+
+#[ntex::test]
+async fn test_client_events() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v5(server)
+            .expect(|pkt| std::matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck(codec::ConnectAck {
+                receive_max: NonZeroU16::new(2),
+                ..Default::default()
+            }))
+            .run(),
+    );
+
+    let client =
+        client::MqttConnector::new("localhost").client_id("user").connect_io(io).await.unwrap();
+    let mut broker = broker.await.unwrap();
+    let sink = client.sink();
+    let mut events = client.events();
+    ntex::rt::spawn(client.start_default());
+
+    match events.next().await {
+        Some(client::ClientEvent::Connected(ack)) => {
+            assert_eq!(ack.receive_max, NonZeroU16::new(2))
+        }
+        ev => panic!("Unexpected event: {:?}", ev),
+    }
+
+    sink.close();
+    assert!(std::matches!(broker.recv().await, Some(codec::Packet::Disconnect(_))));
+    assert!(std::matches!(
+        events.next().await,
+        Some(client::ClientEvent::Disconnected(client::DisconnectCause::Closed))
+    ));
+    assert!(events.next().await.is_none());
+}
+
+#[ntex::test]
+async fn test_slow_consumer() {
+    use std::{cell::RefCell, rc::Rc};
+
+    use ntex::Service;
+    use ntex_mqtt::{v5::MqttSink, SlowConsumerPolicy};
+
+    let (client, server) = testing::Io::create();
+    client.remote_buffer_cap(1024);
+
+    let sink: Rc<RefCell<Option<MqttSink>>> = Rc::new(RefCell::new(None));
+    let sink2 = sink.clone();
+    let srv = MqttServer::new(fn_service(move |h: Handshake<_>| {
+        *sink2.borrow_mut() = Some(h.sink());
+        ok::<_, TestError>(h.ack(St))
+    }))
+    .slow_consumer(16, Duration::ZERO, SlowConsumerPolicy::Disconnect)
+    .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+    .finish()
+    .new_service(())
+    .await
+    .ok()
+    .unwrap();
+    ntex::rt::spawn(async move { srv.call(server).await });
+
+    let mut broker = testing::MockBroker::v5(client)
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .expect(|pkt| std::matches!(pkt, codec::Packet::ConnectAck(_)))
+        .run()
+        .await;
+    // peer stops reading
+    broker.io().remote_buffer_cap(0);
+
+    let sink = sink.borrow_mut().take().unwrap();
+    let payload = Bytes::from(vec![0; 32]);
+    sink.publish(ByteString::from_static("t1"), payload.clone()).send_at_most_once().unwrap();
+    assert!(sink.is_open());
+    // outbound queue is over watermark, connection gets closed
+    let _ = sink.publish(ByteString::from_static("t2"), payload).send_at_most_once();
+    assert!(!sink.is_open());
+
+    broker.io().remote_buffer_cap(usize::MAX);
+    let pkt = broker.recv().await.unwrap();
+    assert!(std::matches!(pkt, codec::Packet::Publish(ref p) if p.topic == "t1"));
+    match broker.recv().await {
+        Some(codec::Packet::Disconnect(pkt)) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::QuotaExceeded)
+        }
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    }
+}
+
+#[ntex::test]
+async fn test_client_unsolicited() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v5(server)
+            .expect(|pkt| std::matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck(codec::ConnectAck::default()))
+            .run(),
+    );
 
+    let client =
+        client::MqttConnector::new("localhost").client_id("user").connect_io(io).await.unwrap();
+    let mut broker = broker.await.unwrap();
     let sink = client.sink();
 
-    ntex::rt::spawn(client.start_default());
+    let packets = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let packets2 = packets.clone();
+    ntex::rt::spawn(client.start(move |msg| match msg {
+        client::ControlMessage::Unsolicited(pkt) => {
+            packets2.lock().unwrap().push(pkt.packet().clone());
+            ok::<_, TestError>(pkt.ack())
+        }
+        msg => ok(msg.disconnect(codec::Disconnect::default())),
+    }));
 
-    let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
-    assert!(res.is_ok());
+    let ack = codec::PublishAck {
+        packet_id: NonZeroU16::new(100).unwrap(),
+        reason_code: codec::PublishAckReason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    };
+    broker.write(codec::Packet::PublishAck(ack.clone()));
+
+    // connection is still open
+    sink.publish("topic", Bytes::new()).send_at_most_once().unwrap();
+    let pkt = broker.recv().await.unwrap();
+    assert!(std::matches!(pkt, codec::Packet::Publish(ref p) if p.topic == "topic"));
+    assert_eq!(*packets.lock().unwrap(), vec![codec::Packet::PublishAck(ack)]);
+
+    sink.close();
 }
 
 #[ntex::test]
-async fn test_sink_encoder_error_pub_qos0() {
+async fn test_config_handle() -> std::io::Result<()> {
+    let config = ConfigHandle::new();
+    let cfg = config.clone();
     let srv = server::test_server(move || {
-        MqttServer::new(|con: Handshake<_>| async move {
-            let builder = con.sink().publish("test", Bytes::new()).properties(|props| {
-                props.user_properties.push((
-                    "ssssssssssssssssssssssssssssssssssss".into(),
-                    "ssssssssssssssssssssssssssssssssssss".into(),
-                ));
-            });
-            let res = builder.send_at_most_once();
-            assert_eq!(
-                res,
-                Err(error::SendPacketError::Encode(error::EncodeError::InvalidLength))
-            );
-            Ok(con.ack(St))
-        })
-        .publish(|p: Publish| {
-            sleep(Duration::from_millis(50)).map(move |_| Ok::<_, TestError>(p.ack()))
-        })
-        .control(move |msg| match msg {
-            ControlMessage::ProtocolError(msg) => ok::<_, TestError>(msg.ack()),
-            _ => ok(msg.disconnect()),
-        })
-        .finish()
+        MqttServer::new(handshake)
+            .receive_max(15)
+            .config(cfg.clone())
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
     });
 
-    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert_eq!(client.packet().receive_max, NonZeroU16::new(15));
+    assert_eq!(client.packet().server_keepalive_sec, None);
+    client.sink().close();
+
+    // new connections use updated values
+    config.set_max_inflight(4);
+    config.set_keep_alive(Duration::from_secs(10));
     let client = client::MqttConnector::new(srv.addr())
         .client_id("user")
-        .max_packet_size(30)
+        .keep_alive(Duration::from_secs(60))
         .connect()
         .await
         .unwrap();
+    assert_eq!(client.packet().receive_max, NonZeroU16::new(4));
+    assert_eq!(client.packet().server_keepalive_sec, Some(10));
+    client.sink().close();
 
-    let sink = client.sink();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_stuck_ack() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v5(server)
+            .expect(|pkt| std::matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck(codec::ConnectAck::default()))
+            .run(),
+    );
 
+    let client =
+        client::MqttConnector::new("localhost").client_id("user").connect_io(io).await.unwrap();
+    let mut broker = broker.await.unwrap();
+    let sink = client.sink();
     ntex::rt::spawn(client.start_default());
 
-    let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
-    assert!(res.is_ok());
+    let stuck = Arc::new(AtomicUsize::new(0));
+    let stuck2 = stuck.clone();
+    sink.on_stuck_ack(Duration::from_millis(50), move |idx, _| {
+        stuck2.store(idx as usize, Relaxed);
+    });
+
+    let publish = ntex::rt::spawn(
+        sink.publish(ByteString::from_static("topic"), Bytes::from_static(b"1"))
+            .send_at_least_once(),
+    );
+    let pkt = broker.recv().await.unwrap();
+    assert!(std::matches!(pkt, codec::Packet::Publish(_)));
+    assert_eq!(sink.inflight(), 1);
+
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(stuck.load(Relaxed), 1);
+    assert!(sink.inflight_age().unwrap() >= Duration::from_millis(50));
+
+    assert_eq!(sink.expire_inflight(Duration::from_millis(50)), 1);
+    assert_eq!(publish.await.unwrap(), Err(error::PublishQos1Error::Expired));
+
+    // late ack releases slot
+    broker.write(codec::Packet::PublishAck(codec::PublishAck {
+        packet_id: NonZeroU16::new(1).unwrap(),
+        reason_code: codec::PublishAckReason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    }));
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(sink.inflight(), 0);
+    assert!(sink.is_open());
+    sink.close();
 }
 
 #[ntex::test]
-async fn test_request_problem_info() {
-    let srv = server::test_server(move || {
-        MqttServer::new(|con: Handshake<_>| async move { Ok(con.ack(St)) })
-            .publish(|p: Publish| async move {
-                Ok::<_, TestError>(
-                    p.ack()
-                        .properties(|props| {
-                            props.push((
-                                "ssssssssssssssssssssssssssssssssssss".into(),
-                                "ssssssssssssssssssssssssssssssssssss".into(),
-                            ))
-                        })
-                        .reason("TEST".into()),
-                )
+async fn test_connect_properties() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v5(server)
+            .expect(|pkt| match pkt {
+                codec::Packet::Connect(pkt) => {
+                    pkt.clean_start
+                        && pkt.session_expiry_interval_secs == Some(3600)
+                        && pkt.receive_max == NonZeroU16::new(8)
+                        && pkt.max_packet_size.map(|v| v.get()) == Some(1024)
+                        && pkt.topic_alias_max == 4
+                        && pkt.request_response_info
+                        && !pkt.request_problem_info
+                        && pkt.user_properties
+                            == vec![(ByteString::from("key"), ByteString::from("value"))]
+                }
+                _ => false,
             })
+            .send(codec::Packet::ConnectAck(codec::ConnectAck::default()))
+            .run(),
+    );
+
+    let client = client::MqttConnector::new("localhost")
+        .client_id("user")
+        .clean_start()
+        .session_expiry(Duration::from_secs(3600))
+        .receive_max(8)
+        .max_packet_size(1024)
+        .topic_alias_max(4)
+        .request_response_info(true)
+        .request_problem_info(false)
+        .user_property("key", "value")
+        .connect_io(io)
+        .await;
+    assert!(client.is_ok());
+    assert!(broker.await.is_ok());
+}
+
+#[ntex::test]
+async fn test_disconnect_with_will() -> std::io::Result<()> {
+    let delay = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let delay2 = delay.clone();
+    let ends = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let ends2 = ends.clone();
+
+    let srv = server::test_server(move || {
+        let delay = delay2.clone();
+        let ends = ends2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(ntex::fn_factory_with_config(move |session: Session<St>| {
+                let delay = delay.clone();
+                let ends = ends.clone();
+                ok::<_, TestError>(ntex::fn_service(move |msg| match msg {
+                    ControlMessage::Disconnect(msg) => {
+                        delay.lock().unwrap().push(session.will_delay(Some(msg.packet())));
+                        ok::<_, TestError>(msg.ack())
+                    }
+                    ControlMessage::Closed(msg) => {
+                        ends.lock().unwrap().push(msg.session_end());
+                        ok(msg.ack())
+                    }
+                    _ => ok(msg.disconnect()),
+                }))
+            }))
             .finish()
     });
 
-    // connect to server
+    let will = codec::LastWill {
+        qos: codec::QoS::AtLeastOnce,
+        retain: false,
+        topic: ByteString::from_static("will"),
+        message: Bytes::from_static(b"gone"),
+        will_delay_interval_sec: Some(30),
+        correlation_data: None,
+        message_expiry_interval: None,
+        content_type: None,
+        user_properties: Default::default(),
+        is_utf8_payload: None,
+        response_topic: None,
+    };
+
+    // will is published after session expiry
     let client = client::MqttConnector::new(srv.addr())
         .client_id("user")
-        .max_packet_size(30)
-        .packet(|pkt| pkt.request_problem_info = false)
+        .session_expiry(Duration::from_secs(10))
+        .last_will(will.clone())
         .connect()
         .await
         .unwrap();
-
     let sink = client.sink();
-
     ntex::rt::spawn(client.start_default());
+    sink.disconnect_with_will();
+    sleep(Duration::from_millis(100)).await;
 
-    let res = sink
-        .publish(ByteString::from_static("#"), Bytes::new())
-        .send_at_least_once()
+    // normal disconnect deletes will
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .last_will(will)
+        .connect()
         .await
         .unwrap();
-    assert!(res.properties.is_empty());
-    assert!(res.reason_string.is_none());
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.close();
+    sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(*delay.lock().unwrap(), vec![Some(Duration::from_secs(10)), None]);
+    // will is published for disconnect with will message reason
+    assert_eq!(*ends.lock().unwrap(), vec![SessionEnd::Abnormal, SessionEnd::Clean]);
+    Ok(())
 }
 
 #[ntex::test]
-async fn test_suback_with_reason() -> std::io::Result<()> {
+async fn test_server_ping_not_allowed() {
+    let refused = Arc::new(AtomicBool::new(false));
+    let refused2 = refused.clone();
+
     let srv = server::test_server(move || {
-        MqttServer::new(handshake)
-            .control(move |msg| match msg {
-                ControlMessage::Subscribe(mut msg) => {
-                    msg.iter_mut().for_each(|mut s| {
-                        s.fail(codec::SubscribeAckReason::ImplementationSpecificError)
-                    });
-                    ok::<_, TestError>(msg.ack_reason("some reason".into()).ack())
-                }
-                _ => ok(msg.disconnect()),
-            })
-            .finish()
+        let refused = refused2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            let refused = refused.clone();
+            async move {
+                // servers must not send PINGREQ
+                let res = con.sink().ping().await;
+                refused.store(res == Err(error::SendPacketError::NotAllowed), Relaxed);
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
     });
 
     let io = srv.connect().await.unwrap();
-    let mut framed = Framed::new(io, codec::Codec::new());
+    let mut framed = Framed::new(io, codec::Codec::default());
     framed
         .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
         .await
         .unwrap();
     let _ = framed.next().await.unwrap().unwrap();
+    assert!(refused.load(Relaxed));
+}
 
-    framed
-        .send(codec::Packet::Subscribe(codec::Subscribe {
-            packet_id: NonZeroU16::new(1).unwrap(),
-            topic_filters: vec![(
-                "topic1".into(),
-                codec::SubscriptionOptions {
-                    qos: codec::QoS::AtLeastOnce,
-                    no_local: false,
-                    retain_as_published: false,
-                    retain_handling: codec::RetainHandling::AtSubscribe,
-                },
-            )],
-            id: None,
-            user_properties: codec::UserProperties::default(),
+#[ntex::test]
+async fn test_ping_rtt() {
+    let (io, server) = testing::duplex();
+
+    let broker = ntex::rt::spawn(
+        testing::MockBroker::v5(server)
+            .expect(|pkt| std::matches!(pkt, codec::Packet::Connect(_)))
+            .send(codec::Packet::ConnectAck(codec::ConnectAck::default()))
+            .expect_packet(codec::Packet::PingRequest)
+            .send(codec::Packet::PingResponse)
+            .run(),
+    );
+
+    let client =
+        client::MqttConnector::new("localhost").client_id("user").connect_io(io).await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let rtt = sink.ping().await.unwrap();
+    assert_eq!(sink.rtt(), Some(rtt));
+    let _broker = broker.await.unwrap();
+    sink.close();
+}
+
+#[cfg(feature = "compress")]
+#[ntex::test]
+async fn test_compression() -> std::io::Result<()> {
+    use ntex_mqtt::v5::compress::Compression;
+
+    let payload = Bytes::from(vec![b'a'; 4096]);
+    let srv = server::test_server(|| {
+        MqttServer::new(|h: Handshake<_>| {
+            assert!(Compression::is_requested(h.packet()));
+            ok::<_, TestError>(Compression::new().ack(h, St))
+        })
+        .publish(ntex::fn_factory_with_config(|session: Session<St>| {
+            ok::<_, TestError>(fn_service(move |p: Publish| {
+                assert_eq!(p.payload().len(), 4096);
+                session
+                    .sink()
+                    .publish(ByteString::from_static("echo"), p.payload().clone())
+                    .send_at_most_once()
+                    .unwrap();
+                ok::<_, TestError>(p.ack())
+            }))
         }))
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .compression(Compression::new())
+        .connect()
         .await
         .unwrap();
-    let pkt = framed.next().await.unwrap().unwrap();
-    assert_eq!(
-        pkt,
-        codec::Packet::SubscribeAck(codec::SubscribeAck {
-            packet_id: NonZeroU16::new(1).unwrap(),
-            status: vec![codec::SubscribeAckReason::ImplementationSpecificError],
-            properties: codec::UserProperties::default(),
-            reason_string: Some("some reason".into()),
-        })
+    assert!(Compression::is_accepted(client.packet()));
+
+    let sink = client.sink();
+    let received = Arc::new(std::sync::Mutex::new(None));
+    let received2 = received.clone();
+    ntex::rt::spawn(
+        client
+            .resource(
+                "echo",
+                fn_service(move |p: Publish| {
+                    *received2.lock().unwrap() = Some(p.payload().clone());
+                    ok::<_, TestError>(p.ack())
+                }),
+            )
+            .start_default(),
     );
 
+    sink.publish(ByteString::from_static("test"), payload.clone())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(*received.lock().unwrap(), Some(payload));
+
+    sink.close();
     Ok(())
 }
 
 #[ntex::test]
-async fn test_handle_incoming() -> std::io::Result<()> {
-    let publish = Arc::new(AtomicBool::new(false));
-    let publish2 = publish.clone();
-    let disconnect = Arc::new(AtomicBool::new(false));
-    let disconnect2 = disconnect.clone();
+async fn test_deliver_to_subscriptions() -> std::io::Result<()> {
+    async fn handshake<Io>(
+        packet: Handshake<Io>,
+    ) -> Result<HandshakeAck<Io, RefCell<Subscriptions>>, TestError> {
+        Ok(packet.ack(RefCell::new(Subscriptions::new())))
+    }
 
     let srv = server::test_server(move || {
-        let publish = publish2.clone();
-        let disconnect = disconnect2.clone();
         MqttServer::new(handshake)
-            .publish(move |p: Publish| {
-                publish.store(true, Relaxed);
-                ok::<_, TestError>(p.ack())
-            })
-            .control(move |msg| match msg {
-                ControlMessage::Disconnect(msg) => {
-                    disconnect.store(true, Relaxed);
-                    ok::<_, TestError>(msg.ack())
-                }
-                _ => ok(msg.disconnect()),
-            })
+            .publish(fn_factory_with_config(|session: Session<RefCell<Subscriptions>>| {
+                ok::<_, TestError>(fn_service(move |p: Publish| {
+                    let session = session.clone();
+                    async move {
+                        // forward publish back to the publisher
+                        let subs = session.state().borrow().clone();
+                        let _ = subs.deliver(session.sink(), p.packet(), false).await;
+                        Ok::<_, TestError>(p.ack())
+                    }
+                }))
+            }))
+            .control(fn_factory_with_config(|session: Session<RefCell<Subscriptions>>| {
+                ok::<_, TestError>(fn_service(move |msg| match msg {
+                    ControlMessage::Subscribe(mut msg) => {
+                        let id = msg.packet().id;
+                        for mut sub in &mut msg {
+                            session.state().borrow_mut().subscribe(
+                                ntex_mqtt::TopicFilter::new(sub.topic().clone()).unwrap(),
+                                sub.options().clone(),
+                                id,
+                            );
+                            sub.confirm(sub.qos());
+                        }
+                        ok::<_, TestError>(msg.ack())
+                    }
+                    _ => ok(msg.disconnect()),
+                }))
+            }))
             .finish()
     });
 
-    let io = srv.connect().await.unwrap();
-    let mut framed = Framed::new(io, codec::Codec::default());
-    framed.write(codec::Packet::Connect(codec::Connect::default().client_id("user"))).unwrap();
-    framed.write(pkt_publish().into()).unwrap();
-    framed
-        .write(codec::Packet::Disconnect(codec::Disconnect {
-            reason_code: codec::DisconnectReasonCode::ReceiveMaximumExceeded,
-            session_expiry_interval_secs: None,
-            server_reference: None,
-            reason_string: None,
-            user_properties: Default::default(),
-        }))
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    ntex::rt::spawn(client.start(fn_service(
+        move |msg: client::ControlMessage<()>| match msg {
+            client::ControlMessage::Publish(p) => {
+                let pkt = p.packet();
+                received2.lock().unwrap().push((
+                    pkt.topic.clone(),
+                    pkt.qos,
+                    pkt.retain,
+                    pkt.properties.subscription_ids.clone(),
+                ));
+                ready(Ok(p.ack(None)))
+            }
+            msg => ready(Ok(msg.disconnect(codec::Disconnect::default()))),
+        },
+    )));
+
+    sink.subscribe(std::num::NonZeroU32::new(5))
+        .topic_filter(
+            "a/#".into(),
+            codec::SubscriptionOptions::new(codec::QoS::AtMostOnce).retain_as_published(true),
+        )
+        .send()
+        .await
         .unwrap();
-    poll_fn(|cx| framed.flush(cx)).await.unwrap();
-    drop(framed);
-    sleep(Duration::from_millis(500)).await;
 
-    assert!(publish.load(Relaxed));
-    assert!(disconnect.load(Relaxed));
+    sink.publish(ByteString::from_static("a/b"), Bytes::new())
+        .retain()
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sink.publish(ByteString::from_static("b"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(50)).await;
 
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![(
+            ByteString::from_static("a/b"),
+            codec::QoS::AtMostOnce,
+            true,
+            Some(vec![std::num::NonZeroU32::new(5).unwrap()])
+        )]
+    );
     Ok(())
 }
+
+#[ntex::test]
+async fn test_conformance() {
+    let srv = MqttServer::new(|con: Handshake<_>| {
+        let keep_alive = con.packet().keep_alive;
+        let ack = con.ack(St);
+        if keep_alive > 0 {
+            ok::<_, TestError>(ack.keep_alive(Duration::from_secs(keep_alive as u64)))
+        } else {
+            ok(ack)
+        }
+    })
+    .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+    .finish();
+    let report = ntex_mqtt::conformance::v5::check(
+        srv,
+        codec::Connect::default().client_id("conformance"),
+    )
+    .await;
+
+    // server does not validate these packets, handlers are responsible
+    let failed: Vec<_> = report.failed().map(|o| o.rule).collect();
+    assert_eq!(failed, vec!["MQTT-3.1.0-2", "MQTT-3.3.2-2"], "{}", report);
+    assert!(report.get("MQTT-3.1.2-22").unwrap().is_passed());
+}
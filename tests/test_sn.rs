@@ -0,0 +1,298 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::ok;
+use ntex::codec::{Decoder, Encoder};
+use ntex::rt::{net::UdpSocket, time::sleep};
+use ntex::server;
+use ntex::util::{ByteString, Bytes, BytesMut};
+
+use ntex_mqtt::sn::{codec, Gateway};
+use ntex_mqtt::v3::{self, client, ControlMessage, Handshake, MqttServer};
+
+async fn send(socket: &UdpSocket, pkt: codec::Packet) {
+    let mut buf = BytesMut::new();
+    codec::Codec::new().encode(pkt, &mut buf).unwrap();
+    socket.send(&buf).await.unwrap();
+}
+
+async fn recv(socket: &UdpSocket) -> codec::Packet {
+    let mut buf = vec![0; 1024];
+    let size = socket.recv(&mut buf).await.unwrap();
+    codec::Codec::new().decode(&mut BytesMut::from(&buf[..size])).unwrap().unwrap()
+}
+
+#[ntex::test]
+async fn test_gateway() -> std::io::Result<()> {
+    let publishes = Arc::new(Mutex::new(Vec::new()));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        MqttServer::new(|h: Handshake<_>| ok::<_, ()>(h.ack((), false)))
+            .publish(move |p: v3::Publish| {
+                publishes
+                    .lock()
+                    .unwrap()
+                    .push((p.publish_topic().to_string(), p.payload().clone()));
+                ok::<_, ()>(())
+            })
+            .control(|msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.confirm(sub.qos());
+                    }
+                    ok::<_, ()>(msg.ack())
+                }
+                ControlMessage::Unsubscribe(msg) => ok(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("gateway").connect().await.unwrap();
+    let gateway = Gateway::new(client.sink()).predefined_topic(7, "predefined");
+    ntex::rt::spawn(client.start_default());
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = socket.local_addr()?;
+    let gw = gateway.clone();
+    ntex::rt::spawn(async move {
+        let _ = gw.run(socket).await;
+    });
+
+    let sn = UdpSocket::bind("127.0.0.1:0").await?;
+    sn.connect(addr).await?;
+
+    // packets from not connected clients are ignored
+    send(&sn, codec::Packet::PingRequest { client_id: None }).await;
+    send(&sn, codec::Packet::SearchGw { radius: 0 }).await;
+    assert_eq!(recv(&sn).await, codec::Packet::GwInfo { gw_id: 1, gw_addr: Bytes::new() });
+
+    send(
+        &sn,
+        codec::Packet::Connect(codec::Connect {
+            will: false,
+            clean_session: true,
+            duration: 30,
+            client_id: ByteString::from_static("sensor"),
+        }),
+    )
+    .await;
+    assert_eq!(
+        recv(&sn).await,
+        codec::Packet::ConnectAck { return_code: codec::ReturnCode::Accepted }
+    );
+    assert_eq!(gateway.clients(), 1);
+
+    // publish with registered topic id
+    send(
+        &sn,
+        codec::Packet::Register {
+            topic_id: 0,
+            msg_id: 1,
+            topic: ByteString::from_static("sensors/temp"),
+        },
+    )
+    .await;
+    assert_eq!(
+        recv(&sn).await,
+        codec::Packet::RegisterAck {
+            topic_id: 1,
+            msg_id: 1,
+            return_code: codec::ReturnCode::Accepted
+        }
+    );
+    let publish = |topic, msg_id| {
+        codec::Packet::Publish(codec::Publish {
+            topic,
+            msg_id,
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            payload: Bytes::from_static(b"21.5"),
+        })
+    };
+    send(&sn, publish(codec::TopicId::Normal(1), 2)).await;
+    assert_eq!(
+        recv(&sn).await,
+        codec::Packet::PublishAck {
+            topic_id: 1,
+            msg_id: 2,
+            return_code: codec::ReturnCode::Accepted
+        }
+    );
+
+    // unknown topic id
+    send(&sn, publish(codec::TopicId::Normal(5), 3)).await;
+    assert_eq!(
+        recv(&sn).await,
+        codec::Packet::PublishAck {
+            topic_id: 5,
+            msg_id: 3,
+            return_code: codec::ReturnCode::InvalidTopicId
+        }
+    );
+
+    // pre-defined topic id
+    send(&sn, publish(codec::TopicId::Predefined(7), 4)).await;
+    assert!(std::matches!(recv(&sn).await, codec::Packet::PublishAck { msg_id: 4, .. }));
+    assert_eq!(
+        *publishes.lock().unwrap(),
+        vec![
+            ("sensors/temp".to_string(), Bytes::from_static(b"21.5")),
+            ("predefined".to_string(), Bytes::from_static(b"21.5"))
+        ]
+    );
+
+    // subscribe and receive upstream messages
+    send(
+        &sn,
+        codec::Packet::Subscribe {
+            dup: false,
+            qos: codec::QoS::AtLeastOnce,
+            msg_id: 5,
+            topic: codec::SubscribeTopic::Name(ByteString::from_static("cmd/#")),
+        },
+    )
+    .await;
+    assert_eq!(
+        recv(&sn).await,
+        codec::Packet::SubscribeAck {
+            qos: codec::QoS::AtMostOnce,
+            topic_id: 0,
+            msg_id: 5,
+            return_code: codec::ReturnCode::Accepted
+        }
+    );
+
+    assert_eq!(gateway.publish("other", Bytes::from_static(b"on")), 0);
+    assert_eq!(gateway.publish("cmd/led", Bytes::from_static(b"on")), 1);
+    assert_eq!(
+        recv(&sn).await,
+        codec::Packet::Register {
+            topic_id: 2,
+            msg_id: 0,
+            topic: ByteString::from_static("cmd/led")
+        }
+    );
+    assert_eq!(
+        recv(&sn).await,
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: codec::TopicId::Normal(2),
+            msg_id: 0,
+            payload: Bytes::from_static(b"on"),
+        })
+    );
+
+    send(&sn, codec::Packet::Disconnect { duration: None }).await;
+    assert_eq!(recv(&sn).await, codec::Packet::Disconnect { duration: None });
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(gateway.clients(), 0);
+    assert_eq!(gateway.publish("cmd/led", Bytes::from_static(b"on")), 0);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_gateway_limits() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|h: Handshake<_>| ok::<_, ()>(h.ack((), false)))
+            .publish(|_: v3::Publish| ok::<_, ()>(()))
+            .control(|msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        if sub.topic().starts_with("denied") {
+                            sub.fail();
+                        } else {
+                            sub.confirm(sub.qos());
+                        }
+                    }
+                    ok::<_, ()>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("gateway").connect().await.unwrap();
+    let gateway = Gateway::new(client.sink()).max_clients(1);
+    ntex::rt::spawn(client.start_default());
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = socket.local_addr()?;
+    let gw = gateway.clone();
+    ntex::rt::spawn(async move {
+        let _ = gw.run(socket).await;
+    });
+
+    let connect = |client_id| {
+        codec::Packet::Connect(codec::Connect {
+            will: false,
+            clean_session: true,
+            duration: 1,
+            client_id: ByteString::from_static(client_id),
+        })
+    };
+    let sn1 = UdpSocket::bind("127.0.0.1:0").await?;
+    sn1.connect(addr).await?;
+    let sn2 = UdpSocket::bind("127.0.0.1:0").await?;
+    sn2.connect(addr).await?;
+
+    send(&sn1, connect("sensor1")).await;
+    assert_eq!(
+        recv(&sn1).await,
+        codec::Packet::ConnectAck { return_code: codec::ReturnCode::Accepted }
+    );
+
+    // subscription refused by upstream server is permanent failure
+    send(
+        &sn1,
+        codec::Packet::Subscribe {
+            dup: false,
+            qos: codec::QoS::AtMostOnce,
+            msg_id: 1,
+            topic: codec::SubscribeTopic::Name(ByteString::from_static("denied/#")),
+        },
+    )
+    .await;
+    assert_eq!(
+        recv(&sn1).await,
+        codec::Packet::SubscribeAck {
+            qos: codec::QoS::AtMostOnce,
+            topic_id: 0,
+            msg_id: 1,
+            return_code: codec::ReturnCode::NotSupported
+        }
+    );
+
+    // max number of clients is reached, reconnect of connected client is allowed
+    send(&sn2, connect("sensor2")).await;
+    assert_eq!(
+        recv(&sn2).await,
+        codec::Packet::ConnectAck { return_code: codec::ReturnCode::Congestion }
+    );
+    send(&sn1, connect("sensor1")).await;
+    assert_eq!(
+        recv(&sn1).await,
+        codec::Packet::ConnectAck { return_code: codec::ReturnCode::Accepted }
+    );
+    assert_eq!(gateway.clients(), 1);
+
+    // inactive client is expired without incoming datagrams
+    sleep(Duration::from_millis(2700)).await;
+    assert_eq!(gateway.clients(), 0);
+
+    send(&sn2, connect("sensor2")).await;
+    assert_eq!(
+        recv(&sn2).await,
+        codec::Packet::ConnectAck { return_code: codec::ReturnCode::Accepted }
+    );
+
+    Ok(())
+}